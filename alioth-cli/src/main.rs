@@ -14,21 +14,34 @@
 
 use std::ffi::CString;
 use std::fs::File;
+#[cfg(target_os = "linux")]
+use std::os::unix::net::UnixListener;
 use std::path::PathBuf;
+#[cfg(target_os = "linux")]
+use std::sync::Arc;
 
+#[cfg(target_arch = "x86_64")]
+use alioth::arch::mce::MceBanks;
+#[cfg(target_arch = "aarch64")]
+use alioth::arch::reg::IdRegs;
 use alioth::board::BoardConfig;
+#[cfg(target_os = "linux")]
+use alioth::device::ivshmem::{IvshMemParam, IvshMemServer};
 use alioth::errors::{trace_error, DebugTrace};
 #[cfg(target_os = "macos")]
 use alioth::hv::Hvf;
 #[cfg(target_os = "linux")]
 use alioth::hv::{Kvm, KvmConfig};
 use alioth::loader::{ExecType, Payload};
+#[cfg(target_os = "linux")]
+use alioth::mem::shared::SharedMemFd;
 use alioth::virtio::dev::blk::BlockParam;
 use alioth::virtio::dev::entropy::EntropyParam;
 #[cfg(target_os = "linux")]
 use alioth::virtio::dev::fs::VuFsParam;
 #[cfg(target_os = "linux")]
 use alioth::virtio::dev::net::NetParam;
+use alioth::virtio::dev::sound::SoundParam;
 #[cfg(target_os = "linux")]
 use alioth::virtio::dev::vsock::VhostVsockParam;
 use alioth::vm::Machine;
@@ -58,7 +71,22 @@ struct Cli {
 
 #[derive(Subcommand, Debug)]
 enum Command {
-    Run(RunArgs),
+    Run(Box<RunArgs>),
+    /// Serve the shared memory and doorbells to ivshmem devices.
+    #[cfg(target_os = "linux")]
+    IvshmemServer(IvshmemServerArgs),
+}
+
+#[cfg(target_os = "linux")]
+#[derive(Args, Debug, Clone)]
+struct IvshmemServerArgs {
+    /// Path to the socket that ivshmem devices connect to.
+    #[arg(long)]
+    socket: PathBuf,
+
+    /// Size of the shared memory, which must be a power of 2.
+    #[arg(long, default_value = "4M")]
+    size: String,
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -108,6 +136,15 @@ struct RunArgs {
     #[arg(long, short)]
     firmware: Option<PathBuf>,
 
+    /// UEFI variable store placed right below the firmware.
+    #[arg(long)]
+    firmware_vars: Option<PathBuf>,
+
+    /// Legacy BIOS, e.g. SeaBIOS.
+    #[cfg(target_arch = "x86_64")]
+    #[arg(long)]
+    bios: Option<PathBuf>,
+
     #[arg(short, long)]
     cmd_line: Option<String>,
 
@@ -123,12 +160,33 @@ struct RunArgs {
     #[arg(long)]
     pvpanic: bool,
 
+    /// Write firmware debug messages from port 0x402 and 0xe9 to a file.
+    #[cfg(target_arch = "x86_64")]
+    #[arg(long)]
+    debugcon: Option<PathBuf>,
+
+    /// Add an xHCI controller.
+    #[arg(long)]
+    xhci: bool,
+
+    /// Add an ivshmem device, e.g. `socket=/tmp/ivshmem.sock,vectors=2`.
+    #[cfg(target_os = "linux")]
+    #[arg(long)]
+    ivshmem: Vec<String>,
+
     #[arg(long = "fw-cfg")]
     fw_cfgs: Vec<String>,
 
     #[arg(long)]
     entropy: bool,
 
+    /// Source of the entropy device: hwrng, urandom, or getrandom.
+    #[arg(long)]
+    rng_source: Option<String>,
+
+    #[arg(long)]
+    audio_backend: Option<String>,
+
     #[arg(long)]
     net: Vec<String>,
 
@@ -138,11 +196,48 @@ struct RunArgs {
     #[arg(long)]
     coco: Option<String>,
 
+    #[arg(long)]
+    bounce_buffer_size: Option<String>,
+
+    #[arg(long)]
+    trace_mmio: bool,
+
     #[arg(long)]
     fs: Vec<String>,
 
     #[arg(long)]
     vsock: Option<String>,
+
+    #[cfg(target_arch = "x86_64")]
+    #[arg(long)]
+    cpuid: Vec<String>,
+
+    #[cfg(target_arch = "x86_64")]
+    #[arg(long)]
+    tsc: Option<String>,
+
+    #[cfg(target_arch = "x86_64")]
+    #[arg(long)]
+    hyperv: Option<String>,
+
+    /// Number of machine check banks, or 0 to disable them.
+    #[cfg(target_arch = "x86_64")]
+    #[arg(long)]
+    mce_banks: Option<u8>,
+
+    /// Override the ID registers reported to the guest.
+    #[cfg(target_arch = "aarch64")]
+    #[arg(long)]
+    id_regs: Option<String>,
+
+    #[cfg(target_os = "linux")]
+    #[arg(long)]
+    cgroup: Option<String>,
+
+    /// Preempt a vCPU every given milliseconds of CPU time it uses.
+    #[cfg(target_os = "linux")]
+    #[arg(long)]
+    vcpu_quantum_ms: Option<u64>,
 }
 
 #[trace_error]
@@ -169,6 +264,14 @@ pub enum Error {
     FwCfg { error: std::io::Error },
     #[snafu(display("{s} is not a valid CString"))]
     CreateCString { s: String },
+    #[cfg(target_os = "linux")]
+    #[snafu(display("Failed to create shared memory"))]
+    SharedMem { source: alioth::mem::Error },
+    #[cfg(target_os = "linux")]
+    #[snafu(display("ivshmem server failed"))]
+    IvshmemServer {
+        source: alioth::device::ivshmem::Error,
+    },
     #[snafu(display("Failed to boot a VM"))]
     BootVm { source: alioth::vm::Error },
     #[snafu(display("VM did not shutdown peacefully"))]
@@ -191,15 +294,64 @@ fn main_run(args: RunArgs) -> Result<(), Error> {
         None => None,
         Some(c) => Some(serde_aco::from_arg(&c).context(error::ParseArg { arg: c })?),
     };
+    let bounce_buffer_size = match args.bounce_buffer_size {
+        None => None,
+        Some(s) => Some(serde_aco::from_arg(&s).context(error::ParseArg { arg: s })?),
+    };
     let board_config = BoardConfig {
         mem_size: serde_aco::from_arg(&args.mem_size)
             .context(error::ParseArg { arg: args.mem_size })?,
         num_cpu: args.num_cpu,
         coco,
+        bounce_buffer_size,
+        trace_mmio: args.trace_mmio,
+        #[cfg(target_arch = "x86_64")]
+        cpuid_overrides: args
+            .cpuid
+            .into_iter()
+            .map(|s| serde_aco::from_arg(&s).context(error::ParseArg { arg: s }))
+            .collect::<Result<Vec<_>, _>>()?,
+        #[cfg(target_arch = "x86_64")]
+        tsc: match args.tsc {
+            None => None,
+            Some(s) => Some(serde_aco::from_arg(&s).context(error::ParseArg { arg: s })?),
+        },
+        #[cfg(target_arch = "x86_64")]
+        hyperv: match args.hyperv {
+            None => None,
+            Some(s) => Some(serde_aco::from_arg(&s).context(error::ParseArg { arg: s })?),
+        },
+        #[cfg(target_arch = "x86_64")]
+        mce_banks: match args.mce_banks {
+            None => MceBanks::default(),
+            Some(count) => MceBanks { count },
+        },
+        #[cfg(target_arch = "aarch64")]
+        id_regs: match args.id_regs {
+            None => IdRegs::default(),
+            Some(s) => serde_aco::from_arg(&s).context(error::ParseArg { arg: s })?,
+        },
+        #[cfg(target_os = "linux")]
+        cgroup: match args.cgroup {
+            None => None,
+            Some(s) => Some(serde_aco::from_arg(&s).context(error::ParseArg { arg: s })?),
+        },
+        #[cfg(target_os = "linux")]
+        vcpu_quantum_ms: args.vcpu_quantum_ms,
     };
     let mut vm = Machine::new(hypervisor, board_config).context(error::CreateVm)?;
     #[cfg(target_arch = "x86_64")]
     vm.add_com1().context(error::CreateDevice)?;
+    #[cfg(target_arch = "x86_64")]
+    vm.add_pit().context(error::CreateDevice)?;
+    #[cfg(target_arch = "x86_64")]
+    vm.add_rtc().context(error::CreateDevice)?;
+    #[cfg(target_arch = "x86_64")]
+    if let Some(path) = &args.debugcon {
+        let file = File::create(path).context(error::OpenFile { path })?;
+        vm.add_debugcon(Box::new(file))
+            .context(error::CreateDevice)?;
+    }
     #[cfg(target_arch = "aarch64")]
     vm.add_pl011().context(error::CreateDevice)?;
 
@@ -207,7 +359,27 @@ fn main_run(args: RunArgs) -> Result<(), Error> {
         vm.add_pvpanic().context(error::CreateDevice)?;
     }
 
-    if args.firmware.is_some() || !args.fw_cfgs.is_empty() {
+    if args.xhci {
+        vm.add_xhci().context(error::CreateDevice)?;
+    }
+
+    #[cfg(target_os = "linux")]
+    for (index, ivshmem) in args.ivshmem.into_iter().enumerate() {
+        let param: IvshMemParam =
+            serde_aco::from_arg(&ivshmem).context(error::ParseArg { arg: ivshmem })?;
+        vm.add_ivshmem(format!("ivshmem-{index}"), &param)
+            .context(error::CreateDevice)?;
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    let firmware = match args.bios {
+        Some(bios) => Some((bios, ExecType::Bios)),
+        None => args.firmware.map(|fw| (fw, ExecType::Firmware)),
+    };
+    #[cfg(not(target_arch = "x86_64"))]
+    let firmware = args.firmware.map(|fw| (fw, ExecType::Firmware));
+
+    if firmware.is_some() || !args.fw_cfgs.is_empty() {
         let params = args
             .fw_cfgs
             .into_iter()
@@ -240,7 +412,18 @@ fn main_run(args: RunArgs) -> Result<(), Error> {
     };
 
     if args.entropy {
-        vm.add_virtio_dev("virtio-entropy".to_owned(), EntropyParam)
+        let param = match args.rng_source {
+            Some(s) => EntropyParam {
+                source: serde_aco::from_arg(&s).context(error::ParseArg { arg: s })?,
+            },
+            None => EntropyParam::default(),
+        };
+        vm.add_virtio_dev("virtio-entropy".to_owned(), param)
+            .context(error::CreateDevice)?;
+    }
+    if let Some(backend) = args.audio_backend {
+        let backend = serde_aco::from_arg(&backend).context(error::ParseArg { arg: backend })?;
+        vm.add_virtio_dev("virtio-sound".to_owned(), SoundParam { backend })
             .context(error::CreateDevice)?;
     }
     #[cfg(target_os = "linux")]
@@ -251,7 +434,14 @@ fn main_run(args: RunArgs) -> Result<(), Error> {
             .context(error::CreateDevice)?;
     }
     for (index, blk) in args.blk.into_iter().enumerate() {
-        let param = BlockParam { path: blk.into() };
+        let param = if blk.contains('=') {
+            serde_aco::from_arg(&blk).context(error::ParseArg { arg: blk })?
+        } else {
+            BlockParam {
+                path: blk.into(),
+                ..Default::default()
+            }
+        };
         vm.add_virtio_dev(format!("virtio-blk-{index}"), param)
             .context(error::CreateDevice)?;
     }
@@ -274,12 +464,13 @@ fn main_run(args: RunArgs) -> Result<(), Error> {
         };
     }
 
-    let payload = if let Some(fw) = args.firmware {
+    let payload = if let Some((fw, exec_type)) = firmware {
         Some(Payload {
             executable: fw,
-            exec_type: ExecType::Firmware,
+            exec_type,
             initramfs: None,
             cmd_line: None,
+            firmware_vars: args.firmware_vars,
         })
     } else if let Some(kernel) = args.kernel {
         Some(Payload {
@@ -287,6 +478,7 @@ fn main_run(args: RunArgs) -> Result<(), Error> {
             executable: kernel,
             initramfs: args.initramfs,
             cmd_line: args.cmd_line,
+            firmware_vars: None,
         })
     } else {
         #[cfg(target_arch = "x86_64")]
@@ -296,6 +488,7 @@ fn main_run(args: RunArgs) -> Result<(), Error> {
                 exec_type: ExecType::Pvh,
                 initramfs: args.initramfs,
                 cmd_line: args.cmd_line,
+                firmware_vars: None,
             })
         } else {
             None
@@ -311,9 +504,23 @@ fn main_run(args: RunArgs) -> Result<(), Error> {
     for result in vm.wait() {
         result.context(error::WaitVm)?;
     }
+    for (id, stats) in vm.vcpu_stats() {
+        log::info!("vcpu {id}: {} exits: {stats:?}", stats.total());
+    }
     Ok(())
 }
 
+#[cfg(target_os = "linux")]
+fn main_ivshmem_server(args: IvshmemServerArgs) -> Result<(), Error> {
+    let size = serde_aco::from_arg(&args.size).context(error::ParseArg { arg: args.size })?;
+    let shm = SharedMemFd::new(c"alioth-ivshmem", size).context(error::SharedMem)?;
+    let listener = UnixListener::bind(&args.socket).context(error::OpenFile {
+        path: args.socket.clone(),
+    })?;
+    let server = Arc::new(IvshMemServer::new(shm));
+    server.serve(listener).context(error::IvshmemServer)
+}
+
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     let cli = Cli::parse();
     let logger = if let Some(ref spec) = cli.log_spec {
@@ -341,7 +548,9 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     };
 
     match cmd {
-        Command::Run(args) => main_run(args)?,
+        Command::Run(args) => main_run(*args)?,
+        #[cfg(target_os = "linux")]
+        Command::IvshmemServer(args) => main_ivshmem_server(args)?,
     }
     Ok(())
 }
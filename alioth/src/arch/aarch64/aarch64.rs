@@ -14,3 +14,4 @@
 
 pub mod layout;
 pub mod reg;
+pub mod smccc;
@@ -15,6 +15,7 @@
 use crate::c_enum;
 use bitfield::bitfield;
 use bitflags::bitflags;
+use serde::Deserialize;
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum Reg {
@@ -64,6 +65,12 @@ c_enum! {
     {
         /// Exception Syndrome Register (EL2)
         ESR_EL2 = encode(3, 4, 5, 2, 0);
+        /// AArch64 Memory Model Feature Register 0
+        ID_AA64MMFR0_EL1 = encode(3, 0, 0, 7, 0);
+        /// AArch64 Processor Feature Register 0
+        ID_AA64PFR0_EL1 = encode(3, 0, 0, 4, 0);
+        /// Main ID Register
+        MIDR_EL1 = encode(3, 0, 0, 0, 0);
         /// Multiprocessor Affinity Register
         MPIDR_EL1 = encode(3, 0, 0, 0, 5);
         /// Stack Pointer (EL0)
@@ -71,6 +78,30 @@ c_enum! {
     }
 }
 
+/// Values of the ID registers reported to the guest.
+///
+/// Registers left as `None` keep the values chosen by the hypervisor. The
+/// hypervisor may reject values claiming features the host does not have.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+pub struct IdRegs {
+    pub midr: Option<u64>,
+    pub pfr0: Option<u64>,
+    pub mmfr0: Option<u64>,
+}
+
+impl IdRegs {
+    pub fn sregs(&self) -> Vec<(SReg, u64)> {
+        let regs = [
+            (SReg::MIDR_EL1, self.midr),
+            (SReg::ID_AA64PFR0_EL1, self.pfr0),
+            (SReg::ID_AA64MMFR0_EL1, self.mmfr0),
+        ];
+        regs.into_iter()
+            .filter_map(|(reg, val)| Some((reg, val?)))
+            .collect()
+    }
+}
+
 // https://developer.arm.com/documentation/den0024/a/ARMv8-Registers/Processor-state
 bitflags! {
     #[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Hash)]
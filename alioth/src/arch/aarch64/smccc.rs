@@ -0,0 +1,169 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::HashMap;
+use std::fmt::Debug;
+
+use bitfield::bitfield;
+
+use crate::c_enum;
+
+bitfield! {
+    /// Function Identifier of the SMC Calling Convention, passed in W0.
+    ///
+    /// https://developer.arm.com/documentation/den0028/latest
+    #[derive(Copy, Clone, Default, PartialEq, Eq, Hash)]
+    #[repr(transparent)]
+    pub struct SmcccFuncId(u32);
+    impl Debug;
+    pub fast, _: 31;
+    pub smc64, _: 30;
+    pub u8, into SmcccOwner, owner, _: 29, 24;
+    pub u16, number, _: 15, 0;
+}
+
+c_enum! {
+    pub struct SmcccOwner(u8);
+    {
+        ARCH = 0;
+        CPU = 1;
+        SIP = 2;
+        OEM = 3;
+        STANDARD = 4;
+        STANDARD_HYP = 5;
+        VENDOR_HYP = 6;
+    }
+}
+
+pub const SMCCC_RET_NOT_SUPPORTED: u64 = -1i64 as u64;
+
+pub const ARM_SMCCC_VENDOR_HYP_CALL_UID: u32 = 0x8600_ff01;
+
+/// 9fc44ff9-b8f5-4d58-8ffc-20c45c6298b6
+pub const ALIOTH_UUID: [u8; 16] = [
+    0x9f, 0xc4, 0x4f, 0xf9, 0xb8, 0xf5, 0x4d, 0x58, 0x8f, 0xfc, 0x20, 0xc4, 0x5c, 0x62, 0x98, 0xb6,
+];
+
+pub trait SmcccHandler: Debug + Send + Sync {
+    /// Handles a call with arguments in X1-X7 and returns X0-X3.
+    fn handle(&self, func_id: SmcccFuncId, args: &[u64; 7]) -> [u64; 4];
+}
+
+/// Returns [`ALIOTH_UUID`] for `ARM_SMCCC_VENDOR_HYP_CALL_UID`, with
+/// which a guest identifies the hypervisor.
+#[derive(Debug)]
+struct VendorHypCallUid;
+
+impl SmcccHandler for VendorHypCallUid {
+    fn handle(&self, _func_id: SmcccFuncId, _args: &[u64; 7]) -> [u64; 4] {
+        let mut ret = [0; 4];
+        for (r, bytes) in ret.iter_mut().zip(ALIOTH_UUID.chunks_exact(4)) {
+            *r = u32::from_le_bytes(bytes.try_into().unwrap()) as u64;
+        }
+        ret
+    }
+}
+
+/// Routes SMC and HVC calls that the hypervisor forwards to user space.
+#[derive(Debug)]
+pub struct SmcccDispatcher {
+    handlers: HashMap<u32, Box<dyn SmcccHandler>>,
+}
+
+impl Default for SmcccDispatcher {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SmcccDispatcher {
+    pub fn new() -> Self {
+        let mut dispatcher = SmcccDispatcher {
+            handlers: HashMap::new(),
+        };
+        dispatcher.register(ARM_SMCCC_VENDOR_HYP_CALL_UID, Box::new(VendorHypCallUid));
+        dispatcher
+    }
+
+    pub fn register(&mut self, fn_id: u32, handler: Box<dyn SmcccHandler>) {
+        if self.handlers.insert(fn_id, handler).is_some() {
+            log::warn!("SMCCC function {fn_id:#x}: handler replaced");
+        }
+    }
+
+    pub fn func_ids(&self) -> impl Iterator<Item = u32> + '_ {
+        self.handlers.keys().copied()
+    }
+
+    pub fn dispatch(&self, func_id: u32, args: &[u64; 7]) -> [u64; 4] {
+        let Some(handler) = self.handlers.get(&func_id) else {
+            log::warn!("unhandled SMCCC call {func_id:#x}, args = {args:x?}");
+            return [SMCCC_RET_NOT_SUPPORTED, 0, 0, 0];
+        };
+        handler.handle(SmcccFuncId(func_id), args)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::arch::smccc::{
+        SmcccDispatcher, SmcccFuncId, SmcccHandler, SmcccOwner, ARM_SMCCC_VENDOR_HYP_CALL_UID,
+        SMCCC_RET_NOT_SUPPORTED,
+    };
+
+    #[derive(Debug)]
+    struct Echo;
+
+    impl SmcccHandler for Echo {
+        fn handle(&self, func_id: SmcccFuncId, args: &[u64; 7]) -> [u64; 4] {
+            [func_id.number() as u64, args[0], args[1], args[6]]
+        }
+    }
+
+    #[test]
+    fn test_func_id() {
+        let id = SmcccFuncId(ARM_SMCCC_VENDOR_HYP_CALL_UID);
+        assert!(id.fast());
+        assert!(!id.smc64());
+        assert_eq!(id.owner(), SmcccOwner::VENDOR_HYP);
+        assert_eq!(id.number(), 0xff01);
+
+        // PSCI 0.2 CPU_ON, SMC64
+        let id = SmcccFuncId(0xc400_0003);
+        assert!(id.fast());
+        assert!(id.smc64());
+        assert_eq!(id.owner(), SmcccOwner::STANDARD);
+        assert_eq!(id.number(), 3);
+    }
+
+    #[test]
+    fn test_dispatch() {
+        let mut dispatcher = SmcccDispatcher::new();
+        let args = [1, 2, 3, 4, 5, 6, 7];
+        assert_eq!(
+            dispatcher.dispatch(ARM_SMCCC_VENDOR_HYP_CALL_UID, &args),
+            [0xf94f_c49f, 0x584d_f5b8, 0xc420_fc8f, 0xb698_625c]
+        );
+        assert_eq!(
+            dispatcher.dispatch(0xc600_0001, &args),
+            [SMCCC_RET_NOT_SUPPORTED, 0, 0, 0]
+        );
+
+        dispatcher.register(0xc600_0001, Box::new(Echo));
+        assert_eq!(dispatcher.dispatch(0xc600_0001, &args), [1, 1, 2, 7]);
+        let mut ids: Vec<_> = dispatcher.func_ids().collect();
+        ids.sort();
+        assert_eq!(ids, [ARM_SMCCC_VENDOR_HYP_CALL_UID, 0xc600_0001]);
+    }
+}
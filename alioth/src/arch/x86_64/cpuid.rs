@@ -12,6 +12,8 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use serde::Deserialize;
+
 #[derive(Debug, Default, Clone)]
 pub struct Cpuid {
     pub func: u32,
@@ -21,3 +23,141 @@ pub struct Cpuid {
     pub ecx: u32,
     pub edx: u32,
 }
+
+impl Cpuid {
+    fn reg_mut(&mut self, reg: CpuidReg) -> &mut u32 {
+        match reg {
+            CpuidReg::Eax => &mut self.eax,
+            CpuidReg::Ebx => &mut self.ebx,
+            CpuidReg::Ecx => &mut self.ecx,
+            CpuidReg::Edx => &mut self.edx,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum CpuidReg {
+    #[serde(alias = "eax")]
+    Eax,
+    #[serde(alias = "ebx")]
+    Ebx,
+    #[serde(alias = "ecx")]
+    Ecx,
+    #[serde(alias = "edx")]
+    Edx,
+}
+
+/// Overrides one register of a CPUID leaf.
+///
+/// If `value` is given, the register is replaced by `value` first. Bits in
+/// `set` are then forced to 1 and bits in `clear` are forced to 0. A leaf
+/// not reported by the hypervisor is added.
+#[derive(Debug, Clone, Deserialize)]
+pub struct CpuidOverride {
+    pub func: u32,
+    pub index: Option<u32>,
+    pub reg: CpuidReg,
+    pub value: Option<u32>,
+    #[serde(default)]
+    pub set: u32,
+    #[serde(default)]
+    pub clear: u32,
+}
+
+impl CpuidOverride {
+    fn apply(&self, cpuid: &mut Cpuid) {
+        let reg = cpuid.reg_mut(self.reg);
+        if let Some(value) = self.value {
+            *reg = value;
+        }
+        *reg = (*reg | self.set) & !self.clear;
+    }
+}
+
+pub fn apply_cpuid_overrides(cpuids: &mut Vec<Cpuid>, overrides: &[CpuidOverride]) {
+    for o in overrides {
+        let mut found = false;
+        for cpuid in cpuids.iter_mut() {
+            if cpuid.func == o.func && (o.index.is_none() || o.index == cpuid.index) {
+                o.apply(cpuid);
+                found = true;
+            }
+        }
+        if !found {
+            let mut cpuid = Cpuid {
+                func: o.func,
+                index: o.index,
+                ..Default::default()
+            };
+            o.apply(&mut cpuid);
+            cpuids.push(cpuid);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{apply_cpuid_overrides, Cpuid, CpuidOverride, CpuidReg};
+
+    #[test]
+    fn test_apply_cpuid_overrides() {
+        let mut cpuids = vec![
+            Cpuid {
+                func: 0x1,
+                ecx: 1 << 31,
+                ..Default::default()
+            },
+            Cpuid {
+                func: 0x4000_0000,
+                eax: 0x4000_0001,
+                ebx: u32::from_le_bytes(*b"KVMK"),
+                ecx: u32::from_le_bytes(*b"VMKV"),
+                edx: u32::from_le_bytes(*b"M\0\0\0"),
+                ..Default::default()
+            },
+        ];
+        let vendor = |reg, bytes: &[u8; 4]| CpuidOverride {
+            func: 0x4000_0000,
+            index: None,
+            reg,
+            value: Some(u32::from_le_bytes(*bytes)),
+            set: 0,
+            clear: 0,
+        };
+        let overrides = [
+            vendor(CpuidReg::Ebx, b"Alio"),
+            vendor(CpuidReg::Ecx, b"thAl"),
+            vendor(CpuidReg::Edx, b"ioth"),
+            CpuidOverride {
+                func: 0x1,
+                index: None,
+                reg: CpuidReg::Ecx,
+                value: None,
+                set: 1 << 26,
+                clear: 1 << 31,
+            },
+            CpuidOverride {
+                func: 0x7,
+                index: Some(0),
+                reg: CpuidReg::Ebx,
+                value: None,
+                set: 1 << 3,
+                clear: 0,
+            },
+        ];
+        apply_cpuid_overrides(&mut cpuids, &overrides);
+
+        assert_eq!(cpuids[0].ecx, 1 << 26);
+        let leaf = &cpuids[1];
+        assert_eq!(leaf.eax, 0x4000_0001);
+        let mut vendor = [0u8; 12];
+        vendor[0..4].copy_from_slice(&leaf.ebx.to_le_bytes());
+        vendor[4..8].copy_from_slice(&leaf.ecx.to_le_bytes());
+        vendor[8..12].copy_from_slice(&leaf.edx.to_le_bytes());
+        assert_eq!(&vendor, b"AliothAlioth");
+        assert_eq!(cpuids.len(), 3);
+        assert_eq!(cpuids[2].func, 0x7);
+        assert_eq!(cpuids[2].index, Some(0));
+        assert_eq!(cpuids[2].ebx, 1 << 3);
+    }
+}
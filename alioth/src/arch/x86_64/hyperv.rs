@@ -0,0 +1,223 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use bitflags::bitflags;
+use serde::Deserialize;
+
+use crate::arch::cpuid::Cpuid;
+
+// Hyper-V Top Level Functional Specification, section 2.4.
+pub const HV_CPUID_BASE: u32 = 0x4000_0000;
+pub const HV_CPUID_MAX: u32 = 0x4000_0006;
+/// Hypervisor leaves reported by KVM are moved here so that guests can
+/// still find them after the Hyper-V leaves.
+pub const KVM_CPUID_BASE_WITH_HV: u32 = 0x4000_0100;
+
+pub const HV_X64_MSR_GUEST_OS_ID: u32 = 0x4000_0000;
+pub const HV_X64_MSR_HYPERCALL: u32 = 0x4000_0001;
+pub const HV_X64_MSR_VP_INDEX: u32 = 0x4000_0002;
+pub const HV_X64_MSR_REFERENCE_TSC: u32 = 0x4000_0021;
+
+pub const HV_X64_MSR_REFERENCE_TSC_ENABLE: u64 = 1 << 0;
+
+bitflags! {
+    /// Partition privileges in EAX of leaf 0x4000_0003.
+    #[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    pub struct HvPrivilege: u32 {
+        const VP_RUNTIME = 1 << 0;
+        const TIME_REF_COUNT = 1 << 1;
+        const SYNIC = 1 << 2;
+        const SYNTIMER = 1 << 3;
+        const APIC_ACCESS = 1 << 4;
+        const HYPERCALL = 1 << 5;
+        const VP_INDEX = 1 << 6;
+        const RESET = 1 << 7;
+        const STAT_PAGES = 1 << 8;
+        const REFERENCE_TSC = 1 << 9;
+    }
+}
+
+bitflags! {
+    /// Features in EDX of leaf 0x4000_0003.
+    #[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    pub struct HvFeature: u32 {
+        const STIMER_DIRECT_MODE = 1 << 19;
+    }
+}
+
+/// Recommended in EAX of leaf 0x4000_0004.
+const HV_RELAXED_TIMING_RECOMMENDED: u32 = 1 << 5;
+
+/// Hyper-V enlightenments exposed to the guest.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+pub struct HypervEnlightenments {
+    /// Partition reference counter and reference TSC page.
+    #[serde(default)]
+    pub time: bool,
+    /// Synthetic interrupt controller.
+    #[serde(default)]
+    pub synic: bool,
+    /// Synthetic timers, which require `synic` and `time`.
+    #[serde(default)]
+    pub stimer: bool,
+}
+
+impl HypervEnlightenments {
+    pub fn is_empty(&self) -> bool {
+        !self.time && !self.synic && !self.stimer
+    }
+
+    fn privileges(&self) -> HvPrivilege {
+        let mut privileges = HvPrivilege::HYPERCALL | HvPrivilege::VP_INDEX;
+        if self.time {
+            privileges |= HvPrivilege::TIME_REF_COUNT | HvPrivilege::REFERENCE_TSC;
+        }
+        if self.synic {
+            privileges |= HvPrivilege::SYNIC;
+        }
+        if self.stimer {
+            privileges |= HvPrivilege::SYNTIMER;
+        }
+        privileges
+    }
+
+    fn features(&self) -> HvFeature {
+        if self.stimer {
+            HvFeature::STIMER_DIRECT_MODE
+        } else {
+            HvFeature::empty()
+        }
+    }
+
+    pub fn cpuids(&self, num_cpu: u32) -> Vec<Cpuid> {
+        let vendor = |s: &[u8; 4]| u32::from_le_bytes(*s);
+        vec![
+            Cpuid {
+                func: HV_CPUID_BASE,
+                eax: HV_CPUID_MAX,
+                ebx: vendor(b"Micr"),
+                ecx: vendor(b"osof"),
+                edx: vendor(b"t Hv"),
+                ..Default::default()
+            },
+            Cpuid {
+                func: 0x4000_0001,
+                eax: vendor(b"Hv#1"),
+                ..Default::default()
+            },
+            Cpuid {
+                func: 0x4000_0002,
+                eax: 0x3839,
+                ebx: 0x000a_0000,
+                ..Default::default()
+            },
+            Cpuid {
+                func: 0x4000_0003,
+                eax: self.privileges().bits(),
+                edx: self.features().bits(),
+                ..Default::default()
+            },
+            Cpuid {
+                func: 0x4000_0004,
+                eax: HV_RELAXED_TIMING_RECOMMENDED,
+                ebx: u32::MAX,
+                ..Default::default()
+            },
+            Cpuid {
+                func: 0x4000_0005,
+                eax: num_cpu,
+                ebx: num_cpu,
+                ..Default::default()
+            },
+            Cpuid {
+                func: HV_CPUID_MAX,
+                ..Default::default()
+            },
+        ]
+    }
+}
+
+/// Moves the hypervisor leaves in `cpuids` to [`KVM_CPUID_BASE_WITH_HV`]
+/// and inserts the Hyper-V leaves.
+pub fn set_hyperv_cpuids(cpuids: &mut Vec<Cpuid>, hv: &HypervEnlightenments, num_cpu: u32) {
+    for cpuid in cpuids.iter_mut() {
+        if cpuid.func & 0xffff_ff00 == HV_CPUID_BASE {
+            cpuid.func += KVM_CPUID_BASE_WITH_HV - HV_CPUID_BASE;
+            if cpuid.func == KVM_CPUID_BASE_WITH_HV {
+                cpuid.eax += KVM_CPUID_BASE_WITH_HV - HV_CPUID_BASE;
+            }
+        }
+    }
+    cpuids.extend(hv.cpuids(num_cpu));
+}
+
+#[cfg(test)]
+mod test {
+    use crate::arch::cpuid::Cpuid;
+
+    use super::{
+        set_hyperv_cpuids, HvFeature, HvPrivilege, HypervEnlightenments, HV_CPUID_MAX,
+        KVM_CPUID_BASE_WITH_HV,
+    };
+
+    #[test]
+    fn test_set_hyperv_cpuids() {
+        let mut cpuids = vec![
+            Cpuid {
+                func: 0x4000_0000,
+                eax: 0x4000_0001,
+                ebx: u32::from_le_bytes(*b"KVMK"),
+                ..Default::default()
+            },
+            Cpuid {
+                func: 0x4000_0001,
+                eax: 0x1,
+                ..Default::default()
+            },
+        ];
+        let hv = HypervEnlightenments {
+            time: true,
+            synic: true,
+            stimer: true,
+        };
+        set_hyperv_cpuids(&mut cpuids, &hv, 4);
+
+        assert_eq!(cpuids[0].func, KVM_CPUID_BASE_WITH_HV);
+        assert_eq!(cpuids[0].eax, KVM_CPUID_BASE_WITH_HV + 1);
+        assert_eq!(cpuids[1].func, KVM_CPUID_BASE_WITH_HV + 1);
+        assert_eq!(cpuids[1].eax, 0x1);
+
+        let leaf = |func| cpuids.iter().find(|c| c.func == func).unwrap();
+        let base = leaf(0x4000_0000);
+        assert_eq!(base.eax, HV_CPUID_MAX);
+        let mut vendor = [0u8; 12];
+        vendor[0..4].copy_from_slice(&base.ebx.to_le_bytes());
+        vendor[4..8].copy_from_slice(&base.ecx.to_le_bytes());
+        vendor[8..12].copy_from_slice(&base.edx.to_le_bytes());
+        assert_eq!(&vendor, b"Microsoft Hv");
+        assert_eq!(leaf(0x4000_0001).eax.to_le_bytes(), *b"Hv#1");
+
+        let privileges = HvPrivilege::from_bits_retain(leaf(0x4000_0003).eax);
+        assert!(privileges.contains(
+            HvPrivilege::HYPERCALL
+                | HvPrivilege::REFERENCE_TSC
+                | HvPrivilege::SYNIC
+                | HvPrivilege::SYNTIMER
+        ));
+        let features = HvFeature::from_bits_retain(leaf(0x4000_0003).edx);
+        assert!(features.contains(HvFeature::STIMER_DIRECT_MODE));
+        assert_eq!(leaf(0x4000_0005).eax, 4);
+        assert_eq!(cpuids.len(), 2 + 7);
+    }
+}
@@ -31,9 +31,18 @@ pub const KERNEL_CMD_LINE_LIMIT: u64 = 0x1000;
 pub const BOOT_PAGING_START: u64 = 0x3000;
 pub const BOOT_PAGING_LIMIT: u64 = 0x4000;
 
+pub const PVCLOCK_START: u64 = 0x7_d000; // size: 4KiB
+pub const HV_REF_TSC_START: u64 = 0x7_e000; // size: 4KiB
+
+pub const VAPIC_START: u64 = 0x7_f000; // 4 bytes per vCPU
+pub const VAPIC_LIMIT: u64 = 0x1000;
+
 pub const EBDA_START: u64 = 0x8_0000;
 pub const EBDA_END: u64 = 0xA_0000;
 
+pub const BIOS_ROM_START: u64 = 0xE_0000;
+pub const BIOS_ROM_END: u64 = 0x10_0000;
+
 pub const KERNEL_IMAGE_START: u64 = 0x100_0000; // 16 MiB
 
 pub const RAM_32_END: u64 = 0x8000_0000; // 2 GiB
@@ -52,6 +61,7 @@ pub const PCIE_CONFIG_START: u64 = 0xe000_0000; // 3.5 GiB
 pub const PCIE_CONFIG_END: u64 = 0xf000_0000; // 3.75 GiB, size = 256 MiB
 
 pub const IOAPIC_START: u64 = 0xfec0_0000;
+pub const HPET_START: u64 = 0xfed0_0000;
 pub const APIC_START: u64 = 0xfee0_0000;
 
 pub const MEM_64_START: u64 = 0x1_0000_0000; // 4GiB
@@ -0,0 +1,52 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::arch::msr::McgCap;
+
+const DEFAULT_BANKS: u8 = 10;
+
+/// Machine check banks exposed to the guest through `IA32_MCG_CAP` and
+/// the `IA32_MCi_{CTL,STATUS,ADDR,MISC}` MSRs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MceBanks {
+    /// Number of banks, or 0 to disable machine check emulation.
+    pub count: u8,
+}
+
+impl Default for MceBanks {
+    fn default() -> Self {
+        MceBanks {
+            count: DEFAULT_BANKS,
+        }
+    }
+}
+
+impl MceBanks {
+    pub fn mcg_cap(&self) -> McgCap {
+        McgCap::from_bits_retain(self.count as u64) | McgCap::CTL_P | McgCap::SER_P
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::arch::mce::MceBanks;
+    use crate::arch::msr::McgCap;
+
+    #[test]
+    fn test_mcg_cap() {
+        let banks = MceBanks::default();
+        assert_eq!(banks.mcg_cap().bits(), 0x100_010a);
+        assert_eq!((banks.mcg_cap() & McgCap::COUNT).bits(), banks.count as u64);
+    }
+}
@@ -38,3 +38,47 @@ bitflags! {
         const NXE = 1 << 11;
     }
 }
+
+// Intel Vol.3B, Chapter 16.3.
+pub const IA32_MCG_CAP: u32 = 0x179;
+pub const IA32_MCG_STATUS: u32 = 0x17a;
+pub const IA32_MC0_CTL: u32 = 0x400;
+
+bitflags! {
+    #[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    pub struct McgCap: u64 {
+        const COUNT = 0xff;
+        const CTL_P = 1 << 8;
+        const SER_P = 1 << 24;
+    }
+}
+
+bitflags! {
+    #[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    pub struct McgStatus: u64 {
+        /// Restart IP valid
+        const RIPV = 1 << 0;
+        /// Error IP valid
+        const EIPV = 1 << 1;
+        /// Machine check in progress
+        const MCIP = 1 << 2;
+        /// Local machine check exception signaled
+        const LMCE_S = 1 << 3;
+    }
+}
+
+bitflags! {
+    #[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    pub struct MciStatus: u64 {
+        /// Processor context corrupt
+        const PCC = 1 << 57;
+        /// Error enabled
+        const EN = 1 << 60;
+        /// Error uncorrected
+        const UC = 1 << 61;
+        /// Error overflow
+        const OVER = 1 << 62;
+        /// Register valid
+        const VAL = 1 << 63;
+    }
+}
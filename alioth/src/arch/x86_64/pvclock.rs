@@ -0,0 +1,108 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use bitflags::bitflags;
+use zerocopy::{AsBytes, FromBytes, FromZeroes};
+
+pub const MSR_KVM_WALL_CLOCK_NEW: u32 = 0x4b56_4d00;
+pub const MSR_KVM_SYSTEM_TIME_NEW: u32 = 0x4b56_4d01;
+
+pub const KVM_SYSTEM_TIME_ENABLE: u64 = 1 << 0;
+
+bitflags! {
+    /// Features in EAX of CPUID leaf 0x4000_0001.
+    #[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    pub struct KvmCpuidFeature: u32 {
+        const CLOCKSOURCE = 1 << 0;
+        const CLOCKSOURCE2 = 1 << 3;
+        const CLOCKSOURCE_STABLE_BIT = 1 << 24;
+    }
+}
+
+bitflags! {
+    #[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    pub struct PvclockFlag: u8 {
+        const TSC_STABLE = 1 << 0;
+        const GUEST_STOPPED = 1 << 1;
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Clone, FromBytes, FromZeroes, AsBytes)]
+pub struct PvclockWallClock {
+    pub version: u32,
+    pub sec: u32,
+    pub nsec: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Clone, FromBytes, FromZeroes, AsBytes)]
+pub struct PvclockVcpuTimeInfo {
+    pub version: u32,
+    pub pad0: u32,
+    pub tsc_timestamp: u64,
+    pub system_time: u64,
+    pub tsc_to_system_mul: u32,
+    pub tsc_shift: i8,
+    pub flags: u8,
+    pub pad: [u8; 2],
+}
+
+impl PvclockVcpuTimeInfo {
+    /// Converts a TSC value read by the vCPU to nanoseconds.
+    pub fn tsc_to_ns(&self, tsc: u64) -> u64 {
+        let mut delta = tsc.wrapping_sub(self.tsc_timestamp);
+        if self.tsc_shift < 0 {
+            delta >>= -self.tsc_shift;
+        } else {
+            delta <<= self.tsc_shift;
+        }
+        let scaled = (delta as u128 * self.tsc_to_system_mul as u128) >> 32;
+        self.system_time.wrapping_add(scaled as u64)
+    }
+}
+
+/// A pvclock page starts with [`PvclockWallClock`], followed by one
+/// [`PvclockVcpuTimeInfo`] per vCPU from this offset.
+pub const PVCLOCK_VCPU_OFFSET: u64 = 0x40;
+
+#[cfg(test)]
+mod test {
+    use std::mem::size_of;
+
+    use super::{PvclockVcpuTimeInfo, PvclockWallClock};
+
+    #[test]
+    fn test_pvclock_tsc_to_ns() {
+        assert_eq!(size_of::<PvclockWallClock>(), 12);
+        assert_eq!(size_of::<PvclockVcpuTimeInfo>(), 32);
+
+        // 2 GHz TSC: 0.5 ns per cycle, i.e. mul = 2^31 with shift 0.
+        let info = PvclockVcpuTimeInfo {
+            tsc_timestamp: 1_000,
+            system_time: 5_000,
+            tsc_to_system_mul: 1 << 31,
+            tsc_shift: 0,
+            ..Default::default()
+        };
+        assert_eq!(info.tsc_to_ns(1_000), 5_000);
+        assert_eq!(info.tsc_to_ns(3_000), 6_000);
+
+        let info = PvclockVcpuTimeInfo {
+            tsc_shift: -1,
+            ..info
+        };
+        assert_eq!(info.tsc_to_ns(5_000), 6_000);
+    }
+}
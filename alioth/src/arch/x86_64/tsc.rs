@@ -0,0 +1,135 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::arch::x86_64::__cpuid;
+use std::fs;
+
+use serde::Deserialize;
+
+use crate::arch::cpuid::Cpuid;
+
+const TSC_FREQ_KHZ_PATH: &str = "/sys/devices/system/cpu/cpu0/tsc_freq_khz";
+
+/// TSC frequency of the guest, in kHz, advertised through CPUID leaf 0x15
+/// with a 1 MHz crystal.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct TscConfig {
+    /// Defaults to the host TSC frequency if 0.
+    #[serde(default)]
+    pub freq_khz: u32,
+    /// Reports an invariant TSC to the guest. Otherwise the TSC-deadline
+    /// mode of the local APIC timer is hidden from the guest.
+    #[serde(default)]
+    pub invariant: bool,
+}
+
+pub fn host_tsc_khz() -> Option<u32> {
+    if let Ok(s) = fs::read_to_string(TSC_FREQ_KHZ_PATH) {
+        if let Ok(khz) = s.trim().parse() {
+            return Some(khz);
+        }
+    }
+    let max_leaf = __cpuid(0).eax;
+    if max_leaf >= 0x15 {
+        let leaf = __cpuid(0x15);
+        if leaf.eax != 0 && leaf.ebx != 0 && leaf.ecx != 0 {
+            let hz = leaf.ecx as u64 * leaf.ebx as u64 / leaf.eax as u64;
+            return Some((hz / 1000) as u32);
+        }
+    }
+    if max_leaf >= 0x16 {
+        let mhz = __cpuid(0x16).eax & 0xffff;
+        if mhz != 0 {
+            return Some(mhz * 1000);
+        }
+    }
+    None
+}
+
+fn leaf_mut(cpuids: &mut Vec<Cpuid>, func: u32) -> &mut Cpuid {
+    let pos = match cpuids.iter().position(|c| c.func == func) {
+        Some(pos) => pos,
+        None => {
+            cpuids.push(Cpuid {
+                func,
+                ..Default::default()
+            });
+            cpuids.len() - 1
+        }
+    };
+    &mut cpuids[pos]
+}
+
+pub fn set_tsc_cpuids(cpuids: &mut Vec<Cpuid>, freq_khz: u32, invariant: bool) {
+    let leaf_0 = leaf_mut(cpuids, 0x0);
+    leaf_0.eax = std::cmp::max(leaf_0.eax, 0x15);
+    let leaf_15 = leaf_mut(cpuids, 0x15);
+    leaf_15.eax = 1;
+    leaf_15.ebx = freq_khz;
+    leaf_15.ecx = 1000;
+
+    let leaf_1 = leaf_mut(cpuids, 0x1);
+    if !invariant {
+        leaf_1.ecx &= !(1 << 24);
+    }
+    let leaf_8000_0007 = leaf_mut(cpuids, 0x8000_0007);
+    if invariant {
+        leaf_8000_0007.edx |= 1 << 8;
+    } else {
+        leaf_8000_0007.edx &= !(1 << 8);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::arch::cpuid::Cpuid;
+
+    use super::set_tsc_cpuids;
+
+    #[test]
+    fn test_set_tsc_cpuids() {
+        let mut cpuids = vec![
+            Cpuid {
+                func: 0x0,
+                eax: 0xd,
+                ..Default::default()
+            },
+            Cpuid {
+                func: 0x1,
+                ecx: (1 << 24) | (1 << 31),
+                ..Default::default()
+            },
+            Cpuid {
+                func: 0x8000_0007,
+                edx: 1 << 8,
+                ..Default::default()
+            },
+        ];
+        set_tsc_cpuids(&mut cpuids, 2_100_000, false);
+        assert_eq!(cpuids[0].eax, 0x15);
+        assert_eq!(cpuids[1].ecx, 1 << 31);
+        assert_eq!(cpuids[2].edx, 0);
+        let leaf = cpuids.iter().find(|c| c.func == 0x15).unwrap();
+        // TSC frequency = ECX * EBX / EAX
+        assert_eq!(
+            leaf.ecx as u64 * leaf.ebx as u64 / leaf.eax as u64,
+            2_100_000_000
+        );
+
+        set_tsc_cpuids(&mut cpuids, 3_000_000, true);
+        assert_eq!(cpuids.len(), 4);
+        assert_eq!(cpuids[2].edx, 1 << 8);
+        assert_eq!(cpuids[3].ebx, 3_000_000);
+    }
+}
@@ -13,8 +13,12 @@
 // limitations under the License.
 
 pub mod cpuid;
+pub mod hyperv;
 pub mod layout;
+pub mod mce;
 pub mod msr;
 pub mod paging;
+pub mod pvclock;
 pub mod reg;
 pub mod sev;
+pub mod tsc;
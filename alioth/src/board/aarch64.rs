@@ -25,9 +25,10 @@ use crate::arch::layout::{
     RAM_32_START,
 };
 use crate::arch::reg::SReg;
+use crate::arch::smccc::SmcccDispatcher;
 use crate::board::{Board, BoardConfig, Result, VcpuGuard, PCIE_MMIO_64_SIZE};
 use crate::firmware::dt::{DeviceTree, Node, PropVal};
-use crate::hv::{GicV2, GicV3, Hypervisor, Its, Vcpu, Vm};
+use crate::hv::{self, GicV2, GicV3, Hypervisor, Its, Vcpu, Vm};
 use crate::loader::{ExecType, InitState};
 use crate::mem::mapped::ArcMemPages;
 use crate::mem::{MemRegion, MemRegionType};
@@ -46,6 +47,7 @@ where
 {
     gic: Gic<V>,
     mpidrs: Mutex<Vec<u64>>,
+    pub smccc: SmcccDispatcher,
 }
 
 impl<V: Vm> ArchBoard<V> {
@@ -66,7 +68,11 @@ impl<V: Vm> ArchBoard<V> {
             }
         };
         let mpidrs = Mutex::new(vec![u64::MAX; config.num_cpu as usize]);
-        Ok(ArchBoard { gic, mpidrs })
+        Ok(ArchBoard {
+            gic,
+            mpidrs,
+            smccc: SmcccDispatcher::new(),
+        })
     }
 }
 
@@ -90,6 +96,9 @@ where
 
     pub fn init_vcpu(&self, id: u32, vcpu: &mut V::Vcpu) -> Result<()> {
         vcpu.reset(id == 0)?;
+        // KVM fixes the ID registers once any vCPU has run, so they must be
+        // set here rather than in reset_vcpu().
+        vcpu.set_sregs(&self.config.id_regs.sregs())?;
         self.arch.mpidrs.lock()[id as usize] = vcpu.get_sreg(SReg::MPIDR_EL1)?;
         Ok(())
     }
@@ -144,6 +153,16 @@ where
                 its.init()?;
             }
         };
+        for func_id in self.arch.smccc.func_ids() {
+            match self.vm.forward_smccc(func_id) {
+                Ok(()) => {}
+                Err(e @ hv::Error::Unsupported { .. }) => {
+                    log::warn!("{e}, SMCCC services of the VMM are not available");
+                    break;
+                }
+                Err(e) => Err(e)?,
+            }
+        }
         Ok(())
     }
 
@@ -17,22 +17,36 @@ mod aarch64;
 #[cfg(target_arch = "x86_64")]
 mod x86_64;
 
+use std::collections::BTreeMap;
 use std::sync::atomic::{AtomicU8, Ordering};
 use std::sync::mpsc::{Receiver, Sender};
 use std::sync::Arc;
-use std::thread::JoinHandle;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 
 use parking_lot::{Condvar, Mutex, RwLock, RwLockReadGuard};
 use snafu::{ResultExt, Snafu};
 
+#[cfg(target_arch = "x86_64")]
+use crate::arch::cpuid::CpuidOverride;
+#[cfg(target_arch = "x86_64")]
+use crate::arch::hyperv::HypervEnlightenments;
 use crate::arch::layout::{
     MEM_64_START, PCIE_CONFIG_START, PCIE_MMIO_32_NON_PREFETCHABLE_END,
     PCIE_MMIO_32_NON_PREFETCHABLE_START, PCIE_MMIO_32_PREFETCHABLE_END,
     PCIE_MMIO_32_PREFETCHABLE_START, RAM_32_SIZE,
 };
+#[cfg(target_arch = "x86_64")]
+use crate::arch::mce::MceBanks;
+#[cfg(target_arch = "aarch64")]
+use crate::arch::reg::IdRegs;
+#[cfg(target_arch = "x86_64")]
+use crate::arch::tsc::TscConfig;
+#[cfg(target_os = "linux")]
+use crate::cgroup::{Cgroup, CgroupConfig};
 use crate::device::fw_cfg::FwCfg;
 use crate::errors::{trace_error, DebugTrace};
-use crate::hv::{Coco, Vcpu, Vm, VmEntry, VmExit};
+use crate::hv::{Coco, Vcpu, VcpuStats, VcpuStatsSnapshot, Vm, VmEntry, VmExit};
 #[cfg(target_arch = "x86_64")]
 use crate::loader::xen;
 use crate::loader::{firmware, linux, ExecType, InitState, Payload};
@@ -41,7 +55,12 @@ use crate::mem::{MemRegion, MemRegionType, Memory};
 use crate::pci::bus::PciBus;
 #[cfg(target_arch = "x86_64")]
 use crate::pci::bus::CONFIG_ADDRESS;
+use crate::pci::hotplug::VmBus;
+#[cfg(target_arch = "x86_64")]
+use crate::pci::hotplug::PCI_HOTPLUG_PORT;
 use crate::pci::Bdf;
+#[cfg(target_os = "linux")]
+use crate::vfio::container::VfioContainer;
 
 #[cfg(target_arch = "aarch64")]
 pub(crate) use aarch64::ArchBoard;
@@ -82,6 +101,21 @@ pub enum Error {
     VmExit { msg: String },
     #[snafu(display("Failed to configure firmware"))]
     Firmware { error: std::io::Error },
+    #[cfg(target_arch = "x86_64")]
+    #[snafu(display("Failed to create HPET"))]
+    CreateHpet { error: std::io::Error },
+    #[cfg(target_arch = "x86_64")]
+    #[snafu(display("Cannot determine the TSC frequency of the host"))]
+    HostTscFreq,
+    #[cfg(target_os = "linux")]
+    #[snafu(display("Failed to set up VFIO DMA mappings"), context(false))]
+    Vfio { source: Box<crate::vfio::Error> },
+    #[cfg(target_os = "linux")]
+    #[snafu(display("Failed to move VCPU-{id} into the cgroup"))]
+    CgroupVcpu {
+        id: u32,
+        source: Box<crate::cgroup::Error>,
+    },
 }
 
 type Result<T, E = Error> = std::result::Result<T, E>;
@@ -93,10 +127,78 @@ pub const STATE_REBOOT_PENDING: u8 = 3;
 
 pub const PCIE_MMIO_64_SIZE: u64 = 1 << 40;
 
+/// Exits per second of a single vCPU above which the exit rate is logged
+/// as a likely exit storm, e.g. a guest polling a misconfigured device.
+const EXIT_STORM_RATE: u64 = 200_000;
+/// Number of exits between two checks of the exit rate.
+const EXIT_RATE_CHECK_INTERVAL: u64 = 4096;
+
+struct ExitRateMonitor {
+    stats: Arc<VcpuStats>,
+    start: Instant,
+    base: VcpuStatsSnapshot,
+    exits: u64,
+}
+
+impl ExitRateMonitor {
+    fn new(stats: Arc<VcpuStats>) -> Self {
+        let base = stats.snapshot();
+        ExitRateMonitor {
+            stats,
+            start: Instant::now(),
+            base,
+            exits: 0,
+        }
+    }
+
+    fn tick(&mut self, id: u32) {
+        self.exits += 1;
+        if self.exits < EXIT_RATE_CHECK_INTERVAL {
+            return;
+        }
+        self.exits = 0;
+        let elapsed = self.start.elapsed();
+        if elapsed < Duration::from_secs(1) {
+            return;
+        }
+        let current = self.stats.snapshot();
+        let delta = current - self.base;
+        let rate = delta.total() as u128 * 1000 / elapsed.as_millis();
+        if rate > EXIT_STORM_RATE as u128 {
+            log::warn!("vcpu {id}: {rate} exits per second: {delta:?}");
+        }
+        self.start = Instant::now();
+        self.base = current;
+    }
+}
+
+#[derive(Default)]
 pub struct BoardConfig {
     pub mem_size: u64,
     pub num_cpu: u32,
     pub coco: Option<Coco>,
+    /// Size of the bounce buffer pool of each virtio device, used when
+    /// the guest memory is restricted.
+    pub bounce_buffer_size: Option<u64>,
+    /// Logs accesses to the MMIO registers of virtio devices.
+    pub trace_mmio: bool,
+    #[cfg(target_arch = "x86_64")]
+    pub cpuid_overrides: Vec<CpuidOverride>,
+    #[cfg(target_arch = "x86_64")]
+    pub tsc: Option<TscConfig>,
+    #[cfg(target_arch = "x86_64")]
+    pub hyperv: Option<HypervEnlightenments>,
+    #[cfg(target_arch = "x86_64")]
+    pub mce_banks: MceBanks,
+    #[cfg(target_arch = "aarch64")]
+    pub id_regs: IdRegs,
+    /// Limits the resources of the VMM process.
+    #[cfg(target_os = "linux")]
+    pub cgroup: Option<CgroupConfig>,
+    /// Preempts a vCPU each time it uses this many milliseconds of CPU
+    /// time.
+    #[cfg(target_os = "linux")]
+    pub vcpu_quantum_ms: Option<u64>,
 }
 
 impl BoardConfig {
@@ -124,7 +226,13 @@ where
     #[cfg(target_arch = "aarch64")]
     pub mmio_devs: RwLock<Vec<(u64, Arc<MemRegion>)>>,
     pub pci_bus: PciBus,
+    pub vm_bus: VmBus,
     pub fw_cfg: Mutex<Option<Arc<Mutex<FwCfg>>>>,
+    pub vcpu_stats: RwLock<BTreeMap<u32, Arc<VcpuStats>>>,
+    #[cfg(target_os = "linux")]
+    pub vfio_container: Mutex<Option<Arc<VfioContainer>>>,
+    #[cfg(target_os = "linux")]
+    pub cgroup: Option<Cgroup>,
 }
 
 impl<V> Board<V>
@@ -154,10 +262,14 @@ where
                 payload.initramfs.as_ref(),
             )?,
             ExecType::Firmware => {
-                let (init_state, mut rom) = firmware::load(&self.memory, &payload.executable)?;
+                let vars = payload.firmware_vars.as_deref();
+                let (init_state, mut rom) =
+                    firmware::load(&self.memory, &payload.executable, vars)?;
                 self.setup_firmware(&mut rom)?;
                 init_state
             }
+            #[cfg(target_arch = "x86_64")]
+            ExecType::Bios => firmware::load_bios(&self.memory, &payload.executable)?.0,
         };
         Ok(init_state)
     }
@@ -166,6 +278,9 @@ where
         #[cfg(target_arch = "x86_64")]
         self.memory
             .add_io_dev(CONFIG_ADDRESS, self.pci_bus.io_bus.clone())?;
+        #[cfg(target_arch = "x86_64")]
+        self.memory
+            .add_io_dev(PCI_HOTPLUG_PORT, self.vm_bus.slots.clone())?;
         self.memory.add_region(
             PCIE_CONFIG_START,
             Arc::new(MemRegion::with_emulated(
@@ -191,11 +306,17 @@ where
 
     fn vcpu_loop(&self, vcpu: &mut <V as Vm>::Vcpu, id: u32) -> Result<bool, Error> {
         let mut vm_entry = VmEntry::None;
+        let mut exit_rate = ExitRateMonitor::new(vcpu.stats());
         loop {
             let vm_exit = vcpu.run(vm_entry).context(error::RunVcpu { id })?;
+            exit_rate.tick(id);
             vm_entry = match vm_exit {
                 VmExit::Io { port, write, size } => self.memory.handle_io(port, write, size)?,
                 VmExit::Mmio { addr, write, size } => self.memory.handle_mmio(addr, write, size)?,
+                #[cfg(target_arch = "aarch64")]
+                VmExit::Smccc { func_id, args } => VmEntry::Smccc {
+                    ret: self.arch.smccc.dispatch(func_id, &args),
+                },
                 VmExit::Shutdown => {
                     log::info!("vcpu {id} requested shutdown");
                     break Ok(false);
@@ -208,7 +329,11 @@ where
                     match state {
                         STATE_SHUTDOWN => VmEntry::Shutdown,
                         STATE_REBOOT_PENDING => VmEntry::Reboot,
-                        _ => VmEntry::None,
+                        _ => {
+                            // The vCPU might be preempted, give up the CPU.
+                            thread::yield_now();
+                            VmEntry::None
+                        }
                     }
                 }
                 VmExit::ConvertMemory { gpa, size, private } => {
@@ -240,7 +365,13 @@ where
         event_tx: &Sender<u32>,
         boot_rx: &Receiver<()>,
     ) -> Result<(), Error> {
+        #[cfg(target_os = "linux")]
+        if let Some(cgroup) = &self.cgroup {
+            let tid = unsafe { libc::gettid() };
+            cgroup.add_thread(tid).context(error::CgroupVcpu { id })?;
+        }
         let mut vcpu = self.vm.create_vcpu(id).context(error::CreateVcpu { id })?;
+        self.vcpu_stats.write().insert(id, vcpu.stats());
         event_tx.send(id).unwrap();
         self.init_vcpu(id, &mut vcpu)?;
         boot_rx.recv().unwrap();
@@ -252,6 +383,10 @@ where
             self.coco_init(id)?;
             if id == 0 {
                 self.create_ram()?;
+                #[cfg(target_os = "linux")]
+                if let Some(container) = &*self.vfio_container.lock() {
+                    container.map_ram(&self.memory.ram_bus())?;
+                }
                 for (port, dev) in self.io_devs.read().iter() {
                     self.memory.add_io_dev(*port, dev.clone())?;
                 }
@@ -299,10 +434,23 @@ where
             self.sync_vcpus(&vcpus);
 
             if id == 0 {
-                let devices = self.pci_bus.segment.devices.read();
-                for (bdf, dev) in devices.iter() {
-                    dev.dev.reset().context(error::ResetPci { bdf: *bdf })?;
-                    dev.dev.config().reset();
+                // Resetting a device may add or remove devices, e.g. SR-IOV
+                // VFs, so the bus is not locked meanwhile.
+                let devices: Vec<_> = self
+                    .pci_bus
+                    .segment
+                    .devices
+                    .read()
+                    .iter()
+                    .map(|(bdf, dev)| (*bdf, dev.dev.clone()))
+                    .collect();
+                for (bdf, dev) in devices {
+                    dev.reset().context(error::ResetPci { bdf })?;
+                    dev.config().reset();
+                }
+                #[cfg(target_os = "linux")]
+                if let Some(container) = &*self.vfio_container.lock() {
+                    container.unmap_all()?;
                 }
                 self.memory.reset()?;
             }
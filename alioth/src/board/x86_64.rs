@@ -14,40 +14,65 @@
 
 use std::arch::x86_64::__cpuid;
 use std::iter::zip;
-use std::marker::PhantomData;
 use std::mem::{offset_of, size_of, size_of_val};
 use std::sync::atomic::{AtomicU32, Ordering};
 use std::sync::Arc;
 
 use parking_lot::Mutex;
-use snafu::ResultExt;
+use snafu::{OptionExt, ResultExt};
 use zerocopy::{AsBytes, FromBytes, FromZeroes};
 
-use crate::arch::cpuid::Cpuid;
-use crate::arch::layout::{BIOS_DATA_END, EBDA_END, EBDA_START, MEM_64_START, RAM_32_SIZE};
+use crate::align_up;
+use crate::arch::cpuid::{apply_cpuid_overrides, Cpuid};
+use crate::arch::hyperv::{
+    set_hyperv_cpuids, HV_X64_MSR_GUEST_OS_ID, HV_X64_MSR_HYPERCALL, HV_X64_MSR_REFERENCE_TSC,
+    HV_X64_MSR_REFERENCE_TSC_ENABLE,
+};
+use crate::arch::layout::{
+    BIOS_DATA_END, EBDA_END, EBDA_START, HPET_START, HV_REF_TSC_START, MEM_64_START, PAGE_SIZE,
+    PVCLOCK_START, RAM_32_SIZE, VAPIC_LIMIT, VAPIC_START,
+};
+use crate::arch::msr::IA32_MCG_STATUS;
+use crate::arch::pvclock::{
+    KvmCpuidFeature, PvclockVcpuTimeInfo, KVM_SYSTEM_TIME_ENABLE, MSR_KVM_SYSTEM_TIME_NEW,
+    MSR_KVM_WALL_CLOCK_NEW, PVCLOCK_VCPU_OFFSET,
+};
 use crate::arch::reg::{Reg, SegAccess, SegReg, SegRegVal};
 use crate::arch::sev::SnpPageType;
+use crate::arch::tsc::{host_tsc_khz, set_tsc_cpuids};
 use crate::board::{error, Board, BoardConfig, Result, VcpuGuard, PCIE_MMIO_64_SIZE};
+use crate::device::hpet::{Hpet, HPET_GSI, HPET_RTC_GSI};
 use crate::firmware::acpi::bindings::{
     AcpiTableFadt, AcpiTableHeader, AcpiTableRsdp, AcpiTableXsdt,
 };
 use crate::firmware::acpi::{
-    create_fadt, create_madt, create_mcfg, create_rsdp, create_xsdt, AcpiTable,
+    create_fadt, create_hpet, create_madt, create_mcfg, create_rsdp, create_xsdt, AcpiTable,
 };
 use crate::hv::{Coco, Hypervisor, Vcpu, Vm};
 use crate::loader::InitState;
 use crate::mem::mapped::ArcMemPages;
 use crate::mem::{MemRange, MemRegion, MemRegionEntry, MemRegionType};
+use crate::migration::ClockData;
 use crate::utils::wrapping_sum;
 
-pub struct ArchBoard<V> {
+pub struct ArchBoard<V>
+where
+    V: Vm,
+{
     cpuids: Vec<Cpuid>,
+    tsc_khz: Option<u32>,
     sev_ap_eip: AtomicU32,
-    _phantom: PhantomData<V>,
+    hpet: Arc<Hpet>,
+    /// Shared by the RTC and timer 1 of the HPET, which replaces the RTC
+    /// in legacy replacement mode.
+    rtc_irq: Arc<V::IrqSender>,
+    /// Shared by the PIT and timer 0 of the HPET, which replaces the PIT
+    /// in legacy replacement mode.
+    timer_irq: Arc<V::IrqSender>,
 }
 
 impl<V: Vm> ArchBoard<V> {
-    pub fn new<H>(hv: &H, _vm: &V, config: &BoardConfig) -> Result<Self>
+    pub fn new<H>(hv: &H, vm: &V, config: &BoardConfig) -> Result<Self>
     where
         H: Hypervisor<Vm = V>,
     {
@@ -55,6 +80,8 @@ impl<V: Vm> ArchBoard<V> {
         for cpuid in &mut cpuids {
             if cpuid.func == 0x1 {
                 cpuid.ecx |= (1 << 24) | (1 << 31);
+            } else if cpuid.func == 0x4000_0001 && config.coco.is_none() {
+                cpuid.eax |= (KvmCpuidFeature::CLOCKSOURCE | KvmCpuidFeature::CLOCKSOURCE2).bits();
             } else if cpuid.func == 0x8000_001f {
                 // AMD Volume 3, section E.4.17.
                 if matches!(
@@ -74,12 +101,38 @@ impl<V: Vm> ArchBoard<V> {
                 }
             }
         }
+        let tsc_khz = match &config.tsc {
+            None => None,
+            Some(tsc) => {
+                let khz = match tsc.freq_khz {
+                    0 => host_tsc_khz().context(error::HostTscFreq)?,
+                    khz => khz,
+                };
+                set_tsc_cpuids(&mut cpuids, khz, tsc.invariant);
+                Some(khz)
+            }
+        };
+        apply_cpuid_overrides(&mut cpuids, &config.cpuid_overrides);
+        let rtc_irq = Arc::new(vm.create_irq_sender(HPET_RTC_GSI)?);
+        let timer_irq = Arc::new(vm.create_irq_sender(HPET_GSI)?);
+        let hpet = Hpet::new(timer_irq.clone(), rtc_irq.clone()).context(error::CreateHpet)?;
         Ok(Self {
             cpuids,
+            tsc_khz,
             sev_ap_eip: AtomicU32::new(0),
-            _phantom: PhantomData,
+            hpet: Arc::new(hpet),
+            rtc_irq,
+            timer_irq,
         })
     }
+
+    pub fn rtc_irq_sender(&self) -> Arc<V::IrqSender> {
+        self.rtc_irq.clone()
+    }
+
+    pub fn timer_irq_sender(&self) -> Arc<V::IrqSender> {
+        self.timer_irq.clone()
+    }
 }
 
 impl<V> Board<V>
@@ -189,7 +242,8 @@ where
         match &self.config.coco {
             Some(Coco::AmdSev { policy }) if policy.es() => {}
             Some(Coco::AmdSnp { .. }) => {}
-            _ => return Ok(()),
+            Some(_) => return Ok(()),
+            None => return self.init_paravirt(id, vcpu, vcpus),
         }
         self.sync_vcpus(vcpus);
         if id == 0 {
@@ -213,6 +267,28 @@ where
         Ok(())
     }
 
+    fn init_paravirt(&self, id: u32, vcpu: &mut V::Vcpu, vcpus: &VcpuGuard) -> Result<()> {
+        // The vAPIC area and the pvclock page must be backed by guest RAM,
+        // which is created by vCPU 0.
+        self.sync_vcpus(vcpus);
+        let offset = id as u64 * size_of::<u32>() as u64;
+        if offset < VAPIC_LIMIT && vcpu.set_vapic_addr(VAPIC_START + offset)? {
+            log::debug!("vcpu-{id}: vAPIC enabled at {:#x}", VAPIC_START + offset);
+        }
+
+        let mut msrs = vec![];
+        if id == 0 {
+            msrs.push((MSR_KVM_WALL_CLOCK_NEW, PVCLOCK_START));
+        }
+        let offset = PVCLOCK_VCPU_OFFSET + id as u64 * size_of::<PvclockVcpuTimeInfo>() as u64;
+        if offset < PAGE_SIZE {
+            let system_time = (PVCLOCK_START + offset) | KVM_SYSTEM_TIME_ENABLE;
+            msrs.push((MSR_KVM_SYSTEM_TIME_NEW, system_time));
+        }
+        vcpu.set_msrs(&msrs)?;
+        Ok(())
+    }
+
     pub fn init_boot_vcpu(&self, vcpu: &mut V::Vcpu, init_state: &InitState) -> Result<()> {
         vcpu.set_sregs(&init_state.sregs, &init_state.seg_regs, &init_state.dt_regs)?;
         vcpu.set_regs(&init_state.regs)?;
@@ -229,11 +305,67 @@ where
                 cpuid.edx = id;
             }
         }
+        let hyperv = match &self.config.hyperv {
+            Some(hv) if self.config.coco.is_none() => {
+                let enabled = vcpu.enable_hyperv(hv)?;
+                if enabled != *hv {
+                    log::warn!("vcpu-{id}: Hyper-V enlightenments {hv:?} reduced to {enabled:?}");
+                }
+                Some(enabled).filter(|e| !e.is_empty())
+            }
+            _ => None,
+        };
+        if let Some(hv) = &hyperv {
+            set_hyperv_cpuids(&mut cpuids, hv, self.config.num_cpu);
+        }
         vcpu.set_cpuids(cpuids)?;
+        if id == 0 && self.config.coco.is_none() {
+            // The guest clock starts when the VM boots.
+            self.vm.set_clock(0)?;
+        }
+        if let Some(hv) = hyperv.filter(|_| id == 0) {
+            // Partition-wide MSRs. The guest re-enables the hypercall page
+            // after it sets its OS ID.
+            let mut msrs = vec![(HV_X64_MSR_GUEST_OS_ID, 0), (HV_X64_MSR_HYPERCALL, 0)];
+            if hv.time {
+                let ref_tsc = HV_REF_TSC_START | HV_X64_MSR_REFERENCE_TSC_ENABLE;
+                msrs.push((HV_X64_MSR_REFERENCE_TSC, ref_tsc));
+            }
+            vcpu.set_msrs(&msrs)?;
+        }
+        if let Some(khz) = self.arch.tsc_khz {
+            if !vcpu.set_tsc_khz(khz)? {
+                log::warn!("vcpu-{id}: cannot set the TSC frequency to {khz} kHz");
+            }
+        }
+        let mce_banks = self.config.mce_banks;
+        if mce_banks.count > 0 {
+            let enabled = vcpu.setup_mce(mce_banks)?;
+            if enabled != mce_banks {
+                log::warn!("vcpu-{id}: machine check banks {mce_banks:?} reduced to {enabled:?}");
+            }
+        }
         Ok(())
     }
 
-    pub fn reset_vcpu(&self, _id: u32, _vcpu: &mut V::Vcpu) -> Result<()> {
+    pub fn reset_vcpu(&self, _id: u32, vcpu: &mut V::Vcpu) -> Result<()> {
+        if self.config.coco.is_none() {
+            // A machine check in progress does not survive a reset.
+            vcpu.set_msrs(&[(IA32_MCG_STATUS, 0)])?;
+        }
+        Ok(())
+    }
+
+    /// Reads the guest clock for a snapshot.
+    pub fn save_clock(&self) -> Result<ClockData> {
+        let clock = self.vm.get_clock()?;
+        Ok(ClockData { clock })
+    }
+
+    /// Restores the guest clock from a snapshot. Must be called before any
+    /// vCPU enters the guest.
+    pub fn restore_clock(&self, clock: &ClockData) -> Result<()> {
+        self.vm.set_clock(clock.clock)?;
         Ok(())
     }
 
@@ -252,9 +384,13 @@ where
                     type_: MemRegionType::Reserved,
                 },
                 MemRegionEntry {
-                    size: EBDA_START - BIOS_DATA_END,
+                    size: PVCLOCK_START - BIOS_DATA_END,
                     type_: MemRegionType::Ram,
                 },
+                MemRegionEntry {
+                    size: EBDA_START - PVCLOCK_START,
+                    type_: MemRegionType::Reserved,
+                },
                 MemRegionEntry {
                     size: EBDA_END - EBDA_START,
                     type_: MemRegionType::Acpi,
@@ -265,6 +401,7 @@ where
                 },
             ],
             callbacks: Mutex::new(vec![]),
+            dirty_callbacks: Mutex::new(vec![]),
         };
         memory.add_region(0, Arc::new(region_low))?;
         if let Some(coco) = &self.config.coco {
@@ -285,6 +422,9 @@ where
                 }
             }
         }
+        self.arch.hpet.reset();
+        let hpet = MemRegion::with_emulated(self.arch.hpet.clone(), MemRegionType::Reserved);
+        memory.add_region(HPET_START, Arc::new(hpet))?;
         Ok(())
     }
 
@@ -340,7 +480,7 @@ where
         let mut pointers = vec![];
         let mut checksums = vec![];
 
-        let mut xsdt: AcpiTableXsdt<3> = FromZeroes::new_zeroed();
+        let mut xsdt: AcpiTableXsdt<4> = FromZeroes::new_zeroed();
         let offset_xsdt = 0;
         table_bytes.extend(xsdt.as_bytes());
 
@@ -359,24 +499,35 @@ where
 
         let offset_madt = offset_fadt + size_of_val(&fadt);
         debug_assert_eq!(offset_madt % 4, 0);
-        let (madt, madt_ioapic, madt_apics) = create_madt(self.config.num_cpu);
+        let (madt, madt_ioapic, madt_override, madt_apics) = create_madt(self.config.num_cpu);
         table_bytes.extend(madt.as_bytes());
         table_bytes.extend(madt_ioapic.as_bytes());
+        table_bytes.extend(madt_override.as_bytes());
         for apic in madt_apics {
             table_bytes.extend(apic.as_bytes());
         }
 
-        let offset_mcfg = offset_madt + madt.header.length as usize;
-        debug_assert_eq!(offset_mcfg % 4, 0);
+        let offset_mcfg = align_up!(offset_madt + madt.header.length as usize, 4);
+        table_bytes.resize(offset_mcfg, 0);
         let mcfg = create_mcfg();
         table_bytes.extend(mcfg.as_bytes());
 
+        let offset_hpet = offset_mcfg + size_of_val(&mcfg);
+        debug_assert_eq!(offset_hpet % 4, 0);
+        let hpet = create_hpet();
+        table_bytes.extend(hpet.as_bytes());
+
         debug_assert_eq!(offset_xsdt % 4, 0);
-        let xsdt_entries = [offset_fadt as u64, offset_madt as u64, offset_mcfg as u64];
+        let xsdt_entries = [
+            offset_fadt as u64,
+            offset_madt as u64,
+            offset_mcfg as u64,
+            offset_hpet as u64,
+        ];
         xsdt = create_xsdt(xsdt_entries);
         xsdt.write_to_prefix(&mut table_bytes);
         for index in 0..xsdt_entries.len() {
-            pointers.push(offset_xsdt + offset_of!(AcpiTableXsdt<3>, entries) + index * 8);
+            pointers.push(offset_xsdt + offset_of!(AcpiTableXsdt<4>, entries) + index * 8);
         }
         checksums.push((offset_xsdt, size_of_val(&xsdt)));
 
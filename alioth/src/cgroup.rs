@@ -0,0 +1,195 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Resource limits of a VM through a cgroup v2 directory.
+//!
+//! Memory is charged to processes rather than threads, so the whole VMM
+//! process joins the cgroup, and threads spawned later, like vCPUs and
+//! device workers, stay in it.
+
+use std::fmt::Display;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+use snafu::{OptionExt, ResultExt, Snafu};
+
+use crate::errors::{trace_error, DebugTrace};
+
+/// Period of `cpu.max`, in microseconds.
+pub const CPU_PERIOD_US: u64 = 100_000;
+
+#[trace_error]
+#[derive(Snafu, DebugTrace)]
+#[snafu(module, context(suffix(false)))]
+pub enum Error {
+    #[snafu(display("Failed to create cgroup {path:?}"))]
+    CreateDir {
+        path: PathBuf,
+        error: std::io::Error,
+    },
+    #[snafu(display("Failed to write {path:?}"))]
+    WriteFile {
+        path: PathBuf,
+        error: std::io::Error,
+    },
+    #[snafu(display("Failed to read {path:?}"))]
+    ReadFile {
+        path: PathBuf,
+        error: std::io::Error,
+    },
+    #[snafu(display("Cannot find the cgroup v2 hierarchy of the process"))]
+    NoHierarchy,
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+pub struct CgroupConfig {
+    /// Path of the cgroup to create, e.g. `/sys/fs/cgroup/alioth/vm0`.
+    pub path: PathBuf,
+    /// CPU time the VM can use in every [`CPU_PERIOD_US`], or 0 for no
+    /// limit.
+    #[serde(default)]
+    pub cpu_quota_us: u64,
+    /// Memory the VM can use, or 0 for no limit.
+    #[serde(default)]
+    pub memory_limit_bytes: u64,
+}
+
+impl CgroupConfig {
+    fn cpu_max(&self) -> String {
+        match self.cpu_quota_us {
+            0 => format!("max {CPU_PERIOD_US}"),
+            quota => format!("{quota} {CPU_PERIOD_US}"),
+        }
+    }
+
+    fn memory_max(&self) -> String {
+        match self.memory_limit_bytes {
+            0 => "max".to_owned(),
+            limit => limit.to_string(),
+        }
+    }
+}
+
+/// A cgroup holding the VMM process, removed on drop.
+#[derive(Debug)]
+pub struct Cgroup {
+    path: PathBuf,
+    /// The cgroup the process is moved back to before removing `path`.
+    origin: PathBuf,
+}
+
+impl Cgroup {
+    pub fn new(config: &CgroupConfig) -> Result<Self> {
+        let origin = current_cgroup()?;
+        let path = &config.path;
+        fs::create_dir_all(path).context(error::CreateDir { path })?;
+        let cgroup = Cgroup {
+            path: path.clone(),
+            origin,
+        };
+        cgroup.write("cpu.max", config.cpu_max())?;
+        cgroup.write("memory.max", config.memory_max())?;
+        cgroup.write("cgroup.procs", std::process::id())?;
+        log::info!("joined cgroup {path:?}");
+        Ok(cgroup)
+    }
+
+    /// Moves the thread of `tid` into the cgroup.
+    pub fn add_thread(&self, tid: i32) -> Result<()> {
+        self.write("cgroup.threads", tid)
+    }
+
+    fn write(&self, name: &str, val: impl Display) -> Result<()> {
+        write_file(&self.path.join(name), val)
+    }
+}
+
+impl Drop for Cgroup {
+    fn drop(&mut self) {
+        let procs = self.origin.join("cgroup.procs");
+        if let Err(e) = write_file(&procs, std::process::id()) {
+            log::error!("{e}");
+        }
+        if let Err(e) = fs::remove_dir(&self.path) {
+            log::error!("Failed to remove cgroup {:?}: {e}", self.path);
+        }
+    }
+}
+
+fn write_file(path: &Path, val: impl Display) -> Result<()> {
+    fs::write(path, val.to_string()).context(error::WriteFile { path })
+}
+
+fn read_file(path: &str) -> Result<String> {
+    fs::read_to_string(path).context(error::ReadFile { path })
+}
+
+/// Returns the mount point of the cgroup v2 hierarchy from the content of
+/// `/proc/self/mountinfo`.
+fn cgroup2_mount(mountinfo: &str) -> Option<&str> {
+    mountinfo.lines().find_map(|line| {
+        let (mount, fs) = line.split_once(" - ")?;
+        fs.starts_with("cgroup2 ")
+            .then(|| mount.split(' ').nth(4))?
+    })
+}
+
+/// Returns the cgroup v2 path from the content of `/proc/self/cgroup`.
+fn cgroup2_path(cgroup: &str) -> Option<&str> {
+    cgroup.lines().find_map(|line| line.strip_prefix("0::"))
+}
+
+fn current_cgroup() -> Result<PathBuf> {
+    let mountinfo = read_file("/proc/self/mountinfo")?;
+    let mount = cgroup2_mount(&mountinfo).context(error::NoHierarchy)?;
+    let cgroup = read_file("/proc/self/cgroup")?;
+    let path = cgroup2_path(&cgroup).context(error::NoHierarchy)?;
+    Ok(Path::new(mount).join(path.trim_start_matches('/')))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::cgroup::{cgroup2_mount, cgroup2_path, CgroupConfig};
+
+    #[test]
+    fn test_cgroup_limits() {
+        let config = CgroupConfig {
+            path: "/sys/fs/cgroup/alioth".into(),
+            cpu_quota_us: 50_000,
+            memory_limit_bytes: 1 << 30,
+        };
+        assert_eq!(config.cpu_max(), "50000 100000");
+        assert_eq!(config.memory_max(), "1073741824");
+
+        let unlimited = CgroupConfig::default();
+        assert_eq!(unlimited.cpu_max(), "max 100000");
+        assert_eq!(unlimited.memory_max(), "max");
+    }
+
+    #[test]
+    fn test_current_cgroup() {
+        let mountinfo = "\
+            24 30 0:22 / /sys rw,nosuid,nodev,noexec,relatime shared:7 - sysfs sysfs rw\n\
+            34 24 0:29 / /sys/fs/cgroup rw,nosuid,nodev,noexec,relatime shared:9 - cgroup2 cgroup2 rw\n";
+        assert_eq!(cgroup2_mount(mountinfo), Some("/sys/fs/cgroup"));
+        assert_eq!(cgroup2_mount("24 30 0:22 / /sys rw - sysfs sysfs rw"), None);
+
+        let cgroup = "4:memory:/vm\n0::/user.slice/alioth.scope\n";
+        assert_eq!(cgroup2_path(cgroup), Some("/user.slice/alioth.scope"));
+        assert_eq!(cgroup2_path("4:memory:/vm\n"), None);
+    }
+}
@@ -0,0 +1,135 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::fmt::Debug;
+use std::io::Write;
+
+use parking_lot::Mutex;
+
+use crate::mem;
+use crate::mem::emulated::{Action, Mmio};
+
+/// The debug port of SeaBIOS and OVMF.
+pub const DEBUGCON_PORT: u16 = 0x402;
+/// The debug port of Bochs.
+pub const BOCHS_DEBUGCON_PORT: u16 = 0xe9;
+
+/// Reading the port returns this value, with which firmware detects the
+/// debug console.
+const DEBUGCON_READBACK: u64 = 0xe9;
+
+struct DebugConInner {
+    output: Box<dyn Write + Send>,
+    line: Vec<u8>,
+}
+
+/// A write-only port that firmware prints debug messages to.
+///
+/// Bytes go to `output` as they are written, and every complete line is
+/// also logged at debug level.
+pub struct DebugCon {
+    name: String,
+    inner: Mutex<DebugConInner>,
+}
+
+impl Debug for DebugCon {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DebugCon")
+            .field("name", &self.name)
+            .finish()
+    }
+}
+
+impl DebugCon {
+    pub fn new(name: String, output: Box<dyn Write + Send>) -> Self {
+        DebugCon {
+            name,
+            inner: Mutex::new(DebugConInner {
+                output,
+                line: Vec::new(),
+            }),
+        }
+    }
+}
+
+impl Mmio for DebugCon {
+    fn size(&self) -> u64 {
+        1
+    }
+
+    fn read(&self, _offset: u64, _size: u8) -> mem::Result<u64> {
+        Ok(DEBUGCON_READBACK)
+    }
+
+    fn write(&self, _offset: u64, _size: u8, val: u64) -> mem::Result<Action> {
+        let byte = val as u8;
+        let inner = &mut *self.inner.lock();
+        if let Err(e) = inner.output.write_all(&[byte]) {
+            log::error!("{}: failed to write output: {e}", self.name);
+        }
+        match byte {
+            b'\n' => {
+                let line = String::from_utf8_lossy(&inner.line);
+                log::debug!("{}: {}", self.name, line.trim_end_matches('\r'));
+                inner.line.clear();
+                if let Err(e) = inner.output.flush() {
+                    log::error!("{}: failed to flush output: {e}", self.name);
+                }
+            }
+            _ => inner.line.push(byte),
+        }
+        Ok(Action::None)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{self, Write};
+    use std::sync::Arc;
+
+    use parking_lot::Mutex;
+
+    use crate::device::debugcon::DebugCon;
+    use crate::mem::emulated::Mmio;
+
+    #[derive(Clone, Default)]
+    struct Output(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Output {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_debugcon() {
+        let output = Output::default();
+        let debugcon = DebugCon::new("debugcon".to_owned(), Box::new(output.clone()));
+        assert_eq!(debugcon.read(0, 1).unwrap(), 0xe9);
+
+        for byte in b"BdsDxe: loading Boot0001\r\nSecCoreStartupWithStack" {
+            debugcon.write(0, 1, *byte as u64).unwrap();
+        }
+        assert_eq!(
+            output.0.lock().as_slice(),
+            b"BdsDxe: loading Boot0001\r\nSecCoreStartupWithStack"
+        );
+        assert_eq!(debugcon.inner.lock().line, b"SecCoreStartupWithStack");
+    }
+}
@@ -13,10 +13,21 @@
 // limitations under the License.
 
 pub mod console;
+#[cfg(target_arch = "x86_64")]
+pub mod debugcon;
 #[path = "fw_cfg/fw_cfg.rs"]
 pub mod fw_cfg;
+#[cfg(target_arch = "x86_64")]
+pub mod hpet;
+#[cfg(target_os = "linux")]
+pub mod ivshmem;
+#[cfg(target_arch = "x86_64")]
+pub mod pit;
 #[cfg(target_arch = "aarch64")]
 pub mod pl011;
 pub mod pvpanic;
 #[cfg(target_arch = "x86_64")]
+pub mod rtc;
+#[cfg(target_arch = "x86_64")]
 pub mod serial;
+pub mod xhci;
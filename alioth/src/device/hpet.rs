@@ -0,0 +1,593 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::io::{ErrorKind, Result};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::ptr::null_mut;
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use bitfield::bitfield;
+use libc::{
+    itimerspec, timerfd_create, timerfd_settime, timespec, CLOCK_MONOTONIC, TFD_CLOEXEC,
+    TFD_NONBLOCK,
+};
+use mio::unix::SourceFd;
+use mio::{Events, Interest, Poll, Token, Waker};
+use parking_lot::Mutex;
+
+use crate::hv::IrqSender;
+use crate::mem::emulated::{Action, Mmio};
+use crate::{ffi, mem};
+
+pub const HPET_NUM_TIMERS: usize = 3;
+/// The IOAPIC pin of timer 0 in legacy replacement mode, and the only pin
+/// timers can be routed to otherwise.
+pub const HPET_GSI: u8 = 2;
+/// The IOAPIC pin of timer 1 in legacy replacement mode.
+pub const HPET_RTC_GSI: u8 = 8;
+/// Minimum number of ticks the guest should program in periodic mode,
+/// reported in the ACPI HPET table.
+pub const HPET_MIN_TICK: u16 = 128;
+
+const HPET_FREQUENCY: u64 = 100_000_000;
+const HPET_PERIOD_FS: u64 = 1_000_000_000_000_000 / HPET_FREQUENCY;
+const HPET_VENDOR_ID: u64 = 0x8086;
+
+const REG_CAP: u64 = 0x000;
+const REG_CONFIG: u64 = 0x010;
+const REG_INT_STATUS: u64 = 0x020;
+const REG_COUNTER: u64 = 0x0f0;
+const REG_TIMER_BASE: u64 = 0x100;
+const REG_TIMER_END: u64 = REG_TIMER_BASE + TIMER_STRIDE * HPET_NUM_TIMERS as u64;
+
+const TIMER_STRIDE: u64 = 0x20;
+const TIMER_CONFIG: u64 = 0x00;
+const TIMER_COMPARATOR: u64 = 0x08;
+
+const CONFIG_ENABLE: u64 = 1 << 0;
+const CONFIG_LEGACY: u64 = 1 << 1;
+
+bitfield! {
+    #[derive(Copy, Clone, Default)]
+    pub struct Capabilities(u64);
+    impl Debug;
+    pub u8, rev_id, _: 7, 0;
+    pub u8, num_timers_minus_one, _: 12, 8;
+    pub count_size_cap, _: 13;
+    pub legacy_cap, _: 15;
+    pub u16, vendor_id, _: 31, 16;
+    pub u32, period_fs, _: 63, 32;
+}
+
+impl Capabilities {
+    pub fn new() -> Self {
+        Capabilities(
+            0x01 | ((HPET_NUM_TIMERS as u64 - 1) << 8)
+                | (1 << 13)
+                | (1 << 15)
+                | (HPET_VENDOR_ID << 16)
+                | (HPET_PERIOD_FS << 32),
+        )
+    }
+}
+
+bitfield! {
+    #[derive(Copy, Clone, Default)]
+    struct TimerConfig(u64);
+    impl Debug;
+    level_triggered, _: 1;
+    int_enabled, _: 2;
+    periodic, _: 3;
+    periodic_cap, _: 4;
+    val_set, set_val_set: 6;
+    mode_32, _: 8;
+    u8, int_route, _: 13, 9;
+    u32, int_route_cap, _: 63, 32;
+}
+
+impl TimerConfig {
+    const WRITABLE: u64 = (1 << 1) | (1 << 2) | (1 << 3) | (1 << 6) | (1 << 8) | (0x1f << 9);
+
+    fn new(index: usize) -> Self {
+        let periodic_cap = if index == 0 { 1 << 4 } else { 0 };
+        TimerConfig(periodic_cap | (1 << 5) | ((1 << HPET_GSI) << 32) | (HPET_GSI as u64) << 9)
+    }
+}
+
+#[derive(Debug)]
+struct Timer {
+    config: TimerConfig,
+    comparator: u64,
+    period: u64,
+}
+
+impl Timer {
+    fn mask(&self) -> u64 {
+        if self.config.mode_32() {
+            u32::MAX as u64
+        } else {
+            u64::MAX
+        }
+    }
+}
+
+#[derive(Debug)]
+struct HpetRegs {
+    config: u64,
+    int_status: u64,
+    counter: u64,
+    start: Option<Instant>,
+    timers: [Timer; HPET_NUM_TIMERS],
+}
+
+impl HpetRegs {
+    fn new() -> Self {
+        HpetRegs {
+            config: 0,
+            int_status: 0,
+            counter: 0,
+            start: None,
+            timers: std::array::from_fn(|index| Timer {
+                config: TimerConfig::new(index),
+                comparator: u64::MAX,
+                period: 0,
+            }),
+        }
+    }
+
+    fn counter(&self, now: Instant) -> u64 {
+        let Some(start) = self.start else {
+            return self.counter;
+        };
+        let nanos = now.saturating_duration_since(start).as_nanos();
+        let ticks = nanos * HPET_FREQUENCY as u128 / 1_000_000_000;
+        self.counter.wrapping_add(ticks as u64)
+    }
+
+    /// Returns the index of the IRQ sender that timer `index` is wired to.
+    fn irq_line(&self, index: usize) -> usize {
+        if self.config & CONFIG_LEGACY == CONFIG_LEGACY && index == 1 {
+            1
+        } else {
+            0
+        }
+    }
+}
+
+fn ticks_to_timespec(ticks: u64) -> timespec {
+    let nanos = ticks as u128 * 1_000_000_000 / HPET_FREQUENCY as u128;
+    let duration = Duration::from_nanos(u64::try_from(nanos).unwrap_or(u64::MAX));
+    timespec {
+        tv_sec: duration.as_secs() as _,
+        tv_nsec: duration.subsec_nanos() as _,
+    }
+}
+
+const TOKEN_SHUTDOWN: Token = Token(HPET_NUM_TIMERS);
+
+struct HpetWorker<I> {
+    name: Arc<String>,
+    regs: Arc<Mutex<HpetRegs>>,
+    irq_senders: [I; 2],
+    timers: Arc<[OwnedFd; HPET_NUM_TIMERS]>,
+    poll: Poll,
+}
+
+impl<I> HpetWorker<I>
+where
+    I: IrqSender,
+{
+    fn expire(&self, index: usize, expirations: u64) {
+        let mut regs = self.regs.lock();
+        if regs.config & CONFIG_ENABLE == 0 {
+            return;
+        }
+        let timer = &mut regs.timers[index];
+        if !timer.config.int_enabled() {
+            return;
+        }
+        if timer.config.periodic() {
+            let step = timer.period.wrapping_mul(expirations);
+            timer.comparator = timer.comparator.wrapping_add(step) & timer.mask();
+        }
+        if timer.config.level_triggered() {
+            regs.int_status |= 1 << index;
+        }
+        let line = regs.irq_line(index);
+        drop(regs);
+        if let Err(e) = self.irq_senders[line].send() {
+            log::error!("{}: sending interrupt: {e:?}", self.name);
+        }
+    }
+
+    fn do_work_inner(&mut self) -> Result<()> {
+        for (index, timer) in self.timers.iter().enumerate() {
+            self.poll.registry().register(
+                &mut SourceFd(&timer.as_raw_fd()),
+                Token(index),
+                Interest::READABLE,
+            )?;
+        }
+        let mut events = Events::with_capacity(HPET_NUM_TIMERS + 1);
+        loop {
+            self.poll.poll(&mut events, None)?;
+            for event in events.iter() {
+                if event.token() == TOKEN_SHUTDOWN {
+                    return Ok(());
+                }
+                let index = event.token().0;
+                let mut expirations = 0u64;
+                let buf = &mut expirations as *mut u64 as *mut _;
+                let fd = self.timers[index].as_raw_fd();
+                match ffi!(unsafe { libc::read(fd, buf, 8) }) {
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => continue,
+                    Err(e) => return Err(e),
+                    Ok(_) => {}
+                }
+                self.expire(index, expirations);
+            }
+        }
+    }
+
+    fn do_work(&mut self) {
+        log::trace!("{}: start", self.name);
+        if let Err(e) = self.do_work_inner() {
+            log::error!("{}: {e:?}", self.name)
+        } else {
+            log::trace!("{}: done", self.name)
+        }
+    }
+}
+
+/// High Precision Event Timer with a 64-bit main counter running at 100 MHz.
+///
+/// Timer 0 and timer 1 replace the PIT and the RTC in legacy replacement
+/// mode. Otherwise all timers are routed to [`HPET_GSI`].
+#[derive(Debug)]
+pub struct Hpet {
+    name: Arc<String>,
+    regs: Arc<Mutex<HpetRegs>>,
+    timers: Arc<[OwnedFd; HPET_NUM_TIMERS]>,
+    worker_thread: Option<JoinHandle<()>>,
+    exit_waker: Waker,
+}
+
+impl Hpet {
+    /// Creates an HPET with `irq_gsi` wired to [`HPET_GSI`] and `irq_rtc` to
+    /// [`HPET_RTC_GSI`].
+    pub fn new<I>(irq_gsi: I, irq_rtc: I) -> Result<Self>
+    where
+        I: IrqSender,
+    {
+        let name = Arc::new("hpet".to_owned());
+        let mut fds = vec![];
+        for _ in 0..HPET_NUM_TIMERS {
+            let fd = ffi!(unsafe { timerfd_create(CLOCK_MONOTONIC, TFD_NONBLOCK | TFD_CLOEXEC) })?;
+            fds.push(unsafe { OwnedFd::from_raw_fd(fd) });
+        }
+        let Ok(timers) = <[OwnedFd; HPET_NUM_TIMERS]>::try_from(fds) else {
+            unreachable!()
+        };
+        let timers = Arc::new(timers);
+        let regs = Arc::new(Mutex::new(HpetRegs::new()));
+        let poll = Poll::new()?;
+        let exit_waker = Waker::new(poll.registry(), TOKEN_SHUTDOWN)?;
+        let mut worker = HpetWorker {
+            name: name.clone(),
+            regs: regs.clone(),
+            irq_senders: [irq_gsi, irq_rtc],
+            timers: timers.clone(),
+            poll,
+        };
+        let worker_thread = std::thread::Builder::new()
+            .name(name.to_string())
+            .spawn(move || worker.do_work())?;
+        Ok(Hpet {
+            name,
+            regs,
+            timers,
+            worker_thread: Some(worker_thread),
+            exit_waker,
+        })
+    }
+
+    /// Stops the counter and all timers.
+    pub fn reset(&self) {
+        let mut regs = self.regs.lock();
+        *regs = HpetRegs::new();
+        for index in 0..HPET_NUM_TIMERS {
+            self.set_timer(index, ticks_to_timespec(0), ticks_to_timespec(0));
+        }
+    }
+
+    fn set_timer(&self, index: usize, value: timespec, interval: timespec) {
+        let spec = itimerspec {
+            it_interval: interval,
+            it_value: value,
+        };
+        let fd = self.timers[index].as_raw_fd();
+        let ret = ffi!(unsafe { timerfd_settime(fd, 0, &spec, null_mut()) });
+        if let Err(e) = ret {
+            log::error!("{}: cannot set timer {index}: {e:?}", self.name);
+        }
+    }
+
+    fn arm_timer(&self, regs: &HpetRegs, index: usize, now: Instant) {
+        let timer = &regs.timers[index];
+        if regs.config & CONFIG_ENABLE == 0 || !timer.config.int_enabled() {
+            self.set_timer(index, ticks_to_timespec(0), ticks_to_timespec(0));
+            return;
+        }
+        let mask = timer.mask();
+        let counter = regs.counter(now) & mask;
+        let delta = timer.comparator.wrapping_sub(counter) & mask;
+        // A zero timespec disarms the timer.
+        let value = ticks_to_timespec(std::cmp::max(delta, 1));
+        let interval = if timer.config.periodic() && timer.period != 0 {
+            ticks_to_timespec(std::cmp::max(timer.period, 1))
+        } else {
+            ticks_to_timespec(0)
+        };
+        log::trace!(
+            "{}: timer {index}: comparator {:#x}, counter {counter:#x}, period {:#x}",
+            self.name,
+            timer.comparator,
+            timer.period
+        );
+        self.set_timer(index, value, interval);
+    }
+
+    fn arm_timers(&self, regs: &HpetRegs, now: Instant) {
+        for index in 0..HPET_NUM_TIMERS {
+            self.arm_timer(regs, index, now);
+        }
+    }
+
+    fn read_reg(&self, regs: &HpetRegs, reg: u64) -> u64 {
+        match reg {
+            REG_CAP => Capabilities::new().0,
+            REG_CONFIG => regs.config,
+            REG_INT_STATUS => regs.int_status,
+            REG_COUNTER => regs.counter(Instant::now()),
+            REG_TIMER_BASE..REG_TIMER_END => {
+                let timer = &regs.timers[((reg - REG_TIMER_BASE) / TIMER_STRIDE) as usize];
+                match (reg - REG_TIMER_BASE) % TIMER_STRIDE {
+                    TIMER_CONFIG => timer.config.0,
+                    TIMER_COMPARATOR => timer.comparator,
+                    _ => 0,
+                }
+            }
+            _ => 0,
+        }
+    }
+
+    fn write_reg(&self, regs: &mut HpetRegs, reg: u64, val: u64, mask: u64) {
+        let now = Instant::now();
+        match reg {
+            REG_CONFIG => {
+                let old = regs.config;
+                let mask = mask & (CONFIG_ENABLE | CONFIG_LEGACY);
+                regs.config = (old & !mask) | (val & mask);
+                if (old ^ regs.config) & CONFIG_ENABLE == 0 {
+                    return;
+                }
+                if regs.config & CONFIG_ENABLE == CONFIG_ENABLE {
+                    regs.start = Some(now);
+                } else {
+                    regs.counter = regs.counter(now);
+                    regs.start = None;
+                }
+                self.arm_timers(regs, now);
+            }
+            REG_INT_STATUS => regs.int_status &= !(val & mask),
+            REG_COUNTER => {
+                if regs.start.is_some() {
+                    log::warn!(
+                        "{}: writing the main counter while it is running",
+                        self.name
+                    );
+                }
+                let counter = regs.counter(now);
+                regs.counter = (counter & !mask) | (val & mask);
+                regs.start = regs.start.map(|_| now);
+                self.arm_timers(regs, now);
+            }
+            REG_TIMER_BASE..REG_TIMER_END => {
+                let index = ((reg - REG_TIMER_BASE) / TIMER_STRIDE) as usize;
+                let timer = &mut regs.timers[index];
+                match (reg - REG_TIMER_BASE) % TIMER_STRIDE {
+                    TIMER_CONFIG => {
+                        let mut writable = mask & TimerConfig::WRITABLE;
+                        if !timer.config.periodic_cap() {
+                            writable &= !(1 << 3);
+                        }
+                        let old = timer.config;
+                        let mut config = TimerConfig((old.0 & !writable) | (val & writable));
+                        if config.int_route_cap() & (1 << config.int_route()) == 0 {
+                            log::warn!(
+                                "{}: timer {index}: unsupported route {}",
+                                self.name,
+                                config.int_route()
+                            );
+                            config.0 = (config.0 & !(0x1f << 9)) | (old.0 & (0x1f << 9));
+                        }
+                        timer.config = config;
+                        if config.mode_32() {
+                            timer.comparator &= u32::MAX as u64;
+                            timer.period &= u32::MAX as u64;
+                        }
+                        if !config.level_triggered() {
+                            regs.int_status &= !(1 << index);
+                        }
+                    }
+                    TIMER_COMPARATOR => {
+                        let val = ((timer.comparator & !mask) | (val & mask)) & timer.mask();
+                        if !timer.config.periodic() || timer.config.val_set() {
+                            timer.comparator = val;
+                        }
+                        if timer.config.periodic() {
+                            timer.period = val;
+                        }
+                        timer.config.set_val_set(false);
+                    }
+                    _ => return,
+                }
+                self.arm_timer(regs, index, now);
+            }
+            _ => log::error!("{}: write {val:#x} to unknown register {reg:#x}", self.name),
+        }
+    }
+}
+
+impl Mmio for Hpet {
+    fn size(&self) -> u64 {
+        0x400
+    }
+
+    fn read(&self, offset: u64, size: u8) -> mem::Result<u64> {
+        let regs = self.regs.lock();
+        let val = self.read_reg(&regs, offset & !0x7) >> ((offset & 0x4) << 3);
+        let ret = match size {
+            1 => val as u8 as u64,
+            2 => val as u16 as u64,
+            4 => val as u32 as u64,
+            _ => val,
+        };
+        Ok(ret)
+    }
+
+    fn write(&self, offset: u64, size: u8, val: u64) -> mem::Result<Action> {
+        let shift = (offset & 0x4) << 3;
+        let mask = match size {
+            4 => (u32::MAX as u64) << shift,
+            8 => u64::MAX,
+            _ => {
+                log::error!(
+                    "{}: unsupported {size}-byte write at {offset:#x}",
+                    self.name
+                );
+                return Ok(Action::None);
+            }
+        };
+        let mut regs = self.regs.lock();
+        self.write_reg(&mut regs, offset & !0x7, val << shift, mask);
+        Ok(Action::None)
+    }
+}
+
+impl Drop for Hpet {
+    fn drop(&mut self) {
+        if let Err(e) = self.exit_waker.wake() {
+            log::error!("{}: {e:?}", self.name);
+            return;
+        }
+        let Some(thread) = self.worker_thread.take() else {
+            return;
+        };
+        if let Err(e) = thread.join() {
+            log::error!("{}: {e:?}", self.name);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Arc;
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    use crate::device::hpet::Hpet;
+    use crate::hv::{IrqSender, Result};
+    use crate::mem::emulated::Mmio;
+
+    #[derive(Debug, Default)]
+    struct CountIrq(AtomicU32);
+
+    impl IrqSender for CountIrq {
+        fn send(&self) -> Result<()> {
+            self.0.fetch_add(1, Ordering::Relaxed);
+            Ok(())
+        }
+    }
+
+    fn new_hpet() -> (Hpet, Arc<CountIrq>, Arc<CountIrq>) {
+        let irq_gsi = Arc::new(CountIrq::default());
+        let irq_rtc = Arc::new(CountIrq::default());
+        let hpet = Hpet::new(irq_gsi.clone(), irq_rtc.clone()).unwrap();
+        (hpet, irq_gsi, irq_rtc)
+    }
+
+    #[test]
+    fn test_hpet_registers() {
+        let (hpet, _, _) = new_hpet();
+        // rev 1, 3 timers, 64-bit counter, legacy capable, 10 ns period
+        assert_eq!(hpet.read(0x0, 4).unwrap(), 0x8086_a201);
+        assert_eq!(hpet.read(0x4, 4).unwrap(), 10_000_000);
+        // the counter is halted after reset
+        assert_eq!(hpet.read(0xf0, 8).unwrap(), 0);
+        hpet.write(0xf0, 4, 0x1234).unwrap();
+        assert_eq!(hpet.read(0xf0, 8).unwrap(), 0x1234);
+
+        hpet.write(0x10, 4, 0b1).unwrap();
+        sleep(Duration::from_millis(1));
+        let counter = hpet.read(0xf0, 8).unwrap();
+        assert!(counter >= 0x1234 + 100_000);
+        hpet.write(0x10, 4, 0b0).unwrap();
+        let counter = hpet.read(0xf0, 8).unwrap();
+        sleep(Duration::from_millis(1));
+        assert_eq!(hpet.read(0xf0, 8).unwrap(), counter);
+
+        // timer 0 is periodic capable, timer 1 is not
+        assert_eq!(hpet.read(0x100, 4).unwrap(), 0b11_0000 | (2 << 9));
+        assert_eq!(hpet.read(0x104, 4).unwrap(), 1 << 2);
+        assert_eq!(hpet.read(0x120, 4).unwrap(), 0b10_0000 | (2 << 9));
+        hpet.write(0x120, 4, 0b1000).unwrap();
+        assert_eq!(hpet.read(0x120, 4).unwrap() & 0b1000, 0);
+        // only GSI 2 is routable
+        hpet.write(0x120, 4, 5 << 9).unwrap();
+        assert_eq!(hpet.read(0x120, 4).unwrap() >> 9 & 0x1f, 2);
+    }
+
+    #[test]
+    fn test_hpet_one_shot() {
+        let (hpet, irq_gsi, irq_rtc) = new_hpet();
+        // legacy replacement mode, timer 1 fires at 1 ms
+        hpet.write(0x128, 8, 100_000).unwrap();
+        hpet.write(0x120, 4, 0b100).unwrap();
+        hpet.write(0x10, 4, 0b11).unwrap();
+        sleep(Duration::from_millis(50));
+        assert_eq!(irq_rtc.0.load(Ordering::Relaxed), 1);
+        assert_eq!(irq_gsi.0.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn test_hpet_periodic() {
+        let (hpet, irq_gsi, _) = new_hpet();
+        // timer 0, periodic every 1 ms, level triggered
+        hpet.write(0x100, 4, 0b100_1110).unwrap();
+        hpet.write(0x108, 8, 100_000).unwrap();
+        hpet.write(0x108, 8, 100_000).unwrap();
+        hpet.write(0x10, 4, 0b1).unwrap();
+        sleep(Duration::from_millis(50));
+        hpet.write(0x10, 4, 0b0).unwrap();
+        assert!(irq_gsi.0.load(Ordering::Relaxed) >= 2);
+        assert_eq!(hpet.read(0x20, 4).unwrap(), 0b1);
+        hpet.write(0x20, 4, 0b1).unwrap();
+        assert_eq!(hpet.read(0x20, 4).unwrap(), 0);
+        assert!(hpet.read(0x108, 8).unwrap() > 100_000);
+    }
+}
@@ -0,0 +1,595 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Inter-VM shared memory, compatible with `ivshmem-doorbell` of QEMU.
+//!
+//! BAR0 holds the registers, BAR1 the MSI-X table, and BAR2 the memory
+//! shared by all peers. Peers join through an [`IvshMemServer`], which
+//! hands out the shared memory and the eventfds of each peer's vectors.
+//! The eventfds are the irqfds of the MSI-X vectors, so a doorbell write
+//! interrupts the peer VM without going through the peer VMM.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, ErrorKind, Read, Write};
+use std::mem::size_of;
+use std::net::Shutdown;
+use std::os::fd::{AsFd, AsRawFd, OwnedFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+
+use parking_lot::{Mutex, RwLock};
+use serde::Deserialize;
+use snafu::{ResultExt, Snafu};
+use zerocopy::{AsBytes, FromBytes, FromZeroes};
+
+use crate::c_enum;
+use crate::errors::{trace_error, DebugTrace};
+use crate::hv::MsiSender;
+use crate::mem::emulated::{Action, Mmio};
+use crate::mem::shared::{recv_fds, send_fds, SharedMemFd};
+use crate::mem::{self, MemRange, MemRegion, MemRegionEntry, MemRegionType};
+use crate::pci::cap::{
+    MsixCap, MsixCapMmio, MsixCapOffset, MsixMsgCtrl, MsixPbaMmio, MsixTableMmio,
+    MsixTableMmioEntry, PciCap, PciCapHdr, PciCapId, PciCapList,
+};
+use crate::pci::config::{
+    CommonHeader, DeviceHeader, EmulatedConfig, HeaderType, PciConfig, BAR_MEM32, BAR_MEM64,
+    BAR_PREFETCHABLE,
+};
+use crate::pci::{self, Pci, PciBar};
+
+const IVSHMEM_VENDOR_ID: u16 = 0x1af4;
+const IVSHMEM_DEVICE_ID: u16 = 0x1110;
+
+const REGS_SIZE: u64 = 0x100;
+const REG_INTR_MASK: u64 = 0x0;
+const REG_INTR_STATUS: u64 = 0x4;
+const REG_IV_POSITION: u64 = 0x8;
+const REG_DOORBELL: u64 = 0xc;
+
+const BAR1_SIZE: u64 = 0x1000;
+const MSIX_PBA_OFFSET: u64 = 0x800;
+
+/// Vectors of a peer, limited by the room for the MSI-X table in BAR1.
+pub const IVSHMEM_MAX_VECTORS: u16 = 128;
+
+#[trace_error]
+#[derive(Snafu, DebugTrace)]
+#[snafu(module, context(suffix(false)))]
+pub enum Error {
+    #[snafu(display("Failed to talk to the ivshmem peer"))]
+    Socket { error: std::io::Error },
+    #[snafu(display("Connection closed by the ivshmem peer"))]
+    Disconnected,
+    #[snafu(display("Unexpected message type {type_}"))]
+    UnexpectedMsg { type_: u32 },
+    #[snafu(display("No more peer IDs for the doorbell"))]
+    TooManyPeers,
+    #[snafu(display("Shared memory size {size:#x} is not a power of 2"))]
+    SizeNotPowerOf2 { size: u64 },
+    #[snafu(display("Number of vectors {num} is not in 1..={IVSHMEM_MAX_VECTORS}"))]
+    InvalidVectors { num: u16 },
+    #[snafu(display("Failed to map shared memory"), context(false))]
+    Memory { source: Box<mem::Error> },
+    #[snafu(display("Failed to create irqfds"), context(false))]
+    Hv { source: Box<crate::hv::Error> },
+    #[snafu(display("Failed to create the PCI config"), context(false))]
+    Pci { source: Box<pci::Error> },
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+c_enum! {
+    #[derive(Default, FromBytes, FromZeroes, AsBytes)]
+    struct MsgType(u32);
+    {
+        /// From a new peer, with the eventfds of its vectors.
+        REGISTER = 0;
+        /// To a new peer, with its ID and the shared memory.
+        WELCOME = 1;
+        /// A peer joined, with the eventfds of its vectors.
+        PEER = 2;
+        /// A peer left.
+        GONE = 3;
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Default, FromBytes, FromZeroes, AsBytes)]
+struct Msg {
+    type_: MsgType,
+    id: u32,
+    size: u64,
+}
+
+fn send_msg(conn: &UnixStream, type_: MsgType, id: u32, size: u64, fds: &[RawFd]) -> Result<()> {
+    let msg = Msg { type_, id, size };
+    send_fds(conn, msg.as_bytes(), fds).context(error::Socket)
+}
+
+fn recv_msg(conn: &UnixStream, max_fds: usize) -> Result<(Msg, Vec<OwnedFd>)> {
+    let mut msg = Msg::default();
+    let (len, fds) = recv_fds(conn, msg.as_bytes_mut(), max_fds).context(error::Socket)?;
+    match len {
+        0 => error::Disconnected.fail(),
+        len if len == size_of::<Msg>() => Ok((msg, fds)),
+        _ => Err(io::Error::from(ErrorKind::InvalidData)).context(error::Socket),
+    }
+}
+
+fn raw_fds(fds: &[OwnedFd]) -> Vec<RawFd> {
+    fds.iter().map(|fd| fd.as_raw_fd()).collect()
+}
+
+#[derive(Debug)]
+struct ServerPeer {
+    conn: UnixStream,
+    fds: Vec<OwnedFd>,
+}
+
+/// Hands out the shared memory to peers and tells each peer the eventfds
+/// of the others.
+#[derive(Debug)]
+pub struct IvshMemServer {
+    shm: SharedMemFd,
+    peers: Mutex<HashMap<u32, ServerPeer>>,
+    next_id: AtomicU32,
+}
+
+impl IvshMemServer {
+    pub fn new(shm: SharedMemFd) -> Self {
+        IvshMemServer {
+            shm,
+            peers: Mutex::new(HashMap::new()),
+            next_id: AtomicU32::new(0),
+        }
+    }
+
+    /// Registers the peer connected through `conn` and returns its ID.
+    pub fn add_peer(&self, conn: UnixStream) -> Result<u32> {
+        let (msg, fds) = recv_msg(&conn, IVSHMEM_MAX_VECTORS as usize)?;
+        if msg.type_ != MsgType::REGISTER {
+            return error::UnexpectedMsg {
+                type_: msg.type_.raw(),
+            }
+            .fail();
+        }
+        // The doorbell register has 16 bits for the peer ID.
+        let id = self.next_id.fetch_add(1, Ordering::AcqRel);
+        if id > u16::MAX as u32 {
+            return error::TooManyPeers.fail();
+        }
+        let shm_fd = self.shm.as_fd().as_raw_fd();
+        send_msg(&conn, MsgType::WELCOME, id, self.shm.size(), &[shm_fd])?;
+
+        let mut peers = self.peers.lock();
+        for (peer_id, peer) in peers.iter() {
+            send_msg(&conn, MsgType::PEER, *peer_id, 0, &raw_fds(&peer.fds))?;
+            if let Err(e) = send_msg(&peer.conn, MsgType::PEER, id, 0, &raw_fds(&fds)) {
+                log::error!("ivshmem: failed to notify peer {peer_id}: {e:?}");
+            }
+        }
+        // A peer can ring its own doorbell.
+        send_msg(&conn, MsgType::PEER, id, 0, &raw_fds(&fds))?;
+        peers.insert(id, ServerPeer { conn, fds });
+        log::info!("ivshmem: peer {id} joined");
+        Ok(id)
+    }
+
+    /// Removes peer `id` and tells the others.
+    pub fn remove_peer(&self, id: u32) {
+        let mut peers = self.peers.lock();
+        if peers.remove(&id).is_none() {
+            return;
+        }
+        for (peer_id, peer) in peers.iter() {
+            if let Err(e) = send_msg(&peer.conn, MsgType::GONE, id, 0, &[]) {
+                log::error!("ivshmem: failed to notify peer {peer_id}: {e:?}");
+            }
+        }
+        log::info!("ivshmem: peer {id} left");
+    }
+
+    /// Serves peers connecting to `listener`.
+    pub fn serve(self: Arc<Self>, listener: UnixListener) -> Result<()> {
+        for conn in listener.incoming() {
+            let conn = conn.context(error::Socket)?;
+            let mut watch = conn.try_clone().context(error::Socket)?;
+            let id = match self.add_peer(conn) {
+                Ok(id) => id,
+                Err(e) => {
+                    log::error!("ivshmem: failed to add a peer: {e:?}");
+                    continue;
+                }
+            };
+            let server = self.clone();
+            let watch_peer = move || {
+                // A peer sends nothing after joining, so the read returns
+                // only when the peer is gone.
+                let _ = watch.read(&mut [0u8; 1]);
+                server.remove_peer(id);
+            };
+            std::thread::Builder::new()
+                .name(format!("ivshmem-peer-{id}"))
+                .spawn(watch_peer)
+                .context(error::Socket)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct IvshMemParam {
+    /// Path to the socket of the ivshmem server.
+    pub socket: PathBuf,
+    /// Number of MSI-X vectors.
+    #[serde(default = "default_vectors")]
+    pub vectors: u16,
+}
+
+fn default_vectors() -> u16 {
+    1
+}
+
+#[derive(Debug)]
+struct IvshMemRegs {
+    name: Arc<String>,
+    id: u32,
+    intr_mask: AtomicU32,
+    intr_status: AtomicU32,
+    /// Eventfds of the vectors of each peer.
+    peers: Arc<Mutex<HashMap<u32, Vec<File>>>>,
+}
+
+impl IvshMemRegs {
+    fn ring(&self, peer: u32, vector: u16) {
+        let peers = self.peers.lock();
+        let Some(fd) = peers.get(&peer).and_then(|fds| fds.get(vector as usize)) else {
+            log::error!("{}: peer {peer} has no vector {vector}", self.name);
+            return;
+        };
+        if let Err(e) = (&*fd).write_all(&1u64.to_ne_bytes()) {
+            log::error!("{}: ring peer {peer} vector {vector}: {e}", self.name);
+        }
+    }
+}
+
+impl Mmio for IvshMemRegs {
+    fn size(&self) -> u64 {
+        REGS_SIZE
+    }
+
+    fn read(&self, offset: u64, _size: u8) -> mem::Result<u64> {
+        let val = match offset {
+            REG_INTR_MASK => self.intr_mask.load(Ordering::Acquire),
+            REG_INTR_STATUS => self.intr_status.swap(0, Ordering::AcqRel),
+            REG_IV_POSITION => self.id,
+            _ => 0,
+        };
+        Ok(val as u64)
+    }
+
+    fn write(&self, offset: u64, _size: u8, val: u64) -> mem::Result<Action> {
+        let val = val as u32;
+        match offset {
+            REG_INTR_MASK => self.intr_mask.store(val, Ordering::Release),
+            REG_INTR_STATUS => self.intr_status.store(val, Ordering::Release),
+            REG_DOORBELL => self.ring(val >> 16, val as u16),
+            _ => log::error!("{}: write {val:#x} to {offset:#x}", self.name),
+        }
+        Ok(Action::None)
+    }
+}
+
+fn handle_peer_msgs(name: &str, conn: &UnixStream, peers: &Mutex<HashMap<u32, Vec<File>>>) {
+    loop {
+        let (msg, fds) = match recv_msg(conn, IVSHMEM_MAX_VECTORS as usize) {
+            Ok(m) => m,
+            Err(Error::Disconnected { .. }) => break,
+            Err(e) => {
+                log::error!("{name}: {e:?}");
+                break;
+            }
+        };
+        match msg.type_ {
+            MsgType::PEER => {
+                let fds = fds.into_iter().map(File::from).collect();
+                peers.lock().insert(msg.id, fds);
+            }
+            MsgType::GONE => {
+                peers.lock().remove(&msg.id);
+            }
+            type_ => log::error!("{name}: unexpected message {type_:?}"),
+        }
+    }
+    log::info!("{name}: disconnected from the ivshmem server");
+}
+
+#[derive(Debug)]
+pub struct IvshMem<M>
+where
+    M: MsiSender,
+{
+    config: Arc<EmulatedConfig>,
+    regs: Arc<IvshMemRegs>,
+    msix_table: Arc<MsixTableMmio<M>>,
+    conn: UnixStream,
+    worker: Option<JoinHandle<()>>,
+}
+
+impl<M> IvshMem<M>
+where
+    M: MsiSender,
+{
+    pub fn new(name: Arc<String>, param: &IvshMemParam, msi_sender: M) -> Result<Self> {
+        let conn = UnixStream::connect(&param.socket).context(error::Socket)?;
+        Self::with_conn(name, conn, param.vectors, msi_sender)
+    }
+
+    /// Joins the ivshmem server at the other end of `conn`.
+    pub fn with_conn(
+        name: Arc<String>,
+        conn: UnixStream,
+        num_vectors: u16,
+        msi_sender: M,
+    ) -> Result<Self> {
+        if num_vectors == 0 || num_vectors > IVSHMEM_MAX_VECTORS {
+            return error::InvalidVectors { num: num_vectors }.fail();
+        }
+        let num = num_vectors as usize;
+        let msix_table = Arc::new(MsixTableMmio::new(num, num, msi_sender));
+        let mut irqfds = vec![];
+        for entry in msix_table.entries.read().iter() {
+            let irqfd = msix_table.msi_sender.create_irqfd()?;
+            irqfds.push(irqfd.as_fd().as_raw_fd());
+            *entry.write() = MsixTableMmioEntry::IrqFd(irqfd);
+        }
+        send_msg(&conn, MsgType::REGISTER, 0, 0, &irqfds)?;
+        let (welcome, fds) = recv_msg(&conn, 1)?;
+        let Some(shm_fd) = fds
+            .into_iter()
+            .next()
+            .filter(|_| welcome.type_ == MsgType::WELCOME)
+        else {
+            return error::UnexpectedMsg {
+                type_: welcome.type_.raw(),
+            }
+            .fail();
+        };
+        let size = welcome.size;
+        if !size.is_power_of_two() {
+            return error::SizeNotPowerOf2 { size }.fail();
+        }
+        let shm = SharedMemFd::from_fd(shm_fd, size);
+        log::info!("{name}: joined as peer {} with {size:#x} bytes", welcome.id);
+
+        let header = DeviceHeader {
+            common: CommonHeader {
+                vendor: IVSHMEM_VENDOR_ID,
+                device: IVSHMEM_DEVICE_ID,
+                revision: 1,
+                header_type: HeaderType::Device as u8,
+                class: 0x05,
+                subclass: 0x00,
+                ..Default::default()
+            },
+            bars: [BAR_MEM32, BAR_MEM32, BAR_MEM64 | BAR_PREFETCHABLE, 0, 0, 0],
+            ..Default::default()
+        };
+        let mut table_offset = MsixCapOffset(0);
+        table_offset.set_bar(1);
+        let mut pba_offset = MsixCapOffset(MSIX_PBA_OFFSET as u32);
+        pba_offset.set_bar(1);
+        let mut control = MsixMsgCtrl::default();
+        control.set_table_len(num_vectors - 1);
+        let msix_cap = MsixCap {
+            header: PciCapHdr {
+                id: PciCapId::Msix as u8,
+                ..Default::default()
+            },
+            control,
+            table_offset,
+            pba_offset,
+        };
+        let caps: Vec<Box<dyn PciCap>> = vec![Box::new(MsixCapMmio {
+            cap: Arc::new(RwLock::new(msix_cap)),
+        })];
+        let cap_list = PciCapList::try_from(caps)?;
+
+        let peers = Arc::new(Mutex::new(HashMap::new()));
+        let regs = Arc::new(IvshMemRegs {
+            name: name.clone(),
+            id: welcome.id,
+            intr_mask: AtomicU32::new(0),
+            intr_status: AtomicU32::new(0),
+            peers: peers.clone(),
+        });
+        let msix_pba = Arc::new(MsixPbaMmio {
+            table: msix_table.clone(),
+        });
+        let bar0 = MemRegion::with_emulated(regs.clone(), MemRegionType::Hidden);
+        let bar1 = MemRegion {
+            ranges: vec![
+                MemRange::emulated(msix_table.clone()),
+                MemRange::Span(MSIX_PBA_OFFSET - msix_table.size()),
+                MemRange::emulated(msix_pba.clone()),
+                MemRange::Span(BAR1_SIZE - MSIX_PBA_OFFSET - msix_pba.size()),
+            ],
+            entries: vec![MemRegionEntry {
+                size: BAR1_SIZE,
+                type_: MemRegionType::Hidden,
+            }],
+            callbacks: Mutex::new(vec![]),
+            dirty_callbacks: Mutex::new(vec![]),
+        };
+        let bar2 = MemRegion::with_mapped(shm.map()?, MemRegionType::Reserved);
+        let bar2_mask = !(size - 1);
+        let bar_masks = [
+            !(REGS_SIZE as u32 - 1),
+            !(BAR1_SIZE as u32 - 1),
+            bar2_mask as u32,
+            (bar2_mask >> 32) as u32,
+            0,
+            0,
+        ];
+        let mut bars = [const { PciBar::Empty }; 6];
+        bars[0] = PciBar::Mem(Arc::new(bar0));
+        bars[1] = PciBar::Mem(Arc::new(bar1));
+        bars[2] = PciBar::Mem(Arc::new(bar2));
+        let config = EmulatedConfig::new_device(header, bar_masks, bars, cap_list);
+
+        let worker_conn = conn.try_clone().context(error::Socket)?;
+        let worker = std::thread::Builder::new()
+            .name(name.to_string())
+            .spawn(move || handle_peer_msgs(&name, &worker_conn, &peers))
+            .context(error::Socket)?;
+        Ok(IvshMem {
+            config: Arc::new(config),
+            regs,
+            msix_table,
+            conn,
+            worker: Some(worker),
+        })
+    }
+
+    /// The peer ID assigned by the server.
+    pub fn id(&self) -> u32 {
+        self.regs.id
+    }
+}
+
+impl<M> Drop for IvshMem<M>
+where
+    M: MsiSender,
+{
+    fn drop(&mut self) {
+        let _ = self.conn.shutdown(Shutdown::Both);
+        if let Some(worker) = self.worker.take() {
+            if let Err(e) = worker.join() {
+                log::error!("{}: failed to join worker: {e:?}", self.regs.name);
+            }
+        }
+    }
+}
+
+impl<M> Pci for IvshMem<M>
+where
+    M: MsiSender,
+{
+    fn config(&self) -> Arc<dyn PciConfig> {
+        self.config.clone()
+    }
+
+    fn reset(&self) -> pci::Result<()> {
+        self.regs.intr_mask.store(0, Ordering::Release);
+        self.regs.intr_status.store(0, Ordering::Release);
+        self.msix_table.clear_pending();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::os::fd::{AsFd, AsRawFd};
+    use std::os::unix::net::{UnixListener, UnixStream};
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    use crate::device::ivshmem::{
+        IvshMem, IvshMemServer, REG_DOORBELL, REG_INTR_STATUS, REG_IV_POSITION,
+    };
+    use crate::hv::test::FakeMsiSender;
+    use crate::mem::emulated::Mmio;
+    use crate::mem::shared::SharedMemFd;
+    use crate::mem::MemRange;
+    use crate::pci::cap::MsixTableMmioEntry;
+    use crate::pci::PciBar;
+
+    fn wait_until(f: impl Fn() -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !f() {
+            assert!(Instant::now() < deadline);
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    fn read_eventfd(dev: &IvshMem<FakeMsiSender>, vector: usize) -> u64 {
+        let entries = dev.msix_table.entries.read();
+        let MsixTableMmioEntry::IrqFd(fd) = &*entries[vector].read() else {
+            panic!("vector {vector} has no irqfd")
+        };
+        let mut count = 0u64;
+        let ret = unsafe { libc::read(fd.as_fd().as_raw_fd(), &mut count as *mut _ as _, 8) };
+        if ret == 8 {
+            count
+        } else {
+            0
+        }
+    }
+
+    fn shm_addr(dev: &IvshMem<FakeMsiSender>) -> usize {
+        let bars = dev.config.header.bars.read();
+        let PciBar::Mem(region) = &bars[2] else {
+            panic!("BAR2 is not memory")
+        };
+        let MemRange::Mapped(pages) = &region.ranges[0] else {
+            panic!("BAR2 is not mapped")
+        };
+        pages.addr()
+    }
+
+    #[test]
+    fn test_ivshmem_doorbell() {
+        let path = std::env::temp_dir().join(format!("alioth-ivshmem-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let shm = SharedMemFd::new(c"ivshmem", 0x10000).unwrap();
+        let server = Arc::new(IvshMemServer::new(shm));
+        std::thread::spawn(move || server.serve(listener));
+
+        let name = Arc::new("ivshmem".to_owned());
+        let conn = UnixStream::connect(&path).unwrap();
+        let dev0 = IvshMem::with_conn(name.clone(), conn, 2, FakeMsiSender::default()).unwrap();
+        let conn = UnixStream::connect(&path).unwrap();
+        let dev1 = IvshMem::with_conn(name, conn, 2, FakeMsiSender::default()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(dev0.regs.read(REG_IV_POSITION, 4).unwrap(), 0);
+        assert_eq!(dev1.regs.read(REG_IV_POSITION, 4).unwrap(), 1);
+        wait_until(|| dev0.regs.peers.lock().len() == 2);
+        wait_until(|| dev1.regs.peers.lock().len() == 2);
+
+        let addr0 = shm_addr(&dev0) as *mut u64;
+        let addr1 = shm_addr(&dev1) as *const u64;
+        unsafe { addr0.add(1).write_volatile(0xdead_beef) };
+        assert_eq!(unsafe { addr1.add(1).read_volatile() }, 0xdead_beef);
+
+        // peer 0 rings vector 1 of peer 1
+        dev0.regs.write(REG_DOORBELL, 4, (1 << 16) | 1).unwrap();
+        assert_eq!(read_eventfd(&dev1, 1), 1);
+        assert_eq!(read_eventfd(&dev1, 0), 0);
+        assert_eq!(read_eventfd(&dev0, 1), 0);
+
+        dev1.regs.write(REG_INTR_STATUS, 4, 1).unwrap();
+        assert_eq!(dev1.regs.read(REG_INTR_STATUS, 4).unwrap(), 1);
+        assert_eq!(dev1.regs.read(REG_INTR_STATUS, 4).unwrap(), 0);
+
+        drop(dev1);
+        wait_until(|| dev0.regs.peers.lock().len() == 1);
+    }
+}
@@ -0,0 +1,464 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::io::{ErrorKind, Result};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::ptr::null_mut;
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use bitfield::bitfield;
+use libc::{
+    itimerspec, timerfd_create, timerfd_settime, timespec, CLOCK_MONOTONIC, TFD_CLOEXEC,
+    TFD_NONBLOCK,
+};
+use mio::unix::SourceFd;
+use mio::{Events, Interest, Poll, Token, Waker};
+use parking_lot::Mutex;
+
+use crate::hv::IrqSender;
+use crate::mem::emulated::{Action, Mmio};
+use crate::{ffi, mem};
+
+pub const PIT_PORT: u16 = 0x40;
+
+const PIT_FREQUENCY: u64 = 1_193_182;
+
+const PORT_CONTROL: u64 = 0x3;
+
+const ACCESS_LATCH: u8 = 0b00;
+const ACCESS_LO: u8 = 0b01;
+const ACCESS_HI: u8 = 0b10;
+const ACCESS_LO_HI: u8 = 0b11;
+
+const CHANNEL_READ_BACK: u8 = 0b11;
+
+bitfield! {
+    #[derive(Copy, Clone, Default)]
+    pub struct ControlWord(u8);
+    impl Debug;
+    pub u8, channel, _: 7, 6;
+    pub u8, access, _: 5, 4;
+    pub u8, mode, _: 3, 1;
+    pub bcd, _: 0;
+}
+
+bitfield! {
+    #[derive(Copy, Clone, Default)]
+    pub struct ReadBack(u8);
+    impl Debug;
+    pub no_count, _: 5;
+    pub no_status, _: 4;
+    pub u8, channels, _: 3, 1;
+}
+
+#[derive(Debug, Default)]
+struct Channel {
+    mode: u8,
+    access: u8,
+    bcd: bool,
+    reload: u16,
+    start: Option<Instant>,
+    write_lsb: Option<u8>,
+    read_msb: bool,
+    latched_count: Option<u16>,
+    latched_status: Option<u8>,
+}
+
+impl Channel {
+    fn period(&self) -> u64 {
+        if self.reload == 0 {
+            1 << 16
+        } else {
+            self.reload as u64
+        }
+    }
+
+    fn ticks(&self, now: Instant) -> Option<u64> {
+        let start = self.start?;
+        let nanos = now.saturating_duration_since(start).as_nanos();
+        Some((nanos * PIT_FREQUENCY as u128 / 1_000_000_000) as u64)
+    }
+
+    fn count(&self, now: Instant) -> u16 {
+        let period = self.period();
+        let Some(ticks) = self.ticks(now) else {
+            return self.reload;
+        };
+        let count = match self.mode {
+            2 => period - ticks % period,
+            3 => {
+                let half = std::cmp::max(period / 2, 1);
+                period - (ticks % half) * 2
+            }
+            _ => period.wrapping_sub(ticks),
+        };
+        count as u16
+    }
+
+    fn output(&self, now: Instant) -> bool {
+        let period = self.period();
+        let Some(ticks) = self.ticks(now) else {
+            return self.mode != 0;
+        };
+        match self.mode {
+            0 => ticks >= period,
+            2 => ticks % period != period - 1,
+            3 => ticks % period < period.div_ceil(2),
+            _ => true,
+        }
+    }
+
+    fn status(&self, now: Instant) -> u8 {
+        (self.output(now) as u8) << 7
+            | (self.start.is_none() as u8) << 6
+            | self.access << 4
+            | self.mode << 1
+            | self.bcd as u8
+    }
+
+    fn latch_count(&mut self, now: Instant) {
+        if self.latched_count.is_none() {
+            self.latched_count = Some(self.count(now));
+            self.read_msb = false;
+        }
+    }
+
+    fn latch_status(&mut self, now: Instant) {
+        if self.latched_status.is_none() {
+            self.latched_status = Some(self.status(now));
+        }
+    }
+
+    fn set_control(&mut self, control: ControlWord) {
+        let mode = control.mode();
+        *self = Channel {
+            mode: if mode > 5 { mode & 0b11 } else { mode },
+            access: control.access(),
+            bcd: control.bcd(),
+            ..Default::default()
+        };
+    }
+
+    fn read(&mut self, now: Instant) -> u8 {
+        if let Some(status) = self.latched_status.take() {
+            return status;
+        }
+        let count = self.latched_count.unwrap_or_else(|| self.count(now));
+        let (byte, done) = match self.access {
+            ACCESS_LO => (count as u8, true),
+            ACCESS_HI => ((count >> 8) as u8, true),
+            _ if self.read_msb => ((count >> 8) as u8, true),
+            _ => (count as u8, false),
+        };
+        self.read_msb = !done;
+        if done {
+            self.latched_count = None;
+        }
+        byte
+    }
+
+    /// Returns true if a new count is loaded.
+    fn write(&mut self, byte: u8, now: Instant) -> bool {
+        let reload = match self.access {
+            ACCESS_LO => byte as u16,
+            ACCESS_HI => (byte as u16) << 8,
+            ACCESS_LO_HI => match self.write_lsb.take() {
+                Some(lsb) => lsb as u16 | (byte as u16) << 8,
+                None => {
+                    self.write_lsb = Some(byte);
+                    return false;
+                }
+            },
+            _ => return false,
+        };
+        self.reload = reload;
+        self.start = Some(now);
+        true
+    }
+}
+
+fn ticks_to_timespec(ticks: u64) -> timespec {
+    let nanos = ticks * 1_000_000_000 / PIT_FREQUENCY;
+    let duration = Duration::from_nanos(nanos);
+    timespec {
+        tv_sec: duration.as_secs() as _,
+        tv_nsec: duration.subsec_nanos() as _,
+    }
+}
+
+const TOKEN_TIMER: Token = Token(0);
+const TOKEN_SHUTDOWN: Token = Token(1);
+
+struct PitWorker<I> {
+    name: Arc<String>,
+    irq_sender: I,
+    timer: Arc<OwnedFd>,
+    poll: Poll,
+}
+
+impl<I> PitWorker<I>
+where
+    I: IrqSender,
+{
+    fn do_work_inner(&mut self) -> Result<()> {
+        let timer_fd = self.timer.as_raw_fd();
+        self.poll
+            .registry()
+            .register(&mut SourceFd(&timer_fd), TOKEN_TIMER, Interest::READABLE)?;
+        let mut events = Events::with_capacity(4);
+        loop {
+            self.poll.poll(&mut events, None)?;
+            for event in events.iter() {
+                if event.token() == TOKEN_SHUTDOWN {
+                    return Ok(());
+                }
+                let mut expirations = 0u64;
+                let buf = &mut expirations as *mut u64 as *mut _;
+                match ffi!(unsafe { libc::read(timer_fd, buf, 8) }) {
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => continue,
+                    Err(e) => return Err(e),
+                    Ok(_) => {}
+                }
+                if let Err(e) = self.irq_sender.send() {
+                    log::error!("{}: sending interrupt: {e:?}", self.name);
+                }
+            }
+        }
+    }
+
+    fn do_work(&mut self) {
+        log::trace!("{}: start", self.name);
+        if let Err(e) = self.do_work_inner() {
+            log::error!("{}: {e:?}", self.name)
+        } else {
+            log::trace!("{}: done", self.name)
+        }
+    }
+}
+
+/// Intel 8254 programmable interval timer. Channel 0 drives ISA IRQ 0,
+/// which is wired to GSI 2 as the MADT describes.
+#[derive(Debug)]
+pub struct I8254 {
+    name: Arc<String>,
+    channels: Mutex<[Channel; 3]>,
+    timer: Arc<OwnedFd>,
+    worker_thread: Option<JoinHandle<()>>,
+    exit_waker: Waker,
+}
+
+impl I8254 {
+    pub fn new<I>(irq_sender: I) -> Result<Self>
+    where
+        I: IrqSender,
+    {
+        let name = Arc::new("pit".to_owned());
+        let fd = ffi!(unsafe { timerfd_create(CLOCK_MONOTONIC, TFD_NONBLOCK | TFD_CLOEXEC) })?;
+        let timer = Arc::new(unsafe { OwnedFd::from_raw_fd(fd) });
+        let poll = Poll::new()?;
+        let exit_waker = Waker::new(poll.registry(), TOKEN_SHUTDOWN)?;
+        let mut worker = PitWorker {
+            name: name.clone(),
+            irq_sender,
+            timer: timer.clone(),
+            poll,
+        };
+        let worker_thread = std::thread::Builder::new()
+            .name(name.to_string())
+            .spawn(move || worker.do_work())?;
+        Ok(I8254 {
+            name,
+            channels: Mutex::new(Default::default()),
+            timer,
+            worker_thread: Some(worker_thread),
+            exit_waker,
+        })
+    }
+
+    fn set_timer(&self, value: timespec, interval: timespec) {
+        let spec = itimerspec {
+            it_interval: interval,
+            it_value: value,
+        };
+        let ret = ffi!(unsafe { timerfd_settime(self.timer.as_raw_fd(), 0, &spec, null_mut()) });
+        if let Err(e) = ret {
+            log::error!("{}: cannot set timer: {e:?}", self.name);
+        }
+    }
+
+    fn arm_timer(&self, channel: &Channel) {
+        let period = ticks_to_timespec(channel.period());
+        match channel.mode {
+            2 | 3 => self.set_timer(period, period),
+            _ => self.set_timer(period, ticks_to_timespec(0)),
+        }
+    }
+
+    fn disarm_timer(&self) {
+        self.set_timer(ticks_to_timespec(0), ticks_to_timespec(0))
+    }
+
+    fn write_control(&self, control: ControlWord) {
+        let now = Instant::now();
+        let mut channels = self.channels.lock();
+        if control.channel() == CHANNEL_READ_BACK {
+            let read_back = ReadBack(control.0);
+            for (index, channel) in channels.iter_mut().enumerate() {
+                if read_back.channels() & (1 << index) == 0 {
+                    continue;
+                }
+                if !read_back.no_status() {
+                    channel.latch_status(now);
+                }
+                if !read_back.no_count() {
+                    channel.latch_count(now);
+                }
+            }
+            return;
+        }
+        let channel = &mut channels[control.channel() as usize];
+        if control.access() == ACCESS_LATCH {
+            channel.latch_count(now);
+            return;
+        }
+        channel.set_control(control);
+        if control.channel() == 0 {
+            self.disarm_timer();
+        }
+    }
+}
+
+impl Mmio for I8254 {
+    fn size(&self) -> u64 {
+        4
+    }
+
+    fn read(&self, offset: u64, _size: u8) -> mem::Result<u64> {
+        let mut channels = self.channels.lock();
+        let ret = match channels.get_mut(offset as usize) {
+            Some(channel) => channel.read(Instant::now()),
+            None => 0,
+        };
+        Ok(ret as u64)
+    }
+
+    fn write(&self, offset: u64, _size: u8, val: u64) -> mem::Result<Action> {
+        let byte = val as u8;
+        if offset == PORT_CONTROL {
+            self.write_control(ControlWord(byte));
+            return Ok(Action::None);
+        }
+        let mut channels = self.channels.lock();
+        let Some(channel) = channels.get_mut(offset as usize) else {
+            return Ok(Action::None);
+        };
+        if channel.write(byte, Instant::now()) && offset == 0 {
+            log::trace!(
+                "{}: channel 0: mode {}, reload {:#x}",
+                self.name,
+                channel.mode,
+                channel.reload
+            );
+            self.arm_timer(channel);
+        }
+        Ok(Action::None)
+    }
+}
+
+impl Drop for I8254 {
+    fn drop(&mut self) {
+        if let Err(e) = self.exit_waker.wake() {
+            log::error!("{}: {e:?}", self.name);
+            return;
+        }
+        let Some(thread) = self.worker_thread.take() else {
+            return;
+        };
+        if let Err(e) = thread.join() {
+            log::error!("{}: {e:?}", self.name);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Arc;
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    use crate::device::pit::I8254;
+    use crate::hv::{IrqSender, Result};
+    use crate::mem::emulated::Mmio;
+
+    #[derive(Debug, Default)]
+    struct CountIrq(AtomicU32);
+
+    impl IrqSender for CountIrq {
+        fn send(&self) -> Result<()> {
+            self.0.fetch_add(1, Ordering::Relaxed);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_pit_latch() {
+        let pit = I8254::new(Arc::new(CountIrq::default())).unwrap();
+        // channel 2, lobyte/hibyte, mode 0
+        pit.write(3, 1, 0b1011_0000).unwrap();
+        // read back status of channel 2 before the count is loaded
+        pit.write(3, 1, 0b1110_1000).unwrap();
+        assert_eq!(pit.read(2, 1).unwrap(), 0b0111_0000);
+
+        pit.write(2, 1, 0x34).unwrap();
+        pit.write(2, 1, 0x12).unwrap();
+        // latch the count, later reads do not change the latched value
+        pit.write(3, 1, 0b1000_0000).unwrap();
+        let lo = pit.read(2, 1).unwrap();
+        sleep(Duration::from_millis(1));
+        let hi = pit.read(2, 1).unwrap();
+        let count = (hi << 8) | lo;
+        assert!(count <= 0x1234 && count > 0x1000, "count = {count:#x}");
+
+        // read back both status and count, status is read first
+        pit.write(3, 1, 0b1100_1000).unwrap();
+        assert_eq!(pit.read(2, 1).unwrap(), 0b0011_0000);
+        let lo = pit.read(2, 1).unwrap();
+        let hi = pit.read(2, 1).unwrap();
+        assert!((hi << 8) | lo < count);
+    }
+
+    #[test]
+    fn test_pit_period() {
+        let irq = Arc::new(CountIrq::default());
+        let pit = I8254::new(irq.clone()).unwrap();
+        // channel 0, lobyte/hibyte, mode 3, 100 Hz
+        pit.write(3, 1, 0b0011_0110).unwrap();
+        let reload: u16 = 11932;
+        pit.write(0, 1, reload as u8 as u64).unwrap();
+        pit.write(0, 1, (reload >> 8) as u64).unwrap();
+        sleep(Duration::from_millis(205));
+        let count = irq.0.load(Ordering::Relaxed);
+        assert!((15..=21).contains(&count), "count = {count}");
+
+        // reprogramming the control word stops the timer
+        pit.write(3, 1, 0b0011_0110).unwrap();
+        sleep(Duration::from_millis(15));
+        let count = irq.0.load(Ordering::Relaxed);
+        sleep(Duration::from_millis(50));
+        assert_eq!(irq.0.load(Ordering::Relaxed), count);
+    }
+}
@@ -0,0 +1,582 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::io::{ErrorKind, Result};
+use std::mem::MaybeUninit;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::ptr::null_mut;
+use std::sync::Arc;
+use std::thread::JoinHandle;
+
+use bitflags::bitflags;
+use libc::{
+    clock_gettime, itimerspec, timerfd_create, timerfd_settime, timespec, CLOCK_MONOTONIC,
+    CLOCK_REALTIME, TFD_CLOEXEC, TFD_NONBLOCK,
+};
+use mio::unix::SourceFd;
+use mio::{Events, Interest, Poll, Token, Waker};
+use parking_lot::Mutex;
+
+use crate::hv::IrqSender;
+use crate::mem::emulated::{Action, Mmio};
+use crate::{ffi, mem};
+
+pub const RTC_PORT: u16 = 0x70;
+pub const RTC_IRQ: u8 = 8;
+
+const PORT_INDEX: u64 = 0x0;
+const PORT_DATA: u64 = 0x1;
+
+const REG_SECONDS: u8 = 0x00;
+const REG_SECONDS_ALARM: u8 = 0x01;
+const REG_MINUTES: u8 = 0x02;
+const REG_MINUTES_ALARM: u8 = 0x03;
+const REG_HOURS: u8 = 0x04;
+const REG_HOURS_ALARM: u8 = 0x05;
+const REG_WEEKDAY: u8 = 0x06;
+const REG_DAY: u8 = 0x07;
+const REG_MONTH: u8 = 0x08;
+const REG_YEAR: u8 = 0x09;
+const REG_STATUS_A: u8 = 0x0a;
+const REG_STATUS_B: u8 = 0x0b;
+const REG_STATUS_C: u8 = 0x0c;
+const REG_STATUS_D: u8 = 0x0d;
+const REG_CENTURY: u8 = 0x32;
+
+const CMOS_SIZE: usize = 128;
+
+// Alarm registers with the 2 most significant bits set match any value.
+const ALARM_DONT_CARE: u8 = 0xc0;
+
+bitflags! {
+    #[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+    pub struct StatusA: u8 {
+        const UIP = 1 << 7;
+        const DIVIDER = 0b111 << 4;
+        const RATE = 0b1111;
+    }
+}
+
+bitflags! {
+    #[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+    pub struct StatusB: u8 {
+        const SET = 1 << 7;
+        const PIE = 1 << 6;
+        const AIE = 1 << 5;
+        const UIE = 1 << 4;
+        const SQWE = 1 << 3;
+        const DM = 1 << 2;
+        const HOUR_24 = 1 << 1;
+        const DSE = 1 << 0;
+    }
+}
+
+bitflags! {
+    #[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+    pub struct StatusC: u8 {
+        const IRQF = 1 << 7;
+        const PF = 1 << 6;
+        const AF = 1 << 5;
+        const UF = 1 << 4;
+    }
+}
+
+const STATUS_D_VRT: u8 = 1 << 7;
+
+fn realtime() -> timespec {
+    let mut ts = MaybeUninit::uninit();
+    unsafe { clock_gettime(CLOCK_REALTIME, ts.as_mut_ptr()) };
+    unsafe { ts.assume_init() }
+}
+
+// http://howardhinnant.github.io/date_algorithms.html
+fn civil_from_days(days: i64) -> (i64, u8, u8) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = doy - (153 * mp + 2) / 5 + 1;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 };
+    let y = yoe + era * 400 + (m <= 2) as i64;
+    (y, m as u8, d as u8)
+}
+
+fn days_from_civil(y: i64, m: u8, d: u8) -> i64 {
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = y.div_euclid(400);
+    let yoe = y.rem_euclid(400);
+    let m = m as i64;
+    let doy = (153 * (if m > 2 { m - 3 } else { m + 9 }) + 2) / 5 + d as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+#[derive(Debug)]
+struct RtcRegs {
+    index: u8,
+    cmos: [u8; CMOS_SIZE],
+    // Guest time minus host time, in seconds.
+    offset: i64,
+    last_update: i64,
+}
+
+impl RtcRegs {
+    fn status_a(&self) -> StatusA {
+        StatusA::from_bits_retain(self.cmos[REG_STATUS_A as usize])
+    }
+
+    fn status_b(&self) -> StatusB {
+        StatusB::from_bits_retain(self.cmos[REG_STATUS_B as usize])
+    }
+
+    fn encode(&self, val: u8) -> u8 {
+        if self.status_b().contains(StatusB::DM) {
+            val
+        } else {
+            (val / 10) << 4 | (val % 10)
+        }
+    }
+
+    fn decode(&self, val: u8) -> u8 {
+        if self.status_b().contains(StatusB::DM) {
+            val
+        } else {
+            (val >> 4) * 10 + (val & 0xf)
+        }
+    }
+
+    fn encode_hour(&self, hour: u8) -> u8 {
+        if self.status_b().contains(StatusB::HOUR_24) {
+            return self.encode(hour);
+        }
+        let pm = if hour >= 12 { 0x80 } else { 0 };
+        let hour_12 = match hour % 12 {
+            0 => 12,
+            h => h,
+        };
+        self.encode(hour_12) | pm
+    }
+
+    fn decode_hour(&self, val: u8) -> u8 {
+        if self.status_b().contains(StatusB::HOUR_24) {
+            return self.decode(val);
+        }
+        let hour = self.decode(val & 0x7f) % 12;
+        if val & 0x80 != 0 {
+            hour + 12
+        } else {
+            hour
+        }
+    }
+
+    fn guest_time(&self) -> i64 {
+        realtime().tv_sec + self.offset
+    }
+
+    fn update_time(&mut self) {
+        let secs = self.guest_time();
+        let days = secs.div_euclid(86400);
+        let secs_of_day = secs.rem_euclid(86400);
+        let (year, month, day) = civil_from_days(days);
+        // 1970-01-01 is a Thursday, Sunday is 1.
+        let weekday = (days + 4).rem_euclid(7) + 1;
+        self.cmos[REG_SECONDS as usize] = self.encode((secs_of_day % 60) as u8);
+        self.cmos[REG_MINUTES as usize] = self.encode((secs_of_day / 60 % 60) as u8);
+        self.cmos[REG_HOURS as usize] = self.encode_hour((secs_of_day / 3600) as u8);
+        self.cmos[REG_WEEKDAY as usize] = self.encode(weekday as u8);
+        self.cmos[REG_DAY as usize] = self.encode(day);
+        self.cmos[REG_MONTH as usize] = self.encode(month);
+        self.cmos[REG_YEAR as usize] = self.encode((year % 100) as u8);
+        self.cmos[REG_CENTURY as usize] = self.encode((year / 100) as u8);
+    }
+
+    fn set_offset_from_cmos(&mut self) {
+        let read = |reg: u8| self.decode(self.cmos[reg as usize]) as i64;
+        let year = read(REG_CENTURY) * 100 + read(REG_YEAR);
+        let month = self.decode(self.cmos[REG_MONTH as usize]);
+        let day = self.decode(self.cmos[REG_DAY as usize]);
+        let hours = self.decode_hour(self.cmos[REG_HOURS as usize]) as i64;
+        let secs = days_from_civil(year, month, day) * 86400
+            + hours * 3600
+            + read(REG_MINUTES) * 60
+            + read(REG_SECONDS);
+        self.offset = secs - realtime().tv_sec;
+    }
+
+    fn alarm_matched(&self) -> bool {
+        [
+            (REG_SECONDS_ALARM, REG_SECONDS),
+            (REG_MINUTES_ALARM, REG_MINUTES),
+            (REG_HOURS_ALARM, REG_HOURS),
+        ]
+        .iter()
+        .all(|(alarm, reg)| {
+            let alarm = self.cmos[*alarm as usize];
+            alarm & ALARM_DONT_CARE == ALARM_DONT_CARE || alarm == self.cmos[*reg as usize]
+        })
+    }
+
+    /// Returns true if an interrupt should be sent to the guest.
+    fn tick(&mut self) -> bool {
+        let status_b = self.status_b();
+        let mut flags = StatusC::empty();
+        if status_b.contains(StatusB::PIE) {
+            flags |= StatusC::PF;
+        }
+        let now = self.guest_time();
+        if now != self.last_update && !status_b.contains(StatusB::SET) {
+            self.last_update = now;
+            self.update_time();
+            flags |= StatusC::UF;
+            if self.alarm_matched() {
+                flags |= StatusC::AF;
+            }
+        }
+        let mut status_c = StatusC::from_bits_retain(self.cmos[REG_STATUS_C as usize]);
+        status_c |= flags;
+        let enabled = StatusC::from_bits_retain(status_b.bits() & 0x70);
+        let fire = !(status_c & enabled).is_empty() && !status_c.contains(StatusC::IRQF);
+        if fire {
+            status_c |= StatusC::IRQF;
+        }
+        self.cmos[REG_STATUS_C as usize] = status_c.bits();
+        fire
+    }
+
+    fn timer_period(&self) -> Option<i64> {
+        let status_b = self.status_b();
+        let rate = (self.status_a() & StatusA::RATE).bits();
+        if status_b.contains(StatusB::PIE) && rate != 0 {
+            let rate = if rate < 3 { rate + 7 } else { rate };
+            Some(1_000_000_000 / (32768 >> (rate - 1)))
+        } else if status_b.intersects(StatusB::AIE | StatusB::UIE) {
+            Some(1_000_000_000)
+        } else {
+            None
+        }
+    }
+}
+
+const TOKEN_TIMER: Token = Token(0);
+const TOKEN_SHUTDOWN: Token = Token(1);
+
+struct RtcWorker<I> {
+    irq_sender: I,
+    regs: Arc<Mutex<RtcRegs>>,
+    timer: Arc<OwnedFd>,
+    poll: Poll,
+}
+
+impl<I> RtcWorker<I>
+where
+    I: IrqSender,
+{
+    fn do_work_inner(&mut self) -> Result<()> {
+        let timer_fd = self.timer.as_raw_fd();
+        self.poll
+            .registry()
+            .register(&mut SourceFd(&timer_fd), TOKEN_TIMER, Interest::READABLE)?;
+        let mut events = Events::with_capacity(4);
+        loop {
+            self.poll.poll(&mut events, None)?;
+            for event in events.iter() {
+                if event.token() == TOKEN_SHUTDOWN {
+                    return Ok(());
+                }
+                let mut expirations = 0u64;
+                let buf = &mut expirations as *mut u64 as *mut _;
+                match ffi!(unsafe { libc::read(timer_fd, buf, 8) }) {
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => continue,
+                    Err(e) => return Err(e),
+                    Ok(_) => {}
+                }
+                if !self.regs.lock().tick() {
+                    continue;
+                }
+                if let Err(e) = self.irq_sender.send() {
+                    log::error!("rtc: sending interrupt: {e:?}");
+                }
+            }
+        }
+    }
+
+    fn do_work(&mut self) {
+        log::trace!("rtc: start");
+        if let Err(e) = self.do_work_inner() {
+            log::error!("rtc: {e:?}")
+        } else {
+            log::trace!("rtc: done")
+        }
+    }
+}
+
+/// MC146818 real-time clock and CMOS memory.
+#[derive(Debug)]
+pub struct Rtc {
+    regs: Arc<Mutex<RtcRegs>>,
+    timer: Arc<OwnedFd>,
+    worker_thread: Option<JoinHandle<()>>,
+    exit_waker: Waker,
+}
+
+impl Rtc {
+    pub fn new<I>(irq_sender: I) -> Result<Self>
+    where
+        I: IrqSender,
+    {
+        let mut cmos = [0; CMOS_SIZE];
+        cmos[REG_STATUS_A as usize] = 0x26;
+        cmos[REG_STATUS_B as usize] = StatusB::HOUR_24.bits();
+        cmos[REG_STATUS_D as usize] = STATUS_D_VRT;
+        let regs = Arc::new(Mutex::new(RtcRegs {
+            index: 0,
+            cmos,
+            offset: 0,
+            last_update: 0,
+        }));
+        let fd = ffi!(unsafe { timerfd_create(CLOCK_MONOTONIC, TFD_NONBLOCK | TFD_CLOEXEC) })?;
+        let timer = Arc::new(unsafe { OwnedFd::from_raw_fd(fd) });
+        let poll = Poll::new()?;
+        let exit_waker = Waker::new(poll.registry(), TOKEN_SHUTDOWN)?;
+        let mut worker = RtcWorker {
+            irq_sender,
+            regs: regs.clone(),
+            timer: timer.clone(),
+            poll,
+        };
+        let worker_thread = std::thread::Builder::new()
+            .name("rtc".to_owned())
+            .spawn(move || worker.do_work())?;
+        Ok(Rtc {
+            regs,
+            timer,
+            worker_thread: Some(worker_thread),
+            exit_waker,
+        })
+    }
+
+    fn update_timer(&self, regs: &RtcRegs) {
+        let period = regs.timer_period().unwrap_or(0);
+        let ts = timespec {
+            tv_sec: (period / 1_000_000_000) as _,
+            tv_nsec: (period % 1_000_000_000) as _,
+        };
+        let spec = itimerspec {
+            it_interval: ts,
+            it_value: ts,
+        };
+        let ret = ffi!(unsafe { timerfd_settime(self.timer.as_raw_fd(), 0, &spec, null_mut()) });
+        if let Err(e) = ret {
+            log::error!("rtc: cannot set timer: {e:?}");
+        }
+    }
+
+    fn read_reg(&self, regs: &mut RtcRegs) -> u8 {
+        let index = regs.index;
+        match index {
+            REG_SECONDS | REG_MINUTES | REG_HOURS | REG_WEEKDAY | REG_DAY | REG_MONTH
+            | REG_YEAR | REG_CENTURY => {
+                if !regs.status_b().contains(StatusB::SET) {
+                    regs.update_time();
+                }
+                regs.cmos[index as usize]
+            }
+            REG_STATUS_A => {
+                let mut status_a = regs.status_a() & !StatusA::UIP;
+                // The update cycle takes 244 us at the end of each second.
+                if !regs.status_b().contains(StatusB::SET) && realtime().tv_nsec >= 999_756_000 {
+                    status_a |= StatusA::UIP;
+                }
+                status_a.bits()
+            }
+            REG_STATUS_C => std::mem::take(&mut regs.cmos[REG_STATUS_C as usize]),
+            _ => regs.cmos[index as usize],
+        }
+    }
+
+    fn write_reg(&self, regs: &mut RtcRegs, val: u8) {
+        let index = regs.index;
+        match index {
+            REG_SECONDS | REG_MINUTES | REG_HOURS | REG_WEEKDAY | REG_DAY | REG_MONTH
+            | REG_YEAR | REG_CENTURY => {
+                let set = regs.status_b().contains(StatusB::SET);
+                if !set {
+                    regs.update_time();
+                }
+                regs.cmos[index as usize] = val;
+                if !set {
+                    regs.set_offset_from_cmos();
+                }
+            }
+            REG_STATUS_A => {
+                regs.cmos[index as usize] = val & !StatusA::UIP.bits();
+                self.update_timer(regs);
+            }
+            REG_STATUS_B => {
+                let old = regs.status_b();
+                let mut new = StatusB::from_bits_retain(val);
+                if new.contains(StatusB::SET) {
+                    new.remove(StatusB::UIE);
+                }
+                // Time registers are interpreted in the format they were
+                // written in.
+                if old.contains(StatusB::SET) && !new.contains(StatusB::SET) {
+                    regs.set_offset_from_cmos();
+                }
+                regs.cmos[index as usize] = new.bits();
+                if new.contains(StatusB::SET) && !old.contains(StatusB::SET) {
+                    regs.update_time();
+                }
+                self.update_timer(regs);
+            }
+            REG_STATUS_C | REG_STATUS_D => {}
+            _ => regs.cmos[index as usize] = val,
+        }
+    }
+}
+
+impl Mmio for Rtc {
+    fn size(&self) -> u64 {
+        2
+    }
+
+    fn read(&self, offset: u64, _size: u8) -> mem::Result<u64> {
+        let mut regs = self.regs.lock();
+        let ret = match offset {
+            PORT_INDEX => 0xff,
+            PORT_DATA => self.read_reg(&mut regs),
+            _ => 0,
+        };
+        Ok(ret as u64)
+    }
+
+    fn write(&self, offset: u64, _size: u8, val: u64) -> mem::Result<Action> {
+        let mut regs = self.regs.lock();
+        match offset {
+            // Bit 7 of the index port controls NMI.
+            PORT_INDEX => regs.index = val as u8 & 0x7f,
+            PORT_DATA => self.write_reg(&mut regs, val as u8),
+            _ => {}
+        }
+        Ok(Action::None)
+    }
+}
+
+impl Drop for Rtc {
+    fn drop(&mut self) {
+        if let Err(e) = self.exit_waker.wake() {
+            log::error!("rtc: {e:?}");
+            return;
+        }
+        let Some(thread) = self.worker_thread.take() else {
+            return;
+        };
+        if let Err(e) = thread.join() {
+            log::error!("rtc: {e:?}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Arc;
+    use std::thread::sleep;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    use crate::device::rtc::{days_from_civil, Rtc, StatusB, StatusC};
+    use crate::hv::{IrqSender, Result};
+    use crate::mem::emulated::Mmio;
+
+    #[derive(Debug, Default)]
+    struct CountIrq(AtomicU32);
+
+    impl IrqSender for CountIrq {
+        fn send(&self) -> Result<()> {
+            self.0.fetch_add(1, Ordering::Relaxed);
+            Ok(())
+        }
+    }
+
+    fn read_reg(rtc: &Rtc, reg: u8) -> u8 {
+        rtc.write(0, 1, reg as u64).unwrap();
+        rtc.read(1, 1).unwrap() as u8
+    }
+
+    fn write_reg(rtc: &Rtc, reg: u8, val: u8) {
+        rtc.write(0, 1, reg as u64).unwrap();
+        rtc.write(1, 1, val as u64).unwrap();
+    }
+
+    fn bcd(val: u8) -> i64 {
+        ((val >> 4) * 10 + (val & 0xf)) as i64
+    }
+
+    fn read_time(rtc: &Rtc) -> i64 {
+        let sec = bcd(read_reg(rtc, 0x00));
+        let min = bcd(read_reg(rtc, 0x02));
+        let hour = bcd(read_reg(rtc, 0x04));
+        let day = bcd(read_reg(rtc, 0x07)) as u8;
+        let month = bcd(read_reg(rtc, 0x08)) as u8;
+        let year = bcd(read_reg(rtc, 0x32)) * 100 + bcd(read_reg(rtc, 0x09));
+        days_from_civil(year, month, day) * 86400 + hour * 3600 + min * 60 + sec
+    }
+
+    fn host_time() -> i64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64
+    }
+
+    #[test]
+    fn test_rtc_time() {
+        let rtc = Rtc::new(CountIrq::default()).unwrap();
+        let guest = read_time(&rtc);
+        assert!((guest - host_time()).abs() <= 1);
+
+        // binary mode, set the clock back by one day
+        write_reg(
+            &rtc,
+            0x0b,
+            (StatusB::SET | StatusB::HOUR_24 | StatusB::DM).bits(),
+        );
+        let day = read_reg(&rtc, 0x07);
+        write_reg(&rtc, 0x07, day - 1);
+        write_reg(&rtc, 0x0b, (StatusB::HOUR_24).bits());
+        let guest = read_time(&rtc);
+        let diff = host_time() - 86400 - guest;
+        if day > 1 {
+            assert!(diff.abs() <= 1, "diff = {diff}");
+        }
+        assert_eq!(read_reg(&rtc, 0x0d), 0x80);
+    }
+
+    #[test]
+    fn test_rtc_periodic_interrupt() {
+        let irq = Arc::new(CountIrq::default());
+        let rtc = Rtc::new(irq.clone()).unwrap();
+        // 1024 Hz
+        write_reg(&rtc, 0x0a, 0x26);
+        write_reg(&rtc, 0x0b, (StatusB::PIE | StatusB::HOUR_24).bits());
+        sleep(Duration::from_millis(20));
+        assert_eq!(irq.0.load(Ordering::Relaxed), 1);
+        write_reg(&rtc, 0x0b, StatusB::HOUR_24.bits());
+        let status_c = StatusC::from_bits_retain(read_reg(&rtc, 0x0c));
+        assert!(status_c.contains(StatusC::IRQF | StatusC::PF));
+        assert_eq!(read_reg(&rtc, 0x0c), 0);
+    }
+}
@@ -0,0 +1,1124 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! An xHCI host controller with a single root hub.
+//!
+//! The controller handles the command ring, the primary event ring, and the
+//! port registers. No USB device class is emulated yet: devices plugged
+//! with [`Xhci::plug_device`] show up as connections on root hub ports.
+
+use std::mem::size_of;
+use std::sync::Arc;
+
+use bitfield::bitfield;
+use bitflags::bitflags;
+use parking_lot::{Mutex, RwLock};
+use snafu::Snafu;
+use zerocopy::{AsBytes, FromBytes, FromZeroes};
+
+use crate::c_enum;
+use crate::errors::{trace_error, DebugTrace};
+use crate::hv::MsiSender;
+use crate::mem::emulated::{Action, Mmio};
+use crate::mem::mapped::RamBus;
+use crate::mem::{self, MemRange, MemRegion, MemRegionEntry, MemRegionType};
+use crate::pci::cap::{
+    MsixCap, MsixCapMmio, MsixCapOffset, MsixMsgCtrl, MsixPbaMmio, MsixTableMmio, PciCap,
+    PciCapHdr, PciCapId, PciCapList,
+};
+use crate::pci::config::{
+    CommonHeader, DeviceHeader, EmulatedConfig, HeaderType, PciConfig, BAR_MEM64, BAR_PREFETCHABLE,
+};
+use crate::pci::{self, Pci, PciBar};
+
+const XHCI_VENDOR_ID: u16 = 0x1b36;
+const XHCI_DEVICE_ID: u16 = 0x000d;
+
+pub const XHCI_NUM_PORTS: u8 = 4;
+const XHCI_MAX_SLOTS: u8 = 8;
+
+const BAR_SIZE: u64 = 0x4000;
+
+const CAP_LENGTH: u64 = 0x20;
+const OP_BASE: u64 = CAP_LENGTH;
+const PORT_BASE: u64 = OP_BASE + 0x400;
+const PORT_REGS_SIZE: u64 = 0x10;
+const XECP_BASE: u64 = 0x800;
+const RT_BASE: u64 = 0x1000;
+const INTR0_BASE: u64 = RT_BASE + 0x20;
+const DB_BASE: u64 = 0x2000;
+const REGS_SIZE: u64 = 0x3000;
+const MSIX_PBA_OFFSET: u64 = 0x3800;
+
+const HCIVERSION: u32 = 0x0100;
+const HCCPARAMS1_AC64: u32 = 1 << 0;
+
+/// Stops processing a command ring that keeps looping through link TRBs.
+const MAX_TRBS_PER_DOORBELL: usize = 256;
+
+/// Valid numbers of TRBs in an event ring segment.
+const ERST_SEGMENT_SIZE: std::ops::RangeInclusive<u32> = 16..=4096;
+
+#[trace_error]
+#[derive(Snafu, DebugTrace)]
+#[snafu(module, context(suffix(false)))]
+pub enum Error {
+    #[snafu(display("Invalid root hub port {port}"))]
+    InvalidPort { port: u8 },
+    #[snafu(display("A device is already attached to port {port}"))]
+    PortInUse { port: u8 },
+    #[snafu(display("Failed to access guest memory"), context(false))]
+    Memory { source: Box<mem::Error> },
+    #[snafu(display("Invalid event ring segment {index}: base {base:#x}, size {size}"))]
+    InvalidErstEntry { index: u32, base: u64, size: u32 },
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// The standard USB device descriptor.
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy, Default, FromBytes, FromZeroes, AsBytes)]
+pub struct UsbDeviceDescriptor {
+    pub length: u8,
+    pub descriptor_type: u8,
+    pub bcd_usb: u16,
+    pub device_class: u8,
+    pub device_subclass: u8,
+    pub device_protocol: u8,
+    pub max_packet_size0: u8,
+    pub vendor: u16,
+    pub product: u16,
+    pub bcd_device: u16,
+    pub i_manufacturer: u8,
+    pub i_product: u8,
+    pub i_serial_number: u8,
+    pub num_configurations: u8,
+}
+
+bitflags! {
+    #[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+    pub struct UsbCmd: u32 {
+        const RUN = 1 << 0;
+        const HCRST = 1 << 1;
+        const INTE = 1 << 2;
+        const HSEE = 1 << 3;
+    }
+}
+
+bitflags! {
+    #[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+    pub struct UsbSts: u32 {
+        const HCH = 1 << 0;
+        const HSE = 1 << 2;
+        const EINT = 1 << 3;
+        const PCD = 1 << 4;
+        const SRE = 1 << 10;
+        const CNR = 1 << 11;
+    }
+}
+
+impl UsbSts {
+    const RW1C: UsbSts = UsbSts::HSE
+        .union(UsbSts::EINT)
+        .union(UsbSts::PCD)
+        .union(UsbSts::SRE);
+}
+
+bitfield! {
+    #[derive(Copy, Clone, Default, PartialEq, Eq)]
+    pub struct PortSc(u32);
+    impl Debug;
+    pub ccs, set_ccs: 0;
+    pub ped, set_ped: 1;
+    pub pr, set_pr: 4;
+    pub pls, set_pls: 8, 5;
+    pub pp, set_pp: 9;
+    pub speed, set_speed: 13, 10;
+    pub csc, set_csc: 17;
+    pub pec, set_pec: 18;
+    pub prc, set_prc: 21;
+}
+
+impl PortSc {
+    /// Change bits, which are cleared by writing 1.
+    const RW1C: u32 = 0x00fe_0000;
+    /// Port wake bits, which are RW.
+    const WAKE: u32 = 0x0e00_0000;
+
+    const PLS_U0: u32 = 0;
+    const PLS_RX_DETECT: u32 = 5;
+    const PLS_POLLING: u32 = 7;
+
+    const SPEED_FULL: u32 = 1;
+    const SPEED_HIGH: u32 = 3;
+    const SPEED_SUPER: u32 = 4;
+}
+
+bitflags! {
+    #[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+    pub struct Iman: u32 {
+        const IP = 1 << 0;
+        const IE = 1 << 1;
+    }
+}
+
+const ERDP_EHB: u64 = 1 << 3;
+const CRCR_RCS: u64 = 1 << 0;
+const CRCR_CRR: u64 = 1 << 3;
+
+c_enum! {
+    pub struct TrbType(u8);
+    {
+        LINK = 6;
+        ENABLE_SLOT = 9;
+        DISABLE_SLOT = 10;
+        NO_OP_COMMAND = 23;
+        COMMAND_COMPLETION = 33;
+        PORT_STATUS_CHANGE = 34;
+        HOST_CONTROLLER = 37;
+    }
+}
+
+c_enum! {
+    pub struct CompletionCode(u8);
+    {
+        SUCCESS = 1;
+        TRB_ERROR = 5;
+        NO_SLOTS_AVAILABLE = 9;
+        SLOT_NOT_ENABLED = 11;
+        EVENT_RING_FULL_ERROR = 21;
+    }
+}
+
+bitfield! {
+    #[derive(Copy, Clone, Default, FromBytes, FromZeroes, AsBytes)]
+    #[repr(C)]
+    pub struct TrbControl(u32);
+    impl Debug;
+    pub cycle, set_cycle: 0;
+    pub toggle_cycle, _: 1;
+    pub u8, type_, set_type: 15, 10;
+    pub u8, slot_id, set_slot_id: 31, 24;
+}
+
+/// A transfer request block.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, FromBytes, FromZeroes, AsBytes)]
+pub struct Trb {
+    pub param: u64,
+    pub status: u32,
+    pub control: TrbControl,
+}
+
+impl Trb {
+    fn event(type_: TrbType, param: u64, code: CompletionCode) -> Self {
+        let mut control = TrbControl::default();
+        control.set_type(type_.raw());
+        Trb {
+            param,
+            status: (code.raw() as u32) << 24,
+            control,
+        }
+    }
+}
+
+/// An entry of the event ring segment table.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, FromBytes, FromZeroes, AsBytes)]
+struct ErstEntry {
+    base: u64,
+    size: u32,
+    _reserved: u32,
+}
+
+#[derive(Debug, Default)]
+struct Port {
+    portsc: PortSc,
+    device: Option<UsbDeviceDescriptor>,
+}
+
+#[derive(Debug, Default)]
+struct Interrupter {
+    iman: Iman,
+    imod: u32,
+    erstsz: u32,
+    erstba: u64,
+    erdp: u64,
+    segment: u32,
+    enqueue: u64,
+    remaining: u32,
+    cycle: bool,
+    /// Set after an Event Ring Full Error is posted. Events are dropped
+    /// until the driver moves ERDP.
+    full: bool,
+}
+
+#[derive(Debug, Default)]
+struct XhciRegs {
+    usbcmd: UsbCmd,
+    usbsts: UsbSts,
+    dnctrl: u32,
+    crcr: u64,
+    dcbaap: u64,
+    config: u32,
+    cmd_dequeue: u64,
+    cmd_cycle: bool,
+    slots: u64,
+    ports: [Port; XHCI_NUM_PORTS as usize],
+    intr: Interrupter,
+}
+
+fn set_low32(reg: &mut u64, val: u32) {
+    *reg = (*reg & !0xffff_ffff) | val as u64;
+}
+
+fn set_high32(reg: &mut u64, val: u32) {
+    *reg = (*reg & 0xffff_ffff) | (val as u64) << 32;
+}
+
+#[derive(Debug)]
+struct XhciMmio<M>
+where
+    M: MsiSender,
+{
+    name: Arc<String>,
+    memory: Arc<RamBus>,
+    regs: Mutex<XhciRegs>,
+    msix_table: Arc<MsixTableMmio<M>>,
+}
+
+impl<M> XhciMmio<M>
+where
+    M: MsiSender,
+{
+    fn reset(&self, regs: &mut XhciRegs) {
+        let ports = std::mem::take(&mut regs.ports);
+        *regs = XhciRegs {
+            usbsts: UsbSts::HCH,
+            ..Default::default()
+        };
+        for (port, old) in regs.ports.iter_mut().zip(ports) {
+            port.portsc.set_pp(true);
+            port.portsc.set_pls(PortSc::PLS_RX_DETECT);
+            if let Some(desc) = old.device {
+                Self::connect(port, desc);
+            }
+        }
+    }
+
+    fn connect(port: &mut Port, desc: UsbDeviceDescriptor) {
+        let bcd_usb = desc.bcd_usb;
+        let speed = match bcd_usb {
+            0x0300.. => PortSc::SPEED_SUPER,
+            0x0200.. => PortSc::SPEED_HIGH,
+            _ => PortSc::SPEED_FULL,
+        };
+        port.portsc.set_ccs(true);
+        port.portsc.set_csc(true);
+        port.portsc.set_speed(speed);
+        port.portsc.set_pls(PortSc::PLS_POLLING);
+        port.device = Some(desc);
+    }
+
+    fn read_cap(&self, offset: u64) -> u32 {
+        match offset {
+            0x00 => CAP_LENGTH as u32 | HCIVERSION << 16,
+            // HCSPARAMS1
+            0x04 => XHCI_MAX_SLOTS as u32 | 1 << 8 | (XHCI_NUM_PORTS as u32) << 24,
+            // HCSPARAMS2, HCSPARAMS3: no scratchpad buffers, one ERST entry
+            0x08 | 0x0c => 0,
+            // HCCPARAMS1
+            0x10 => HCCPARAMS1_AC64 | ((XECP_BASE >> 2) as u32) << 16,
+            // DBOFF
+            0x14 => DB_BASE as u32,
+            // RTSOFF
+            0x18 => RT_BASE as u32,
+            _ => 0,
+        }
+    }
+
+    /// Reads the supported protocol capability, which declares all ports
+    /// as USB 2.0 ports.
+    fn read_xecp(&self, offset: u64) -> u32 {
+        match offset {
+            0x0 => 0x02 | 0x02 << 24,
+            0x4 => u32::from_le_bytes(*b"USB "),
+            0x8 => 1 | (XHCI_NUM_PORTS as u32) << 8,
+            _ => 0,
+        }
+    }
+
+    fn read_op(&self, regs: &XhciRegs, offset: u64) -> u32 {
+        match offset {
+            0x00 => regs.usbcmd.bits(),
+            0x04 => regs.usbsts.bits(),
+            // PAGESIZE: 4KiB
+            0x08 => 1,
+            0x14 => regs.dnctrl,
+            // Only CRR is readable in CRCR.
+            0x18 => (regs.crcr & CRCR_CRR) as u32,
+            0x1c => 0,
+            0x30 => regs.dcbaap as u32,
+            0x34 => (regs.dcbaap >> 32) as u32,
+            0x38 => regs.config,
+            _ => 0,
+        }
+    }
+
+    fn read_intr(&self, regs: &XhciRegs, offset: u64) -> u32 {
+        let intr = &regs.intr;
+        match offset {
+            0x00 => intr.iman.bits(),
+            0x04 => intr.imod,
+            0x08 => intr.erstsz,
+            0x10 => intr.erstba as u32,
+            0x14 => (intr.erstba >> 32) as u32,
+            0x18 => intr.erdp as u32,
+            0x1c => (intr.erdp >> 32) as u32,
+            _ => 0,
+        }
+    }
+
+    fn read_dword(&self, offset: u64) -> u32 {
+        let regs = self.regs.lock();
+        match offset {
+            0..CAP_LENGTH => self.read_cap(offset),
+            OP_BASE..PORT_BASE => self.read_op(&regs, offset - OP_BASE),
+            PORT_BASE..XECP_BASE => {
+                let index = ((offset - PORT_BASE) / PORT_REGS_SIZE) as usize;
+                match (regs.ports.get(index), offset % PORT_REGS_SIZE) {
+                    (Some(port), 0) => port.portsc.0,
+                    _ => 0,
+                }
+            }
+            XECP_BASE..RT_BASE => self.read_xecp(offset - XECP_BASE),
+            INTR0_BASE..DB_BASE => self.read_intr(&regs, offset - INTR0_BASE),
+            _ => 0,
+        }
+    }
+
+    fn write_usbcmd(&self, regs: &mut XhciRegs, val: u32) {
+        let cmd = UsbCmd::from_bits_truncate(val);
+        if cmd.contains(UsbCmd::HCRST) {
+            log::info!("{}: host controller reset", self.name);
+            self.reset(regs);
+            return;
+        }
+        regs.usbcmd = cmd;
+        regs.usbsts.set(UsbSts::HCH, !cmd.contains(UsbCmd::RUN));
+    }
+
+    fn write_op(&self, regs: &mut XhciRegs, offset: u64, val: u32) {
+        match offset {
+            0x00 => self.write_usbcmd(regs, val),
+            0x04 => {
+                let clear = UsbSts::from_bits_truncate(val) & UsbSts::RW1C;
+                regs.usbsts.remove(clear);
+            }
+            0x14 => regs.dnctrl = val & 0xffff,
+            0x18 | 0x1c => {
+                if regs.crcr & CRCR_CRR != 0 {
+                    return;
+                }
+                if offset == 0x18 {
+                    set_low32(&mut regs.crcr, val);
+                } else {
+                    set_high32(&mut regs.crcr, val);
+                }
+                regs.cmd_dequeue = regs.crcr & !0x3f;
+                regs.cmd_cycle = regs.crcr & CRCR_RCS != 0;
+            }
+            0x30 => set_low32(&mut regs.dcbaap, val & !0x3f),
+            0x34 => set_high32(&mut regs.dcbaap, val),
+            0x38 => regs.config = val & 0xff,
+            _ => log::error!("{}: write {val:#x} to op register {offset:#x}", self.name),
+        }
+    }
+
+    fn write_portsc(&self, regs: &mut XhciRegs, index: usize, val: u32) {
+        let Some(port) = regs.ports.get_mut(index) else {
+            return;
+        };
+        let written = PortSc(val);
+        let portsc = &mut port.portsc;
+        portsc.0 &= !(val & PortSc::RW1C);
+        portsc.0 = (portsc.0 & !PortSc::WAKE) | (val & PortSc::WAKE);
+        portsc.set_pp(written.pp());
+        if written.ped() {
+            // Writing 1 to PED disables the port.
+            portsc.set_ped(false);
+        }
+        if !written.pr() || !portsc.ccs() {
+            return;
+        }
+        portsc.set_ped(true);
+        portsc.set_prc(true);
+        portsc.set_pls(PortSc::PLS_U0);
+        self.port_status_change(regs, index);
+    }
+
+    fn write_intr(&self, regs: &mut XhciRegs, offset: u64, val: u32) {
+        let intr = &mut regs.intr;
+        match offset {
+            0x00 => {
+                let iman = Iman::from_bits_truncate(val);
+                if iman.contains(Iman::IP) {
+                    intr.iman.remove(Iman::IP);
+                }
+                intr.iman.set(Iman::IE, iman.contains(Iman::IE));
+            }
+            0x04 => intr.imod = val,
+            0x08 => intr.erstsz = val & 0xffff,
+            0x10 | 0x14 => {
+                if offset == 0x10 {
+                    set_low32(&mut intr.erstba, val & !0x3f);
+                } else {
+                    set_high32(&mut intr.erstba, val);
+                }
+                intr.segment = 0;
+                intr.cycle = true;
+                if let Err(e) = self.load_segment(intr) {
+                    log::error!("{}: failed to load the event ring: {e}", self.name);
+                    regs.usbsts.insert(UsbSts::HSE);
+                }
+            }
+            0x18 | 0x1c => {
+                let ehb = intr.erdp & ERDP_EHB;
+                if offset == 0x18 {
+                    set_low32(&mut intr.erdp, val);
+                } else {
+                    set_high32(&mut intr.erdp, val);
+                }
+                if val as u64 & ERDP_EHB != 0 && offset == 0x18 {
+                    intr.erdp &= !ERDP_EHB;
+                } else {
+                    intr.erdp = (intr.erdp & !ERDP_EHB) | ehb;
+                }
+                intr.full = false;
+            }
+            _ => {}
+        }
+    }
+
+    fn write_doorbell(&self, regs: &mut XhciRegs, slot: u64, val: u32) {
+        if slot != 0 {
+            log::warn!("{}: slot {slot}: doorbell {val:#x} ignored", self.name);
+            return;
+        }
+        if !regs.usbcmd.contains(UsbCmd::RUN) {
+            return;
+        }
+        regs.crcr |= CRCR_CRR;
+        if let Err(e) = self.process_commands(regs) {
+            log::error!("{}: failed to process commands: {e}", self.name);
+            regs.usbsts.insert(UsbSts::HSE);
+        }
+    }
+
+    /// Reads entry `index` of the event ring segment table and returns the
+    /// base address and the number of TRBs of the segment.
+    fn read_segment(&self, intr: &Interrupter, index: u32) -> Result<(u64, u32)> {
+        let addr = intr.erstba + (index as u64) * size_of::<ErstEntry>() as u64;
+        let entry: ErstEntry = self.memory.read(addr)?;
+        let base = entry.base & !0x3f;
+        let size = entry.size & 0xffff;
+        if base == 0 || !ERST_SEGMENT_SIZE.contains(&size) {
+            return error::InvalidErstEntry { index, base, size }.fail();
+        }
+        Ok((base, size))
+    }
+
+    fn load_segment(&self, intr: &mut Interrupter) -> Result<()> {
+        intr.remaining = 0;
+        let (base, size) = self.read_segment(intr, intr.segment)?;
+        intr.enqueue = base;
+        intr.remaining = size;
+        Ok(())
+    }
+
+    /// Returns where the event after the one at the enqueue pointer goes.
+    fn next_enqueue(&self, intr: &Interrupter) -> Result<u64> {
+        if intr.remaining > 1 {
+            return Ok(intr.enqueue + size_of::<Trb>() as u64);
+        }
+        let next = if intr.segment + 1 >= intr.erstsz {
+            0
+        } else {
+            intr.segment + 1
+        };
+        let (base, _) = self.read_segment(intr, next)?;
+        Ok(base)
+    }
+
+    fn post_event(&self, regs: &mut XhciRegs, mut trb: Trb) -> Result<()> {
+        let intr = &mut regs.intr;
+        if intr.erstsz == 0 {
+            log::warn!("{}: event ring is not set up, dropping {trb:x?}", self.name);
+            return Ok(());
+        }
+        if intr.full {
+            log::warn!("{}: event ring is full, dropping {trb:x?}", self.name);
+            return Ok(());
+        }
+        if intr.remaining == 0 {
+            intr.segment += 1;
+            if intr.segment >= intr.erstsz {
+                intr.segment = 0;
+                intr.cycle = !intr.cycle;
+            }
+            self.load_segment(intr)?;
+        }
+        // The last free TRB before the dequeue pointer is kept for the
+        // Event Ring Full Error.
+        if self.next_enqueue(intr)? == intr.erdp & !0xf {
+            log::warn!("{}: event ring is full, dropping {trb:x?}", self.name);
+            trb = Trb::event(
+                TrbType::HOST_CONTROLLER,
+                0,
+                CompletionCode::EVENT_RING_FULL_ERROR,
+            );
+            intr.full = true;
+        }
+        trb.control.set_cycle(intr.cycle);
+        self.memory.write(intr.enqueue, &trb)?;
+        intr.enqueue += size_of::<Trb>() as u64;
+        intr.remaining -= 1;
+
+        intr.iman.insert(Iman::IP);
+        intr.erdp |= ERDP_EHB;
+        regs.usbsts.insert(UsbSts::EINT);
+        if intr.iman.contains(Iman::IE) && regs.usbcmd.contains(UsbCmd::INTE) {
+            self.send_msi();
+        }
+        Ok(())
+    }
+
+    fn send_msi(&self) {
+        let entries = self.msix_table.entries.read();
+        let Some(entry) = entries.first() else {
+            return;
+        };
+        let entry = entry.read();
+        if entry.get_masked() {
+            self.msix_table.set_pending(0);
+            return;
+        }
+        let addr = ((entry.get_addr_hi() as u64) << 32) | entry.get_addr_lo() as u64;
+        let data = entry.get_data();
+        if let Err(e) = self.msix_table.msi_sender.send(addr, data) {
+            log::error!("{}: send msi data = {data:#x} to {addr:#x}: {e}", self.name)
+        }
+    }
+
+    fn port_status_change(&self, regs: &mut XhciRegs, index: usize) {
+        regs.usbsts.insert(UsbSts::PCD);
+        if !regs.usbcmd.contains(UsbCmd::RUN) {
+            return;
+        }
+        let port_id = index as u64 + 1;
+        let event = Trb::event(
+            TrbType::PORT_STATUS_CHANGE,
+            port_id << 24,
+            CompletionCode::SUCCESS,
+        );
+        if let Err(e) = self.post_event(regs, event) {
+            log::error!("{}: port {port_id}: {e}", self.name);
+            regs.usbsts.insert(UsbSts::HSE);
+        }
+    }
+
+    fn handle_command(&self, regs: &mut XhciRegs, trb: &Trb) -> (CompletionCode, u8) {
+        match TrbType(trb.control.type_()) {
+            TrbType::NO_OP_COMMAND => (CompletionCode::SUCCESS, 0),
+            TrbType::ENABLE_SLOT => {
+                let free = (1..=XHCI_MAX_SLOTS).find(|id| regs.slots & (1 << id) == 0);
+                match free {
+                    Some(id) => {
+                        regs.slots |= 1 << id;
+                        (CompletionCode::SUCCESS, id)
+                    }
+                    None => (CompletionCode::NO_SLOTS_AVAILABLE, 0),
+                }
+            }
+            TrbType::DISABLE_SLOT => {
+                let id = trb.control.slot_id();
+                if id == 0 || id > XHCI_MAX_SLOTS || regs.slots & (1 << id) == 0 {
+                    (CompletionCode::SLOT_NOT_ENABLED, id)
+                } else {
+                    regs.slots &= !(1 << id);
+                    (CompletionCode::SUCCESS, id)
+                }
+            }
+            type_ => {
+                log::error!("{}: unsupported command {type_:?}", self.name);
+                (CompletionCode::TRB_ERROR, 0)
+            }
+        }
+    }
+
+    fn process_commands(&self, regs: &mut XhciRegs) -> Result<()> {
+        for _ in 0..MAX_TRBS_PER_DOORBELL {
+            let trb: Trb = self.memory.read(regs.cmd_dequeue)?;
+            if trb.control.cycle() != regs.cmd_cycle {
+                return Ok(());
+            }
+            if TrbType(trb.control.type_()) == TrbType::LINK {
+                regs.cmd_dequeue = trb.param & !0xf;
+                if trb.control.toggle_cycle() {
+                    regs.cmd_cycle = !regs.cmd_cycle;
+                }
+                continue;
+            }
+            let (code, slot_id) = self.handle_command(regs, &trb);
+            let mut event = Trb::event(TrbType::COMMAND_COMPLETION, regs.cmd_dequeue, code);
+            event.control.set_slot_id(slot_id);
+            self.post_event(regs, event)?;
+            regs.cmd_dequeue += size_of::<Trb>() as u64;
+        }
+        log::error!("{}: too many TRBs in the command ring", self.name);
+        Ok(())
+    }
+}
+
+impl<M> Mmio for XhciMmio<M>
+where
+    M: MsiSender,
+{
+    fn size(&self) -> u64 {
+        REGS_SIZE
+    }
+
+    fn read(&self, offset: u64, size: u8) -> mem::Result<u64> {
+        let val = match size {
+            8 => self.read_dword(offset) as u64 | (self.read_dword(offset + 4) as u64) << 32,
+            1 | 2 | 4 => {
+                let shift = (offset & 0b11) * 8;
+                let dword = self.read_dword(offset & !0b11) as u64 >> shift;
+                dword & (u64::MAX >> (64 - 8 * size as u64))
+            }
+            _ => 0,
+        };
+        Ok(val)
+    }
+
+    fn write(&self, offset: u64, size: u8, val: u64) -> mem::Result<Action> {
+        if size == 8 {
+            self.write(offset, 4, val & 0xffff_ffff)?;
+            return self.write(offset + 4, 4, val >> 32);
+        }
+        if size != 4 || offset & 0b11 != 0 {
+            log::error!("{}: unaligned write to {offset:#x}", self.name);
+            return Ok(Action::None);
+        }
+        let val = val as u32;
+        let mut regs = self.regs.lock();
+        match offset {
+            OP_BASE..PORT_BASE => self.write_op(&mut regs, offset - OP_BASE, val),
+            PORT_BASE..XECP_BASE => {
+                let index = ((offset - PORT_BASE) / PORT_REGS_SIZE) as usize;
+                if offset.is_multiple_of(PORT_REGS_SIZE) {
+                    self.write_portsc(&mut regs, index, val)
+                }
+            }
+            INTR0_BASE..DB_BASE => self.write_intr(&mut regs, offset - INTR0_BASE, val),
+            DB_BASE..REGS_SIZE => self.write_doorbell(&mut regs, (offset - DB_BASE) / 4, val),
+            _ => log::error!("{}: write {val:#x} to {offset:#x}", self.name),
+        }
+        Ok(Action::None)
+    }
+}
+
+#[derive(Debug)]
+pub struct Xhci<M>
+where
+    M: MsiSender,
+{
+    config: Arc<EmulatedConfig>,
+    regs: Arc<XhciMmio<M>>,
+}
+
+impl<M> Xhci<M>
+where
+    M: MsiSender,
+{
+    pub fn new(name: Arc<String>, memory: Arc<RamBus>, msi_sender: M) -> pci::Result<Self> {
+        let header = DeviceHeader {
+            common: CommonHeader {
+                vendor: XHCI_VENDOR_ID,
+                device: XHCI_DEVICE_ID,
+                revision: 1,
+                header_type: HeaderType::Device as u8,
+                class: 0x0c,
+                subclass: 0x03,
+                prog_if: 0x30,
+                ..Default::default()
+            },
+            bars: [BAR_MEM64 | BAR_PREFETCHABLE, 0, 0, 0, 0, 0],
+            ..Default::default()
+        };
+        let msix_cap = MsixCap {
+            header: PciCapHdr {
+                id: PciCapId::Msix as u8,
+                ..Default::default()
+            },
+            control: MsixMsgCtrl(0),
+            table_offset: MsixCapOffset(REGS_SIZE as u32),
+            pba_offset: MsixCapOffset(MSIX_PBA_OFFSET as u32),
+        };
+        let caps: Vec<Box<dyn PciCap>> = vec![Box::new(MsixCapMmio {
+            cap: Arc::new(RwLock::new(msix_cap)),
+        })];
+        let cap_list = PciCapList::try_from(caps)?;
+
+        let msix_table = Arc::new(MsixTableMmio::new(1, 1, msi_sender));
+        let msix_pba = Arc::new(MsixPbaMmio {
+            table: msix_table.clone(),
+        });
+        let regs = Arc::new(XhciMmio {
+            name,
+            memory,
+            regs: Mutex::new(XhciRegs::default()),
+            msix_table: msix_table.clone(),
+        });
+        regs.reset(&mut regs.regs.lock());
+        let bar0 = MemRegion {
+            ranges: vec![
+                MemRange::emulated(regs.clone()),
+                MemRange::Span(MSIX_PBA_OFFSET - REGS_SIZE - msix_table.size()),
+                MemRange::emulated(msix_table.clone()),
+                MemRange::emulated(msix_pba.clone()),
+                MemRange::Span(BAR_SIZE - MSIX_PBA_OFFSET - msix_pba.size()),
+            ],
+            entries: vec![MemRegionEntry {
+                size: BAR_SIZE,
+                type_: MemRegionType::Hidden,
+            }],
+            callbacks: Mutex::new(vec![]),
+            dirty_callbacks: Mutex::new(vec![]),
+        };
+        let bar_masks = [!(BAR_SIZE as u32 - 1), 0xffff_ffff, 0, 0, 0, 0];
+        let mut bars = [const { PciBar::Empty }; 6];
+        bars[0] = PciBar::Mem(Arc::new(bar0));
+        let config = EmulatedConfig::new_device(header, bar_masks, bars, cap_list);
+        Ok(Xhci {
+            config: Arc::new(config),
+            regs,
+        })
+    }
+
+    /// Connects a device to root hub `port`, counting from 1, and notifies
+    /// the driver with a port status change event.
+    pub fn plug_device(&self, port: u8, desc: UsbDeviceDescriptor) -> Result<()> {
+        let index = port.wrapping_sub(1) as usize;
+        let mut regs = self.regs.regs.lock();
+        let Some(p) = regs.ports.get_mut(index) else {
+            return error::InvalidPort { port }.fail();
+        };
+        if p.device.is_some() {
+            return error::PortInUse { port }.fail();
+        }
+        XhciMmio::<M>::connect(p, desc);
+        self.regs.port_status_change(&mut regs, index);
+        Ok(())
+    }
+}
+
+impl<M> Pci for Xhci<M>
+where
+    M: MsiSender,
+{
+    fn config(&self) -> Arc<dyn PciConfig> {
+        self.config.clone()
+    }
+
+    fn reset(&self) -> pci::Result<()> {
+        self.regs.reset(&mut self.regs.regs.lock());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::mem::size_of;
+    use std::sync::Arc;
+
+    use assert_matches::assert_matches;
+
+    use crate::device::xhci::{
+        CompletionCode, Error, PortSc, Trb, TrbControl, TrbType, UsbDeviceDescriptor, UsbSts, Xhci,
+        CAP_LENGTH, DB_BASE, INTR0_BASE, OP_BASE, PORT_BASE, XHCI_NUM_PORTS,
+    };
+    use crate::hv::test::{FakeMsiSender, FakeVmMemory};
+    use crate::mem::emulated::Mmio;
+    use crate::mem::mapped::{ArcMemPages, RamBus};
+    use crate::mem::MemRegionType;
+    use crate::pci::config::ConfigHeader;
+    use crate::pci::Pci;
+
+    const ERST_ADDR: u64 = 0x0;
+    const EVENT_RING_ADDR: u64 = 0x1000;
+    const CMD_RING_ADDR: u64 = 0x2000;
+
+    fn setup() -> (Arc<RamBus>, Xhci<FakeMsiSender>) {
+        let memory = Arc::new(RamBus::new(FakeVmMemory));
+        let pages = ArcMemPages::from_anonymous(0x4000, None).unwrap();
+        memory.add(0, pages, MemRegionType::Ram, false).unwrap();
+        // One event ring segment of 16 TRBs.
+        memory.write(ERST_ADDR, &[EVENT_RING_ADDR, 16]).unwrap();
+        let name = Arc::new("xhci".to_owned());
+        let xhci = Xhci::new(name, memory.clone(), FakeMsiSender::default()).unwrap();
+
+        let regs = &xhci.regs;
+        // MSI-X entry 0: unmasked
+        regs.msix_table.write(0, 4, 0xfee0_0000).unwrap();
+        regs.msix_table.write(8, 4, 0x30).unwrap();
+        regs.msix_table.write(12, 4, 0).unwrap();
+        // ERSTSZ, ERSTBA, IMAN.IE
+        regs.write(INTR0_BASE + 0x08, 4, 1).unwrap();
+        regs.write(INTR0_BASE + 0x10, 8, ERST_ADDR).unwrap();
+        regs.write(INTR0_BASE, 4, 0b10).unwrap();
+        // CRCR with RCS = 1
+        regs.write(OP_BASE + 0x18, 8, CMD_RING_ADDR | 1).unwrap();
+        // USBCMD: RUN | INTE
+        regs.write(OP_BASE, 4, 0b101).unwrap();
+        (memory, xhci)
+    }
+
+    fn command(type_: TrbType, slot_id: u8) -> Trb {
+        let mut control = TrbControl::default();
+        control.set_cycle(true);
+        control.set_type(type_.raw());
+        control.set_slot_id(slot_id);
+        Trb {
+            control,
+            ..Default::default()
+        }
+    }
+
+    fn read_event(memory: &RamBus, index: u64) -> Trb {
+        memory
+            .read(EVENT_RING_ADDR + index * size_of::<Trb>() as u64)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_xhci_registers() {
+        let (_, xhci) = setup();
+        let config = xhci.config();
+        let data = config.get_header().data.read();
+        let ConfigHeader::Device(header) = &data.header;
+        let common = &header.common;
+        assert_eq!(
+            (common.class, common.subclass, common.prog_if),
+            (0x0c, 0x03, 0x30)
+        );
+        drop(data);
+
+        let regs = &xhci.regs;
+        assert_eq!(regs.read(0, 1).unwrap(), CAP_LENGTH);
+        assert_eq!(regs.read(2, 2).unwrap(), 0x0100);
+        assert_eq!(regs.read(0x04, 4).unwrap() >> 24, XHCI_NUM_PORTS as u64);
+        assert_eq!(regs.read(0x14, 4).unwrap(), DB_BASE);
+        // USBSTS.HCH is cleared once the controller runs.
+        assert_eq!(regs.read(OP_BASE + 0x04, 4).unwrap() & 1, 0);
+
+        for port in 0..XHCI_NUM_PORTS as u64 {
+            let portsc = PortSc(regs.read(PORT_BASE + 0x10 * port, 4).unwrap() as u32);
+            assert!(portsc.pp());
+            assert!(!portsc.ccs());
+        }
+
+        // Host controller reset
+        regs.write(OP_BASE, 4, 0b10).unwrap();
+        assert_eq!(regs.read(OP_BASE, 4).unwrap(), 0);
+        assert_eq!(regs.read(OP_BASE + 0x04, 4).unwrap() & 1, 1);
+    }
+
+    #[test]
+    fn test_xhci_command_ring() {
+        let (memory, xhci) = setup();
+        let mut link = command(TrbType::LINK, 0);
+        link.param = CMD_RING_ADDR;
+        // toggle cycle
+        link.control.0 |= 1 << 1;
+        let cmds = [
+            command(TrbType::NO_OP_COMMAND, 0),
+            command(TrbType::ENABLE_SLOT, 0),
+            command(TrbType::DISABLE_SLOT, 2),
+            link,
+        ];
+        memory.write(CMD_RING_ADDR, &cmds).unwrap();
+        xhci.regs.write(DB_BASE, 4, 0).unwrap();
+
+        let expected = [
+            (CompletionCode::SUCCESS, 0),
+            (CompletionCode::SUCCESS, 1),
+            (CompletionCode::SLOT_NOT_ENABLED, 2),
+        ];
+        for (index, (code, slot_id)) in expected.into_iter().enumerate() {
+            let event = read_event(&memory, index as u64);
+            assert_eq!(event.control.type_(), TrbType::COMMAND_COMPLETION.raw());
+            assert!(event.control.cycle());
+            assert_eq!(event.param, CMD_RING_ADDR + 16 * index as u64);
+            assert_eq!((event.status >> 24) as u8, code.raw());
+            assert_eq!(event.control.slot_id(), slot_id);
+        }
+        assert_eq!(xhci.regs.msix_table.msi_sender.msis.lock().len(), 3);
+
+        // After the link TRB, the ring continues with cycle state 0, so the
+        // stale commands are not processed again.
+        xhci.regs.write(DB_BASE, 4, 0).unwrap();
+        assert_eq!(xhci.regs.msix_table.msi_sender.msis.lock().len(), 3);
+        let mut disable = command(TrbType::DISABLE_SLOT, 1);
+        disable.control.set_cycle(false);
+        memory.write(CMD_RING_ADDR, &disable).unwrap();
+        xhci.regs.write(DB_BASE, 4, 0).unwrap();
+
+        let event = read_event(&memory, 3);
+        assert!(event.control.cycle());
+        assert_eq!((event.status >> 24) as u8, CompletionCode::SUCCESS.raw());
+        assert_eq!(xhci.regs.msix_table.msi_sender.msis.lock().len(), 4);
+    }
+
+    #[test]
+    fn test_xhci_disable_invalid_slot() {
+        let (memory, xhci) = setup();
+        let cmds = [
+            command(TrbType::DISABLE_SLOT, 9),
+            command(TrbType::DISABLE_SLOT, 64),
+            command(TrbType::DISABLE_SLOT, 255),
+        ];
+        memory.write(CMD_RING_ADDR, &cmds).unwrap();
+        xhci.regs.write(DB_BASE, 4, 0).unwrap();
+
+        for (index, cmd) in cmds.iter().enumerate() {
+            let event = read_event(&memory, index as u64);
+            let code = (event.status >> 24) as u8;
+            assert_eq!(code, CompletionCode::SLOT_NOT_ENABLED.raw());
+            assert_eq!(event.control.slot_id(), cmd.control.slot_id());
+        }
+    }
+
+    #[test]
+    fn test_xhci_invalid_erst() {
+        let (memory, xhci) = setup();
+        let usbsts = OP_BASE + 0x04;
+        for (base, size) in [(EVENT_RING_ADDR, 0), (EVENT_RING_ADDR, 8), (0, 16)] {
+            memory.write(ERST_ADDR, &[base, size]).unwrap();
+            xhci.regs.write(INTR0_BASE + 0x10, 8, ERST_ADDR).unwrap();
+            let status = UsbSts::from_bits_retain(xhci.regs.read(usbsts, 4).unwrap() as u32);
+            assert!(status.contains(UsbSts::HSE), "{base:#x} {size}");
+            xhci.regs
+                .write(usbsts, 4, UsbSts::HSE.bits() as u64)
+                .unwrap();
+
+            // Commands do not write past a segment that was not loaded.
+            memory
+                .write(CMD_RING_ADDR, &command(TrbType::NO_OP_COMMAND, 0))
+                .unwrap();
+            xhci.regs.write(DB_BASE, 4, 0).unwrap();
+            let status = UsbSts::from_bits_retain(xhci.regs.read(usbsts, 4).unwrap() as u32);
+            assert!(status.contains(UsbSts::HSE), "{base:#x} {size}");
+            xhci.regs
+                .write(usbsts, 4, UsbSts::HSE.bits() as u64)
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn test_xhci_event_ring_full() {
+        let (memory, xhci) = setup();
+        // The driver has not consumed any event.
+        xhci.regs
+            .write(INTR0_BASE + 0x18, 8, EVENT_RING_ADDR)
+            .unwrap();
+        let cmds = [command(TrbType::NO_OP_COMMAND, 0); 17];
+        memory.write(CMD_RING_ADDR, &cmds).unwrap();
+        xhci.regs.write(DB_BASE, 4, 0).unwrap();
+
+        for index in 0..15 {
+            let event = read_event(&memory, index);
+            assert_eq!(event.control.type_(), TrbType::COMMAND_COMPLETION.raw());
+            assert_eq!(event.param, CMD_RING_ADDR + 16 * index);
+        }
+        let event = read_event(&memory, 15);
+        assert_eq!(event.control.type_(), TrbType::HOST_CONTROLLER.raw());
+        let code = (event.status >> 24) as u8;
+        assert_eq!(code, CompletionCode::EVENT_RING_FULL_ERROR.raw());
+        assert!(event.control.cycle());
+        // The completion of the last command is dropped.
+        assert_eq!(xhci.regs.msix_table.msi_sender.msis.lock().len(), 16);
+
+        // Once the driver consumes events, the ring wraps around with a
+        // toggled cycle.
+        xhci.regs
+            .write(INTR0_BASE + 0x18, 8, EVENT_RING_ADDR + 16 * 8)
+            .unwrap();
+        memory
+            .write(CMD_RING_ADDR + 16 * 17, &command(TrbType::NO_OP_COMMAND, 0))
+            .unwrap();
+        xhci.regs.write(DB_BASE, 4, 0).unwrap();
+        let event = read_event(&memory, 0);
+        assert!(!event.control.cycle());
+        assert_eq!(event.param, CMD_RING_ADDR + 16 * 17);
+    }
+
+    #[test]
+    fn test_xhci_plug_device() {
+        let (memory, xhci) = setup();
+        let desc = UsbDeviceDescriptor {
+            length: size_of::<UsbDeviceDescriptor>() as u8,
+            descriptor_type: 1,
+            bcd_usb: 0x0200,
+            max_packet_size0: 64,
+            num_configurations: 1,
+            ..Default::default()
+        };
+        assert_matches!(
+            xhci.plug_device(0, desc),
+            Err(Error::InvalidPort { port: 0, .. })
+        );
+        xhci.plug_device(2, desc).unwrap();
+        assert_matches!(
+            xhci.plug_device(2, desc),
+            Err(Error::PortInUse { port: 2, .. })
+        );
+
+        let event = read_event(&memory, 0);
+        assert_eq!(event.control.type_(), TrbType::PORT_STATUS_CHANGE.raw());
+        assert_eq!(event.param >> 24, 2);
+        assert_eq!(
+            *xhci.regs.msix_table.msi_sender.msis.lock(),
+            [(0xfee0_0000, 0x30)]
+        );
+        // USBSTS: EINT | PCD
+        assert_eq!(xhci.regs.read(OP_BASE + 0x04, 4).unwrap(), 0b11000);
+
+        let portsc_offset = PORT_BASE + 0x10;
+        let portsc = PortSc(xhci.regs.read(portsc_offset, 4).unwrap() as u32);
+        assert!(portsc.ccs() && portsc.csc() && !portsc.ped());
+        assert_eq!(portsc.speed(), PortSc::SPEED_HIGH);
+
+        // Clear CSC and reset the port.
+        let mut val = portsc;
+        val.set_pr(true);
+        xhci.regs.write(portsc_offset, 4, val.0 as u64).unwrap();
+        let portsc = PortSc(xhci.regs.read(portsc_offset, 4).unwrap() as u32);
+        assert!(portsc.ccs() && !portsc.csc() && portsc.ped() && portsc.prc());
+        assert_eq!(portsc.pls(), PortSc::PLS_U0);
+        let event = read_event(&memory, 1);
+        assert_eq!(event.control.type_(), TrbType::PORT_STATUS_CHANGE.raw());
+        assert_eq!(event.param >> 24, 2);
+    }
+}
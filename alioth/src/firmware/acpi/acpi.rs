@@ -20,20 +20,23 @@ use zerocopy::{transmute, AsBytes, FromBytes};
 
 use crate::arch::layout::PCIE_CONFIG_START;
 #[cfg(target_arch = "x86_64")]
-use crate::arch::layout::{APIC_START, IOAPIC_START};
+use crate::arch::layout::{APIC_START, HPET_START, IOAPIC_START};
+#[cfg(target_arch = "x86_64")]
+use crate::device::hpet::{Capabilities, HPET_GSI, HPET_MIN_TICK};
 use crate::unsafe_impl_zerocopy;
 use crate::utils::wrapping_sum;
 
 use bindings::{
-    AcpiGenericAddress, AcpiMadtIoApic, AcpiMadtLocalX2apic, AcpiMcfgAllocation,
-    AcpiSubtableHeader, AcpiTableFadt, AcpiTableHeader, AcpiTableMadt, AcpiTableMcfg,
-    AcpiTableRsdp, AcpiTableXsdt, FADT_MAJOR_VERSION, FADT_MINOR_VERSION, MADT_IO_APIC,
-    MADT_LOCAL_X2APIC, MADT_REVISION, MCFG_REVISION, RSDP_REVISION, SIG_FADT, SIG_MADT, SIG_MCFG,
-    SIG_RSDP, SIG_XSDT, XSDT_REVISION,
+    AcpiGenericAddress, AcpiMadtInterruptOverride, AcpiMadtIoApic, AcpiMadtLocalX2apic,
+    AcpiMcfgAllocation, AcpiSubtableHeader, AcpiTableFadt, AcpiTableHeader, AcpiTableHpet,
+    AcpiTableMadt, AcpiTableMcfg, AcpiTableRsdp, AcpiTableXsdt, FADT_MAJOR_VERSION,
+    FADT_MINOR_VERSION, HPET_REVISION, MADT_INT_OVERRIDE, MADT_IO_APIC, MADT_LOCAL_X2APIC,
+    MADT_REVISION, MCFG_REVISION, RSDP_REVISION, SIG_FADT, SIG_HPET, SIG_MADT, SIG_MCFG, SIG_RSDP,
+    SIG_XSDT, XSDT_REVISION,
 };
 
 unsafe_impl_zerocopy!(AcpiTableMcfg<1>, FromBytes, FromZeroes, AsBytes);
-unsafe_impl_zerocopy!(AcpiTableXsdt<3>, FromBytes, FromZeroes, AsBytes);
+unsafe_impl_zerocopy!(AcpiTableXsdt<4>, FromBytes, FromZeroes, AsBytes);
 
 const OEM_ID: [u8; 6] = *b"ALIOTH";
 
@@ -117,9 +120,17 @@ pub fn create_fadt(dsdt_addr: u64) -> AcpiTableFadt {
 
 // https://uefi.org/specs/ACPI/6.5/05_ACPI_Software_Programming_Model.html#multiple-apic-description-table-madt
 #[cfg(target_arch = "x86_64")]
-pub fn create_madt(num_cpu: u32) -> (AcpiTableMadt, AcpiMadtIoApic, Vec<AcpiMadtLocalX2apic>) {
+pub fn create_madt(
+    num_cpu: u32,
+) -> (
+    AcpiTableMadt,
+    AcpiMadtIoApic,
+    AcpiMadtInterruptOverride,
+    Vec<AcpiMadtLocalX2apic>,
+) {
     let total_length = size_of::<AcpiTableMadt>()
         + size_of::<AcpiMadtIoApic>()
+        + size_of::<AcpiMadtInterruptOverride>()
         + num_cpu as usize * size_of::<AcpiMadtLocalX2apic>();
     let mut checksum = 0u8;
 
@@ -147,6 +158,21 @@ pub fn create_madt(num_cpu: u32) -> (AcpiTableMadt, AcpiMadtIoApic, Vec<AcpiMadt
     };
     checksum = checksum.wrapping_sub(wrapping_sum(io_apic.as_bytes()));
 
+    // The PIT and HPET timer 0 in legacy replacement mode raise ISA IRQ 0
+    // on GSI 2. The FADT is hardware-reduced, so there is no SCI to
+    // override.
+    let timer_override = AcpiMadtInterruptOverride {
+        header: AcpiSubtableHeader {
+            type_: MADT_INT_OVERRIDE,
+            length: size_of::<AcpiMadtInterruptOverride>() as u8,
+        },
+        bus: 0,
+        source_irq: 0,
+        global_irq: transmute!(HPET_GSI as u32),
+        inti_flags: 0,
+    };
+    checksum = checksum.wrapping_sub(wrapping_sum(timer_override.as_bytes()));
+
     let mut x2apics = vec![];
     for i in 0..num_cpu {
         let x2apic = AcpiMadtLocalX2apic {
@@ -164,7 +190,7 @@ pub fn create_madt(num_cpu: u32) -> (AcpiTableMadt, AcpiMadtIoApic, Vec<AcpiMadt
     }
     madt.header.checksum = checksum;
 
-    (madt, io_apic, x2apics)
+    (madt, io_apic, timer_override, x2apics)
 }
 
 pub fn create_mcfg() -> AcpiTableMcfg<1> {
@@ -188,6 +214,34 @@ pub fn create_mcfg() -> AcpiTableMcfg<1> {
     mcfg
 }
 
+// https://www.intel.com/content/dam/www/public/us/en/documents/technical-specifications/software-developers-hpet-spec-1-0a.pdf
+// Sec. 3.2.4 The ACPI 2.0 HPET Description Table (HPET)
+#[cfg(target_arch = "x86_64")]
+pub fn create_hpet() -> AcpiTableHpet {
+    let mut hpet = AcpiTableHpet {
+        header: AcpiTableHeader {
+            signature: SIG_HPET,
+            length: size_of::<AcpiTableHpet>() as u32,
+            revision: HPET_REVISION,
+            ..default_header()
+        },
+        id: Capabilities::new().0 as u32,
+        address: AcpiGenericAddress {
+            space_id: 0,
+            bit_width: 64,
+            bit_offset: 0,
+            access_width: 0,
+            address: transmute!(HPET_START),
+        },
+        sequence: 0,
+        minimum_tick: HPET_MIN_TICK.to_le_bytes(),
+        // 4 KiB page protection
+        flags: 1,
+    };
+    hpet.header.checksum = 0u8.wrapping_sub(wrapping_sum(hpet.as_bytes()));
+    hpet
+}
+
 pub struct AcpiTable {
     pub(crate) rsdp: AcpiTableRsdp,
     pub(crate) tables: Vec<u8>,
@@ -19,6 +19,7 @@ pub const SIG_XSDT: [u8; 4] = *b"XSDT";
 pub const SIG_FADT: [u8; 4] = *b"FACP";
 pub const SIG_MADT: [u8; 4] = *b"APIC";
 pub const SIG_MCFG: [u8; 4] = *b"MCFG";
+pub const SIG_HPET: [u8; 4] = *b"HPET";
 #[allow(dead_code)]
 pub const SIG_DSDT: [u8; 4] = *b"DSDT";
 
@@ -148,6 +149,7 @@ pub struct AcpiTableMadt {
 }
 
 pub const MADT_IO_APIC: u8 = 1;
+pub const MADT_INT_OVERRIDE: u8 = 2;
 pub const MADT_LOCAL_X2APIC: u8 = 9;
 
 #[repr(C)]
@@ -177,6 +179,16 @@ pub struct AcpiMadtIoApic {
     pub global_irq_base: u32,
 }
 
+#[repr(C)]
+#[derive(Debug, Clone, AsBytes, Default, FromBytes, FromZeroes)]
+pub struct AcpiMadtInterruptOverride {
+    pub header: AcpiSubtableHeader,
+    pub bus: u8,
+    pub source_irq: u8,
+    pub global_irq: [u16; 2],
+    pub inti_flags: u16,
+}
+
 #[repr(C, align(4))]
 #[derive(Debug, Clone, AsBytes, Default, FromBytes, FromZeroes)]
 pub struct AcpiMcfgAllocation {
@@ -197,13 +209,27 @@ pub struct AcpiTableMcfg<const N: usize> {
     pub allocations: [AcpiMcfgAllocation; N],
 }
 
+pub const HPET_REVISION: u8 = 1;
+
+#[repr(C, align(4))]
+#[derive(Debug, Clone, Default, AsBytes, FromBytes, FromZeroes)]
+pub struct AcpiTableHpet {
+    pub header: AcpiTableHeader,
+    pub id: u32,
+    pub address: AcpiGenericAddress,
+    pub sequence: u8,
+    pub minimum_tick: [u8; 2],
+    pub flags: u8,
+}
+
 #[cfg(test)]
 mod test {
     use std::mem::size_of;
 
     use super::{
-        AcpiGenericAddress, AcpiMadtIoApic, AcpiMadtLocalX2apic, AcpiMcfgAllocation, AcpiTableFadt,
-        AcpiTableHeader, AcpiTableMadt, AcpiTableMcfg, AcpiTableRsdp, AcpiTableXsdt,
+        AcpiGenericAddress, AcpiMadtInterruptOverride, AcpiMadtIoApic, AcpiMadtLocalX2apic,
+        AcpiMcfgAllocation, AcpiTableFadt, AcpiTableHeader, AcpiTableHpet, AcpiTableMadt,
+        AcpiTableMcfg, AcpiTableRsdp, AcpiTableXsdt,
     };
 
     #[test]
@@ -214,9 +240,11 @@ mod test {
         assert_eq!(size_of::<AcpiTableFadt>(), 276);
         assert_eq!(size_of::<AcpiTableMadt>(), 44);
         assert_eq!(size_of::<AcpiMadtIoApic>(), 12);
+        assert_eq!(size_of::<AcpiMadtInterruptOverride>(), 10);
         assert_eq!(size_of::<AcpiMadtLocalX2apic>(), 16);
         assert_eq!(size_of::<AcpiMcfgAllocation>(), 16);
         assert_eq!(size_of::<AcpiTableMcfg<1>>(), 60);
+        assert_eq!(size_of::<AcpiTableHpet>(), 56);
         assert_eq!(size_of::<AcpiTableXsdt<0>>(), 36);
         assert_eq!(size_of::<AcpiTableXsdt<4>>(), 36 + 4 * 8);
     }
@@ -18,19 +18,25 @@ mod hvf;
 #[cfg(target_os = "linux")]
 #[path = "kvm/kvm.rs"]
 mod kvm;
-#[cfg(test)]
-pub(crate) mod test;
+#[cfg(any(test, fuzzing))]
+pub mod test;
 use std::fmt::Debug;
+use std::ops::Sub;
 use std::os::fd::AsFd;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 use std::thread::JoinHandle;
 
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use snafu::Snafu;
 
 #[cfg(target_arch = "x86_64")]
 use crate::arch::cpuid::Cpuid;
 #[cfg(target_arch = "x86_64")]
+use crate::arch::hyperv::HypervEnlightenments;
+#[cfg(target_arch = "x86_64")]
+use crate::arch::mce::MceBanks;
+#[cfg(target_arch = "x86_64")]
 use crate::arch::reg::{DtReg, DtRegVal, SegReg, SegRegVal};
 use crate::arch::reg::{Reg, SReg};
 #[cfg(target_arch = "x86_64")]
@@ -40,7 +46,7 @@ use crate::errors::{trace_error, DebugTrace};
 #[cfg(target_os = "macos")]
 pub use hvf::Hvf;
 #[cfg(target_os = "linux")]
-pub use kvm::{Kvm, KvmConfig, KvmError};
+pub use kvm::{Kvm, KvmConfig, KvmError, VcpuPreemptor};
 
 #[trace_error]
 #[derive(Snafu, DebugTrace)]
@@ -59,8 +65,12 @@ pub enum Error {
         size: u64,
         error: std::io::Error,
     },
+    #[snafu(display("Failed to get the dirty log of slot {slot}"))]
+    DirtyLog { slot: u32, error: std::io::Error },
     #[snafu(display("Hypervisor is missing capability: {cap}"))]
     Capability { cap: &'static str },
+    #[snafu(display("{op} is not supported by the hypervisor"))]
+    Unsupported { op: &'static str },
     #[snafu(display("Failed to setup signal handlers"))]
     SetupSignal { error: std::io::Error },
     #[snafu(display("Failed to create a VM"))]
@@ -75,6 +85,20 @@ pub enum Error {
     VcpuReg { error: std::io::Error },
     #[snafu(display("Failed to configure the guest CPUID"))]
     GuestCpuid { error: std::io::Error },
+    #[snafu(display("Failed to configure the vAPIC address"))]
+    VapicAddr { error: std::io::Error },
+    #[snafu(display("Failed to configure the TSC frequency"))]
+    TscKhz { error: std::io::Error },
+    #[snafu(display("Failed to set MSRs"))]
+    SetMsrs { error: std::io::Error },
+    #[snafu(display("Failed to set up machine check banks"))]
+    SetupMce { error: std::io::Error },
+    #[snafu(display("Failed to inject a machine check"))]
+    InjectMce { error: std::io::Error },
+    #[snafu(display("Failed to set the guest clock"))]
+    SetClock { error: std::io::Error },
+    #[snafu(display("Failed to get the guest clock"))]
+    GetClock { error: std::io::Error },
     #[snafu(display("Failed to configure an encrypted region"))]
     EncryptedRegion { error: std::io::Error },
     #[snafu(display("Cannot create multiple VM memories"))]
@@ -91,6 +115,8 @@ pub enum Error {
     RunVcpu { error: std::io::Error },
     #[snafu(display("Failed to stop a VCPU"))]
     StopVcpu { error: std::io::Error },
+    #[snafu(display("Failed to forward SMCCC function {func_id:#x} to user space"))]
+    ForwardSmccc { func_id: u32, error: std::io::Error },
     #[cfg(target_os = "linux")]
     #[snafu(display("KVM internal error"), context(false))]
     KvmErr { source: Box<KvmError> },
@@ -148,9 +174,87 @@ pub trait Vcpu {
     #[cfg(target_arch = "x86_64")]
     fn set_cpuids(&mut self, cpuids: Vec<Cpuid>) -> Result<(), Error>;
 
+    /// Lets the hypervisor mirror the APIC task priority of the vCPU into the
+    /// guest memory at `addr`. Returns `false` if the hypervisor does not
+    /// support it.
+    #[cfg(target_arch = "x86_64")]
+    fn set_vapic_addr(&mut self, addr: u64) -> Result<bool, Error>;
+
+    /// Sets the TSC frequency of the vCPU in kHz. Returns `false` if the
+    /// hypervisor cannot scale the TSC.
+    #[cfg(target_arch = "x86_64")]
+    fn set_tsc_khz(&mut self, khz: u32) -> Result<bool, Error>;
+
+    /// Enables the Hyper-V enlightenments in `hv` that the hypervisor
+    /// supports and returns them.
+    #[cfg(target_arch = "x86_64")]
+    fn enable_hyperv(&mut self, hv: &HypervEnlightenments) -> Result<HypervEnlightenments, Error>;
+
+    #[cfg(target_arch = "x86_64")]
+    fn set_msrs(&mut self, msrs: &[(u32, u64)]) -> Result<(), Error>;
+
+    /// Sets up the machine check banks, reduced to the number of banks
+    /// the hypervisor supports, and returns them.
+    #[cfg(target_arch = "x86_64")]
+    fn setup_mce(&mut self, banks: MceBanks) -> Result<MceBanks, Error>;
+
     fn dump(&self) -> Result<(), Error>;
+
+    fn stats(&self) -> Arc<VcpuStats>;
+}
+
+macro_rules! vcpu_stats {
+    ($($field:ident),+ $(,)?) => {
+        /// Number of exits to userspace of a vCPU, by exit reason.
+        #[derive(Debug, Default)]
+        pub struct VcpuStats {
+            $(pub $field: AtomicU64,)+
+        }
+
+        #[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+        pub struct VcpuStatsSnapshot {
+            $(pub $field: u64,)+
+        }
+
+        impl VcpuStats {
+            pub fn snapshot(&self) -> VcpuStatsSnapshot {
+                VcpuStatsSnapshot {
+                    $($field: self.$field.load(Ordering::Relaxed),)+
+                }
+            }
+        }
+
+        impl VcpuStatsSnapshot {
+            pub fn total(&self) -> u64 {
+                0 $(+ self.$field)+
+            }
+        }
+
+        impl Sub for VcpuStatsSnapshot {
+            type Output = VcpuStatsSnapshot;
+
+            fn sub(self, rhs: Self) -> Self::Output {
+                VcpuStatsSnapshot {
+                    $($field: self.$field.wrapping_sub(rhs.$field),)+
+                }
+            }
+        }
+    };
 }
 
+vcpu_stats!(
+    io,
+    mmio,
+    hlt,
+    irq_window_open,
+    shutdown,
+    debug,
+    hypercall,
+    system_event,
+    interrupted,
+    other,
+);
+
 pub trait IrqSender: Debug + Send + Sync + 'static {
     fn send(&self) -> Result<(), Error>;
 }
@@ -167,7 +271,20 @@ where
 pub trait MsiSender: Debug + Send + Sync + 'static {
     type IrqFd: IrqFd;
     fn send(&self, addr: u64, data: u32) -> Result<()>;
+    /// Sends a batch of `(addr, data)` messages. Backends with a batched
+    /// interface should override the default, which calls `send` in a loop.
+    fn send_batch(&self, msgs: &[(u64, u32)]) -> Result<()> {
+        for &(addr, data) in msgs {
+            self.send(addr, data)?;
+        }
+        Ok(())
+    }
     fn create_irqfd(&self) -> Result<Self::IrqFd>;
+    /// Returns the maximum number of MSI-X vectors a device can have, or
+    /// `None` if the hypervisor has no limit.
+    fn max_vectors(&self) -> Option<u32> {
+        None
+    }
 }
 
 pub trait VmMemory: Debug + Send + Sync + 'static {
@@ -184,6 +301,14 @@ pub trait VmMemory: Debug + Send + Sync + 'static {
 
     fn max_mem_slots(&self) -> Result<u32, Error>;
 
+    /// Fetches and clears the dirty bitmap of a slot mapped with `log_dirty`.
+    fn get_dirty_log(&self, _slot: u32, _bitmap: &mut [u64]) -> Result<()> {
+        error::Unsupported {
+            op: "dirty page tracking",
+        }
+        .fail()
+    }
+
     fn register_encrypted_range(&self, _range: &[u8]) -> Result<()> {
         unimplemented!()
     }
@@ -298,6 +423,14 @@ pub trait Vm {
     #[cfg(target_arch = "x86_64")]
     fn snp_launch_finish(&self) -> Result<()>;
 
+    /// Sets the paravirtual clock of the guest to `ns` nanoseconds.
+    #[cfg(target_arch = "x86_64")]
+    fn set_clock(&self, ns: u64) -> Result<()>;
+
+    /// Returns the paravirtual clock of the guest in nanoseconds.
+    #[cfg(target_arch = "x86_64")]
+    fn get_clock(&self) -> Result<u64>;
+
     #[cfg(target_arch = "aarch64")]
     type GicV2: GicV2;
     #[cfg(target_arch = "aarch64")]
@@ -317,6 +450,12 @@ pub trait Vm {
     type Its: Its;
     #[cfg(target_arch = "aarch64")]
     fn create_its(&self, base: u64) -> Result<Self::Its>;
+
+    /// Makes SMC and HVC calls to `func_id` exit to user space with
+    /// [`VmExit::Smccc`]. Must be called before any VCPU runs. Returns
+    /// [`Error::Unsupported`] if the hypervisor cannot forward calls.
+    #[cfg(target_arch = "aarch64")]
+    fn forward_smccc(&self, func_id: u32) -> Result<()>;
 }
 
 pub trait Hypervisor {
@@ -345,6 +484,11 @@ pub enum VmExit {
         size: u64,
         private: bool,
     },
+    #[cfg(target_arch = "aarch64")]
+    Smccc {
+        func_id: u32,
+        args: [u64; 7],
+    },
     Shutdown,
     Reboot,
     Unknown(String),
@@ -356,6 +500,14 @@ pub enum VmEntry {
     None,
     Shutdown,
     Reboot,
-    Io { data: u32 },
-    Mmio { data: u64 },
+    Io {
+        data: u32,
+    },
+    Mmio {
+        data: u64,
+    },
+    #[cfg(target_arch = "aarch64")]
+    Smccc {
+        ret: [u64; 4],
+    },
 }
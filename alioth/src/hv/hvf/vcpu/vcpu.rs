@@ -15,6 +15,8 @@
 mod vmentry;
 mod vmexit;
 
+use std::sync::Arc;
+
 use snafu::ResultExt;
 
 use crate::arch::reg::{Reg, SReg};
@@ -23,7 +25,7 @@ use crate::hv::hvf::bindings::{
     hv_vcpu_set_sys_reg, HvExitReason, HvReg, HvVcpuExit,
 };
 use crate::hv::hvf::check_ret;
-use crate::hv::{error, Result, Vcpu, VmEntry, VmExit};
+use crate::hv::{error, Result, Vcpu, VcpuStats, VmEntry, VmExit};
 
 #[derive(Debug)]
 pub struct HvfVcpu {
@@ -31,6 +33,7 @@ pub struct HvfVcpu {
     pub vcpu_id: u64,
     pub vmexit: VmExit,
     pub exit_reg: Option<HvReg>,
+    pub stats: Arc<VcpuStats>,
 }
 
 impl Drop for HvfVcpu {
@@ -97,6 +100,10 @@ impl Vcpu for HvfVcpu {
         unimplemented!()
     }
 
+    fn stats(&self) -> Arc<VcpuStats> {
+        self.stats.clone()
+    }
+
     fn run(&mut self, entry: VmEntry) -> Result<VmExit> {
         match entry {
             VmEntry::None => {}
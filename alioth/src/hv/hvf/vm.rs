@@ -15,6 +15,7 @@
 use std::collections::HashMap;
 use std::os::fd::{AsFd, BorrowedFd};
 use std::ptr::null_mut;
+use std::sync::Arc;
 use std::thread::JoinHandle;
 
 use parking_lot::Mutex;
@@ -27,7 +28,7 @@ use crate::hv::hvf::check_ret;
 use crate::hv::hvf::vcpu::HvfVcpu;
 use crate::hv::{
     error, GicV2, GicV3, IoeventFd, IoeventFdRegistry, IrqFd, IrqSender, Its, MemMapOption,
-    MsiSender, Result, Vm, VmExit, VmMemory,
+    MsiSender, Result, VcpuStats, Vm, VmExit, VmMemory,
 };
 
 #[derive(Debug)]
@@ -250,6 +251,7 @@ impl Vm for HvfVm {
             vcpu_id,
             vmexit: VmExit::Shutdown,
             exit_reg: None,
+            stats: Arc::new(VcpuStats::default()),
         })
     }
     fn create_vm_memory(&mut self) -> Result<Self::Memory> {
@@ -286,4 +288,11 @@ impl Vm for HvfVm {
     fn create_its(&self, _base: u64) -> Result<Self::Its> {
         unimplemented!()
     }
+
+    fn forward_smccc(&self, _func_id: u32) -> Result<()> {
+        error::Unsupported {
+            op: "SMCCC forwarding",
+        }
+        .fail()
+    }
 }
@@ -40,6 +40,8 @@ pub struct KvmVmType(#[allow(dead_code)] pub u64);
 
 #[cfg(target_arch = "x86_64")]
 pub const KVM_MAX_CPUID_ENTRIES: usize = 256;
+#[cfg(target_arch = "x86_64")]
+pub const KVM_MAX_MSR_ENTRIES: usize = 32;
 
 bitflags! {
     #[derive(Debug, Clone, Copy, Default)]
@@ -89,6 +91,14 @@ pub struct KvmUserspaceMemoryRegion {
     pub userspace_addr: u64,
 }
 
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct KvmDirtyLog {
+    pub slot: u32,
+    pub _padding: u32,
+    pub dirty_bitmap: u64,
+}
+
 #[repr(C)]
 #[derive(Debug, Default, Copy, Clone)]
 pub struct KvmUserspaceMemoryRegion2 {
@@ -200,6 +210,56 @@ pub struct KvmSregs {
     pub interrupt_bitmap: [u64; 4],
 }
 
+#[cfg(target_arch = "x86_64")]
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct KvmVapicAddr {
+    pub vapic_addr: u64,
+}
+
+#[cfg(target_arch = "x86_64")]
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct KvmX86Mce {
+    pub status: u64,
+    pub addr: u64,
+    pub misc: u64,
+    pub mcg_status: u64,
+    pub bank: u8,
+    pub pad1: [u8; 7],
+    pub pad2: [u64; 3],
+}
+
+#[cfg(target_arch = "x86_64")]
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct KvmClockData {
+    pub clock: u64,
+    pub flags: u32,
+    pub pad0: u32,
+    pub realtime: u64,
+    pub host_tsc: u64,
+    pub pad: [u32; 4],
+}
+
+#[cfg(target_arch = "x86_64")]
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct KvmMsrEntry {
+    pub index: u32,
+    pub reserved: u32,
+    pub data: u64,
+}
+
+#[cfg(target_arch = "x86_64")]
+#[repr(C)]
+#[derive(Debug, Clone)]
+pub struct KvmMsrs<const N: usize> {
+    pub nmsrs: u32,
+    pub pad: u32,
+    pub entries: [KvmMsrEntry; N],
+}
+
 #[repr(C)]
 #[derive(Debug, Default, Copy, Clone)]
 pub struct KvmSregs2 {
@@ -220,16 +280,26 @@ pub struct KvmSregs2 {
     pub cr8: u64,
     pub efer: u64,
     pub apic_base: u64,
-    pub flags: u64,
+    pub flags: KvmSregs2Flag,
     pub pdptrs: [u64; 4],
 }
 
+bitflags! {
+    #[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+    pub struct KvmSregs2Flag: u64 {
+        const PDPTRS_VALID = 1 << 0;
+    }
+}
+
 c_enum! {
     pub struct KvmExit(u32);
     {
         IO = 2;
         HYPERCALL = 3;
+        DEBUG = 4;
+        HLT = 5;
         MMIO = 6;
+        IRQ_WINDOW_OPEN = 7;
         SHUTDOWN = 8;
         SYSTEM_EVENT = 24;
     }
@@ -444,16 +514,26 @@ pub struct KvmMsi {
 c_enum! {
     pub struct KvmCap(u32);
     {
+        VAPIC = 6;
         NR_MEMSLOTS = 10;
+        MCE = 31;
         IRQFD = 32;
+        HYPERV = 44;
+        TSC_CONTROL = 60;
+        IMMEDIATE_EXIT = 136;
         SIGNAL_MSI = 77;
+        HYPERV_TIME = 89;
+        HYPERV_SYNIC2 = 148;
         ARM_PSCI_0_2 = 102;
+        SREGS2 = 200;
         EXIT_HYPERCALL = 201;
-        // GUEST_MEMFD = 234;
-        // VM_TYPES = 235;
+        USER_MEMORY2 = 231;
+        GUEST_MEMFD = 234;
+        VM_TYPES = 235;
     }
 }
 
+#[cfg(target_arch = "x86_64")]
 pub const KVM_HC_MAP_GPA_RANGE: u64 = 12;
 
 bitflags! {
@@ -578,6 +658,40 @@ c_enum! {
     }
 }
 
+#[cfg(target_arch = "aarch64")]
+pub const KVM_ARM_VM_SMCCC_CTRL: u32 = 0;
+#[cfg(target_arch = "aarch64")]
+pub const KVM_ARM_VM_SMCCC_FILTER: u64 = 0;
+
+#[cfg(target_arch = "aarch64")]
+c_enum! {
+    pub struct KvmSmcccFilterAction(u8);
+    {
+        HANDLE = 0;
+        DENY = 1;
+        FWD_TO_USER = 2;
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct KvmSmcccFilter {
+    pub base: u32,
+    pub nr_functions: u32,
+    pub action: KvmSmcccFilterAction,
+    pub pad: [u8; 15],
+}
+
+#[cfg(target_arch = "aarch64")]
+bitflags! {
+    #[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct KvmHypercallExitFlag: u64 {
+        const SMC = 1 << 0;
+        const BIT16 = 1 << 1;
+    }
+}
+
 #[cfg(target_arch = "aarch64")]
 bitfield! {
     #[derive(Copy, Clone, Default, PartialEq, Eq, Hash)]
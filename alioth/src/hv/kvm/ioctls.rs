@@ -15,12 +15,13 @@
 #[cfg(not(target_arch = "x86_64"))]
 use crate::hv::kvm::bindings::KvmOneReg;
 use crate::hv::kvm::bindings::{
-    KvmCap, KvmEncRegion, KvmIoEventFd, KvmIrqRouting, KvmIrqfd, KvmMemoryAttributes, KvmMsi,
-    KvmUserspaceMemoryRegion, KvmUserspaceMemoryRegion2, KvmVmType, KVMIO,
+    KvmCap, KvmDirtyLog, KvmEncRegion, KvmIoEventFd, KvmIrqRouting, KvmIrqfd, KvmMemoryAttributes,
+    KvmMsi, KvmUserspaceMemoryRegion, KvmUserspaceMemoryRegion2, KvmVmType, KVMIO,
 };
 #[cfg(target_arch = "x86_64")]
 use crate::hv::kvm::bindings::{
-    KvmCpuid2, KvmCreateGuestMemfd, KvmEnableCap, KvmRegs, KvmSregs, KvmSregs2,
+    KvmClockData, KvmCpuid2, KvmCreateGuestMemfd, KvmEnableCap, KvmMsrs, KvmRegs, KvmSregs,
+    KvmSregs2, KvmVapicAddr, KvmX86Mce,
 };
 #[cfg(target_arch = "aarch64")]
 use crate::hv::kvm::bindings::{KvmCreateDevice, KvmDeviceAttr, KvmVcpuInit};
@@ -41,6 +42,7 @@ ioctl_none!(kvm_get_vcpu_mmap_size, KVMIO, 0x04, 0);
 ioctl_writeread_buf!(kvm_get_supported_cpuid, KVMIO, 0x05, KvmCpuid2);
 
 ioctl_write_val!(kvm_create_vcpu, ioctl_io(KVMIO, 0x41), u32);
+ioctl_write_ptr!(kvm_get_dirty_log, KVMIO, 0x42, KvmDirtyLog);
 ioctl_write_ptr!(
     kvm_set_user_memory_region,
     KVMIO,
@@ -65,6 +67,11 @@ ioctl_write_buf!(kvm_set_gsi_routing, KVMIO, 0x6a, KvmIrqRouting);
 ioctl_write_ptr!(kvm_irqfd, KVMIO, 0x76, KvmIrqfd);
 ioctl_write_ptr!(kvm_ioeventfd, KVMIO, 0x79, KvmIoEventFd);
 
+#[cfg(target_arch = "x86_64")]
+ioctl_write_ptr!(kvm_set_clock, KVMIO, 0x7b, KvmClockData);
+#[cfg(target_arch = "x86_64")]
+ioctl_read!(kvm_get_clock, KVMIO, 0x7c, KvmClockData);
+
 ioctl_none!(kvm_run, KVMIO, 0x80, 0);
 #[cfg(target_arch = "x86_64")]
 ioctl_read!(kvm_get_regs, KVMIO, 0x81, KvmRegs);
@@ -75,9 +82,25 @@ ioctl_read!(kvm_get_sregs, KVMIO, 0x83, KvmSregs);
 #[cfg(target_arch = "x86_64")]
 ioctl_write_ptr!(kvm_set_sregs, KVMIO, 0x84, KvmSregs);
 
+#[cfg(target_arch = "x86_64")]
+ioctl_writeread_buf!(kvm_get_msrs, KVMIO, 0x88, KvmMsrs);
+#[cfg(target_arch = "x86_64")]
+ioctl_write_buf!(kvm_set_msrs, KVMIO, 0x89, KvmMsrs);
+
 #[cfg(target_arch = "x86_64")]
 ioctl_write_buf!(kvm_set_cpuid2, KVMIO, 0x90, KvmCpuid2);
+#[cfg(target_arch = "x86_64")]
+ioctl_write_ptr!(kvm_set_vapic_addr, KVMIO, 0x93, KvmVapicAddr);
+
+#[cfg(target_arch = "x86_64")]
+ioctl_write_ptr!(kvm_x86_setup_mce, KVMIO, 0x9c, u64);
+#[cfg(target_arch = "x86_64")]
+ioctl_write_ptr!(kvm_x86_set_mce, KVMIO, 0x9e, KvmX86Mce);
 
+#[cfg(target_arch = "x86_64")]
+ioctl_write_val!(kvm_set_tsc_khz, ioctl_io(KVMIO, 0xa2));
+#[cfg(target_arch = "x86_64")]
+ioctl_none!(kvm_get_tsc_khz, KVMIO, 0xa3, 0);
 #[cfg(target_arch = "x86_64")]
 ioctl_write_ptr!(kvm_enable_cap, KVMIO, 0xa3, KvmEnableCap);
 ioctl_write_ptr!(kvm_signal_msi, KVMIO, 0xa5, KvmMsi);
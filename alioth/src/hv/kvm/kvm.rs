@@ -21,6 +21,7 @@ mod bindings;
 #[cfg(target_arch = "aarch64")]
 mod device;
 mod ioctls;
+mod preempt;
 #[path = "sev/sev.rs"]
 mod sev;
 #[path = "vcpu/vcpu.rs"]
@@ -61,6 +62,8 @@ use ioctls::{kvm_create_vm, kvm_get_api_version, kvm_get_vcpu_mmap_size};
 use libc::SIGRTMIN;
 use vm::{KvmVm, VmInner};
 
+pub use preempt::VcpuPreemptor;
+
 #[trace_error]
 #[derive(DebugTrace, Snafu)]
 #[snafu(module, context(suffix(false)))]
@@ -125,7 +128,9 @@ pub struct KvmConfig {
     pub dev_sev: Option<PathBuf>,
 }
 
-extern "C" fn sigrtmin_handler(_: libc::c_int, _: *mut libc::siginfo_t, _: *mut libc::c_void) {}
+extern "C" fn sigrtmin_handler(_: libc::c_int, _: *mut libc::siginfo_t, _: *mut libc::c_void) {
+    vcpu::request_immediate_exit();
+}
 
 impl Kvm {
     pub fn new(config: KvmConfig) -> Result<Self> {
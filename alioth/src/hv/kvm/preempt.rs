@@ -0,0 +1,220 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::io;
+use std::mem::MaybeUninit;
+use std::os::unix::thread::RawPthread;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use libc::{clockid_t, SIGRTMIN};
+
+use crate::ffi;
+
+fn cpu_time(clock: clockid_t) -> io::Result<Duration> {
+    let mut ts = MaybeUninit::uninit();
+    ffi!(unsafe { libc::clock_gettime(clock, ts.as_mut_ptr()) })?;
+    let ts = unsafe { ts.assume_init() };
+    Ok(Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32))
+}
+
+#[derive(Debug)]
+struct VcpuThread {
+    id: u32,
+    thread: RawPthread,
+    clock: clockid_t,
+}
+
+#[derive(Debug)]
+struct VcpuThreads {
+    threads: Vec<VcpuThread>,
+    preemptions: AtomicU64,
+}
+
+impl VcpuThreads {
+    fn preempt(&self, vcpu_id: u32) -> io::Result<()> {
+        let Some(vcpu) = self.threads.iter().find(|t| t.id == vcpu_id) else {
+            return Err(io::ErrorKind::NotFound.into());
+        };
+        ffi!(unsafe { libc::pthread_kill(vcpu.thread, SIGRTMIN()) })?;
+        self.preemptions.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+/// Kicks vCPUs out of `KVM_RUN` once they have used up a time quantum.
+///
+/// The CPU time of each vCPU thread is read from the clock returned by
+/// `pthread_getcpuclockid()`, i.e. the `CLOCK_THREAD_CPUTIME_ID` of that
+/// thread. A vCPU is kicked with `SIGRTMIN`, which sets
+/// `kvm_run.immediate_exit` if `KVM_CAP_IMMEDIATE_EXIT` is available, and
+/// is expected to yield the CPU on [`VmExit::Interrupted`][crate::hv::VmExit].
+///
+/// The vCPU threads must not be joined before the preemptor is dropped.
+#[derive(Debug)]
+pub struct VcpuPreemptor {
+    vcpus: Arc<VcpuThreads>,
+    stop_tx: Option<Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl VcpuPreemptor {
+    pub fn new(quantum: Duration, vcpus: &[(u32, RawPthread)]) -> io::Result<Self> {
+        let mut threads = vec![];
+        for (id, thread) in vcpus {
+            let mut clock = 0;
+            let ret = unsafe { libc::pthread_getcpuclockid(*thread, &mut clock) };
+            if ret != 0 {
+                return Err(io::Error::from_raw_os_error(ret));
+            }
+            threads.push(VcpuThread {
+                id: *id,
+                thread: *thread,
+                clock,
+            });
+        }
+        let vcpus = Arc::new(VcpuThreads {
+            threads,
+            preemptions: AtomicU64::new(0),
+        });
+        let (stop_tx, stop_rx) = mpsc::channel();
+        let thread_vcpus = vcpus.clone();
+        let scheduler = move || {
+            let mut starts = thread_vcpus
+                .threads
+                .iter()
+                .map(|t| cpu_time(t.clock).unwrap_or_default())
+                .collect::<Vec<_>>();
+            let mut timeout = quantum;
+            while let Err(RecvTimeoutError::Timeout) = stop_rx.recv_timeout(timeout) {
+                timeout = quantum;
+                for (vcpu, start) in thread_vcpus.threads.iter().zip(starts.iter_mut()) {
+                    // The clock is gone once the vCPU thread exits.
+                    let Ok(now) = cpu_time(vcpu.clock) else {
+                        continue;
+                    };
+                    let used = now.saturating_sub(*start);
+                    if used < quantum {
+                        timeout = timeout.min(quantum - used);
+                        continue;
+                    }
+                    if let Err(e) = thread_vcpus.preempt(vcpu.id) {
+                        log::error!("failed to preempt vcpu-{}: {e}", vcpu.id);
+                    }
+                    *start = now;
+                }
+            }
+        };
+        let handle = std::thread::Builder::new()
+            .name("vcpu-preemptor".to_owned())
+            .spawn(scheduler)?;
+        Ok(VcpuPreemptor {
+            vcpus,
+            stop_tx: Some(stop_tx),
+            handle: Some(handle),
+        })
+    }
+
+    /// Forces vCPU `vcpu_id` out of `KVM_RUN`.
+    pub fn preempt(&self, vcpu_id: u32) -> io::Result<()> {
+        self.vcpus.preempt(vcpu_id)
+    }
+
+    /// Number of times vCPUs were preempted.
+    pub fn preemptions(&self) -> u64 {
+        self.vcpus.preemptions.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for VcpuPreemptor {
+    fn drop(&mut self) {
+        self.stop_tx.take();
+        if let Some(handle) = self.handle.take() {
+            if let Err(e) = handle.join() {
+                log::error!("failed to join vcpu preemptor thread: {e:?}");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+#[cfg(target_arch = "x86_64")]
+mod test {
+    use std::os::unix::thread::JoinHandleExt;
+    use std::ptr::null_mut;
+    use std::thread;
+    use std::time::Duration;
+
+    use libc::{mmap, MAP_ANONYMOUS, MAP_FAILED, MAP_SHARED, PROT_EXEC, PROT_READ, PROT_WRITE};
+
+    use crate::ffi;
+    use crate::hv::kvm::preempt::{cpu_time, VcpuPreemptor};
+    use crate::hv::kvm::KvmConfig;
+    use crate::hv::{Hypervisor, Kvm, MemMapOption, Vcpu, Vm, VmConfig, VmEntry, VmExit, VmMemory};
+
+    #[test]
+    #[cfg_attr(not(feature = "test-hv"), ignore)]
+    fn test_preempt_tight_loop() {
+        const QUANTUM: Duration = Duration::from_millis(5);
+        const SLICES: usize = 20;
+
+        let kvm = Kvm::new(KvmConfig::default()).unwrap();
+        let mut vm = kvm.create_vm(&VmConfig { coco: None }).unwrap();
+        let memory = vm.create_vm_memory().unwrap();
+        let prot = PROT_WRITE | PROT_EXEC | PROT_READ;
+        let user_mem = ffi!(
+            unsafe { mmap(null_mut(), 0x1000, prot, MAP_ANONYMOUS | MAP_SHARED, -1, 0) },
+            MAP_FAILED
+        )
+        .unwrap();
+        // jmp $ at the reset vector 0xffff_fff0
+        unsafe { ((user_mem as usize + 0xff0) as *mut [u8; 2]).write([0xeb, 0xfe]) };
+        let option = MemMapOption {
+            read: true,
+            write: true,
+            exec: true,
+            ..Default::default()
+        };
+        memory
+            .mem_map(0, 0xffff_f000, 0x1000, user_mem as usize, option)
+            .unwrap();
+
+        let mut vcpu = vm.create_vcpu(0).unwrap();
+        let handle = thread::spawn(move || {
+            let mut slices = vec![];
+            let mut start = cpu_time(libc::CLOCK_THREAD_CPUTIME_ID).unwrap();
+            while slices.len() < SLICES {
+                assert!(matches!(vcpu.run(VmEntry::None), Ok(VmExit::Interrupted)));
+                let now = cpu_time(libc::CLOCK_THREAD_CPUTIME_ID).unwrap();
+                slices.push(now - start);
+                thread::yield_now();
+                start = cpu_time(libc::CLOCK_THREAD_CPUTIME_ID).unwrap();
+            }
+            slices
+        });
+        let preemptor = VcpuPreemptor::new(QUANTUM, &[(0, handle.as_pthread_t())]).unwrap();
+        while !handle.is_finished() {
+            thread::sleep(QUANTUM);
+        }
+        drop(preemptor);
+        let slices = handle.join().unwrap();
+
+        // The first slice starts before the preemptor.
+        let average = slices[1..].iter().sum::<Duration>() / (SLICES - 1) as u32;
+        assert!(average <= QUANTUM * 11 / 10, "{slices:?}");
+    }
+}
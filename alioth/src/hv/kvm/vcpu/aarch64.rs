@@ -34,6 +34,9 @@ impl KvmVcpu {
     pub fn kvm_vcpu_init(&self, is_bsp: bool) -> Result<()> {
         let mut arm_cpu_init =
             unsafe { kvm_arm_preferred_target(&self.vm) }.context(error::CreateVcpu)?;
+        // With PSCI 0.2+, KVM handles PSCI calls in the kernel. CPU_ON and
+        // CPU_OFF never exit to user space, while SYSTEM_OFF and
+        // SYSTEM_RESET(2) exit with KVM_EXIT_SYSTEM_EVENT.
         if self.vm.check_extension(KvmCap::ARM_PSCI_0_2)? == 1 {
             arm_cpu_init.features[0] |= KvmArmVcpuFeature::PSCI_0_2.bits();
         }
@@ -85,3 +88,78 @@ impl KvmVcpu {
         self.get_one_reg(encode_system_reg(reg))
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::ptr::null_mut;
+
+    use assert_matches::assert_matches;
+    use libc::{mmap, MAP_ANONYMOUS, MAP_FAILED, MAP_SHARED, PROT_EXEC, PROT_READ, PROT_WRITE};
+
+    use crate::arch::reg::{IdRegs, Pstate, Reg};
+    use crate::ffi;
+    use crate::hv::kvm::KvmConfig;
+    use crate::hv::{Hypervisor, Kvm, MemMapOption, Vcpu, Vm, VmConfig, VmEntry, VmExit, VmMemory};
+
+    #[test]
+    #[cfg_attr(not(feature = "test-hv"), ignore)]
+    fn test_id_regs() {
+        let kvm = Kvm::new(KvmConfig::default()).unwrap();
+        let vm_config = VmConfig { coco: None };
+        let mut vm = kvm.create_vm(&vm_config).unwrap();
+        let memory = vm.create_vm_memory().unwrap();
+
+        let prot = PROT_WRITE | PROT_EXEC | PROT_READ;
+        let flag = MAP_ANONYMOUS | MAP_SHARED;
+        let user_mem = ffi!(
+            unsafe { mmap(null_mut(), 0x1000, prot, flag, -1, 0) },
+            MAP_FAILED
+        )
+        .unwrap();
+        let mmap_option = MemMapOption {
+            read: true,
+            write: true,
+            exec: true,
+            ..Default::default()
+        };
+        memory
+            .mem_map(0, 0, 0x1000, user_mem as usize, mmap_option)
+            .unwrap();
+
+        #[rustfmt::skip]
+        const CODE: [u32; 3] = [
+            // mrs x0, midr_el1
+            0xd5380000,
+            // str x0, [x1]
+            0xf9000020,
+            // b .
+            0x14000000,
+        ];
+        unsafe { (user_mem as *mut [u32; 3]).write(CODE) };
+
+        // Arm Neoverse N1 r4p1
+        const MIDR: u64 = 0x414f_d0c1;
+        let id_regs = IdRegs {
+            midr: Some(MIDR),
+            ..Default::default()
+        };
+        let mut vcpu = vm.create_vcpu(0).unwrap();
+        vcpu.reset(true).unwrap();
+        vcpu.set_sregs(&id_regs.sregs()).unwrap();
+        let pstate = Pstate::D | Pstate::A | Pstate::I | Pstate::F | Pstate::EL_BIT2 | Pstate::EL_H;
+        vcpu.set_regs(&[
+            (Reg::Pc, 0),
+            (Reg::X1, 0x1_0000),
+            (Reg::Pstate, pstate.bits() as u64),
+        ])
+        .unwrap();
+        assert_matches!(
+            vcpu.run(VmEntry::None),
+            Ok(VmExit::Mmio {
+                addr: 0x1_0000,
+                write: Some(MIDR),
+                size: 8
+            })
+        );
+    }
+}
@@ -17,10 +17,12 @@ mod aarch64;
 #[cfg(target_arch = "x86_64")]
 mod x86_64;
 
+use std::cell::Cell;
 use std::io::ErrorKind;
 use std::ops::{Deref, DerefMut};
 use std::os::fd::{OwnedFd, RawFd};
 use std::ptr::null_mut;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
 
 use libc::{mmap, munmap, MAP_FAILED, MAP_SHARED, PROT_READ, PROT_WRITE};
@@ -29,6 +31,10 @@ use snafu::ResultExt;
 #[cfg(target_arch = "x86_64")]
 use crate::arch::cpuid::Cpuid;
 #[cfg(target_arch = "x86_64")]
+use crate::arch::hyperv::HypervEnlightenments;
+#[cfg(target_arch = "x86_64")]
+use crate::arch::mce::MceBanks;
+#[cfg(target_arch = "x86_64")]
 use crate::arch::reg::{DtReg, DtRegVal, SegReg, SegRegVal};
 use crate::arch::reg::{Reg, SReg};
 use crate::ffi;
@@ -36,7 +42,24 @@ use crate::hv::kvm::bindings::{KvmExit, KvmRun};
 use crate::hv::kvm::ioctls::kvm_run;
 use crate::hv::kvm::vm::VmInner;
 use crate::hv::kvm::{kvm_error, KvmError};
-use crate::hv::{error, Error, Vcpu, VmEntry, VmExit};
+use crate::hv::{error, Error, Vcpu, VcpuStats, VmEntry, VmExit};
+
+thread_local! {
+    /// Address of the kvm_run block of the vCPU last run on this thread.
+    static KVM_RUN: Cell<usize> = const { Cell::new(0) };
+}
+
+/// Makes the next `KVM_RUN` on this thread return immediately.
+///
+/// Called from the signal handler that kicks a vCPU, so that a kick
+/// arriving while the vCPU is out of `KVM_RUN` is not lost.
+pub(super) fn request_immediate_exit() {
+    let addr = KVM_RUN.with(|r| r.get());
+    if addr != 0 {
+        let kvm_run = addr as *mut KvmRun;
+        unsafe { std::ptr::addr_of_mut!((*kvm_run).immediate_exit).write_volatile(1) };
+    }
+}
 
 pub(super) struct KvmRunBlock {
     addr: usize,
@@ -82,6 +105,11 @@ impl DerefMut for KvmRunBlock {
 
 impl Drop for KvmRunBlock {
     fn drop(&mut self) {
+        KVM_RUN.with(|r| {
+            if r.get() == self.addr {
+                r.set(0)
+            }
+        });
         if let Err(e) = ffi!(unsafe { munmap(self.addr as _, self.size) }) {
             log::error!("unmap kvm_run: {}", e)
         }
@@ -93,6 +121,31 @@ pub struct KvmVcpu {
     pub(super) fd: OwnedFd,
     #[allow(dead_code)]
     pub(super) vm: Arc<VmInner>,
+    pub(super) stats: Arc<VcpuStats>,
+    #[cfg(target_arch = "x86_64")]
+    pub(super) vapic: bool,
+    #[cfg(target_arch = "x86_64")]
+    pub(super) tsc_control: bool,
+    #[cfg(target_arch = "x86_64")]
+    pub(super) sregs2: bool,
+    pub(super) immediate_exit: bool,
+}
+
+impl KvmVcpu {
+    fn count_exit(&self, exit: KvmExit) {
+        let counter = match exit {
+            KvmExit::IO => &self.stats.io,
+            KvmExit::MMIO => &self.stats.mmio,
+            KvmExit::HLT => &self.stats.hlt,
+            KvmExit::IRQ_WINDOW_OPEN => &self.stats.irq_window_open,
+            KvmExit::SHUTDOWN => &self.stats.shutdown,
+            KvmExit::DEBUG => &self.stats.debug,
+            KvmExit::HYPERCALL => &self.stats.hypercall,
+            KvmExit::SYSTEM_EVENT => &self.stats.system_event,
+            _ => &self.stats.other,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
 }
 
 impl Vcpu for KvmVcpu {
@@ -107,14 +160,32 @@ impl Vcpu for KvmVcpu {
 
     #[cfg(target_arch = "x86_64")]
     fn get_dt_reg(&self, reg: DtReg) -> Result<DtRegVal, Error> {
-        self.kvm_get_dt_reg(reg)
+        if self.sregs2 {
+            self.kvm_get_dt_reg2(reg)
+        } else {
+            self.kvm_get_dt_reg(reg)
+        }
     }
 
     #[cfg(target_arch = "x86_64")]
     fn get_seg_reg(&self, reg: SegReg) -> Result<SegRegVal, Error> {
-        self.kvm_get_seg_reg(reg)
+        if self.sregs2 {
+            self.kvm_get_seg_reg2(reg)
+        } else {
+            self.kvm_get_seg_reg(reg)
+        }
     }
 
+    #[cfg(target_arch = "x86_64")]
+    fn get_sreg(&self, reg: SReg) -> Result<u64, Error> {
+        if self.sregs2 {
+            self.kvm_get_sreg2(reg)
+        } else {
+            self.kvm_get_sreg(reg)
+        }
+    }
+
+    #[cfg(target_arch = "aarch64")]
     fn get_sreg(&self, reg: SReg) -> Result<u64, Error> {
         self.kvm_get_sreg(reg)
     }
@@ -130,7 +201,11 @@ impl Vcpu for KvmVcpu {
         seg_regs: &[(SegReg, SegRegVal)],
         dt_regs: &[(DtReg, DtRegVal)],
     ) -> Result<(), Error> {
-        self.kvm_set_sregs(sregs, seg_regs, dt_regs)
+        if self.sregs2 {
+            self.kvm_set_sregs2(sregs, seg_regs, dt_regs)
+        } else {
+            self.kvm_set_sregs(sregs, seg_regs, dt_regs)
+        }
     }
 
     #[cfg(target_arch = "aarch64")]
@@ -143,12 +218,27 @@ impl Vcpu for KvmVcpu {
             VmEntry::None => {}
             VmEntry::Io { data } => self.entry_io(data),
             VmEntry::Mmio { data } => self.entry_mmio(data),
+            #[cfg(target_arch = "aarch64")]
+            VmEntry::Smccc { ret } => self.entry_smccc(ret)?,
             VmEntry::Shutdown | VmEntry::Reboot => self.set_immediate_exit(true),
         };
+        if self.immediate_exit {
+            KVM_RUN.with(|r| r.set(self.kvm_run.addr));
+        }
         let ret = unsafe { kvm_run(&self.fd) };
+        match &ret {
+            Ok(_) => self.count_exit(self.kvm_run.exit_reason),
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::Interrupted) => {
+                self.stats.interrupted.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(_) => {}
+        }
         match ret {
             Err(e) => match (e.kind(), entry) {
-                (ErrorKind::WouldBlock, _) => Ok(VmExit::Interrupted),
+                (ErrorKind::WouldBlock, _) => {
+                    self.set_immediate_exit(false);
+                    Ok(VmExit::Interrupted)
+                }
                 (ErrorKind::Interrupted, VmEntry::Shutdown) => {
                     self.set_immediate_exit(false);
                     Ok(VmExit::Shutdown)
@@ -157,13 +247,19 @@ impl Vcpu for KvmVcpu {
                     self.set_immediate_exit(false);
                     Ok(VmExit::Reboot)
                 }
-                (ErrorKind::Interrupted, _) => Ok(VmExit::Interrupted),
+                (ErrorKind::Interrupted, _) => {
+                    self.set_immediate_exit(false);
+                    Ok(VmExit::Interrupted)
+                }
                 _ => Err(e).context(error::RunVcpu),
             },
             Ok(_) => match self.kvm_run.exit_reason {
                 KvmExit::IO => self.handle_io(),
                 KvmExit::HYPERCALL => self.handle_hypercall(),
                 KvmExit::MMIO => self.handle_mmio(),
+                #[cfg(target_arch = "x86_64")]
+                KvmExit::SHUTDOWN => self.handle_shutdown(),
+                #[cfg(not(target_arch = "x86_64"))]
                 KvmExit::SHUTDOWN => Ok(VmExit::Shutdown),
                 KvmExit::SYSTEM_EVENT => self.handle_system_event(),
                 reason => Ok(VmExit::Unknown(format!("unkown kvm exit: {:#x?}", reason))),
@@ -176,7 +272,36 @@ impl Vcpu for KvmVcpu {
         self.kvm_set_cpuids(cpuids)
     }
 
+    #[cfg(target_arch = "x86_64")]
+    fn set_vapic_addr(&mut self, addr: u64) -> Result<bool, Error> {
+        self.kvm_set_vapic_addr(addr)
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn set_tsc_khz(&mut self, khz: u32) -> Result<bool, Error> {
+        self.kvm_set_tsc_khz(khz)
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn enable_hyperv(&mut self, hv: &HypervEnlightenments) -> Result<HypervEnlightenments, Error> {
+        self.kvm_enable_hyperv(hv)
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn set_msrs(&mut self, msrs: &[(u32, u64)]) -> Result<(), Error> {
+        self.kvm_set_msrs(msrs)
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn setup_mce(&mut self, banks: MceBanks) -> Result<MceBanks, Error> {
+        self.kvm_setup_mce(banks)
+    }
+
     fn dump(&self) -> Result<(), Error> {
         Ok(())
     }
+
+    fn stats(&self) -> Arc<VcpuStats> {
+        self.stats.clone()
+    }
 }
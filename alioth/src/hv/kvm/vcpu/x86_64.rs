@@ -15,17 +15,22 @@
 use snafu::ResultExt;
 
 use crate::arch::cpuid::Cpuid;
+use crate::arch::hyperv::HypervEnlightenments;
+use crate::arch::mce::MceBanks;
+use crate::arch::msr::{McgStatus, MciStatus, IA32_MCG_STATUS};
 use crate::arch::reg::{DtReg, DtRegVal, Reg, SReg, SegAccess, SegReg, SegRegVal};
 use crate::hv::kvm::bindings::{
-    KvmCpuid2, KvmCpuid2Flag, KvmCpuidEntry2, KvmRegs, KVM_MAX_CPUID_ENTRIES,
+    KvmCap, KvmCpuid2, KvmCpuid2Flag, KvmCpuidEntry2, KvmEnableCap, KvmMsrEntry, KvmMsrs, KvmRegs,
+    KvmSregs2Flag, KvmVapicAddr, KvmX86Mce, KVM_MAX_CPUID_ENTRIES, KVM_MAX_MSR_ENTRIES,
 };
 use crate::hv::kvm::ioctls::{
-    kvm_get_regs, kvm_get_sregs, kvm_get_sregs2, kvm_set_cpuid2, kvm_set_regs, kvm_set_sregs,
-    kvm_set_sregs2,
+    kvm_enable_cap, kvm_get_msrs, kvm_get_regs, kvm_get_sregs, kvm_get_sregs2, kvm_get_tsc_khz,
+    kvm_set_cpuid2, kvm_set_msrs, kvm_set_regs, kvm_set_sregs, kvm_set_sregs2, kvm_set_tsc_khz,
+    kvm_set_vapic_addr, kvm_x86_set_mce, kvm_x86_setup_mce,
 };
 use crate::hv::kvm::kvm_error;
 use crate::hv::kvm::vcpu::KvmVcpu;
-use crate::hv::{error, Error, Result};
+use crate::hv::{error, Error, Result, VmExit};
 
 macro_rules! set_kvm_sreg {
     ($kvm_sregs:ident, $sreg:ident, $val:expr) => {
@@ -215,6 +220,9 @@ impl KvmVcpu {
         for (reg, val) in seg_regs {
             set_kvm_seg_reg!(kvm_sregs2, reg, val);
         }
+        // The PDPTRs read above belong to the previous paging mode. Have
+        // KVM load them from guest memory, as KVM_SET_SREGS does.
+        kvm_sregs2.flags.remove(KvmSregs2Flag::PDPTRS_VALID);
         unsafe { kvm_set_sregs2(&self.fd, &kvm_sregs2) }.context(error::VcpuReg)?;
         Ok(())
     }
@@ -300,6 +308,139 @@ impl KvmVcpu {
         unsafe { kvm_set_cpuid2(&self.fd, &kvm_cpuid2) }.context(error::GuestCpuid)?;
         Ok(())
     }
+
+    pub fn kvm_set_vapic_addr(&mut self, addr: u64) -> Result<bool, Error> {
+        if !self.vapic {
+            return Ok(false);
+        }
+        let vapic_addr = KvmVapicAddr { vapic_addr: addr };
+        unsafe { kvm_set_vapic_addr(&self.fd, &vapic_addr) }.context(error::VapicAddr)?;
+        Ok(true)
+    }
+
+    pub fn kvm_set_tsc_khz(&mut self, khz: u32) -> Result<bool, Error> {
+        if !self.tsc_control {
+            // Without TSC scaling, only the host frequency can be used.
+            let current = unsafe { kvm_get_tsc_khz(&self.fd) }.context(error::TscKhz)?;
+            return Ok(current as u32 == khz);
+        }
+        unsafe { kvm_set_tsc_khz(&self.fd, khz as _) }.context(error::TscKhz)?;
+        Ok(true)
+    }
+
+    pub fn kvm_enable_hyperv(
+        &mut self,
+        hv: &HypervEnlightenments,
+    ) -> Result<HypervEnlightenments, Error> {
+        let mut enabled = HypervEnlightenments::default();
+        if self.vm.check_extension(KvmCap::HYPERV)? == 0 {
+            return Ok(enabled);
+        }
+        enabled.time = hv.time && self.vm.check_extension(KvmCap::HYPERV_TIME)? > 0;
+        if (hv.synic || hv.stimer) && self.vm.check_extension(KvmCap::HYPERV_SYNIC2)? > 0 {
+            let request = KvmEnableCap {
+                cap: KvmCap::HYPERV_SYNIC2,
+                flags: 0,
+                args: [0; 4],
+                pad: [0; 64],
+            };
+            unsafe { kvm_enable_cap(&self.fd, &request) }.context(kvm_error::EnableCap {
+                cap: "KVM_CAP_HYPERV_SYNIC2",
+            })?;
+            enabled.synic = true;
+            // Synthetic timers are driven by the partition reference time
+            // and deliver messages or interrupts through the SynIC.
+            enabled.stimer = hv.stimer && enabled.time;
+        }
+        Ok(enabled)
+    }
+
+    pub fn kvm_set_msrs(&mut self, msrs: &[(u32, u64)]) -> Result<(), Error> {
+        if msrs.len() > KVM_MAX_MSR_ENTRIES {
+            let e = std::io::Error::from(std::io::ErrorKind::InvalidInput);
+            return Err(e).context(error::SetMsrs);
+        }
+        let mut kvm_msrs = KvmMsrs {
+            nmsrs: msrs.len() as u32,
+            pad: 0,
+            entries: [KvmMsrEntry::default(); KVM_MAX_MSR_ENTRIES],
+        };
+        for ((index, data), entry) in std::iter::zip(msrs, kvm_msrs.entries.iter_mut()) {
+            entry.index = *index;
+            entry.data = *data;
+        }
+        let ret = unsafe { kvm_set_msrs(&self.fd, &kvm_msrs) }.context(error::SetMsrs)?;
+        if ret as usize != msrs.len() {
+            let (index, _) = msrs[ret as usize];
+            let e = std::io::Error::other(format!("MSR {index:#x} is not accepted"));
+            return Err(e).context(error::SetMsrs);
+        }
+        Ok(())
+    }
+
+    fn kvm_get_msr(&self, index: u32) -> std::io::Result<u64> {
+        let mut kvm_msrs = KvmMsrs {
+            nmsrs: 1,
+            pad: 0,
+            entries: [KvmMsrEntry {
+                index,
+                ..Default::default()
+            }],
+        };
+        let ret = unsafe { kvm_get_msrs(&self.fd, &mut kvm_msrs) }?;
+        if ret != 1 {
+            let e = std::io::Error::other(format!("MSR {index:#x} is not readable"));
+            return Err(e);
+        }
+        Ok(kvm_msrs.entries[0].data)
+    }
+
+    pub fn kvm_setup_mce(&mut self, banks: MceBanks) -> Result<MceBanks, Error> {
+        let max_banks = self.vm.check_extension(KvmCap::MCE)?;
+        let count = std::cmp::min(banks.count as i32, max_banks) as u8;
+        let enabled = MceBanks { count };
+        if count == 0 {
+            return Ok(enabled);
+        }
+        let mcg_cap = enabled.mcg_cap().bits();
+        unsafe { kvm_x86_setup_mce(&self.fd, &mcg_cap) }.context(error::SetupMce)?;
+        Ok(enabled)
+    }
+
+    /// Raises a machine check reported by `bank`. KVM shuts the guest down
+    /// instead if the error is uncorrected and the guest cannot take a #MC.
+    pub fn kvm_inject_mce(
+        &mut self,
+        bank: u8,
+        status: MciStatus,
+        mcg_status: McgStatus,
+    ) -> Result<(), Error> {
+        let mce = KvmX86Mce {
+            status: status.bits(),
+            mcg_status: mcg_status.bits(),
+            bank,
+            ..Default::default()
+        };
+        unsafe { kvm_x86_set_mce(&self.fd, &mce) }.context(error::InjectMce)?;
+        Ok(())
+    }
+
+    /// A guest shuts down on a machine check raised while another one is in
+    /// progress, which real hardware reports with a reset.
+    pub(super) fn handle_shutdown(&mut self) -> Result<VmExit, Error> {
+        let status = match self.kvm_get_msr(IA32_MCG_STATUS) {
+            Ok(status) => McgStatus::from_bits_retain(status),
+            Err(e) => {
+                log::error!("failed to read IA32_MCG_STATUS: {e}");
+                return Ok(VmExit::Shutdown);
+            }
+        };
+        if !status.contains(McgStatus::MCIP) {
+            return Ok(VmExit::Shutdown);
+        }
+        log::error!("guest shut down by a machine check, IA32_MCG_STATUS = {status:?}");
+        Ok(VmExit::Reboot)
+    }
 }
 
 #[cfg(test)]
@@ -467,6 +608,65 @@ mod test {
         }
     }
 
+    #[test]
+    #[cfg_attr(not(feature = "test-hv"), ignore)]
+    fn test_vcpu_sregs2() {
+        use crate::hv::kvm::bindings::KvmCap;
+        use crate::hv::kvm::KvmConfig;
+        use crate::hv::VmConfig;
+
+        let kvm = Kvm::new(KvmConfig::default()).unwrap();
+        let vm = kvm.create_vm(&VmConfig { coco: None }).unwrap();
+        if vm.vm.check_extension(KvmCap::SREGS2).unwrap() == 0 {
+            return;
+        }
+        let mut vcpu = vm.create_vcpu(0).unwrap();
+        assert!(vcpu.sregs2);
+
+        let sregs = [
+            (SReg::Cr0, (Cr0::PE | Cr0::NE | Cr0::PG).bits() as u64),
+            (SReg::Cr3, 0x1000),
+            (SReg::Cr4, Cr4::PAE.bits() as u64),
+            (SReg::Efer, (Efer::LME | Efer::LMA).bits() as u64),
+        ];
+        let code = SegRegVal {
+            selector: 0x10,
+            base: 0,
+            limit: 0xffff_ffff,
+            access: SegAccess(0xa09b),
+        };
+        let data = SegRegVal {
+            selector: 0x18,
+            base: 0,
+            limit: 0xffff_ffff,
+            access: SegAccess(0xc093),
+        };
+        let seg_regs = [
+            (SegReg::Cs, code),
+            (SegReg::Ds, data),
+            (SegReg::Es, data),
+            (SegReg::Ss, data),
+        ];
+        let dt_regs = [(
+            DtReg::Gdtr,
+            DtRegVal {
+                base: 0x2000,
+                limit: 0x1f,
+            },
+        )];
+        vcpu.kvm_set_sregs2(&sregs, &seg_regs, &dt_regs).unwrap();
+
+        for (sreg, val) in sregs {
+            assert_eq!(vcpu.kvm_get_sreg2(sreg).unwrap(), val);
+        }
+        for (seg_reg, val) in seg_regs {
+            assert_eq!(vcpu.kvm_get_seg_reg2(seg_reg).unwrap(), val);
+        }
+        for (dt_reg, val) in dt_regs {
+            assert_eq!(vcpu.kvm_get_dt_reg2(dt_reg).unwrap(), val);
+        }
+    }
+
     #[test]
     #[cfg_attr(not(feature = "test-hv"), ignore)]
     fn test_kvm_run() {
@@ -629,4 +829,347 @@ mod test {
             })
         );
     }
+
+    #[test]
+    #[cfg_attr(not(feature = "test-hv"), ignore)]
+    fn test_vapic_addr() {
+        use crate::hv::kvm::KvmConfig;
+        use crate::hv::VmConfig;
+
+        let kvm = Kvm::new(KvmConfig::default()).unwrap();
+        let vm_config = VmConfig { coco: None };
+        let mut vm = kvm.create_vm(&vm_config).unwrap();
+        let memory = vm.create_vm_memory().unwrap();
+        let prot = PROT_WRITE | PROT_READ;
+        let flag = MAP_ANONYMOUS | MAP_SHARED;
+        let user_mem = ffi!(
+            unsafe { mmap(null_mut(), 0x1000, prot, flag, -1, 0) },
+            MAP_FAILED
+        )
+        .unwrap();
+        memory
+            .mem_map(0, 0, 0x1000, user_mem as usize, MemMapOption::default())
+            .unwrap();
+
+        let mut vcpu = vm.create_vcpu(0).unwrap();
+        if !vcpu.vapic {
+            return;
+        }
+        assert!(vcpu.set_vapic_addr(0x800).unwrap());
+        // the address must be backed by guest memory
+        assert_matches!(vcpu.set_vapic_addr(0x10_0000), Err(_));
+    }
+
+    #[test]
+    #[cfg_attr(not(feature = "test-hv"), ignore)]
+    fn test_tsc_khz() {
+        use crate::arch::tsc::set_tsc_cpuids;
+        use crate::hv::kvm::ioctls::kvm_get_tsc_khz;
+        use crate::hv::kvm::KvmConfig;
+        use crate::hv::VmConfig;
+
+        let kvm = Kvm::new(KvmConfig::default()).unwrap();
+        let vm_config = VmConfig { coco: None };
+        let mut vm = kvm.create_vm(&vm_config).unwrap();
+        let memory = vm.create_vm_memory().unwrap();
+        let prot = PROT_WRITE | PROT_EXEC | PROT_READ;
+        let flag = MAP_ANONYMOUS | MAP_SHARED;
+        let user_mem = ffi!(
+            unsafe { mmap(null_mut(), 0x1000, prot, flag, -1, 0) },
+            MAP_FAILED
+        )
+        .unwrap();
+        let mmap_option = MemMapOption {
+            read: true,
+            write: true,
+            exec: true,
+            ..Default::default()
+        };
+        memory
+            .mem_map(0, 0, 0x1000, user_mem as usize, mmap_option)
+            .unwrap();
+
+        #[rustfmt::skip]
+        const CODE: [u8; 15] = [
+            // mov eax, 0x15
+            0x66, 0xb8, 0x15, 0x00, 0x00, 0x00,
+            // cpuid
+            0x0f, 0xa2,
+            // mov eax, ebx
+            0x66, 0x89, 0xd8,
+            // out 0x10, eax
+            0x66, 0xe7, 0x10,
+            // hlt
+            0xf4,
+        ];
+        unsafe { (user_mem as *mut [u8; 15]).write(CODE) };
+
+        let mut vcpu = vm.create_vcpu(0).unwrap();
+        let host_khz = unsafe { kvm_get_tsc_khz(&vcpu.fd) }.unwrap() as u32;
+        let khz = if vcpu.tsc_control {
+            1_000_000
+        } else {
+            host_khz
+        };
+        assert!(vcpu.set_tsc_khz(khz).unwrap());
+        assert_eq!(unsafe { kvm_get_tsc_khz(&vcpu.fd) }.unwrap() as u32, khz);
+
+        let mut cpuids = kvm.get_supported_cpuids().unwrap();
+        set_tsc_cpuids(&mut cpuids, khz, true);
+        vcpu.set_cpuids(cpuids).unwrap();
+
+        let cs = SegRegVal {
+            selector: 0,
+            base: 0,
+            limit: 0xffff,
+            access: SegAccess(0x9b),
+        };
+        vcpu.set_sregs(&[], &[(SegReg::Cs, cs)], &[]).unwrap();
+        vcpu.set_regs(&[(Reg::Rip, 0), (Reg::Rflags, 0x2)]).unwrap();
+        assert_matches!(
+            vcpu.run(VmEntry::None),
+            Ok(VmExit::Io {
+                port: 0x10,
+                write: Some(w),
+                size: 4
+            }) if w == khz
+        );
+    }
+
+    #[test]
+    #[cfg_attr(not(feature = "test-hv"), ignore)]
+    fn test_mce_shutdown() {
+        use crate::arch::mce::MceBanks;
+        use crate::arch::msr::{McgStatus, MciStatus, IA32_MCG_STATUS};
+        use crate::hv::kvm::KvmConfig;
+        use crate::hv::VmConfig;
+
+        let kvm = Kvm::new(KvmConfig::default()).unwrap();
+        let vm_config = VmConfig { coco: None };
+        let mut vm = kvm.create_vm(&vm_config).unwrap();
+        let memory = vm.create_vm_memory().unwrap();
+        let prot = PROT_WRITE | PROT_EXEC | PROT_READ;
+        let flag = MAP_ANONYMOUS | MAP_SHARED;
+        let user_mem = ffi!(
+            unsafe { mmap(null_mut(), 0x1000, prot, flag, -1, 0) },
+            MAP_FAILED
+        )
+        .unwrap();
+        let mmap_option = MemMapOption {
+            read: true,
+            write: true,
+            exec: true,
+            ..Default::default()
+        };
+        memory
+            .mem_map(0, 0, 0x1000, user_mem as usize, mmap_option)
+            .unwrap();
+
+        #[rustfmt::skip]
+        const CODE: [u8; 10] = [
+            // mov ecx, 0x179
+            0xb9, 0x79, 0x01, 0x00, 0x00,
+            // rdmsr
+            0x0f, 0x32,
+            // out 0x10, eax
+            0xe7, 0x10,
+            // hlt
+            0xf4,
+        ];
+        unsafe { (user_mem as *mut [u8; 10]).write(CODE) };
+
+        let mut vcpu = vm.create_vcpu(0).unwrap();
+        let banks = vcpu.setup_mce(MceBanks::default()).unwrap();
+        assert!(banks.count > 0);
+
+        let cs = SegRegVal {
+            selector: 0x8,
+            base: 0,
+            limit: 0xffff_ffff,
+            access: SegAccess(0xc09b),
+        };
+        let ds = SegRegVal {
+            selector: 0x10,
+            base: 0,
+            limit: 0xffff_ffff,
+            access: SegAccess(0xc093),
+        };
+        vcpu.set_sregs(
+            &[
+                (SReg::Cr0, (Cr0::NE | Cr0::PE).bits() as u64),
+                (SReg::Cr4, Cr4::MCE.bits() as u64),
+            ],
+            &[
+                (SegReg::Cs, cs),
+                (SegReg::Ds, ds),
+                (SegReg::Es, ds),
+                (SegReg::Fs, ds),
+                (SegReg::Gs, ds),
+                (SegReg::Ss, ds),
+            ],
+            &[],
+        )
+        .unwrap();
+        let regs = [(Reg::Rip, 0), (Reg::Rflags, 0x2)];
+        vcpu.set_regs(&regs).unwrap();
+        assert_matches!(
+            vcpu.run(VmEntry::None),
+            Ok(VmExit::Io {
+                port: 0x10,
+                write: Some(cap),
+                size: 4
+            }) if cap & 0xff == banks.count as u32
+        );
+
+        // A second uncorrected error while the first #MC is still being
+        // handled escalates to a shutdown.
+        let mcg_status = McgStatus::MCIP | McgStatus::RIPV;
+        vcpu.set_msrs(&[(IA32_MCG_STATUS, mcg_status.bits())])
+            .unwrap();
+        let status = MciStatus::VAL | MciStatus::UC | MciStatus::EN | MciStatus::PCC;
+        vcpu.kvm_inject_mce(0, status, mcg_status).unwrap();
+        assert_matches!(vcpu.run(VmEntry::None), Ok(VmExit::Reboot));
+    }
+
+    #[test]
+    #[cfg_attr(not(feature = "test-hv"), ignore)]
+    fn test_hyperv_reference_tsc() {
+        use crate::arch::hyperv::{
+            set_hyperv_cpuids, HypervEnlightenments, HV_X64_MSR_GUEST_OS_ID,
+            HV_X64_MSR_REFERENCE_TSC, HV_X64_MSR_REFERENCE_TSC_ENABLE,
+        };
+        use crate::hv::kvm::KvmConfig;
+        use crate::hv::VmConfig;
+
+        let kvm = Kvm::new(KvmConfig::default()).unwrap();
+        let vm_config = VmConfig { coco: None };
+        let mut vm = kvm.create_vm(&vm_config).unwrap();
+        let memory = vm.create_vm_memory().unwrap();
+        let prot = PROT_WRITE | PROT_EXEC | PROT_READ;
+        let flag = MAP_ANONYMOUS | MAP_SHARED;
+        let user_mem = ffi!(
+            unsafe { mmap(null_mut(), 0x2000, prot, flag, -1, 0) },
+            MAP_FAILED
+        )
+        .unwrap();
+        let mmap_option = MemMapOption {
+            read: true,
+            write: true,
+            exec: true,
+            ..Default::default()
+        };
+        memory
+            .mem_map(0, 0, 0x2000, user_mem as usize, mmap_option)
+            .unwrap();
+        // out 0x10, al
+        unsafe { (user_mem as *mut [u8; 2]).write([0xe6, 0x10]) };
+
+        let mut vcpu = vm.create_vcpu(0).unwrap();
+        let hv = HypervEnlightenments {
+            time: true,
+            synic: true,
+            stimer: true,
+        };
+        assert_matches!(vcpu.set_msrs(&[(0xdead_beef, 0)]), Err(_));
+        let enabled = vcpu.enable_hyperv(&hv).unwrap();
+        // KVM may be built without Hyper-V support.
+        if !enabled.time {
+            return;
+        }
+        let mut cpuids = kvm.get_supported_cpuids().unwrap();
+        set_hyperv_cpuids(&mut cpuids, &enabled, 1);
+        vcpu.set_cpuids(cpuids).unwrap();
+        vcpu.set_msrs(&[
+            (HV_X64_MSR_GUEST_OS_ID, 0x8100_0000_0000_0000),
+            (
+                HV_X64_MSR_REFERENCE_TSC,
+                0x1000 | HV_X64_MSR_REFERENCE_TSC_ENABLE,
+            ),
+        ])
+        .unwrap();
+
+        let cs = SegRegVal {
+            selector: 0,
+            base: 0,
+            limit: 0xffff,
+            access: SegAccess(0x9b),
+        };
+        vcpu.set_sregs(&[], &[(SegReg::Cs, cs)], &[]).unwrap();
+        vcpu.set_regs(&[(Reg::Rip, 0), (Reg::Rflags, 0x2)]).unwrap();
+        assert_matches!(
+            vcpu.run(VmEntry::None),
+            Ok(VmExit::Io {
+                port: 0x10,
+                write: Some(_),
+                size: 1
+            })
+        );
+
+        // struct ms_hyperv_tsc_page: tsc_sequence, reserved, tsc_scale
+        let page = unsafe { &*((user_mem as usize + 0x1000) as *const [u64; 2]) };
+        assert_ne!(page[0] as u32, 0);
+        assert_ne!(page[1], 0);
+    }
+
+    #[test]
+    #[cfg_attr(not(feature = "test-hv"), ignore)]
+    fn test_pvclock() {
+        use crate::arch::pvclock::{
+            PvclockVcpuTimeInfo, PvclockWallClock, KVM_SYSTEM_TIME_ENABLE, MSR_KVM_SYSTEM_TIME_NEW,
+            MSR_KVM_WALL_CLOCK_NEW,
+        };
+        use crate::hv::kvm::KvmConfig;
+        use crate::hv::VmConfig;
+
+        let kvm = Kvm::new(KvmConfig::default()).unwrap();
+        let vm_config = VmConfig { coco: None };
+        let mut vm = kvm.create_vm(&vm_config).unwrap();
+        let memory = vm.create_vm_memory().unwrap();
+        let prot = PROT_WRITE | PROT_EXEC | PROT_READ;
+        let flag = MAP_ANONYMOUS | MAP_SHARED;
+        let user_mem = ffi!(
+            unsafe { mmap(null_mut(), 0x2000, prot, flag, -1, 0) },
+            MAP_FAILED
+        )
+        .unwrap();
+        let mmap_option = MemMapOption {
+            read: true,
+            write: true,
+            exec: true,
+            ..Default::default()
+        };
+        memory
+            .mem_map(0, 0, 0x2000, user_mem as usize, mmap_option)
+            .unwrap();
+        // out 0x10, al
+        unsafe { (user_mem as *mut [u8; 2]).write([0xe6, 0x10]) };
+
+        const NS: u64 = 1_000_000_000;
+        vm.set_clock(100 * NS).unwrap();
+        let mut vcpu = vm.create_vcpu(0).unwrap();
+        vcpu.set_msrs(&[
+            (MSR_KVM_WALL_CLOCK_NEW, 0x1000),
+            (MSR_KVM_SYSTEM_TIME_NEW, 0x1040 | KVM_SYSTEM_TIME_ENABLE),
+        ])
+        .unwrap();
+        let cs = SegRegVal {
+            selector: 0,
+            base: 0,
+            limit: 0xffff,
+            access: SegAccess(0x9b),
+        };
+        vcpu.set_sregs(&[], &[(SegReg::Cs, cs)], &[]).unwrap();
+        vcpu.set_regs(&[(Reg::Rip, 0), (Reg::Rflags, 0x2)]).unwrap();
+        assert_matches!(vcpu.run(VmEntry::None), Ok(VmExit::Io { port: 0x10, .. }));
+
+        let wall_clock = unsafe { &*((user_mem as usize + 0x1000) as *const PvclockWallClock) };
+        assert_eq!(wall_clock.version & 1, 0);
+        assert_ne!(wall_clock.version, 0);
+        let info = unsafe { &*((user_mem as usize + 0x1040) as *const PvclockVcpuTimeInfo) };
+        assert_eq!(info.version & 1, 0);
+        assert_ne!(info.version, 0);
+        assert_ne!(info.tsc_to_system_mul, 0);
+        assert!(info.system_time >= 100 * NS);
+        assert!(info.system_time < 110 * NS);
+    }
 }
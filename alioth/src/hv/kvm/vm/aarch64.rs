@@ -12,13 +12,18 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use snafu::ResultExt;
+
 use crate::hv::kvm::bindings::{
-    KvmDevArmVgicCtrl, KvmDevArmVgicGrp, KvmDevType, KvmVgicAddrType, KvmVgicV3RedistRegion,
+    KvmDevArmVgicCtrl, KvmDevArmVgicGrp, KvmDevType, KvmDeviceAttr, KvmSmcccFilter,
+    KvmSmcccFilterAction, KvmVgicAddrType, KvmVgicV3RedistRegion, KVM_ARM_VM_SMCCC_CTRL,
+    KVM_ARM_VM_SMCCC_FILTER,
 };
 use crate::hv::kvm::device::KvmDevice;
+use crate::hv::kvm::ioctls::kvm_set_device_attr;
 use crate::hv::kvm::vm::KvmVm;
 use crate::hv::kvm::Result;
-use crate::hv::{GicV2, GicV3, Its};
+use crate::hv::{error, GicV2, GicV3, Its};
 
 #[derive(Debug)]
 pub struct KvmGicV2 {
@@ -162,3 +167,29 @@ impl KvmVm {
         Ok(KvmIts { dev })
     }
 }
+
+impl KvmVm {
+    pub fn kvm_forward_smccc(&self, func_id: u32) -> Result<()> {
+        let filter = KvmSmcccFilter {
+            base: func_id,
+            nr_functions: 1,
+            action: KvmSmcccFilterAction::FWD_TO_USER,
+            pad: [0; 15],
+        };
+        let attr = KvmDeviceAttr {
+            _flags: 0,
+            group: KVM_ARM_VM_SMCCC_CTRL,
+            attr: KVM_ARM_VM_SMCCC_FILTER,
+            addr: &filter as *const _ as u64,
+        };
+        match unsafe { kvm_set_device_attr(&self.vm, &attr) } {
+            Ok(_) => Ok(()),
+            // The SMCCC filter is available since Linux 6.4.
+            Err(e) if e.raw_os_error() == Some(libc::ENXIO) => error::Unsupported {
+                op: "SMCCC forwarding",
+            }
+            .fail(),
+            Err(e) => Err(e).context(error::ForwardSmccc { func_id }),
+        }
+    }
+}
@@ -36,21 +36,22 @@ use crate::hv::kvm::bindings::KvmMsiFlag;
 #[cfg(target_arch = "x86_64")]
 use crate::hv::kvm::bindings::KVM_IRQCHIP_IOAPIC;
 use crate::hv::kvm::bindings::{
-    KvmCap, KvmEncRegion, KvmIoEventFd, KvmIoEventFdFlag, KvmIrqRouting, KvmIrqRoutingEntry,
-    KvmIrqRoutingIrqchip, KvmIrqRoutingMsi, KvmIrqfd, KvmIrqfdFlag, KvmMemFlag, KvmMemoryAttribute,
-    KvmMemoryAttributes, KvmMsi, KvmUserspaceMemoryRegion, KvmUserspaceMemoryRegion2,
-    KVM_IRQ_ROUTING_IRQCHIP, KVM_IRQ_ROUTING_MSI,
+    KvmCap, KvmDirtyLog, KvmEncRegion, KvmIoEventFd, KvmIoEventFdFlag, KvmIrqRouting,
+    KvmIrqRoutingEntry, KvmIrqRoutingIrqchip, KvmIrqRoutingMsi, KvmIrqfd, KvmIrqfdFlag, KvmMemFlag,
+    KvmMemoryAttribute, KvmMemoryAttributes, KvmMsi, KvmUserspaceMemoryRegion,
+    KvmUserspaceMemoryRegion2, KVM_IRQ_ROUTING_IRQCHIP, KVM_IRQ_ROUTING_MSI,
 };
 use crate::hv::kvm::ioctls::{
-    kvm_check_extension, kvm_create_vcpu, kvm_ioeventfd, kvm_irqfd, kvm_memory_encrypt_reg_region,
-    kvm_memory_encrypt_unreg_region, kvm_set_gsi_routing, kvm_set_memory_attributes,
-    kvm_set_user_memory_region, kvm_set_user_memory_region2, kvm_signal_msi,
+    kvm_check_extension, kvm_create_vcpu, kvm_get_dirty_log, kvm_ioeventfd, kvm_irqfd,
+    kvm_memory_encrypt_reg_region, kvm_memory_encrypt_unreg_region, kvm_set_gsi_routing,
+    kvm_set_memory_attributes, kvm_set_user_memory_region, kvm_set_user_memory_region2,
+    kvm_signal_msi,
 };
 use crate::hv::kvm::vcpu::{KvmRunBlock, KvmVcpu};
 use crate::hv::kvm::{kvm_error, KvmError};
 use crate::hv::{
     error, Error, IoeventFd, IoeventFdRegistry, IrqFd, IrqSender, MemMapOption, MsiSender, Result,
-    Vm, VmMemory,
+    VcpuStats, Vm, VmMemory,
 };
 
 #[cfg(target_arch = "x86_64")]
@@ -216,6 +217,16 @@ impl VmMemory for KvmMemory {
             .map(|r| r as u32)
     }
 
+    fn get_dirty_log(&self, slot: u32, bitmap: &mut [u64]) -> Result<()> {
+        let log = KvmDirtyLog {
+            slot,
+            dirty_bitmap: bitmap.as_mut_ptr() as u64,
+            ..Default::default()
+        };
+        unsafe { kvm_get_dirty_log(&self.vm, &log) }.context(error::DirtyLog { slot })?;
+        Ok(())
+    }
+
     fn register_encrypted_range(&self, range: &[u8]) -> Result<()> {
         let region = KvmEncRegion {
             addr: range.as_ptr() as u64,
@@ -427,6 +438,11 @@ impl IrqFd for KvmIrqFd {
 
 const MAX_GSI_ROUTES: usize = 256;
 
+/// Sends MSIs with `KVM_SIGNAL_MSI`.
+///
+/// On AArch64, messages carry the device ID, with which the in-kernel ITS
+/// looks up the LPI in the tables that the guest set up with its own ITS
+/// commands.
 #[derive(Debug)]
 pub struct KvmMsiSender {
     vm: Arc<VmInner>,
@@ -570,6 +586,14 @@ impl Vm for KvmVm {
             fd: unsafe { OwnedFd::from_raw_fd(vcpu_fd) },
             kvm_run,
             vm: self.vm.clone(),
+            stats: Arc::new(VcpuStats::default()),
+            #[cfg(target_arch = "x86_64")]
+            vapic: self.vm.check_extension(KvmCap::VAPIC)? > 0,
+            #[cfg(target_arch = "x86_64")]
+            tsc_control: self.vm.check_extension(KvmCap::TSC_CONTROL)? > 0,
+            #[cfg(target_arch = "x86_64")]
+            sregs2: self.vm.check_extension(KvmCap::SREGS2)? > 0,
+            immediate_exit: self.vm.check_extension(KvmCap::IMMEDIATE_EXIT)? > 0,
         })
     }
 
@@ -681,6 +705,16 @@ impl Vm for KvmVm {
         self.kvm_snp_launch_finish()
     }
 
+    #[cfg(target_arch = "x86_64")]
+    fn set_clock(&self, ns: u64) -> Result<()> {
+        self.kvm_set_clock(ns)
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn get_clock(&self) -> Result<u64> {
+        self.kvm_get_clock()
+    }
+
     #[cfg(target_arch = "aarch64")]
     type GicV2 = aarch64::KvmGicV2;
     #[cfg(target_arch = "aarch64")]
@@ -706,6 +740,11 @@ impl Vm for KvmVm {
     fn create_its(&self, base: u64) -> Result<Self::Its> {
         self.kvm_create_its(base)
     }
+
+    #[cfg(target_arch = "aarch64")]
+    fn forward_smccc(&self, func_id: u32) -> Result<()> {
+        self.kvm_forward_smccc(func_id)
+    }
 }
 
 #[cfg(test)]
@@ -766,4 +805,18 @@ mod test {
             .unwrap();
         vm_memory.mem_map(0, 0x0, 0, 0, option).unwrap();
     }
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    #[cfg_attr(not(feature = "test-hv"), ignore)]
+    fn test_clock() {
+        let kvm = Kvm::new(KvmConfig::default()).unwrap();
+        let vm_config = VmConfig { coco: None };
+        let vm = kvm.create_vm(&vm_config).unwrap();
+        let ns = 3_600_000_000_000;
+        vm.set_clock(ns).unwrap();
+        let clock = vm.get_clock().unwrap();
+        assert!(clock >= ns);
+        assert!(clock - ns < 1_000_000_000, "{clock} - {ns}");
+    }
 }
@@ -17,7 +17,8 @@ use std::os::fd::{AsFd, AsRawFd};
 use snafu::ResultExt;
 
 use crate::arch::sev::{SnpPageType, SnpPolicy};
-use crate::hv::kvm::ioctls::kvm_memory_encrypt_op;
+use crate::hv::kvm::bindings::KvmClockData;
+use crate::hv::kvm::ioctls::{kvm_get_clock, kvm_memory_encrypt_op, kvm_set_clock};
 use crate::hv::kvm::sev::bindings::{
     KvmSevCmd, KvmSevLaunchMeasure, KvmSevLaunchStart, KvmSevLaunchUpdateData,
     KvmSevSnpLaunchFinish, KvmSevSnpLaunchStart, KvmSevSnpLaunchUpdate, KVM_SEV_LAUNCH_FINISH,
@@ -27,7 +28,7 @@ use crate::hv::kvm::sev::bindings::{
 };
 use crate::hv::kvm::sev::SevFd;
 use crate::hv::kvm::{kvm_error, KvmError, KvmVm};
-use crate::hv::Result;
+use crate::hv::{error, Result};
 
 #[derive(Debug)]
 pub struct VmArch {
@@ -124,4 +125,18 @@ impl KvmVm {
         self.sev_op(KVM_SEV_SNP_LAUNCH_FINISH, Some(&mut finish))?;
         Ok(())
     }
+
+    pub fn kvm_set_clock(&self, ns: u64) -> Result<()> {
+        let data = KvmClockData {
+            clock: ns,
+            ..Default::default()
+        };
+        unsafe { kvm_set_clock(&self.vm, &data) }.context(error::SetClock)?;
+        Ok(())
+    }
+
+    pub fn kvm_get_clock(&self) -> Result<u64> {
+        let data = unsafe { kvm_get_clock(&self.vm) }.context(error::GetClock)?;
+        Ok(data.clock)
+    }
 }
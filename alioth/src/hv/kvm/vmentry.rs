@@ -12,7 +12,11 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+#[cfg(target_arch = "aarch64")]
+use crate::arch::reg::Reg;
 use crate::hv::kvm::bindings::{KvmExit, KvmExitIo};
+#[cfg(target_arch = "aarch64")]
+use crate::hv::Result;
 
 use super::vcpu::KvmVcpu;
 
@@ -50,4 +54,12 @@ impl KvmVcpu {
             _ => unreachable!("kvm_io.size = {}", kvm_io.size),
         }
     }
+
+    #[cfg(target_arch = "aarch64")]
+    pub(super) fn entry_smccc(&mut self, ret: [u64; 4]) -> Result<()> {
+        assert_eq!(self.kvm_run.exit_reason, KvmExit::HYPERCALL);
+        let regs = [Reg::X0, Reg::X1, Reg::X2, Reg::X3];
+        let vals: Vec<_> = regs.into_iter().zip(ret).collect();
+        self.kvm_set_regs(&vals)
+    }
 }
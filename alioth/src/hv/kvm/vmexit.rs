@@ -12,9 +12,13 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use crate::hv::kvm::bindings::{
-    KvmExitIo, KvmMapGpaRangeFlag, KvmSystemEvent, KVM_HC_MAP_GPA_RANGE,
-};
+#[cfg(target_arch = "aarch64")]
+use crate::arch::reg::Reg;
+#[cfg(target_arch = "aarch64")]
+use crate::hv::kvm::bindings::KvmHypercallExitFlag;
+use crate::hv::kvm::bindings::{KvmExitIo, KvmSystemEvent};
+#[cfg(target_arch = "x86_64")]
+use crate::hv::kvm::bindings::{KvmMapGpaRangeFlag, KVM_HC_MAP_GPA_RANGE};
 use crate::hv::{Error, VmExit};
 
 use super::vcpu::KvmVcpu;
@@ -63,6 +67,7 @@ impl KvmVcpu {
         })
     }
 
+    #[cfg(target_arch = "x86_64")]
     pub(super) fn handle_hypercall(&mut self) -> Result<VmExit, Error> {
         let hypercall = unsafe { self.kvm_run.exit.hypercall };
         match hypercall.nr {
@@ -78,6 +83,40 @@ impl KvmVcpu {
         }
     }
 
+    /// Decodes an SMC or HVC call forwarded by the SMCCC filter.
+    #[cfg(target_arch = "aarch64")]
+    pub(super) fn handle_hypercall(&mut self) -> Result<VmExit, Error> {
+        let hypercall = unsafe { self.kvm_run.exit.hypercall };
+        let flags = KvmHypercallExitFlag::from_bits_retain(hypercall.flags);
+        // PC points to an SMC instruction but past an HVC instruction.
+        if flags.contains(KvmHypercallExitFlag::SMC) {
+            let len = if flags.contains(KvmHypercallExitFlag::BIT16) {
+                2
+            } else {
+                4
+            };
+            let pc = self.kvm_get_reg(Reg::Pc)?;
+            self.kvm_set_regs(&[(Reg::Pc, pc + len)])?;
+        }
+        let mut args = [0; 7];
+        let regs = [
+            Reg::X1,
+            Reg::X2,
+            Reg::X3,
+            Reg::X4,
+            Reg::X5,
+            Reg::X6,
+            Reg::X7,
+        ];
+        for (arg, reg) in args.iter_mut().zip(regs) {
+            *arg = self.kvm_get_reg(reg)?;
+        }
+        Ok(VmExit::Smccc {
+            func_id: hypercall.nr as u32,
+            args,
+        })
+    }
+
     pub(super) fn handle_system_event(&mut self) -> Result<VmExit, Error> {
         let kvm_system_event = unsafe { &self.kvm_run.exit.system_event };
         match kvm_system_event.type_ {
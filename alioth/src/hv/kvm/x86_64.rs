@@ -41,19 +41,36 @@ impl Kvm {
         vm_fd: &OwnedFd,
     ) -> Result<Option<OwnedFd>> {
         let memfd = if let Some(Coco::AmdSnp { .. }) = &config.coco {
-            let mut request = KvmCreateGuestMemfd {
-                size: 1 << 48,
-                ..Default::default()
-            };
-            let ret = unsafe { kvm_create_guest_memfd(vm_fd, &mut request) }
-                .context(kvm_error::GuestMemfd)?;
-            Some(unsafe { OwnedFd::from_raw_fd(ret) })
+            Some(Self::new_guest_memfd(vm_fd, 1 << 48)?)
         } else {
             None
         };
         Ok(memfd)
     }
 
+    /// Creates a guest memfd of `size` bytes. Memory slots of a VM with a
+    /// guest memfd are set up with KVM_SET_USER_MEMORY_REGION2.
+    fn new_guest_memfd(vm_fd: &OwnedFd, size: u64) -> Result<OwnedFd> {
+        let caps = [
+            (KvmCap::USER_MEMORY2, "KVM_CAP_USER_MEMORY2"),
+            (KvmCap::GUEST_MEMFD, "KVM_CAP_GUEST_MEMFD"),
+        ];
+        for (cap, name) in caps {
+            let ret = unsafe { kvm_check_extension(vm_fd, cap) }
+                .context(kvm_error::CheckExtension { ext: name })?;
+            if ret == 0 {
+                return error::Capability { cap: name }.fail();
+            }
+        }
+        let mut request = KvmCreateGuestMemfd {
+            size,
+            ..Default::default()
+        };
+        let ret = unsafe { kvm_create_guest_memfd(vm_fd, &mut request) }
+            .context(kvm_error::GuestMemfd)?;
+        Ok(unsafe { OwnedFd::from_raw_fd(ret) })
+    }
+
     pub(super) fn create_vm_arch(&self, config: &VmConfig) -> Result<VmArch> {
         let sev_fd = if let Some(cv) = &config.coco {
             match cv {
@@ -114,8 +131,20 @@ impl Kvm {
 
 #[cfg(test)]
 mod test {
+    use std::collections::HashMap;
+    use std::os::fd::{FromRawFd, OwnedFd};
+    use std::sync::atomic::AtomicU32;
+    use std::sync::Arc;
+
+    use parking_lot::{Mutex, RwLock};
+
+    use crate::hv::kvm::bindings::{KvmCap, KvmVmType};
+    use crate::hv::kvm::ioctls::{kvm_check_extension, kvm_create_vm};
+    use crate::hv::kvm::vm::{KvmMemory, VmArch, VmInner};
     use crate::hv::kvm::{Kvm, KvmConfig};
     use crate::hv::Hypervisor;
+    use crate::mem::mapped::{ArcMemPages, RamBus};
+    use crate::mem::MemRegionType;
 
     #[test]
     #[cfg_attr(not(feature = "test-hv"), ignore)]
@@ -134,4 +163,35 @@ mod test {
         }
         assert!(kvm_cpuid_exist);
     }
+
+    #[test]
+    #[cfg_attr(not(feature = "test-hv"), ignore)]
+    fn test_guest_memfd_mem_map() {
+        let kvm = Kvm::new(KvmConfig::default()).unwrap();
+        let vm_types = unsafe { kvm_check_extension(&kvm.fd, KvmCap::VM_TYPES) }.unwrap();
+        // KVM_X86_SW_PROTECTED_VM is not supported
+        if vm_types & (1 << KvmVmType::SW_PROTECTED.raw()) == 0 {
+            return;
+        }
+        let vm_fd = unsafe { kvm_create_vm(&kvm.fd, KvmVmType::SW_PROTECTED) }.unwrap();
+        let fd = unsafe { OwnedFd::from_raw_fd(vm_fd) };
+        let memfd = Kvm::new_guest_memfd(&fd, 1 << 30).unwrap();
+        let vm = Arc::new(VmInner {
+            fd,
+            memfd: Some(memfd),
+            ioeventfds: Mutex::new(HashMap::new()),
+            msi_table: RwLock::new(HashMap::new()),
+            next_msi_gsi: AtomicU32::new(0),
+            pin_map: AtomicU32::new(0),
+            arch: VmArch { sev_fd: None },
+        });
+
+        let ram_bus = RamBus::new(KvmMemory { vm });
+        let pages = ArcMemPages::from_anonymous(0x2000, None).unwrap();
+        ram_bus
+            .add(0x1000, pages, MemRegionType::Ram, false)
+            .unwrap();
+        ram_bus.write(0x1ff8, &0x1234_5678_9abc_def0u64).unwrap();
+        assert_eq!(ram_bus.read::<u64>(0x1ff8).unwrap(), 0x1234_5678_9abc_def0);
+    }
 }
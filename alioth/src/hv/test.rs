@@ -11,8 +11,16 @@
 // WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
 // See the License for the specific language governing permissions and
 // limitations under the License.
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
 
-use super::{error, MemMapOption, Result};
+use libc::{eventfd, EFD_CLOEXEC, EFD_NONBLOCK};
+use parking_lot::Mutex;
+use snafu::ResultExt;
+
+use crate::ffi;
+
+use super::{error, IoeventFd, IoeventFdRegistry, IrqFd, MemMapOption, MsiSender, Result};
 
 #[derive(Debug)]
 pub struct FakeVmMemory;
@@ -37,7 +45,178 @@ impl crate::hv::VmMemory for FakeVmMemory {
         error::Capability { cap: "MaxMemSlots" }.fail()
     }
 
+    fn get_dirty_log(&self, _slot: u32, bitmap: &mut [u64]) -> Result<()> {
+        bitmap.fill(0);
+        Ok(())
+    }
+
     fn mark_private_memory(&self, _gpa: u64, _size: u64, _private: bool) -> Result<()> {
         unimplemented!()
     }
 }
+
+/// An eventfd that is never routed to a vCPU, with the MSI kept in memory.
+#[derive(Debug)]
+pub struct FakeIrqFd {
+    fd: OwnedFd,
+    addr_lo: AtomicU32,
+    addr_hi: AtomicU32,
+    data: AtomicU32,
+    masked: AtomicBool,
+}
+
+impl AsFd for FakeIrqFd {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.fd.as_fd()
+    }
+}
+
+impl IrqFd for FakeIrqFd {
+    fn set_addr_lo(&self, val: u32) -> Result<()> {
+        self.addr_lo.store(val, Ordering::Release);
+        Ok(())
+    }
+    fn get_addr_lo(&self) -> u32 {
+        self.addr_lo.load(Ordering::Acquire)
+    }
+    fn set_addr_hi(&self, val: u32) -> Result<()> {
+        self.addr_hi.store(val, Ordering::Release);
+        Ok(())
+    }
+    fn get_addr_hi(&self) -> u32 {
+        self.addr_hi.load(Ordering::Acquire)
+    }
+    fn set_data(&self, val: u32) -> Result<()> {
+        self.data.store(val, Ordering::Release);
+        Ok(())
+    }
+    fn get_data(&self) -> u32 {
+        self.data.load(Ordering::Acquire)
+    }
+    fn set_masked(&self, val: bool) -> Result<()> {
+        self.masked.store(val, Ordering::Release);
+        Ok(())
+    }
+    fn get_masked(&self) -> bool {
+        self.masked.load(Ordering::Acquire)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct FakeMsiSender {
+    pub msis: Mutex<Vec<(u64, u32)>>,
+    /// Number of calls into the sender, each standing for one syscall.
+    pub calls: AtomicU32,
+    /// Makes [`MsiSender::send`] fail.
+    pub fail: AtomicBool,
+    pub max_vectors: Option<u32>,
+}
+
+impl MsiSender for FakeMsiSender {
+    type IrqFd = FakeIrqFd;
+
+    fn send(&self, addr: u64, data: u32) -> Result<()> {
+        self.calls.fetch_add(1, Ordering::Relaxed);
+        if self.fail.load(Ordering::Relaxed) {
+            let e = std::io::ErrorKind::WouldBlock.into();
+            return Err(e).context(error::SendInterrupt);
+        }
+        self.msis.lock().push((addr, data));
+        Ok(())
+    }
+
+    fn send_batch(&self, msgs: &[(u64, u32)]) -> Result<()> {
+        self.calls.fetch_add(1, Ordering::Relaxed);
+        self.msis.lock().extend_from_slice(msgs);
+        Ok(())
+    }
+
+    fn create_irqfd(&self) -> Result<Self::IrqFd> {
+        let fd = ffi!(unsafe { eventfd(0, EFD_CLOEXEC | EFD_NONBLOCK) }).context(error::IrqFd)?;
+        Ok(FakeIrqFd {
+            fd: unsafe { OwnedFd::from_raw_fd(fd) },
+            addr_lo: AtomicU32::new(0),
+            addr_hi: AtomicU32::new(0),
+            data: AtomicU32::new(0),
+            masked: AtomicBool::new(true),
+        })
+    }
+
+    fn max_vectors(&self) -> Option<u32> {
+        self.max_vectors
+    }
+}
+
+/// An eventfd that is never registered with a hypervisor.
+#[derive(Debug)]
+pub struct FakeIoeventFd(OwnedFd);
+
+impl AsFd for FakeIoeventFd {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.0.as_fd()
+    }
+}
+
+impl IoeventFd for FakeIoeventFd {}
+
+#[derive(Debug, Default)]
+pub struct FakeIoeventFdRegistry {
+    /// Raw fds of all ioeventfds created.
+    pub fds: Mutex<Vec<RawFd>>,
+}
+
+impl IoeventFdRegistry for FakeIoeventFdRegistry {
+    type IoeventFd = FakeIoeventFd;
+
+    fn create(&self) -> Result<Self::IoeventFd> {
+        let fd =
+            ffi!(unsafe { eventfd(0, EFD_CLOEXEC | EFD_NONBLOCK) }).context(error::IoeventFd)?;
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        self.fds.lock().push(fd.as_raw_fd());
+        Ok(FakeIoeventFd(fd))
+    }
+
+    fn register(
+        &self,
+        _fd: &Self::IoeventFd,
+        _gpa: u64,
+        _len: u8,
+        _data: Option<u64>,
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn register_port(
+        &self,
+        _fd: &Self::IoeventFd,
+        _port: u16,
+        _len: u8,
+        _data: Option<u64>,
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    fn deregister(&self, _fd: &Self::IoeventFd) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn test_vcpu_stats_snapshot() {
+    use std::sync::atomic::Ordering;
+
+    use super::VcpuStats;
+
+    let stats = VcpuStats::default();
+    stats.io.fetch_add(3, Ordering::Relaxed);
+    stats.hlt.fetch_add(1, Ordering::Relaxed);
+    let before = stats.snapshot();
+    assert_eq!(before.total(), 4);
+
+    stats.mmio.fetch_add(5, Ordering::Relaxed);
+    let delta = stats.snapshot() - before;
+    assert_eq!(delta.mmio, 5);
+    assert_eq!(delta.io, 0);
+    assert_eq!(delta.total(), 5);
+}
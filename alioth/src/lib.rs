@@ -16,6 +16,8 @@
 pub mod arch;
 #[path = "board/board.rs"]
 pub mod board;
+#[cfg(target_os = "linux")]
+pub mod cgroup;
 #[path = "device/device.rs"]
 pub mod device;
 pub mod errors;
@@ -27,12 +29,17 @@ pub mod hv;
 pub mod loader;
 #[path = "mem/mem.rs"]
 pub mod mem;
+pub mod migration;
 #[path = "net/net.rs"]
 pub mod net;
 #[path = "pci/pci.rs"]
 pub mod pci;
 #[path = "utils/utils.rs"]
 pub(crate) mod utils;
+#[cfg(target_os = "linux")]
+#[path = "vfio/vfio.rs"]
+pub mod vfio;
 #[path = "virtio/virtio.rs"]
 pub mod virtio;
+#[path = "vm/vm.rs"]
 pub mod vm;
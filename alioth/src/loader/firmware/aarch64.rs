@@ -18,6 +18,10 @@ use crate::loader::{InitState, Result};
 use crate::mem::mapped::ArcMemPages;
 use crate::mem::Memory;
 
-pub fn load<P: AsRef<Path>>(_memory: &Memory, _path: P) -> Result<(InitState, ArcMemPages)> {
+pub fn load<P: AsRef<Path>>(
+    _memory: &Memory,
+    _path: P,
+    _vars: Option<&Path>,
+) -> Result<(InitState, ArcMemPages)> {
     unimplemented!()
 }
@@ -20,4 +20,4 @@ mod x86_64;
 #[cfg(target_arch = "aarch64")]
 pub use aarch64::load;
 #[cfg(target_arch = "x86_64")]
-pub use x86_64::load;
+pub use x86_64::{load, load_bios};
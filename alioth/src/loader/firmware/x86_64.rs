@@ -19,26 +19,50 @@ use std::sync::Arc;
 
 use snafu::ResultExt;
 
-use crate::arch::layout::MEM_64_START;
+use crate::arch::layout::{BIOS_ROM_END, BIOS_ROM_START, MEM_64_START};
 use crate::arch::reg::{Cr0, DtReg, DtRegVal, Reg, Rflags, SReg, SegAccess, SegReg, SegRegVal};
 use crate::loader::{error, InitState, Result};
 use crate::mem::mapped::ArcMemPages;
 use crate::mem::{MemRegion, MemRegionType, Memory};
 
-pub fn load<P: AsRef<Path>>(memory: &Memory, path: P) -> Result<(InitState, ArcMemPages)> {
-    let access_firmware = error::AccessFile {
-        path: path.as_ref(),
-    };
-    let mut file = File::open(&path).context(access_firmware)?;
-    let size = file.metadata().context(access_firmware)?.len();
+fn open_image(path: &Path) -> Result<(File, u64)> {
+    let access_image = error::AccessFile { path };
+    let file = File::open(path).context(access_image)?;
+    let size = file.metadata().context(access_image)?.len();
     if size & 0xfff != 0 {
         return error::SizeNotAligned { size }.fail();
     }
+    Ok((file, size))
+}
+
+/// Maps the firmware right below 4 GiB, with the variable store `vars`, if
+/// any, right below the firmware.
+pub fn load<P: AsRef<Path>>(
+    memory: &Memory,
+    path: P,
+    vars: Option<&Path>,
+) -> Result<(InitState, ArcMemPages)> {
+    let path = path.as_ref();
+    let (mut file, code_size) = open_image(path)?;
+    let (vars_file, vars_size) = match vars {
+        Some(vars) => {
+            let (file, size) = open_image(vars)?;
+            (Some((file, vars)), size)
+        }
+        None => (None, 0),
+    };
+    let size = vars_size + code_size;
 
     let mut rom =
         ArcMemPages::from_memfd(size as usize, None, Some(c"rom")).context(error::AddMemSlot)?;
-    file.read_exact(rom.as_slice_mut())
-        .context(access_firmware)?;
+    let (buf_vars, buf_code) = rom.as_slice_mut().split_at_mut(vars_size as usize);
+    if let Some((mut vars_file, path)) = vars_file {
+        vars_file
+            .read_exact(buf_vars)
+            .context(error::AccessFile { path })?;
+    }
+    file.read_exact(buf_code)
+        .context(error::AccessFile { path })?;
 
     let gpa = MEM_64_START - size;
     let region = Arc::new(MemRegion::with_mapped(rom.clone(), MemRegionType::Reserved));
@@ -132,3 +156,73 @@ pub fn load<P: AsRef<Path>>(memory: &Memory, path: P) -> Result<(InitState, ArcM
     };
     Ok((init, rom))
 }
+
+/// Maps the BIOS right below 4 GiB for the reset vector, and copies its
+/// last 128 KiB to the legacy BIOS area for the real mode code after it.
+pub fn load_bios<P: AsRef<Path>>(memory: &Memory, path: P) -> Result<(InitState, ArcMemPages)> {
+    let (init, rom) = load(memory, path, None)?;
+    let rom_slice = rom.as_slice();
+    let len = std::cmp::min(rom_slice.len() as u64, BIOS_ROM_END - BIOS_ROM_START);
+    let shadow = &rom_slice[rom_slice.len() - len as usize..];
+    memory
+        .ram_bus()
+        .write_range(BIOS_ROM_END - len, len, shadow)?;
+    Ok((init, rom))
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+
+    use crate::arch::layout::{BIOS_ROM_END, MEM_64_START};
+    use crate::hv::test::FakeVmMemory;
+    use crate::mem::mapped::ArcMemPages;
+    use crate::mem::{MemRegion, MemRegionType, Memory};
+
+    use super::{load, load_bios};
+
+    fn image(name: &str, pages: &[u8]) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("alioth-{name}-{}.fd", std::process::id()));
+        let data: Vec<u8> = pages.iter().flat_map(|p| [*p; 0x1000]).collect();
+        fs::write(&path, data).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_load_firmware_vars() {
+        let code = image("code", &[0xc0, 0xc1]);
+        let vars = image("vars", &[0xd0]);
+        let memory = Memory::new(FakeVmMemory);
+        let (_, rom) = load(&memory, &code, Some(&vars)).unwrap();
+        fs::remove_file(code).unwrap();
+        fs::remove_file(vars).unwrap();
+
+        assert_eq!(rom.size(), 0x3000);
+        let ram_bus = memory.ram_bus();
+        assert_eq!(ram_bus.read::<u8>(MEM_64_START - 0x3000).unwrap(), 0xd0);
+        assert_eq!(ram_bus.read::<u8>(MEM_64_START - 0x2000).unwrap(), 0xc0);
+        assert_eq!(ram_bus.read::<u8>(MEM_64_START - 1).unwrap(), 0xc1);
+    }
+
+    #[test]
+    fn test_load_bios() {
+        let mut pages = [0xb0; 0x40];
+        pages[0x3f] = 0xbf;
+        let bios = image("bios", &pages);
+        let memory = Memory::new(FakeVmMemory);
+        let ram = ArcMemPages::from_anonymous(BIOS_ROM_END as usize, None).unwrap();
+        let region = MemRegion::with_mapped(ram, MemRegionType::Ram);
+        memory.add_region(0, Arc::new(region)).unwrap();
+        load_bios(&memory, &bios).unwrap();
+        fs::remove_file(bios).unwrap();
+
+        let ram_bus = memory.ram_bus();
+        assert_eq!(ram_bus.read::<u8>(MEM_64_START - 0x40000).unwrap(), 0xb0);
+        assert_eq!(ram_bus.read::<u8>(MEM_64_START - 1).unwrap(), 0xbf);
+        assert_eq!(ram_bus.read::<u8>(0xd_ffff).unwrap(), 0);
+        assert_eq!(ram_bus.read::<u8>(0xe_0000).unwrap(), 0xb0);
+        assert_eq!(ram_bus.read::<u8>(0xf_ffff).unwrap(), 0xbf);
+    }
+}
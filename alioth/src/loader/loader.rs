@@ -38,6 +38,9 @@ pub struct Payload {
     pub exec_type: ExecType,
     pub initramfs: Option<PathBuf>,
     pub cmd_line: Option<String>,
+    /// UEFI variable store placed right below the firmware, like the
+    /// `VARS` half of `OVMF.fd`.
+    pub firmware_vars: Option<PathBuf>,
 }
 
 #[derive(Debug)]
@@ -46,6 +49,9 @@ pub enum ExecType {
     #[cfg(target_arch = "x86_64")]
     Pvh,
     Firmware,
+    /// A legacy BIOS like SeaBIOS.
+    #[cfg(target_arch = "x86_64")]
+    Bios,
 }
 
 #[derive(Debug, Clone, Default)]
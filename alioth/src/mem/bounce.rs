@@ -0,0 +1,129 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use libc::{PROT_READ, PROT_WRITE};
+
+use crate::mem::mapped::ArcMemPages;
+use crate::mem::Result;
+
+pub const BOUNCE_SLOT_SIZE: usize = 4096;
+
+/// A pool of host pages that device emulation can copy guest data through,
+/// for cases where the guest buffers should not be accessed in place.
+#[derive(Debug)]
+pub struct BounceBuffer {
+    pages: ArcMemPages,
+    bitmap: Box<[AtomicU64]>,
+}
+
+impl BounceBuffer {
+    /// Creates a pool of `size` bytes, rounded up to a multiple of
+    /// [`BOUNCE_SLOT_SIZE`].
+    pub fn new(size: usize) -> Result<Self> {
+        let num_slots = size.div_ceil(BOUNCE_SLOT_SIZE);
+        let pages = ArcMemPages::from_anonymous(
+            num_slots * BOUNCE_SLOT_SIZE,
+            Some(PROT_READ | PROT_WRITE),
+        )?;
+        let bitmap: Box<[AtomicU64]> = (0..num_slots.div_ceil(64))
+            .map(|_| AtomicU64::new(0))
+            .collect();
+        if !num_slots.is_multiple_of(64) {
+            // Slots beyond the end of the pool are never handed out.
+            if let Some(last) = bitmap.last() {
+                last.store(u64::MAX << (num_slots % 64), Ordering::Relaxed);
+            }
+        }
+        Ok(BounceBuffer { pages, bitmap })
+    }
+
+    pub fn alloc(&self) -> Option<BounceSlot<'_>> {
+        for (i, word) in self.bitmap.iter().enumerate() {
+            let mut current = word.load(Ordering::Relaxed);
+            while current != u64::MAX {
+                let bit = (!current).trailing_zeros();
+                match word.compare_exchange_weak(
+                    current,
+                    current | (1 << bit),
+                    Ordering::Acquire,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => {
+                        return Some(BounceSlot {
+                            buffer: self,
+                            index: i * 64 + bit as usize,
+                        })
+                    }
+                    Err(val) => current = val,
+                }
+            }
+        }
+        None
+    }
+
+    fn free(&self, index: usize) {
+        let word = &self.bitmap[index / 64];
+        word.fetch_and(!(1 << (index % 64)), Ordering::Release);
+    }
+}
+
+/// A slot of [`BOUNCE_SLOT_SIZE`] bytes, returned to the pool on drop.
+#[derive(Debug)]
+pub struct BounceSlot<'b> {
+    buffer: &'b BounceBuffer,
+    index: usize,
+}
+
+impl<'b> BounceSlot<'b> {
+    /// Returns the first `len` bytes of the slot.
+    ///
+    /// # Safety
+    ///
+    /// The returned slice must not be used after the slot is dropped.
+    pub unsafe fn as_mut_slice(&self, len: usize) -> &'b mut [u8] {
+        assert!(len <= BOUNCE_SLOT_SIZE);
+        let addr = self.buffer.pages.addr() + self.index * BOUNCE_SLOT_SIZE;
+        std::slice::from_raw_parts_mut(addr as *mut u8, len)
+    }
+}
+
+impl Drop for BounceSlot<'_> {
+    fn drop(&mut self) {
+        self.buffer.free(self.index)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::mem::bounce::{BounceBuffer, BOUNCE_SLOT_SIZE};
+
+    #[test]
+    fn test_bounce_buffer_alloc() {
+        let buffer = BounceBuffer::new(70 * BOUNCE_SLOT_SIZE - 1).unwrap();
+        let slots: Vec<_> = (0..70).map(|_| buffer.alloc().unwrap()).collect();
+        assert!(buffer.alloc().is_none());
+
+        let mut indexes: Vec<_> = slots.iter().map(|s| s.index).collect();
+        indexes.sort();
+        assert_eq!(indexes, (0..70).collect::<Vec<_>>());
+
+        drop(slots);
+        let slot = buffer.alloc().unwrap();
+        let buf = unsafe { slot.as_mut_slice(BOUNCE_SLOT_SIZE) };
+        buf.fill(0xa5);
+        assert!(buf.iter().all(|b| *b == 0xa5));
+    }
+}
@@ -58,6 +58,92 @@ impl SlotBackend for MmioRange {
     }
 }
 
+/// Logs every access to the inner [`Mmio`] at the trace level.
+#[derive(Debug)]
+pub struct TracingMmio<T> {
+    name: Arc<String>,
+    inner: T,
+}
+
+impl<T> TracingMmio<T> {
+    pub fn new(name: Arc<String>, inner: T) -> Self {
+        TracingMmio { name, inner }
+    }
+}
+
+impl<T> Mmio for TracingMmio<T>
+where
+    T: Mmio,
+{
+    fn read(&self, offset: u64, size: u8) -> Result<u64> {
+        let val = self.inner.read(offset, size)?;
+        log::trace!("{}: read {offset:#x}, size {size}: {val:#x}", self.name);
+        Ok(val)
+    }
+
+    fn write(&self, offset: u64, size: u8, val: u64) -> Result<Action> {
+        log::trace!("{}: write {offset:#x}, size {size}: {val:#x}", self.name);
+        self.inner.write(offset, size, val)
+    }
+
+    fn size(&self) -> u64 {
+        self.inner.size()
+    }
+}
+
+/// Drops accesses beyond [`Mmio::size`] of the inner [`Mmio`]: reads
+/// return 0 and writes are ignored, like unmapped PCI register space.
+#[derive(Debug)]
+pub struct MmioSafe<T> {
+    inner: T,
+}
+
+impl<T> MmioSafe<T>
+where
+    T: Mmio,
+{
+    pub fn new(inner: T) -> Self {
+        MmioSafe { inner }
+    }
+
+    fn in_bounds(&self, offset: u64, size: u8) -> bool {
+        matches!(offset.checked_add(size as u64), Some(end) if end <= self.inner.size())
+    }
+}
+
+impl<T> Mmio for MmioSafe<T>
+where
+    T: Mmio,
+{
+    fn read(&self, offset: u64, size: u8) -> Result<u64> {
+        if self.in_bounds(offset, size) {
+            self.inner.read(offset, size)
+        } else {
+            log::debug!(
+                "read out of bounds: offset = {offset:#x}, size = {size}, range size = {:#x}",
+                self.inner.size()
+            );
+            Ok(0)
+        }
+    }
+
+    fn write(&self, offset: u64, size: u8, val: u64) -> Result<Action> {
+        if self.in_bounds(offset, size) {
+            self.inner.write(offset, size, val)
+        } else {
+            log::debug!(
+                "write out of bounds: offset = {offset:#x}, size = {size}, range size = {:#x}",
+                self.inner.size()
+            );
+            Ok(Action::None)
+        }
+    }
+
+    fn size(&self) -> u64 {
+        self.inner.size()
+    }
+}
+
 #[macro_export]
 macro_rules! impl_mmio_for_zerocopy {
     ($ty:ident) => {
@@ -162,3 +248,45 @@ where
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    use crate::mem::emulated::{Action, Mmio, MmioSafe};
+    use crate::mem::Result;
+
+    #[derive(Debug, Default)]
+    struct Reg(AtomicU64);
+
+    impl Mmio for Reg {
+        fn read(&self, offset: u64, size: u8) -> Result<u64> {
+            assert!(offset + size as u64 <= 8);
+            Ok(self.0.load(Ordering::Acquire) >> (offset * 8))
+        }
+
+        fn write(&self, offset: u64, size: u8, val: u64) -> Result<Action> {
+            assert!(offset + size as u64 <= 8);
+            self.0.store(val << (offset * 8), Ordering::Release);
+            Ok(Action::None)
+        }
+
+        fn size(&self) -> u64 {
+            8
+        }
+    }
+
+    #[test]
+    fn test_mmio_safe() {
+        let reg = MmioSafe::new(Reg::default());
+        assert!(matches!(reg.write(0, 8, 0x1234), Ok(Action::None)));
+        assert_eq!(reg.read(0, 8).unwrap(), 0x1234);
+        assert_eq!(reg.read(4, 4).unwrap(), 0);
+
+        assert!(matches!(reg.write(4, 8, 0xffff), Ok(Action::None)));
+        assert!(matches!(reg.write(u64::MAX, 1, 0xffff), Ok(Action::None)));
+        assert_eq!(reg.read(0, 8).unwrap(), 0x1234);
+        assert_eq!(reg.read(1, 8).unwrap(), 0);
+        assert_eq!(reg.read(8, 1).unwrap(), 0);
+    }
+}
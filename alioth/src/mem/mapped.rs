@@ -23,8 +23,8 @@ use std::ops::Deref;
 #[cfg(target_os = "linux")]
 use std::os::fd::FromRawFd;
 use std::os::fd::{AsFd, AsRawFd, BorrowedFd};
-use std::ptr::{null_mut, NonNull};
-use std::sync::atomic::{AtomicU32, Ordering};
+use std::ptr::{copy_nonoverlapping, null_mut, NonNull};
+use std::sync::atomic::{fence, AtomicU32, AtomicU64, Ordering};
 use std::sync::Arc;
 
 #[cfg(target_os = "linux")]
@@ -37,10 +37,13 @@ use parking_lot::{RwLock, RwLockReadGuard};
 use snafu::ResultExt;
 use zerocopy::{AsBytes, FromBytes};
 
+use crate::arch::layout::PAGE_SIZE;
 use crate::ffi;
 use crate::hv::{MemMapOption, VmMemory};
 use crate::mem::addressable::{Addressable, SlotBackend};
-use crate::mem::{error, Error, Result};
+#[cfg(target_os = "linux")]
+use crate::mem::shared::SharedMemFd;
+use crate::mem::{error, Error, MemRegionType, Result};
 
 #[derive(Debug)]
 struct MemPages {
@@ -206,7 +209,25 @@ impl ArcMemPages {
 #[derive(Debug)]
 pub struct MappedSlot {
     pub pages: ArcMemPages,
+    pub type_: MemRegionType,
     slot_id: u32,
+    dirty: Option<Box<[AtomicU64]>>,
+}
+
+impl MappedSlot {
+    fn mark_dirty(&self, offset: u64, len: u64) {
+        let Some(dirty) = &self.dirty else {
+            return;
+        };
+        if len == 0 {
+            return;
+        }
+        let first = offset / PAGE_SIZE;
+        let last = (offset + len - 1) / PAGE_SIZE;
+        for page in first..=last {
+            dirty[(page / 64) as usize].fetch_or(1 << (page % 64), Ordering::Relaxed);
+        }
+    }
 }
 
 impl SlotBackend for MappedSlot {
@@ -215,12 +236,66 @@ impl SlotBackend for MappedSlot {
     }
 }
 
+/// Limits the host memory mapped into a VM, so a device cannot map an
+/// unbounded amount of memory through shared memory or BARs.
+#[derive(Debug)]
+pub struct MemBudget {
+    limit_bytes: AtomicU64,
+    used_bytes: AtomicU64,
+}
+
+impl Default for MemBudget {
+    fn default() -> Self {
+        MemBudget {
+            limit_bytes: AtomicU64::new(u64::MAX),
+            used_bytes: AtomicU64::new(0),
+        }
+    }
+}
+
+impl MemBudget {
+    pub fn limit(&self) -> u64 {
+        self.limit_bytes.load(Ordering::Acquire)
+    }
+
+    pub fn used(&self) -> u64 {
+        self.used_bytes.load(Ordering::Acquire)
+    }
+
+    pub fn set_limit(&self, limit: u64) {
+        self.limit_bytes.store(limit, Ordering::Release)
+    }
+
+    /// Raises the limit for memory the VMM maps on purpose, e.g. the
+    /// shared memory window of a device.
+    pub fn grow(&self, size: u64) {
+        let update = |limit: u64| Some(limit.saturating_add(size));
+        let _ = self
+            .limit_bytes
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, update);
+    }
+
+    fn charge(&self, size: u64) -> Result<()> {
+        let limit = self.limit();
+        let update = |used: u64| used.checked_add(size).filter(|&total| total <= limit);
+        match (self.used_bytes).fetch_update(Ordering::AcqRel, Ordering::Acquire, update) {
+            Ok(_) => Ok(()),
+            Err(used) => error::OutOfMemory { size, used, limit }.fail(),
+        }
+    }
+
+    fn release(&self, size: u64) {
+        self.used_bytes.fetch_sub(size, Ordering::AcqRel);
+    }
+}
+
 #[derive(Debug)]
 pub struct RamBus {
     inner: RwLock<Addressable<MappedSlot>>,
     vm_memory: Box<dyn VmMemory>,
     pub(super) next_slot_id: AtomicU32,
     max_mem_slots: u32,
+    budget: MemBudget,
 }
 
 pub struct RamLayoutGuard<'a> {
@@ -249,7 +324,7 @@ impl<'a> Iterator for Iter<'a> {
         }
         let r = self.inner.get_partial_slice(self.gpa, self.remain);
         if let Ok(s) = r {
-            self.gpa += s.len() as u64;
+            self.gpa = self.gpa.wrapping_add(s.len() as u64);
             self.remain -= s.len() as u64;
         }
         Some(r)
@@ -270,7 +345,7 @@ impl<'a> Iterator for IterMut<'a> {
         }
         let r = self.inner.get_partial_slice_mut(self.gpa, self.remain);
         if let Ok(ref s) = r {
-            self.gpa += s.len() as u64;
+            self.gpa = self.gpa.wrapping_add(s.len() as u64);
             self.remain -= s.len() as u64;
         }
         Some(r)
@@ -278,20 +353,71 @@ impl<'a> Iterator for IterMut<'a> {
 }
 
 impl Addressable<MappedSlot> {
-    fn slice_iter(&self, gpa: u64, len: u64) -> Iter {
-        Iter {
+    /// Checks that `[gpa, gpa + len)` is entirely backed by guest RAM.
+    pub fn check_range(&self, gpa: u64, len: u64) -> Result<()> {
+        if len == 0 {
+            return Ok(());
+        }
+        let Some(last) = gpa.checked_add(len - 1) else {
+            return error::ExceedsLimit {
+                addr: gpa,
+                size: len,
+            }
+            .fail();
+        };
+        let mut addr = gpa;
+        loop {
+            let Some((start, slot)) = self.search(addr) else {
+                return error::NotMapped { addr }.fail();
+            };
+            let slot_last = start + (slot.size() - 1);
+            if slot_last >= last {
+                return Ok(());
+            }
+            addr = slot_last + 1;
+        }
+    }
+
+    /// Checks that `[gpa, gpa + len)` is entirely backed by slots of
+    /// [`MemRegionType::Ram`].
+    fn check_ram(&self, gpa: u64, len: u64) -> Result<()> {
+        self.check_range(gpa, len)?;
+        if len == 0 {
+            return Ok(());
+        }
+        let last = gpa + (len - 1);
+        let mut addr = gpa;
+        loop {
+            let Some((start, slot)) = self.search(addr) else {
+                return error::NotMapped { addr }.fail();
+            };
+            if slot.type_ != MemRegionType::Ram {
+                return error::NotRam { addr }.fail();
+            }
+            let slot_last = start + (slot.size() - 1);
+            if slot_last >= last {
+                return Ok(());
+            }
+            addr = slot_last + 1;
+        }
+    }
+
+    fn slice_iter(&self, gpa: u64, len: u64) -> Result<Iter<'_>> {
+        self.check_range(gpa, len)?;
+        Ok(Iter {
             inner: self,
             gpa,
             remain: len,
-        }
+        })
     }
 
-    fn slice_iter_mut(&self, gpa: u64, len: u64) -> IterMut {
-        IterMut {
+    fn slice_iter_mut(&self, gpa: u64, len: u64) -> Result<IterMut<'_>> {
+        self.check_range(gpa, len)?;
+        Ok(IterMut {
             inner: self,
             gpa,
             remain: len,
-        }
+        })
     }
 
     fn get_partial_slice(&self, gpa: u64, len: u64) -> Result<&[u8]> {
@@ -307,9 +433,11 @@ impl Addressable<MappedSlot> {
         let Some((start, user_mem)) = self.search(gpa) else {
             return error::NotMapped { addr: gpa }.fail();
         };
-        user_mem
+        let s = user_mem
             .pages
-            .get_partial_slice_mut((gpa - start) as usize, len as usize)
+            .get_partial_slice_mut((gpa - start) as usize, len as usize)?;
+        user_mem.mark_dirty(gpa - start, s.len() as u64);
+        Ok(s)
     }
 
     pub fn get_slice<T>(&self, gpa: u64, len: u64) -> Result<&[UnsafeCell<T>], Error> {
@@ -333,6 +461,15 @@ impl Addressable<MappedSlot> {
         }
     }
 
+    /// Marks `[gpa, gpa + len)` dirty after it is written through
+    /// [`Self::get_ref`] or [`Self::get_slice`], which do not know whether
+    /// the caller writes.
+    pub fn mark_dirty(&self, gpa: u64, len: u64) {
+        if let Some((start, user_mem)) = self.search(gpa) {
+            user_mem.mark_dirty(gpa - start, len);
+        }
+    }
+
     pub fn get_ref<T>(&self, gpa: u64) -> Result<&UnsafeCell<T>, Error> {
         let host_ref = self.get_partial_slice(gpa, size_of::<T>() as u64)?;
         let ptr = host_ref.as_ptr() as *const UnsafeCell<T>;
@@ -365,7 +502,7 @@ impl Addressable<MappedSlot> {
             Ok(val)
         } else {
             let mut cur = 0;
-            for r in self.slice_iter(gpa, size_of::<T>() as u64) {
+            for r in self.slice_iter(gpa, size_of::<T>() as u64)? {
                 let s = r?;
                 let s_len = s.len();
                 buf[cur..(cur + s_len)].copy_from_slice(s);
@@ -386,7 +523,7 @@ impl Addressable<MappedSlot> {
             Ok(())
         } else {
             let mut cur = 0;
-            for r in self.slice_iter_mut(gpa, size_of::<T>() as u64) {
+            for r in self.slice_iter_mut(gpa, size_of::<T>() as u64)? {
                 let s = r?;
                 let s_len = s.len();
                 s.copy_from_slice(&buf[cur..(cur + s_len)]);
@@ -404,7 +541,7 @@ impl Addressable<MappedSlot> {
     pub fn translate_iov<'a>(&'a self, iov: &[(u64, u64)]) -> Result<Vec<IoSlice<'a>>> {
         let mut slices = vec![];
         for (gpa, len) in iov {
-            for r in self.slice_iter(*gpa, *len) {
+            for r in self.slice_iter(*gpa, *len)? {
                 slices.push(IoSlice::new(r?));
             }
         }
@@ -414,12 +551,36 @@ impl Addressable<MappedSlot> {
     pub fn translate_iov_mut<'a>(&'a self, iov: &[(u64, u64)]) -> Result<Vec<IoSliceMut<'a>>> {
         let mut slices = vec![];
         for (gpa, len) in iov {
-            for r in self.slice_iter_mut(*gpa, *len) {
+            for r in self.slice_iter_mut(*gpa, *len)? {
                 slices.push(IoSliceMut::new(r?));
             }
         }
         Ok(slices)
     }
+
+    /// Copies guest memory at `gpa` into `buf`.
+    pub fn read_bytes(&self, gpa: u64, buf: &mut [u8]) -> Result<()> {
+        let mut cur = 0;
+        for r in self.slice_iter(gpa, buf.len() as u64)? {
+            let s = r?;
+            let dst = &mut buf[cur..cur + s.len()];
+            unsafe { copy_nonoverlapping(s.as_ptr(), dst.as_mut_ptr(), s.len()) };
+            cur += s.len();
+        }
+        Ok(())
+    }
+
+    /// Copies `buf` into guest memory at `gpa`.
+    pub fn write_bytes(&self, gpa: u64, buf: &[u8]) -> Result<()> {
+        let mut cur = 0;
+        for r in self.slice_iter_mut(gpa, buf.len() as u64)? {
+            let s = r?;
+            let src = &buf[cur..cur + s.len()];
+            unsafe { copy_nonoverlapping(src.as_ptr(), s.as_mut_ptr(), s.len()) };
+            cur += s.len();
+        }
+        Ok(())
+    }
 }
 
 impl Drop for RamBus {
@@ -456,15 +617,20 @@ impl RamBus {
             vm_memory: Box::new(vm_memory),
             next_slot_id: AtomicU32::new(0),
             max_mem_slots,
+            budget: MemBudget::default(),
         }
     }
 
+    pub fn budget(&self) -> &MemBudget {
+        &self.budget
+    }
+
     fn map_to_vm(&self, user_mem: &MappedSlot, addr: u64) -> Result<(), Error> {
         let mem_options = MemMapOption {
             read: true,
             write: true,
             exec: true,
-            log_dirty: false,
+            log_dirty: user_mem.dirty.is_some(),
         };
         self.vm_memory.mem_map(
             user_mem.slot_id,
@@ -494,20 +660,49 @@ impl RamBus {
         Ok(())
     }
 
-    pub(crate) fn add(&self, gpa: u64, user_mem: ArcMemPages) -> Result<(), Error> {
+    pub(crate) fn add(
+        &self,
+        gpa: u64,
+        user_mem: ArcMemPages,
+        type_: MemRegionType,
+        log_dirty: bool,
+    ) -> Result<()> {
+        let size = user_mem.size();
         let mut inner = self.inner.write();
+        self.budget.charge(size)?;
+        let dirty = log_dirty.then(|| {
+            let num_pages = (user_mem.size as u64).div_ceil(PAGE_SIZE);
+            (0..num_pages.div_ceil(64))
+                .map(|_| AtomicU64::new(0))
+                .collect()
+        });
         let slot = MappedSlot {
             slot_id: self.next_slot_id.fetch_add(1, Ordering::AcqRel) % self.max_mem_slots,
             pages: user_mem,
+            type_,
+            dirty,
+        };
+        let slot = match inner.add(gpa, slot) {
+            Ok(slot) => slot,
+            Err(e) => {
+                self.budget.release(size);
+                return Err(e);
+            }
         };
-        let slot = inner.add(gpa, slot)?;
         self.map_to_vm(slot, gpa)?;
         Ok(())
     }
 
+    /// Maps memory shared with other VMs at `gpa`.
+    #[cfg(target_os = "linux")]
+    pub fn add_shared(&self, gpa: u64, shm: &SharedMemFd) -> Result<()> {
+        self.add(gpa, shm.map()?, MemRegionType::Reserved, false)
+    }
+
     fn clear(&self) -> Result<()> {
         let mut innter = self.inner.write();
         for (gpa, user_mem) in innter.drain(..) {
+            self.budget.release(user_mem.size());
             self.unmap_from_vm(&user_mem, gpa)?;
         }
         Ok(())
@@ -516,10 +711,53 @@ impl RamBus {
     pub(super) fn remove(&self, gpa: u64) -> Result<ArcMemPages, Error> {
         let mut inner = self.inner.write();
         let mem = inner.remove(gpa)?;
+        self.budget.release(mem.size());
         self.unmap_from_vm(&mem, gpa)?;
         Ok(mem.pages)
     }
 
+    /// Collects and clears dirty pages within `[gpa, gpa + size)`.
+    ///
+    /// Pages dirtied by the guest are reported by the hypervisor, while pages
+    /// written by the VMM through this bus are tracked separately. Returns
+    /// runs of contiguous dirty pages as `(gpa, size)`.
+    pub fn get_dirty(&self, gpa: u64, size: u64) -> Result<Vec<(u64, u64)>> {
+        let inner = self.inner.read();
+        let end = gpa + size;
+        let mut runs: Vec<(u64, u64)> = vec![];
+        let mut start = gpa;
+        while let Some((addr, slot)) = inner.search_next(start) {
+            if addr >= end {
+                break;
+            }
+            start = addr + slot.size();
+            let Some(dirty) = &slot.dirty else {
+                continue;
+            };
+            let mut bitmap = vec![0u64; dirty.len()];
+            self.vm_memory.get_dirty_log(slot.slot_id, &mut bitmap)?;
+            for (index, (word, sw_word)) in bitmap.iter_mut().zip(dirty.iter()).enumerate() {
+                *word |= sw_word.swap(0, Ordering::Relaxed);
+                while *word != 0 {
+                    let bit = word.trailing_zeros() as u64;
+                    *word &= *word - 1;
+                    let page_gpa = addr + (index as u64 * 64 + bit) * PAGE_SIZE;
+                    if page_gpa < gpa || page_gpa >= end {
+                        dirty[index].fetch_or(1 << bit, Ordering::Relaxed);
+                        continue;
+                    }
+                    match runs.last_mut() {
+                        Some((run_gpa, run_size)) if *run_gpa + *run_size == page_gpa => {
+                            *run_size += PAGE_SIZE
+                        }
+                        _ => runs.push((page_gpa, PAGE_SIZE)),
+                    }
+                }
+            }
+        }
+        Ok(runs)
+    }
+
     pub fn read<T>(&self, gpa: u64) -> Result<T, Error>
     where
         T: FromBytes + AsBytes,
@@ -536,9 +774,50 @@ impl RamBus {
         inner.write(gpa, val)
     }
 
+    /// Copies guest RAM at `gpa` into `buf`, for accesses initiated by the
+    /// VMM rather than on behalf of a device.
+    pub fn read_bytes(&self, gpa: u64, buf: &mut [u8]) -> Result<()> {
+        let inner = self.inner.read();
+        inner.check_ram(gpa, buf.len() as u64)?;
+        // Observe all guest stores made before the copy.
+        fence(Ordering::SeqCst);
+        inner.read_bytes(gpa, buf)
+    }
+
+    /// Copies `buf` into guest RAM at `gpa`.
+    pub fn write_bytes(&self, gpa: u64, buf: &[u8]) -> Result<()> {
+        let inner = self.inner.read();
+        inner.check_ram(gpa, buf.len() as u64)?;
+        inner.write_bytes(gpa, buf)?;
+        // Make the data visible to vCPUs before any subsequent notification.
+        fence(Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Returns the host address of `[gpa, gpa + size)`, for devices that
+    /// access guest RAM directly. The range must be within one slot of
+    /// [`MemRegionType::Ram`], and is marked dirty.
+    ///
+    /// The pointer is only valid until the slot is removed, e.g. on memory
+    /// hot-unplug. Synchronizing with vCPUs and other devices is up to the
+    /// caller.
+    pub fn lookup_hva(&self, gpa: u64, size: usize) -> Result<NonNull<u8>> {
+        let inner = self.inner.read();
+        inner.check_ram(gpa, size as u64)?;
+        let s = inner.get_partial_slice_mut(gpa, size as u64)?;
+        if s.len() != size {
+            return error::NotContinuous {
+                addr: gpa,
+                size: size as u64,
+            }
+            .fail();
+        }
+        Ok(NonNull::from(s).cast())
+    }
+
     pub fn read_range(&self, gpa: u64, len: u64, dst: &mut impl Write) -> Result<()> {
         let inner = self.inner.read();
-        for r in inner.slice_iter(gpa, len) {
+        for r in inner.slice_iter(gpa, len)? {
             dst.write_all(r?).context(error::Write)?;
         }
         Ok(())
@@ -546,7 +825,7 @@ impl RamBus {
 
     pub fn write_range(&self, gpa: u64, len: u64, mut src: impl Read) -> Result<()> {
         let inner = self.inner.read();
-        for r in inner.slice_iter_mut(gpa, len) {
+        for r in inner.slice_iter_mut(gpa, len)? {
             src.read_exact(r?).context(error::Read)?;
         }
         Ok(())
@@ -559,7 +838,7 @@ impl RamBus {
         let inner = self.inner.read();
         let mut iov = vec![];
         for (gpa, len) in bufs {
-            for r in inner.slice_iter(*gpa, *len) {
+            for r in inner.slice_iter(*gpa, *len)? {
                 iov.push(IoSlice::new(r?));
             }
         }
@@ -573,7 +852,7 @@ impl RamBus {
         let inner = self.inner.read();
         let mut iov = vec![];
         for (gpa, len) in bufs {
-            for r in inner.slice_iter_mut(*gpa, *len) {
+            for r in inner.slice_iter_mut(*gpa, *len)? {
                 iov.push(IoSliceMut::new(r?));
             }
         }
@@ -583,7 +862,7 @@ impl RamBus {
     pub fn mark_private_memory(&self, gpa: u64, size: u64, private: bool) -> Result<()> {
         let inner = self.inner.read();
         let mut start = gpa;
-        let end = gpa + size;
+        let end = gpa.saturating_add(size);
         while let Some((addr, slot)) = inner.search_next(start) {
             let gpa_start = std::cmp::max(addr, start);
             let gpa_end = std::cmp::min(end, addr + slot.size());
@@ -619,10 +898,13 @@ mod test {
     use std::mem::size_of;
 
     use libc::{PROT_READ, PROT_WRITE};
+    use rand::Rng;
     use zerocopy::{AsBytes, FromBytes, FromZeroes};
 
     use crate::hv::test::FakeVmMemory;
 
+    use crate::mem::{Error, MemRegionType};
+
     use super::{ArcMemPages, RamBus};
 
     #[derive(Debug, AsBytes, FromBytes, FromZeroes, PartialEq, Eq)]
@@ -641,11 +923,11 @@ mod test {
         let mem2 = ArcMemPages::from_anonymous(PAGE_SIZE as usize, Some(prot)).unwrap();
 
         if mem1.addr > mem2.addr {
-            bus.add(0x0, mem1).unwrap();
-            bus.add(PAGE_SIZE, mem2).unwrap();
+            bus.add(0x0, mem1, MemRegionType::Ram, false).unwrap();
+            bus.add(PAGE_SIZE, mem2, MemRegionType::Ram, false).unwrap();
         } else {
-            bus.add(0x0, mem2).unwrap();
-            bus.add(PAGE_SIZE, mem1).unwrap();
+            bus.add(0x0, mem2, MemRegionType::Ram, false).unwrap();
+            bus.add(PAGE_SIZE, mem1, MemRegionType::Ram, false).unwrap();
         }
 
         let data = MyStruct {
@@ -695,4 +977,160 @@ mod test {
         drop(locked_bus);
         bus.remove(0x0).unwrap();
     }
+
+    #[test]
+    fn test_ram_bus_range_fuzz() {
+        let bus = RamBus::new(FakeVmMemory);
+        let prot = PROT_READ | PROT_WRITE;
+        let new_pages = || ArcMemPages::from_anonymous(PAGE_SIZE as usize, Some(prot)).unwrap();
+        bus.add(0x0, new_pages(), MemRegionType::Ram, false)
+            .unwrap();
+        bus.add(PAGE_SIZE, new_pages(), MemRegionType::Ram, false)
+            .unwrap();
+        bus.add(3 * PAGE_SIZE, new_pages(), MemRegionType::Ram, false)
+            .unwrap();
+        bus.add(
+            u64::MAX - PAGE_SIZE + 1,
+            new_pages(),
+            MemRegionType::Ram,
+            false,
+        )
+        .unwrap();
+        let ram = [
+            (0, 2 * PAGE_SIZE),
+            (3 * PAGE_SIZE, PAGE_SIZE),
+            (u64::MAX - PAGE_SIZE + 1, PAGE_SIZE),
+        ];
+        let is_valid = |gpa: u64, len: u64| {
+            len == 0
+                || ram
+                    .iter()
+                    .any(|(start, size)| gpa >= *start && len <= *size && gpa - start <= size - len)
+        };
+
+        let mut rng = rand::thread_rng();
+        let mut random_u64 = |near: &[u64]| {
+            let base = near[rng.gen_range(0..near.len())];
+            base.wrapping_add(rng.gen_range(0..2 * PAGE_SIZE))
+                .wrapping_sub(PAGE_SIZE)
+        };
+        let addrs = [0, 2 * PAGE_SIZE, 4 * PAGE_SIZE, u64::MAX];
+        let lens = [0, PAGE_SIZE, u64::MAX];
+        for _ in 0..10000 {
+            let gpa = random_u64(&addrs);
+            let len = random_u64(&lens);
+            let valid = is_valid(gpa, len);
+            let iov = [(gpa, len)];
+
+            let mut buf = Vec::new();
+            let read = bus.read_range(gpa, len, &mut buf);
+            assert_eq!(read.is_ok(), valid, "read_range({gpa:#x}, {len:#x})");
+            if valid {
+                assert_eq!(buf.len() as u64, len);
+                let write = bus.write_range(gpa, len, &*buf);
+                assert_matches!(write, Ok(()));
+            }
+            let read = bus.read_vectored(&iov, |iov| iov.len());
+            assert_eq!(read.is_ok(), valid, "read_vectored({gpa:#x}, {len:#x})");
+            let write = bus.write_vectored(&iov, |iov| iov.len());
+            assert_eq!(write.is_ok(), valid, "write_vectored({gpa:#x}, {len:#x})");
+            let locked_bus = bus.lock_layout();
+            let translated = locked_bus.translate_iov_mut(&iov);
+            assert_eq!(translated.is_ok(), valid, "translate({gpa:#x}, {len:#x})");
+        }
+    }
+
+    #[test]
+    fn test_ram_bus_read_write_bytes() {
+        let bus = RamBus::new(FakeVmMemory);
+        let prot = PROT_READ | PROT_WRITE;
+        let new_pages = || ArcMemPages::from_anonymous(PAGE_SIZE as usize, Some(prot)).unwrap();
+        bus.add(0x0, new_pages(), MemRegionType::Ram, false)
+            .unwrap();
+        bus.add(PAGE_SIZE, new_pages(), MemRegionType::Ram, false)
+            .unwrap();
+        bus.add(2 * PAGE_SIZE, new_pages(), MemRegionType::Reserved, false)
+            .unwrap();
+
+        let magic = 0xa110_7a5e_c0de_cafe_u64.to_le_bytes();
+        let gpa = PAGE_SIZE - 4;
+        bus.write_bytes(gpa, &magic).unwrap();
+        assert_eq!(bus.read::<u64>(gpa).unwrap(), 0xa110_7a5e_c0de_cafe);
+        let mut buf = [0u8; 8];
+        bus.read_bytes(gpa, &mut buf).unwrap();
+        assert_eq!(buf, magic);
+
+        assert_matches!(
+            bus.write_bytes(2 * PAGE_SIZE - 4, &magic),
+            Err(Error::NotRam { addr, .. }) if addr == 2 * PAGE_SIZE
+        );
+        assert_matches!(
+            bus.read_bytes(3 * PAGE_SIZE - 4, &mut buf),
+            Err(Error::NotMapped { .. })
+        );
+        bus.read_bytes(3 * PAGE_SIZE, &mut []).unwrap();
+    }
+
+    #[test]
+    fn test_ram_bus_lookup_hva() {
+        let bus = RamBus::new(FakeVmMemory);
+        let prot = PROT_READ | PROT_WRITE;
+        let new_pages = || ArcMemPages::from_anonymous(PAGE_SIZE as usize, Some(prot)).unwrap();
+        bus.add(0x0, new_pages(), MemRegionType::Ram, false)
+            .unwrap();
+        bus.add(PAGE_SIZE, new_pages(), MemRegionType::Ram, false)
+            .unwrap();
+        bus.add(2 * PAGE_SIZE, new_pages(), MemRegionType::Reserved, false)
+            .unwrap();
+
+        let gpa = PAGE_SIZE + 0x10;
+        bus.write_bytes(gpa, b"alioth").unwrap();
+        let hva = bus.lookup_hva(gpa, 6).unwrap();
+        let data = unsafe { std::slice::from_raw_parts(hva.as_ptr(), 6) };
+        assert_eq!(data, b"alioth");
+        unsafe { hva.as_ptr().write(b'A') };
+        assert_eq!(bus.read::<[u8; 6]>(gpa).unwrap(), *b"Alioth");
+
+        assert_matches!(
+            bus.lookup_hva(PAGE_SIZE - 4, 8),
+            Err(Error::NotContinuous { addr, size: 8, .. }) if addr == PAGE_SIZE - 4
+        );
+        assert_matches!(bus.lookup_hva(2 * PAGE_SIZE, 8), Err(Error::NotRam { .. }));
+        assert_matches!(
+            bus.lookup_hva(3 * PAGE_SIZE, 8),
+            Err(Error::NotMapped { .. })
+        );
+    }
+
+    #[test]
+    fn test_mem_budget() {
+        let bus = RamBus::new(FakeVmMemory);
+        bus.budget().set_limit(2 * PAGE_SIZE);
+        let new_pages = |n| ArcMemPages::from_anonymous((n * PAGE_SIZE) as usize, None).unwrap();
+
+        bus.add(0x0, new_pages(1), MemRegionType::Ram, false)
+            .unwrap();
+        assert_matches!(
+            bus.add(PAGE_SIZE, new_pages(2), MemRegionType::Reserved, false),
+            Err(Error::OutOfMemory { size, used, limit, .. })
+                if size == 2 * PAGE_SIZE && used == PAGE_SIZE && limit == 2 * PAGE_SIZE
+        );
+        assert_matches!(bus.read::<u64>(PAGE_SIZE), Err(Error::NotMapped { .. }));
+        assert_eq!(bus.budget().used(), PAGE_SIZE);
+
+        // a failed mapping does not consume the budget
+        assert_matches!(
+            bus.add(0x0, new_pages(1), MemRegionType::Ram, false),
+            Err(Error::Overlap { .. })
+        );
+        assert_eq!(bus.budget().used(), PAGE_SIZE);
+
+        bus.budget().grow(PAGE_SIZE);
+        bus.add(PAGE_SIZE, new_pages(2), MemRegionType::Reserved, false)
+            .unwrap();
+        assert_eq!(bus.budget().used(), 3 * PAGE_SIZE);
+
+        bus.remove(0x0).unwrap();
+        assert_eq!(bus.budget().used(), 2 * PAGE_SIZE);
+    }
 }
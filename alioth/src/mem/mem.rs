@@ -24,11 +24,14 @@ use crate::errors::{trace_error, DebugTrace};
 use crate::hv::{VmEntry, VmMemory};
 
 pub mod addressable;
+pub mod bounce;
 pub mod emulated;
 pub mod mapped;
+#[cfg(target_os = "linux")]
+pub mod shared;
 
 use addressable::{Addressable, SlotBackend};
-use emulated::{Action, MmioBus, MmioRange};
+use emulated::{Action, MmioBus, MmioRange, MmioSafe};
 use mapped::{ArcMemPages, RamBus};
 
 #[trace_error]
@@ -48,6 +51,8 @@ pub enum Error {
     },
     #[snafu(display("{addr:#x} is not mapped"))]
     NotMapped { addr: u64 },
+    #[snafu(display("{addr:#x} is not guest RAM"))]
+    NotRam { addr: u64 },
     #[snafu(display("Sum of backend range sizes {sum:#x} exceeds the region total size"))]
     BackendTooBig { sum: u64, size: u64 },
     #[snafu(display("address {addr:#x} is not {align}-byte aligned"))]
@@ -56,6 +61,10 @@ pub enum Error {
         "Guest address {addr:#x} (size = {:#x}) is not backed by continuous host memory"
     ))]
     NotContinuous { addr: u64, size: u64 },
+    #[snafu(display(
+        "Mapping {size:#x} bytes exceeds the memory budget, {used:#x} of {limit:#x} used"
+    ))]
+    OutOfMemory { size: u64, used: u64, limit: u64 },
     #[snafu(display("Error from OS"), context(false))]
     System { error: std::io::Error },
     #[snafu(display("Failed to write data to destination"))]
@@ -66,7 +75,7 @@ pub enum Error {
 
 pub type Result<T, E = Error> = std::result::Result<T, E>;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum MemRange {
     Mapped(ArcMemPages),
     Emulated(MmioRange),
@@ -74,6 +83,12 @@ pub enum MemRange {
 }
 
 impl MemRange {
+    /// Wraps `range` with [`MmioSafe`] so that the bus never forwards an
+    /// access beyond its size.
+    pub fn emulated(range: MmioRange) -> MemRange {
+        MemRange::Emulated(Arc::new(MmioSafe::new(range)))
+    }
+
     pub fn size(&self) -> u64 {
         match self {
             MemRange::Mapped(pages) => pages.size(),
@@ -106,11 +121,19 @@ pub trait MemRegionCallback: Debug + Send + Sync + 'static {
     }
 }
 
+pub trait DirtyPageCallback: Debug + Send + Sync + 'static {
+    fn page_dirtied(&self, gpa: u64, size: u64);
+}
+
 #[derive(Debug)]
 pub struct MemRegion {
     pub ranges: Vec<MemRange>,
     pub entries: Vec<MemRegionEntry>,
     pub callbacks: Mutex<Vec<Box<dyn MemRegionCallback>>>,
+    /// If not empty when the region is added, dirty logging is enabled on
+    /// its mapped ranges and the callbacks are invoked by
+    /// [`Memory::flush_dirty`].
+    pub dirty_callbacks: Mutex<Vec<Box<dyn DirtyPageCallback>>>,
 }
 
 impl MemRegion {
@@ -124,16 +147,30 @@ impl MemRegion {
             ranges: vec![MemRange::Mapped(pages)],
             entries: vec![MemRegionEntry { type_, size }],
             callbacks: Mutex::new(vec![]),
+            dirty_callbacks: Mutex::new(vec![]),
         }
     }
 
     pub fn with_emulated(range: MmioRange, type_: MemRegionType) -> MemRegion {
         let size = range.size();
         MemRegion {
-            ranges: vec![MemRange::Emulated(range)],
+            ranges: vec![MemRange::emulated(range)],
             entries: vec![MemRegionEntry { type_, size }],
             callbacks: Mutex::new(vec![]),
+            dirty_callbacks: Mutex::new(vec![]),
+        }
+    }
+
+    /// Returns the type of the entry covering `offset`.
+    fn type_at(&self, offset: u64) -> MemRegionType {
+        let mut start = 0;
+        for entry in &self.entries {
+            if offset < start + entry.size {
+                return entry.type_;
+            }
+            start += entry.size;
         }
+        MemRegionType::Hidden
     }
 
     pub fn validate(&self) -> Result<()> {
@@ -211,11 +248,16 @@ impl Memory {
         region.validate()?;
         let mut regions = self.regions.lock();
         regions.add(addr, region.clone())?;
+        let log_dirty = !region.dirty_callbacks.lock().is_empty();
         let mut offset = 0;
         for range in &region.ranges {
             match range {
                 MemRange::Emulated(r) => self.mmio_bus.add(addr + offset, r.clone())?,
-                MemRange::Mapped(r) => self.ram_bus.add(addr + offset, r.clone())?,
+                MemRange::Mapped(r) => {
+                    let type_ = region.type_at(offset);
+                    self.ram_bus
+                        .add(addr + offset, r.clone(), type_, log_dirty)?
+                }
                 MemRange::Span(_) => {}
             }
             offset += range.size();
@@ -273,6 +315,29 @@ impl Memory {
         Ok(())
     }
 
+    pub fn flush_dirty(&self) -> Result<()> {
+        let regions = self.regions.lock();
+        for (addr, region) in regions.iter() {
+            let callbacks = region.dirty_callbacks.lock();
+            if callbacks.is_empty() {
+                continue;
+            }
+            let mut offset = 0;
+            for range in &region.ranges {
+                if let MemRange::Mapped(pages) = range {
+                    let dirty = self.ram_bus.get_dirty(addr + offset, pages.size())?;
+                    for (gpa, size) in dirty {
+                        for callback in callbacks.iter() {
+                            callback.page_dirtied(gpa, size);
+                        }
+                    }
+                }
+                offset += range.size();
+            }
+        }
+        Ok(())
+    }
+
     pub fn mem_region_entries(&self) -> Vec<(u64, MemRegionEntry)> {
         let mut entries = vec![];
         let regions = self.regions.lock();
@@ -368,3 +433,50 @@ impl Memory {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use parking_lot::Mutex;
+
+    use crate::hv::test::FakeVmMemory;
+    use crate::mem::mapped::ArcMemPages;
+    use crate::mem::{DirtyPageCallback, MemRegion, MemRegionType, Memory};
+
+    const PAGE_SIZE: u64 = 1 << 12;
+
+    #[derive(Debug, Default)]
+    struct DirtyPages(Arc<Mutex<Vec<(u64, u64)>>>);
+
+    impl DirtyPageCallback for DirtyPages {
+        fn page_dirtied(&self, gpa: u64, size: u64) {
+            self.0.lock().push((gpa, size));
+        }
+    }
+
+    #[test]
+    fn test_flush_dirty() {
+        let memory = Memory::new(FakeVmMemory);
+        let pages = ArcMemPages::from_anonymous(4 * PAGE_SIZE as usize, None).unwrap();
+        let region = MemRegion::with_mapped(pages, MemRegionType::Ram);
+        let dirty = Arc::new(Mutex::new(vec![]));
+        region
+            .dirty_callbacks
+            .lock()
+            .push(Box::new(DirtyPages(dirty.clone())));
+        memory.add_region(0x10000, Arc::new(region)).unwrap();
+
+        let ram_bus = memory.ram_bus();
+        ram_bus.write(0x10000 + PAGE_SIZE + 8, &0xdeadu32).unwrap();
+        ram_bus
+            .write(0x10000 + 3 * PAGE_SIZE - 2, &0xbeefu32)
+            .unwrap();
+        memory.flush_dirty().unwrap();
+        assert_eq!(*dirty.lock(), [(0x10000 + PAGE_SIZE, 3 * PAGE_SIZE)]);
+
+        dirty.lock().clear();
+        memory.flush_dirty().unwrap();
+        assert!(dirty.lock().is_empty());
+    }
+}
@@ -0,0 +1,199 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Memory shared between VMs through a memfd.
+
+use std::ffi::CStr;
+use std::fs::File;
+use std::io::{self, ErrorKind, IoSlice, IoSliceMut};
+use std::mem::{size_of, size_of_val};
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::ptr::null_mut;
+
+use libc::{MFD_CLOEXEC, MSG_CMSG_CLOEXEC, PROT_READ, PROT_WRITE, SCM_RIGHTS, SOL_SOCKET};
+use zerocopy::AsBytes;
+
+use crate::ffi;
+use crate::mem::mapped::ArcMemPages;
+use crate::mem::Result;
+
+#[derive(Debug)]
+pub struct SharedMemFd {
+    file: File,
+    size: u64,
+}
+
+impl SharedMemFd {
+    pub fn new(name: &CStr, size: u64) -> Result<Self> {
+        let fd = ffi!(unsafe { libc::memfd_create(name.as_ptr(), MFD_CLOEXEC) })?;
+        let file = unsafe { File::from_raw_fd(fd) };
+        file.set_len(size)?;
+        Ok(SharedMemFd { file, size })
+    }
+
+    /// Wraps a memfd of `size` bytes, e.g. one received from a peer.
+    pub fn from_fd(fd: OwnedFd, size: u64) -> Self {
+        SharedMemFd {
+            file: File::from(fd),
+            size,
+        }
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Maps the memory into the address space of the VMM.
+    pub fn map(&self) -> Result<ArcMemPages> {
+        let file = self.file.try_clone()?;
+        ArcMemPages::from_file(file, 0, self.size as usize, PROT_READ | PROT_WRITE)
+    }
+
+    /// Sends the memfd and its size to the peer of `conn`.
+    pub fn send(&self, conn: &UnixStream) -> Result<()> {
+        send_fds(conn, self.size.as_bytes(), &[self.file.as_raw_fd()])?;
+        Ok(())
+    }
+
+    /// Receives a memfd sent by [`SharedMemFd::send`].
+    pub fn recv(conn: &UnixStream) -> Result<Self> {
+        let mut size = 0u64;
+        let (len, fds) = recv_fds(conn, size.as_bytes_mut(), 1)?;
+        let Some(fd) = fds.into_iter().next().filter(|_| len == size_of::<u64>()) else {
+            return Err(io::Error::from(ErrorKind::InvalidData).into());
+        };
+        Ok(SharedMemFd::from_fd(fd, size))
+    }
+}
+
+/// Sends `payload` to the peer of `conn`, with `fds` attached through
+/// `SCM_RIGHTS`.
+pub fn send_fds(conn: &UnixStream, payload: &[u8], fds: &[RawFd]) -> io::Result<()> {
+    let bufs = [IoSlice::new(payload)];
+    let fd_size = size_of_val(fds);
+    let mut cmsg_buf = if fds.is_empty() {
+        vec![]
+    } else {
+        vec![0u8; unsafe { libc::CMSG_SPACE(fd_size as _) } as _]
+    };
+    let msg = libc::msghdr {
+        msg_name: null_mut(),
+        msg_namelen: 0,
+        msg_iov: bufs.as_ptr() as _,
+        msg_iovlen: bufs.len(),
+        msg_control: if fds.is_empty() {
+            null_mut()
+        } else {
+            cmsg_buf.as_mut_ptr() as _
+        },
+        msg_controllen: cmsg_buf.len(),
+        msg_flags: 0,
+    };
+    if !fds.is_empty() {
+        let cmsg_ptr = unsafe { libc::CMSG_FIRSTHDR(&msg) };
+        let cmsg = libc::cmsghdr {
+            cmsg_level: SOL_SOCKET,
+            cmsg_type: SCM_RIGHTS,
+            cmsg_len: unsafe { libc::CMSG_LEN(fd_size as _) } as _,
+        };
+        unsafe { std::ptr::write_unaligned(cmsg_ptr, cmsg) };
+        let data = unsafe { std::slice::from_raw_parts_mut(libc::CMSG_DATA(cmsg_ptr), fd_size) };
+        data.copy_from_slice(fds.as_bytes());
+    }
+    ffi!(unsafe { libc::sendmsg(conn.as_raw_fd(), &msg, 0) })?;
+    Ok(())
+}
+
+/// Receives data into `payload` from the peer of `conn`, and at most
+/// `max_fds` file descriptors attached to it.
+///
+/// Returns the number of bytes received, which is 0 if the peer closed
+/// the connection.
+pub fn recv_fds(
+    conn: &UnixStream,
+    payload: &mut [u8],
+    max_fds: usize,
+) -> io::Result<(usize, Vec<OwnedFd>)> {
+    let mut bufs = [IoSliceMut::new(payload)];
+    let fd_size = size_of::<RawFd>() * max_fds;
+    let mut cmsg_buf = vec![0u8; unsafe { libc::CMSG_SPACE(fd_size as _) } as _];
+    let mut msg = libc::msghdr {
+        msg_name: null_mut(),
+        msg_namelen: 0,
+        msg_iov: bufs.as_mut_ptr() as _,
+        msg_iovlen: bufs.len(),
+        msg_control: cmsg_buf.as_mut_ptr() as _,
+        msg_controllen: cmsg_buf.len(),
+        msg_flags: 0,
+    };
+    let len = ffi!(unsafe { libc::recvmsg(conn.as_raw_fd(), &mut msg, MSG_CMSG_CLOEXEC) })?;
+    let mut fds = vec![];
+    let mut cmsg_ptr = unsafe { libc::CMSG_FIRSTHDR(&msg) };
+    while !cmsg_ptr.is_null() {
+        let cmsg = unsafe { std::ptr::read_unaligned(cmsg_ptr) };
+        if cmsg.cmsg_level == SOL_SOCKET && cmsg.cmsg_type == SCM_RIGHTS {
+            let data = unsafe { libc::CMSG_DATA(cmsg_ptr) } as *const RawFd;
+            let data_len = cmsg.cmsg_len as usize - unsafe { libc::CMSG_LEN(0) } as usize;
+            for i in 0..data_len / size_of::<RawFd>() {
+                let fd = unsafe { std::ptr::read_unaligned(data.add(i)) };
+                fds.push(unsafe { OwnedFd::from_raw_fd(fd) });
+            }
+        }
+        cmsg_ptr = unsafe { libc::CMSG_NXTHDR(&msg, cmsg_ptr) };
+    }
+    if msg.msg_flags & libc::MSG_CTRUNC != 0 {
+        return Err(io::Error::from(ErrorKind::InvalidData));
+    }
+    Ok((len as usize, fds))
+}
+
+impl AsFd for SharedMemFd {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.file.as_fd()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::os::unix::net::UnixStream;
+
+    use crate::hv::test::FakeVmMemory;
+    use crate::mem::mapped::RamBus;
+    use crate::mem::shared::SharedMemFd;
+
+    #[test]
+    fn test_shared_mem_between_vms() {
+        const GPA: u64 = 0x1_0000_0000;
+        let (conn1, conn2) = UnixStream::pair().unwrap();
+
+        let shm1 = SharedMemFd::new(c"ivshmem", 0x2000).unwrap();
+        let vm1 = RamBus::new(FakeVmMemory);
+        vm1.add_shared(GPA, &shm1).unwrap();
+        shm1.send(&conn1).unwrap();
+
+        let shm2 = SharedMemFd::recv(&conn2).unwrap();
+        assert_eq!(shm2.size(), 0x2000);
+        let vm2 = RamBus::new(FakeVmMemory);
+        vm2.add_shared(GPA, &shm2).unwrap();
+
+        vm1.write(GPA + 0x1ff8, &0x1234_5678_9abc_def0u64).unwrap();
+        assert_eq!(
+            vm2.read::<u64>(GPA + 0x1ff8).unwrap(),
+            0x1234_5678_9abc_def0
+        );
+        vm2.write(GPA, b"pong").unwrap();
+        assert_eq!(&vm1.read::<[u8; 4]>(GPA).unwrap(), b"pong");
+    }
+}
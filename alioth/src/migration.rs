@@ -0,0 +1,442 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Versioned wire format of VM snapshots.
+//!
+//! A snapshot starts with a [`MigrationHeader`], followed by one section per
+//! device. Each section is a [`DeviceHeader`] followed by `data_len` bytes
+//! of device state. All integers are little-endian.
+
+use std::io::{Read, Write};
+use std::mem::size_of;
+
+use sha2::{Digest, Sha256};
+use snafu::{ResultExt, Snafu};
+use zerocopy::{AsBytes, FromBytes, FromZeroes};
+
+use crate::errors::{trace_error, DebugTrace};
+
+pub const MIGRATION_MAGIC: [u8; 4] = *b"ALTH";
+pub const MIGRATION_VERSION: u32 = 1;
+
+/// Name of the section holding [`ClockData`].
+pub const CLOCK_SECTION: &str = "clock";
+const CLOCK_SCHEMA_VERSION: u32 = 1;
+
+#[trace_error]
+#[derive(Snafu, DebugTrace)]
+#[snafu(module, visibility(pub(crate)), context(suffix(false)))]
+pub enum Error {
+    #[snafu(display("Failed to write snapshot"))]
+    Write { error: std::io::Error },
+    #[snafu(display("Failed to read snapshot"))]
+    Read { error: std::io::Error },
+    #[snafu(display("Invalid snapshot magic {magic:x?}"))]
+    Magic { magic: [u8; 4] },
+    #[snafu(display(
+        "Snapshot format version {version} is newer than the supported version {supported}"
+    ))]
+    StreamVersion { version: u32, supported: u32 },
+    #[snafu(display("Snapshot checksum mismatch"))]
+    Checksum,
+    #[snafu(display("Device name {name:?} is not valid UTF-8"))]
+    DeviceName { name: Vec<u8> },
+    #[snafu(display("Device name {name} is longer than {} bytes", u16::MAX))]
+    DeviceNameTooLong { name: String },
+    #[snafu(display("Section of {name} is too large: {len} bytes"))]
+    SectionTooLarge { name: String, len: usize },
+    #[snafu(display(
+        "State of {name} has schema version {version}, but only versions up to {supported} are supported"
+    ))]
+    SchemaVersion {
+        name: String,
+        version: u32,
+        supported: u32,
+    },
+    #[snafu(display("Snapshot has no state for {name}"))]
+    MissingDevice { name: String },
+    #[snafu(display("Failed to restore {name}: {msg}"))]
+    Restore { name: String, msg: String },
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+#[repr(C)]
+#[derive(Debug, Clone, Default, PartialEq, Eq, FromBytes, FromZeroes, AsBytes)]
+pub struct MigrationHeader {
+    pub magic: [u8; 4],
+    pub version: u32,
+    pub device_count: u32,
+    /// SHA-256 of all device sections.
+    pub checksum: [u8; 32],
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceHeader {
+    pub name_len: u16,
+    pub name: Vec<u8>,
+    pub schema_version: u32,
+    pub data_len: u32,
+}
+
+impl DeviceHeader {
+    fn write_to(&self, w: &mut impl Write) -> std::io::Result<()> {
+        w.write_all(&self.name_len.to_le_bytes())?;
+        w.write_all(&self.name)?;
+        w.write_all(&self.schema_version.to_le_bytes())?;
+        w.write_all(&self.data_len.to_le_bytes())
+    }
+}
+
+/// State of a device that can be saved into and restored from a snapshot.
+pub trait Migrate {
+    /// Version of the layout of the saved state. It must be increased
+    /// whenever the layout changes.
+    fn schema_version(&self) -> u32;
+    fn save(&self) -> Vec<u8>;
+    /// Restores state saved by schema version `version`, which is never
+    /// newer than [`Migrate::schema_version`].
+    fn restore(&self, version: u32, data: &[u8]) -> Result<()>;
+}
+
+/// Decodes `T` from state saved by an older schema whose layout is a
+/// prefix of `T`. Fields missing in `data` keep their values in
+/// `T::default()`.
+pub fn read_with_defaults<T>(data: &[u8]) -> T
+where
+    T: AsBytes + FromBytes + Default,
+{
+    let mut val = T::default();
+    let bytes = val.as_bytes_mut();
+    let len = std::cmp::min(bytes.len(), data.len());
+    bytes[..len].copy_from_slice(&data[..len]);
+    val
+}
+
+/// The guest clock at the time of the snapshot. Restoring it before any
+/// vCPU runs makes the guest clock continue from there, instead of
+/// jumping by the time the VM was stopped.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, FromBytes, FromZeroes, AsBytes)]
+pub struct ClockData {
+    /// Nanoseconds of the paravirtual clock.
+    pub clock: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceSection {
+    pub name: String,
+    pub schema_version: u32,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Default)]
+pub struct Snapshot {
+    pub sections: Vec<DeviceSection>,
+}
+
+impl Snapshot {
+    pub fn new() -> Self {
+        Snapshot::default()
+    }
+
+    pub fn save_device(&mut self, name: &str, dev: &impl Migrate) {
+        self.sections.push(DeviceSection {
+            name: name.to_owned(),
+            schema_version: dev.schema_version(),
+            data: dev.save(),
+        })
+    }
+
+    pub fn restore_device(&self, name: &str, dev: &impl Migrate) -> Result<()> {
+        let Some(section) = self.sections.iter().find(|s| s.name == name) else {
+            return error::MissingDevice { name }.fail();
+        };
+        let supported = dev.schema_version();
+        let version = section.schema_version;
+        if version > supported {
+            return error::SchemaVersion {
+                name,
+                version,
+                supported,
+            }
+            .fail();
+        }
+        if version < supported {
+            log::info!("{name}: restoring state of schema version {version} as {supported}");
+        }
+        dev.restore(version, &section.data)
+    }
+
+    pub fn save_clock(&mut self, clock: ClockData) {
+        self.sections.push(DeviceSection {
+            name: CLOCK_SECTION.to_owned(),
+            schema_version: CLOCK_SCHEMA_VERSION,
+            data: clock.as_bytes().to_vec(),
+        })
+    }
+
+    pub fn clock(&self) -> Result<ClockData> {
+        let name = CLOCK_SECTION;
+        let Some(section) = self.sections.iter().find(|s| s.name == name) else {
+            return error::MissingDevice { name }.fail();
+        };
+        if section.schema_version > CLOCK_SCHEMA_VERSION {
+            return error::SchemaVersion {
+                name,
+                version: section.schema_version,
+                supported: CLOCK_SCHEMA_VERSION,
+            }
+            .fail();
+        }
+        Ok(read_with_defaults(&section.data))
+    }
+
+    fn encode_sections(&self) -> Result<Vec<u8>> {
+        let mut buf = vec![];
+        for section in &self.sections {
+            let name = &section.name;
+            let Ok(name_len) = u16::try_from(name.len()) else {
+                return error::DeviceNameTooLong { name }.fail();
+            };
+            let Ok(data_len) = u32::try_from(section.data.len()) else {
+                let len = section.data.len();
+                return error::SectionTooLarge { name, len }.fail();
+            };
+            let header = DeviceHeader {
+                name_len,
+                name: name.as_bytes().to_vec(),
+                schema_version: section.schema_version,
+                data_len,
+            };
+            header.write_to(&mut buf).context(error::Write)?;
+            buf.extend_from_slice(&section.data);
+        }
+        Ok(buf)
+    }
+
+    pub fn write_to(&self, mut w: impl Write) -> Result<()> {
+        let sections = self.encode_sections()?;
+        let header = MigrationHeader {
+            magic: MIGRATION_MAGIC,
+            version: MIGRATION_VERSION,
+            device_count: self.sections.len() as u32,
+            checksum: Sha256::digest(&sections).into(),
+        };
+        w.write_all(header.as_bytes()).context(error::Write)?;
+        w.write_all(&sections).context(error::Write)
+    }
+
+    pub fn read_from(mut r: impl Read) -> Result<Self> {
+        let mut header = MigrationHeader::new_zeroed();
+        r.read_exact(header.as_bytes_mut()).context(error::Read)?;
+        if header.magic != MIGRATION_MAGIC {
+            return error::Magic {
+                magic: header.magic,
+            }
+            .fail();
+        }
+        if header.version > MIGRATION_VERSION {
+            return error::StreamVersion {
+                version: header.version,
+                supported: MIGRATION_VERSION,
+            }
+            .fail();
+        }
+        let mut buf = vec![];
+        r.read_to_end(&mut buf).context(error::Read)?;
+        if <[u8; 32]>::from(Sha256::digest(&buf)) != header.checksum {
+            return error::Checksum.fail();
+        }
+        let mut sections = vec![];
+        let mut remain = buf.as_slice();
+        for _ in 0..header.device_count {
+            let name_len = u16::from_le_bytes(take(&mut remain)?);
+            let name = take_slice(&mut remain, name_len as usize)?;
+            let Ok(name) = String::from_utf8(name.to_vec()) else {
+                return error::DeviceName {
+                    name: name.to_vec(),
+                }
+                .fail();
+            };
+            let schema_version = u32::from_le_bytes(take(&mut remain)?);
+            let data_len = u32::from_le_bytes(take(&mut remain)?);
+            let data = take_slice(&mut remain, data_len as usize)?.to_vec();
+            sections.push(DeviceSection {
+                name,
+                schema_version,
+                data,
+            });
+        }
+        Ok(Snapshot { sections })
+    }
+}
+
+fn take_slice<'a>(buf: &mut &'a [u8], len: usize) -> Result<&'a [u8]> {
+    if buf.len() < len {
+        return Err(std::io::ErrorKind::UnexpectedEof.into()).context(error::Read);
+    }
+    let (head, tail) = buf.split_at(len);
+    *buf = tail;
+    Ok(head)
+}
+
+fn take<const N: usize>(buf: &mut &[u8]) -> Result<[u8; N]> {
+    let mut val = [0u8; N];
+    val.copy_from_slice(take_slice(buf, N)?);
+    Ok(val)
+}
+
+const _: () = assert!(size_of::<MigrationHeader>() == 44);
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use assert_matches::assert_matches;
+    use parking_lot::Mutex;
+    use zerocopy::{AsBytes, FromBytes, FromZeroes};
+
+    use super::{read_with_defaults, ClockData, Error, Migrate, Result, Snapshot};
+
+    #[repr(C)]
+    #[derive(Debug, Clone, PartialEq, Eq, FromBytes, FromZeroes, AsBytes)]
+    struct CounterState {
+        count: u32,
+        // added in schema version 2
+        step: u32,
+    }
+
+    impl Default for CounterState {
+        fn default() -> Self {
+            CounterState { count: 0, step: 1 }
+        }
+    }
+
+    #[derive(Debug)]
+    struct Counter {
+        version: AtomicU32,
+        state: Mutex<CounterState>,
+    }
+
+    impl Migrate for Counter {
+        fn schema_version(&self) -> u32 {
+            self.version.load(Ordering::Relaxed)
+        }
+
+        fn save(&self) -> Vec<u8> {
+            let state = self.state.lock();
+            match self.schema_version() {
+                1 => state.count.as_bytes().to_vec(),
+                _ => state.as_bytes().to_vec(),
+            }
+        }
+
+        fn restore(&self, _version: u32, data: &[u8]) -> Result<()> {
+            *self.state.lock() = read_with_defaults(data);
+            Ok(())
+        }
+    }
+
+    fn counter(version: u32, count: u32, step: u32) -> Counter {
+        Counter {
+            version: AtomicU32::new(version),
+            state: Mutex::new(CounterState { count, step }),
+        }
+    }
+
+    #[test]
+    fn test_restore_older_schema() {
+        let dev = counter(1, 42, 1);
+        let mut snapshot = Snapshot::new();
+        snapshot.save_device("counter", &dev);
+        let mut buf = vec![];
+        snapshot.write_to(&mut buf).unwrap();
+        assert_eq!(&buf[0..4], b"ALTH");
+
+        // the device gains a field in schema version 2
+        let new_dev = counter(2, 0, 5);
+        let snapshot = Snapshot::read_from(buf.as_slice()).unwrap();
+        snapshot.restore_device("counter", &new_dev).unwrap();
+        assert_eq!(*new_dev.state.lock(), CounterState { count: 42, step: 1 });
+
+        new_dev.version.store(3, Ordering::Relaxed);
+        new_dev.state.lock().step = 2;
+        let mut snapshot = Snapshot::new();
+        snapshot.save_device("counter", &new_dev);
+        let mut buf = vec![];
+        snapshot.write_to(&mut buf).unwrap();
+        let snapshot = Snapshot::read_from(buf.as_slice()).unwrap();
+        assert_matches!(
+            snapshot.restore_device("counter", &dev),
+            Err(Error::SchemaVersion {
+                version: 3,
+                supported: 1,
+                ..
+            })
+        );
+        assert_matches!(
+            snapshot.restore_device("timer", &dev),
+            Err(Error::MissingDevice { .. })
+        );
+    }
+
+    #[test]
+    fn test_clock() {
+        let mut snapshot = Snapshot::new();
+        assert_matches!(snapshot.clock(), Err(Error::MissingDevice { .. }));
+
+        snapshot.save_device("counter", &counter(2, 1, 1));
+        snapshot.save_clock(ClockData {
+            clock: 0x1234_5678_9abc,
+        });
+        let mut buf = vec![];
+        snapshot.write_to(&mut buf).unwrap();
+        let snapshot = Snapshot::read_from(buf.as_slice()).unwrap();
+        assert_eq!(
+            snapshot.clock().unwrap(),
+            ClockData {
+                clock: 0x1234_5678_9abc
+            }
+        );
+    }
+
+    #[test]
+    fn test_corrupted_snapshot() {
+        let mut snapshot = Snapshot::new();
+        snapshot.save_device("counter", &counter(2, 1, 1));
+        let mut buf = vec![];
+        snapshot.write_to(&mut buf).unwrap();
+
+        let mut corrupted = buf.clone();
+        *corrupted.last_mut().unwrap() ^= 1;
+        assert_matches!(
+            Snapshot::read_from(corrupted.as_slice()),
+            Err(Error::Checksum { .. })
+        );
+
+        let mut newer = buf.clone();
+        newer[4..8].copy_from_slice(&2u32.to_le_bytes());
+        assert_matches!(
+            Snapshot::read_from(newer.as_slice()),
+            Err(Error::StreamVersion { version: 2, .. })
+        );
+
+        buf[0] = b'X';
+        assert_matches!(
+            Snapshot::read_from(buf.as_slice()),
+            Err(Error::Magic { .. })
+        );
+    }
+}
@@ -16,10 +16,20 @@ use serde::de::{self, Visitor};
 use serde::Deserialize;
 use zerocopy::{AsBytes, FromBytes, FromZeroes};
 
-#[derive(Debug, Default, FromBytes, FromZeroes, AsBytes, PartialEq, Eq)]
+#[derive(Debug, Default, Clone, Copy, FromBytes, FromZeroes, AsBytes, PartialEq, Eq)]
 #[repr(transparent)]
 pub struct MacAddr([u8; 6]);
 
+impl MacAddr {
+    pub const fn new(addr: [u8; 6]) -> Self {
+        MacAddr(addr)
+    }
+
+    pub fn octets(&self) -> [u8; 6] {
+        self.0
+    }
+}
+
 struct MacAddrVisitor;
 
 impl<'de> Visitor<'de> for MacAddrVisitor {
@@ -29,7 +39,7 @@ impl<'de> Visitor<'de> for MacAddrVisitor {
         formatter.write_str("a MAC address like ea:d7:a8:e8:c6:2f")
     }
 
-    fn visit_borrowed_str<E>(self, v: &'de str) -> Result<Self::Value, E>
+    fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
     where
         E: de::Error,
     {
@@ -19,9 +19,14 @@ use std::sync::Arc;
 
 use bitfield::bitfield;
 use parking_lot::{Mutex, RwLock};
+use serde::{Deserialize, Serialize};
 
 use crate::mem::emulated::{Action, Mmio};
-use crate::pci::config::{BAR_IO, BAR_MEM64, BAR_PREFETCHABLE};
+use crate::pci::cap::{MsixMsgCtrl, PciCapId};
+use crate::pci::config::{
+    ConfigHeader, DeviceHeader, PciConfig, BAR_IO, BAR_IO_MASK, BAR_MEM64, BAR_MEM_MASK,
+    BAR_PREFETCHABLE,
+};
 use crate::pci::host_bridge::HostBridge;
 use crate::pci::segment::PciSegment;
 use crate::pci::{Bdf, PciDevice, Result};
@@ -92,6 +97,94 @@ impl Mmio for PciIoBus {
     }
 }
 
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PciBarInfo {
+    pub index: u8,
+    pub addr: u64,
+    pub size: u64,
+    pub io: bool,
+    pub mem64: bool,
+    pub prefetchable: bool,
+}
+
+/// What `lspci -v` shows about a device.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PciDeviceInfo {
+    pub name: String,
+    /// In the format of `bus:dev.func`.
+    pub bdf: String,
+    pub vendor: u16,
+    pub device: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+    pub revision: u8,
+    pub subsystem_vendor: u16,
+    pub subsystem: u16,
+    pub bars: Vec<PciBarInfo>,
+    /// IDs of the capabilities, in the order of the capability list.
+    pub capabilities: Vec<u8>,
+    pub msix_vectors: Option<u16>,
+    pub intx_pin: u8,
+}
+
+fn bar_info(header: &DeviceHeader, masks: &[u32; 6]) -> Vec<PciBarInfo> {
+    let mut bars = vec![];
+    let mut index = 0;
+    while index < 6 {
+        let (val, mask) = (header.bars[index], masks[index]);
+        let mut info = PciBarInfo {
+            index: index as u8,
+            addr: 0,
+            size: 0,
+            io: val & BAR_IO == BAR_IO,
+            mem64: false,
+            prefetchable: false,
+        };
+        index += 1;
+        if info.io {
+            info.addr = (val & !BAR_IO_MASK) as u64;
+            info.size = (!(mask | BAR_IO_MASK)).wrapping_add(1) as u64;
+        } else {
+            info.addr = (val & !BAR_MEM_MASK) as u64;
+            let mut mask = mask as u64;
+            info.mem64 = val & BAR_MEM64 == BAR_MEM64;
+            info.prefetchable = val & BAR_PREFETCHABLE == BAR_PREFETCHABLE;
+            if info.mem64 && index < 6 {
+                info.addr |= (header.bars[index] as u64) << 32;
+                mask |= (masks[index] as u64) << 32;
+                index += 1;
+            }
+            if mask != 0 {
+                info.size = 1 << mask.trailing_zeros();
+            }
+        }
+        if info.size != 0 {
+            bars.push(info);
+        }
+    }
+    bars
+}
+
+/// Walks the capability list like a guest would.
+fn cap_info(config: &dyn PciConfig) -> (Vec<u8>, Option<u16>) {
+    let mut caps = vec![];
+    let mut msix_vectors = None;
+    let read = |offset: u64, size| config.read(offset, size).unwrap_or(0);
+    let mut ptr = read(DeviceHeader::OFFSET_CAPABILITY_POINTER as u64, 1) & !0b11;
+    // A capability takes at least 4 bytes, so a longer list has a loop.
+    while ptr != 0 && caps.len() < 0x100 / 4 {
+        let id = read(ptr, 1) as u8;
+        caps.push(id);
+        if id == PciCapId::Msix as u8 {
+            let ctrl = MsixMsgCtrl(read(ptr + 2, 2) as u16);
+            msix_vectors = Some(ctrl.table_len() + 1);
+        }
+        ptr = read(ptr + 1, 1) & !0b11;
+    }
+    (caps, msix_vectors)
+}
+
 #[derive(Debug)]
 pub struct PciBus {
     pub io_bus: Arc<PciIoBus>,
@@ -134,6 +227,48 @@ impl PciBus {
         self.segment.add(bdf, dev)
     }
 
+    /// Adds `dev` to `slot` of bus 0, or to the first free slot if `slot`
+    /// is `None`. Returns the slot number.
+    pub fn add_device(&self, slot: Option<u8>, dev: PciDevice) -> Result<u8> {
+        self.segment.add_to_slot(slot, dev)
+    }
+
+    /// Returns the devices on the bus, ordered by BDF.
+    pub fn enumerate(&self) -> Vec<PciDeviceInfo> {
+        let devices = self.segment.devices.read();
+        let mut devices: Vec<_> = devices.iter().collect();
+        devices.sort_by_key(|(bdf, _)| **bdf);
+        devices
+            .into_iter()
+            .map(|(bdf, dev)| {
+                let config = dev.dev.config();
+                let (header, bars) = {
+                    let data = config.get_header().data.read();
+                    let ConfigHeader::Device(header) = &data.header;
+                    (header.clone(), bar_info(header, &data.bar_masks))
+                };
+                let (capabilities, msix_vectors) = cap_info(&*config);
+                let common = &header.common;
+                PciDeviceInfo {
+                    name: dev.name.to_string(),
+                    bdf: bdf.to_string(),
+                    vendor: common.vendor,
+                    device: common.device,
+                    class: common.class,
+                    subclass: common.subclass,
+                    prog_if: common.prog_if,
+                    revision: common.revision,
+                    subsystem_vendor: header.subsystem_vendor,
+                    subsystem: header.subsystem,
+                    bars,
+                    capabilities,
+                    msix_vectors,
+                    intx_pin: header.intx_pin,
+                }
+            })
+            .collect()
+    }
+
     /// Assigns addresses to all devices' base address registers
     ///
     /// `resources` is an array of 4 `(start, end)` tuples, corresponds to
@@ -208,3 +343,116 @@ impl Default for PciBus {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use assert_matches::assert_matches;
+
+    use crate::device::pvpanic::PvPanic;
+    use crate::device::xhci::Xhci;
+    use crate::hv::test::{FakeMsiSender, FakeVmMemory};
+    use crate::mem::emulated::Mmio;
+    use crate::mem::mapped::RamBus;
+    use crate::pci::bus::{PciBarInfo, PciBus};
+    use crate::pci::cap::PciCapId;
+    use crate::pci::host_bridge::HostBridge;
+    use crate::pci::segment::NUM_SLOTS;
+    use crate::pci::{Bdf, Error, PciDevice};
+
+    #[test]
+    fn test_enumerate() {
+        let bus = PciBus::new();
+        let name = Arc::new("host_bridge".to_owned());
+        bus.add(Bdf(0), PciDevice::new(name, Arc::new(HostBridge::new())));
+        let name = Arc::new("xhci".to_owned());
+        let memory = Arc::new(RamBus::new(FakeVmMemory));
+        let xhci = Xhci::new(name.clone(), memory, FakeMsiSender::default()).unwrap();
+        bus.add(Bdf(0x18), PciDevice::new(name, Arc::new(xhci)));
+        let name = Arc::new("pvpanic".to_owned());
+        bus.add(Bdf(0x10), PciDevice::new(name, Arc::new(PvPanic::new())));
+        bus.assign_resources(&[
+            (0x1000, 0x10000),
+            (0xa000_0000, 0xc000_0000),
+            (0x8000_0000, 0xa000_0000),
+            (0x1_0000_0000, 0x2_0000_0000),
+        ]);
+
+        let infos = bus.enumerate();
+        let ids: Vec<_> = infos
+            .iter()
+            .map(|i| (i.bdf.as_str(), i.vendor, i.device))
+            .collect();
+        assert_eq!(
+            ids,
+            [
+                ("00:00.0", 0x1022, 0x1480),
+                ("00:02.0", 0x1b36, 0x0011),
+                ("00:03.0", 0x1b36, 0x000d),
+            ]
+        );
+
+        let pvpanic = &infos[1];
+        assert_eq!(pvpanic.name, "pvpanic");
+        assert!(pvpanic.capabilities.is_empty());
+        assert_eq!(pvpanic.msix_vectors, None);
+        assert_eq!(pvpanic.bars.len(), 1);
+        assert_eq!(pvpanic.bars[0].size, 0x1000);
+
+        let xhci = &infos[2];
+        assert_eq!(
+            (xhci.class, xhci.subclass, xhci.prog_if),
+            (0x0c, 0x03, 0x30)
+        );
+        assert_eq!(xhci.capabilities, [PciCapId::Msix as u8]);
+        assert_eq!(xhci.msix_vectors, Some(1));
+        assert_eq!(
+            xhci.bars,
+            [PciBarInfo {
+                index: 0,
+                addr: 0x1_0000_0000,
+                size: 0x4000,
+                io: false,
+                mem64: true,
+                prefetchable: true,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_add_device() {
+        let bus = PciBus::new();
+        let new_dev =
+            |name: &str| PciDevice::new(Arc::new(name.to_owned()), Arc::new(PvPanic::new()));
+
+        assert_matches!(bus.add_device(Some(5), new_dev("dev5")), Ok(5));
+        assert_matches!(
+            bus.add_device(Some(5), new_dev("dup")),
+            Err(Error::SlotOccupied { slot: 5, .. })
+        );
+        assert_matches!(
+            bus.add_device(Some(NUM_SLOTS), new_dev("dev32")),
+            Err(Error::InvalidSlot { slot: 32, .. })
+        );
+        loop {
+            match bus.add_device(None, new_dev("dev")) {
+                Ok(slot) => assert_ne!(slot, 5),
+                Err(e) => {
+                    assert_matches!(e, Error::BusFull { .. });
+                    break;
+                }
+            }
+        }
+        let devices = bus.segment.devices.read();
+        for slot in 0..NUM_SLOTS as u16 {
+            assert!(devices.contains_key(&Bdf(slot << 3)));
+        }
+        drop(devices);
+
+        // vendor and device ID of pvpanic
+        assert_eq!(bus.segment.read(5 << 15, 4).unwrap(), 0x0011_1b36);
+        // function 1 is empty
+        assert_eq!(bus.segment.read(5 << 15 | 1 << 12, 4).unwrap(), u64::MAX);
+    }
+}
@@ -13,22 +13,31 @@
 // limitations under the License.
 
 use std::fmt::Debug;
+use std::iter::zip;
 use std::mem::size_of;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 
 use bitfield::bitfield;
-use parking_lot::RwLock;
+use bitflags::bitflags;
+use macros::Layout;
+use parking_lot::{Mutex, RwLock};
 use zerocopy::{AsBytes, FromBytes, FromZeroes};
 
-use crate::hv::IrqFd;
+use crate::hv::{IrqFd, MsiSender};
 use crate::mem::addressable::SlotBackend;
-use crate::mem::emulated::{Action, Mmio, MmioBus};
-use crate::pci::config::DeviceHeader;
-use crate::pci::Error;
-use crate::{align_up, impl_mmio_for_zerocopy, mem};
+use crate::mem::emulated::{Action, ChangeLayout, Mmio, MmioBus};
+use crate::mem::{MemRegion, MemRegionEntry, MemRegionType};
+use crate::pci::config::{
+    Command, ConfigHeader, DeviceHeader, EmulatedHeader, HeaderData, BAR_MEM64, BAR_MEM_MASK,
+};
+use crate::pci::{error, Error, PciBar, Result};
+use crate::{align_up, c_enum, impl_mmio_for_zerocopy, mask_bits, mem, unsafe_impl_zerocopy};
 
 #[repr(u8)]
 #[derive(Debug, Clone, Copy)]
 pub enum PciCapId {
+    PowerManagement = 0x01,
     Msi = 0x05,
     Vendor = 0x09,
     Msix = 0x11,
@@ -41,6 +50,17 @@ pub struct PciCapHdr {
     pub next: u8,
 }
 
+#[repr(u16)]
+#[derive(Debug, Clone, Copy)]
+pub enum PcieExtCapId {
+    AdvancedErrorReporting = 0x01,
+    SingleRootIoVirtualization = 0x10,
+    ResizableBar = 0x15,
+}
+
+/// Offset of the first PCIe extended capability in the config space.
+pub const PCIE_EXT_CAP_START: u64 = 0x100;
+
 bitfield! {
     #[derive(Copy, Clone, Default)]
     #[repr(C)]
@@ -51,16 +71,29 @@ bitfield! {
     pub id, _: 15,0;
 }
 
+impl PcieExtCapHdr {
+    pub fn new(id: PcieExtCapId, version: u8, next: u16) -> Self {
+        PcieExtCapHdr((next as u32) << 20 | (version as u32 & 0xf) << 16 | id as u32)
+    }
+
+    pub fn set_next(&mut self, next: u16) {
+        self.0 = (self.0 & 0xf_ffff) | (next as u32) << 20;
+    }
+}
+
 bitfield! {
     #[derive(Copy, Clone, Default, FromBytes, FromZeroes, AsBytes)]
     #[repr(C)]
     pub struct MsixMsgCtrl(u16);
     impl Debug;
-    pub table_len, _ : 10, 0;
+    pub table_len, set_table_len : 10, 0;
     pub masked, set_masked: 14;
     pub enabled, set_enabled: 15;
 }
 
+/// The table size field of the message control register has 11 bits.
+pub const MAX_MSIX_TABLE_SIZE: u32 = 2048;
+
 impl MsixMsgCtrl {
     pub fn new(len: u16) -> Self {
         assert_ne!(len, 1);
@@ -126,6 +159,21 @@ impl Default for MsixTableEntry {
 pub trait PciCap: Mmio {
     fn set_next(&mut self, val: u8);
     fn reset(&self);
+
+    /// Sets the offset of the next PCIe extended capability.
+    fn set_next_ext(&mut self, _val: u16) {}
+
+    fn save(&self) -> Vec<u8> {
+        (0..Mmio::size(self))
+            .map(|offset| Mmio::read(self, offset, 1).unwrap_or(0) as u8)
+            .collect()
+    }
+
+    /// Restores the registers from the bytes returned by [`PciCap::save`].
+    /// `memory` is for capabilities whose state decides guest memory
+    /// mappings. There is no default, so that a capability cannot drop its
+    /// state silently.
+    fn restore(&self, data: &[u8], memory: &mem::Memory) -> Result<()>;
 }
 
 impl SlotBackend for Box<dyn PciCap> {
@@ -176,6 +224,35 @@ impl PciCapList {
             cap.reset();
         }
     }
+
+    pub fn save(&self) -> Vec<Vec<u8>> {
+        let inner = self.inner.inner.read();
+        inner.iter().map(|(_, cap)| cap.save()).collect()
+    }
+
+    pub fn restore(&self, caps: &[Vec<u8>], memory: &mem::Memory) -> Result<()> {
+        let inner = self.inner.inner.read();
+        let expected = inner.iter().count();
+        if caps.len() != expected {
+            return error::SnapshotCapCount {
+                count: caps.len(),
+                expected,
+            }
+            .fail();
+        }
+        for ((_, cap), data) in zip(inner.iter(), caps) {
+            let expected = Mmio::size(cap.as_ref()) as usize;
+            if data.len() != expected {
+                return error::SnapshotSize {
+                    size: data.len(),
+                    expected,
+                }
+                .fail();
+            }
+            cap.restore(data, memory)?;
+        }
+        Ok(())
+    }
 }
 
 impl Mmio for PciCapList {
@@ -192,6 +269,28 @@ impl Mmio for PciCapList {
     }
 }
 
+impl PciCapList {
+    /// Appends a PCIe extended capability to the list starting at
+    /// [`PCIE_EXT_CAP_START`].
+    pub fn add_ext_cap(&self, cap: Box<dyn PciCap>) -> Result<()> {
+        let mut inner = self.inner.inner.write();
+        let last = match inner.last() {
+            Some((addr, _)) if addr >= PCIE_EXT_CAP_START => Some(addr),
+            _ => None,
+        };
+        let Some(last) = last else {
+            inner.add(PCIE_EXT_CAP_START, cap)?;
+            return Ok(());
+        };
+        let mut prev = inner.remove(last)?;
+        let next = align_up!(last + Mmio::size(&prev), 4);
+        prev.set_next_ext(next as u16);
+        inner.add(last, prev)?;
+        inner.add(next, cap)?;
+        Ok(())
+    }
+}
+
 impl TryFrom<Vec<Box<dyn PciCap>>> for PciCapList {
     type Error = Error;
     fn try_from(caps: Vec<Box<dyn PciCap>>) -> Result<Self, Self::Error> {
@@ -214,7 +313,7 @@ impl TryFrom<Vec<Box<dyn PciCap>>> for PciCapList {
 
 #[derive(Debug)]
 pub struct MsixCapMmio {
-    pub cap: RwLock<MsixCap>,
+    pub cap: Arc<RwLock<MsixCap>>,
 }
 
 impl Mmio for MsixCapMmio {
@@ -248,6 +347,16 @@ impl PciCap for MsixCapMmio {
         cap.control.set_enabled(false);
         cap.control.set_masked(false);
     }
+
+    fn restore(&self, data: &[u8], _memory: &mem::Memory) -> Result<()> {
+        let Some(saved) = MsixCap::read_from_prefix(data) else {
+            return Ok(());
+        };
+        let mut cap = self.cap.write();
+        cap.control.set_enabled(saved.control.enabled());
+        cap.control.set_masked(saved.control.masked());
+        Ok(())
+    }
 }
 
 #[derive(Debug)]
@@ -297,16 +406,85 @@ where
 }
 
 #[derive(Debug)]
-pub struct MsixTableMmio<F> {
-    pub entries: Vec<RwLock<MsixTableMmioEntry<F>>>,
+pub struct MsixTableMmio<M>
+where
+    M: MsiSender,
+{
+    pub entries: RwLock<Vec<RwLock<MsixTableMmioEntry<M::IrqFd>>>>,
+    pub msi_sender: M,
+    /// The pending bit array, one bit per vector.
+    pending: Box<[AtomicU64]>,
+    max_len: usize,
 }
 
-impl<F> Mmio for MsixTableMmio<F>
+impl<M> MsixTableMmio<M>
 where
-    F: IrqFd,
+    M: MsiSender,
+{
+    /// Creates a table of `len` entries, which can later grow up to
+    /// `max_len` entries.
+    pub fn new(len: usize, max_len: usize, msi_sender: M) -> Self {
+        assert!(len <= max_len);
+        let entries = (0..len)
+            .map(|_| RwLock::new(MsixTableMmioEntry::Entry(MsixTableEntry::default())))
+            .collect();
+        let pending = (0..max_len.div_ceil(64))
+            .map(|_| AtomicU64::new(0))
+            .collect();
+        MsixTableMmio {
+            entries: RwLock::new(entries),
+            msi_sender,
+            pending,
+            max_len,
+        }
+    }
+
+    /// Records an interrupt of a masked `vector`, which is sent when the
+    /// vector is unmasked.
+    pub fn set_pending(&self, vector: u16) {
+        let vector = vector as usize;
+        if let Some(word) = self.pending.get(vector / 64) {
+            word.fetch_or(1 << (vector % 64), Ordering::AcqRel);
+        }
+    }
+
+    fn take_pending(&self, vector: usize) -> bool {
+        let Some(word) = self.pending.get(vector / 64) else {
+            return false;
+        };
+        let bit = 1 << (vector % 64);
+        word.fetch_and(!bit, Ordering::AcqRel) & bit != 0
+    }
+
+    pub fn clear_pending(&self) {
+        for word in self.pending.iter() {
+            word.store(0, Ordering::Release);
+        }
+    }
+
+    /// Grows or shrinks the table to `len` entries. New entries are masked.
+    pub fn resize(&self, len: usize) -> Result<()> {
+        if len == 0 || len > self.max_len {
+            return error::MsixTableSize {
+                size: len,
+                max: self.max_len,
+            }
+            .fail();
+        }
+        let mut entries = self.entries.write();
+        entries.resize_with(len, || {
+            RwLock::new(MsixTableMmioEntry::Entry(MsixTableEntry::default()))
+        });
+        Ok(())
+    }
+}
+
+impl<M> Mmio for MsixTableMmio<M>
+where
+    M: MsiSender,
 {
     fn size(&self) -> u64 {
-        (size_of::<MsixTableEntry>() * self.entries.len()) as u64
+        (size_of::<MsixTableEntry>() * self.max_len) as u64
     }
 
     fn read(&self, offset: u64, size: u8) -> mem::Result<u64> {
@@ -315,10 +493,11 @@ where
             return Ok(0);
         }
         let index = offset as usize / size_of::<MsixTableEntry>();
-        let Some(entry) = self.entries.get(index) else {
+        let entries = self.entries.read();
+        let Some(entry) = entries.get(index) else {
             log::error!(
                 "MSI-X table size: {}, accessing index {index}",
-                self.entries.len()
+                entries.len()
             );
             return Ok(0);
         };
@@ -340,10 +519,11 @@ where
         }
         let val = val as u32;
         let index = offset as usize / size_of::<MsixTableEntry>();
-        let Some(entry) = self.entries.get(index) else {
+        let entries = self.entries.read();
+        let Some(entry) = entries.get(index) else {
             log::error!(
                 "MSI-X table size: {}, accessing index {index}",
-                self.entries.len()
+                entries.len()
             );
             return Ok(Action::None);
         };
@@ -352,9 +532,1197 @@ where
             0 => entry.set_addr_lo(val)?,
             4 => entry.set_addr_hi(val)?,
             8 => entry.set_data(val)?,
-            12 => entry.set_masked(MsixVectorCtrl(val).masked())?,
+            12 => {
+                let masked = MsixVectorCtrl(val).masked();
+                entry.set_masked(masked)?;
+                if !masked && self.take_pending(index) {
+                    let addr = ((entry.get_addr_hi() as u64) << 32) | entry.get_addr_lo() as u64;
+                    let data = entry.get_data();
+                    if let Err(e) = self.msi_sender.send(addr, data) {
+                        log::error!("send pending msi data = {data:#x} to {addr:#x}: {e}");
+                        // Keeps the bit so that the next unmask retries.
+                        self.set_pending(index as u16);
+                    }
+                }
+                Action::None
+            }
             _ => unreachable!(),
         };
         Ok(Action::None)
     }
 }
+
+/// The MSI-X pending bit array, which is read-only to the guest.
+#[derive(Debug)]
+pub struct MsixPbaMmio<M>
+where
+    M: MsiSender,
+{
+    pub table: Arc<MsixTableMmio<M>>,
+}
+
+impl<M> Mmio for MsixPbaMmio<M>
+where
+    M: MsiSender,
+{
+    fn size(&self) -> u64 {
+        (self.table.pending.len() * size_of::<u64>()) as u64
+    }
+
+    fn read(&self, offset: u64, size: u8) -> mem::Result<u64> {
+        let Some(word) = self.table.pending.get(offset as usize / size_of::<u64>()) else {
+            return Ok(0);
+        };
+        let val = word.load(Ordering::Acquire);
+        let ret = match (offset & 0b111, size) {
+            (0, 8) => val,
+            (0, 4) => val & 0xffff_ffff,
+            (4, 4) => val >> 32,
+            _ => {
+                log::error!("unaligned access to msix pba: size = {size}, offset = {offset:#x}");
+                0
+            }
+        };
+        Ok(ret)
+    }
+
+    fn write(&self, _offset: u64, _size: u8, _val: u64) -> mem::Result<Action> {
+        Ok(Action::None)
+    }
+}
+
+/// The smallest size of a resizable BAR. Sizes are encoded as
+/// `REBAR_SIZE_MIN << n`.
+pub const REBAR_SIZE_MIN: u64 = 1 << 20;
+const REBAR_SIZE_MAX_SHIFT: u32 = 27;
+
+bitfield! {
+    #[derive(Copy, Clone, Default)]
+    pub struct RebarCtrl(u32);
+    impl Debug;
+    pub u8, bar_index, set_bar_index: 2, 0;
+    pub u8, num_bars, set_num_bars: 7, 5;
+    pub u8, bar_size, set_bar_size: 13, 8;
+}
+
+#[derive(Debug)]
+struct ResizableBar {
+    index: u8,
+    /// Bit `n` is set if the BAR can be `REBAR_SIZE_MIN << n` bytes.
+    sizes: u32,
+    default: u8,
+    current: u8,
+}
+
+/// The PCIe Resizable BAR capability.
+///
+/// Writing a supported size to a control register replaces the region of
+/// the BAR with one of the new size and updates the BAR mask. The guest
+/// must disable memory decoding before resizing and reassign the BAR
+/// afterwards.
+#[derive(Debug)]
+pub struct RebarCap {
+    header: Arc<RwLock<HeaderData>>,
+    bars: Arc<RwLock<[PciBar; 6]>>,
+    entries: Mutex<Vec<ResizableBar>>,
+    next: u16,
+}
+
+impl RebarCap {
+    /// Creates the capability for memory BARs in `max_sizes`, each of which
+    /// can grow up to the given power-of-two size. BARs smaller than
+    /// [`REBAR_SIZE_MIN`] are enlarged to it.
+    pub fn new(header: &EmulatedHeader, max_sizes: &[(u8, u64)]) -> Result<Self> {
+        let cap = RebarCap {
+            header: header.data.clone(),
+            bars: header.bars.clone(),
+            entries: Mutex::new(vec![]),
+            next: 0,
+        };
+        let mut entries = vec![];
+        for &(index, max_size) in max_sizes {
+            let size = match cap.bars.read().get(index as usize) {
+                Some(PciBar::Mem(region)) => region.size(),
+                _ => return error::InvalidBar { index }.fail(),
+            };
+            let min = std::cmp::max(size.next_power_of_two(), REBAR_SIZE_MIN);
+            let valid = max_size.is_power_of_two()
+                && max_size >= min
+                && max_size <= REBAR_SIZE_MIN << REBAR_SIZE_MAX_SHIFT;
+            if !valid {
+                return error::BarSize {
+                    index,
+                    size: max_size,
+                }
+                .fail();
+            }
+            let shift = |size: u64| (size / REBAR_SIZE_MIN).trailing_zeros();
+            let sizes = (u32::MAX << shift(min)) & (u32::MAX >> (31 - shift(max_size)));
+            let default = shift(min) as u8;
+            if size != min {
+                cap.resize(index, min)?;
+            }
+            entries.push(ResizableBar {
+                index,
+                sizes,
+                default,
+                current: default,
+            });
+        }
+        *cap.entries.lock() = entries;
+        Ok(cap)
+    }
+
+    fn resize(&self, index: u8, size: u64) -> Result<()> {
+        let mut header = self.header.write();
+        let HeaderData {
+            header: ConfigHeader::Device(dev_header),
+            bar_masks,
+            bdf,
+        } = &mut *header;
+        if dev_header.common.command.contains(Command::MEM) {
+            log::error!("{bdf}: bar {index}: cannot resize while enabled");
+            return error::BarSize { index, size }.fail();
+        }
+        let i = index as usize;
+        let is_64 = dev_header.bars[i] & BAR_MEM64 == BAR_MEM64;
+        if !is_64 && size > 1 << 31 {
+            return error::BarSize { index, size }.fail();
+        }
+        dev_header.bars[i] &= BAR_MEM_MASK;
+        let mask = !(size - 1);
+        bar_masks[i] = mask as u32 & !BAR_MEM_MASK;
+        if is_64 {
+            dev_header.bars[i + 1] = 0;
+            bar_masks[i + 1] = (mask >> 32) as u32;
+        }
+
+        let mut bars = self.bars.write();
+        let PciBar::Mem(region) = &bars[i] else {
+            return error::InvalidBar { index }.fail();
+        };
+        let type_ = region
+            .entries
+            .first()
+            .map_or(MemRegionType::Hidden, |e| e.type_);
+        let resized = MemRegion {
+            ranges: region.ranges.clone(),
+            entries: vec![MemRegionEntry { size, type_ }],
+            callbacks: Mutex::new(std::mem::take(&mut *region.callbacks.lock())),
+            dirty_callbacks: Mutex::new(std::mem::take(&mut *region.dirty_callbacks.lock())),
+        };
+        resized.validate()?;
+        log::info!("{bdf}: bar {index}: resized to {size:#x}");
+        bars[i] = PciBar::Mem(Arc::new(resized));
+        Ok(())
+    }
+
+    fn set_size(&self, entry: &mut ResizableBar, size: u8) {
+        if size == entry.current {
+            return;
+        }
+        if entry.sizes & (1 << size) == 0 {
+            log::error!("bar {}: unsupported size {size}", entry.index);
+            return;
+        }
+        if self.resize(entry.index, REBAR_SIZE_MIN << size).is_ok() {
+            entry.current = size;
+        }
+    }
+
+    fn regs(&self) -> Vec<u32> {
+        let entries = self.entries.lock();
+        let mut regs = vec![PcieExtCapHdr::new(PcieExtCapId::ResizableBar, 1, self.next).0];
+        for (i, entry) in entries.iter().enumerate() {
+            let mut ctrl = RebarCtrl::default();
+            ctrl.set_bar_index(entry.index);
+            if i == 0 {
+                ctrl.set_num_bars(entries.len() as u8);
+            }
+            ctrl.set_bar_size(entry.current);
+            regs.push(entry.sizes << 4);
+            regs.push(ctrl.0);
+        }
+        regs
+    }
+}
+
+impl Mmio for RebarCap {
+    fn size(&self) -> u64 {
+        (size_of::<u32>() * (1 + 2 * self.entries.lock().len())) as u64
+    }
+
+    fn read(&self, offset: u64, size: u8) -> mem::Result<u64> {
+        let regs = self.regs();
+        let bytes = regs.as_bytes();
+        let offset = offset as usize;
+        let ret = match size {
+            1 => bytes.get(offset).map(|b| *b as u64),
+            2 => u16::read_from_prefix(&bytes[offset..]).map(|w| w as u64),
+            4 => u32::read_from_prefix(&bytes[offset..]).map(|d| d as u64),
+            _ => None,
+        };
+        Ok(ret.unwrap_or(0))
+    }
+
+    fn write(&self, offset: u64, size: u8, val: u64) -> mem::Result<Action> {
+        // control registers are at offset 8, 16, ...
+        if size != 4 || !offset.is_multiple_of(8) || offset == 0 {
+            log::error!("rebar: write {val:#x} to offset {offset:#x}, size {size}: ignored");
+            return Ok(Action::None);
+        }
+        let mut entries = self.entries.lock();
+        if let Some(entry) = entries.get_mut(offset as usize / 8 - 1) {
+            self.set_size(entry, RebarCtrl(val as u32).bar_size());
+        }
+        Ok(Action::None)
+    }
+}
+
+impl PciCap for RebarCap {
+    fn set_next(&mut self, _val: u8) {}
+
+    fn set_next_ext(&mut self, val: u16) {
+        self.next = val;
+    }
+
+    fn reset(&self) {
+        let mut entries = self.entries.lock();
+        for entry in entries.iter_mut() {
+            let default = entry.default;
+            self.set_size(entry, default);
+        }
+    }
+
+    fn restore(&self, data: &[u8], _memory: &mem::Memory) -> Result<()> {
+        let mut entries = self.entries.lock();
+        let ctrls = data.chunks_exact(size_of::<u32>()).skip(2).step_by(2);
+        for (entry, ctrl) in zip(entries.iter_mut(), ctrls) {
+            let ctrl = RebarCtrl(u32::from_le_bytes(ctrl.try_into().unwrap()));
+            self.set_size(entry, ctrl.bar_size());
+        }
+        Ok(())
+    }
+}
+
+bitflags! {
+    #[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    pub struct AerUncorrectable: u32 {
+        const DATA_LINK_PROTOCOL = 1 << 4;
+        const SURPRISE_DOWN = 1 << 5;
+        const POISONED_TLP = 1 << 12;
+        const FLOW_CONTROL_PROTOCOL = 1 << 13;
+        const COMPLETION_TIMEOUT = 1 << 14;
+        const COMPLETER_ABORT = 1 << 15;
+        const UNEXPECTED_COMPLETION = 1 << 16;
+        const RECEIVER_OVERFLOW = 1 << 17;
+        const MALFORMED_TLP = 1 << 18;
+        const ECRC = 1 << 19;
+        const UNSUPPORTED_REQUEST = 1 << 20;
+        const INTERNAL = 1 << 22;
+    }
+}
+
+bitflags! {
+    #[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    pub struct AerCorrectable: u32 {
+        const RECEIVER = 1 << 0;
+        const BAD_TLP = 1 << 6;
+        const BAD_DLLP = 1 << 7;
+        const REPLAY_NUM_ROLLOVER = 1 << 8;
+        const REPLAY_TIMER_TIMEOUT = 1 << 12;
+        const ADVISORY_NON_FATAL = 1 << 13;
+        const INTERNAL = 1 << 14;
+        const HEADER_LOG_OVERFLOW = 1 << 15;
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, FromBytes, FromZeroes, AsBytes, Layout)]
+pub struct AerRegs {
+    pub header: u32,
+    pub uncorrectable_status: u32,
+    pub uncorrectable_mask: u32,
+    pub uncorrectable_severity: u32,
+    pub correctable_status: u32,
+    pub correctable_mask: u32,
+    pub cap_control: u32,
+    pub header_log: [u32; 4],
+    pub root_command: u32,
+    pub root_status: u32,
+    pub error_source: u32,
+}
+
+impl_mmio_for_zerocopy!(AerRegs);
+
+impl Default for AerRegs {
+    fn default() -> Self {
+        // Default masks and severities from the PCIe specification.
+        AerRegs {
+            header: PcieExtCapHdr::new(PcieExtCapId::AdvancedErrorReporting, 2, 0).0,
+            uncorrectable_mask: AerUncorrectable::INTERNAL.bits(),
+            uncorrectable_severity: (AerUncorrectable::DATA_LINK_PROTOCOL
+                | AerUncorrectable::SURPRISE_DOWN
+                | AerUncorrectable::FLOW_CONTROL_PROTOCOL
+                | AerUncorrectable::RECEIVER_OVERFLOW
+                | AerUncorrectable::MALFORMED_TLP
+                | AerUncorrectable::INTERNAL)
+                .bits(),
+            correctable_mask: (AerCorrectable::ADVISORY_NON_FATAL | AerCorrectable::INTERNAL)
+                .bits(),
+            uncorrectable_status: 0,
+            correctable_status: 0,
+            cap_control: 0,
+            header_log: [0; 4],
+            root_command: 0,
+            root_status: 0,
+            error_source: 0,
+        }
+    }
+}
+
+bitflags! {
+    #[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    pub struct AerRootStatus: u32 {
+        const COR_RECEIVED = 1 << 0;
+        const MULTIPLE_COR_RECEIVED = 1 << 1;
+        const UNCOR_RECEIVED = 1 << 2;
+        const MULTIPLE_UNCOR_RECEIVED = 1 << 3;
+        const FIRST_UNCOR_FATAL = 1 << 4;
+        const NON_FATAL_RECEIVED = 1 << 5;
+        const FATAL_RECEIVED = 1 << 6;
+    }
+}
+
+/// The PCIe Advanced Error Reporting capability.
+///
+/// Cloned capabilities share the same registers, so a device can keep a
+/// handle to report errors after the capability is added to its config
+/// space.
+#[derive(Debug, Clone, Default)]
+pub struct AerCap {
+    regs: Arc<Mutex<AerRegs>>,
+}
+
+impl AerCap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records uncorrectable errors. Returns `true` if any of them is not
+    /// masked and should be signaled.
+    pub fn report_uncorrectable(&self, errors: AerUncorrectable) -> bool {
+        let mut regs = self.regs.lock();
+        regs.uncorrectable_status |= errors.bits();
+        let unmasked = errors.bits() & !regs.uncorrectable_mask;
+        if unmasked == 0 {
+            return false;
+        }
+        let mut root_status = AerRootStatus::from_bits_retain(regs.root_status);
+        if root_status.contains(AerRootStatus::UNCOR_RECEIVED) {
+            root_status |= AerRootStatus::MULTIPLE_UNCOR_RECEIVED;
+        } else {
+            root_status |= AerRootStatus::UNCOR_RECEIVED;
+            // first error pointer
+            regs.cap_control = (regs.cap_control & !0x1f) | unmasked.trailing_zeros();
+        }
+        if unmasked & regs.uncorrectable_severity != 0 {
+            if !root_status.contains(AerRootStatus::FATAL_RECEIVED) {
+                root_status |= AerRootStatus::FIRST_UNCOR_FATAL;
+            }
+            root_status |= AerRootStatus::FATAL_RECEIVED;
+        } else {
+            root_status |= AerRootStatus::NON_FATAL_RECEIVED;
+        }
+        regs.root_status = root_status.bits();
+        true
+    }
+
+    /// Records correctable errors. Returns `true` if any of them is not
+    /// masked and should be signaled.
+    pub fn report_correctable(&self, errors: AerCorrectable) -> bool {
+        let mut regs = self.regs.lock();
+        regs.correctable_status |= errors.bits();
+        if errors.bits() & !regs.correctable_mask == 0 {
+            return false;
+        }
+        let mut root_status = AerRootStatus::from_bits_retain(regs.root_status);
+        if root_status.contains(AerRootStatus::COR_RECEIVED) {
+            root_status |= AerRootStatus::MULTIPLE_COR_RECEIVED;
+        } else {
+            root_status |= AerRootStatus::COR_RECEIVED;
+        }
+        regs.root_status = root_status.bits();
+        true
+    }
+}
+
+impl Mmio for AerCap {
+    fn size(&self) -> u64 {
+        size_of::<AerRegs>() as u64
+    }
+
+    fn read(&self, offset: u64, size: u8) -> mem::Result<u64> {
+        Mmio::read(&*self.regs.lock(), offset, size)
+    }
+
+    fn write(&self, offset: u64, size: u8, val: u64) -> mem::Result<Action> {
+        if size != 4 || offset & 0b11 != 0 {
+            log::error!("aer: write {val:#x} to offset {offset:#x}, size {size}: ignored");
+            return Ok(Action::None);
+        }
+        let val = val as u32;
+        let mut regs = self.regs.lock();
+        match offset as usize {
+            AerRegs::OFFSET_UNCORRECTABLE_STATUS => regs.uncorrectable_status &= !val,
+            AerRegs::OFFSET_UNCORRECTABLE_MASK => {
+                regs.uncorrectable_mask = val & AerUncorrectable::all().bits()
+            }
+            AerRegs::OFFSET_UNCORRECTABLE_SEVERITY => {
+                regs.uncorrectable_severity = val & AerUncorrectable::all().bits()
+            }
+            AerRegs::OFFSET_CORRECTABLE_STATUS => regs.correctable_status &= !val,
+            AerRegs::OFFSET_CORRECTABLE_MASK => {
+                regs.correctable_mask = val & AerCorrectable::all().bits()
+            }
+            AerRegs::OFFSET_ROOT_COMMAND => regs.root_command = val & 0b111,
+            AerRegs::OFFSET_ROOT_STATUS => regs.root_status &= !val,
+            _ => log::error!("aer: write {val:#x} to read-only offset {offset:#x}"),
+        }
+        Ok(Action::None)
+    }
+}
+
+impl PciCap for AerCap {
+    fn set_next(&mut self, _val: u8) {}
+
+    fn set_next_ext(&mut self, val: u16) {
+        let mut regs = self.regs.lock();
+        let mut header = PcieExtCapHdr(regs.header);
+        header.set_next(val);
+        regs.header = header.0;
+    }
+
+    fn reset(&self) {
+        let mut regs = self.regs.lock();
+        let header = regs.header;
+        *regs = AerRegs::default();
+        regs.header = header;
+    }
+
+    fn restore(&self, data: &[u8], _memory: &mem::Memory) -> Result<()> {
+        if let Some(saved) = AerRegs::read_from_prefix(data) {
+            *self.regs.lock() = saved;
+        }
+        Ok(())
+    }
+}
+
+c_enum! {
+    pub struct PowerState(u8);
+    {
+        D0 = 0;
+        D1 = 1;
+        D2 = 2;
+        D3_HOT = 3;
+    }
+}
+
+bitflags! {
+    /// The Power Management Capabilities register.
+    #[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    pub struct PmCapFlag: u16 {
+        const VERSION_1_2 = 0b011;
+        const PME_CLOCK = 1 << 3;
+        const DSI = 1 << 5;
+        const D1 = 1 << 9;
+        const D2 = 1 << 10;
+        const PME_D0 = 1 << 11;
+        const PME_D1 = 1 << 12;
+        const PME_D2 = 1 << 13;
+        const PME_D3_HOT = 1 << 14;
+        const PME_D3_COLD = 1 << 15;
+    }
+}
+
+bitfield! {
+    /// The Power Management Control/Status register.
+    #[derive(Copy, Clone, Default, FromBytes, FromZeroes, AsBytes)]
+    #[repr(transparent)]
+    pub struct Pmcsr(u16);
+    impl Debug;
+    pub u8, power_state, set_power_state: 1, 0;
+    pub no_soft_reset, _: 3;
+    pub pme_en, set_pme_en: 8;
+    pub u8, data_select, set_data_select: 12, 9;
+    pub u8, data_scale, _: 14, 13;
+    pub pme_status, set_pme_status: 15;
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Clone, FromBytes, FromZeroes, AsBytes, Layout)]
+pub struct PmRegs {
+    pub header: PciCapHdr,
+    pub pmcap: u16,
+    pub pmcsr: Pmcsr,
+    pub pmcsr_bse: u8,
+    pub data: u8,
+}
+
+impl_mmio_for_zerocopy!(PmRegs);
+
+/// Receives power state changes written by the driver.
+pub trait PmHandler: Debug + Send + Sync + 'static {
+    fn set_power_state(&self, old: PowerState, new: PowerState);
+}
+
+/// The PCI Power Management capability.
+///
+/// Only D0 and D3hot are supported. No_Soft_Reset is clear, so a device
+/// is expected to lose its internal state in D3hot. The Data register
+/// reports 0 for every Data_Select.
+#[derive(Debug)]
+pub struct PmCap {
+    regs: Mutex<PmRegs>,
+    handler: Arc<dyn PmHandler>,
+}
+
+impl PmCap {
+    pub fn new(handler: Arc<dyn PmHandler>) -> Self {
+        PmCap {
+            regs: Mutex::new(Self::default_regs()),
+            handler,
+        }
+    }
+
+    fn default_regs() -> PmRegs {
+        PmRegs {
+            header: PciCapHdr {
+                id: PciCapId::PowerManagement as u8,
+                next: 0,
+            },
+            pmcap: (PmCapFlag::VERSION_1_2 | PmCapFlag::PME_D0 | PmCapFlag::PME_D3_HOT).bits(),
+            ..Default::default()
+        }
+    }
+
+    pub fn power_state(&self) -> PowerState {
+        PowerState::from(self.regs.lock().pmcsr.power_state())
+    }
+}
+
+impl Mmio for PmCap {
+    fn size(&self) -> u64 {
+        size_of::<PmRegs>() as u64
+    }
+
+    fn read(&self, offset: u64, size: u8) -> mem::Result<u64> {
+        Mmio::read(&*self.regs.lock(), offset, size)
+    }
+
+    fn write(&self, offset: u64, size: u8, val: u64) -> mem::Result<Action> {
+        if offset != PmRegs::OFFSET_PMCSR as u64 || size < 2 {
+            return Ok(Action::None);
+        }
+        let val = Pmcsr(val as u16);
+        let mut regs = self.regs.lock();
+        let pmcsr = &mut regs.pmcsr;
+        pmcsr.set_pme_en(val.pme_en());
+        pmcsr.set_data_select(val.data_select());
+        if val.pme_status() {
+            pmcsr.set_pme_status(false);
+        }
+        let old = PowerState::from(pmcsr.power_state());
+        let new = PowerState::from(val.power_state());
+        if !matches!(new, PowerState::D0 | PowerState::D3_HOT) {
+            log::warn!("pm: unsupported power state {new:?}");
+            return Ok(Action::None);
+        }
+        if old == new {
+            return Ok(Action::None);
+        }
+        pmcsr.set_power_state(new.raw());
+        drop(regs);
+        self.handler.set_power_state(old, new);
+        Ok(Action::None)
+    }
+}
+
+impl PciCap for PmCap {
+    fn set_next(&mut self, val: u8) {
+        self.regs.lock().header.next = val;
+    }
+
+    fn reset(&self) {
+        let mut regs = self.regs.lock();
+        let next = regs.header.next;
+        *regs = Self::default_regs();
+        regs.header.next = next;
+    }
+
+    fn restore(&self, data: &[u8], _memory: &mem::Memory) -> Result<()> {
+        let Some(saved) = PmRegs::read_from_prefix(data) else {
+            return Ok(());
+        };
+        let mut regs = self.regs.lock();
+        regs.pmcsr.set_power_state(saved.pmcsr.power_state());
+        regs.pmcsr.set_pme_en(saved.pmcsr.pme_en());
+        regs.pmcsr.set_data_select(saved.pmcsr.data_select());
+        Ok(())
+    }
+}
+
+bitflags! {
+    /// The SR-IOV Control register.
+    #[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    pub struct SrIovCtrl: u16 {
+        const VF_ENABLE = 1 << 0;
+        const VF_MIGRATION_ENABLE = 1 << 1;
+        const VF_MIGRATION_INTR_ENABLE = 1 << 2;
+        const VF_MSE = 1 << 3;
+        const ARI_CAPABLE = 1 << 4;
+    }
+}
+unsafe_impl_zerocopy!(SrIovCtrl, FromBytes, FromZeroes, AsBytes);
+
+#[repr(C)]
+#[derive(Debug, Default, Clone, FromBytes, FromZeroes, AsBytes, Layout)]
+pub struct SrIovRegs {
+    pub header: u32,
+    pub cap: u32,
+    pub control: SrIovCtrl,
+    pub status: u16,
+    pub initial_vfs: u16,
+    pub total_vfs: u16,
+    pub num_vfs: u16,
+    pub func_dep_link: u8,
+    pub reserved0: u8,
+    pub first_vf_offset: u16,
+    pub vf_stride: u16,
+    pub reserved1: u16,
+    pub vf_device_id: u16,
+    pub supported_page_sizes: u32,
+    pub system_page_size: u32,
+    pub vf_bars: [u32; 6],
+    pub vf_migration_state: u32,
+}
+
+impl_mmio_for_zerocopy!(SrIovRegs);
+
+/// Routing ID offset of the first VF from the PF. VFs take the functions
+/// right after the PF.
+pub const SRIOV_VF_OFFSET: u16 = 1;
+/// Routing ID distance between 2 consecutive VFs.
+pub const SRIOV_VF_STRIDE: u16 = 1;
+
+/// Creates and removes VFs for an SR-IOV capability.
+pub trait SrIovHandler: Debug + Send + Sync + 'static {
+    /// Replaces the current VFs with `num_vfs` new ones. `0` removes all
+    /// of them.
+    fn set_num_vfs(&self, num_vfs: u16);
+
+    /// Returns the memory region of BAR `index` of VF `vf`.
+    fn vf_bar(&self, vf: u16, index: usize) -> Option<Arc<MemRegion>>;
+}
+
+#[derive(Debug)]
+struct UpdateVfsCallback {
+    handler: Arc<dyn SrIovHandler>,
+    /// Address and size of each VF BAR of VF 0.
+    bars: [(u64, u64); 6],
+    old_vfs: u16,
+    new_vfs: u16,
+    unmap: bool,
+    map: bool,
+}
+
+impl ChangeLayout for UpdateVfsCallback {
+    fn change(&self, memory: &mem::Memory) -> mem::Result<()> {
+        let regions = |num_vfs: u16| {
+            (0..num_vfs).flat_map(|vf| {
+                self.bars
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, (_, size))| *size > 0)
+                    .map(move |(index, (addr, size))| (vf, index, addr + vf as u64 * size, *size))
+            })
+        };
+        if self.unmap {
+            for (_, _, addr, _) in regions(self.old_vfs) {
+                memory.remove_region(addr)?;
+            }
+        }
+        if self.old_vfs != self.new_vfs {
+            self.handler.set_num_vfs(self.new_vfs);
+        }
+        if self.map {
+            for (vf, index, addr, size) in regions(self.new_vfs) {
+                let Some(region) = self.handler.vf_bar(vf, index) else {
+                    continue;
+                };
+                if region.size() != size {
+                    log::error!(
+                        "sriov: VF {vf} BAR {index} has {:#x} bytes, expected {size:#x}",
+                        region.size()
+                    );
+                    continue;
+                }
+                memory.add_region(addr, region)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The PCIe Single Root I/O Virtualization capability.
+///
+/// VFs are created when VF Enable is set and removed when it is cleared.
+/// VF BAR `n` of VF `i` is placed at the address in VF BAR `n` plus `i`
+/// times the BAR size, and is mapped while VF Memory Space Enable is set.
+/// Only the 4 KiB system page size is supported.
+#[derive(Debug)]
+pub struct SrIovCap {
+    regs: Mutex<SrIovRegs>,
+    bar_masks: [u32; 6],
+    handler: Arc<dyn SrIovHandler>,
+}
+
+impl SrIovCap {
+    const CTRL_WRITABLE: SrIovCtrl = SrIovCtrl::VF_ENABLE
+        .union(SrIovCtrl::VF_MSE)
+        .union(SrIovCtrl::ARI_CAPABLE);
+
+    /// `vf_bars` has the type bits of the VF BARs, and `bar_masks` the
+    /// masks of a single VF, like those of a device header.
+    pub fn new(
+        total_vfs: u16,
+        vf_device_id: u16,
+        vf_bars: [u32; 6],
+        bar_masks: [u32; 6],
+        handler: Arc<dyn SrIovHandler>,
+    ) -> Self {
+        let regs = SrIovRegs {
+            header: PcieExtCapHdr::new(PcieExtCapId::SingleRootIoVirtualization, 1, 0).0,
+            initial_vfs: total_vfs,
+            total_vfs,
+            first_vf_offset: SRIOV_VF_OFFSET,
+            vf_stride: SRIOV_VF_STRIDE,
+            vf_device_id,
+            supported_page_sizes: 1,
+            system_page_size: 1,
+            vf_bars,
+            ..Default::default()
+        };
+        SrIovCap {
+            regs: Mutex::new(regs),
+            bar_masks,
+            handler,
+        }
+    }
+
+    fn num_vfs(regs: &SrIovRegs) -> u16 {
+        if regs.control.contains(SrIovCtrl::VF_ENABLE) {
+            regs.num_vfs
+        } else {
+            0
+        }
+    }
+
+    fn mapped(regs: &SrIovRegs) -> bool {
+        regs.control
+            .contains(SrIovCtrl::VF_ENABLE | SrIovCtrl::VF_MSE)
+    }
+
+    fn vf_bar_layout(&self, regs: &SrIovRegs) -> [(u64, u64); 6] {
+        let mut layout = [(0, 0); 6];
+        let mut index = 0;
+        while index < 6 {
+            let val = regs.vf_bars[index];
+            let mut addr = (val & !BAR_MEM_MASK) as u64;
+            let mut mask = self.bar_masks[index] as u64;
+            let bar_index = index;
+            index += 1;
+            if val & BAR_MEM64 == BAR_MEM64 && index < 6 {
+                addr |= (regs.vf_bars[index] as u64) << 32;
+                mask |= (self.bar_masks[index] as u64) << 32;
+                index += 1;
+            }
+            if mask != 0 {
+                layout[bar_index] = (addr, 1 << mask.trailing_zeros());
+            }
+        }
+        layout
+    }
+}
+
+impl Mmio for SrIovCap {
+    fn size(&self) -> u64 {
+        size_of::<SrIovRegs>() as u64
+    }
+
+    fn read(&self, offset: u64, size: u8) -> mem::Result<u64> {
+        Mmio::read(&*self.regs.lock(), offset, size)
+    }
+
+    fn write(&self, offset: u64, size: u8, val: u64) -> mem::Result<Action> {
+        let mut regs = self.regs.lock();
+        let enabled = regs.control.contains(SrIovCtrl::VF_ENABLE);
+        match (offset as usize, size as usize) {
+            SrIovRegs::LAYOUT_CONTROL => {
+                let control = SrIovCtrl::from_bits_truncate(val as u16) & Self::CTRL_WRITABLE;
+                let (old_vfs, unmap) = (Self::num_vfs(&regs), Self::mapped(&regs));
+                regs.control = control;
+                let (new_vfs, map) = (Self::num_vfs(&regs), Self::mapped(&regs));
+                if old_vfs == new_vfs && unmap == map {
+                    return Ok(Action::None);
+                }
+                log::info!("sriov: VFs: {old_vfs} -> {new_vfs}, mapped: {unmap} -> {map}");
+                let callback = UpdateVfsCallback {
+                    handler: self.handler.clone(),
+                    bars: self.vf_bar_layout(&regs),
+                    old_vfs,
+                    new_vfs,
+                    unmap,
+                    map,
+                };
+                return Ok(Action::ChangeLayout {
+                    callback: Box::new(callback),
+                });
+            }
+            SrIovRegs::LAYOUT_NUM_VFS if !enabled => {
+                regs.num_vfs = std::cmp::min(val as u16, regs.total_vfs);
+            }
+            SrIovRegs::LAYOUT_SYSTEM_PAGE_SIZE if !enabled => {
+                if val as u32 != regs.supported_page_sizes {
+                    log::error!("sriov: unsupported system page size {val:#x}");
+                }
+            }
+            (SrIovRegs::OFFSET_VF_BARS..SrIovRegs::OFFSET_VF_MIGRATION_STATE, 4) => {
+                if Self::mapped(&regs) {
+                    log::error!("sriov: write {val:#x} to VF BAR at {offset:#x} while mapped");
+                    return Ok(Action::None);
+                }
+                let index = (offset as usize - SrIovRegs::OFFSET_VF_BARS) >> 2;
+                let bar = &mut regs.vf_bars[index];
+                *bar = mask_bits!(*bar, val as u32, self.bar_masks[index]);
+            }
+            _ => log::error!("sriov: write {val:#x} to offset {offset:#x}, size {size}: ignored"),
+        }
+        Ok(Action::None)
+    }
+}
+
+impl PciCap for SrIovCap {
+    fn set_next(&mut self, _val: u8) {}
+
+    fn set_next_ext(&mut self, val: u16) {
+        let mut regs = self.regs.lock();
+        let mut header = PcieExtCapHdr(regs.header);
+        header.set_next(val);
+        regs.header = header.0;
+    }
+
+    fn reset(&self) {
+        let mut regs = self.regs.lock();
+        if Self::num_vfs(&regs) > 0 {
+            self.handler.set_num_vfs(0);
+        }
+        regs.control = SrIovCtrl::empty();
+        regs.num_vfs = 0;
+        for (bar, mask) in zip(&mut regs.vf_bars, self.bar_masks) {
+            *bar &= !mask;
+        }
+    }
+
+    /// Re-creates the VFs and maps their BARs as the snapshot describes.
+    fn restore(&self, data: &[u8], memory: &mem::Memory) -> Result<()> {
+        let Some(saved) = SrIovRegs::read_from_prefix(data) else {
+            return error::SnapshotSize {
+                size: data.len(),
+                expected: size_of::<SrIovRegs>(),
+            }
+            .fail();
+        };
+        let mut regs = self.regs.lock();
+        let (old_vfs, unmap) = (Self::num_vfs(&regs), Self::mapped(&regs));
+        let unmap = UpdateVfsCallback {
+            handler: self.handler.clone(),
+            bars: self.vf_bar_layout(&regs),
+            old_vfs,
+            new_vfs: old_vfs,
+            unmap,
+            map: false,
+        };
+        regs.control = SrIovCtrl::from_bits_truncate(saved.control.bits()) & Self::CTRL_WRITABLE;
+        regs.num_vfs = std::cmp::min(saved.num_vfs, regs.total_vfs);
+        for (index, bar) in regs.vf_bars.iter_mut().enumerate() {
+            *bar = mask_bits!(*bar, saved.vf_bars[index], self.bar_masks[index]);
+        }
+        let map = UpdateVfsCallback {
+            handler: self.handler.clone(),
+            bars: self.vf_bar_layout(&regs),
+            old_vfs,
+            new_vfs: Self::num_vfs(&regs),
+            unmap: false,
+            map: Self::mapped(&regs),
+        };
+        drop(regs);
+        unmap.change(memory)?;
+        map.change(memory)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use assert_matches::assert_matches;
+    use parking_lot::Mutex;
+
+    use crate::hv::test::{FakeMsiSender, FakeVmMemory};
+    use crate::hv::VmEntry;
+    use crate::mem::emulated::{Action, Mmio};
+    use crate::mem::{self, MemRegion, MemRegionType, Memory};
+    use crate::pci::cap::{
+        AerCap, AerCorrectable, AerRegs, AerRootStatus, AerUncorrectable, MsixTableMmio, PciCap,
+        PciCapList, RebarCap, SrIovCap, SrIovCtrl, SrIovHandler, SrIovRegs, PCIE_EXT_CAP_START,
+        REBAR_SIZE_MIN,
+    };
+    use crate::pci::config::{Command, DeviceHeader, EmulatedConfig, BAR_MEM32, OFFSET_BAR0};
+    use crate::pci::{Error, PciBar};
+
+    #[derive(Debug, Default)]
+    struct Scratch(Mutex<u64>);
+
+    impl Mmio for Scratch {
+        fn size(&self) -> u64 {
+            16 << 10
+        }
+
+        fn read(&self, _offset: u64, _size: u8) -> mem::Result<u64> {
+            Ok(*self.0.lock())
+        }
+
+        fn write(&self, _offset: u64, _size: u8, val: u64) -> mem::Result<Action> {
+            *self.0.lock() = val;
+            Ok(Action::None)
+        }
+    }
+
+    fn write_config(config: &EmulatedConfig, memory: &Memory, offset: u64, size: u8, val: u32) {
+        if let Action::ChangeLayout { callback } = config.write(offset, size, val as u64).unwrap() {
+            callback.change(memory).unwrap();
+        }
+    }
+
+    fn bar0_size(config: &EmulatedConfig, memory: &Memory) -> u32 {
+        write_config(config, memory, OFFSET_BAR0 as u64, 4, u32::MAX);
+        !config.read(OFFSET_BAR0 as u64, 4).unwrap() as u32 + 1
+    }
+
+    #[test]
+    fn test_resize_bar() {
+        let memory = Memory::new(FakeVmMemory);
+        let region = MemRegion::with_emulated(Arc::new(Scratch::default()), MemRegionType::Hidden);
+        let mut bars = [const { PciBar::Empty }; 6];
+        bars[0] = PciBar::Mem(Arc::new(region));
+        let mut bar_masks = [0; 6];
+        bar_masks[0] = !((16 << 10) - 1);
+        let header = DeviceHeader {
+            bars: [BAR_MEM32, 0, 0, 0, 0, 0],
+            ..Default::default()
+        };
+        let config = EmulatedConfig::new_device(header, bar_masks, bars, PciCapList::new());
+        assert_eq!(bar0_size(&config, &memory), 16 << 10);
+
+        let rebar = RebarCap::new(&config.header, &[(0, 512 << 20)]).unwrap();
+        config.caps.add_ext_cap(Box::new(rebar)).unwrap();
+        // the BAR is enlarged to the smallest resizable size
+        assert_eq!(bar0_size(&config, &memory), REBAR_SIZE_MIN as u32);
+
+        let read_cap = |offset| config.read(PCIE_EXT_CAP_START + offset, 4).unwrap();
+        assert_eq!(read_cap(0), 0x0001_0015);
+        // 1 MiB to 512 MiB
+        assert_eq!(read_cap(4), 0x3ff << 4);
+        // 1 resizable BAR, bar 0, 1 MiB
+        assert_eq!(read_cap(8), 1 << 5);
+
+        write_config(&config, &memory, PCIE_EXT_CAP_START + 8, 4, 9 << 8);
+        assert_eq!(read_cap(8), (1 << 5) | (9 << 8));
+        assert_eq!(bar0_size(&config, &memory), 512 << 20);
+        // unsupported size
+        write_config(&config, &memory, PCIE_EXT_CAP_START + 8, 4, 10 << 8);
+        assert_eq!(read_cap(8), (1 << 5) | (9 << 8));
+
+        write_config(&config, &memory, OFFSET_BAR0 as u64, 4, 0xc000_0000);
+        write_config(&config, &memory, 4, 2, Command::MEM.bits() as u32);
+        let entries = memory.mem_region_entries();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].0, 0xc000_0000);
+        assert_eq!(entries[0].1.size, 512 << 20);
+        memory.handle_mmio(0xc000_0010, Some(0x1234), 4).unwrap();
+        assert_matches!(
+            memory.handle_mmio(0xc000_0010, None, 4),
+            Ok(VmEntry::Mmio { data: 0x1234 })
+        );
+
+        // resizing is not allowed while memory decoding is enabled
+        write_config(&config, &memory, PCIE_EXT_CAP_START + 8, 4, 0);
+        assert_eq!(read_cap(8), (1 << 5) | (9 << 8));
+    }
+
+    #[derive(Debug, Default)]
+    struct FakeVfs(Mutex<u16>);
+
+    impl SrIovHandler for FakeVfs {
+        fn set_num_vfs(&self, num_vfs: u16) {
+            *self.0.lock() = num_vfs;
+        }
+
+        fn vf_bar(&self, _vf: u16, index: usize) -> Option<Arc<MemRegion>> {
+            let scratch = Arc::new(Scratch::default());
+            let region = MemRegion::with_emulated(scratch, MemRegionType::Hidden);
+            (index == 0).then(|| Arc::new(region))
+        }
+    }
+
+    #[test]
+    fn test_sriov_save_restore() {
+        let new_cap = |vfs| {
+            let bar_masks = [!(Scratch::default().size() as u32 - 1), 0, 0, 0, 0, 0];
+            SrIovCap::new(4, 0x1044, [BAR_MEM32; 6], bar_masks, vfs)
+        };
+        let write = |cap: &SrIovCap, memory, offset: usize, size, val| {
+            if let Action::ChangeLayout { callback } = cap.write(offset as u64, size, val).unwrap()
+            {
+                callback.change(memory).unwrap();
+            }
+        };
+        let memory = Memory::new(FakeVmMemory);
+        let cap = new_cap(Arc::new(FakeVfs::default()));
+        write(&cap, &memory, SrIovRegs::OFFSET_VF_BARS, 4, 0xc000_0000);
+        write(&cap, &memory, SrIovRegs::OFFSET_NUM_VFS, 2, 2);
+        let control = SrIovCtrl::VF_ENABLE | SrIovCtrl::VF_MSE;
+        write(
+            &cap,
+            &memory,
+            SrIovRegs::OFFSET_CONTROL,
+            2,
+            control.bits() as u64,
+        );
+        let snapshot = cap.save();
+
+        let memory = Memory::new(FakeVmMemory);
+        let vfs = Arc::new(FakeVfs::default());
+        let restored = new_cap(vfs.clone());
+        restored.restore(&snapshot, &memory).unwrap();
+        assert_eq!(restored.save(), snapshot);
+        assert_eq!(*vfs.0.lock(), 2);
+        let entries = memory.mem_region_entries();
+        let addrs: Vec<_> = entries.iter().map(|(addr, e)| (*addr, e.size)).collect();
+        assert_eq!(addrs, [(0xc000_0000, 0x4000), (0xc000_4000, 0x4000)]);
+
+        // VFs not in the snapshot are removed
+        let disabled = new_cap(Arc::new(FakeVfs::default())).save();
+        restored.restore(&disabled, &memory).unwrap();
+        assert_eq!(*vfs.0.lock(), 0);
+        assert!(memory.mem_region_entries().is_empty());
+
+        assert_matches!(
+            restored.restore(&snapshot[..8], &memory),
+            Err(Error::SnapshotSize { size: 8, .. })
+        );
+    }
+
+    #[test]
+    fn test_msix_table_resize() {
+        let table = MsixTableMmio::new(2, 4, FakeMsiSender::default());
+        assert_eq!(table.size(), 64);
+        table.write(16 + 8, 4, 0x31).unwrap();
+        // entries beyond the current length are not accessible
+        table.write(32 + 8, 4, 0x32).unwrap();
+        assert_eq!(table.read(32 + 8, 4).unwrap(), 0);
+
+        table.resize(4).unwrap();
+        assert_eq!(table.size(), 64);
+        assert_eq!(table.read(16 + 8, 4).unwrap(), 0x31);
+        // new entries are masked
+        assert_eq!(table.read(48 + 12, 4).unwrap(), 1);
+        table.write(48 + 8, 4, 0x33).unwrap();
+        assert_eq!(table.read(48 + 8, 4).unwrap(), 0x33);
+
+        table.resize(1).unwrap();
+        assert_eq!(table.entries.read().len(), 1);
+        assert_eq!(table.read(16 + 8, 4).unwrap(), 0);
+
+        assert_matches!(
+            table.resize(0),
+            Err(Error::MsixTableSize {
+                size: 0,
+                max: 4,
+                ..
+            })
+        );
+        assert_matches!(
+            table.resize(5),
+            Err(Error::MsixTableSize {
+                size: 5,
+                max: 4,
+                ..
+            })
+        );
+    }
+
+    #[test]
+    fn test_aer_cap() {
+        let aer = AerCap::new();
+        let read = |offset| aer.read(offset as u64, 4).unwrap() as u32;
+        assert_eq!(read(0), 0x0002_0001);
+        assert_eq!(read(AerRegs::OFFSET_UNCORRECTABLE_SEVERITY), 0x0046_2030);
+
+        // internal errors are masked by default
+        assert!(!aer.report_uncorrectable(AerUncorrectable::INTERNAL));
+        assert_eq!(read(AerRegs::OFFSET_UNCORRECTABLE_STATUS), 1 << 22);
+        assert_eq!(read(AerRegs::OFFSET_ROOT_STATUS), 0);
+
+        aer.write(AerRegs::OFFSET_UNCORRECTABLE_MASK as u64, 4, 0)
+            .unwrap();
+        assert!(aer.report_uncorrectable(AerUncorrectable::INTERNAL));
+        let status = AerRootStatus::UNCOR_RECEIVED
+            | AerRootStatus::FIRST_UNCOR_FATAL
+            | AerRootStatus::FATAL_RECEIVED;
+        assert_eq!(read(AerRegs::OFFSET_ROOT_STATUS), status.bits());
+        assert_eq!(read(AerRegs::OFFSET_CAP_CONTROL), 22);
+
+        assert!(aer.report_correctable(AerCorrectable::BAD_TLP));
+        assert!(!aer.report_correctable(AerCorrectable::ADVISORY_NON_FATAL));
+        assert_eq!(
+            read(AerRegs::OFFSET_CORRECTABLE_STATUS),
+            (1 << 6) | (1 << 13)
+        );
+
+        // status registers are write-1-to-clear
+        for offset in [
+            AerRegs::OFFSET_UNCORRECTABLE_STATUS,
+            AerRegs::OFFSET_CORRECTABLE_STATUS,
+            AerRegs::OFFSET_ROOT_STATUS,
+        ] {
+            let val = read(offset);
+            let low = val & val.wrapping_neg();
+            aer.write(offset as u64, 4, low as u64).unwrap();
+            assert_eq!(read(offset), val & !low);
+            aer.write(offset as u64, 4, u32::MAX as u64).unwrap();
+            assert_eq!(read(offset), 0);
+        }
+
+        // the header is read-only
+        aer.write(0, 4, 0).unwrap();
+        assert_eq!(read(0), 0x0002_0001);
+
+        aer.write(AerRegs::OFFSET_CORRECTABLE_MASK as u64, 4, 0)
+            .unwrap();
+        aer.reset();
+        assert_eq!(read(AerRegs::OFFSET_CORRECTABLE_MASK), 0x6000);
+    }
+}
@@ -19,11 +19,12 @@ use std::sync::Arc;
 use bitflags::bitflags;
 use macros::Layout;
 use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
 use zerocopy::{AsBytes, FromBytes, FromZeroes};
 
 use crate::mem::emulated::{Action, ChangeLayout, Mmio};
 use crate::pci::cap::PciCapList;
-use crate::pci::{Bdf, PciBar};
+use crate::pci::{error, Bdf, PciBar, Result};
 use crate::{assign_bits, mask_bits, mem, unsafe_impl_zerocopy};
 
 bitflags! {
@@ -343,7 +344,7 @@ impl HeaderData {
 #[derive(Debug)]
 pub struct EmulatedHeader {
     pub data: Arc<RwLock<HeaderData>>,
-    pub bars: [PciBar; 6],
+    pub bars: Arc<RwLock<[PciBar; 6]>>,
 }
 
 impl EmulatedHeader {
@@ -360,6 +361,30 @@ impl EmulatedHeader {
         let mut header = self.data.write();
         header.set_command(Command::empty());
     }
+
+    /// Removes the BARs enabled by the command register from `memory` and
+    /// disables memory and IO decoding.
+    pub fn unmap_bars(&self, memory: &mem::Memory) -> mem::Result<()> {
+        let enabled = Command::MEM | Command::IO;
+        let (command, bars) = match &self.data.read().header {
+            ConfigHeader::Device(header) => (header.common.command & enabled, header.bars),
+        };
+        if command.is_empty() {
+            return Ok(());
+        }
+        let unmap = UpdateCommandCallback {
+            pci_bars: self.bars.read().clone(),
+            bars,
+            changed: command,
+            current: Command::empty(),
+        };
+        unmap.change(memory)?;
+        let mut header = self.data.write();
+        match &mut header.header {
+            ConfigHeader::Device(header) => header.common.command &= !enabled,
+        }
+        Ok(())
+    }
 }
 
 impl Mmio for EmulatedHeader {
@@ -385,7 +410,7 @@ impl Mmio for EmulatedHeader {
 
     fn write(&self, offset: u64, size: u8, val: u64) -> mem::Result<Action> {
         let mut data = self.data.write();
-        if let Some(callback) = data.write_header(offset, size, val, &self.bars) {
+        if let Some(callback) = data.write_header(offset, size, val, &self.bars.read()) {
             Ok(Action::ChangeLayout { callback })
         } else {
             Ok(Action::None)
@@ -398,6 +423,14 @@ pub trait PciConfig: Mmio {
     fn reset(&self);
 }
 
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PciConfigSnapshot {
+    /// The 64-byte device header, including the command register and BARs.
+    pub header: Vec<u8>,
+    /// Contents of each capability, in the order of the capability list.
+    pub caps: Vec<Vec<u8>>,
+}
+
 #[derive(Debug)]
 pub struct EmulatedConfig {
     pub header: EmulatedHeader,
@@ -443,10 +476,63 @@ impl EmulatedConfig {
                 bar_masks,
                 bdf: Bdf(0),
             })),
-            bars,
+            bars: Arc::new(RwLock::new(bars)),
         };
         EmulatedConfig { header, caps }
     }
+
+    pub fn save(&self) -> PciConfigSnapshot {
+        let data = self.header.data.read();
+        let header = match &data.header {
+            ConfigHeader::Device(header) => header.as_bytes().to_vec(),
+        };
+        PciConfigSnapshot {
+            header,
+            caps: self.caps.save(),
+        }
+    }
+
+    pub fn restore(&self, snapshot: PciConfigSnapshot, memory: &mem::Memory) -> Result<()> {
+        let Some(new_header) = DeviceHeader::read_from(snapshot.header.as_slice()) else {
+            return error::SnapshotSize {
+                size: snapshot.header.len(),
+                expected: size_of::<DeviceHeader>(),
+            }
+            .fail();
+        };
+        let enabled = Command::MEM | Command::IO;
+        let old_header = match &self.header.data.read().header {
+            ConfigHeader::Device(header) => header.clone(),
+        };
+        let old_command = old_header.common.command & enabled;
+        if !old_command.is_empty() {
+            let unmap = UpdateCommandCallback {
+                pci_bars: self.header.bars.read().clone(),
+                bars: old_header.bars,
+                changed: old_command,
+                current: Command::empty(),
+            };
+            unmap.change(memory)?;
+        }
+        let new_command = new_header.common.command & enabled;
+        let new_bars = new_header.bars;
+        match &mut self.header.data.write().header {
+            ConfigHeader::Device(header) => *header = new_header,
+        }
+        // BAR callbacks lock the header, so the header lock must have been
+        // released before the BARs are mapped.
+        if !new_command.is_empty() {
+            let map = UpdateCommandCallback {
+                pci_bars: self.header.bars.read().clone(),
+                bars: new_bars,
+                changed: new_command,
+                current: new_command,
+            };
+            map.change(memory)?;
+        }
+        self.caps.restore(&snapshot.caps, memory)?;
+        Ok(())
+    }
 }
 
 impl PciConfig for EmulatedConfig {
@@ -459,3 +545,156 @@ impl PciConfig for EmulatedConfig {
         self.caps.reset();
     }
 }
+
+/// The config space of an SR-IOV virtual function.
+///
+/// The vendor and device IDs of a VF are in the SR-IOV capability of its PF,
+/// and so are the BARs and the memory space enable bit. These registers of
+/// `config` read as all-ones, zeros, and 0 respectively, and cannot be
+/// written.
+#[derive(Debug)]
+pub struct VfConfig {
+    config: Arc<dyn PciConfig>,
+}
+
+impl VfConfig {
+    pub fn new(config: Arc<dyn PciConfig>) -> Self {
+        VfConfig { config }
+    }
+
+    fn mask_byte(offset: usize, byte: u8) -> u8 {
+        match offset {
+            0..=3 => 0xff,
+            CommonHeader::OFFSET_COMMAND => byte & !(Command::MEM | Command::IO).bits() as u8,
+            OFFSET_BAR0..=OFFSET_BAR5_END => 0,
+            _ => byte,
+        }
+    }
+}
+
+const OFFSET_BAR5_END: usize = OFFSET_BAR5 + size_of::<u32>() - 1;
+
+impl Mmio for VfConfig {
+    fn size(&self) -> u64 {
+        self.config.size()
+    }
+
+    fn read(&self, offset: u64, size: u8) -> mem::Result<u64> {
+        let mut bytes = self.config.read(offset, size)?.to_le_bytes();
+        for (index, byte) in bytes.iter_mut().take(size as usize).enumerate() {
+            *byte = Self::mask_byte(offset as usize + index, *byte);
+        }
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    fn write(&self, offset: u64, size: u8, val: u64) -> mem::Result<Action> {
+        let mut val = val;
+        for index in 0..size as usize {
+            match offset as usize + index {
+                OFFSET_BAR0..=OFFSET_BAR5_END => return Ok(Action::None),
+                CommonHeader::OFFSET_COMMAND => {
+                    val &= !(((Command::MEM | Command::IO).bits() as u64) << (8 * index))
+                }
+                _ => {}
+            }
+        }
+        self.config.write(offset, size, val)
+    }
+}
+
+impl PciConfig for VfConfig {
+    fn get_header(&self) -> &EmulatedHeader {
+        self.config.get_header()
+    }
+
+    fn reset(&self) {
+        self.config.reset()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use assert_matches::assert_matches;
+
+    use crate::hv::test::FakeVmMemory;
+    use crate::mem::emulated::Mmio;
+    use crate::mem::mapped::ArcMemPages;
+    use crate::mem::{MemRegion, MemRegionType, Memory};
+    use crate::pci::cap::PciCapList;
+    use crate::pci::config::{
+        Command, DeviceHeader, EmulatedConfig, BAR_MEM32, BAR_MEM_MASK, OFFSET_BAR0,
+    };
+    use crate::pci::{Error, PciBar};
+
+    const BAR_SIZE: u64 = 0x1000;
+
+    fn new_config() -> EmulatedConfig {
+        let pages = ArcMemPages::from_anonymous(BAR_SIZE as usize, None).unwrap();
+        let region = Arc::new(MemRegion::with_mapped(pages, MemRegionType::Hidden));
+        let mut bars = [const { PciBar::Empty }; 6];
+        bars[0] = PciBar::Mem(region);
+        let mut bar_masks = [0; 6];
+        bar_masks[0] = !(BAR_SIZE as u32 - 1) | BAR_MEM_MASK;
+        let header = DeviceHeader {
+            bars: [BAR_MEM32, 0, 0, 0, 0, 0],
+            ..Default::default()
+        };
+        EmulatedConfig::new_device(header, bar_masks, bars, PciCapList::new())
+    }
+
+    #[test]
+    fn test_save_restore() {
+        let memory = Memory::new(FakeVmMemory);
+        let config = new_config();
+        config.write(OFFSET_BAR0 as u64, 4, 0xc000_0000).unwrap();
+        config
+            .header
+            .set_command(Command::MEM | Command::BUS_MASTER);
+        let snapshot = config.save();
+        assert_eq!(snapshot.header.len(), 64);
+
+        let restored = new_config();
+        restored.restore(snapshot.clone(), &memory).unwrap();
+        assert_eq!(restored.save(), snapshot);
+        assert_eq!(restored.read(OFFSET_BAR0 as u64, 4).unwrap(), 0xc000_0000);
+        let entries = memory.mem_region_entries();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].0, 0xc000_0000);
+        assert_eq!(entries[0].1.size, BAR_SIZE);
+
+        let disabled = new_config().save();
+        restored.restore(disabled, &memory).unwrap();
+        assert!(memory.mem_region_entries().is_empty());
+
+        // every capability must be restored
+        let mut extra_cap = snapshot;
+        extra_cap.caps.push(vec![0; 8]);
+        assert_matches!(
+            restored.restore(extra_cap, &memory),
+            Err(Error::SnapshotCapCount {
+                count: 1,
+                expected: 0,
+                ..
+            })
+        );
+    }
+    #[test]
+    fn test_unmap_bars() {
+        let memory = Memory::new(FakeVmMemory);
+        let enabled = new_config();
+        enabled.write(OFFSET_BAR0 as u64, 4, 0xc000_0000).unwrap();
+        enabled.header.set_command(Command::MEM);
+        let config = new_config();
+        config.restore(enabled.save(), &memory).unwrap();
+        assert_eq!(memory.mem_region_entries().len(), 1);
+
+        config.header.unmap_bars(&memory).unwrap();
+        assert!(memory.mem_region_entries().is_empty());
+        // the command register
+        assert_eq!(config.read(4, 2).unwrap(), 0);
+        // a second call is a no-op
+        config.header.unmap_bars(&memory).unwrap();
+    }
+}
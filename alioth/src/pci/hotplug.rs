@@ -0,0 +1,197 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+
+use crate::hv::IrqSender;
+use crate::mem::emulated::{Action, Mmio};
+use crate::mem::{self, Memory};
+use crate::pci::segment::PciSegment;
+use crate::pci::{error, Bdf, PciDevice, Result};
+
+pub const PCI_HOTPLUG_PORT: u16 = 0xae00;
+pub const PCI_HOTPLUG_SLOTS: u16 = 32;
+
+const REG_UP: u64 = 0x0;
+const REG_DOWN: u64 = 0x4;
+const REG_EJECT: u64 = 0x8;
+
+/// Slot status registers of bus 0, in the layout of QEMU's ACPI PCI hotplug
+/// controller.
+///
+/// - `0x0`: slots with a device inserted since the last read,
+/// - `0x4`: slots with a device removed since the last read,
+/// - `0x8`: slots the guest has ejected, write only.
+#[derive(Debug)]
+pub struct HotplugSlots {
+    up: AtomicU32,
+    down: AtomicU32,
+    irq_sender: Option<Box<dyn IrqSender>>,
+}
+
+impl HotplugSlots {
+    fn notify(&self) -> Result<()> {
+        if let Some(irq_sender) = &self.irq_sender {
+            irq_sender.send()?;
+        }
+        Ok(())
+    }
+}
+
+impl Mmio for HotplugSlots {
+    fn size(&self) -> u64 {
+        12
+    }
+
+    fn read(&self, offset: u64, _size: u8) -> mem::Result<u64> {
+        let val = match offset {
+            REG_UP => self.up.swap(0, Ordering::AcqRel),
+            REG_DOWN => self.down.swap(0, Ordering::AcqRel),
+            _ => 0,
+        };
+        Ok(val as u64)
+    }
+
+    fn write(&self, offset: u64, _size: u8, val: u64) -> mem::Result<Action> {
+        match offset {
+            REG_EJECT => log::info!("pci hotplug: guest ejected slots {val:#x}"),
+            _ => log::error!("pci hotplug: write {val:#x} to unknown offset {offset:#x}"),
+        }
+        Ok(Action::None)
+    }
+}
+
+/// Attaches and detaches PCI devices on bus 0 while the VM is running.
+#[derive(Debug)]
+pub struct VmBus {
+    segment: Arc<PciSegment>,
+    pub slots: Arc<HotplugSlots>,
+}
+
+impl VmBus {
+    pub fn new(segment: Arc<PciSegment>, irq_sender: Option<Box<dyn IrqSender>>) -> Self {
+        VmBus {
+            segment,
+            slots: Arc::new(HotplugSlots {
+                up: AtomicU32::new(0),
+                down: AtomicU32::new(0),
+                irq_sender,
+            }),
+        }
+    }
+
+    /// Places `dev` in the first free slot of bus 0 and notifies the guest.
+    pub fn attach(&self, dev: PciDevice) -> Result<Bdf> {
+        let mut devices = self.segment.devices.write();
+        let Some(bdf) = (0..PCI_HOTPLUG_SLOTS)
+            .map(|slot| Bdf(slot << 3))
+            .find(|bdf| !devices.contains_key(bdf))
+        else {
+            return error::NoFreeSlot.fail();
+        };
+        dev.dev.config().get_header().set_bdf(bdf);
+        log::info!("{bdf}: attached device: {}", dev.name);
+        devices.insert(bdf, dev);
+        drop(devices);
+
+        self.slots.up.fetch_or(1 << bdf.dev(), Ordering::AcqRel);
+        self.slots.notify()?;
+        Ok(bdf)
+    }
+
+    /// Removes the device at `bdf`, unmaps its BARs from `memory`, and
+    /// notifies the guest.
+    ///
+    /// For a virtio device, dropping the last reference to it shuts down its
+    /// worker thread.
+    pub fn detach(&self, bdf: Bdf, memory: &Memory) -> Result<()> {
+        let Some(dev) = self.segment.devices.write().remove(&bdf) else {
+            return error::NotAttached { bdf }.fail();
+        };
+        dev.dev.config().get_header().unmap_bars(memory)?;
+        log::info!("{bdf}: detached device: {}", dev.name);
+        drop(dev);
+
+        self.slots.down.fetch_or(1 << bdf.dev(), Ordering::AcqRel);
+        self.slots.notify()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Arc;
+
+    use assert_matches::assert_matches;
+    use parking_lot::{Mutex, RwLock};
+
+    use crate::hv::test::FakeVmMemory;
+    use crate::hv::{IrqSender, Result};
+    use crate::mem::emulated::Mmio;
+    use crate::mem::Memory;
+    use crate::pci::host_bridge::HostBridge;
+    use crate::pci::hotplug::{VmBus, REG_DOWN, REG_UP};
+    use crate::pci::segment::PciSegment;
+    use crate::pci::{Bdf, Error, PciDevice};
+
+    #[derive(Debug, Default)]
+    struct CountIrq(Arc<AtomicU32>);
+
+    impl IrqSender for CountIrq {
+        fn send(&self) -> Result<()> {
+            self.0.fetch_add(1, Ordering::Relaxed);
+            Ok(())
+        }
+    }
+
+    fn new_dev(name: &str) -> PciDevice {
+        PciDevice::new(Arc::new(name.to_owned()), Arc::new(HostBridge::new()))
+    }
+
+    #[test]
+    fn test_attach_detach() {
+        let segment = Arc::new(PciSegment {
+            devices: RwLock::new(HashMap::from([(Bdf(0), new_dev("host_bridge"))])),
+            next_bdf: Mutex::new(8),
+        });
+        let count = Arc::new(AtomicU32::new(0));
+        let irq_sender = CountIrq(count.clone());
+        let bus = VmBus::new(segment.clone(), Some(Box::new(irq_sender)));
+        let memory = Memory::new(FakeVmMemory);
+
+        assert_eq!(bus.attach(new_dev("dev1")).unwrap(), Bdf(1 << 3));
+        assert_eq!(bus.attach(new_dev("dev2")).unwrap(), Bdf(2 << 3));
+        assert_eq!(count.load(Ordering::Relaxed), 2);
+        assert_eq!(bus.slots.read(REG_UP, 4).unwrap(), 0b110);
+        assert_eq!(bus.slots.read(REG_UP, 4).unwrap(), 0);
+
+        bus.detach(Bdf(1 << 3), &memory).unwrap();
+        assert_eq!(count.load(Ordering::Relaxed), 3);
+        assert_eq!(bus.slots.read(REG_DOWN, 4).unwrap(), 0b10);
+        assert!(!segment.devices.read().contains_key(&Bdf(1 << 3)));
+        assert_matches!(
+            bus.detach(Bdf(1 << 3), &memory),
+            Err(Error::NotAttached { .. })
+        );
+
+        // the freed slot is reused
+        assert_eq!(bus.attach(new_dev("dev3")).unwrap(), Bdf(1 << 3));
+        for _ in 3..32 {
+            bus.attach(new_dev("dev")).unwrap();
+        }
+        assert_matches!(bus.attach(new_dev("dev")), Err(Error::NoFreeSlot { .. }));
+    }
+}
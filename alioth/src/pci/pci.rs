@@ -27,6 +27,7 @@ pub mod bus;
 pub mod cap;
 pub mod config;
 pub mod host_bridge;
+pub mod hotplug;
 pub mod segment;
 
 use config::{HeaderData, PciConfig, BAR_MEM64};
@@ -52,6 +53,28 @@ impl Display for Bdf {
 pub enum Error {
     #[snafu(display("Failed to access guest memory"), context(false))]
     Memory { source: Box<crate::mem::Error> },
+    #[snafu(display("Snapshot of PCI config header has {size} bytes, expected {expected}"))]
+    SnapshotSize { size: usize, expected: usize },
+    #[snafu(display("Snapshot has {count} capabilities, expected {expected}"))]
+    SnapshotCapCount { count: usize, expected: usize },
+    #[snafu(display("No free slot for hotplug"))]
+    NoFreeSlot,
+    #[snafu(display("PCI slot {slot} is occupied"))]
+    SlotOccupied { slot: u8 },
+    #[snafu(display("PCI slot {slot} does not exist"))]
+    InvalidSlot { slot: u8 },
+    #[snafu(display("All slots on PCI bus 0 are occupied"))]
+    BusFull,
+    #[snafu(display("No device is attached at {bdf}"))]
+    NotAttached { bdf: Bdf },
+    #[snafu(display("BAR {index} is not a memory BAR"))]
+    InvalidBar { index: u8 },
+    #[snafu(display("BAR {index} cannot be resized to {size:#x} bytes"))]
+    BarSize { index: u8, size: u64 },
+    #[snafu(display("MSI-X table cannot be resized to {size} entries, the maximum is {max}"))]
+    MsixTableSize { size: usize, max: usize },
+    #[snafu(display("Failed to send hotplug interrupt"), context(false))]
+    HvError { source: Box<crate::hv::Error> },
 }
 
 pub type Result<T, E = Error> = std::result::Result<T, E>;
@@ -95,7 +118,7 @@ pub struct PciDevice {
 impl PciDevice {
     pub fn new(name: Arc<String>, dev: Arc<dyn Pci>) -> PciDevice {
         let config = dev.config();
-        let dev_bars = &config.get_header().bars;
+        let dev_bars = config.get_header().bars.read();
         for (index, dev_bar) in dev_bars.iter().enumerate() {
             let header = config.get_header().data.clone();
             match dev_bar {
@@ -20,7 +20,10 @@ use parking_lot::{Mutex, RwLock};
 use crate::mem;
 use crate::mem::emulated::{Action, Mmio};
 use crate::pci::config::PciConfig;
-use crate::pci::{Bdf, Pci, PciDevice, Result};
+use crate::pci::{error, Bdf, Pci, PciDevice, Result};
+
+/// Number of device slots on a PCI bus.
+pub const NUM_SLOTS: u8 = 32;
 
 #[derive(Debug)]
 struct EmptyDevice;
@@ -90,6 +93,28 @@ impl PciSegment {
         let mut configs = self.devices.write();
         Self::add_dev(&mut configs, bdf, config)
     }
+
+    pub fn remove(&self, bdf: Bdf) -> Option<PciDevice> {
+        self.devices.write().remove(&bdf)
+    }
+
+    /// Adds `dev` as function 0 of `slot` on bus 0, or of the first free
+    /// slot if `slot` is `None`. Returns the slot number.
+    pub fn add_to_slot(&self, slot: Option<u8>, dev: PciDevice) -> Result<u8> {
+        let mut configs = self.devices.write();
+        let is_free = |slot: u8| !configs.contains_key(&Bdf((slot as u16) << 3));
+        let slot = match slot {
+            Some(slot) if slot >= NUM_SLOTS => return error::InvalidSlot { slot }.fail(),
+            Some(slot) if !is_free(slot) => return error::SlotOccupied { slot }.fail(),
+            Some(slot) => slot,
+            None => match (0..NUM_SLOTS).find(|s| is_free(*s)) {
+                Some(slot) => slot,
+                None => return error::BusFull.fail(),
+            },
+        };
+        configs.insert(Bdf((slot as u16) << 3), dev);
+        Ok(slot)
+    }
 }
 
 impl Mmio for PciSegment {
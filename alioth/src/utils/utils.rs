@@ -50,6 +50,26 @@ where
     data.into_iter().fold(0u8, |accu, e| accu.wrapping_add(*e))
 }
 
+/// Linux keeps at most 15 bytes of a thread name, see `PR_SET_NAME`.
+const THREAD_NAME_MAX: usize = 15;
+
+/// Shortens `name` to fit in a Linux thread name.
+///
+/// `virtio-` is abbreviated as `v`, and if the name is still too long, the
+/// beginning is dropped since the suffix, e.g. the device index, tells
+/// threads apart.
+pub fn thread_name(name: &str) -> String {
+    let name = match name.strip_prefix("virtio-") {
+        Some(rest) => format!("v{rest}"),
+        None => name.to_owned(),
+    };
+    let mut start = name.len().saturating_sub(THREAD_NAME_MAX);
+    while !name.is_char_boundary(start) {
+        start += 1;
+    }
+    name[start..].to_owned()
+}
+
 pub fn get_low32(num: u64) -> u32 {
     num as u32
 }
@@ -76,16 +96,22 @@ pub fn set_high32(num: &mut u64, val: u32) {
     *num |= (val as u64) << 32;
 }
 
+// The other half is kept by compare-exchange, so concurrent writes to the
+// two halves never overwrite each other.
+fn update_atomic(num: &AtomicU64, f: impl Fn(&mut u64)) {
+    let update = |mut cur| {
+        f(&mut cur);
+        Some(cur)
+    };
+    let _ = num.fetch_update(Ordering::AcqRel, Ordering::Acquire, update);
+}
+
 pub fn set_atomic_low32(num: &AtomicU64, val: u32) {
-    let mut cur = num.load(Ordering::Acquire);
-    set_low32(&mut cur, val);
-    num.store(cur, Ordering::Release)
+    update_atomic(num, |cur| set_low32(cur, val))
 }
 
 pub fn set_atomic_high32(num: &AtomicU64, val: u32) {
-    let mut cur = num.load(Ordering::Acquire);
-    set_high32(&mut cur, val);
-    num.store(cur, Ordering::Release)
+    update_atomic(num, |cur| set_high32(cur, val))
 }
 
 #[macro_export]
@@ -179,6 +205,76 @@ macro_rules! unsafe_impl_zerocopy {
 
 #[cfg(test)]
 mod test {
+    use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+    use std::sync::Barrier;
+
+    use super::{get_high32, get_low32, set_atomic_high32, set_atomic_low32, thread_name};
+
+    #[test]
+    fn test_set_atomic_halves() {
+        const N: u32 = 100_000;
+        let num = AtomicU64::new(0);
+        let done = AtomicBool::new(false);
+        let start = Barrier::new(2);
+        std::thread::scope(|s| {
+            // Each half only grows, so a lost update shows up as a half
+            // going backwards.
+            let readers: Vec<_> = (0..2)
+                .map(|_| {
+                    s.spawn(|| {
+                        let (mut low, mut high) = (0, 0);
+                        while !done.load(Ordering::Acquire) {
+                            let cur = num.load(Ordering::Acquire);
+                            assert!(get_low32(cur) >= low, "{cur:#x}");
+                            assert!(get_high32(cur) >= high, "{cur:#x}");
+                            (low, high) = (get_low32(cur), get_high32(cur));
+                        }
+                    })
+                })
+                .collect();
+            let low = s.spawn(|| {
+                start.wait();
+                (1..=N).for_each(|v| set_atomic_low32(&num, v))
+            });
+            let high = s.spawn(|| {
+                start.wait();
+                (1..=N).for_each(|v| set_atomic_high32(&num, v))
+            });
+            low.join().unwrap();
+            high.join().unwrap();
+            done.store(true, Ordering::Release);
+            readers.into_iter().for_each(|r| r.join().unwrap());
+        });
+        assert_eq!(num.load(Ordering::Acquire), (N as u64) << 32 | N as u64);
+    }
+
+    #[test]
+    fn test_thread_name() {
+        assert_eq!(thread_name("vcpu-0"), "vcpu-0");
+        assert_eq!(thread_name("virtio-blk-0"), "vblk-0");
+        assert_eq!(thread_name("virtio-blk-device-0"), "vblk-device-0");
+        assert_eq!(thread_name("virtio-blk-0-watchdog"), "vblk-0-watchdog");
+        assert_eq!(thread_name("ivshmem-device-12"), "shmem-device-12");
+        assert_eq!(thread_name("virtio-ßßßßßßßß"), "ßßßßßßß");
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_thread_name_comm() {
+        let name = thread_name("virtio-entropy-device-3");
+        let comm = std::thread::Builder::new()
+            .name(name.clone())
+            .spawn(|| {
+                let tid = unsafe { libc::gettid() };
+                std::fs::read_to_string(format!("/proc/self/task/{tid}/comm")).unwrap()
+            })
+            .unwrap()
+            .join()
+            .unwrap();
+        assert_eq!(comm.trim_end(), name);
+        assert_eq!(name, "ntropy-device-3");
+    }
+
     #[test]
     fn test_align_up() {
         assert_eq!(align_up!(0u64, 4), 0);
@@ -0,0 +1,109 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use bitflags::bitflags;
+
+use crate::c_enum;
+
+pub const VFIO_TYPE: u8 = b';';
+pub const VFIO_API_VERSION: i32 = 0;
+
+c_enum! {
+    pub struct VfioIommu(i32);
+    {
+        TYPE1 = 1;
+        SPAPR_TCE = 2;
+        TYPE1_V2 = 3;
+        DMA_CC = 4;
+        NOIOMMU = 8;
+    }
+}
+
+bitflags! {
+    #[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+    pub struct VfioGroupFlag: u32 {
+        const VIABLE = 1 << 0;
+        const CONTAINER_SET = 1 << 1;
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Default)]
+pub struct VfioGroupStatus {
+    pub argsz: u32,
+    pub flags: VfioGroupFlag,
+}
+
+bitflags! {
+    #[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+    pub struct VfioDeviceFlag: u32 {
+        const RESET = 1 << 0;
+        const PCI = 1 << 1;
+        const PLATFORM = 1 << 2;
+        const AMBA = 1 << 3;
+        const CCW = 1 << 4;
+        const AP = 1 << 5;
+        const FSL_MC = 1 << 6;
+        const CAPS = 1 << 7;
+        const CDX = 1 << 8;
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Default)]
+pub struct VfioDeviceInfo {
+    pub argsz: u32,
+    pub flags: VfioDeviceFlag,
+    pub num_regions: u32,
+    pub num_irqs: u32,
+    pub cap_offset: u32,
+    pub pad: u32,
+}
+
+bitflags! {
+    #[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+    pub struct VfioDmaMapFlag: u32 {
+        const READ = 1 << 0;
+        const WRITE = 1 << 1;
+        const VADDR = 1 << 2;
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Default)]
+pub struct VfioIommuType1DmaMap {
+    pub argsz: u32,
+    pub flags: VfioDmaMapFlag,
+    pub vaddr: u64,
+    pub iova: u64,
+    pub size: u64,
+}
+
+bitflags! {
+    #[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+    pub struct VfioDmaUnmapFlag: u32 {
+        const GET_DIRTY_BITMAP = 1 << 0;
+        const ALL = 1 << 1;
+        const VADDR = 1 << 2;
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Default)]
+pub struct VfioIommuType1DmaUnmap {
+    pub argsz: u32,
+    pub flags: VfioDmaUnmapFlag,
+    pub iova: u64,
+    pub size: u64,
+}
@@ -0,0 +1,176 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd};
+use std::path::Path;
+
+use parking_lot::Mutex;
+use snafu::ResultExt;
+
+use crate::mem::mapped::RamBus;
+use crate::vfio::bindings::{
+    VfioDmaMapFlag, VfioDmaUnmapFlag, VfioIommu, VfioIommuType1DmaMap, VfioIommuType1DmaUnmap,
+    VFIO_API_VERSION,
+};
+use crate::vfio::ioctls::{
+    vfio_check_extension, vfio_get_api_version, vfio_iommu_map_dma, vfio_iommu_unmap_dma,
+    vfio_set_iommu,
+};
+use crate::vfio::{error, Result};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DmaMapping {
+    pub vaddr: usize,
+    pub size: u64,
+}
+
+#[derive(Debug, Default)]
+struct ContainerState {
+    iommu: Option<VfioIommu>,
+    /// IOVA -> host virtual address mappings programmed into the IOMMU.
+    dma_maps: BTreeMap<u64, DmaMapping>,
+}
+
+/// A VFIO container backed by `/dev/vfio/vfio`, holding the IOMMU context
+/// shared by all groups attached to it.
+#[derive(Debug)]
+pub struct VfioContainer {
+    fd: File,
+    state: Mutex<ContainerState>,
+}
+
+impl VfioContainer {
+    pub fn new() -> Result<Self> {
+        let path = Path::new("/dev/vfio/vfio");
+        let fd = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)
+            .context(error::AccessDevice { path })?;
+        let version = unsafe { vfio_get_api_version(&fd) }.context(error::AccessDevice { path })?;
+        if version != VFIO_API_VERSION {
+            return error::ApiVersion { version }.fail();
+        }
+        let ret = unsafe { vfio_check_extension(&fd, VfioIommu::TYPE1_V2) };
+        if !matches!(ret, Ok(1)) {
+            return error::IommuNotSupported.fail();
+        }
+        Ok(VfioContainer {
+            fd,
+            state: Mutex::new(ContainerState::default()),
+        })
+    }
+
+    /// Sets the IOMMU model of the container. The kernel accepts it only
+    /// after at least one group is attached.
+    pub(super) fn set_iommu(&self) -> Result<()> {
+        let mut state = self.state.lock();
+        if state.iommu.is_some() {
+            return Ok(());
+        }
+        let iommu = VfioIommu::TYPE1_V2;
+        unsafe { vfio_set_iommu(&self.fd, iommu) }.context(error::SetIommu)?;
+        state.iommu = Some(iommu);
+        Ok(())
+    }
+
+    /// Maps `size` bytes of host memory at `vaddr` to `iova` in the IOMMU.
+    pub fn map_dma(&self, iova: u64, vaddr: usize, size: u64) -> Result<()> {
+        let mut state = self.state.lock();
+        if let Some((start, m)) = state.dma_maps.range(..iova.saturating_add(size)).last() {
+            if start + m.size > iova {
+                return error::DmaOverlap { iova, size }.fail();
+            }
+        }
+        let dma_map = VfioIommuType1DmaMap {
+            argsz: size_of::<VfioIommuType1DmaMap>() as u32,
+            flags: VfioDmaMapFlag::READ | VfioDmaMapFlag::WRITE,
+            vaddr: vaddr as u64,
+            iova,
+            size,
+        };
+        unsafe { vfio_iommu_map_dma(&self.fd, &dma_map) }.context(error::MapDma { iova, size })?;
+        log::trace!("vfio: mapped {vaddr:#x} -> iova {iova:#x}, size = {size:#x}");
+        state.dma_maps.insert(iova, DmaMapping { vaddr, size });
+        Ok(())
+    }
+
+    fn unmap_dma_locked(&self, state: &mut ContainerState, iova: u64) -> Result<()> {
+        let Some(m) = state.dma_maps.get(&iova) else {
+            return Ok(());
+        };
+        let size = m.size;
+        let mut dma_unmap = VfioIommuType1DmaUnmap {
+            argsz: size_of::<VfioIommuType1DmaUnmap>() as u32,
+            flags: VfioDmaUnmapFlag::empty(),
+            iova,
+            size,
+        };
+        unsafe { vfio_iommu_unmap_dma(&self.fd, &mut dma_unmap) }
+            .context(error::UnmapDma { iova, size })?;
+        state.dma_maps.remove(&iova);
+        log::trace!("vfio: unmapped iova {iova:#x}, size = {size:#x}");
+        Ok(())
+    }
+
+    pub fn unmap_dma(&self, iova: u64) -> Result<()> {
+        let mut state = self.state.lock();
+        self.unmap_dma_locked(&mut state, iova)
+    }
+
+    /// Maps all guest RAM, using guest physical addresses as IOVAs.
+    pub fn map_ram(&self, ram: &RamBus) -> Result<()> {
+        let layout = ram.lock_layout();
+        for (gpa, slot) in layout.iter() {
+            self.map_dma(gpa, slot.pages.addr(), slot.pages.size())?;
+        }
+        Ok(())
+    }
+
+    /// Removes all DMA mappings, e.g. before guest RAM is released.
+    pub fn unmap_all(&self) -> Result<()> {
+        let mut state = self.state.lock();
+        while let Some((&iova, _)) = state.dma_maps.first_key_value() {
+            self.unmap_dma_locked(&mut state, iova)?;
+        }
+        Ok(())
+    }
+
+    pub fn dma_maps(&self) -> Vec<(u64, DmaMapping)> {
+        let state = self.state.lock();
+        state.dma_maps.iter().map(|(k, v)| (*k, *v)).collect()
+    }
+}
+
+impl AsFd for VfioContainer {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.fd.as_fd()
+    }
+}
+
+impl AsRawFd for VfioContainer {
+    fn as_raw_fd(&self) -> i32 {
+        self.fd.as_raw_fd()
+    }
+}
+
+impl Drop for VfioContainer {
+    fn drop(&mut self) {
+        if let Err(e) = self.unmap_all() {
+            log::error!("vfio: failed to unmap DMA ranges: {e}");
+        }
+    }
+}
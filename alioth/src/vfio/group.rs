@@ -0,0 +1,130 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::ffi::CString;
+use std::fs::{File, OpenOptions};
+use std::io::ErrorKind;
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use snafu::ResultExt;
+
+use crate::vfio::bindings::{VfioDeviceInfo, VfioGroupFlag, VfioGroupStatus};
+use crate::vfio::container::VfioContainer;
+use crate::vfio::ioctls::{
+    vfio_device_get_info, vfio_group_get_device_fd, vfio_group_get_status,
+    vfio_group_set_container, vfio_group_unset_container,
+};
+use crate::vfio::{error, Result};
+
+/// An IOMMU group opened from `/dev/vfio/<id>` and attached to a container.
+#[derive(Debug)]
+pub struct VfioGroup {
+    id: u32,
+    fd: File,
+    container: Arc<VfioContainer>,
+}
+
+impl VfioGroup {
+    pub fn new(container: Arc<VfioContainer>, id: u32) -> Result<Self> {
+        let path = PathBuf::from(format!("/dev/vfio/{id}"));
+        let fd = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&path)
+            .context(error::AccessDevice { path })?;
+        let mut status = VfioGroupStatus {
+            argsz: size_of::<VfioGroupStatus>() as u32,
+            ..Default::default()
+        };
+        unsafe { vfio_group_get_status(&fd, &mut status) }.context(error::GroupStatus { id })?;
+        if !status.flags.contains(VfioGroupFlag::VIABLE) {
+            return error::GroupNotViable { id }.fail();
+        }
+        let container_fd = container.as_raw_fd();
+        unsafe { vfio_group_set_container(&fd, &container_fd) }
+            .context(error::SetContainer { id })?;
+        let group = VfioGroup { id, fd, container };
+        group.container.set_iommu()?;
+        Ok(group)
+    }
+
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    pub fn container(&self) -> &Arc<VfioContainer> {
+        &self.container
+    }
+
+    /// Gets the device `name`, e.g. `0000:01:00.0` for a PCI device, from
+    /// the group.
+    pub fn add_device(self: &Arc<Self>, name: &str) -> Result<VfioDevice> {
+        let id = self.id;
+        let c_name = CString::new(name)
+            .map_err(|_| ErrorKind::InvalidInput.into())
+            .context(error::GetDevice { id, name })?;
+        let raw_fd = unsafe { vfio_group_get_device_fd(&self.fd, &c_name) }
+            .context(error::GetDevice { id, name })?;
+        let fd = unsafe { File::from_raw_fd(raw_fd) };
+        let mut info = VfioDeviceInfo {
+            argsz: size_of::<VfioDeviceInfo>() as u32,
+            ..Default::default()
+        };
+        unsafe { vfio_device_get_info(&fd, &mut info) }.context(error::DeviceInfo { name })?;
+        log::info!("vfio: group {id}: device {name}: {info:x?}");
+        Ok(VfioDevice {
+            name: name.to_owned(),
+            fd,
+            info,
+            _group: self.clone(),
+        })
+    }
+}
+
+impl Drop for VfioGroup {
+    fn drop(&mut self) {
+        if let Err(e) = unsafe { vfio_group_unset_container(&self.fd) } {
+            log::error!(
+                "vfio: group {}: failed to detach from container: {e}",
+                self.id
+            );
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct VfioDevice {
+    name: String,
+    fd: File,
+    info: VfioDeviceInfo,
+    _group: Arc<VfioGroup>,
+}
+
+impl VfioDevice {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn info(&self) -> &VfioDeviceInfo {
+        &self.info
+    }
+}
+
+impl AsFd for VfioDevice {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.fd.as_fd()
+    }
+}
@@ -0,0 +1,61 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::ffi::CStr;
+use std::os::fd::AsRawFd;
+
+use crate::utils::ioctls::ioctl_io;
+use crate::vfio::bindings::{VfioIommu, VfioIommuType1DmaMap, VFIO_TYPE};
+use crate::{ffi, ioctl_none, ioctl_write_ptr, ioctl_write_val, ioctl_writeread};
+
+ioctl_none!(vfio_get_api_version, VFIO_TYPE, 100, 0);
+ioctl_write_val!(vfio_check_extension, ioctl_io(VFIO_TYPE, 101), VfioIommu);
+ioctl_write_val!(vfio_set_iommu, ioctl_io(VFIO_TYPE, 102), VfioIommu);
+ioctl_writeread!(vfio_group_get_status, ioctl_io(VFIO_TYPE, 103));
+ioctl_write_ptr!(vfio_group_set_container, ioctl_io(VFIO_TYPE, 104), i32);
+ioctl_none!(vfio_group_unset_container, VFIO_TYPE, 105, 0);
+ioctl_writeread!(vfio_device_get_info, ioctl_io(VFIO_TYPE, 107));
+ioctl_write_ptr!(
+    vfio_iommu_map_dma,
+    ioctl_io(VFIO_TYPE, 113),
+    VfioIommuType1DmaMap
+);
+ioctl_writeread!(vfio_iommu_unmap_dma, ioctl_io(VFIO_TYPE, 114));
+
+/// Returns a new file descriptor of the device `name` in the group.
+///
+/// # Safety
+///
+/// `fd` must be a VFIO group file descriptor.
+pub unsafe fn vfio_group_get_device_fd<F: AsRawFd>(fd: &F, name: &CStr) -> std::io::Result<i32> {
+    ffi!(libc::ioctl(
+        fd.as_raw_fd(),
+        ioctl_io(VFIO_TYPE, 106) as _,
+        name.as_ptr()
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::utils::ioctls::ioctl_io;
+    use crate::vfio::bindings::VFIO_TYPE;
+
+    #[test]
+    fn test_vfio_ioctl_codes() {
+        assert_eq!(ioctl_io(VFIO_TYPE, 100), 0x3b64);
+        assert_eq!(ioctl_io(VFIO_TYPE, 104), 0x3b68);
+        assert_eq!(ioctl_io(VFIO_TYPE, 113), 0x3b71);
+        assert_eq!(ioctl_io(VFIO_TYPE, 114), 0x3b72);
+    }
+}
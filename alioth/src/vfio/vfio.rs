@@ -0,0 +1,73 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+pub mod bindings;
+pub mod container;
+pub mod group;
+pub mod ioctls;
+
+use std::path::PathBuf;
+
+use snafu::Snafu;
+
+use crate::errors::{trace_error, DebugTrace};
+
+#[trace_error]
+#[derive(Snafu, DebugTrace)]
+#[snafu(module, context(suffix(false)))]
+pub enum Error {
+    #[snafu(display("Failed to access {path:?}"))]
+    AccessDevice {
+        path: PathBuf,
+        error: std::io::Error,
+    },
+    #[snafu(display("VFIO API version {version} is not supported"))]
+    ApiVersion { version: i32 },
+    #[snafu(display("Type1 IOMMU is not supported"))]
+    IommuNotSupported,
+    #[snafu(display("Failed to set the IOMMU type"))]
+    SetIommu { error: std::io::Error },
+    #[snafu(display("Group {id} is not viable, some devices are not bound to VFIO"))]
+    GroupNotViable { id: u32 },
+    #[snafu(display("Failed to query the status of group {id}"))]
+    GroupStatus { id: u32, error: std::io::Error },
+    #[snafu(display("Failed to attach group {id} to the container"))]
+    SetContainer { id: u32, error: std::io::Error },
+    #[snafu(display("Failed to get device {name} from group {id}"))]
+    GetDevice {
+        id: u32,
+        name: String,
+        error: std::io::Error,
+    },
+    #[snafu(display("Failed to query the info of device {name}"))]
+    DeviceInfo { name: String, error: std::io::Error },
+    #[snafu(display(
+        "DMA range iova={iova:#x}, size={size:#x} overlaps with an existing mapping"
+    ))]
+    DmaOverlap { iova: u64, size: u64 },
+    #[snafu(display("Failed to map DMA range iova={iova:#x}, size={size:#x}"))]
+    MapDma {
+        iova: u64,
+        size: u64,
+        error: std::io::Error,
+    },
+    #[snafu(display("Failed to unmap DMA range iova={iova:#x}, size={size:#x}"))]
+    UnmapDma {
+        iova: u64,
+        size: u64,
+        error: std::io::Error,
+    },
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
@@ -0,0 +1,929 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::fmt::Debug;
+use std::fs::{File, OpenOptions};
+use std::io::{self, ErrorKind, IoSliceMut, Seek, SeekFrom};
+use std::mem::MaybeUninit;
+use std::os::fd::AsRawFd;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::FileExt;
+#[cfg(target_os = "linux")]
+use std::os::unix::fs::FileTypeExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use bitflags::bitflags;
+use mio::event::Event;
+use mio::Registry;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use snafu::ResultExt;
+use zerocopy::{AsBytes, FromBytes, FromZeroes};
+
+#[cfg(target_os = "linux")]
+use crate::ioctl_read;
+use crate::mem::mapped::RamBus;
+#[cfg(target_os = "linux")]
+use crate::utils::ioctls::ioctl_io;
+use crate::virtio::dev::blk::zone::{ZoneOp, ZoneReportHeader, ZonedConfig, Zones};
+use crate::virtio::dev::fault::{FaultInjector, FaultSpec};
+use crate::virtio::dev::watchdog::WatchdogParam;
+use crate::virtio::dev::{DevParam, Virtio};
+use crate::virtio::queue::handlers::handle_desc;
+use crate::virtio::queue::{Descriptor, Queue, VirtQueue};
+use crate::virtio::{error, DeviceId, IrqSender, Result, VirtioFeature, FEATURE_BUILT_IN};
+use crate::{c_enum, ffi, impl_mmio_for_zerocopy};
+
+#[cfg(target_os = "linux")]
+ioctl_read!(blk_ssz_get, ioctl_io(0x12, 104), libc::c_int);
+#[cfg(target_os = "linux")]
+ioctl_read!(blk_io_min, ioctl_io(0x12, 120), libc::c_uint);
+#[cfg(target_os = "linux")]
+ioctl_read!(blk_io_opt, ioctl_io(0x12, 121), libc::c_uint);
+#[cfg(target_os = "linux")]
+ioctl_read!(blk_align_off, ioctl_io(0x12, 122), libc::c_int);
+#[cfg(target_os = "linux")]
+ioctl_read!(blk_pbsz_get, ioctl_io(0x12, 123), libc::c_uint);
+
+mod zone;
+
+c_enum! {
+    #[derive(FromBytes, FromZeroes)]
+    pub struct RequestType(u32);
+    {
+        IN = 0;
+        OUT = 1;
+        FLUSH = 4;
+        GET_ID = 8;
+        GET_LIFETIME = 10;
+        DISCARD = 11;
+        WRITE_ZEROES = 13;
+        SECURE_ERASE = 14;
+        ZONE_APPEND = 15;
+        ZONE_REPORT = 16;
+        ZONE_OPEN = 18;
+        ZONE_CLOSE = 20;
+        ZONE_FINISH = 22;
+        ZONE_RESET = 24;
+        ZONE_RESET_ALL = 26;
+    }
+}
+
+c_enum! {
+    #[derive(FromBytes, FromZeroes)]
+    pub struct Status(u8);
+    {
+        OK = 0;
+        IOERR = 1;
+        UNSUPP = 2;
+        ZONE_INVALID_CMD = 3;
+        ZONE_UNALIGNED_WP = 4;
+        ZONE_OPEN_RESOURCE = 5;
+        ZONE_ACTIVE_RESOURCE = 6;
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, FromZeroes, FromBytes)]
+pub struct Request {
+    type_: RequestType,
+    reserved: u32,
+    sector: u64,
+}
+
+pub const VIRTIO_BLK_ID_SIZE: usize = 20;
+
+const SECTOR_SIZE: usize = 1 << 9;
+
+bitflags! {
+    #[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    pub struct BlockFeature: u64 {
+        const SIZE_MAX = 1 << 1;
+        const SEG_MAX = 1 << 2;
+        const GEOMETRY = 1 << 4;
+        const RO = 1 << 5;
+        const BLK_SIZE = 1 << 6;
+        const FLUSH = 1 << 9;
+        const TOPOLOGY = 1 << 10;
+        const CONFIG_WCE = 1 << 11;
+        const MQ = 1 << 12;
+        const DISCARD = 1 << 13;
+        const WRITE_ZEROS = 1 << 14;
+        const LIFETIME = 1 << 15;
+        const SECURE_ERASE = 1 << 16;
+        const ZONED = 1 << 17;
+    }
+}
+
+#[derive(Debug, Default, FromBytes, FromZeroes, AsBytes)]
+#[repr(C)]
+pub struct BlockConfig {
+    capacity: u64,
+    size_max: u32,
+    seg_max: u32,
+
+    // geometry
+    cylinders: u16,
+    heads: u8,
+    sectors: u8,
+
+    blk_size: u32,
+
+    // topology
+    physical_block_exp: u8,
+    alignment_offset: u8,
+    min_io_size: u16,
+    opt_io_size: u32,
+
+    writeback: u8,
+    unused0: u8,
+    num_queues: u16,
+    max_discard_sectors: u32,
+    max_discard_seg: u32,
+    discard_sector_alignment: u32,
+    max_write_zeroes_sectors: u32,
+    max_write_zeroes_seg: u32,
+    write_zeroes_may_unmap: u8,
+    _unused1: [u8; 3],
+    max_secure_erase_sectors: u32,
+    max_secure_erase_seg: u32,
+    secure_erase_sector_alignment: u32,
+
+    zoned: ZonedConfig,
+}
+impl_mmio_for_zerocopy!(BlockConfig);
+
+/// I/O hints of the backing storage, in bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Topology {
+    logical_block: u32,
+    physical_block: u32,
+    alignment_offset: u32,
+    min_io: u32,
+    opt_io: u32,
+}
+
+impl Topology {
+    #[cfg(target_os = "linux")]
+    fn from_block_device(disk: &File) -> io::Result<Self> {
+        unsafe {
+            Ok(Topology {
+                logical_block: blk_ssz_get(disk)? as u32,
+                physical_block: blk_pbsz_get(disk)?,
+                alignment_offset: blk_align_off(disk)? as u32,
+                min_io: blk_io_min(disk)?,
+                opt_io: blk_io_opt(disk)?,
+            })
+        }
+    }
+
+    /// Treats a block of the file system holding the image as a physical
+    /// block.
+    fn from_image(disk: &File) -> io::Result<Self> {
+        let mut stat = MaybeUninit::uninit();
+        ffi!(unsafe { libc::fstatfs(disk.as_raw_fd(), stat.as_mut_ptr()) })?;
+        let stat = unsafe { stat.assume_init() };
+        let block_size = stat.f_bsize as u32;
+        Ok(Topology {
+            logical_block: SECTOR_SIZE as u32,
+            physical_block: block_size,
+            alignment_offset: 0,
+            min_io: block_size,
+            opt_io: 0,
+        })
+    }
+
+    fn detect(disk: &File) -> io::Result<Self> {
+        #[cfg(target_os = "linux")]
+        if disk.metadata()?.file_type().is_block_device() {
+            return Self::from_block_device(disk);
+        }
+        Self::from_image(disk)
+    }
+
+    /// Fills the block size and topology fields, which the specification
+    /// counts in logical blocks.
+    fn update_config(&self, config: &mut BlockConfig) {
+        let logical = match self.logical_block {
+            0 => SECTOR_SIZE as u32,
+            size => size,
+        };
+        let per_physical = (self.physical_block / logical).max(1);
+        config.blk_size = logical;
+        config.physical_block_exp = per_physical.ilog2() as u8;
+        config.alignment_offset = (self.alignment_offset / logical) as u8;
+        config.min_io_size = (self.min_io / logical).min(u16::MAX as u32) as u16;
+        config.opt_io_size = self.opt_io / logical;
+    }
+}
+
+/// Storage holding the content of a block device.
+pub trait BlockBackend: Debug + Send + Sync + 'static {
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()>;
+    fn write_all_at(&self, buf: &[u8], offset: u64) -> io::Result<()>;
+    /// Persists completed writes, for `VIRTIO_BLK_T_FLUSH`.
+    fn flush(&self) -> io::Result<()>;
+    /// Returns the size of the storage in bytes.
+    fn size(&self) -> io::Result<u64>;
+}
+
+impl BlockBackend for File {
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        FileExt::read_exact_at(self, buf, offset)
+    }
+
+    fn write_all_at(&self, buf: &[u8], offset: u64) -> io::Result<()> {
+        FileExt::write_all_at(self, buf, offset)
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        self.sync_data()
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        // The metadata of a block device reports a length of 0.
+        let mut file = self;
+        file.seek(SeekFrom::End(0))
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct BlockParam {
+    pub path: PathBuf,
+    /// Disk identifier returned by VIRTIO_BLK_T_GET_ID, truncated to 20
+    /// bytes. Defaults to a UUID derived from the hash of `path`.
+    pub id: Option<String>,
+    /// PCI subsystem device ID, e.g. for guest drivers that select a
+    /// device model with it. Defaults to the one defined by the spec.
+    pub subsystem_device_id: Option<u16>,
+    /// Opens the disk read-only and offers `VIRTIO_BLK_F_RO`.
+    #[serde(default)]
+    pub read_only: bool,
+    /// Emulates host-managed zones of this many bytes on an image, whose
+    /// write pointers are kept in memory. Zoned block devices of the host
+    /// are always exposed as zoned.
+    pub zone_size: Option<u64>,
+    /// Limits of open and active emulated zones. Defaults to no limit.
+    pub max_open_zones: Option<u32>,
+    pub max_active_zones: Option<u32>,
+    /// Marks the device as needing a reset if its worker is busy with one
+    /// request for longer than this.
+    pub watchdog: Option<WatchdogParam>,
+}
+
+impl DevParam for BlockParam {
+    type Device = Block;
+
+    fn build(self, name: Arc<String>) -> Result<Block> {
+        Block::new(self, name)
+    }
+}
+
+#[derive(Debug)]
+pub struct Block {
+    name: Arc<String>,
+    config: Arc<BlockConfig>,
+    disk: FaultInjector<File>,
+    feature: BlockFeature,
+    id: [u8; VIRTIO_BLK_ID_SIZE],
+    subsystem_device_id: Option<u16>,
+    watchdog: Option<WatchdogParam>,
+    zones: Option<Zones>,
+}
+
+/// Copies `data` to the device-writable buffers, starting `offset` bytes
+/// into them.
+fn copy_to_writable(writable: &mut [IoSliceMut], mut offset: usize, data: &[u8]) {
+    let mut data = data;
+    for buf in writable.iter_mut() {
+        if data.is_empty() {
+            break;
+        }
+        if offset >= buf.len() {
+            offset -= buf.len();
+            continue;
+        }
+        let count = std::cmp::min(buf.len() - offset, data.len());
+        buf[offset..offset + count].copy_from_slice(&data[..count]);
+        data = &data[count..];
+        offset = 0;
+    }
+}
+
+/// Derives an identifier from the disk path, which stays the same across
+/// runs and builds.
+fn default_id(path: &Path) -> String {
+    let digest = Sha256::digest(path.as_os_str().as_bytes());
+    let hi = u64::from_be_bytes(digest[..8].try_into().unwrap());
+    let lo = u64::from_be_bytes(digest[8..16].try_into().unwrap());
+    format!(
+        "{:08x}-{:04x}-{:04x}-{:04x}-{:012x}",
+        hi >> 32,
+        (hi >> 16) & 0xffff,
+        hi & 0xffff,
+        lo >> 48,
+        lo & 0xffff_ffff_ffff
+    )
+}
+
+impl Block {
+    pub fn new(param: BlockParam, name: Arc<String>) -> Result<Self> {
+        let access_disk = error::AccessFile {
+            path: param.path.as_path(),
+        };
+        let disk = OpenOptions::new()
+            .read(true)
+            .write(!param.read_only)
+            .open(&param.path)
+            .context(access_disk)?;
+        let len = BlockBackend::size(&disk).context(access_disk)?;
+        let mut config = BlockConfig {
+            capacity: len / SECTOR_SIZE as u64,
+            num_queues: 1,
+            ..Default::default()
+        };
+        let topology = Topology::detect(&disk).context(access_disk)?;
+        topology.update_config(&mut config);
+        log::debug!("{name}: {topology:x?}");
+        let zones = Self::create_zones(&param, &name, &disk, config.capacity)?;
+        let id_str = param.id.unwrap_or_else(|| default_id(&param.path));
+        let mut feature = BlockFeature::FLUSH | BlockFeature::BLK_SIZE | BlockFeature::TOPOLOGY;
+        if param.read_only {
+            feature |= BlockFeature::RO;
+        }
+        if let Some(zones) = &zones {
+            config.zoned = zones.config();
+            feature |= BlockFeature::ZONED;
+        }
+        let config = Arc::new(config);
+        let mut id = [0u8; VIRTIO_BLK_ID_SIZE];
+        let id_len = std::cmp::min(id_str.len(), VIRTIO_BLK_ID_SIZE);
+        id[..id_len].copy_from_slice(&id_str.as_bytes()[..id_len]);
+        Ok(Block {
+            name,
+            disk: FaultInjector::new(disk, vec![]),
+            config,
+            feature,
+            id,
+            subsystem_device_id: param.subsystem_device_id,
+            watchdog: param.watchdog,
+            zones,
+        })
+    }
+
+    fn create_zones(
+        param: &BlockParam,
+        name: &Arc<String>,
+        disk: &File,
+        capacity: u64,
+    ) -> Result<Option<Zones>> {
+        #[cfg(target_os = "linux")]
+        if disk.metadata()?.file_type().is_block_device() {
+            return Ok(Zones::from_block_device(name.clone(), disk, capacity)?);
+        }
+        let Some(size) = param.zone_size else {
+            return Ok(None);
+        };
+        let sectors = size / SECTOR_SIZE as u64;
+        // Linux guests require the zone size to be a power of 2.
+        if size % SECTOR_SIZE as u64 != 0 || !sectors.is_power_of_two() || sectors > u32::MAX as u64
+        {
+            return error::InvalidZoneSize { size }.fail();
+        }
+        Ok(Some(Zones::emulated(
+            name.clone(),
+            capacity,
+            sectors as u32,
+            param.max_open_zones.unwrap_or(0),
+            param.max_active_zones.unwrap_or(0),
+        )))
+    }
+
+    fn handle_zone_req(
+        &self,
+        zones: &Zones,
+        type_: RequestType,
+        sector: u64,
+        desc: &mut Descriptor,
+    ) -> io::Result<usize> {
+        let w_len = desc.writable.iter().map(|b| b.len()).sum::<usize>();
+        if w_len == 0 {
+            return Err(ErrorKind::InvalidData.into());
+        }
+        let disk = &self.disk;
+        let op = match type_ {
+            RequestType::ZONE_APPEND => {
+                // le64 append_sector, followed by the status byte
+                let Some(offset) = w_len.checked_sub(9) else {
+                    return Err(ErrorKind::InvalidData.into());
+                };
+                let status = match zones.append(disk, sector, &desc.readable[1..]) {
+                    Ok(append_sector) => {
+                        copy_to_writable(&mut desc.writable, offset, &append_sector.to_le_bytes());
+                        Status::OK
+                    }
+                    Err(status) => status,
+                };
+                copy_to_writable(&mut desc.writable, w_len - 1, &[status.raw()]);
+                return Ok(w_len);
+            }
+            RequestType::ZONE_REPORT => {
+                let header_size = size_of::<ZoneReportHeader>();
+                let desc_size = size_of::<zone::ZoneDescriptor>();
+                let max = (w_len - 1).saturating_sub(header_size) / desc_size;
+                let status = match zones.report(disk, sector, max) {
+                    Ok(reported) => {
+                        let header = ZoneReportHeader {
+                            nr_zones: reported.len() as u64,
+                            reserved: [0; 56],
+                        };
+                        copy_to_writable(&mut desc.writable, 0, header.as_bytes());
+                        copy_to_writable(&mut desc.writable, header_size, reported.as_bytes());
+                        Status::OK
+                    }
+                    Err(status) => status,
+                };
+                copy_to_writable(&mut desc.writable, w_len - 1, &[status.raw()]);
+                return Ok(w_len);
+            }
+            RequestType::ZONE_OPEN => ZoneOp::Open,
+            RequestType::ZONE_CLOSE => ZoneOp::Close,
+            RequestType::ZONE_FINISH => ZoneOp::Finish,
+            RequestType::ZONE_RESET => ZoneOp::Reset,
+            RequestType::ZONE_RESET_ALL => ZoneOp::ResetAll,
+            _ => unreachable!(),
+        };
+        let status = zones.manage(disk, op, sector);
+        copy_to_writable(&mut desc.writable, w_len - 1, &[status.raw()]);
+        Ok(1)
+    }
+
+    fn handle_req_queue(&self, desc: &mut Descriptor) -> io::Result<usize> {
+        let disk = &self.disk;
+        let Some(buf0) = desc.readable.first() else {
+            return Err(ErrorKind::InvalidData.into());
+        };
+        let request_clone;
+        let request = match Request::ref_from(buf0) {
+            Some(r) => r,
+            None => match FromBytes::read_from(buf0) {
+                Some(r) => {
+                    request_clone = r;
+                    &request_clone
+                }
+                None => return Err(ErrorKind::InvalidData.into()),
+            },
+        };
+        let offset = request.sector * SECTOR_SIZE as u64;
+        let w_len = match request.type_ {
+            RequestType::IN => {
+                let Some(buf1) = desc.writable.first_mut() else {
+                    return Err(ErrorKind::InvalidData.into());
+                };
+                let l = buf1.len();
+                let status = match disk.read_exact_at(buf1, offset) {
+                    Ok(()) => Status::OK,
+                    Err(e) => {
+                        log::error!("{}: read {l} bytes from offset {offset:#x}: {e}", self.name);
+                        Status::IOERR
+                    }
+                };
+                let Some(buf2) = desc.writable.get_mut(1) else {
+                    return Err(ErrorKind::InvalidData.into());
+                };
+                let Some(status_byte) = buf2.first_mut() else {
+                    return Err(ErrorKind::InvalidData.into());
+                };
+                *status_byte = status.into();
+                l + 1
+            }
+            RequestType::OUT => {
+                let Some(buf1) = desc.readable.get(1) else {
+                    return Err(ErrorKind::InvalidData.into());
+                };
+                let l = buf1.len();
+                let status = if self.feature.contains(BlockFeature::RO) {
+                    log::error!("{}: write {l} bytes to a read-only disk", self.name);
+                    Status::IOERR
+                } else if let Some(zones) = &self.zones {
+                    zones.write(disk, request.sector, &desc.readable[1..])
+                } else {
+                    match disk.write_all_at(buf1, offset) {
+                        Ok(()) => Status::OK,
+                        Err(e) => {
+                            log::error!(
+                                "{}: write {l} bytes to offset {offset:#x}: {e}",
+                                self.name
+                            );
+                            Status::IOERR
+                        }
+                    }
+                };
+                let Some(buf2) = desc.writable.first_mut() else {
+                    return Err(ErrorKind::InvalidData.into());
+                };
+                let Some(status_byte) = buf2.first_mut() else {
+                    return Err(ErrorKind::InvalidData.into());
+                };
+                *status_byte = status.into();
+                1
+            }
+            RequestType::FLUSH => {
+                let status = match disk.flush() {
+                    Ok(()) => Status::OK,
+                    Err(e) => {
+                        log::error!("{}: flush: {e}", self.name);
+                        Status::IOERR
+                    }
+                };
+                let Some(w_buf) = desc.writable.last_mut() else {
+                    return Err(ErrorKind::InvalidData.into());
+                };
+                let Some(status_byte) = w_buf.get_mut(0) else {
+                    return Err(ErrorKind::InvalidData.into());
+                };
+                *status_byte = status.into();
+                1
+            }
+            RequestType::GET_ID => {
+                let Some(buf1) = desc.writable.first_mut() else {
+                    return Err(ErrorKind::InvalidData.into());
+                };
+                let len = std::cmp::min(self.id.len(), buf1.len());
+                buf1[0..len].copy_from_slice(&self.id[0..len]);
+                let Some(buf2) = desc.writable.get_mut(1) else {
+                    return Err(ErrorKind::InvalidData.into());
+                };
+                let Some(status_byte) = buf2.first_mut() else {
+                    return Err(ErrorKind::InvalidData.into());
+                };
+                *status_byte = Status::OK.into();
+                1 + len
+            }
+            RequestType::ZONE_APPEND
+            | RequestType::ZONE_REPORT
+            | RequestType::ZONE_OPEN
+            | RequestType::ZONE_CLOSE
+            | RequestType::ZONE_FINISH
+            | RequestType::ZONE_RESET
+            | RequestType::ZONE_RESET_ALL
+                if self.zones.is_some() =>
+            {
+                let (type_, sector) = (request.type_, request.sector);
+                let zones = self.zones.as_ref().unwrap();
+                return self.handle_zone_req(zones, type_, sector, desc);
+            }
+            _ => {
+                log::error!("unimplemented op: {:#x?}", request.type_);
+                let Some(w_buf) = desc.writable.last_mut() else {
+                    return Err(ErrorKind::InvalidData.into());
+                };
+                let Some(w_byte) = w_buf.get_mut(0) else {
+                    return Err(ErrorKind::InvalidData.into());
+                };
+                *w_byte = Status::UNSUPP.into();
+                1
+            }
+        };
+        Ok(w_len)
+    }
+}
+
+impl Virtio for Block {
+    type Config = BlockConfig;
+    type Feature = BlockFeature;
+
+    fn reset(&mut self, _registry: &Registry) {}
+
+    fn device_id() -> DeviceId {
+        DeviceId::Block
+    }
+
+    fn subsystem_device_id(&self) -> Option<u16> {
+        self.subsystem_device_id
+    }
+
+    fn watchdog(&self) -> Option<WatchdogParam> {
+        self.watchdog
+    }
+
+    fn inject_fault(&mut self, spec: FaultSpec) -> bool {
+        self.disk.inject(spec);
+        true
+    }
+
+    fn num_queues(&self) -> u16 {
+        self.config.num_queues
+    }
+
+    fn config(&self) -> Arc<BlockConfig> {
+        self.config.clone()
+    }
+
+    fn feature(&self) -> u64 {
+        self.feature.bits() | FEATURE_BUILT_IN | VirtioFeature::IN_ORDER.bits()
+    }
+
+    fn activate(
+        &mut self,
+        _registry: &Registry,
+        _feature: u64,
+        _memory: &RamBus,
+        _irq_sender: &impl IrqSender,
+        _queues: &[Queue],
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    fn handle_event(
+        &mut self,
+        _event: &Event,
+        _queues: &[impl VirtQueue],
+        _irq_sender: &impl IrqSender,
+        _registry: &Registry,
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    fn handle_queue(
+        &mut self,
+        index: u16,
+        queues: &[impl VirtQueue],
+        irq_sender: &impl IrqSender,
+        _registry: &Registry,
+    ) -> Result<()> {
+        let Some(queue) = queues.get(index as usize) else {
+            log::error!("{}: invalid queue index {index}", self.name);
+            return Ok(());
+        };
+        handle_desc(&self.name, index, queue, irq_sender, |desc| {
+            self.handle_req_queue(desc)
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::io::{ErrorKind, IoSlice, IoSliceMut};
+    use std::path::PathBuf;
+    use std::sync::Arc;
+
+    use zerocopy::FromBytes;
+
+    use crate::hv::test::{FakeIoeventFdRegistry, FakeMsiSender, FakeVmMemory};
+    use crate::mem::mapped::RamBus;
+    use crate::pci::Pci;
+    use crate::virtio::dev::blk::zone::{ZoneDescriptor, ZoneState, ZoneType};
+    use crate::virtio::dev::fault::{FaultOp, FaultSpec};
+    use crate::virtio::dev::{Virtio, VirtioDevice};
+    use crate::virtio::pci::VirtioPciDevice;
+    use crate::virtio::queue::Descriptor;
+
+    use super::{
+        default_id, Block, BlockConfig, BlockFeature, BlockParam, RequestType, Status, Topology,
+        SECTOR_SIZE, VIRTIO_BLK_ID_SIZE,
+    };
+
+    /// A disk image in the temporary directory, deleted on drop.
+    struct TempImage(PathBuf);
+
+    impl TempImage {
+        fn read(&self) -> Vec<u8> {
+            fs::read(&self.0).unwrap()
+        }
+    }
+
+    impl Drop for TempImage {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    /// Creates a device with `param` on a zeroed image of `size` bytes.
+    fn new_blk(name: &str, size: usize, param: BlockParam) -> (TempImage, Block) {
+        let path =
+            std::env::temp_dir().join(format!("alioth-blk-{name}-{}.img", std::process::id()));
+        fs::write(&path, vec![0u8; size]).unwrap();
+        let image = TempImage(path.clone());
+        let param = BlockParam { path, ..param };
+        let blk = Block::new(param, Arc::new("blk-test".to_owned())).unwrap();
+        (image, blk)
+    }
+
+    fn request(type_: RequestType, sector: u64) -> [u8; 16] {
+        let mut request = [0u8; 16];
+        request[0..4].copy_from_slice(&type_.raw().to_le_bytes());
+        request[8..16].copy_from_slice(&sector.to_le_bytes());
+        request
+    }
+
+    /// Returns the number of bytes the device writes to `writable`.
+    fn handle_req(blk: &Block, readable: &[&[u8]], writable: &mut [&mut [u8]]) -> usize {
+        let mut desc = Descriptor {
+            id: 0,
+            readable: readable.iter().map(|buf| IoSlice::new(buf)).collect(),
+            writable: writable
+                .iter_mut()
+                .map(|buf| IoSliceMut::new(buf))
+                .collect(),
+        };
+        blk.handle_req_queue(&mut desc).unwrap()
+    }
+
+    #[test]
+    fn test_topology_config() {
+        let topology = Topology {
+            logical_block: 512,
+            physical_block: 4096,
+            alignment_offset: 3584,
+            min_io: 4096,
+            opt_io: 1 << 20,
+        };
+        let mut config = BlockConfig::default();
+        topology.update_config(&mut config);
+        assert_eq!(config.blk_size, 512);
+        assert_eq!(config.physical_block_exp, 3);
+        assert_eq!(config.alignment_offset, 7);
+        assert_eq!(config.min_io_size, 8);
+        assert_eq!(config.opt_io_size, 2048);
+
+        let (_image, blk) = new_blk("topo", 1 << 12, BlockParam::default());
+        assert_eq!(blk.config.blk_size, 512);
+        assert_eq!(blk.config.capacity, 8);
+        let physical = 512 << blk.config.physical_block_exp;
+        assert_eq!(blk.config.min_io_size as u32 * 512, physical);
+    }
+
+    #[test]
+    fn test_get_id() {
+        let param = BlockParam {
+            id: Some("alioth-disk-0123456789abcdef".to_owned()),
+            ..Default::default()
+        };
+        let (_image, blk) = new_blk("id", 1 << 12, param);
+
+        let request = request(RequestType::GET_ID, 0);
+        let mut id = [0xffu8; VIRTIO_BLK_ID_SIZE];
+        let mut status = [0xffu8; 1];
+        let len = handle_req(&blk, &[&request], &mut [&mut id, &mut status]);
+
+        assert_eq!(len, VIRTIO_BLK_ID_SIZE + 1);
+        assert_eq!(&id, b"alioth-disk-01234567");
+        assert_eq!(status[0], Status::OK.raw());
+    }
+
+    #[test]
+    fn test_default_id() {
+        assert_eq!(
+            default_id("/var/lib/alioth/disk.img".as_ref()),
+            "45ca5beb-8294-cb41-6f7e-9e9a7599603b"
+        );
+    }
+
+    #[test]
+    fn test_read_only() {
+        let param = BlockParam {
+            read_only: true,
+            ..Default::default()
+        };
+        let (image, blk) = new_blk("ro", 1 << 12, param);
+        assert!(blk.feature.contains(BlockFeature::RO));
+
+        let request = request(RequestType::OUT, 0);
+        let data = [0xaau8; 512];
+        let mut status = [0xffu8; 1];
+        let len = handle_req(&blk, &[&request, &data], &mut [&mut status]);
+
+        assert_eq!(len, 1);
+        assert_eq!(status[0], Status::IOERR.raw());
+        assert!(image.read().iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn test_flush() {
+        let (_image, blk) = new_blk("flush", 1 << 12, BlockParam::default());
+
+        let request = request(RequestType::FLUSH, 0);
+        let mut status = [0xffu8; 1];
+        assert_eq!(handle_req(&blk, &[&request], &mut [&mut status]), 1);
+        assert_eq!(status[0], Status::OK.raw());
+    }
+
+    #[test]
+    fn test_inject_write_fault() {
+        let (image, mut blk) = new_blk("fault", 64 * SECTOR_SIZE, BlockParam::default());
+        let offset = 42 * SECTOR_SIZE as u64;
+        assert!(blk.inject_fault(FaultSpec {
+            offset_range: offset..offset + SECTOR_SIZE as u64,
+            op: FaultOp::Write,
+            nth_occurrence: 1,
+            error: ErrorKind::Other,
+        }));
+
+        let data = [0xaau8; SECTOR_SIZE];
+        let write = |sector: u64| {
+            let request = request(RequestType::OUT, sector);
+            let mut status = [0xffu8; 1];
+            assert_eq!(handle_req(&blk, &[&request, &data], &mut [&mut status]), 1);
+            status[0]
+        };
+        assert_eq!(write(41), Status::OK.raw());
+        assert_eq!(write(42), Status::IOERR.raw());
+        let disk = image.read();
+        assert!(disk[offset as usize..][..SECTOR_SIZE]
+            .iter()
+            .all(|b| *b == 0));
+        // the driver retries the request
+        assert_eq!(write(42), Status::OK.raw());
+        assert_eq!(image.read()[offset as usize..][..SECTOR_SIZE], data);
+    }
+
+    #[test]
+    fn test_zoned_image() {
+        let param = BlockParam {
+            zone_size: Some(16 * SECTOR_SIZE as u64),
+            ..Default::default()
+        };
+        let (image, blk) = new_blk("zoned", 64 * SECTOR_SIZE, param);
+        assert!(blk.feature.contains(BlockFeature::ZONED));
+        assert_eq!(blk.config.zoned.zone_sectors, 16);
+
+        let data = [0xaau8; SECTOR_SIZE];
+        let append = |sector: u64| {
+            let request = request(RequestType::ZONE_APPEND, sector);
+            let mut out = [0xffu8; 9];
+            assert_eq!(handle_req(&blk, &[&request, &data], &mut [&mut out]), 9);
+            (u64::from_le_bytes(out[..8].try_into().unwrap()), out[8])
+        };
+        let write = |sector: u64| {
+            let request = request(RequestType::OUT, sector);
+            let mut status = [0xffu8; 1];
+            assert_eq!(handle_req(&blk, &[&request, &data], &mut [&mut status]), 1);
+            status[0]
+        };
+
+        assert_eq!(append(16), (16, Status::OK.raw()));
+        assert_eq!(append(16), (17, Status::OK.raw()));
+        assert_eq!(write(16), Status::ZONE_UNALIGNED_WP.raw());
+        assert_eq!(write(18), Status::OK.raw());
+
+        let request = request(RequestType::ZONE_REPORT, 0);
+        const REPORT_LEN: usize = 64 + 2 * 64 + 1;
+        let mut report = [0u8; REPORT_LEN];
+        let len = handle_req(&blk, &[&request], &mut [&mut report]);
+        assert_eq!(len, REPORT_LEN);
+        assert_eq!(report[REPORT_LEN - 1], Status::OK.raw());
+        assert_eq!(u64::from_le_bytes(report[..8].try_into().unwrap()), 2);
+        let zone1 = ZoneDescriptor::read_from(&report[128..192]).unwrap();
+        assert_eq!(zone1.z_start, 16);
+        assert_eq!(zone1.z_cap, 16);
+        assert_eq!(zone1.z_wp, 19);
+        assert_eq!(zone1.z_type, ZoneType::SWR);
+        assert_eq!(zone1.z_state, ZoneState::IMP_OPEN);
+
+        let disk = image.read();
+        assert_eq!(disk[16 * SECTOR_SIZE..][..SECTOR_SIZE], data);
+        assert_eq!(disk[18 * SECTOR_SIZE..][..SECTOR_SIZE], data);
+    }
+
+    #[test]
+    fn test_subsystem_device_id() {
+        let param = BlockParam {
+            subsystem_device_id: Some(0x0002),
+            ..Default::default()
+        };
+        let (_image, blk) = new_blk("ss", 1 << 12, param);
+
+        let registry = FakeIoeventFdRegistry::default();
+        let memory = Arc::new(RamBus::new(FakeVmMemory));
+        let dev = VirtioDevice::new(
+            Arc::new("blk-test".to_owned()),
+            blk,
+            memory,
+            &registry,
+            false,
+            None,
+            #[cfg(target_os = "linux")]
+            None,
+        )
+        .unwrap();
+        let pci_dev = VirtioPciDevice::new(dev, FakeMsiSender::default(), registry, false).unwrap();
+        let config = pci_dev.config();
+        assert_eq!(config.read(0x2e, 2).unwrap(), 0x0002);
+        // the device ID still follows the spec
+        assert_eq!(config.read(0x2, 2).unwrap(), 0x1042);
+    }
+}
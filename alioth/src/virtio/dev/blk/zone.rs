@@ -0,0 +1,720 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::fs::File;
+use std::io::{self, IoSlice};
+use std::sync::Arc;
+
+#[cfg(target_os = "linux")]
+use std::fs;
+#[cfg(target_os = "linux")]
+use std::os::unix::fs::MetadataExt;
+
+use parking_lot::Mutex;
+use zerocopy::{AsBytes, FromBytes, FromZeroes};
+
+use crate::c_enum;
+#[cfg(target_os = "linux")]
+use crate::utils::ioctls::{ioctl_ior, ioctl_iow};
+use crate::virtio::dev::blk::{BlockBackend, Status, SECTOR_SIZE};
+use crate::virtio::dev::fault::FaultInjector;
+#[cfg(target_os = "linux")]
+use crate::{ioctl_read, ioctl_write_ptr, ioctl_writeread_buf};
+
+#[cfg(target_os = "linux")]
+ioctl_writeread_buf!(blk_report_zone, 0x12, 130, BlkZoneReport);
+#[cfg(target_os = "linux")]
+ioctl_write_ptr!(
+    blk_reset_zone,
+    ioctl_iow::<BlkZoneRange>(0x12, 131),
+    BlkZoneRange
+);
+#[cfg(target_os = "linux")]
+ioctl_read!(blk_get_zone_sz, ioctl_ior::<u32>(0x12, 132), u32);
+#[cfg(target_os = "linux")]
+ioctl_write_ptr!(
+    blk_open_zone,
+    ioctl_iow::<BlkZoneRange>(0x12, 134),
+    BlkZoneRange
+);
+#[cfg(target_os = "linux")]
+ioctl_write_ptr!(
+    blk_close_zone,
+    ioctl_iow::<BlkZoneRange>(0x12, 135),
+    BlkZoneRange
+);
+#[cfg(target_os = "linux")]
+ioctl_write_ptr!(
+    blk_finish_zone,
+    ioctl_iow::<BlkZoneRange>(0x12, 136),
+    BlkZoneRange
+);
+
+c_enum! {
+    #[derive(FromBytes, FromZeroes, AsBytes)]
+    pub struct ZonedModel(u8);
+    {
+        NONE = 0;
+        HOST_MANAGED = 1;
+        HOST_AWARE = 2;
+    }
+}
+
+c_enum! {
+    #[derive(FromBytes, FromZeroes, AsBytes)]
+    pub struct ZoneType(u8);
+    {
+        CONV = 1;
+        SWR = 2;
+        SWP = 3;
+    }
+}
+
+c_enum! {
+    #[derive(FromBytes, FromZeroes, AsBytes)]
+    pub struct ZoneState(u8);
+    {
+        NOT_WP = 0;
+        EMPTY = 1;
+        IMP_OPEN = 2;
+        EXP_OPEN = 3;
+        CLOSED = 4;
+        READ_ONLY = 13;
+        FULL = 14;
+        OFFLINE = 15;
+    }
+}
+
+/// `struct virtio_blk_zoned_characteristics` in the device configuration.
+#[derive(Debug, Clone, Copy, FromBytes, FromZeroes, AsBytes)]
+#[repr(C)]
+pub struct ZonedConfig {
+    pub zone_sectors: u32,
+    pub max_open_zones: u32,
+    pub max_active_zones: u32,
+    pub max_append_sectors: u32,
+    pub write_granularity: u32,
+    pub model: ZonedModel,
+    pub unused2: [u8; 3],
+}
+
+impl Default for ZonedConfig {
+    fn default() -> Self {
+        FromZeroes::new_zeroed()
+    }
+}
+
+#[derive(Debug, Clone, Copy, FromBytes, FromZeroes, AsBytes)]
+#[repr(C)]
+pub struct ZoneReportHeader {
+    pub nr_zones: u64,
+    pub reserved: [u8; 56],
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, FromBytes, FromZeroes, AsBytes)]
+#[repr(C)]
+pub struct ZoneDescriptor {
+    pub z_cap: u64,
+    pub z_start: u64,
+    pub z_wp: u64,
+    pub z_type: ZoneType,
+    pub z_state: ZoneState,
+    pub reserved: [u8; 38],
+}
+
+/// `struct blk_zone` of Linux.
+#[cfg(target_os = "linux")]
+#[derive(Debug, Default, Clone, Copy)]
+#[repr(C)]
+pub struct BlkZone {
+    start: u64,
+    len: u64,
+    wp: u64,
+    type_: u8,
+    cond: u8,
+    non_seq: u8,
+    reset: u8,
+    resv: [u8; 4],
+    capacity: u64,
+    reserved: [u8; 24],
+}
+
+#[cfg(target_os = "linux")]
+const BLK_ZONE_REP_CAPACITY: u32 = 1 << 0;
+
+/// `struct blk_zone_report` of Linux.
+#[cfg(target_os = "linux")]
+#[derive(Debug)]
+#[repr(C)]
+pub struct BlkZoneReport<const N: usize> {
+    sector: u64,
+    nr_zones: u32,
+    flags: u32,
+    zones: [BlkZone; N],
+}
+
+/// `struct blk_zone_range` of Linux.
+#[cfg(target_os = "linux")]
+#[derive(Debug)]
+#[repr(C)]
+pub struct BlkZoneRange {
+    sector: u64,
+    nr_sectors: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ZoneOp {
+    Open,
+    Close,
+    Finish,
+    Reset,
+    ResetAll,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Zone {
+    start: u64,
+    cap: u64,
+    wp: u64,
+    state: ZoneState,
+}
+
+impl Zone {
+    fn end(&self) -> u64 {
+        self.start + self.cap
+    }
+
+    fn is_open(&self) -> bool {
+        matches!(self.state, ZoneState::IMP_OPEN | ZoneState::EXP_OPEN)
+    }
+
+    fn is_active(&self) -> bool {
+        self.is_open() || self.state == ZoneState::CLOSED
+    }
+
+    fn descriptor(&self) -> ZoneDescriptor {
+        ZoneDescriptor {
+            z_cap: self.cap,
+            z_start: self.start,
+            z_wp: self.wp,
+            z_type: ZoneType::SWR,
+            z_state: self.state,
+            reserved: [0; 38],
+        }
+    }
+}
+
+/// Sequential write required zones tracked in memory, with their write
+/// pointers lost when the VMM exits.
+#[derive(Debug)]
+struct EmulatedZones {
+    zones: Vec<Zone>,
+    max_open: u32,
+    max_active: u32,
+}
+
+impl EmulatedZones {
+    fn new(capacity: u64, zone_sectors: u64, max_open: u32, max_active: u32) -> Self {
+        let zones = (0..capacity.div_ceil(zone_sectors))
+            .map(|index| {
+                let start = index * zone_sectors;
+                Zone {
+                    start,
+                    cap: std::cmp::min(zone_sectors, capacity - start),
+                    wp: start,
+                    state: ZoneState::EMPTY,
+                }
+            })
+            .collect();
+        EmulatedZones {
+            zones,
+            max_open,
+            max_active,
+        }
+    }
+
+    fn count(&self, f: impl Fn(&Zone) -> bool) -> u32 {
+        self.zones.iter().filter(|z| f(z)).count() as u32
+    }
+
+    /// Makes room for opening one more zone, by closing an implicitly
+    /// opened zone if the limit is reached.
+    fn reserve_open(&mut self) -> Result<(), Status> {
+        if self.max_open == 0 || self.count(Zone::is_open) < self.max_open {
+            return Ok(());
+        }
+        let Some(zone) = self
+            .zones
+            .iter_mut()
+            .find(|z| z.state == ZoneState::IMP_OPEN)
+        else {
+            return Err(Status::ZONE_OPEN_RESOURCE);
+        };
+        zone.state = ZoneState::CLOSED;
+        Ok(())
+    }
+
+    fn reserve_active(&self) -> Result<(), Status> {
+        if self.max_active == 0 || self.count(Zone::is_active) < self.max_active {
+            Ok(())
+        } else {
+            Err(Status::ZONE_ACTIVE_RESOURCE)
+        }
+    }
+
+    /// Moves zone `index` to the open `state`, claiming the resources it
+    /// needs.
+    fn open(&mut self, index: usize, state: ZoneState) -> Result<(), Status> {
+        match self.zones[index].state {
+            ZoneState::EMPTY => {
+                self.reserve_active()?;
+                self.reserve_open()?;
+            }
+            ZoneState::CLOSED => self.reserve_open()?,
+            ZoneState::IMP_OPEN | ZoneState::EXP_OPEN => {}
+            _ => return Err(Status::ZONE_INVALID_CMD),
+        }
+        let zone = &mut self.zones[index];
+        if zone.state != ZoneState::EXP_OPEN {
+            zone.state = state;
+        }
+        Ok(())
+    }
+
+    fn zone_at(&self, sector: u64) -> Result<usize, Status> {
+        match self.zones.iter().position(|z| z.start == sector) {
+            Some(index) => Ok(index),
+            None => Err(Status::ZONE_INVALID_CMD),
+        }
+    }
+
+    /// Checks a write of `len` sectors at `sector` and returns the index of
+    /// the zone.
+    fn check_write(&mut self, sector: u64, len: u64) -> Result<usize, Status> {
+        let Some(index) = self.zones.iter().position(|z| z.end() > sector) else {
+            return Err(Status::IOERR);
+        };
+        let zone = self.zones[index];
+        if matches!(
+            zone.state,
+            ZoneState::FULL | ZoneState::READ_ONLY | ZoneState::OFFLINE
+        ) {
+            return Err(Status::ZONE_INVALID_CMD);
+        }
+        if sector != zone.wp {
+            return Err(Status::ZONE_UNALIGNED_WP);
+        }
+        if sector + len > zone.end() {
+            return Err(Status::IOERR);
+        }
+        self.open(index, ZoneState::IMP_OPEN)?;
+        Ok(index)
+    }
+
+    fn advance(&mut self, index: usize, len: u64) {
+        let zone = &mut self.zones[index];
+        zone.wp += len;
+        if zone.wp == zone.end() {
+            zone.state = ZoneState::FULL;
+        }
+    }
+
+    fn manage(&mut self, op: ZoneOp, sector: u64) -> Result<(), Status> {
+        if op == ZoneOp::ResetAll {
+            for zone in self.zones.iter_mut() {
+                if !matches!(zone.state, ZoneState::READ_ONLY | ZoneState::OFFLINE) {
+                    zone.wp = zone.start;
+                    zone.state = ZoneState::EMPTY;
+                }
+            }
+            return Ok(());
+        }
+        let index = self.zone_at(sector)?;
+        let state = self.zones[index].state;
+        if matches!(state, ZoneState::READ_ONLY | ZoneState::OFFLINE) {
+            return Err(Status::ZONE_INVALID_CMD);
+        }
+        match op {
+            ZoneOp::Open if state == ZoneState::FULL => {}
+            ZoneOp::Open => self.open(index, ZoneState::EXP_OPEN)?,
+            ZoneOp::Close => {
+                let zone = &mut self.zones[index];
+                if zone.is_open() {
+                    zone.state = if zone.wp == zone.start {
+                        ZoneState::EMPTY
+                    } else {
+                        ZoneState::CLOSED
+                    };
+                }
+            }
+            ZoneOp::Finish => {
+                let zone = &mut self.zones[index];
+                zone.wp = zone.end();
+                zone.state = ZoneState::FULL;
+            }
+            ZoneOp::Reset | ZoneOp::ResetAll => {
+                let zone = &mut self.zones[index];
+                zone.wp = zone.start;
+                zone.state = ZoneState::EMPTY;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+enum ZoneBackend {
+    Emulated(Mutex<EmulatedZones>),
+    /// A zoned block device of the host, managed with the `BLK*ZONE`
+    /// ioctls.
+    #[cfg(target_os = "linux")]
+    Device {
+        capacity: u64,
+    },
+}
+
+/// Zones of a block device with `VIRTIO_BLK_F_ZONED`.
+#[derive(Debug)]
+pub struct Zones {
+    name: Arc<String>,
+    config: ZonedConfig,
+    backend: ZoneBackend,
+}
+
+fn write_bufs(disk: &FaultInjector<File>, sector: u64, bufs: &[IoSlice]) -> io::Result<()> {
+    let mut offset = sector * SECTOR_SIZE as u64;
+    for buf in bufs {
+        disk.write_all_at(buf, offset)?;
+        offset += buf.len() as u64;
+    }
+    Ok(())
+}
+
+fn sectors_of(bufs: &[IoSlice]) -> Result<u64, Status> {
+    let len = bufs.iter().map(|b| b.len() as u64).sum::<u64>();
+    if len % SECTOR_SIZE as u64 != 0 {
+        return Err(Status::IOERR);
+    }
+    Ok(len / SECTOR_SIZE as u64)
+}
+
+impl Zones {
+    /// Emulates host-managed zones of `zone_sectors` sectors on an image
+    /// of `capacity` sectors. A limit of 0 means no limit.
+    pub fn emulated(
+        name: Arc<String>,
+        capacity: u64,
+        zone_sectors: u32,
+        max_open: u32,
+        max_active: u32,
+    ) -> Self {
+        let zones = EmulatedZones::new(capacity, zone_sectors as u64, max_open, max_active);
+        Zones {
+            name,
+            config: ZonedConfig {
+                zone_sectors,
+                max_open_zones: max_open,
+                max_active_zones: max_active,
+                max_append_sectors: zone_sectors,
+                write_granularity: SECTOR_SIZE as u32,
+                model: ZonedModel::HOST_MANAGED,
+                unused2: [0; 3],
+            },
+            backend: ZoneBackend::Emulated(Mutex::new(zones)),
+        }
+    }
+
+    /// Returns `None` if `disk` is not a zoned block device.
+    #[cfg(target_os = "linux")]
+    pub fn from_block_device(
+        name: Arc<String>,
+        disk: &File,
+        capacity: u64,
+    ) -> io::Result<Option<Self>> {
+        let zone_sectors = unsafe { blk_get_zone_sz(disk) }?;
+        if zone_sectors == 0 {
+            return Ok(None);
+        }
+        let rdev = disk.metadata()?.rdev();
+        let (major, minor) = unsafe { (libc::major(rdev), libc::minor(rdev)) };
+        let queue = format!("/sys/dev/block/{major}:{minor}/queue");
+        let read_attr = |attr: &str| -> io::Result<String> {
+            let s = fs::read_to_string(format!("{queue}/{attr}"))?;
+            Ok(s.trim().to_owned())
+        };
+        let read_num = |attr: &str| -> io::Result<u32> {
+            read_attr(attr)?
+                .parse()
+                .map_err(|_| io::ErrorKind::InvalidData.into())
+        };
+        let model = match read_attr("zoned")?.as_str() {
+            "host-aware" => ZonedModel::HOST_AWARE,
+            _ => ZonedModel::HOST_MANAGED,
+        };
+        let config = ZonedConfig {
+            zone_sectors,
+            max_open_zones: read_num("max_open_zones")?,
+            max_active_zones: read_num("max_active_zones")?,
+            max_append_sectors: read_num("zone_append_max_bytes")? / SECTOR_SIZE as u32,
+            write_granularity: read_num("zone_write_granularity")?,
+            model,
+            unused2: [0; 3],
+        };
+        Ok(Some(Zones {
+            name,
+            config,
+            backend: ZoneBackend::Device { capacity },
+        }))
+    }
+
+    pub fn config(&self) -> ZonedConfig {
+        self.config
+    }
+
+    /// Handles `VIRTIO_BLK_T_OUT`.
+    pub fn write(&self, disk: &FaultInjector<File>, sector: u64, bufs: &[IoSlice]) -> Status {
+        let emulated = match &self.backend {
+            ZoneBackend::Emulated(emulated) => emulated,
+            #[cfg(target_os = "linux")]
+            ZoneBackend::Device { .. } => return self.io_status(write_bufs(disk, sector, bufs)),
+        };
+        let len = match sectors_of(bufs) {
+            Ok(len) => len,
+            Err(status) => return status,
+        };
+        let mut emulated = emulated.lock();
+        let index = match emulated.check_write(sector, len) {
+            Ok(index) => index,
+            Err(status) => return status,
+        };
+        let status = self.io_status(write_bufs(disk, sector, bufs));
+        if status == Status::OK {
+            emulated.advance(index, len);
+        }
+        status
+    }
+
+    /// Handles `VIRTIO_BLK_T_ZONE_APPEND` to the zone starting at `sector`
+    /// and returns the sector where the data is written.
+    pub fn append(
+        &self,
+        disk: &FaultInjector<File>,
+        sector: u64,
+        bufs: &[IoSlice],
+    ) -> Result<u64, Status> {
+        let len = sectors_of(bufs)?;
+        if len > self.config.max_append_sectors as u64 {
+            return Err(Status::IOERR);
+        }
+        let emulated = match &self.backend {
+            ZoneBackend::Emulated(emulated) => emulated,
+            #[cfg(target_os = "linux")]
+            ZoneBackend::Device { .. } => {
+                let Some(zone) = self.report(disk, sector, 1)?.pop() else {
+                    return Err(Status::ZONE_INVALID_CMD);
+                };
+                if zone.z_start != sector {
+                    return Err(Status::ZONE_INVALID_CMD);
+                }
+                match self.io_status(write_bufs(disk, zone.z_wp, bufs)) {
+                    Status::OK => return Ok(zone.z_wp),
+                    status => return Err(status),
+                }
+            }
+        };
+        let mut emulated = emulated.lock();
+        let index = emulated.zone_at(sector)?;
+        let wp = emulated.zones[index].wp;
+        emulated.check_write(wp, len)?;
+        match self.io_status(write_bufs(disk, wp, bufs)) {
+            Status::OK => {
+                emulated.advance(index, len);
+                Ok(wp)
+            }
+            status => Err(status),
+        }
+    }
+
+    /// Handles `VIRTIO_BLK_T_ZONE_REPORT` for at most `max` zones, starting
+    /// from the zone containing `sector`.
+    pub fn report(
+        &self,
+        disk: &FaultInjector<File>,
+        sector: u64,
+        max: usize,
+    ) -> Result<Vec<ZoneDescriptor>, Status> {
+        let emulated = match &self.backend {
+            ZoneBackend::Emulated(emulated) => emulated,
+            #[cfg(target_os = "linux")]
+            ZoneBackend::Device { .. } => {
+                return self
+                    .report_device(disk.backend(), sector, max)
+                    .map_err(|e| {
+                        log::error!("{}: report zones from sector {sector:#x}: {e}", self.name);
+                        Status::IOERR
+                    })
+            }
+        };
+        let emulated = emulated.lock();
+        let zones = emulated
+            .zones
+            .iter()
+            .skip_while(|z| z.end() <= sector)
+            .take(max)
+            .map(Zone::descriptor)
+            .collect();
+        Ok(zones)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn report_device(
+        &self,
+        disk: &File,
+        sector: u64,
+        max: usize,
+    ) -> io::Result<Vec<ZoneDescriptor>> {
+        const BATCH: usize = 16;
+        let mut zones = Vec::new();
+        let mut sector = sector;
+        while zones.len() < max {
+            let count = std::cmp::min(BATCH, max - zones.len());
+            let mut report = BlkZoneReport {
+                sector,
+                nr_zones: count as u32,
+                flags: 0,
+                zones: [BlkZone::default(); BATCH],
+            };
+            unsafe { blk_report_zone(disk, &mut report) }?;
+            let reported = &report.zones[..report.nr_zones as usize];
+            for zone in reported {
+                let cap = if report.flags & BLK_ZONE_REP_CAPACITY != 0 {
+                    zone.capacity
+                } else {
+                    zone.len
+                };
+                zones.push(ZoneDescriptor {
+                    z_cap: cap,
+                    z_start: zone.start,
+                    z_wp: zone.wp,
+                    z_type: ZoneType::from(zone.type_),
+                    z_state: ZoneState::from(zone.cond),
+                    reserved: [0; 38],
+                });
+            }
+            match reported.last() {
+                Some(last) if reported.len() == count => sector = last.start + last.len,
+                // The end of the device is reached.
+                _ => break,
+            }
+        }
+        Ok(zones)
+    }
+
+    /// Handles `VIRTIO_BLK_T_ZONE_{OPEN,CLOSE,FINISH,RESET,RESET_ALL}`.
+    pub fn manage(&self, disk: &FaultInjector<File>, op: ZoneOp, sector: u64) -> Status {
+        match &self.backend {
+            ZoneBackend::Emulated(emulated) => match emulated.lock().manage(op, sector) {
+                Ok(()) => Status::OK,
+                Err(status) => status,
+            },
+            #[cfg(target_os = "linux")]
+            ZoneBackend::Device { capacity } => {
+                let disk = disk.backend();
+                let range = match op {
+                    ZoneOp::ResetAll => BlkZoneRange {
+                        sector: 0,
+                        nr_sectors: *capacity,
+                    },
+                    _ => BlkZoneRange {
+                        sector,
+                        nr_sectors: self.config.zone_sectors as u64,
+                    },
+                };
+                let ret = unsafe {
+                    match op {
+                        ZoneOp::Open => blk_open_zone(disk, &range),
+                        ZoneOp::Close => blk_close_zone(disk, &range),
+                        ZoneOp::Finish => blk_finish_zone(disk, &range),
+                        ZoneOp::Reset | ZoneOp::ResetAll => blk_reset_zone(disk, &range),
+                    }
+                };
+                self.io_status(ret.map(|_| ()))
+            }
+        }
+    }
+
+    fn io_status(&self, ret: io::Result<()>) -> Status {
+        match ret {
+            Ok(()) => Status::OK,
+            Err(e) => {
+                log::error!("{}: zoned I/O: {e}", self.name);
+                Status::IOERR
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::virtio::dev::blk::zone::{EmulatedZones, ZoneOp, ZoneState};
+    use crate::virtio::dev::blk::Status;
+
+    #[test]
+    fn test_emulated_zones() {
+        let mut zones = EmulatedZones::new(40, 16, 1, 2);
+        assert_eq!(zones.zones.len(), 3);
+        assert_eq!(zones.zones[2].cap, 8);
+
+        assert_eq!(zones.check_write(8, 1), Err(Status::ZONE_UNALIGNED_WP));
+        assert_eq!(zones.check_write(0, 8), Ok(0));
+        zones.advance(0, 8);
+        assert_eq!(zones.zones[0].state, ZoneState::IMP_OPEN);
+        assert_eq!(zones.zones[0].wp, 8);
+        assert_eq!(zones.check_write(8, 9), Err(Status::IOERR));
+
+        // zone 0 is implicitly closed to open zone 1
+        assert_eq!(zones.check_write(16, 4), Ok(1));
+        zones.advance(1, 4);
+        assert_eq!(zones.zones[0].state, ZoneState::CLOSED);
+        // both active zones are in use
+        assert_eq!(
+            zones.manage(ZoneOp::Open, 32),
+            Err(Status::ZONE_ACTIVE_RESOURCE)
+        );
+        assert_eq!(zones.manage(ZoneOp::Finish, 16), Ok(()));
+        assert_eq!(zones.zones[1].state, ZoneState::FULL);
+        assert_eq!(zones.check_write(32, 1), Ok(2));
+        zones.advance(2, 1);
+        // an explicitly opened zone is never closed implicitly
+        assert_eq!(zones.manage(ZoneOp::Open, 32), Ok(()));
+        assert_eq!(zones.zones[2].state, ZoneState::EXP_OPEN);
+        assert_eq!(
+            zones.manage(ZoneOp::Open, 0),
+            Err(Status::ZONE_OPEN_RESOURCE)
+        );
+        assert_eq!(zones.manage(ZoneOp::Close, 32), Ok(()));
+        assert_eq!(zones.zones[2].state, ZoneState::CLOSED);
+        assert_eq!(zones.manage(ZoneOp::Open, 0), Ok(()));
+        assert_eq!(zones.zones[0].state, ZoneState::EXP_OPEN);
+
+        assert_eq!(
+            zones.manage(ZoneOp::Reset, 1),
+            Err(Status::ZONE_INVALID_CMD)
+        );
+        assert_eq!(zones.manage(ZoneOp::Reset, 0), Ok(()));
+        assert_eq!(zones.zones[0].state, ZoneState::EMPTY);
+        assert_eq!(zones.manage(ZoneOp::ResetAll, 0), Ok(()));
+        assert!(zones.zones.iter().all(|z| z.wp == z.start));
+    }
+}
@@ -14,36 +14,53 @@
 
 use std::fmt::Debug;
 use std::os::fd::AsRawFd;
-use std::sync::atomic::{AtomicU16, AtomicU64, AtomicU8};
-use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::atomic::{AtomicU16, AtomicU64, AtomicU8, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
 use std::sync::Arc;
 use std::thread::JoinHandle;
+use std::time::Duration;
 
 use bitfield::bitfield;
 use bitflags::Flags;
+use log::LevelFilter;
 use mio::event::Event;
 use mio::unix::SourceFd;
 use mio::{Events, Interest, Poll, Registry, Token, Waker};
 use snafu::ResultExt;
 
+use crate::dev_log;
 use crate::hv::{IoeventFd, IoeventFdRegistry};
+use crate::mem::bounce::BounceBuffer;
 use crate::mem::emulated::Mmio;
 use crate::mem::mapped::RamBus;
 use crate::mem::MemRegion;
+use crate::utils::thread_name;
+#[cfg(target_os = "linux")]
+use crate::vfio::container::VfioContainer;
+use crate::virtio::dev::fault::FaultSpec;
+use crate::virtio::dev::idle::IdleStrategy;
+use crate::virtio::dev::wake::{WakeChannelParam, WakeReceiver, WakeSender};
+use crate::virtio::dev::watchdog::{WatchdogParam, WorkerWatchdog};
 use crate::virtio::queue::split::SplitQueue;
 use crate::virtio::queue::{Queue, VirtQueue, QUEUE_SIZE_MAX};
-use crate::virtio::{error, DeviceId, IrqSender, Result, VirtioFeature};
+use crate::virtio::{error, DevStatus, DeviceId, IrqSender, LogLevel, Result, VirtioFeature};
 
+#[path = "blk/blk.rs"]
 pub mod blk;
 pub mod entropy;
+pub mod fault;
 #[cfg(target_os = "linux")]
 pub mod fs;
+pub mod idle;
 #[cfg(target_os = "linux")]
 #[path = "net/net.rs"]
 pub mod net;
+pub mod sound;
 #[cfg(target_os = "linux")]
 #[path = "vsock/vsock.rs"]
 pub mod vsock;
+pub mod wake;
+pub mod watchdog;
 
 pub trait Virtio: Debug + Send + Sync + 'static {
     type Config: Mmio;
@@ -54,6 +71,16 @@ pub trait Virtio: Debug + Send + Sync + 'static {
     fn device_id() -> DeviceId;
     fn config(&self) -> Arc<Self::Config>;
     fn feature(&self) -> u64;
+    /// Features the driver must accept. Setting `FEATURES_OK` without any
+    /// of them fails.
+    fn required_features(&self) -> u64 {
+        0
+    }
+    /// Rejects the features negotiated by the driver if a feature the device
+    /// depends on is missing.
+    fn check_features(&self, _feature: u64) -> Result<()> {
+        Ok(())
+    }
     fn activate(
         &mut self,
         registry: &Registry,
@@ -85,16 +112,72 @@ pub trait Virtio: Debug + Send + Sync + 'static {
     {
         Ok(false)
     }
+    /// Stack size of the worker thread, or `None` for the default size.
+    fn worker_stack_size(&self) -> Option<usize> {
+        None
+    }
+    /// PCI subsystem device ID, or `None` for the one defined by the spec.
+    fn subsystem_device_id(&self) -> Option<u16> {
+        None
+    }
+    /// Watchdog of the worker thread, or `None` for no watchdog. The
+    /// timeout should be longer than any event the device handles.
+    fn watchdog(&self) -> Option<WatchdogParam> {
+        None
+    }
+    /// Limits of the channel carrying wake events to the worker thread.
+    fn wake_channel(&self) -> WakeChannelParam {
+        WakeChannelParam::default()
+    }
+    /// How the worker thread waits for events once the device is started.
+    fn idle_strategy(&self) -> IdleStrategy {
+        IdleStrategy::default()
+    }
+    /// How long the device waits for the worker thread on shutdown.
+    fn shutdown_param(&self) -> ShutdownParam {
+        ShutdownParam::default()
+    }
+    /// Makes the backend fail I/O as described by `spec`. Returns `false`
+    /// if the device does not support fault injection.
+    fn inject_fault(&mut self, _spec: FaultSpec) -> bool {
+        false
+    }
 }
 
 #[derive(Debug, Default)]
 pub struct Register {
     pub device_feature: u64,
+    pub required_feature: u64,
     pub driver_feature: AtomicU64,
     pub device_feature_sel: AtomicU8,
     pub driver_feature_sel: AtomicU8,
     pub queue_sel: AtomicU16,
     pub status: AtomicU8,
+    pub config_generation: AtomicU8,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QueueInfo {
+    pub size: u16,
+    pub enabled: bool,
+    pub desc: u64,
+    pub driver: u64,
+    pub device: u64,
+    /// The MSI-X vector of the queue, if the transport has one assigned.
+    pub msix_vector: Option<u16>,
+}
+
+/// A snapshot of the registers of a device, for introspection.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceDescription {
+    pub name: String,
+    pub device_id: DeviceId,
+    pub num_queues: u16,
+    pub features_device: u64,
+    /// Features acknowledged by the driver.
+    pub features_driver: u64,
+    pub status: DevStatus,
+    pub queue_infos: Vec<QueueInfo>,
 }
 
 const TOKEN_IS_QUEUE: u64 = 1 << 63;
@@ -117,6 +200,45 @@ where
     Shutdown,
     Start { feature: u64, irq_sender: Arc<S> },
     Reset,
+    InjectFault(FaultSpec),
+}
+
+/// How a device worker retries a queue whose handler failed with a
+/// transient error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    /// The delay before the first retry, doubled for every further retry.
+    pub backoff_ms: u64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_retries: 3,
+            backoff_ms: 10,
+        }
+    }
+}
+
+impl RetryPolicy {
+    fn backoff(&self, retry: u32) -> Duration {
+        let factor = 1u64.checked_shl(retry).unwrap_or(u64::MAX);
+        Duration::from_millis(self.backoff_ms.saturating_mul(factor))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ShutdownParam {
+    /// How long to wait for the worker thread to exit. A worker that does
+    /// not exit in time is left behind and the device needs a reset.
+    pub timeout_ms: u64,
+}
+
+impl Default for ShutdownParam {
+    fn default() -> Self {
+        ShutdownParam { timeout_ms: 5000 }
+    }
 }
 
 #[derive(Debug)]
@@ -133,9 +255,16 @@ where
     dev: D,
     poll: Poll,
     memory: Arc<RamBus>,
-    event_rx: Receiver<WakeEvent<S>>,
+    reg: Arc<Register>,
+    bounce_buffer: Option<Arc<BounceBuffer>>,
+    event_rx: WakeReceiver<WakeEvent<S>>,
     queue_regs: Arc<Vec<Queue>>,
     queues: Queues,
+    retry_policy: RetryPolicy,
+    idle_strategy: IdleStrategy,
+    log_level: LogLevel,
+    /// Odd while the worker is busy, see [`WorkerWatchdog`].
+    heartbeat: Arc<AtomicU64>,
 }
 
 #[derive(Debug)]
@@ -151,8 +280,22 @@ where
     pub queue_regs: Arc<Vec<Queue>>,
     pub ioeventfds: Arc<Vec<E>>,
     pub shared_mem_regions: Option<Arc<MemRegion>>,
+    pub subsystem_device_id: Option<u16>,
+    /// Interrupts the `poll` of the worker. On Linux it is an eventfd,
+    /// which mio resets and writes again if the counter would overflow,
+    /// so a wake never gets lost. The worker must not read the eventfd
+    /// itself: it is registered edge-triggered, and draining it before
+    /// `poll` would drop a pending wake.
     pub waker: Arc<Waker>,
-    pub event_tx: Sender<WakeEvent<S>>,
+    pub event_tx: WakeSender<WakeEvent<S>>,
+    pub log_level: LogLevel,
+    /// The container whose IOMMU maps guest RAM at identical IOVAs, so
+    /// addresses from the driver can still be used as guest physical
+    /// addresses.
+    #[cfg(target_os = "linux")]
+    pub iommu: Option<Arc<VfioContainer>>,
+    watchdog: Option<WorkerWatchdog>,
+    shutdown_param: ShutdownParam,
     worker_handle: Option<JoinHandle<()>>,
 }
 
@@ -166,10 +309,29 @@ where
         let Some(handle) = self.worker_handle.take() else {
             return Ok(());
         };
+        self.watchdog.take();
         self.event_tx.send(WakeEvent::Shutdown)?;
         self.waker.wake()?;
-        if let Err(e) = handle.join() {
-            log::error!("{}: failed to join worker thread: {e:?}", self.name)
+        // A worker stuck in a blocking call would hang the VMM in join(),
+        // so the thread is joined by a monitor that may be left behind.
+        let (done_tx, done_rx) = mpsc::channel();
+        let name = self.name.clone();
+        std::thread::Builder::new()
+            .name(thread_name(&format!("{name}-join")))
+            .spawn(move || {
+                if let Err(e) = handle.join() {
+                    log::error!("{name}: failed to join worker thread: {e:?}")
+                }
+                let _ = done_tx.send(());
+            })?;
+        let timeout = Duration::from_millis(self.shutdown_param.timeout_ms);
+        if let Err(RecvTimeoutError::Timeout) = done_rx.recv_timeout(timeout) {
+            log::error!(
+                "{}: worker did not exit within {timeout:?}, detached",
+                self.name
+            );
+            let needs_reset = DevStatus::NEEDS_RESET.bits();
+            self.reg.status.fetch_or(needs_reset, Ordering::AcqRel);
         }
         Ok(())
     }
@@ -180,6 +342,8 @@ where
         memory: Arc<RamBus>,
         registry: &R,
         restricted_memory: bool,
+        bounce_buffer_size: Option<u64>,
+        #[cfg(target_os = "linux")] iommu: Option<Arc<VfioContainer>>,
     ) -> Result<Self>
     where
         R: IoeventFdRegistry<IoeventFd = E>,
@@ -187,13 +351,23 @@ where
         let poll = Poll::new().context(error::CreatePoll)?;
         let device_config = dev.config();
         let mut dev_feat = dev.feature();
-        if restricted_memory {
-            dev_feat |= VirtioFeature::ACCESS_PLATFORM.bits()
+        #[cfg(target_os = "linux")]
+        let access_platform = restricted_memory || iommu.is_some();
+        #[cfg(not(target_os = "linux"))]
+        let access_platform = restricted_memory;
+        let platform = VirtioFeature::ACCESS_PLATFORM | VirtioFeature::ORDER_PLATFORM;
+        if access_platform {
+            dev_feat |= platform.bits()
         } else {
-            dev_feat &= !VirtioFeature::ACCESS_PLATFORM.bits()
+            dev_feat &= !platform.bits()
         }
+        let bounce_buffer = match bounce_buffer_size {
+            Some(size) if restricted_memory => Some(Arc::new(BounceBuffer::new(size as usize)?)),
+            _ => None,
+        };
         let reg = Arc::new(Register {
             device_feature: dev_feat,
+            required_feature: dev.required_features() & dev_feat,
             ..Default::default()
         });
         let num_queues = dev.num_queues();
@@ -222,24 +396,55 @@ where
         let waker =
             Waker::new(poll.registry(), Token(token as usize)).context(error::CreateWaker)?;
         let shared_mem_regions = dev.shared_mem_regions();
-        let (event_tx, event_rx) = mpsc::channel();
+        if let Some(region) = &shared_mem_regions {
+            // The window is sized by the VMM, not by the guest.
+            memory.budget().grow(region.size());
+        }
+        let subsystem_device_id = dev.subsystem_device_id();
+        let watchdog = dev.watchdog();
+        let (event_tx, event_rx) = wake::channel(name.clone(), dev.wake_channel());
+        let idle_strategy = dev.idle_strategy();
+        let shutdown_param = dev.shutdown_param();
+        let log_level = LogLevel::default();
+        let heartbeat = Arc::new(AtomicU64::new(1));
         let mut device_worker = DeviceWorker {
             name: name.clone(),
             dev,
             poll,
             event_rx,
             memory,
+            reg: reg.clone(),
+            bounce_buffer,
             queue_regs: queue_regs.clone(),
             queues: Queues::Split(Vec::new()),
+            retry_policy: RetryPolicy::default(),
+            idle_strategy,
+            log_level: log_level.clone(),
+            heartbeat: heartbeat.clone(),
         };
-        let handle = std::thread::Builder::new()
-            .name(name.as_ref().to_owned())
+        let mut builder = std::thread::Builder::new().name(thread_name(&name));
+        if let Some(size) = device_worker.dev.worker_stack_size() {
+            builder = builder.stack_size(size);
+        }
+        // If the thread cannot be spawned, the worker is dropped with the
+        // closure, closing the poll, the waker, and the ioeventfds.
+        let handle = builder
             .spawn(move || {
                 let r = device_worker.do_work();
                 if let Err(e) = r {
-                    log::error!("worker {}: {e}", device_worker.name)
+                    dev_log!(
+                        device_worker.log_level,
+                        Error,
+                        "worker {}: {e}",
+                        device_worker.name
+                    )
                 } else {
-                    log::debug!("worker {}: done", device_worker.name)
+                    dev_log!(
+                        device_worker.log_level,
+                        Debug,
+                        "worker {}: done",
+                        device_worker.name
+                    )
                 }
             })
             .context(error::WorkerThread)?;
@@ -248,19 +453,80 @@ where
             VirtioFeature::from_bits_retain(reg.device_feature & !D::Feature::all().bits()),
             D::Feature::from_bits_truncate(reg.device_feature)
         );
-        let virtio_dev = VirtioDevice {
-            name,
+        let mut virtio_dev = VirtioDevice {
+            name: name.clone(),
             reg,
             queue_regs,
             ioeventfds,
             worker_handle: Some(handle),
             event_tx,
+            log_level,
             waker: Arc::new(waker),
             device_config,
             shared_mem_regions,
+            subsystem_device_id,
+            #[cfg(target_os = "linux")]
+            iommu,
+            watchdog: None,
+            shutdown_param,
         };
+        if let Some(param) = watchdog {
+            let reg = virtio_dev.reg.clone();
+            let watchdog =
+                WorkerWatchdog::new(name, heartbeat, param, reg).context(error::WorkerThread)?;
+            virtio_dev.watchdog = Some(watchdog);
+        }
         Ok(virtio_dev)
     }
+
+    pub fn describe(&self) -> DeviceDescription {
+        let queue_infos = self.queue_regs.iter().map(|q| QueueInfo {
+            size: q.size.load(Ordering::Acquire),
+            enabled: q.enabled.load(Ordering::Acquire),
+            desc: q.desc.load(Ordering::Acquire),
+            driver: q.driver.load(Ordering::Acquire),
+            device: q.device.load(Ordering::Acquire),
+            msix_vector: None,
+        });
+        DeviceDescription {
+            name: self.name.to_string(),
+            device_id: D::device_id(),
+            num_queues: self.queue_regs.len() as u16,
+            features_device: self.reg.device_feature,
+            features_driver: self.reg.driver_feature.load(Ordering::Acquire),
+            status: DevStatus::from_bits_retain(self.reg.status.load(Ordering::Acquire)),
+            queue_infos: queue_infos.collect(),
+        }
+    }
+
+    /// Limits the log messages of the device worker and the transport to
+    /// `level`, on top of the global log level.
+    pub fn set_log_level(&self, level: LevelFilter) {
+        self.log_level.set(level)
+    }
+
+    /// Asks the worker to process queue `q_index` as if the driver had
+    /// notified it.
+    pub fn notify_queue(&self, q_index: u16) -> Result<()> {
+        if q_index as usize >= self.queue_regs.len() {
+            return error::InvalidQueueIndex { index: q_index }.fail();
+        }
+        if self.event_tx.send(WakeEvent::Notify { q_index }).is_err() {
+            return error::WorkerExited.fail();
+        }
+        self.waker.wake()?;
+        Ok(())
+    }
+
+    /// Asks the worker to inject a fault into the device backend, for
+    /// testing how the driver handles I/O errors.
+    pub fn inject_fault(&self, spec: FaultSpec) -> Result<()> {
+        if self.event_tx.send(WakeEvent::InjectFault(spec)).is_err() {
+            return error::WorkerExited.fail();
+        }
+        self.waker.wake()?;
+        Ok(())
+    }
 }
 
 impl<D, S, E> Drop for VirtioDevice<D, S, E>
@@ -289,9 +555,39 @@ where
     S: IrqSender,
 {
     fn notify_queue(&mut self, q_index: u16, irq_sender: &S) -> Result<()> {
-        let registry = self.poll.registry();
-        match &self.queues {
-            Queues::Split(qs) => self.dev.handle_queue(q_index, qs, irq_sender, registry),
+        let mut retry = 0;
+        loop {
+            let registry = self.poll.registry();
+            let ret = match &self.queues {
+                Queues::Split(qs) => self.dev.handle_queue(q_index, qs, irq_sender, registry),
+            };
+            let Err(e) = ret else {
+                return Ok(());
+            };
+            if !e.is_transient() {
+                return Err(e);
+            }
+            if retry >= self.retry_policy.max_retries {
+                dev_log!(
+                    self.log_level,
+                    Error,
+                    "{}: queue {q_index}: giving up after {retry} retries",
+                    self.name
+                );
+                let needs_reset = DevStatus::NEEDS_RESET.bits();
+                self.reg.status.fetch_or(needs_reset, Ordering::AcqRel);
+                irq_sender.config_irq();
+                return Err(e);
+            }
+            let delay = self.retry_policy.backoff(retry);
+            dev_log!(
+                self.log_level,
+                Warn,
+                "{}: queue {q_index}: {e}, retrying in {delay:?}",
+                self.name
+            );
+            std::thread::sleep(delay);
+            retry += 1;
         }
     }
 
@@ -301,34 +597,66 @@ where
                 WakeEvent::Notify { q_index } => self.notify_queue(q_index, irq_sender)?,
                 WakeEvent::Shutdown => return Ok(DevAction::Shutdown),
                 WakeEvent::Start { .. } => {
-                    log::error!("{}: device has already started", self.name)
+                    dev_log!(
+                        self.log_level,
+                        Error,
+                        "{}: device has already started",
+                        self.name
+                    )
                 }
                 WakeEvent::Reset => {
-                    log::info!("{}: device requested reset", self.name);
+                    dev_log!(
+                        self.log_level,
+                        Info,
+                        "{}: device requested reset",
+                        self.name
+                    );
                     return Ok(DevAction::Reset);
                 }
+                WakeEvent::InjectFault(spec) => self.inject_fault(spec),
             }
         }
         Ok(DevAction::Continue)
     }
 
+    fn inject_fault(&mut self, spec: FaultSpec) {
+        if self.dev.inject_fault(spec.clone()) {
+            dev_log!(self.log_level, Info, "{}: injected {spec:?}", self.name);
+        } else {
+            dev_log!(
+                self.log_level,
+                Warn,
+                "{}: device does not support fault injection",
+                self.name
+            );
+        }
+    }
+
+    fn wait_events(&mut self, events: &mut Events, idle: IdleStrategy) -> Result<()> {
+        self.heartbeat.fetch_add(1, Ordering::AcqRel);
+        let ret = idle.wait(&mut self.poll, events);
+        self.heartbeat.fetch_add(1, Ordering::AcqRel);
+        ret
+    }
+
     fn wait_start(&mut self) -> Result<WakeEvent<S>> {
         let mut events = Events::with_capacity(1);
         loop {
-            self.poll
-                .poll(&mut events, None)
-                .context(error::PollEvents)?;
+            self.wait_events(&mut events, IdleStrategy::default())?;
             while let Ok(wake_event) = self.event_rx.try_recv() {
                 match &wake_event {
                     WakeEvent::Start { .. } | WakeEvent::Shutdown | WakeEvent::Reset => {
                         return Ok(wake_event)
                     }
                     WakeEvent::Notify { q_index } => {
-                        log::error!(
+                        dev_log!(
+                            self.log_level,
+                            Error,
                             "{}: driver notified queue {q_index} before device is ready",
                             self.name
                         )
                     }
+                    WakeEvent::InjectFault(spec) => self.inject_fault(spec.clone()),
                 }
             }
         }
@@ -353,17 +681,29 @@ where
     }
 
     fn loop_until_reset(&mut self) -> Result<DevAction> {
-        let WakeEvent::Start {
-            feature,
-            irq_sender,
-        } = self.wait_start()?
-        else {
-            return Ok(DevAction::Shutdown);
+        let (feature, irq_sender) = loop {
+            match self.wait_start()? {
+                WakeEvent::Start {
+                    feature,
+                    irq_sender,
+                } => {
+                    let Err(e) = self.dev.check_features(feature) else {
+                        break (feature, irq_sender);
+                    };
+                    dev_log!(self.log_level, Error, "{}: cannot activate: {e}", self.name);
+                    let needs_reset = DevStatus::NEEDS_RESET.bits();
+                    self.reg.status.fetch_or(needs_reset, Ordering::AcqRel);
+                    irq_sender.config_irq();
+                }
+                WakeEvent::Reset => {}
+                _ => return Ok(DevAction::Shutdown),
+            }
         };
+        let platform = VirtioFeature::ACCESS_PLATFORM | VirtioFeature::ORDER_PLATFORM;
         let memory = &self.memory;
         self.dev.activate(
             self.poll.registry(),
-            feature & !VirtioFeature::ACCESS_PLATFORM.bits(),
+            feature & !platform.bits(),
             memory,
             irq_sender.as_ref(),
             &self.queue_regs,
@@ -372,29 +712,66 @@ where
             if VirtioFeature::from_bits_retain(feature).contains(VirtioFeature::RING_PACKED) {
                 todo!()
             } else {
-                let new_queue = |reg| SplitQueue::new(reg, memory.clone(), feature);
+                let bounce_buffer = &self.bounce_buffer;
+                let new_queue =
+                    |reg| SplitQueue::new(reg, memory.clone(), feature, bounce_buffer.clone());
                 let split_queues = self.queue_regs.iter().map(new_queue).collect();
                 Queues::Split(split_queues)
             };
-        log::debug!(
+        dev_log!(
+            self.log_level,
+            Debug,
             "{}: activated with {:x?} {:x?}",
             self.name,
             VirtioFeature::from_bits_retain(feature & !D::Feature::all().bits()),
             D::Feature::from_bits_truncate(feature)
         );
-        self.handle_wake_events(&irq_sender)?;
+        // A shutdown or reset may arrive together with the start event.
+        let mut ret = self.handle_wake_events(&irq_sender);
         let mut events = Events::with_capacity(128);
-        loop {
-            self.poll
-                .poll(&mut events, None)
-                .context(error::PollEvents)?;
+        while matches!(ret, Ok(DevAction::Continue)) {
+            irq_sender.flush();
+            self.wait_events(&mut events, self.idle_strategy)?;
             for event in events.iter() {
-                let ret = self.handle_event(event, &irq_sender)?;
-                if ret != DevAction::Continue {
-                    return Ok(ret);
+                ret = self.handle_event(event, &irq_sender);
+                if !matches!(ret, Ok(DevAction::Continue)) {
+                    break;
                 }
             }
         }
+        match ret {
+            Err(_) => irq_sender.device_error(),
+            Ok(DevAction::Shutdown) => self.drain_queues(&irq_sender),
+            Ok(_) => {}
+        }
+        irq_sender.flush();
+        ret
+    }
+
+    /// Returns buffers left in the queues to the driver before the device
+    /// goes away, so the driver does not wait for them forever.
+    ///
+    /// This is not done on reset, since the driver may free the rings as
+    /// soon as it sees the device status cleared.
+    fn drain_queues(&self, irq_sender: &S) {
+        let Queues::Split(queues) = &self.queues;
+        for (index, queue) in queues.iter().enumerate() {
+            match queue.drain_used(index as u16, irq_sender) {
+                Ok(0) => {}
+                Ok(count) => dev_log!(
+                    self.log_level,
+                    Info,
+                    "{}: queue {index}: returned {count} descriptors",
+                    self.name
+                ),
+                Err(e) => dev_log!(
+                    self.log_level,
+                    Error,
+                    "{}: queue {index}: failed to drain: {e}",
+                    self.name
+                ),
+            }
+        }
     }
 
     fn do_work(&mut self) -> Result<()> {
@@ -402,8 +779,10 @@ where
             if self.loop_until_reset()? == DevAction::Shutdown {
                 break;
             }
+            // Queue handlers run on this thread and complete every chain
+            // they take before returning, so no chain is in flight here.
             self.dev.reset(self.poll.registry());
-            log::info!("{}: reset done", self.name)
+            dev_log!(self.log_level, Info, "{}: reset done", self.name)
         }
         Ok(())
     }
@@ -412,4 +791,688 @@ where
 pub trait DevParam {
     type Device;
     fn build(self, name: Arc<String>) -> Result<Self::Device>;
+
+    /// Builds a device that needs guest memory at construction, e.g. to
+    /// register the memory regions backing it.
+    fn build_with_memory(self, name: Arc<String>, memory: Arc<RamBus>) -> Result<Self::Device>
+    where
+        Self: Sized,
+    {
+        let _ = memory;
+        self.build(name)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::VecDeque;
+    use std::io::{self, ErrorKind};
+    use std::os::fd::RawFd;
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    use assert_matches::assert_matches;
+    use log::{Level, LevelFilter};
+    use mio::event::Event;
+    use mio::{Poll, Registry};
+    use parking_lot::Mutex;
+
+    use crate::hv::test::{FakeIoeventFdRegistry, FakeVmMemory};
+    use crate::hv::IoeventFd;
+    use crate::mem::mapped::{ArcMemPages, RamBus};
+    use crate::mem::MemRegionType;
+    use crate::virtio::dev::entropy::{EntropyConfig, EntropyFeature};
+    use crate::virtio::dev::idle::IdleStrategy;
+    use crate::virtio::dev::wake::{self, WakeChannelParam};
+    use crate::virtio::dev::watchdog::WatchdogParam;
+    use crate::virtio::dev::{
+        DevParam, DeviceWorker, Queues, Register, RetryPolicy, ShutdownParam, Virtio, VirtioDevice,
+        WakeEvent,
+    };
+    use crate::virtio::queue::handlers::handle_desc;
+    use crate::virtio::queue::split::{Desc, DescFlag};
+    use crate::virtio::queue::{Queue, VirtQueue};
+    use crate::virtio::test::FakeIrqSender;
+    use crate::virtio::{
+        error, DevStatus, DeviceId, Error, IrqSender, LogLevel, Result, VirtioFeature,
+    };
+
+    #[derive(Debug)]
+    struct FlakyDev {
+        failures: u32,
+        calls: u32,
+    }
+
+    impl Virtio for FlakyDev {
+        type Config = EntropyConfig;
+        type Feature = EntropyFeature;
+
+        fn num_queues(&self) -> u16 {
+            1
+        }
+
+        fn reset(&mut self, _registry: &Registry) {}
+
+        fn device_id() -> DeviceId {
+            DeviceId::Entropy
+        }
+
+        fn config(&self) -> Arc<EntropyConfig> {
+            Arc::new(EntropyConfig)
+        }
+
+        fn feature(&self) -> u64 {
+            0
+        }
+
+        fn activate(
+            &mut self,
+            _registry: &Registry,
+            _feature: u64,
+            _memory: &RamBus,
+            _irq_sender: &impl IrqSender,
+            _queues: &[Queue],
+        ) -> Result<()> {
+            Ok(())
+        }
+
+        fn handle_queue(
+            &mut self,
+            index: u16,
+            _queues: &[impl VirtQueue],
+            _irq_sender: &impl IrqSender,
+            _registry: &Registry,
+        ) -> Result<()> {
+            self.calls += 1;
+            if index > 0 {
+                return error::InvalidQueueIndex { index }.fail();
+            }
+            if self.calls <= self.failures {
+                return Err(io::Error::from(ErrorKind::TimedOut).into());
+            }
+            Ok(())
+        }
+
+        fn handle_event(
+            &mut self,
+            _event: &Event,
+            _queues: &[impl VirtQueue],
+            _irq_sender: &impl IrqSender,
+            _registry: &Registry,
+        ) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    fn flaky_worker(failures: u32) -> DeviceWorker<FlakyDev, FakeIrqSender> {
+        let name = Arc::new("flaky".to_owned());
+        let (_, event_rx) = wake::channel(name.clone(), WakeChannelParam::default());
+        DeviceWorker {
+            name,
+            dev: FlakyDev { failures, calls: 0 },
+            poll: Poll::new().unwrap(),
+            memory: Arc::new(RamBus::new(FakeVmMemory)),
+            reg: Arc::new(Register::default()),
+            bounce_buffer: None,
+            event_rx,
+            queue_regs: Arc::new(Vec::new()),
+            queues: Queues::Split(Vec::new()),
+            retry_policy: RetryPolicy {
+                max_retries: 3,
+                backoff_ms: 1,
+            },
+            idle_strategy: IdleStrategy::default(),
+            log_level: LogLevel::default(),
+            heartbeat: Arc::default(),
+        }
+    }
+
+    fn needs_reset(worker: &DeviceWorker<FlakyDev, FakeIrqSender>) -> bool {
+        let status = DevStatus::from_bits_retain(worker.reg.status.load(Ordering::Acquire));
+        status.contains(DevStatus::NEEDS_RESET)
+    }
+
+    #[test]
+    fn test_retry_backoff() {
+        let policy = RetryPolicy {
+            max_retries: 3,
+            backoff_ms: 10,
+        };
+        assert_eq!(policy.backoff(0), Duration::from_millis(10));
+        assert_eq!(policy.backoff(2), Duration::from_millis(40));
+        assert_eq!(policy.backoff(100), Duration::from_millis(u64::MAX));
+    }
+
+    /// Receives packets queued by the host, but like a tap device without
+    /// a readiness event, only when the queue is notified.
+    #[derive(Debug, Default)]
+    struct RxDev {
+        pending: Arc<Mutex<VecDeque<Vec<u8>>>>,
+        stack_size: Option<usize>,
+        activated: Arc<Mutex<Option<u64>>>,
+        watchdog: Option<WatchdogParam>,
+        shutdown_param: ShutdownParam,
+    }
+
+    impl Virtio for RxDev {
+        type Config = EntropyConfig;
+        type Feature = EntropyFeature;
+
+        fn num_queues(&self) -> u16 {
+            1
+        }
+
+        fn reset(&mut self, _registry: &Registry) {}
+
+        fn device_id() -> DeviceId {
+            DeviceId::Entropy
+        }
+
+        fn config(&self) -> Arc<EntropyConfig> {
+            Arc::new(EntropyConfig)
+        }
+
+        fn feature(&self) -> u64 {
+            0
+        }
+
+        fn activate(
+            &mut self,
+            _registry: &Registry,
+            feature: u64,
+            _memory: &RamBus,
+            _irq_sender: &impl IrqSender,
+            _queues: &[Queue],
+        ) -> Result<()> {
+            *self.activated.lock() = Some(feature);
+            Ok(())
+        }
+
+        fn handle_queue(
+            &mut self,
+            index: u16,
+            queues: &[impl VirtQueue],
+            irq_sender: &impl IrqSender,
+            _registry: &Registry,
+        ) -> Result<()> {
+            let Some(queue) = queues.get(index as usize) else {
+                return error::InvalidQueueIndex { index }.fail();
+            };
+            handle_desc("rx", index, queue, irq_sender, |desc| {
+                let Some(packet) = self.pending.lock().pop_front() else {
+                    return Err(ErrorKind::WouldBlock.into());
+                };
+                desc.writable[0][..packet.len()].copy_from_slice(&packet);
+                Ok(packet.len())
+            })
+        }
+
+        fn handle_event(
+            &mut self,
+            _event: &Event,
+            _queues: &[impl VirtQueue],
+            _irq_sender: &impl IrqSender,
+            _registry: &Registry,
+        ) -> Result<()> {
+            Ok(())
+        }
+
+        fn offload_ioeventfd<E>(&self, _q_index: u16, _fd: &E) -> Result<bool>
+        where
+            E: IoeventFd,
+        {
+            Ok(true)
+        }
+
+        fn worker_stack_size(&self) -> Option<usize> {
+            self.stack_size
+        }
+
+        fn watchdog(&self) -> Option<WatchdogParam> {
+            self.watchdog
+        }
+
+        fn shutdown_param(&self) -> ShutdownParam {
+            self.shutdown_param
+        }
+    }
+
+    struct RxParam;
+
+    impl DevParam for RxParam {
+        type Device = RxDev;
+
+        fn build(self, _name: Arc<String>) -> Result<RxDev> {
+            Ok(RxDev::default())
+        }
+    }
+
+    /// Queues a packet found in guest memory when the device is built.
+    struct PrefillParam {
+        gpa: u64,
+    }
+
+    impl DevParam for PrefillParam {
+        type Device = RxDev;
+
+        fn build(self, _name: Arc<String>) -> Result<RxDev> {
+            unreachable!()
+        }
+
+        fn build_with_memory(self, _name: Arc<String>, memory: Arc<RamBus>) -> Result<RxDev> {
+            let packet = memory.read::<[u8; 4]>(self.gpa)?;
+            let dev = RxDev::default();
+            dev.pending.lock().push_back(packet.to_vec());
+            Ok(dev)
+        }
+    }
+
+    #[test]
+    fn test_build_with_memory() {
+        let memory = Arc::new(RamBus::new(FakeVmMemory));
+        let pages = ArcMemPages::from_anonymous(0x1000, None).unwrap();
+        memory.add(0, pages, MemRegionType::Ram, false).unwrap();
+        memory.write(0x10, b"ping").unwrap();
+        let name = Arc::new("rx".to_owned());
+
+        let dev = RxParam
+            .build_with_memory(name.clone(), memory.clone())
+            .unwrap();
+        assert!(dev.pending.lock().is_empty());
+
+        let param = PrefillParam { gpa: 0x10 };
+        let dev = param.build_with_memory(name, memory).unwrap();
+        assert_eq!(dev.pending.lock().pop_front().unwrap(), b"ping");
+    }
+
+    #[test]
+    fn test_notify_queue_rx() {
+        const DESC_ADDR: u64 = 0x0;
+        const AVAIL_ADDR: u64 = 0x100;
+        const USED_ADDR: u64 = 0x200;
+        const BUF_ADDR: u64 = 0x1000;
+
+        let memory = Arc::new(RamBus::new(FakeVmMemory));
+        let pages = ArcMemPages::from_anonymous(0x2000, None).unwrap();
+        memory.add(0, pages, MemRegionType::Ram, false).unwrap();
+        let desc = Desc {
+            addr: BUF_ADDR,
+            len: 0x100,
+            flag: DescFlag::WRITE.bits(),
+            next: 0,
+        };
+        memory.write(DESC_ADDR, &desc).unwrap();
+        // avail.flags = 0, avail.idx = 1, avail.ring[0] = 0
+        memory.write(AVAIL_ADDR, &[0u16, 1, 0]).unwrap();
+
+        let dev = RxDev::default();
+        let pending = dev.pending.clone();
+        let name = Arc::new("rx".to_owned());
+        let mut virtio_dev: VirtioDevice<RxDev, FakeIrqSender, _> = VirtioDevice::new(
+            name,
+            dev,
+            memory.clone(),
+            &FakeIoeventFdRegistry::default(),
+            false,
+            None,
+            #[cfg(target_os = "linux")]
+            None,
+        )
+        .unwrap();
+        let reg = &virtio_dev.queue_regs[0];
+        reg.size.store(4, Ordering::Release);
+        reg.desc.store(DESC_ADDR, Ordering::Release);
+        reg.driver.store(AVAIL_ADDR, Ordering::Release);
+        reg.device.store(USED_ADDR, Ordering::Release);
+        reg.enabled.store(true, Ordering::Release);
+
+        let irq_sender = Arc::new(FakeIrqSender::default());
+        let start = WakeEvent::Start {
+            feature: 0,
+            irq_sender: irq_sender.clone(),
+        };
+        virtio_dev.event_tx.send(start).unwrap();
+        virtio_dev.waker.wake().unwrap();
+
+        // The driver kicked the queue before any packet arrived.
+        virtio_dev.notify_queue(0).unwrap();
+        pending.lock().push_back(b"hello".to_vec());
+        virtio_dev.notify_queue(0).unwrap();
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while irq_sender.queue_irqs.lock().is_empty() {
+            assert!(Instant::now() < deadline);
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(*irq_sender.queue_irqs.lock(), [0]);
+        // used.idx = 1, used.ring[0] = {id: 0, len: 5}
+        assert_eq!(memory.read::<[u16; 2]>(USED_ADDR).unwrap(), [0, 1]);
+        assert_eq!(memory.read::<[u32; 2]>(USED_ADDR + 4).unwrap(), [0, 5]);
+        assert_eq!(&memory.read::<[u8; 5]>(BUF_ADDR).unwrap(), b"hello");
+
+        assert_matches!(
+            virtio_dev.notify_queue(1),
+            Err(Error::InvalidQueueIndex { index: 1, .. })
+        );
+        virtio_dev.shutdown().unwrap();
+        assert_matches!(virtio_dev.notify_queue(0), Err(Error::WorkerExited { .. }));
+    }
+
+    #[test]
+    fn test_drain_used_on_shutdown() {
+        const QUEUE_SIZE: u16 = 16;
+        const DESC_ADDR: u64 = 0x0;
+        const AVAIL_ADDR: u64 = 0x100;
+        const USED_ADDR: u64 = 0x200;
+        const BUF_ADDR: u64 = 0x1000;
+
+        let memory = Arc::new(RamBus::new(FakeVmMemory));
+        let pages = ArcMemPages::from_anonymous(0x2000, None).unwrap();
+        memory.add(0, pages, MemRegionType::Ram, false).unwrap();
+        for i in 0..QUEUE_SIZE {
+            let desc = Desc {
+                addr: BUF_ADDR + i as u64 * 0x10,
+                len: 0x10,
+                flag: DescFlag::WRITE.bits(),
+                next: 0,
+            };
+            memory.write(DESC_ADDR + i as u64 * 16, &desc).unwrap();
+            memory.write(AVAIL_ADDR + 4 + i as u64 * 2, &i).unwrap();
+        }
+        // 10 buffers available, none of which can be filled
+        memory.write(AVAIL_ADDR, &[0u16, 10]).unwrap();
+
+        let mut virtio_dev: VirtioDevice<RxDev, FakeIrqSender, _> = VirtioDevice::new(
+            Arc::new("rx".to_owned()),
+            RxDev::default(),
+            memory.clone(),
+            &FakeIoeventFdRegistry::default(),
+            false,
+            None,
+            #[cfg(target_os = "linux")]
+            None,
+        )
+        .unwrap();
+        let reg = &virtio_dev.queue_regs[0];
+        reg.size.store(QUEUE_SIZE, Ordering::Release);
+        reg.desc.store(DESC_ADDR, Ordering::Release);
+        reg.driver.store(AVAIL_ADDR, Ordering::Release);
+        reg.device.store(USED_ADDR, Ordering::Release);
+        reg.enabled.store(true, Ordering::Release);
+
+        let irq_sender = Arc::new(FakeIrqSender::default());
+        let start = WakeEvent::Start {
+            feature: 0,
+            irq_sender: irq_sender.clone(),
+        };
+        virtio_dev.event_tx.send(start).unwrap();
+        virtio_dev.waker.wake().unwrap();
+        virtio_dev.notify_queue(0).unwrap();
+
+        virtio_dev.shutdown().unwrap();
+        let [_flags, used_index] = memory.read::<[u16; 2]>(USED_ADDR).unwrap();
+        assert_eq!(used_index, 10);
+        for i in 0..10 {
+            let elem = memory.read::<[u32; 2]>(USED_ADDR + 4 + i * 8).unwrap();
+            assert_eq!(elem, [i as u32, 0]);
+        }
+        assert_eq!(*irq_sender.queue_irqs.lock(), [0]);
+    }
+
+    #[test]
+    fn test_watchdog() {
+        const TIMEOUT: Duration = Duration::from_millis(100);
+        const DESC_ADDR: u64 = 0x0;
+        const AVAIL_ADDR: u64 = 0x100;
+        const USED_ADDR: u64 = 0x200;
+        const BUF_ADDR: u64 = 0x1000;
+
+        let memory = Arc::new(RamBus::new(FakeVmMemory));
+        let pages = ArcMemPages::from_anonymous(0x2000, None).unwrap();
+        memory.add(0, pages, MemRegionType::Ram, false).unwrap();
+        let desc = Desc {
+            addr: BUF_ADDR,
+            len: 0x100,
+            flag: DescFlag::WRITE.bits(),
+            next: 0,
+        };
+        memory.write(DESC_ADDR, &desc).unwrap();
+        memory.write(AVAIL_ADDR, &[0u16, 1, 0]).unwrap();
+
+        let dev = RxDev {
+            watchdog: Some(WatchdogParam {
+                timeout_ms: TIMEOUT.as_millis() as u64,
+            }),
+            ..Default::default()
+        };
+        let pending = dev.pending.clone();
+        let virtio_dev: VirtioDevice<RxDev, FakeIrqSender, _> = VirtioDevice::new(
+            Arc::new("rx".to_owned()),
+            dev,
+            memory.clone(),
+            &FakeIoeventFdRegistry::default(),
+            false,
+            None,
+            #[cfg(target_os = "linux")]
+            None,
+        )
+        .unwrap();
+        let reg = &virtio_dev.queue_regs[0];
+        reg.size.store(4, Ordering::Release);
+        reg.desc.store(DESC_ADDR, Ordering::Release);
+        reg.driver.store(AVAIL_ADDR, Ordering::Release);
+        reg.device.store(USED_ADDR, Ordering::Release);
+        reg.enabled.store(true, Ordering::Release);
+        let watchdog = virtio_dev.watchdog.as_ref().unwrap();
+
+        let irq_sender = Arc::new(FakeIrqSender::default());
+        let start = WakeEvent::Start {
+            feature: 0,
+            irq_sender: irq_sender.clone(),
+        };
+        virtio_dev.event_tx.send(start).unwrap();
+        virtio_dev.waker.wake().unwrap();
+        // An idle worker is not stuck.
+        std::thread::sleep(TIMEOUT * 2);
+        assert_eq!(watchdog.stalls(), 0);
+
+        let mut packets = pending.lock();
+        let start = Instant::now();
+        virtio_dev.notify_queue(0).unwrap();
+        while watchdog.stalls() == 0 {
+            assert!(start.elapsed() < TIMEOUT * 4);
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        // Allows some slack for scheduling the worker and the watchdog.
+        assert!(start.elapsed() < TIMEOUT * 2 + TIMEOUT / 2);
+        let status = DevStatus::from_bits_retain(virtio_dev.reg.status.load(Ordering::Acquire));
+        assert!(status.contains(DevStatus::NEEDS_RESET));
+        packets.push_back(b"hello".to_vec());
+        drop(packets);
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while irq_sender.queue_irqs.lock().is_empty() {
+            assert!(Instant::now() < deadline);
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(watchdog.stalls(), 1);
+    }
+
+    #[test]
+    fn test_shutdown_timeout() {
+        const TIMEOUT: Duration = Duration::from_millis(200);
+        const DESC_ADDR: u64 = 0x0;
+        const AVAIL_ADDR: u64 = 0x100;
+        const USED_ADDR: u64 = 0x200;
+        const BUF_ADDR: u64 = 0x1000;
+
+        assert_eq!(ShutdownParam::default().timeout_ms, 5000);
+
+        let memory = Arc::new(RamBus::new(FakeVmMemory));
+        let pages = ArcMemPages::from_anonymous(0x2000, None).unwrap();
+        memory.add(0, pages, MemRegionType::Ram, false).unwrap();
+        let desc = Desc {
+            addr: BUF_ADDR,
+            len: 0x100,
+            flag: DescFlag::WRITE.bits(),
+            next: 0,
+        };
+        memory.write(DESC_ADDR, &desc).unwrap();
+        memory.write(AVAIL_ADDR, &[0u16, 1, 0]).unwrap();
+
+        let dev = RxDev {
+            shutdown_param: ShutdownParam {
+                timeout_ms: TIMEOUT.as_millis() as u64,
+            },
+            ..Default::default()
+        };
+        let pending = dev.pending.clone();
+        let mut virtio_dev: VirtioDevice<RxDev, FakeIrqSender, _> = VirtioDevice::new(
+            Arc::new("rx".to_owned()),
+            dev,
+            memory.clone(),
+            &FakeIoeventFdRegistry::default(),
+            false,
+            None,
+            #[cfg(target_os = "linux")]
+            None,
+        )
+        .unwrap();
+        let reg = &virtio_dev.queue_regs[0];
+        reg.size.store(4, Ordering::Release);
+        reg.desc.store(DESC_ADDR, Ordering::Release);
+        reg.driver.store(AVAIL_ADDR, Ordering::Release);
+        reg.device.store(USED_ADDR, Ordering::Release);
+        reg.enabled.store(true, Ordering::Release);
+
+        let start = WakeEvent::Start {
+            feature: 0,
+            irq_sender: Arc::new(FakeIrqSender::default()),
+        };
+        virtio_dev.event_tx.send(start).unwrap();
+        // The worker blocks in handle_queue() until the lock is released.
+        let packets = pending.lock();
+        virtio_dev.notify_queue(0).unwrap();
+
+        let start = Instant::now();
+        virtio_dev.shutdown().unwrap();
+        let elapsed = start.elapsed();
+        assert!(elapsed >= TIMEOUT, "{elapsed:?}");
+        assert!(elapsed < TIMEOUT * 3, "{elapsed:?}");
+        assert!(virtio_dev.worker_handle.is_none());
+        let status = DevStatus::from_bits_retain(virtio_dev.reg.status.load(Ordering::Acquire));
+        assert!(status.contains(DevStatus::NEEDS_RESET));
+        drop(packets);
+    }
+
+    #[test]
+    fn test_order_platform() {
+        let dev = RxDev::default();
+        let activated = dev.activated.clone();
+        let virtio_dev: VirtioDevice<RxDev, FakeIrqSender, _> = VirtioDevice::new(
+            Arc::new("rx".to_owned()),
+            dev,
+            Arc::new(RamBus::new(FakeVmMemory)),
+            &FakeIoeventFdRegistry::default(),
+            true,
+            None,
+            #[cfg(target_os = "linux")]
+            None,
+        )
+        .unwrap();
+        let platform = VirtioFeature::ACCESS_PLATFORM | VirtioFeature::ORDER_PLATFORM;
+        assert_eq!(
+            virtio_dev.reg.device_feature & platform.bits(),
+            platform.bits()
+        );
+
+        let irq_sender = Arc::new(FakeIrqSender::default());
+        let start = WakeEvent::Start {
+            feature: platform.bits() | VirtioFeature::VERSION_1.bits(),
+            irq_sender: irq_sender.clone(),
+        };
+        virtio_dev.event_tx.send(start).unwrap();
+        virtio_dev.waker.wake().unwrap();
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while activated.lock().is_none() {
+            assert!(Instant::now() < deadline);
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        // Both platform features are handled by the queues, not the device.
+        assert_eq!(*activated.lock(), Some(VirtioFeature::VERSION_1.bits()));
+        assert_eq!(irq_sender.config_irqs.load(Ordering::Acquire), 0);
+    }
+
+    #[test]
+    fn test_notify_queue_retry() {
+        let irq_sender = FakeIrqSender::default();
+
+        let mut worker = flaky_worker(3);
+        worker.notify_queue(0, &irq_sender).unwrap();
+        assert_eq!(worker.dev.calls, 4);
+        assert!(!needs_reset(&worker));
+        assert_eq!(irq_sender.config_irqs.load(Ordering::Acquire), 0);
+
+        let mut worker = flaky_worker(4);
+        let ret = worker.notify_queue(0, &irq_sender);
+        assert_matches!(ret, Err(e) if e.is_transient());
+        assert_eq!(worker.dev.calls, 4);
+        assert!(needs_reset(&worker));
+        assert_eq!(irq_sender.config_irqs.load(Ordering::Acquire), 1);
+
+        // other errors are not retried
+        let mut worker = flaky_worker(0);
+        let ret = worker.notify_queue(1, &irq_sender);
+        assert_matches!(ret, Err(Error::InvalidQueueIndex { index: 1, .. }));
+        assert_eq!(worker.dev.calls, 1);
+        assert!(!needs_reset(&worker));
+    }
+
+    #[test]
+    fn test_log_level() {
+        let log_level = LogLevel::default();
+        let worker_level = log_level.clone();
+        assert!(worker_level.enabled(Level::Trace));
+
+        log_level.set(LevelFilter::Warn);
+        assert!(worker_level.enabled(Level::Error));
+        assert!(worker_level.enabled(Level::Warn));
+        assert!(!worker_level.enabled(Level::Info));
+
+        log_level.set(LevelFilter::Off);
+        assert!(!worker_level.enabled(Level::Error));
+    }
+
+    #[test]
+    fn test_worker_spawn_failure() {
+        let dev = RxDev {
+            // Larger than the address space, so the stack cannot be mapped.
+            stack_size: Some(1 << 50),
+            ..Default::default()
+        };
+        let registry = FakeIoeventFdRegistry::default();
+        let ret: Result<VirtioDevice<RxDev, FakeIrqSender, _>> = VirtioDevice::new(
+            Arc::new("rx".to_owned()),
+            dev,
+            Arc::new(RamBus::new(FakeVmMemory)),
+            &registry,
+            false,
+            None,
+            #[cfg(target_os = "linux")]
+            None,
+        );
+        assert_matches!(ret, Err(Error::WorkerThread { .. }));
+
+        let fds = registry.fds.lock();
+        assert_eq!(fds.len(), 1);
+        let open_fds: Vec<RawFd> = std::fs::read_dir("/proc/self/fd")
+            .unwrap()
+            .filter_map(|e| e.ok()?.file_name().to_str()?.parse().ok())
+            .collect();
+        for fd in fds.iter() {
+            assert!(!open_fds.contains(fd), "ioeventfd {fd} is leaked");
+        }
+    }
 }
@@ -14,15 +14,20 @@
 
 use std::fmt::Debug;
 use std::fs::{File, OpenOptions};
+use std::io::{self, ErrorKind, IoSliceMut, Read};
 use std::os::unix::prelude::OpenOptionsExt;
+use std::path::Path;
 use std::sync::Arc;
 
 use bitflags::bitflags;
 use libc::O_NONBLOCK;
 use mio::event::Event;
 use mio::Registry;
+use serde::Deserialize;
 use snafu::ResultExt;
 
+#[cfg(target_os = "linux")]
+use crate::ffi;
 use crate::mem;
 use crate::mem::emulated::{Action, Mmio};
 use crate::mem::mapped::RamBus;
@@ -53,22 +58,115 @@ bitflags! {
     pub struct EntropyFeature: u64 { }
 }
 
+const HWRNG_PATH: &str = "/dev/hwrng";
+const URANDOM_PATH: &str = "/dev/urandom";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+pub enum RngSource {
+    /// The hardware TRNG, backed by `/dev/urandom` when it is missing or
+    /// has no data ready.
+    #[default]
+    #[serde(alias = "hwrng")]
+    Hwrng,
+    #[serde(alias = "urandom")]
+    Urandom,
+    /// The `getrandom(2)` syscall.
+    #[cfg(target_os = "linux")]
+    #[serde(alias = "getrandom")]
+    Getrandom,
+}
+
+#[derive(Debug)]
+enum Source {
+    Hwrng {
+        hwrng: File,
+        urandom: File,
+    },
+    Urandom(File),
+    #[cfg(target_os = "linux")]
+    Getrandom,
+}
+
+fn open_nonblock(path: &Path) -> io::Result<File> {
+    let mut options = OpenOptions::new();
+    options.custom_flags(O_NONBLOCK).read(true);
+    options.open(path)
+}
+
+impl Source {
+    fn new(name: &str, source: RngSource) -> Result<Self> {
+        let path = Path::new(URANDOM_PATH);
+        let urandom = || open_nonblock(path).context(error::AccessFile { path });
+        let source = match source {
+            RngSource::Hwrng => match Self::open_hwrng(Path::new(HWRNG_PATH)) {
+                Ok(hwrng) => Source::Hwrng {
+                    hwrng,
+                    urandom: urandom()?,
+                },
+                Err(e) => {
+                    log::info!("{name}: {HWRNG_PATH} is not usable: {e}, using {URANDOM_PATH}");
+                    Source::Urandom(urandom()?)
+                }
+            },
+            RngSource::Urandom => Source::Urandom(urandom()?),
+            #[cfg(target_os = "linux")]
+            RngSource::Getrandom => Source::Getrandom,
+        };
+        Ok(source)
+    }
+
+    fn open_hwrng(path: &Path) -> io::Result<File> {
+        let mut hwrng = open_nonblock(path)?;
+        // A hardware RNG without a backing device returns no data.
+        match hwrng.read(&mut [0u8; 1]) {
+            Ok(0) => Err(ErrorKind::UnexpectedEof.into()),
+            Err(e) if e.kind() != ErrorKind::WouldBlock => Err(e),
+            _ => Ok(hwrng),
+        }
+    }
+}
+
+impl Read for Source {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.read_vectored(&mut [IoSliceMut::new(buf)])
+    }
+
+    fn read_vectored(&mut self, bufs: &mut [IoSliceMut<'_>]) -> io::Result<usize> {
+        match self {
+            Source::Hwrng { hwrng, urandom } => match hwrng.read_vectored(bufs) {
+                Ok(0) => urandom.read_vectored(bufs),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => urandom.read_vectored(bufs),
+                r => r,
+            },
+            Source::Urandom(urandom) => urandom.read_vectored(bufs),
+            #[cfg(target_os = "linux")]
+            Source::Getrandom => {
+                let Some(buf) = bufs.iter_mut().find(|b| !b.is_empty()) else {
+                    return Ok(0);
+                };
+                let flags = libc::GRND_NONBLOCK;
+                let ret =
+                    ffi!(unsafe { libc::getrandom(buf.as_mut_ptr() as _, buf.len(), flags) })?;
+                Ok(ret as usize)
+            }
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Entropy {
     name: Arc<String>,
-    source: File,
+    source: Source,
     config: Arc<EntropyConfig>,
 }
 
 impl Entropy {
-    pub fn new(name: Arc<String>) -> Result<Self> {
-        let mut options = OpenOptions::new();
-        options.custom_flags(O_NONBLOCK).read(true);
-        let path = "/dev/urandom";
-        let file = options.open(path).context(error::AccessFile { path })?;
+    pub fn new(name: Arc<String>, source: RngSource) -> Result<Self> {
+        let source = Source::new(&name, source)?;
+        log::debug!("{name}: using {source:?}");
         Ok(Entropy {
             name,
-            source: file,
+            source,
             config: Arc::new(EntropyConfig),
         })
     }
@@ -132,11 +230,46 @@ impl Virtio for Entropy {
     }
 }
 
-pub struct EntropyParam;
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct EntropyParam {
+    #[serde(default)]
+    pub source: RngSource,
+}
 
 impl DevParam for EntropyParam {
     type Device = Entropy;
     fn build(self, name: Arc<String>) -> Result<Self::Device> {
-        Entropy::new(name)
+        Entropy::new(name, self.source)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{IoSliceMut, Read};
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    use crate::virtio::dev::entropy::{Entropy, RngSource};
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_rng_sources() {
+        for source in [RngSource::Hwrng, RngSource::Urandom, RngSource::Getrandom] {
+            let name = Arc::new(format!("entropy-{source:?}"));
+            let mut entropy = Entropy::new(name, source).unwrap();
+            let mut buf = [0u8; 4096];
+            let start = Instant::now();
+            let mut filled = 0;
+            while filled < buf.len() {
+                let len = entropy
+                    .source
+                    .read_vectored(&mut [IoSliceMut::new(&mut buf[filled..])])
+                    .unwrap();
+                assert_ne!(len, 0, "{source:?}");
+                filled += len;
+            }
+            assert!(start.elapsed() < Duration::from_millis(10), "{source:?}");
+            assert!(buf.iter().any(|b| *b != 0), "{source:?}");
+        }
     }
 }
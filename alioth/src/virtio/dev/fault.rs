@@ -0,0 +1,183 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::io::{self, ErrorKind};
+use std::ops::Range;
+use std::sync::Mutex;
+
+use crate::virtio::dev::blk::BlockBackend;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultOp {
+    Read,
+    Write,
+}
+
+/// Fails the `nth_occurrence`-th `op` touching `offset_range` with `error`,
+/// counting from 1.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FaultSpec {
+    pub offset_range: Range<u64>,
+    pub op: FaultOp,
+    pub nth_occurrence: u32,
+    pub error: ErrorKind,
+}
+
+impl FaultSpec {
+    fn matches(&self, op: FaultOp, offset: u64, len: usize) -> bool {
+        self.op == op
+            && offset < self.offset_range.end
+            && offset + len as u64 > self.offset_range.start
+    }
+}
+
+#[derive(Debug)]
+struct PendingFault {
+    spec: FaultSpec,
+    count: u32,
+}
+
+/// Wraps a [`BlockBackend`] and fails operations matching [`FaultSpec`]s.
+///
+/// Each spec fires once and is then dropped, so a retry of the failed
+/// operation reaches the backend.
+#[derive(Debug)]
+pub struct FaultInjector<B> {
+    backend: B,
+    faults: Mutex<Vec<PendingFault>>,
+}
+
+impl<B> FaultInjector<B> {
+    pub fn new(backend: B, specs: Vec<FaultSpec>) -> Self {
+        let faults = specs
+            .into_iter()
+            .map(|spec| PendingFault { spec, count: 0 })
+            .collect();
+        FaultInjector {
+            backend,
+            faults: Mutex::new(faults),
+        }
+    }
+
+    pub fn backend(&self) -> &B {
+        &self.backend
+    }
+
+    pub fn inject(&self, spec: FaultSpec) {
+        let mut faults = self.faults.lock().unwrap();
+        faults.push(PendingFault { spec, count: 0 });
+    }
+
+    fn check(&self, op: FaultOp, offset: u64, len: usize) -> io::Result<()> {
+        let mut faults = self.faults.lock().unwrap();
+        let mut fired = None;
+        for (index, fault) in faults.iter_mut().enumerate() {
+            if !fault.spec.matches(op, offset, len) {
+                continue;
+            }
+            fault.count += 1;
+            if fired.is_none() && fault.count >= fault.spec.nth_occurrence {
+                fired = Some(index);
+            }
+        }
+        match fired {
+            Some(index) => Err(faults.remove(index).spec.error.into()),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<B> BlockBackend for FaultInjector<B>
+where
+    B: BlockBackend,
+{
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        self.check(FaultOp::Read, offset, buf.len())?;
+        self.backend.read_exact_at(buf, offset)
+    }
+
+    fn write_all_at(&self, buf: &[u8], offset: u64) -> io::Result<()> {
+        self.check(FaultOp::Write, offset, buf.len())?;
+        self.backend.write_all_at(buf, offset)
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        self.backend.flush()
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        self.backend.size()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{self, ErrorKind};
+    use std::sync::Mutex;
+
+    use crate::virtio::dev::blk::BlockBackend;
+    use crate::virtio::dev::fault::{FaultInjector, FaultOp, FaultSpec};
+
+    #[derive(Debug, Default)]
+    struct MemBackend(Mutex<Vec<u8>>);
+
+    impl BlockBackend for MemBackend {
+        fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+            let data = self.0.lock().unwrap();
+            let offset = offset as usize;
+            buf.copy_from_slice(&data[offset..offset + buf.len()]);
+            Ok(())
+        }
+
+        fn write_all_at(&self, buf: &[u8], offset: u64) -> io::Result<()> {
+            let mut data = self.0.lock().unwrap();
+            let offset = offset as usize;
+            data[offset..offset + buf.len()].copy_from_slice(buf);
+            Ok(())
+        }
+
+        fn flush(&self) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn size(&self) -> io::Result<u64> {
+            Ok(self.0.lock().unwrap().len() as u64)
+        }
+    }
+
+    #[test]
+    fn test_nth_occurrence() {
+        let backend = MemBackend(Mutex::new(vec![0; 0x1000]));
+        let spec = FaultSpec {
+            offset_range: 0x200..0x400,
+            op: FaultOp::Read,
+            nth_occurrence: 2,
+            error: ErrorKind::TimedOut,
+        };
+        let injector = FaultInjector::new(backend, vec![spec]);
+        let mut buf = [0u8; 0x200];
+
+        // writes and reads outside the range do not count
+        injector.write_all_at(&[1; 0x200], 0x200).unwrap();
+        injector.read_exact_at(&mut buf, 0x400).unwrap();
+        injector.read_exact_at(&mut buf, 0x100).unwrap();
+        assert_eq!(
+            injector.read_exact_at(&mut buf, 0x300).unwrap_err().kind(),
+            ErrorKind::TimedOut
+        );
+        // the fault fires only once
+        injector.read_exact_at(&mut buf, 0x200).unwrap();
+        assert_eq!(buf, [1; 0x200]);
+    }
+}
@@ -14,7 +14,7 @@
 
 use std::io::ErrorKind;
 use std::iter::zip;
-use std::mem::size_of_val;
+use std::mem::{size_of, size_of_val};
 use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
 use std::path::PathBuf;
 use std::sync::atomic::Ordering;
@@ -41,7 +41,7 @@ use crate::virtio::vu::{
     VirtqState, VuDev, VuFeature,
 };
 use crate::virtio::{error, DeviceId, IrqSender, Result, VirtioFeature};
-use crate::{align_up, ffi, impl_mmio_for_zerocopy};
+use crate::{align_up, c_enum, ffi, impl_mmio_for_zerocopy};
 
 #[repr(C, align(4))]
 #[derive(Debug, FromBytes, FromZeroes, AsBytes)]
@@ -69,6 +69,88 @@ struct VuFsMap {
     pub flags: [u64; 8],
 }
 
+c_enum! {
+    #[derive(FromBytes, FromZeroes, AsBytes)]
+    pub struct FuseNotifyCode(i32);
+    {
+        INVAL_INODE = 2;
+        INVAL_ENTRY = 3;
+        DELETE = 6;
+    }
+}
+
+/// The header of a FUSE message. For notifications, `unique` is 0 and
+/// `error` holds a [`FuseNotifyCode`].
+#[repr(C)]
+#[derive(Debug, Default, FromBytes, FromZeroes, AsBytes)]
+pub struct FuseOutHeader {
+    pub len: u32,
+    pub error: i32,
+    pub unique: u64,
+}
+
+#[repr(C)]
+#[derive(Debug, Default, FromBytes, FromZeroes, AsBytes)]
+pub struct FuseNotifyInvalInodeOut {
+    pub ino: u64,
+    pub off: i64,
+    pub len: i64,
+}
+
+#[repr(C)]
+#[derive(Debug, Default, FromBytes, FromZeroes, AsBytes)]
+pub struct FuseNotifyInvalEntryOut {
+    pub parent: u64,
+    pub namelen: u32,
+    pub flags: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Default, FromBytes, FromZeroes, AsBytes)]
+pub struct FuseNotifyDeleteOut {
+    pub parent: u64,
+    pub child: u64,
+    pub namelen: u32,
+    pub padding: u32,
+}
+
+const FUSE_NAME_MAX: usize = 255;
+
+/// Size of a notification queue buffer that fits any of the notifications
+/// in [`FuseNotifyCode`], including a NUL-terminated name.
+pub const FS_NOTIFY_BUF_SIZE: usize = size_of::<FuseOutHeader>()
+    + max(
+        size_of::<FuseNotifyInvalInodeOut>(),
+        max(
+            size_of::<FuseNotifyInvalEntryOut>(),
+            size_of::<FuseNotifyDeleteOut>(),
+        ) + FUSE_NAME_MAX
+            + 1,
+    );
+
+const fn max(a: usize, b: usize) -> usize {
+    if a > b {
+        a
+    } else {
+        b
+    }
+}
+
+/// Creates the device config for `tag`. With [`FsFeature::NOTIFICATION`],
+/// the last of the `num_queues` queues is the notification queue.
+fn fs_config(tag: &str, num_queues: u16, notification: bool) -> FsConfig {
+    assert!(tag.len() <= 36);
+    assert_ne!(tag.len(), 0);
+    let mut config = FsConfig::new_zeroed();
+    config.tag[0..tag.len()].copy_from_slice(tag.as_bytes());
+    config.num_request_queues = num_queues as u32 - 1;
+    if notification {
+        config.num_request_queues -= 1;
+        config.notify_buf_size = FS_NOTIFY_BUF_SIZE as u32;
+    }
+    config
+}
+
 const VHOST_USER_BACKEND_FS_MAP: u32 = 6;
 const VHOST_USER_BACKEND_FS_UNMAP: u32 = 7;
 
@@ -79,6 +161,7 @@ pub struct VuFs {
     config: Arc<FsConfig>,
     feature: u64,
     num_queues: u16,
+    notification: bool,
     regions: Vec<MemoryRegion>,
     dax_region: Option<ArcMemPages>,
     error_fds: Vec<OwnedFd>,
@@ -117,16 +200,9 @@ impl VuFs {
 
         vu_dev.set_owner()?;
         let num_queues = vu_dev.get_queue_num()? as u16;
+        let notification = FsFeature::from_bits_retain(dev_feat).contains(FsFeature::NOTIFICATION);
         let config = if let Some(tag) = param.tag {
-            assert!(tag.len() <= 36);
-            assert_ne!(tag.len(), 0);
-            let mut config = FsConfig::new_zeroed();
-            config.tag[0..tag.len()].copy_from_slice(tag.as_bytes());
-            config.num_request_queues = num_queues as u32 - 1;
-            if FsFeature::from_bits_retain(dev_feat).contains(FsFeature::NOTIFICATION) {
-                config.num_request_queues -= 1;
-            }
-            config
+            fs_config(&tag, num_queues, notification)
         } else {
             let mut empty_cfg = DeviceConfig::new_zeroed();
             empty_cfg.size = size_of_val(&empty_cfg.region) as _;
@@ -143,6 +219,7 @@ impl VuFs {
 
         Ok(VuFs {
             num_queues,
+            notification,
             name,
             vu_dev,
             config: Arc::new(config),
@@ -212,7 +289,16 @@ impl Virtio for VuFs {
             log::info!("region: {region:x?}");
             self.regions.push(region.region);
         }
-        for (index, queue) in queues.iter().enumerate() {
+        // The backend fills the notification queue with FUSE notifications
+        // from the daemon. Leave it alone if the driver does not use it.
+        let num_active = if self.notification
+            && !FsFeature::from_bits_retain(feature).contains(FsFeature::NOTIFICATION)
+        {
+            queues.len() - 1
+        } else {
+            queues.len()
+        };
+        for (index, queue) in queues[..num_active].iter().enumerate() {
             let irq_fd = irq_sender.queue_irqfd(index as _)?;
             self.vu_dev.set_virtq_call(&(index as u64), irq_fd).unwrap();
 
@@ -254,7 +340,7 @@ impl Virtio for VuFs {
             log::info!("queue: {:x?}", queue);
             log::info!("virtq_addr: {virtq_addr:x?}");
         }
-        for index in 0..queues.len() {
+        for index in 0..num_active {
             let virtq_enable = VirtqState {
                 index: index as _,
                 val: 1,
@@ -425,3 +511,28 @@ impl Virtio for VuFs {
         )))
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::mem::size_of;
+
+    use crate::virtio::dev::fs::{
+        fs_config, FuseNotifyDeleteOut, FuseOutHeader, FS_NOTIFY_BUF_SIZE,
+    };
+
+    #[test]
+    fn test_fs_config_notification() {
+        let config = fs_config("shared", 3, false);
+        assert_eq!(&config.tag[..6], b"shared");
+        assert_eq!(config.num_request_queues, 2);
+        assert_eq!(config.notify_buf_size, 0);
+
+        let config = fs_config("shared", 3, true);
+        assert_eq!(config.num_request_queues, 1);
+        assert_eq!(config.notify_buf_size, FS_NOTIFY_BUF_SIZE as u32);
+        assert_eq!(
+            FS_NOTIFY_BUF_SIZE,
+            size_of::<FuseOutHeader>() + size_of::<FuseNotifyDeleteOut>() + 256
+        );
+    }
+}
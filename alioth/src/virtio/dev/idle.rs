@@ -0,0 +1,145 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::thread;
+use std::time::Duration;
+
+use mio::{Events, Poll};
+use snafu::ResultExt;
+
+use crate::virtio::{error, Result};
+
+/// How a device worker waits for the next batch of events.
+///
+/// The default blocks in the poll right away, which costs the least CPU
+/// time but the most wake-up latency.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct IdleStrategy {
+    /// Number of non-blocking polls, separated by spin loop hints, before
+    /// the worker blocks.
+    pub spin_count: u32,
+    /// Number of non-blocking polls, separated by yielding the CPU, after
+    /// spinning and before the worker blocks.
+    pub yield_count: u32,
+    /// Time the worker sleeps after handling a batch of events. Events
+    /// arriving meanwhile are handled in the next batch, so a driver
+    /// notifying at a high rate cannot keep the worker on a CPU.
+    pub park_ns: u64,
+}
+
+impl IdleStrategy {
+    pub fn wait(&self, poll: &mut Poll, events: &mut Events) -> Result<()> {
+        if self.park_ns > 0 {
+            thread::sleep(Duration::from_nanos(self.park_ns));
+        }
+        for round in 0..self.spin_count.saturating_add(self.yield_count) {
+            poll.poll(events, Some(Duration::ZERO))
+                .context(error::PollEvents)?;
+            if !events.is_empty() {
+                return Ok(());
+            }
+            if round < self.spin_count {
+                std::hint::spin_loop();
+            } else {
+                thread::yield_now();
+            }
+        }
+        poll.poll(events, None).context(error::PollEvents)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use libc::{clock_gettime, timespec, CLOCK_THREAD_CPUTIME_ID};
+    use mio::{Events, Poll, Token, Waker};
+
+    use crate::virtio::dev::idle::IdleStrategy;
+
+    fn thread_cpu_time() -> Duration {
+        let mut ts = timespec {
+            tv_sec: 0,
+            tv_nsec: 0,
+        };
+        unsafe { clock_gettime(CLOCK_THREAD_CPUTIME_ID, &mut ts) };
+        Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32)
+    }
+
+    #[test]
+    fn test_spin_then_block() {
+        let mut poll = Poll::new().unwrap();
+        let waker = Arc::new(Waker::new(poll.registry(), Token(0)).unwrap());
+        let mut events = Events::with_capacity(4);
+        let idle = IdleStrategy {
+            spin_count: 1000,
+            yield_count: 100,
+            park_ns: 0,
+        };
+
+        waker.wake().unwrap();
+        idle.wait(&mut poll, &mut events).unwrap();
+        assert_eq!(events.iter().next().unwrap().token(), Token(0));
+
+        // the event arrives after spinning and yielding are over
+        let remote_waker = waker.clone();
+        let notifier = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            remote_waker.wake().unwrap();
+        });
+        idle.wait(&mut poll, &mut events).unwrap();
+        assert_eq!(events.iter().next().unwrap().token(), Token(0));
+        notifier.join().unwrap();
+    }
+
+    #[test]
+    fn test_park_under_load() {
+        let mut poll = Poll::new().unwrap();
+        let waker = Arc::new(Waker::new(poll.registry(), Token(0)).unwrap());
+        let stop = Arc::new(AtomicBool::new(false));
+        let remote_waker = waker.clone();
+        let remote_stop = stop.clone();
+        let notifier = thread::spawn(move || {
+            while !remote_stop.load(Ordering::Relaxed) {
+                remote_waker.wake().unwrap();
+            }
+        });
+
+        let idle = IdleStrategy {
+            spin_count: 100,
+            yield_count: 0,
+            park_ns: 10_000_000,
+        };
+        let mut events = Events::with_capacity(4);
+        let start = Instant::now();
+        let cpu_start = thread_cpu_time();
+        let mut batches = 0;
+        while start.elapsed() < Duration::from_millis(200) {
+            idle.wait(&mut poll, &mut events).unwrap();
+            assert!(!events.is_empty());
+            batches += 1;
+        }
+        let cpu_time = thread_cpu_time() - cpu_start;
+        let wall_time = start.elapsed();
+        stop.store(true, Ordering::Relaxed);
+        notifier.join().unwrap();
+
+        // each batch parks for 10ms
+        assert!(batches <= 21, "{batches} batches");
+        assert!(cpu_time < wall_time / 2, "{cpu_time:?} of {wall_time:?}");
+    }
+}
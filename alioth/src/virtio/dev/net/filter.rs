@@ -0,0 +1,162 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::mem::size_of;
+
+use zerocopy::FromBytes;
+
+use crate::net::MacAddr;
+
+const BROADCAST: MacAddr = MacAddr::new([0xff; 6]);
+
+/// Receive filter set up by the driver with `VIRTIO_NET_CTRL_RX` and
+/// `VIRTIO_NET_CTRL_MAC_TABLE_SET`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RxFilter {
+    pub promisc: bool,
+    pub all_multi: bool,
+    pub all_uni: bool,
+    pub no_multi: bool,
+    pub no_uni: bool,
+    pub no_bcast: bool,
+    pub uni: Vec<MacAddr>,
+    pub multi: Vec<MacAddr>,
+}
+
+impl Default for RxFilter {
+    /// Accepts everything until the driver configures the filter, like a
+    /// device without `VIRTIO_NET_F_CTRL_RX`.
+    fn default() -> Self {
+        RxFilter {
+            promisc: true,
+            all_multi: false,
+            all_uni: false,
+            no_multi: false,
+            no_uni: false,
+            no_bcast: false,
+            uni: Vec::new(),
+            multi: Vec::new(),
+        }
+    }
+}
+
+/// Reads a `virtio_net_ctrl_mac`: a 32-bit count followed by the
+/// addresses.
+fn read_mac_table(data: &[u8]) -> Option<(Vec<MacAddr>, &[u8])> {
+    let entries = u32::read_from_prefix(data)? as usize;
+    let data = &data[size_of::<u32>()..];
+    let len = entries.checked_mul(size_of::<MacAddr>())?;
+    if data.len() < len {
+        return None;
+    }
+    let (table, rest) = data.split_at(len);
+    let macs = table
+        .chunks_exact(size_of::<MacAddr>())
+        .filter_map(MacAddr::read_from)
+        .collect();
+    Some((macs, rest))
+}
+
+impl RxFilter {
+    /// Replaces the unicast and multicast tables with those in the data of
+    /// `VIRTIO_NET_CTRL_MAC_TABLE_SET`.
+    pub fn set_mac_table(&mut self, data: &[u8]) -> Option<()> {
+        let (uni, rest) = read_mac_table(data)?;
+        let (multi, rest) = read_mac_table(rest)?;
+        if !rest.is_empty() {
+            return None;
+        }
+        self.uni = uni;
+        self.multi = multi;
+        Some(())
+    }
+
+    /// Returns true if a frame to `dest` should be passed to the driver of
+    /// a device with address `mac`.
+    pub fn accepts(&self, mac: &MacAddr, dest: &MacAddr) -> bool {
+        if self.promisc {
+            return true;
+        }
+        if *dest == BROADCAST {
+            return !self.no_bcast;
+        }
+        if dest.octets()[0] & 1 == 1 {
+            !self.no_multi && (self.all_multi || self.multi.contains(dest))
+        } else {
+            !self.no_uni && (self.all_uni || dest == mac || self.uni.contains(dest))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::net::MacAddr;
+    use crate::virtio::dev::net::filter::RxFilter;
+
+    const MAC: MacAddr = MacAddr::new([0x52, 0x54, 0x00, 0x12, 0x34, 0x56]);
+    const UNI: MacAddr = MacAddr::new([0x52, 0x54, 0x00, 0xab, 0xcd, 0xef]);
+    const MULTI: MacAddr = MacAddr::new([0x01, 0x00, 0x5e, 0x00, 0x00, 0xfb]);
+    const OTHER_UNI: MacAddr = MacAddr::new([0x52, 0x54, 0x00, 0x00, 0x00, 0x01]);
+    const OTHER_MULTI: MacAddr = MacAddr::new([0x33, 0x33, 0x00, 0x00, 0x00, 0x01]);
+    const BROADCAST: MacAddr = MacAddr::new([0xff; 6]);
+
+    #[test]
+    fn test_mac_table() {
+        let mut filter = RxFilter::default();
+        let mut data = vec![1, 0, 0, 0];
+        data.extend_from_slice(&UNI.octets());
+        data.extend_from_slice(&[1, 0, 0, 0]);
+        data.extend_from_slice(&MULTI.octets());
+        assert_eq!(filter.set_mac_table(&data), Some(()));
+        assert_eq!(filter.uni, [UNI]);
+        assert_eq!(filter.multi, [MULTI]);
+
+        // truncated, or with trailing bytes
+        assert_eq!(filter.set_mac_table(&data[..data.len() - 1]), None);
+        data.push(0);
+        assert_eq!(filter.set_mac_table(&data), None);
+        assert_eq!(filter.set_mac_table(&[0xff, 0xff, 0xff, 0xff]), None);
+        assert_eq!(filter.uni, [UNI]);
+
+        assert_eq!(filter.set_mac_table(&[0; 8]), Some(()));
+        assert!(filter.uni.is_empty());
+        assert!(filter.multi.is_empty());
+    }
+
+    #[test]
+    fn test_accepts() {
+        let mut filter = RxFilter {
+            promisc: false,
+            uni: vec![UNI],
+            multi: vec![MULTI],
+            ..Default::default()
+        };
+        let accepted = |filter: &RxFilter| {
+            [MAC, UNI, OTHER_UNI, MULTI, OTHER_MULTI, BROADCAST].map(|d| filter.accepts(&MAC, &d))
+        };
+        assert_eq!(accepted(&filter), [true, true, false, true, false, true]);
+
+        filter.all_uni = true;
+        filter.all_multi = true;
+        assert_eq!(accepted(&filter), [true; 6]);
+
+        filter.no_uni = true;
+        filter.no_multi = true;
+        filter.no_bcast = true;
+        assert_eq!(accepted(&filter), [false; 6]);
+
+        filter.promisc = true;
+        assert_eq!(accepted(&filter), [true; 6]);
+    }
+}
@@ -0,0 +1,387 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::mem::size_of;
+
+use bitflags::bitflags;
+use zerocopy::{AsBytes, FromBytes, FromZeroes};
+
+use crate::c_enum;
+
+bitflags! {
+    #[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    pub struct VnetHdrFlag: u8 {
+        const NEEDS_CSUM = 1 << 0;
+        const DATA_VALID = 1 << 1;
+        const RSC_INFO = 1 << 2;
+    }
+}
+
+c_enum! {
+    #[derive(Default, FromBytes, FromZeroes, AsBytes)]
+    pub struct GsoType(u8);
+    {
+        NONE = 0;
+        TCPV4 = 1;
+        UDP = 3;
+        TCPV6 = 4;
+        UDP_L4 = 5;
+        ECN = 0x80;
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Clone, FromBytes, FromZeroes, AsBytes)]
+pub struct VirtioNetHdr {
+    pub flags: u8,
+    pub gso_type: GsoType,
+    pub hdr_len: u16,
+    pub gso_size: u16,
+    pub csum_start: u16,
+    pub csum_offset: u16,
+    pub num_buffers: u16,
+}
+
+const ETH_HDR_LEN: usize = 14;
+const ETH_P_IPV4: u16 = 0x0800;
+const ETH_P_IPV6: u16 = 0x86dd;
+const ETH_P_8021Q: u16 = 0x8100;
+
+const IPPROTO_TCP: u8 = 6;
+const IPPROTO_UDP: u8 = 17;
+
+const TCP_FLAG_FIN: u8 = 1 << 0;
+const TCP_FLAG_PSH: u8 = 1 << 3;
+const TCP_FLAG_CWR: u8 = 1 << 7;
+
+fn csum_add(mut sum: u32, data: &[u8]) -> u32 {
+    let mut chunks = data.chunks_exact(2);
+    for c in &mut chunks {
+        sum += u16::from_be_bytes([c[0], c[1]]) as u32;
+    }
+    if let [b] = chunks.remainder() {
+        sum += (*b as u32) << 8;
+    }
+    sum
+}
+
+fn csum_fold(mut sum: u32) -> u16 {
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum L3 {
+    V4,
+    V6,
+}
+
+/// Fills in the checksum of a frame whose header requests
+/// [`VnetHdrFlag::NEEDS_CSUM`] and clears the checksum fields of the header.
+///
+/// Returns `false` if the frame is left untouched.
+pub fn complete_csum(frame: &mut [u8]) -> bool {
+    let Some(mut hdr) = VirtioNetHdr::read_from_prefix(frame) else {
+        return false;
+    };
+    let flags = VnetHdrFlag::from_bits_retain(hdr.flags);
+    if !flags.contains(VnetHdrFlag::NEEDS_CSUM) || hdr.gso_type != GsoType::NONE {
+        return false;
+    }
+    let data = &mut frame[size_of::<VirtioNetHdr>()..];
+    let start = hdr.csum_start as usize;
+    let pos = start + hdr.csum_offset as usize;
+    if pos + 2 > data.len() {
+        return false;
+    }
+    // The checksum field holds the sum of the pseudo header.
+    let csum = match csum_fold(csum_add(0, &data[start..])) {
+        0 => 0xffff,
+        csum => csum,
+    };
+    data[pos..pos + 2].copy_from_slice(&csum.to_be_bytes());
+    hdr.flags = (flags - VnetHdrFlag::NEEDS_CSUM).bits();
+    hdr.csum_start = 0;
+    hdr.csum_offset = 0;
+    hdr.write_to_prefix(frame);
+    true
+}
+
+/// Locates the transport header and payload of a TCP or UDP packet in an
+/// Ethernet frame and returns them with the protocol and the sum of the
+/// pseudo header. Returns `None` for other packets, IP fragments, and IPv6
+/// packets with extension headers.
+fn l4_with_pseudo_sum(pkt: &[u8]) -> Option<(u8, &[u8], u32)> {
+    let mut l3_start = ETH_HDR_LEN;
+    let mut ether_type = u16::from_be_bytes(pkt.get(12..14)?.try_into().ok()?);
+    if ether_type == ETH_P_8021Q {
+        l3_start += 4;
+        ether_type = u16::from_be_bytes(pkt.get(16..18)?.try_into().ok()?);
+    }
+    // Ethernet frames may be padded beyond the end of the IP packet.
+    let (proto, l4, addrs) = match ether_type {
+        ETH_P_IPV4 => {
+            let ip = pkt.get(l3_start..l3_start + 20)?;
+            let ihl = (ip[0] & 0xf) as usize * 4;
+            let total_len = u16::from_be_bytes([ip[2], ip[3]]) as usize;
+            // more fragments or a fragment offset
+            if u16::from_be_bytes([ip[6], ip[7]]) & 0x3fff != 0 || ihl < 20 {
+                return None;
+            }
+            let l4 = pkt.get(l3_start + ihl..l3_start + total_len)?;
+            (ip[9], l4, &ip[12..20])
+        }
+        ETH_P_IPV6 => {
+            let ip = pkt.get(l3_start..l3_start + 40)?;
+            let payload_len = u16::from_be_bytes([ip[4], ip[5]]) as usize;
+            let l4 = pkt.get(l3_start + 40..l3_start + 40 + payload_len)?;
+            (ip[6], l4, &ip[8..40])
+        }
+        _ => return None,
+    };
+    if proto != IPPROTO_TCP && proto != IPPROTO_UDP {
+        return None;
+    }
+    let pseudo = csum_add(0, addrs) + proto as u32 + l4.len() as u32;
+    Some((proto, l4, pseudo))
+}
+
+/// Verifies the TCP or UDP checksum of a frame whose header requests no
+/// offloading, and sets [`VnetHdrFlag::DATA_VALID`] if it is correct.
+///
+/// Returns `false` if the frame is left untouched.
+pub fn validate_csum(frame: &mut [u8]) -> bool {
+    let Some(mut hdr) = VirtioNetHdr::read_from_prefix(frame) else {
+        return false;
+    };
+    if hdr.flags != 0 || hdr.gso_type != GsoType::NONE {
+        return false;
+    }
+    let pkt = &frame[size_of::<VirtioNetHdr>()..];
+    let Some((proto, l4, pseudo)) = l4_with_pseudo_sum(pkt) else {
+        return false;
+    };
+    let csum_pos = if proto == IPPROTO_TCP { 16 } else { 6 };
+    let Some(csum) = l4.get(csum_pos..csum_pos + 2) else {
+        return false;
+    };
+    // A UDP packet over IPv4 may carry no checksum.
+    if proto == IPPROTO_UDP && csum == [0, 0] {
+        return false;
+    }
+    if csum_fold(csum_add(pseudo, l4)) != 0 {
+        return false;
+    }
+    hdr.flags = VnetHdrFlag::DATA_VALID.bits();
+    hdr.write_to_prefix(frame);
+    true
+}
+
+/// Splits a frame carrying a GSO packet into frames of at most `gso_size`
+/// bytes of payload each, with checksums filled in. Every returned frame
+/// is prefixed with a virtio-net header that requests no offloading.
+///
+/// Returns `None` if the packet cannot be segmented in software, e.g. UFO
+/// packets which would require IP fragmentation.
+pub fn segment(hdr: &VirtioNetHdr, frame: &[u8]) -> Option<Vec<Vec<u8>>> {
+    let gso_type = GsoType(hdr.gso_type.raw() & !GsoType::ECN.raw());
+    let (proto, expected_l3) = match gso_type {
+        GsoType::TCPV4 => (IPPROTO_TCP, Some(L3::V4)),
+        GsoType::TCPV6 => (IPPROTO_TCP, Some(L3::V6)),
+        GsoType::UDP_L4 => (IPPROTO_UDP, None),
+        _ => return None,
+    };
+    let gso_size = hdr.gso_size as usize;
+    if gso_size == 0 || frame.len() < ETH_HDR_LEN {
+        return None;
+    }
+
+    let mut l3_start = ETH_HDR_LEN;
+    let mut ether_type = u16::from_be_bytes([frame[12], frame[13]]);
+    if ether_type == ETH_P_8021Q {
+        l3_start += 4;
+        let b = frame.get(16..18)?;
+        ether_type = u16::from_be_bytes([b[0], b[1]]);
+    }
+    let l3 = match ether_type {
+        ETH_P_IPV4 => L3::V4,
+        ETH_P_IPV6 => L3::V6,
+        _ => return None,
+    };
+    if expected_l3.is_some_and(|e| e != l3) {
+        return None;
+    }
+    // The driver must set NEEDS_CSUM for GSO packets, and csum_start
+    // points to the transport header, past any IPv6 extension headers.
+    let l4_start = hdr.csum_start as usize;
+    let l3_hdr_min = match l3 {
+        L3::V4 => 20,
+        L3::V6 => 40,
+    };
+    let flags = VnetHdrFlag::from_bits_retain(hdr.flags);
+    if !flags.contains(VnetHdrFlag::NEEDS_CSUM) || l4_start < l3_start + l3_hdr_min {
+        return None;
+    }
+    let l4_hdr_len = match proto {
+        IPPROTO_TCP => (*frame.get(l4_start + 12)? >> 4) as usize * 4,
+        _ => 8,
+    };
+    let hdr_end = l4_start + l4_hdr_len;
+    if frame.len() < hdr_end {
+        return None;
+    }
+    let headers = &frame[..hdr_end];
+    let payload = &frame[hdr_end..];
+
+    let num_segs = payload.len().div_ceil(gso_size);
+    let mut segs = Vec::with_capacity(num_segs);
+    for (index, chunk) in payload.chunks(gso_size).enumerate() {
+        let mut seg = Vec::with_capacity(size_of::<VirtioNetHdr>() + hdr_end + chunk.len());
+        seg.extend_from_slice(VirtioNetHdr::default().as_bytes());
+        let start = seg.len();
+        seg.extend_from_slice(headers);
+        seg.extend_from_slice(chunk);
+        let pkt = &mut seg[start..];
+        let l4_len = pkt.len() - l4_start;
+
+        let pseudo = match l3 {
+            L3::V4 => {
+                let ip = &mut pkt[l3_start..l4_start];
+                let total_len = (l4_start - l3_start + l4_len) as u16;
+                ip[2..4].copy_from_slice(&total_len.to_be_bytes());
+                if proto == IPPROTO_TCP {
+                    let id = u16::from_be_bytes([ip[4], ip[5]]).wrapping_add(index as u16);
+                    ip[4..6].copy_from_slice(&id.to_be_bytes());
+                }
+                ip[10..12].fill(0);
+                let ihl = (ip[0] & 0xf) as usize * 4;
+                let csum = csum_fold(csum_add(0, ip.get(..ihl)?));
+                ip[10..12].copy_from_slice(&csum.to_be_bytes());
+                let sum = csum_add(0, &ip[12..20]);
+                sum + proto as u32 + l4_len as u32
+            }
+            L3::V6 => {
+                let ip = &mut pkt[l3_start..l4_start];
+                let payload_len = (l4_start - l3_start - 40 + l4_len) as u16;
+                ip[4..6].copy_from_slice(&payload_len.to_be_bytes());
+                let sum = csum_add(0, &ip[8..40]);
+                sum + proto as u32 + l4_len as u32
+            }
+        };
+
+        let l4 = &mut pkt[l4_start..];
+        let csum_pos = match proto {
+            IPPROTO_TCP => {
+                let seq = u32::from_be_bytes([l4[4], l4[5], l4[6], l4[7]])
+                    .wrapping_add((index * gso_size) as u32);
+                l4[4..8].copy_from_slice(&seq.to_be_bytes());
+                if index != 0 {
+                    l4[13] &= !TCP_FLAG_CWR;
+                }
+                if index != num_segs - 1 {
+                    l4[13] &= !(TCP_FLAG_FIN | TCP_FLAG_PSH);
+                }
+                16
+            }
+            _ => {
+                l4[4..6].copy_from_slice(&(l4_len as u16).to_be_bytes());
+                6
+            }
+        };
+        l4[csum_pos..csum_pos + 2].fill(0);
+        let mut csum = csum_fold(csum_add(pseudo, l4));
+        if proto == IPPROTO_UDP && csum == 0 {
+            csum = 0xffff;
+        }
+        l4[csum_pos..csum_pos + 2].copy_from_slice(&csum.to_be_bytes());
+        segs.push(seg);
+    }
+    Some(segs)
+}
+
+#[cfg(test)]
+mod test {
+    use std::mem::size_of;
+
+    use zerocopy::FromBytes;
+
+    use super::{csum_add, csum_fold, segment, GsoType, VirtioNetHdr, VnetHdrFlag};
+
+    #[test]
+    fn test_segment_tcpv4() {
+        const MSS: usize = 1448;
+        let payload: Vec<u8> = (0..60 * 1024).map(|i| (i % 251) as u8).collect();
+
+        let mut frame = vec![0u8; 14 + 20 + 20];
+        frame[12..14].copy_from_slice(&0x0800u16.to_be_bytes());
+        let ip = &mut frame[14..34];
+        ip[0] = 0x45;
+        ip[4..6].copy_from_slice(&0x1000u16.to_be_bytes());
+        ip[8] = 64;
+        ip[9] = 6;
+        ip[12..16].copy_from_slice(&[10, 0, 0, 1]);
+        ip[16..20].copy_from_slice(&[10, 0, 0, 2]);
+        let tcp = &mut frame[34..54];
+        tcp[4..8].copy_from_slice(&0xffff_f000u32.to_be_bytes());
+        tcp[12] = 5 << 4;
+        tcp[13] = 0x18 | 0x01;
+        frame.extend_from_slice(&payload);
+
+        let hdr = VirtioNetHdr {
+            flags: VnetHdrFlag::NEEDS_CSUM.bits(),
+            gso_type: GsoType::TCPV4,
+            hdr_len: 54,
+            gso_size: MSS as u16,
+            csum_start: 34,
+            csum_offset: 16,
+            num_buffers: 0,
+        };
+        let segs = segment(&hdr, &frame).unwrap();
+        assert_eq!(segs.len(), payload.len().div_ceil(MSS));
+
+        let mut data = vec![];
+        for (i, seg) in segs.iter().enumerate() {
+            let seg_hdr = VirtioNetHdr::read_from_prefix(seg).unwrap();
+            assert_eq!(seg_hdr.gso_type, GsoType::NONE);
+            assert_eq!(seg_hdr.flags, 0);
+
+            let pkt = &seg[size_of::<VirtioNetHdr>()..];
+            let len = pkt.len() - 54;
+            assert!(len <= MSS);
+            let ip = &pkt[14..34];
+            assert_eq!(u16::from_be_bytes([ip[2], ip[3]]) as usize, 40 + len);
+            assert_eq!(u16::from_be_bytes([ip[4], ip[5]]), 0x1000 + i as u16);
+            assert_eq!(csum_fold(csum_add(0, ip)), 0);
+
+            let tcp = &pkt[34..];
+            let seq = u32::from_be_bytes([tcp[4], tcp[5], tcp[6], tcp[7]]);
+            assert_eq!(seq, 0xffff_f000u32.wrapping_add((i * MSS) as u32));
+            let last = i == segs.len() - 1;
+            assert_eq!(tcp[13] & 0x09 != 0, last);
+            let pseudo = csum_add(0, &ip[12..20]) + 6 + tcp.len() as u32;
+            assert_eq!(csum_fold(csum_add(pseudo, tcp)), 0);
+
+            data.extend_from_slice(&pkt[54..]);
+        }
+        assert_eq!(data, payload);
+
+        let ufo = VirtioNetHdr {
+            gso_type: GsoType::UDP,
+            ..hdr
+        };
+        assert!(segment(&ufo, &frame).is_none());
+    }
+}
@@ -14,38 +14,105 @@
 
 use std::fmt::Debug;
 use std::fs::{self, File};
-use std::io;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::io::{self, ErrorKind, IoSliceMut, Read, Write};
 use std::iter::zip;
-use std::mem::MaybeUninit;
+use std::mem::{size_of, MaybeUninit};
 use std::num::NonZeroU16;
 use std::os::fd::AsRawFd;
 use std::os::unix::prelude::OpenOptionsExt;
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 
 use bitflags::bitflags;
-use libc::{IFF_NO_PI, IFF_TAP, IFF_VNET_HDR, O_NONBLOCK};
+use libc::{if_nametoindex, IFF_LOWER_UP, IFF_NO_PI, IFF_TAP, IFF_UP, IFF_VNET_HDR, O_NONBLOCK};
 use mio::event::Event;
 use mio::unix::SourceFd;
 use mio::{Interest, Registry, Token};
-use serde::Deserialize;
+use parking_lot::RwLock;
+use serde::{de, Deserialize, Deserializer};
 use zerocopy::{AsBytes, FromBytes, FromZeroes};
 
-use crate::impl_mmio_for_zerocopy;
+use crate::mem::emulated::{Action, Mmio};
 use crate::mem::mapped::RamBus;
 use crate::net::MacAddr;
+use crate::virtio::dev::watchdog::WatchdogParam;
 use crate::virtio::dev::{DevParam, DeviceId, Result, Virtio};
-use crate::virtio::queue::handlers::{queue_to_writer, reader_to_queue};
-use crate::virtio::queue::{Queue, VirtQueue};
+use crate::virtio::queue::handlers::{handle_desc, handle_desc_batch, DESC_BATCH_SIZE};
+use crate::virtio::queue::{Descriptor, Queue, VirtQueue};
 use crate::virtio::{IrqSender, FEATURE_BUILT_IN};
+use crate::{c_enum, impl_mmio_for_zerocopy, mem};
 
+pub mod filter;
+pub mod gso;
 pub mod tap;
 
-use tap::{tun_get_iff, tun_set_iff, tun_set_offload, tun_set_vnet_hdr_sz, TunFeature};
+use filter::RxFilter;
+use gso::{GsoType, VirtioNetHdr, VnetHdrFlag};
+use tap::{
+    get_link_settings, tun_get_features, tun_get_iff, tun_set_iff, tun_set_offload,
+    tun_set_vnet_hdr_sz, LinkMonitor, TunFeature,
+};
 
 const QUEUE_RX: u16 = 0;
 const QUEUE_TX: u16 = 1;
 
+c_enum! {
+    #[derive(FromBytes, FromZeroes)]
+    pub struct CtrlClass(u8);
+    {
+        RX = 0;
+        MAC = 1;
+        VLAN = 2;
+        ANNOUNCE = 3;
+        MQ = 4;
+        GUEST_OFFLOADS = 5;
+    }
+}
+
+c_enum! {
+    pub struct CtrlRxCmd(u8);
+    {
+        PROMISC = 0;
+        ALLMULTI = 1;
+        ALLUNI = 2;
+        NOMULTI = 3;
+        NOUNI = 4;
+        NOBCAST = 5;
+    }
+}
+
+c_enum! {
+    pub struct CtrlMacCmd(u8);
+    {
+        TABLE_SET = 0;
+        ADDR_SET = 1;
+    }
+}
+
+c_enum! {
+    pub struct CtrlGuestOffloadsCmd(u8);
+    {
+        SET = 0;
+    }
+}
+
+c_enum! {
+    pub struct CtrlAck(u8);
+    {
+        OK = 0;
+        ERR = 1;
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, FromBytes, FromZeroes)]
+pub struct CtrlHdr {
+    class: CtrlClass,
+    command: u8,
+}
+
 #[repr(C, align(8))]
 #[derive(Debug, Default, FromBytes, FromZeroes, AsBytes)]
 pub struct NetConfig {
@@ -62,6 +129,53 @@ pub struct NetConfig {
 
 impl_mmio_for_zerocopy!(NetConfig);
 
+#[derive(Debug)]
+pub struct NetConfigMmio {
+    config: RwLock<NetConfig>,
+}
+
+impl Mmio for NetConfigMmio {
+    fn size(&self) -> u64 {
+        size_of::<NetConfig>() as u64
+    }
+
+    fn read(&self, offset: u64, size: u8) -> mem::Result<u64> {
+        Mmio::read(&*self.config.read(), offset, size)
+    }
+
+    fn write(&self, offset: u64, size: u8, val: u64) -> mem::Result<Action> {
+        let mac_size = size_of::<MacAddr>() as u64;
+        if offset + size as u64 > mac_size {
+            return Mmio::write(&*self.config.read(), offset, size, val);
+        }
+        let mut config = self.config.write();
+        let start = offset as usize;
+        let end = start + size as usize;
+        config.mac.as_bytes_mut()[start..end].copy_from_slice(&val.to_le_bytes()[..size as usize]);
+        Ok(Action::None)
+    }
+}
+
+/// Reported in [`NetConfig`] when the link speed is unknown.
+pub const SPEED_UNKNOWN: u32 = u32::MAX;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+#[repr(u8)]
+pub enum Duplex {
+    Half = 0,
+    Full = 1,
+    Unknown = 0xff,
+}
+
+bitflags! {
+    #[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    pub struct NetStatus: u16 {
+        const LINK_UP = 1 << 0;
+        const ANNOUNCE = 1 << 1;
+    }
+}
+
 bitflags! {
     #[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Hash)]
     pub struct NetFeature: u64 {
@@ -83,6 +197,7 @@ bitflags! {
         const CTRL_VQ = 1 << 17;
         const CTRL_RX = 1 << 18;
         const CTRL_VLAN = 1 << 19;
+        const CTRL_RX_EXTRA = 1 << 20;
         const GUEST_ANNOUNCE = 1 << 21;
         const MQ = 1 << 22;
         const CTRL_MAC_ADDR = 1 << 23;
@@ -99,12 +214,32 @@ bitflags! {
     }
 }
 
+impl NetFeature {
+    /// Receive offloads that the driver can toggle with
+    /// `VIRTIO_NET_CTRL_GUEST_OFFLOADS_SET`.
+    const GUEST_OFFLOADS: NetFeature = NetFeature::GUEST_CSUM
+        .union(NetFeature::GUEST_TSO4)
+        .union(NetFeature::GUEST_TSO6)
+        .union(NetFeature::GUEST_ECN)
+        .union(NetFeature::GUEST_UFO)
+        .union(NetFeature::GUEST_USO4)
+        .union(NetFeature::GUEST_USO6);
+}
+
 #[derive(Debug)]
 pub struct Net {
     name: Arc<String>,
-    config: Arc<NetConfig>,
+    config: Arc<NetConfigMmio>,
     tap: File,
     feature: NetFeature,
+    driver_feature: NetFeature,
+    rx_offloads: AtomicU64,
+    rx_filter: RwLock<RxFilter>,
+    max_queue_pairs: u16,
+    speed: Option<u32>,
+    duplex: Option<Duplex>,
+    watchdog: Option<WatchdogParam>,
+    link_monitor: Option<LinkMonitor>,
 }
 
 fn default_tap_device() -> PathBuf {
@@ -113,13 +248,39 @@ fn default_tap_device() -> PathBuf {
 
 #[derive(Deserialize)]
 pub struct NetParam {
-    pub mac: MacAddr,
+    /// Defaults to a locally administered address derived from the hash of
+    /// the device name.
+    pub mac: Option<MacAddr>,
     pub mtu: u16,
+    #[serde(default, deserialize_with = "deserialize_queue_pairs")]
     pub queue_pairs: Option<NonZeroU16>,
     #[serde(default = "default_tap_device")]
     pub tap: PathBuf,
     #[serde(alias = "if")]
     pub if_name: Option<String>,
+    /// Link speed in Mbps. Defaults to the speed of the tap interface.
+    pub speed: Option<u32>,
+    /// Defaults to the duplex mode of the tap interface.
+    pub duplex: Option<Duplex>,
+    /// Marks the device as needing a reset if its worker is busy with one
+    /// event for longer than this.
+    pub watchdog: Option<WatchdogParam>,
+}
+
+/// Keeps the number of queues, and thus MSI-X vectors, of a device sane.
+const MAX_QUEUE_PAIRS: u16 = 256;
+
+fn deserialize_queue_pairs<'de, D>(deserializer: D) -> Result<Option<NonZeroU16>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let queue_pairs = Option::<NonZeroU16>::deserialize(deserializer)?;
+    match queue_pairs {
+        Some(n) if n.get() > MAX_QUEUE_PAIRS => Err(de::Error::custom(format!(
+            "queue_pairs {n} is larger than {MAX_QUEUE_PAIRS}"
+        ))),
+        _ => Ok(queue_pairs),
+    }
 }
 
 impl DevParam for NetParam {
@@ -130,6 +291,62 @@ impl DevParam for NetParam {
     }
 }
 
+fn default_mac(name: &str) -> MacAddr {
+    let mut hasher = DefaultHasher::new();
+    name.hash(&mut hasher);
+    let hash = hasher.finish().to_le_bytes();
+    let mut addr = [0u8; 6];
+    addr.copy_from_slice(&hash[..6]);
+    // unicast, locally administered
+    addr[0] = (addr[0] & !0b01) | 0b10;
+    MacAddr::new(addr)
+}
+
+/// Returns the speed and duplex mode to report to the driver. Values not
+/// given by the user are read from the tap interface.
+fn link_settings(name: &str, tap: &File, speed: Option<u32>, duplex: Option<Duplex>) -> (u32, u8) {
+    if let (Some(speed), Some(duplex)) = (speed, duplex) {
+        return (speed, duplex as u8);
+    }
+    let (tap_speed, tap_duplex) =
+        match unsafe { tun_get_iff(tap) }.and_then(|ifreq| get_link_settings(&ifreq.ifr_name)) {
+            Ok(settings) => (settings.speed, settings.duplex),
+            Err(e) => {
+                log::debug!("{name}: cannot get link settings of the tap: {e}");
+                (SPEED_UNKNOWN, Duplex::Unknown as u8)
+            }
+        };
+    (
+        speed.unwrap_or(tap_speed),
+        duplex.map(|d| d as u8).unwrap_or(tap_duplex),
+    )
+}
+
+/// Opens a monitor of the tap interface, whose flags decide the link
+/// status reported to the driver.
+fn link_monitor(name: &str, tap: &File) -> Option<LinkMonitor> {
+    let ifreq = match unsafe { tun_get_iff(tap) } {
+        Ok(ifreq) => ifreq,
+        Err(e) => {
+            log::warn!("{name}: cannot get the tap interface: {e}");
+            return None;
+        }
+    };
+    let if_index = unsafe { if_nametoindex(ifreq.ifr_name.as_ptr()) };
+    if if_index == 0 {
+        let e = io::Error::last_os_error();
+        log::warn!("{name}: cannot get the index of the tap interface: {e}");
+        return None;
+    }
+    match LinkMonitor::new(if_index as i32) {
+        Ok(monitor) => Some(monitor),
+        Err(e) => {
+            log::warn!("{name}: cannot watch the link status of the tap: {e}");
+            None
+        }
+    }
+}
+
 impl Net {
     pub fn new(param: NetParam, name: Arc<String>) -> Result<Self> {
         let mut file = fs::OpenOptions::new()
@@ -145,29 +362,263 @@ impl Net {
             | NetFeature::HOST_ECN
             | NetFeature::HOST_UFO
             | NetFeature::HOST_USO
+            | NetFeature::STATUS
+            | NetFeature::CTRL_VQ
+            | NetFeature::CTRL_RX
+            | NetFeature::CTRL_RX_EXTRA
+            | NetFeature::CTRL_MAC_ADDR
+            | NetFeature::SPEED_DUPLEX
             | detect_tap_offload(&file);
+        let dev_feat = if dev_feat.intersects(NetFeature::GUEST_OFFLOADS) {
+            dev_feat | NetFeature::CTRL_GUEST_OFFLOADS
+        } else {
+            dev_feat
+        };
         setup_tap(&mut file, param.if_name.as_deref())?;
+        let max_queue_pairs = param.queue_pairs.map(|p| p.into()).unwrap_or(1);
+        let (speed, duplex) = link_settings(&name, &file, param.speed, param.duplex);
+        let link_monitor = link_monitor(&name, &file);
+        let config = NetConfig {
+            mac: param.mac.unwrap_or_else(|| default_mac(&name)),
+            status: NetStatus::LINK_UP.bits(),
+            max_queue_pairs,
+            mtu: param.mtu,
+            speed,
+            duplex,
+            ..Default::default()
+        };
         let net = Net {
             name,
-            config: Arc::new(NetConfig {
-                mac: param.mac,
-                max_queue_pairs: param.queue_pairs.map(|p| p.into()).unwrap_or(1),
-                mtu: param.mtu,
-                ..Default::default()
+            config: Arc::new(NetConfigMmio {
+                config: RwLock::new(config),
             }),
             tap: file,
             feature: dev_feat,
+            driver_feature: NetFeature::empty(),
+            rx_offloads: AtomicU64::new(0),
+            rx_filter: RwLock::new(RxFilter::default()),
+            max_queue_pairs,
+            speed: param.speed,
+            duplex: param.duplex,
+            watchdog: param.watchdog,
+            link_monitor,
         };
         Ok(net)
     }
+
+    fn set_link_status(&self, up: bool, irq_sender: &impl IrqSender) {
+        let mut config = self.config.config.write();
+        let mut status = NetStatus::from_bits_retain(config.status);
+        if status.contains(NetStatus::LINK_UP) == up {
+            return;
+        }
+        status.set(NetStatus::LINK_UP, up);
+        config.status = status.bits();
+        drop(config);
+        log::info!("{}: link {}", self.name, if up { "up" } else { "down" });
+        if up {
+            // the tap may have been reconfigured while the link was down
+            self.update_link_settings();
+        }
+        irq_sender.config_irq();
+    }
+
+    fn handle_link_event(&self, irq_sender: &impl IrqSender) {
+        let Some(monitor) = &self.link_monitor else {
+            return;
+        };
+        match monitor.read_flags() {
+            Ok(Some(flags)) => {
+                let up_flags = (IFF_UP | IFF_LOWER_UP) as u32;
+                self.set_link_status(flags & up_flags == up_flags, irq_sender);
+            }
+            Ok(None) => {}
+            Err(e) => log::error!("{}: cannot read link changes of the tap: {e}", self.name),
+        }
+    }
+
+    fn update_link_settings(&self) {
+        let (speed, duplex) = link_settings(&self.name, &self.tap, self.speed, self.duplex);
+        let mut config = self.config.config.write();
+        if config.speed == speed && config.duplex == duplex {
+            return;
+        }
+        config.speed = speed;
+        config.duplex = duplex;
+        drop(config);
+        log::info!("{}: speed {speed} Mbps, duplex {duplex}", self.name);
+    }
+
+    fn set_mac(&self, mac: MacAddr, irq_sender: &impl IrqSender) {
+        let mut config = self.config.config.write();
+        if config.mac == mac {
+            return;
+        }
+        config.mac = mac;
+        drop(config);
+        log::info!("{}: mac address changed to {mac:x?}", self.name);
+        irq_sender.config_irq();
+    }
+
+    fn set_guest_offloads(&self, offloads: NetFeature) -> CtrlAck {
+        let allowed = self.driver_feature & NetFeature::GUEST_OFFLOADS;
+        if !allowed.contains(offloads) {
+            log::error!(
+                "{}: cannot enable offloads {:?}",
+                self.name,
+                offloads - allowed
+            );
+            return CtrlAck::ERR;
+        }
+        if let Err(e) = enable_tap_offload(&self.tap, offloads) {
+            log::error!(
+                "{}: failed to set tap offloads {offloads:?}: {e}",
+                self.name
+            );
+            return CtrlAck::ERR;
+        }
+        self.rx_offloads.store(offloads.bits(), Ordering::Release);
+        log::info!("{}: guest offloads set to {offloads:?}", self.name);
+        CtrlAck::OK
+    }
+
+    fn set_rx_mode(&self, cmd: CtrlRxCmd, on: bool) -> CtrlAck {
+        let extra = self.driver_feature.contains(NetFeature::CTRL_RX_EXTRA);
+        let filter = &mut *self.rx_filter.write();
+        let mode = match cmd {
+            CtrlRxCmd::PROMISC => &mut filter.promisc,
+            CtrlRxCmd::ALLMULTI => &mut filter.all_multi,
+            CtrlRxCmd::ALLUNI if extra => &mut filter.all_uni,
+            CtrlRxCmd::NOMULTI if extra => &mut filter.no_multi,
+            CtrlRxCmd::NOUNI if extra => &mut filter.no_uni,
+            CtrlRxCmd::NOBCAST if extra => &mut filter.no_bcast,
+            _ => {
+                log::error!("{}: unsupported rx mode {cmd:?}", self.name);
+                return CtrlAck::ERR;
+            }
+        };
+        *mode = on;
+        log::info!("{}: rx mode {cmd:?} set to {on}", self.name);
+        CtrlAck::OK
+    }
+
+    fn set_mac_table(&self, data: &[u8]) -> CtrlAck {
+        let filter = &mut *self.rx_filter.write();
+        if filter.set_mac_table(data).is_none() {
+            log::error!("{}: invalid mac table", self.name);
+            return CtrlAck::ERR;
+        }
+        log::debug!(
+            "{}: mac table set to unicast {:x?}, multicast {:x?}",
+            self.name,
+            filter.uni,
+            filter.multi
+        );
+        CtrlAck::OK
+    }
+
+    /// Returns true if the frame in `bufs` passes the receive filter.
+    fn rx_accepts(&self, bufs: &[IoSliceMut], len: usize) -> bool {
+        let filter = self.rx_filter.read();
+        if filter.promisc {
+            return true;
+        }
+        let mut dest = [0u8; 6];
+        let bytes = bufs.iter().flat_map(|b| b.iter()).take(len);
+        let mut n = 0;
+        for (d, s) in zip(&mut dest, bytes.skip(size_of::<VirtioNetHdr>())) {
+            *d = *s;
+            n += 1;
+        }
+        if n < dest.len() {
+            return true;
+        }
+        let mac = self.config.config.read().mac;
+        filter.accepts(&mac, &MacAddr::new(dest))
+    }
+
+    fn tap_to_queue(
+        &self,
+        index: u16,
+        queue: &impl VirtQueue,
+        irq_sender: &impl IrqSender,
+    ) -> Result<()> {
+        let mut tap = &self.tap;
+        handle_desc(&self.name, index, queue, irq_sender, |desc| loop {
+            let len = tap.read_vectored(&mut desc.writable)?;
+            if len == 0 {
+                return Err(ErrorKind::UnexpectedEof.into());
+            }
+            // The buffers are reused for the next frame.
+            if !self.rx_accepts(&desc.writable, len) {
+                continue;
+            }
+            complete_rx_csum(&mut desc.writable, len, &self.rx_offloads);
+            break Ok(len);
+        })
+    }
+
+    fn handle_ctrl(&self, desc: &mut Descriptor, irq_sender: &impl IrqSender) -> io::Result<usize> {
+        let request: Vec<u8> = desc
+            .readable
+            .iter()
+            .flat_map(|b| b.iter().copied())
+            .collect();
+        let Some(hdr) = CtrlHdr::read_from_prefix(&request) else {
+            return Err(ErrorKind::InvalidData.into());
+        };
+        let data = &request[size_of::<CtrlHdr>()..];
+        let ack = match (hdr.class, hdr.command) {
+            (CtrlClass::RX, cmd) => match data {
+                [on] => self.set_rx_mode(CtrlRxCmd(cmd), *on != 0),
+                _ => CtrlAck::ERR,
+            },
+            (CtrlClass::MAC, cmd) if CtrlMacCmd(cmd) == CtrlMacCmd::TABLE_SET => {
+                self.set_mac_table(data)
+            }
+            (CtrlClass::MAC, cmd) if CtrlMacCmd(cmd) == CtrlMacCmd::ADDR_SET => {
+                match MacAddr::read_from(data) {
+                    Some(mac) => {
+                        self.set_mac(mac, irq_sender);
+                        CtrlAck::OK
+                    }
+                    None => CtrlAck::ERR,
+                }
+            }
+            (CtrlClass::GUEST_OFFLOADS, cmd)
+                if CtrlGuestOffloadsCmd(cmd) == CtrlGuestOffloadsCmd::SET =>
+            {
+                match u64::read_from(data) {
+                    Some(offloads) => {
+                        self.set_guest_offloads(NetFeature::from_bits_retain(offloads))
+                    }
+                    None => CtrlAck::ERR,
+                }
+            }
+            _ => {
+                log::error!(
+                    "{}: unsupported control command {:?}:{}",
+                    self.name,
+                    hdr.class,
+                    hdr.command
+                );
+                CtrlAck::ERR
+            }
+        };
+        let Some(buf) = desc.writable.first_mut().and_then(|b| b.first_mut()) else {
+            return Err(ErrorKind::InvalidData.into());
+        };
+        *buf = ack.raw();
+        Ok(1)
+    }
 }
 
 impl Virtio for Net {
-    type Config = NetConfig;
+    type Config = NetConfigMmio;
     type Feature = NetFeature;
 
     fn num_queues(&self) -> u16 {
-        let data_queues = self.config.max_queue_pairs << 1;
+        let data_queues = self.max_queue_pairs << 1;
         if self.feature.contains(NetFeature::CTRL_VQ) {
             data_queues + 1
         } else {
@@ -175,12 +626,16 @@ impl Virtio for Net {
         }
     }
 
-    fn config(&self) -> Arc<NetConfig> {
+    fn config(&self) -> Arc<NetConfigMmio> {
         self.config.clone()
     }
 
     fn reset(&mut self, registry: &Registry) {
         let _ = registry.deregister(&mut SourceFd(&self.tap.as_raw_fd()));
+        if let Some(monitor) = &self.link_monitor {
+            let _ = registry.deregister(&mut SourceFd(&monitor.as_raw_fd()));
+        }
+        *self.rx_filter.get_mut() = RxFilter::default();
     }
 
     fn device_id() -> DeviceId {
@@ -191,6 +646,10 @@ impl Virtio for Net {
         self.feature.bits() | FEATURE_BUILT_IN
     }
 
+    fn watchdog(&self) -> Option<WatchdogParam> {
+        self.watchdog
+    }
+
     fn activate(
         &mut self,
         registry: &Registry,
@@ -200,12 +659,29 @@ impl Virtio for Net {
         _queues: &[Queue],
     ) -> Result<()> {
         let feature = NetFeature::from_bits_retain(feature);
-        enable_tap_offload(&mut self.tap, feature)?;
+        enable_tap_offload(&self.tap, feature)?;
+        self.driver_feature = feature;
+        let rx_offloads = feature & NetFeature::GUEST_OFFLOADS;
+        self.rx_offloads
+            .store(rx_offloads.bits(), Ordering::Release);
         registry.register(
             &mut SourceFd(&self.tap.as_raw_fd()),
             TOKEN_TAP,
             Interest::READABLE | Interest::WRITABLE,
         )?;
+        if let Some(monitor) = &self.link_monitor {
+            registry.register(
+                &mut SourceFd(&monitor.as_raw_fd()),
+                TOKEN_LINK,
+                Interest::READABLE,
+            )?;
+            if let Err(e) = monitor.request() {
+                log::warn!(
+                    "{}: cannot query the link status of the tap: {e}",
+                    self.name
+                );
+            }
+        }
         Ok(())
     }
 
@@ -216,19 +692,23 @@ impl Virtio for Net {
         irq_sender: &impl IrqSender,
         _registry: &Registry,
     ) -> Result<()> {
+        if event.token() == TOKEN_LINK {
+            self.handle_link_event(irq_sender);
+            return Ok(());
+        }
         if event.is_readable() {
             let Some(queue) = queues.get(QUEUE_RX as usize) else {
                 log::error!("{}: cannot find rx queue", self.name);
                 return Ok(());
             };
-            reader_to_queue(&self.name, &self.tap, QUEUE_RX, queue, irq_sender)?;
+            self.tap_to_queue(QUEUE_RX, queue, irq_sender)?;
         }
         if event.is_writable() {
             let Some(queue) = queues.get(QUEUE_TX as usize) else {
                 log::error!("{}: cannot find tx queue", self.name);
                 return Ok(());
             };
-            queue_to_writer(&self.name, &self.tap, QUEUE_TX, queue, irq_sender)?;
+            queue_to_tap(&self.name, &self.tap, QUEUE_TX, queue, irq_sender)?;
         }
         Ok(())
     }
@@ -244,19 +724,118 @@ impl Virtio for Net {
             log::error!("{}: invalid queue index {index}", self.name);
             return Ok(());
         };
-        if index == self.config.max_queue_pairs * 2 {
-            unimplemented!()
+        if index == self.max_queue_pairs * 2 {
+            handle_desc(&self.name, index, queue, irq_sender, |desc| {
+                self.handle_ctrl(desc, irq_sender)
+            })
         } else if index & 1 == 0 {
-            reader_to_queue(&self.name, &self.tap, index, queue, irq_sender)
+            self.tap_to_queue(index, queue, irq_sender)
         } else {
-            queue_to_writer(&self.name, &self.tap, index, queue, irq_sender)
+            queue_to_tap(&self.name, &self.tap, index, queue, irq_sender)
         }
     }
 }
 
 pub const TOKEN_TAP: Token = Token(0);
+pub const TOKEN_LINK: Token = Token(1);
+
+const VNET_HEADER_SIZE: i32 = size_of::<VirtioNetHdr>() as i32;
 
-const VNET_HEADER_SIZE: i32 = 12;
+/// Fixes up the checksum offloading of a frame read from the tap.
+///
+/// With `GUEST_CSUM`, the tap sets `DATA_VALID` for frames whose checksum
+/// the host has verified, and leaves the flags clear for others, e.g.
+/// those from a NIC without receive checksum offloading. The checksum of
+/// the latter is verified here, so the driver need not do it again.
+///
+/// Without `GUEST_CSUM`, frames queued in the tap before the driver
+/// disabled it may still carry partial checksums, which are completed
+/// here.
+fn complete_rx_csum(bufs: &mut [IoSliceMut], len: usize, rx_offloads: &AtomicU64) {
+    let offloads = NetFeature::from_bits_retain(rx_offloads.load(Ordering::Acquire));
+    let Some(flags) = bufs.first().and_then(|b| b.first()) else {
+        return;
+    };
+    let flags = VnetHdrFlag::from_bits_retain(*flags);
+    let guest_csum = offloads.contains(NetFeature::GUEST_CSUM);
+    if guest_csum && !flags.is_empty() || !guest_csum && !flags.contains(VnetHdrFlag::NEEDS_CSUM) {
+        return;
+    }
+    let mut frame: Vec<u8> = bufs
+        .iter()
+        .flat_map(|b| b.iter().copied())
+        .take(len)
+        .collect();
+    if guest_csum {
+        if gso::validate_csum(&mut frame) {
+            bufs[0][0] = frame[0];
+        }
+        return;
+    }
+    if !gso::complete_csum(&mut frame) {
+        return;
+    }
+    let mut rest = frame.as_slice();
+    for buf in bufs {
+        let n = std::cmp::min(buf.len(), rest.len());
+        buf[..n].copy_from_slice(&rest[..n]);
+        rest = &rest[n..];
+    }
+}
+
+/// Writes frames to the tap straight from the guest buffers.
+///
+/// The tap is a character device rather than a socket, so `MSG_ZEROCOPY`
+/// does not apply here; the kernel copies the frame into an skb during
+/// `writev()`.
+fn queue_to_tap(
+    name: &str,
+    mut tap: &File,
+    index: u16,
+    queue: &impl VirtQueue,
+    irq_sender: &impl IrqSender,
+) -> Result<()> {
+    handle_desc_batch(name, index, queue, irq_sender, DESC_BATCH_SIZE, |desc| {
+        match tap.write_vectored(&desc.readable) {
+            Ok(0) => Err(ErrorKind::WriteZero.into()),
+            Ok(_) => Ok(0),
+            // The tap device rejects GSO types it does not know, e.g. USO
+            // on older kernels.
+            Err(e) if e.raw_os_error() == Some(libc::EINVAL) => {
+                let frame: Vec<u8> = desc
+                    .readable
+                    .iter()
+                    .flat_map(|b| b.iter().copied())
+                    .collect();
+                match write_segments(name, tap, &frame) {
+                    Some(r) => r.map(|()| 0),
+                    None => Err(e),
+                }
+            }
+            Err(e) => Err(e),
+        }
+    })
+}
+
+/// Segments a GSO packet in software and writes the segments to the tap.
+///
+/// Returns `None` if the frame is not a GSO packet.
+fn write_segments(name: &str, mut tap: &File, frame: &[u8]) -> Option<io::Result<()>> {
+    let hdr = VirtioNetHdr::read_from_prefix(frame)?;
+    if hdr.gso_type == GsoType::NONE {
+        return None;
+    }
+    let Some(segs) = gso::segment(&hdr, &frame[size_of::<VirtioNetHdr>()..]) else {
+        log::error!("{name}: cannot segment packet with {hdr:x?}, dropped");
+        return Some(Ok(()));
+    };
+    for seg in segs {
+        if let Err(e) = tap.write(&seg) {
+            return Some(Err(e));
+        }
+    }
+    Some(Ok(()))
+}
 
 fn setup_tap(file: &mut File, if_name: Option<&str>) -> Result<()> {
     let mut tap_ifconfig = match if_name {
@@ -277,6 +856,11 @@ fn setup_tap(file: &mut File, if_name: Option<&str>) -> Result<()> {
 }
 
 fn detect_tap_offload(tap: &impl AsRawFd) -> NetFeature {
+    // Offloads are carried in the virtio-net header.
+    match unsafe { tun_get_features(tap) } {
+        Ok(features) if features & IFF_VNET_HDR as u32 != 0 => {}
+        _ => return NetFeature::empty(),
+    }
     let mut tap_feature = TunFeature::all();
     let mut dev_feat = NetFeature::GUEST_CSUM
         | NetFeature::GUEST_TSO4
@@ -301,7 +885,7 @@ fn detect_tap_offload(tap: &impl AsRawFd) -> NetFeature {
     NetFeature::empty()
 }
 
-fn enable_tap_offload(tap: &mut File, feature: NetFeature) -> io::Result<i32> {
+fn enable_tap_offload(tap: &File, feature: NetFeature) -> io::Result<i32> {
     let mut tap_feature = TunFeature::empty();
     if feature.contains(NetFeature::GUEST_CSUM) {
         tap_feature |= TunFeature::CSUM;
@@ -326,3 +910,445 @@ fn enable_tap_offload(tap: &mut File, feature: NetFeature) -> io::Result<i32> {
     }
     unsafe { tun_set_offload(tap, tap_feature.bits()) }
 }
+
+#[cfg(test)]
+mod test {
+    use std::fs::{self, File};
+    use std::io::{IoSlice, IoSliceMut};
+    use std::mem::size_of;
+    use std::os::fd::OwnedFd;
+    use std::os::unix::net::UnixDatagram;
+    use std::process::Command;
+    use std::sync::atomic::{AtomicBool, AtomicU16, AtomicU64, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    use parking_lot::RwLock;
+
+    use crate::hv::test::FakeVmMemory;
+    use crate::mem::emulated::Mmio;
+    use crate::mem::mapped::{ArcMemPages, RamBus};
+    use crate::mem::MemRegionType;
+    use crate::net::MacAddr;
+    use crate::virtio::dev::net::filter::RxFilter;
+    use crate::virtio::queue::split::{AvailHeader, Desc, DescFlag, SplitQueue, UsedHeader};
+    use crate::virtio::queue::{Descriptor, Queue};
+    use crate::virtio::test::FakeIrqSender;
+
+    use zerocopy::{AsBytes, FromBytes};
+
+    use super::gso::{self, VirtioNetHdr, VnetHdrFlag};
+    use super::{
+        complete_rx_csum, default_mac, link_monitor, link_settings, setup_tap, CtrlAck, CtrlClass,
+        CtrlGuestOffloadsCmd, CtrlMacCmd, CtrlRxCmd, Duplex, Net, NetConfig, NetConfigMmio,
+        NetFeature, NetStatus, QUEUE_RX, SPEED_UNKNOWN,
+    };
+
+    fn fake_net(feature: NetFeature) -> Net {
+        Net {
+            name: Arc::new("net-test".to_owned()),
+            config: Arc::new(NetConfigMmio {
+                config: RwLock::new(NetConfig {
+                    status: NetStatus::LINK_UP.bits(),
+                    ..Default::default()
+                }),
+            }),
+            tap: File::open("/dev/null").unwrap(),
+            feature,
+            driver_feature: feature,
+            rx_offloads: AtomicU64::new(0),
+            rx_filter: RwLock::new(RxFilter::default()),
+            max_queue_pairs: 1,
+            speed: None,
+            duplex: None,
+            watchdog: None,
+            link_monitor: None,
+        }
+    }
+
+    #[test]
+    fn test_link_status() {
+        let net = fake_net(NetFeature::STATUS);
+        let irq_sender = FakeIrqSender::default();
+        let status = || NetStatus::from_bits_retain(net.config.config.read().status);
+
+        net.set_link_status(true, &irq_sender);
+        assert_eq!(irq_sender.config_irqs.load(Ordering::Acquire), 0);
+
+        net.set_link_status(false, &irq_sender);
+        assert!(!status().contains(NetStatus::LINK_UP));
+        assert_eq!(irq_sender.config_irqs.load(Ordering::Acquire), 1);
+
+        net.set_link_status(false, &irq_sender);
+        assert_eq!(irq_sender.config_irqs.load(Ordering::Acquire), 1);
+
+        net.set_link_status(true, &irq_sender);
+        assert!(status().contains(NetStatus::LINK_UP));
+        assert_eq!(irq_sender.config_irqs.load(Ordering::Acquire), 2);
+    }
+
+    #[test]
+    fn test_speed_duplex() {
+        let mut net = fake_net(NetFeature::STATUS | NetFeature::SPEED_DUPLEX);
+        net.speed = Some(1000);
+        net.duplex = Some(Duplex::Full);
+        let irq_sender = FakeIrqSender::default();
+
+        net.update_link_settings();
+        let config = &*net.config;
+        assert_eq!(config.read(12, 4).unwrap(), 1000);
+        assert_eq!(config.read(16, 1).unwrap(), 1);
+
+        // changes are picked up when the link comes back
+        net.set_link_status(false, &irq_sender);
+        net.speed = Some(100);
+        net.duplex = Some(Duplex::Half);
+        net.set_link_status(true, &irq_sender);
+        assert_eq!(irq_sender.config_irqs.load(Ordering::Acquire), 2);
+        let config = &*net.config;
+        assert_eq!(config.read(12, 4).unwrap(), 100);
+        assert_eq!(config.read(16, 1).unwrap(), 0);
+
+        // /dev/null is not a tap
+        let tap = File::open("/dev/null").unwrap();
+        assert_eq!(
+            link_settings("net-test", &tap, None, Some(Duplex::Full)),
+            (SPEED_UNKNOWN, 1)
+        );
+    }
+
+    #[test]
+    fn test_tap_link_settings() {
+        let Ok(mut tap) = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open("/dev/net/tun")
+        else {
+            return;
+        };
+        if setup_tap(&mut tap, Some("alioth-test1")).is_err() {
+            return;
+        }
+        // tap interfaces report full duplex unless changed by ethtool
+        let (speed, duplex) = link_settings("net-test", &tap, None, None);
+        assert_ne!(speed, SPEED_UNKNOWN);
+        assert_eq!(duplex, Duplex::Full as u8);
+        assert_eq!(
+            link_settings("net-test", &tap, Some(1), None),
+            (1, Duplex::Full as u8)
+        );
+    }
+
+    #[test]
+    fn test_tap_link_monitor() {
+        let Ok(mut tap) = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open("/dev/net/tun")
+        else {
+            return;
+        };
+        if setup_tap(&mut tap, Some("alioth-test2")).is_err() {
+            return;
+        }
+        let mut net = fake_net(NetFeature::STATUS);
+        net.link_monitor = link_monitor("net-test", &tap);
+        let irq_sender = FakeIrqSender::default();
+        let wait_link_status = |up: bool| {
+            for _ in 0..100 {
+                net.handle_link_event(&irq_sender);
+                let status = NetStatus::from_bits_retain(net.config.config.read().status);
+                if status.contains(NetStatus::LINK_UP) == up {
+                    return;
+                }
+                thread::sleep(Duration::from_millis(10));
+            }
+            panic!("link is not {}", if up { "up" } else { "down" });
+        };
+
+        // a new tap interface is down
+        net.link_monitor.as_ref().unwrap().request().unwrap();
+        wait_link_status(false);
+        assert_eq!(irq_sender.config_irqs.load(Ordering::Acquire), 1);
+
+        let ip_link_set = |state| {
+            Command::new("ip")
+                .args(["link", "set", "alioth-test2", state])
+                .status()
+                .is_ok_and(|s| s.success())
+        };
+        if !ip_link_set("up") {
+            return;
+        }
+        wait_link_status(true);
+        assert!(ip_link_set("down"));
+        wait_link_status(false);
+        assert_eq!(irq_sender.config_irqs.load(Ordering::Acquire), 3);
+    }
+
+    #[test]
+    fn test_ctrl_mac_addr_set() {
+        let net = fake_net(NetFeature::CTRL_VQ | NetFeature::CTRL_MAC_ADDR);
+        let irq_sender = FakeIrqSender::default();
+        let mac = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+
+        let hdr = [CtrlClass::MAC.raw(), CtrlMacCmd::ADDR_SET.raw()];
+        let mut ack = [0xff];
+        let mut desc = Descriptor {
+            id: 0,
+            readable: vec![IoSlice::new(&hdr), IoSlice::new(&mac)],
+            writable: vec![IoSliceMut::new(&mut ack)],
+        };
+        assert_eq!(net.handle_ctrl(&mut desc, &irq_sender).unwrap(), 1);
+        drop(desc);
+        assert_eq!(ack[0], CtrlAck::OK.raw());
+        assert_eq!(irq_sender.config_irqs.load(Ordering::Acquire), 1);
+
+        let config = &*net.config;
+        assert_eq!(config.read(0, 4).unwrap(), 0x1200_5452);
+        assert_eq!(config.read(4, 2).unwrap(), 0x5634);
+
+        // the driver may also write the address directly
+        config.write(4, 2, 0x7856).unwrap();
+        assert_eq!(
+            net.config.config.read().mac,
+            MacAddr::new([0x52, 0x54, 0x00, 0x12, 0x56, 0x78])
+        );
+
+        let hdr = [CtrlClass::MAC.raw(), CtrlMacCmd::TABLE_SET.raw()];
+        let mut desc = Descriptor {
+            id: 0,
+            readable: vec![IoSlice::new(&hdr)],
+            writable: vec![IoSliceMut::new(&mut ack)],
+        };
+        net.handle_ctrl(&mut desc, &irq_sender).unwrap();
+        drop(desc);
+        assert_eq!(ack[0], CtrlAck::ERR.raw());
+    }
+
+    #[test]
+    fn test_default_mac() {
+        let mac = default_mac("virtio-net-0").octets();
+        assert_eq!(mac[0] & 0b11, 0b10);
+        assert_eq!(default_mac("virtio-net-0").octets(), mac);
+        assert_ne!(default_mac("virtio-net-1").octets(), mac);
+    }
+
+    fn set_guest_offloads(net: &Net, offloads: NetFeature) -> u8 {
+        let hdr = [
+            CtrlClass::GUEST_OFFLOADS.raw(),
+            CtrlGuestOffloadsCmd::SET.raw(),
+        ];
+        let offloads = offloads.bits().to_le_bytes();
+        let mut ack = [0xff];
+        let mut desc = Descriptor {
+            id: 0,
+            readable: vec![IoSlice::new(&hdr), IoSlice::new(&offloads)],
+            writable: vec![IoSliceMut::new(&mut ack)],
+        };
+        net.handle_ctrl(&mut desc, &FakeIrqSender::default())
+            .unwrap();
+        drop(desc);
+        ack[0]
+    }
+
+    #[test]
+    fn test_ctrl_guest_offloads() {
+        let feature = NetFeature::CTRL_VQ
+            | NetFeature::CTRL_GUEST_OFFLOADS
+            | NetFeature::GUEST_CSUM
+            | NetFeature::GUEST_TSO4;
+        let mut net = fake_net(feature);
+        net.rx_offloads.store(
+            (NetFeature::GUEST_CSUM | NetFeature::GUEST_TSO4).bits(),
+            Ordering::Relaxed,
+        );
+        // offloads not negotiated are rejected
+        assert_eq!(
+            set_guest_offloads(&net, NetFeature::GUEST_UFO),
+            CtrlAck::ERR.raw()
+        );
+
+        let Ok(mut tap) = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open("/dev/net/tun")
+        else {
+            return;
+        };
+        if setup_tap(&mut tap, Some("alioth-test0")).is_err() {
+            return;
+        }
+        net.tap = tap;
+        assert_eq!(
+            set_guest_offloads(&net, NetFeature::empty()),
+            CtrlAck::OK.raw()
+        );
+        assert_eq!(net.rx_offloads.load(Ordering::Acquire), 0);
+
+        // a UDP frame queued before GUEST_CSUM was disabled
+        let mut frame = vec![0u8; 14 + 20 + 8];
+        frame[12..14].copy_from_slice(&0x0800u16.to_be_bytes());
+        frame[14] = 0x45;
+        frame[14 + 9] = 17;
+        frame[26..30].copy_from_slice(&[10, 0, 0, 1]);
+        frame[30..34].copy_from_slice(&[10, 0, 0, 2]);
+        frame[38..40].copy_from_slice(&(8u16 + 4).to_be_bytes());
+        frame.extend_from_slice(b"ping");
+        // pseudo header: addresses, protocol and UDP length
+        let pseudo: u32 = 0x0a00 + 0x0001 + 0x0a00 + 0x0002 + 17 + 12;
+        frame[40..42].copy_from_slice(&(pseudo as u16).to_be_bytes());
+        let hdr = VirtioNetHdr {
+            flags: VnetHdrFlag::NEEDS_CSUM.bits(),
+            csum_start: 34,
+            csum_offset: 6,
+            ..Default::default()
+        };
+        let hdr = hdr.as_bytes();
+        let mut buf0 = hdr.to_vec();
+        let mut buf1 = frame.clone();
+        let len = buf0.len() + buf1.len();
+        let mut bufs = [IoSliceMut::new(&mut buf0), IoSliceMut::new(&mut buf1)];
+        complete_rx_csum(&mut bufs, len, &net.rx_offloads);
+
+        let hdr = VirtioNetHdr::read_from(buf0.as_slice()).unwrap();
+        assert_eq!(hdr.flags, 0);
+        assert_eq!(hdr.csum_start, 0);
+        assert_eq!(hdr.csum_offset, 0);
+        assert_eq!(&buf1[..40], &frame[..40]);
+        assert_eq!(u16::from_be_bytes([buf1[40], buf1[41]]), 0x0d03);
+    }
+
+    #[test]
+    fn test_rx_csum_data_valid() {
+        let net = fake_net(NetFeature::GUEST_CSUM);
+        net.rx_offloads
+            .store(NetFeature::GUEST_CSUM.bits(), Ordering::Relaxed);
+
+        let mut frame = VirtioNetHdr {
+            flags: VnetHdrFlag::NEEDS_CSUM.bits(),
+            csum_start: 34,
+            csum_offset: 16,
+            ..Default::default()
+        }
+        .as_bytes()
+        .to_vec();
+        let mut pkt = vec![0u8; 14 + 20 + 20];
+        pkt[12..14].copy_from_slice(&0x0800u16.to_be_bytes());
+        pkt[14] = 0x45;
+        pkt[16..18].copy_from_slice(&(20u16 + 20 + 4).to_be_bytes());
+        pkt[14 + 9] = 6;
+        pkt[26..30].copy_from_slice(&[10, 0, 0, 1]);
+        pkt[30..34].copy_from_slice(&[10, 0, 0, 2]);
+        pkt[34 + 12] = 5 << 4;
+        pkt.extend_from_slice(b"ping");
+        // pseudo header: addresses, protocol and TCP length
+        let pseudo: u32 = 0x0a00 + 0x0001 + 0x0a00 + 0x0002 + 6 + 24;
+        pkt[50..52].copy_from_slice(&(pseudo as u16).to_be_bytes());
+        frame.extend_from_slice(&pkt);
+        // a valid TCP frame as the tap delivers it without validation
+        assert!(gso::complete_csum(&mut frame));
+        let (hdr, pkt) = frame.split_at(size_of::<VirtioNetHdr>());
+
+        let rx = |pkt: &[u8]| {
+            let mut buf0 = hdr.to_vec();
+            let mut buf1 = pkt.to_vec();
+            let len = buf0.len() + buf1.len();
+            let mut bufs = [IoSliceMut::new(&mut buf0), IoSliceMut::new(&mut buf1)];
+            complete_rx_csum(&mut bufs, len, &net.rx_offloads);
+            assert_eq!(buf1, pkt);
+            VirtioNetHdr::read_from(buf0.as_slice()).unwrap().flags
+        };
+        assert_eq!(rx(pkt), VnetHdrFlag::DATA_VALID.bits());
+
+        let mut corrupted = pkt.to_vec();
+        corrupted[54] ^= 1;
+        assert_eq!(rx(&corrupted), 0);
+    }
+
+    fn send_ctrl(net: &Net, hdr: [u8; 2], data: &[u8]) -> u8 {
+        let mut ack = [0xff];
+        let mut desc = Descriptor {
+            id: 0,
+            readable: vec![IoSlice::new(&hdr), IoSlice::new(data)],
+            writable: vec![IoSliceMut::new(&mut ack)],
+        };
+        net.handle_ctrl(&mut desc, &FakeIrqSender::default())
+            .unwrap();
+        drop(desc);
+        ack[0]
+    }
+
+    #[test]
+    fn test_rx_filter() {
+        const MAC: [u8; 6] = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+        const UNI: [u8; 6] = [0x52, 0x54, 0x00, 0xab, 0xcd, 0xef];
+        const OTHER: [u8; 6] = [0x52, 0x54, 0x00, 0x00, 0x00, 0x01];
+        const MULTI: [u8; 6] = [0x01, 0x00, 0x5e, 0x00, 0x00, 0xfb];
+        const DESC_ADDR: u64 = 0x0;
+        const AVAIL_ADDR: u64 = 0x100;
+        const USED_ADDR: u64 = 0x200;
+        const BUF_ADDR: u64 = 0x1000;
+        const BUF_SIZE: u32 = 0x40;
+
+        let mut net = fake_net(NetFeature::CTRL_VQ | NetFeature::CTRL_RX);
+        let (host, tap) = UnixDatagram::pair().unwrap();
+        tap.set_nonblocking(true).unwrap();
+        net.tap = File::from(OwnedFd::from(tap));
+        net.config.config.write().mac = MacAddr::new(MAC);
+
+        let rx = |cmd: CtrlRxCmd, on: u8| send_ctrl(&net, [CtrlClass::RX.raw(), cmd.raw()], &[on]);
+        assert_eq!(rx(CtrlRxCmd::PROMISC, 0), CtrlAck::OK.raw());
+        // requires VIRTIO_NET_F_CTRL_RX_EXTRA
+        assert_eq!(rx(CtrlRxCmd::ALLUNI, 1), CtrlAck::ERR.raw());
+        let mut table = vec![1, 0, 0, 0];
+        table.extend_from_slice(&UNI);
+        table.extend_from_slice(&[0, 0, 0, 0]);
+        let hdr = [CtrlClass::MAC.raw(), CtrlMacCmd::TABLE_SET.raw()];
+        assert_eq!(send_ctrl(&net, hdr, &table), CtrlAck::OK.raw());
+
+        let ram_bus = Arc::new(RamBus::new(FakeVmMemory));
+        let pages = ArcMemPages::from_anonymous(0x2000, None).unwrap();
+        ram_bus.add(0, pages, MemRegionType::Ram, false).unwrap();
+        let descs = [0, 1].map(|i| Desc {
+            addr: BUF_ADDR + (i * BUF_SIZE) as u64,
+            len: BUF_SIZE,
+            flag: DescFlag::WRITE.bits(),
+            next: 0,
+        });
+        ram_bus.write(DESC_ADDR, &descs).unwrap();
+        let ring_addr = AVAIL_ADDR + size_of::<AvailHeader>() as u64;
+        ram_bus.write(ring_addr, &[0u16, 1]).unwrap();
+        ram_bus.write(AVAIL_ADDR, &[0u16, 2]).unwrap();
+        let reg = Queue {
+            size: AtomicU16::new(4),
+            desc: AtomicU64::new(DESC_ADDR),
+            driver: AtomicU64::new(AVAIL_ADDR),
+            device: AtomicU64::new(USED_ADDR),
+            enabled: AtomicBool::new(true),
+        };
+        let queue = SplitQueue::new(&reg, ram_bus.clone(), 0, None);
+
+        let hdr_size = size_of::<VirtioNetHdr>();
+        let frame_len = hdr_size + 14;
+        for dest in [OTHER, UNI, MULTI, MAC] {
+            let mut frame = vec![0u8; frame_len];
+            frame[hdr_size..hdr_size + 6].copy_from_slice(&dest);
+            host.send(&frame).unwrap();
+        }
+        net.tap_to_queue(QUEUE_RX, &queue, &FakeIrqSender::default())
+            .unwrap();
+
+        let [_flags, used_index]: [u16; 2] = ram_bus.read(USED_ADDR).unwrap();
+        assert_eq!(used_index, 2);
+        let used_elems: [[u32; 2]; 2] = ram_bus
+            .read(USED_ADDR + size_of::<UsedHeader>() as u64)
+            .unwrap();
+        assert_eq!(used_elems, [[0, frame_len as u32], [1, frame_len as u32]]);
+        for (i, dest) in [UNI, MAC].iter().enumerate() {
+            let addr = BUF_ADDR + (i as u32 * BUF_SIZE) as u64 + hdr_size as u64;
+            assert_eq!(&ram_bus.read::<[u8; 6]>(addr).unwrap(), dest);
+        }
+    }
+}
@@ -12,16 +12,27 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::io::{self, ErrorKind};
+use std::mem::{size_of, MaybeUninit};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+
 use bitflags::bitflags;
-use libc::{c_int, c_uint, c_ulong, ifreq};
+use libc::{
+    bind, c_char, c_int, c_uint, c_ulong, ifreq, recv, send, sockaddr, sockaddr_nl, socket,
+    AF_INET, AF_NETLINK, IFNAMSIZ, NETLINK_ROUTE, NLM_F_REQUEST, RTMGRP_LINK, RTM_GETLINK,
+    RTM_NEWLINK, SIOCETHTOOL, SOCK_CLOEXEC, SOCK_DGRAM, SOCK_NONBLOCK, SOCK_RAW,
+};
+use zerocopy::{AsBytes, FromBytes, FromZeroes};
 
 use crate::utils::ioctls::{ioctl_ior, ioctl_iow};
-use crate::{ioctl_read, ioctl_write_ptr, ioctl_write_val};
+use crate::{ffi, ioctl_read, ioctl_write_ptr, ioctl_write_val, ioctl_writeread};
 
 ioctl_write_ptr!(tun_set_iff, ioctl_iow::<c_int>(b'T', 202), ifreq);
 
 ioctl_write_val!(tun_set_offload, ioctl_iow::<c_uint>(b'T', 208));
 
+ioctl_read!(tun_get_features, b'T', 207, c_uint);
+
 ioctl_read!(tun_get_iff, ioctl_ior::<c_uint>(b'T', 210), ifreq);
 
 ioctl_read!(tun_get_vnet_hdr_sz, b'T', 215, c_int);
@@ -40,3 +51,239 @@ bitflags! {
         const USO6 = 0x40;
     }
 }
+
+ioctl_writeread!(ethtool, SIOCETHTOOL);
+
+const ETHTOOL_GLINKSETTINGS: u32 = 0x4c;
+
+/// `struct ethtool_link_settings`, without the trailing link mode masks.
+#[repr(C)]
+#[derive(Debug, Default, Clone, FromBytes, FromZeroes, AsBytes)]
+pub struct EthtoolLinkSettings {
+    pub cmd: u32,
+    pub speed: u32,
+    pub duplex: u8,
+    pub port: u8,
+    pub phy_address: u8,
+    pub autoneg: u8,
+    pub mdio_support: u8,
+    pub eth_tp_mdix: u8,
+    pub eth_tp_mdix_ctrl: u8,
+    pub link_mode_masks_nwords: i8,
+    pub transceiver: u8,
+    pub master_slave_cfg: u8,
+    pub master_slave_state: u8,
+    pub rate_matching: u8,
+    pub reserved: [u32; 7],
+}
+
+fn ethtool_link_settings(
+    sock: &OwnedFd,
+    if_name: &[c_char; IFNAMSIZ],
+    nwords: i8,
+) -> io::Result<Vec<u32>> {
+    let settings = EthtoolLinkSettings {
+        cmd: ETHTOOL_GLINKSETTINGS,
+        link_mode_masks_nwords: nwords,
+        ..Default::default()
+    };
+    // the link mode masks are supported, advertised and peer-advertised
+    let num_words = size_of::<EthtoolLinkSettings>() / 4 + 3 * nwords as usize;
+    let mut buf = vec![0u32; num_words];
+    settings.write_to_prefix(buf.as_bytes_mut());
+    let mut req = unsafe { MaybeUninit::<ifreq>::zeroed().assume_init() };
+    req.ifr_name = *if_name;
+    req.ifr_ifru.ifru_data = buf.as_mut_ptr() as _;
+    unsafe { ethtool(sock, &mut req) }?;
+    Ok(buf)
+}
+
+/// Queries the link settings of network interface `if_name` with
+/// `ETHTOOL_GLINKSETTINGS`.
+pub fn get_link_settings(if_name: &[c_char; IFNAMSIZ]) -> io::Result<EthtoolLinkSettings> {
+    let fd = ffi!(unsafe { socket(AF_INET, SOCK_DGRAM | SOCK_CLOEXEC, 0) })?;
+    let sock = unsafe { OwnedFd::from_raw_fd(fd) };
+    // The first call returns the negated number of words in each link mode
+    // mask supported by the kernel.
+    let buf = ethtool_link_settings(&sock, if_name, 0)?;
+    let settings = EthtoolLinkSettings::read_from_prefix(buf.as_bytes()).unwrap();
+    let nwords = settings.link_mode_masks_nwords.checked_neg();
+    let Some(nwords) = nwords.filter(|n| *n > 0) else {
+        return Err(io::ErrorKind::Unsupported.into());
+    };
+    let buf = ethtool_link_settings(&sock, if_name, nwords)?;
+    Ok(EthtoolLinkSettings::read_from_prefix(buf.as_bytes()).unwrap())
+}
+
+/// `struct nlmsghdr`
+#[repr(C)]
+#[derive(Debug, Default, Clone, FromBytes, FromZeroes, AsBytes)]
+pub struct NlMsgHdr {
+    pub len: u32,
+    pub type_: u16,
+    pub flags: u16,
+    pub seq: u32,
+    pub pid: u32,
+}
+
+/// `struct ifinfomsg`
+#[repr(C)]
+#[derive(Debug, Default, Clone, FromBytes, FromZeroes, AsBytes)]
+pub struct IfInfoMsg {
+    pub family: u8,
+    pub _pad: u8,
+    pub type_: u16,
+    pub index: i32,
+    pub flags: u32,
+    pub change: u32,
+}
+
+/// Watches the flags of a network interface through `RTM_NEWLINK`
+/// messages from rtnetlink.
+#[derive(Debug)]
+pub struct LinkMonitor {
+    sock: OwnedFd,
+    if_index: i32,
+}
+
+impl LinkMonitor {
+    pub fn new(if_index: i32) -> io::Result<Self> {
+        let fd = ffi!(unsafe {
+            socket(
+                AF_NETLINK,
+                SOCK_RAW | SOCK_CLOEXEC | SOCK_NONBLOCK,
+                NETLINK_ROUTE,
+            )
+        })?;
+        let sock = unsafe { OwnedFd::from_raw_fd(fd) };
+        let mut addr = unsafe { MaybeUninit::<sockaddr_nl>::zeroed().assume_init() };
+        addr.nl_family = AF_NETLINK as _;
+        addr.nl_groups = RTMGRP_LINK as _;
+        ffi!(unsafe {
+            bind(
+                fd,
+                &addr as *const sockaddr_nl as *const sockaddr,
+                size_of::<sockaddr_nl>() as _,
+            )
+        })?;
+        Ok(LinkMonitor { sock, if_index })
+    }
+
+    /// Asks the kernel for the current flags of the interface, which
+    /// arrive as an `RTM_NEWLINK` message.
+    pub fn request(&self) -> io::Result<()> {
+        #[repr(C)]
+        #[derive(AsBytes)]
+        struct Request {
+            hdr: NlMsgHdr,
+            info: IfInfoMsg,
+        }
+        let req = Request {
+            hdr: NlMsgHdr {
+                len: size_of::<Request>() as u32,
+                type_: RTM_GETLINK,
+                flags: NLM_F_REQUEST as u16,
+                ..Default::default()
+            },
+            info: IfInfoMsg {
+                index: self.if_index,
+                ..Default::default()
+            },
+        };
+        let buf = req.as_bytes();
+        ffi!(unsafe { send(self.sock.as_raw_fd(), buf.as_ptr() as _, buf.len(), 0) })?;
+        Ok(())
+    }
+
+    /// Drains pending messages and returns the latest flags of the
+    /// interface, if any.
+    pub fn read_flags(&self) -> io::Result<Option<u32>> {
+        let mut buf = vec![0u8; 8192];
+        let mut flags = None;
+        loop {
+            let ret =
+                ffi!(unsafe { recv(self.sock.as_raw_fd(), buf.as_mut_ptr() as _, buf.len(), 0) });
+            match ret {
+                Ok(len) => {
+                    if let Some(f) = parse_link_flags(&buf[..len as usize], self.if_index) {
+                        flags = Some(f);
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break Ok(flags),
+                Err(e) => break Err(e),
+            }
+        }
+    }
+}
+
+impl AsRawFd for LinkMonitor {
+    fn as_raw_fd(&self) -> RawFd {
+        self.sock.as_raw_fd()
+    }
+}
+
+/// Returns the flags in the last `RTM_NEWLINK` message of interface
+/// `if_index` in `buf`.
+pub fn parse_link_flags(mut buf: &[u8], if_index: i32) -> Option<u32> {
+    let mut flags = None;
+    while let Some(hdr) = NlMsgHdr::read_from_prefix(buf) {
+        let len = hdr.len as usize;
+        if len < size_of::<NlMsgHdr>() || len > buf.len() {
+            break;
+        }
+        if hdr.type_ == RTM_NEWLINK {
+            let info = IfInfoMsg::read_from_prefix(&buf[size_of::<NlMsgHdr>()..len]);
+            if let Some(info) = info.filter(|info| info.index == if_index) {
+                flags = Some(info.flags);
+            }
+        }
+        // messages are aligned to 4 bytes
+        buf = buf.get((len + 3) & !3..).unwrap_or_default();
+    }
+    flags
+}
+
+#[cfg(test)]
+mod test {
+    use std::mem::size_of;
+
+    use libc::{RTM_DELLINK, RTM_NEWLINK};
+    use zerocopy::AsBytes;
+
+    use super::{parse_link_flags, IfInfoMsg, NlMsgHdr};
+
+    fn link_msg(type_: u16, index: i32, flags: u32, attrs_len: usize) -> Vec<u8> {
+        let len = size_of::<NlMsgHdr>() + size_of::<IfInfoMsg>() + attrs_len;
+        let hdr = NlMsgHdr {
+            len: len as u32,
+            type_,
+            ..Default::default()
+        };
+        let info = IfInfoMsg {
+            index,
+            flags,
+            ..Default::default()
+        };
+        let mut msg = [hdr.as_bytes(), info.as_bytes()].concat();
+        msg.resize((len + 3) & !3, 0xff);
+        msg
+    }
+
+    #[test]
+    fn test_parse_link_flags() {
+        let buf = [
+            link_msg(RTM_NEWLINK, 3, 0x1, 5),
+            link_msg(RTM_NEWLINK, 4, 0x2, 8),
+            link_msg(RTM_DELLINK, 3, 0x4, 0),
+            link_msg(RTM_NEWLINK, 3, 0x8, 2),
+            link_msg(RTM_NEWLINK, 5, 0x10, 0),
+        ]
+        .concat();
+        assert_eq!(parse_link_flags(&buf, 3), Some(0x8));
+        assert_eq!(parse_link_flags(&buf, 4), Some(0x2));
+        assert_eq!(parse_link_flags(&buf, 6), None);
+        // a truncated message is ignored
+        let msg = link_msg(RTM_NEWLINK, 3, 0x1, 0);
+        assert_eq!(parse_link_flags(&msg[..msg.len() - 1], 3), None);
+    }
+}
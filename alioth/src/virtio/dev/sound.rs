@@ -0,0 +1,792 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::fmt::Debug;
+use std::io::{self, ErrorKind, IoSliceMut, Write};
+use std::mem::size_of;
+use std::process::{Child, Command, Stdio};
+use std::sync::Arc;
+
+use bitflags::bitflags;
+use mio::event::Event;
+use mio::Registry;
+use serde::Deserialize;
+use zerocopy::{AsBytes, FromBytes, FromZeroes};
+
+use crate::mem::mapped::RamBus;
+use crate::virtio::dev::{DevParam, DeviceId, Virtio};
+use crate::virtio::queue::handlers::handle_desc;
+use crate::virtio::queue::{Descriptor, Queue, VirtQueue};
+use crate::virtio::{IrqSender, Result, FEATURE_BUILT_IN};
+use crate::{c_enum, impl_mmio_for_zerocopy};
+
+const CONTROL_QUEUE: u16 = 0;
+const EVENT_QUEUE: u16 = 1;
+const TX_QUEUE: u16 = 2;
+const RX_QUEUE: u16 = 3;
+
+const NUM_STREAMS: u32 = 1;
+const CHANNELS_MIN: u8 = 1;
+const CHANNELS_MAX: u8 = 2;
+
+c_enum! {
+    #[derive(FromBytes, FromZeroes)]
+    pub struct RequestCode(u32);
+    {
+        JACK_INFO = 1;
+        JACK_REMAP = 2;
+        PCM_INFO = 0x100;
+        PCM_SET_PARAMS = 0x101;
+        PCM_PREPARE = 0x102;
+        PCM_RELEASE = 0x103;
+        PCM_START = 0x104;
+        PCM_STOP = 0x105;
+        CHMAP_INFO = 0x200;
+    }
+}
+
+c_enum! {
+    #[derive(FromBytes, FromZeroes, AsBytes)]
+    pub struct StatusCode(u32);
+    {
+        OK = 0x8000;
+        BAD_MSG = 0x8001;
+        NOT_SUPP = 0x8002;
+        IO_ERR = 0x8003;
+    }
+}
+
+c_enum! {
+    #[derive(FromBytes, FromZeroes)]
+    pub struct PcmFormat(u8);
+    {
+        S8 = 3;
+        U8 = 4;
+        S16 = 5;
+        S32 = 17;
+        FLOAT = 19;
+    }
+}
+
+c_enum! {
+    #[derive(FromBytes, FromZeroes)]
+    pub struct PcmRate(u8);
+    {
+        R8000 = 1;
+        R11025 = 2;
+        R16000 = 3;
+        R22050 = 4;
+        R32000 = 5;
+        R44100 = 6;
+        R48000 = 7;
+        R88200 = 9;
+        R96000 = 10;
+    }
+}
+
+impl PcmRate {
+    pub fn hz(self) -> Option<u32> {
+        let hz = match self {
+            PcmRate::R8000 => 8000,
+            PcmRate::R11025 => 11025,
+            PcmRate::R16000 => 16000,
+            PcmRate::R22050 => 22050,
+            PcmRate::R32000 => 32000,
+            PcmRate::R44100 => 44100,
+            PcmRate::R48000 => 48000,
+            PcmRate::R88200 => 88200,
+            PcmRate::R96000 => 96000,
+            _ => return None,
+        };
+        Some(hz)
+    }
+}
+
+const SUPPORTED_FORMATS: [PcmFormat; 5] = [
+    PcmFormat::S8,
+    PcmFormat::U8,
+    PcmFormat::S16,
+    PcmFormat::S32,
+    PcmFormat::FLOAT,
+];
+
+const SUPPORTED_RATES: [PcmRate; 9] = [
+    PcmRate::R8000,
+    PcmRate::R11025,
+    PcmRate::R16000,
+    PcmRate::R22050,
+    PcmRate::R32000,
+    PcmRate::R44100,
+    PcmRate::R48000,
+    PcmRate::R88200,
+    PcmRate::R96000,
+];
+
+const DIRECTION_OUTPUT: u8 = 0;
+
+#[repr(C)]
+#[derive(Debug, Default, FromZeroes, FromBytes, AsBytes)]
+pub struct SoundConfig {
+    jacks: u32,
+    streams: u32,
+    chmaps: u32,
+}
+impl_mmio_for_zerocopy!(SoundConfig);
+
+bitflags! {
+    #[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    pub struct SoundFeature: u64 { }
+}
+
+#[repr(C)]
+#[derive(Debug, FromZeroes, FromBytes)]
+pub struct QueryInfo {
+    code: RequestCode,
+    start_id: u32,
+    count: u32,
+    size: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Default, FromZeroes, FromBytes, AsBytes)]
+pub struct PcmInfo {
+    hda_fn_nid: u32,
+    features: u32,
+    formats: u64,
+    rates: u64,
+    direction: u8,
+    channels_min: u8,
+    channels_max: u8,
+    padding: [u8; 5],
+}
+
+#[repr(C)]
+#[derive(Debug, FromZeroes, FromBytes)]
+pub struct PcmHdr {
+    code: RequestCode,
+    stream_id: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, FromZeroes, FromBytes)]
+pub struct PcmSetParams {
+    code: RequestCode,
+    stream_id: u32,
+    buffer_bytes: u32,
+    period_bytes: u32,
+    features: u32,
+    channels: u8,
+    format: PcmFormat,
+    rate: PcmRate,
+    padding: u8,
+}
+
+#[repr(C)]
+#[derive(Debug, FromZeroes, FromBytes)]
+pub struct PcmXfer {
+    stream_id: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, FromZeroes, FromBytes, AsBytes)]
+pub struct PcmStatus {
+    status: StatusCode,
+    latency_bytes: u32,
+}
+
+/// Parameters of an output stream negotiated by the driver.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PcmParams {
+    pub channels: u8,
+    pub format: PcmFormat,
+    pub rate: u32,
+}
+
+/// A host audio sink that receives the interleaved PCM frames the guest
+/// plays.
+pub trait AudioBackend: Debug + Send + Sync + 'static {
+    fn open(&mut self, params: &PcmParams) -> io::Result<()>;
+    fn write(&mut self, data: &[u8]) -> io::Result<()>;
+    fn close(&mut self);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum AudioBackendKind {
+    #[serde(alias = "alsa")]
+    Alsa,
+    #[serde(alias = "pipewire")]
+    Pipewire,
+}
+
+/// Plays PCM frames by piping them into `aplay` for ALSA or `pw-cat` for
+/// PipeWire.
+#[derive(Debug)]
+pub struct CommandBackend {
+    kind: AudioBackendKind,
+    child: Option<Child>,
+}
+
+impl CommandBackend {
+    pub fn new(kind: AudioBackendKind) -> Self {
+        CommandBackend { kind, child: None }
+    }
+
+    fn command(&self, params: &PcmParams) -> Command {
+        let channels = params.channels.to_string();
+        let rate = params.rate.to_string();
+        match self.kind {
+            AudioBackendKind::Alsa => {
+                let format = match params.format {
+                    PcmFormat::S8 => "S8",
+                    PcmFormat::U8 => "U8",
+                    PcmFormat::S32 => "S32_LE",
+                    PcmFormat::FLOAT => "FLOAT_LE",
+                    _ => "S16_LE",
+                };
+                let mut cmd = Command::new("aplay");
+                cmd.args([
+                    "-q", "-t", "raw", "-f", format, "-c", &channels, "-r", &rate, "-",
+                ]);
+                cmd
+            }
+            AudioBackendKind::Pipewire => {
+                let format = match params.format {
+                    PcmFormat::S8 => "s8",
+                    PcmFormat::U8 => "u8",
+                    PcmFormat::S32 => "s32",
+                    PcmFormat::FLOAT => "f32",
+                    _ => "s16",
+                };
+                let mut cmd = Command::new("pw-cat");
+                cmd.args([
+                    "--playback",
+                    "--raw",
+                    "--format",
+                    format,
+                    "--channels",
+                    &channels,
+                    "--rate",
+                    &rate,
+                    "-",
+                ]);
+                cmd
+            }
+        }
+    }
+}
+
+impl AudioBackend for CommandBackend {
+    fn open(&mut self, params: &PcmParams) -> io::Result<()> {
+        self.close();
+        let child = self
+            .command(params)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .spawn()?;
+        self.child = Some(child);
+        Ok(())
+    }
+
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        let Some(stdin) = self.child.as_mut().and_then(|c| c.stdin.as_mut()) else {
+            return Err(ErrorKind::NotConnected.into());
+        };
+        stdin.write_all(data)
+    }
+
+    fn close(&mut self) {
+        let Some(mut child) = self.child.take() else {
+            return;
+        };
+        drop(child.stdin.take());
+        if let Err(e) = child.wait() {
+            log::error!("failed to wait for {:?} backend: {e}", self.kind);
+        }
+    }
+}
+
+impl Drop for CommandBackend {
+    fn drop(&mut self) {
+        self.close()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum StreamState {
+    Idle,
+    ParamsSet,
+    Prepared,
+    Running,
+}
+
+#[derive(Debug)]
+struct Stream {
+    state: StreamState,
+    params: Option<PcmParams>,
+}
+
+#[derive(Debug)]
+pub struct Sound {
+    name: Arc<String>,
+    config: Arc<SoundConfig>,
+    backend: Box<dyn AudioBackend>,
+    stream: Stream,
+}
+
+impl Sound {
+    pub fn new(name: Arc<String>, backend: Box<dyn AudioBackend>) -> Self {
+        let config = SoundConfig {
+            jacks: 0,
+            streams: NUM_STREAMS,
+            chmaps: 0,
+        };
+        Sound {
+            name,
+            config: Arc::new(config),
+            backend,
+            stream: Stream {
+                state: StreamState::Idle,
+                params: None,
+            },
+        }
+    }
+
+    fn pcm_info(&self, query: &QueryInfo, buf: &mut Vec<u8>) -> StatusCode {
+        let end = query.start_id.checked_add(query.count);
+        if end.is_none_or(|end| end > NUM_STREAMS) {
+            return StatusCode::BAD_MSG;
+        }
+        let formats = SUPPORTED_FORMATS.iter().fold(0, |f, v| f | 1 << v.raw());
+        let rates = SUPPORTED_RATES.iter().fold(0, |r, v| r | 1 << v.raw());
+        for _ in 0..query.count {
+            let info = PcmInfo {
+                formats,
+                rates,
+                direction: DIRECTION_OUTPUT,
+                channels_min: CHANNELS_MIN,
+                channels_max: CHANNELS_MAX,
+                ..Default::default()
+            };
+            let mut bytes = info.as_bytes().to_vec();
+            bytes.resize(query.size as usize, 0);
+            buf.extend(bytes);
+        }
+        StatusCode::OK
+    }
+
+    fn pcm_set_params(&mut self, req: &PcmSetParams) -> StatusCode {
+        if self.stream.state == StreamState::Running {
+            return StatusCode::BAD_MSG;
+        }
+        let Some(rate) = req.rate.hz() else {
+            return StatusCode::NOT_SUPP;
+        };
+        if !SUPPORTED_FORMATS.contains(&req.format)
+            || !(CHANNELS_MIN..=CHANNELS_MAX).contains(&req.channels)
+        {
+            return StatusCode::NOT_SUPP;
+        }
+        if self.stream.state == StreamState::Prepared {
+            self.backend.close();
+        }
+        self.stream.params = Some(PcmParams {
+            channels: req.channels,
+            format: req.format,
+            rate,
+        });
+        self.stream.state = StreamState::ParamsSet;
+        StatusCode::OK
+    }
+
+    fn pcm_command(&mut self, code: RequestCode) -> StatusCode {
+        let stream = &mut self.stream;
+        let (next, valid) = match code {
+            RequestCode::PCM_PREPARE => (
+                StreamState::Prepared,
+                matches!(stream.state, StreamState::ParamsSet | StreamState::Prepared),
+            ),
+            RequestCode::PCM_START => (StreamState::Running, stream.state == StreamState::Prepared),
+            RequestCode::PCM_STOP => (StreamState::Prepared, stream.state == StreamState::Running),
+            RequestCode::PCM_RELEASE => (
+                StreamState::ParamsSet,
+                stream.state == StreamState::Prepared,
+            ),
+            _ => return StatusCode::NOT_SUPP,
+        };
+        if !valid {
+            log::error!("{}: {code:?} in state {:?}", self.name, stream.state);
+            return StatusCode::BAD_MSG;
+        }
+        match code {
+            RequestCode::PCM_PREPARE if stream.state == StreamState::ParamsSet => {
+                let Some(params) = &stream.params else {
+                    return StatusCode::BAD_MSG;
+                };
+                if let Err(e) = self.backend.open(params) {
+                    log::error!("{}: failed to open audio backend: {e}", self.name);
+                    return StatusCode::IO_ERR;
+                }
+            }
+            RequestCode::PCM_RELEASE => self.backend.close(),
+            _ => {}
+        }
+        stream.state = next;
+        StatusCode::OK
+    }
+
+    fn handle_ctrl(&mut self, desc: &mut Descriptor) -> io::Result<usize> {
+        let request: Vec<u8> = desc
+            .readable
+            .iter()
+            .flat_map(|b| b.iter().copied())
+            .collect();
+        let Some(hdr) = PcmHdr::read_from_prefix(&request) else {
+            return Err(ErrorKind::InvalidData.into());
+        };
+        let mut payload = vec![];
+        let status = match hdr.code {
+            RequestCode::PCM_INFO => match QueryInfo::read_from_prefix(&request) {
+                Some(query) => self.pcm_info(&query, &mut payload),
+                None => StatusCode::BAD_MSG,
+            },
+            RequestCode::PCM_SET_PARAMS
+            | RequestCode::PCM_PREPARE
+            | RequestCode::PCM_RELEASE
+            | RequestCode::PCM_START
+            | RequestCode::PCM_STOP
+                if hdr.stream_id >= NUM_STREAMS =>
+            {
+                StatusCode::BAD_MSG
+            }
+            RequestCode::PCM_SET_PARAMS => match PcmSetParams::read_from_prefix(&request) {
+                Some(req) => self.pcm_set_params(&req),
+                None => StatusCode::BAD_MSG,
+            },
+            RequestCode::PCM_PREPARE
+            | RequestCode::PCM_RELEASE
+            | RequestCode::PCM_START
+            | RequestCode::PCM_STOP => self.pcm_command(hdr.code),
+            // No jacks or channel maps are exposed in the config space.
+            RequestCode::JACK_INFO | RequestCode::CHMAP_INFO => StatusCode::BAD_MSG,
+            code => {
+                log::error!("{}: unsupported request {code:?}", self.name);
+                StatusCode::NOT_SUPP
+            }
+        };
+        log::trace!("{}: {:?} -> {status:?}", self.name, hdr.code);
+        let len = write_slices(&mut desc.writable, status.as_bytes());
+        if status == StatusCode::OK {
+            Ok(len + write_slices_at(&mut desc.writable, len, &payload))
+        } else {
+            Ok(len)
+        }
+    }
+
+    fn handle_tx(&mut self, desc: &mut Descriptor) -> io::Result<usize> {
+        let hdr_len = size_of::<PcmXfer>();
+        let Some(hdr) = desc
+            .readable
+            .first()
+            .and_then(|b| PcmXfer::read_from_prefix(b))
+        else {
+            return Err(ErrorKind::InvalidData.into());
+        };
+        let status = if hdr.stream_id >= NUM_STREAMS
+            || !matches!(
+                self.stream.state,
+                StreamState::Prepared | StreamState::Running
+            ) {
+            StatusCode::BAD_MSG
+        } else {
+            let mut skip = hdr_len;
+            let mut ret = StatusCode::OK;
+            for buf in &desc.readable {
+                let data = &buf[std::cmp::min(skip, buf.len())..];
+                skip = skip.saturating_sub(buf.len());
+                if data.is_empty() {
+                    continue;
+                }
+                if let Err(e) = self.backend.write(data) {
+                    log::error!("{}: failed to write to audio backend: {e}", self.name);
+                    ret = StatusCode::IO_ERR;
+                    break;
+                }
+            }
+            ret
+        };
+        let pcm_status = PcmStatus {
+            status,
+            latency_bytes: 0,
+        };
+        Ok(write_slices(&mut desc.writable, pcm_status.as_bytes()))
+    }
+}
+
+fn write_slices(bufs: &mut [IoSliceMut], data: &[u8]) -> usize {
+    write_slices_at(bufs, 0, data)
+}
+
+/// Copies `data` into `bufs`, starting `offset` bytes into the chain.
+fn write_slices_at(bufs: &mut [IoSliceMut], mut offset: usize, mut data: &[u8]) -> usize {
+    let mut written = 0;
+    for buf in bufs.iter_mut() {
+        if data.is_empty() {
+            break;
+        }
+        if offset >= buf.len() {
+            offset -= buf.len();
+            continue;
+        }
+        let len = std::cmp::min(buf.len() - offset, data.len());
+        buf[offset..offset + len].copy_from_slice(&data[..len]);
+        data = &data[len..];
+        written += len;
+        offset = 0;
+    }
+    written
+}
+
+impl Virtio for Sound {
+    type Config = SoundConfig;
+    type Feature = SoundFeature;
+
+    fn num_queues(&self) -> u16 {
+        4
+    }
+
+    fn reset(&mut self, _registry: &Registry) {
+        self.backend.close();
+        self.stream = Stream {
+            state: StreamState::Idle,
+            params: None,
+        };
+    }
+
+    fn device_id() -> DeviceId {
+        DeviceId::Sound
+    }
+
+    fn config(&self) -> Arc<SoundConfig> {
+        self.config.clone()
+    }
+
+    fn feature(&self) -> u64 {
+        FEATURE_BUILT_IN
+    }
+
+    fn activate(
+        &mut self,
+        _registry: &Registry,
+        _feature: u64,
+        _memory: &RamBus,
+        _irq_sender: &impl IrqSender,
+        _queues: &[Queue],
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    fn handle_event(
+        &mut self,
+        _event: &Event,
+        _queues: &[impl VirtQueue],
+        _irq_sender: &impl IrqSender,
+        _registry: &Registry,
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    fn handle_queue(
+        &mut self,
+        index: u16,
+        queues: &[impl VirtQueue],
+        irq_sender: &impl IrqSender,
+        _registry: &Registry,
+    ) -> Result<()> {
+        let Some(queue) = queues.get(index as usize) else {
+            log::error!("{}: invalid queue index {index}", self.name);
+            return Ok(());
+        };
+        let name = self.name.clone();
+        match index {
+            CONTROL_QUEUE => handle_desc(&name, index, queue, irq_sender, |desc| {
+                self.handle_ctrl(desc)
+            }),
+            TX_QUEUE => handle_desc(&name, index, queue, irq_sender, |desc| self.handle_tx(desc)),
+            // No jack or stream events are reported, and there are no input
+            // streams to capture from.
+            EVENT_QUEUE | RX_QUEUE => Ok(()),
+            _ => {
+                log::error!("{name}: invalid queue index {index}");
+                Ok(())
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SoundParam {
+    pub backend: AudioBackendKind,
+}
+
+impl DevParam for SoundParam {
+    type Device = Sound;
+
+    fn build(self, name: Arc<String>) -> Result<Sound> {
+        Ok(Sound::new(
+            name,
+            Box::new(CommandBackend::new(self.backend)),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::f64::consts::PI;
+    use std::io::{self, IoSlice, IoSliceMut};
+    use std::mem::size_of;
+    use std::sync::Arc;
+
+    use parking_lot::Mutex;
+    use zerocopy::{AsBytes, FromBytes};
+
+    use crate::virtio::queue::Descriptor;
+
+    use super::{
+        AudioBackend, PcmFormat, PcmInfo, PcmParams, PcmRate, RequestCode, Sound, StatusCode,
+    };
+
+    #[derive(Debug, Default)]
+    struct RecordBackend {
+        params: Arc<Mutex<Option<PcmParams>>>,
+        data: Arc<Mutex<Vec<u8>>>,
+    }
+
+    impl AudioBackend for RecordBackend {
+        fn open(&mut self, params: &PcmParams) -> io::Result<()> {
+            *self.params.lock() = Some(*params);
+            Ok(())
+        }
+
+        fn write(&mut self, data: &[u8]) -> io::Result<()> {
+            self.data.lock().extend_from_slice(data);
+            Ok(())
+        }
+
+        fn close(&mut self) {
+            *self.params.lock() = None;
+        }
+    }
+
+    fn control(sound: &mut Sound, request: &[u8], resp: &mut [u8]) -> u32 {
+        let mut desc = Descriptor {
+            id: 0,
+            readable: vec![IoSlice::new(request)],
+            writable: vec![IoSliceMut::new(resp)],
+        };
+        sound.handle_ctrl(&mut desc).unwrap();
+        u32::from_le_bytes(resp[0..4].try_into().unwrap())
+    }
+
+    fn pcm_cmd(sound: &mut Sound, code: RequestCode) -> u32 {
+        let request = [code.raw(), 0];
+        control(sound, request.as_bytes(), &mut [0u8; 4])
+    }
+
+    #[test]
+    fn test_pcm_info() {
+        let mut sound = Sound::new(Arc::new("snd".to_owned()), Box::<RecordBackend>::default());
+        let size = size_of::<PcmInfo>() as u32;
+        let request = [RequestCode::PCM_INFO.raw(), 0, 1, size];
+        let mut resp = [0u8; 4 + 32];
+        let status = control(&mut sound, request.as_bytes(), &mut resp);
+        assert_eq!(status, StatusCode::OK.raw());
+        let info = PcmInfo::read_from(&resp[4..]).unwrap();
+        assert_eq!(info.channels_max, 2);
+        assert_ne!(info.formats & (1 << PcmFormat::S16.raw()), 0);
+        assert_ne!(info.rates & (1 << PcmRate::R48000.raw()), 0);
+
+        let request = [RequestCode::PCM_INFO.raw(), 1, 1, size];
+        let status = control(&mut sound, request.as_bytes(), &mut resp);
+        assert_eq!(status, StatusCode::BAD_MSG.raw());
+    }
+
+    #[test]
+    fn test_play_sine_wave() {
+        let backend = RecordBackend::default();
+        let params = backend.params.clone();
+        let played = backend.data.clone();
+        let mut sound = Sound::new(Arc::new("snd".to_owned()), Box::new(backend));
+
+        // start before the stream is configured
+        assert_eq!(
+            pcm_cmd(&mut sound, RequestCode::PCM_START),
+            StatusCode::BAD_MSG.raw()
+        );
+
+        let mut set_params = [0u8; 24];
+        set_params[0..4].copy_from_slice(RequestCode::PCM_SET_PARAMS.raw().as_bytes());
+        set_params[20] = 2;
+        set_params[21] = PcmFormat::S16.raw();
+        set_params[22] = PcmRate::R48000.raw();
+        let status = control(&mut sound, &set_params, &mut [0u8; 4]);
+        assert_eq!(status, StatusCode::OK.raw());
+        assert_eq!(
+            pcm_cmd(&mut sound, RequestCode::PCM_PREPARE),
+            StatusCode::OK.raw()
+        );
+        assert_eq!(
+            *params.lock(),
+            Some(PcmParams {
+                channels: 2,
+                format: PcmFormat::S16,
+                rate: 48000
+            })
+        );
+        assert_eq!(
+            pcm_cmd(&mut sound, RequestCode::PCM_START),
+            StatusCode::OK.raw()
+        );
+
+        // 10 ms of a 1 kHz sine wave, in stereo
+        let samples: Vec<i16> = (0..480)
+            .map(|i| ((2.0 * PI * 1000.0 * i as f64 / 48000.0).sin() * i16::MAX as f64) as i16)
+            .flat_map(|s| [s, s])
+            .collect();
+        let xfer = 0u32;
+        let mut status = [0xffu8; 8];
+        let mut desc = Descriptor {
+            id: 0,
+            readable: vec![
+                IoSlice::new(xfer.as_bytes()),
+                IoSlice::new(samples.as_bytes()),
+            ],
+            writable: vec![IoSliceMut::new(&mut status)],
+        };
+        let len = sound.handle_tx(&mut desc).unwrap();
+        drop(desc);
+        assert_eq!(len, 8);
+        assert_eq!(status[0..4], StatusCode::OK.raw().to_le_bytes());
+        assert_eq!(*played.lock(), samples.as_bytes());
+
+        assert_eq!(
+            pcm_cmd(&mut sound, RequestCode::PCM_STOP),
+            StatusCode::OK.raw()
+        );
+        assert_eq!(
+            pcm_cmd(&mut sound, RequestCode::PCM_RELEASE),
+            StatusCode::OK.raw()
+        );
+        assert_eq!(*params.lock(), None);
+    }
+}
@@ -47,6 +47,12 @@ impl DevParam for VhostVsockParam {
     }
 }
 
+/// A virtio-vsock device whose data queues are processed by the
+/// `vhost_vsock` module of the host kernel.
+///
+/// Connections never pass through the VMM: host applications connect to
+/// the guest at `cid`, and guest connections to `VMADDR_CID_HOST` are
+/// accepted by host applications listening on `AF_VSOCK`.
 #[derive(Debug)]
 pub struct VhostVsock {
     name: Arc<String>,
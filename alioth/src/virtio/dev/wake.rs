@@ -0,0 +1,240 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, SendError, Sender, TryRecvError};
+use std::sync::Arc;
+
+use parking_lot::{Condvar, Mutex};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WakeChannelParam {
+    /// Number of pending events at which the channel warns that the
+    /// worker is falling behind.
+    pub high_water: usize,
+    /// Blocks senders while `high_water` events are pending.
+    pub backpressure: bool,
+}
+
+impl Default for WakeChannelParam {
+    fn default() -> Self {
+        WakeChannelParam {
+            high_water: 1024,
+            backpressure: false,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Depth {
+    name: Arc<String>,
+    param: WakeChannelParam,
+    /// Estimated number of pending events.
+    count: AtomicUsize,
+    /// How many times `count` reached the high-water mark.
+    high_water_hits: AtomicU64,
+    closed: AtomicBool,
+    lock: Mutex<()>,
+    drained: Condvar,
+}
+
+impl Depth {
+    fn wait_below_high_water(&self) {
+        if self.count.load(Ordering::Acquire) < self.param.high_water {
+            return;
+        }
+        let mut guard = self.lock.lock();
+        while self.count.load(Ordering::Acquire) >= self.param.high_water
+            && !self.closed.load(Ordering::Acquire)
+        {
+            self.drained.wait(&mut guard);
+        }
+    }
+
+    fn wake_senders(&self) {
+        let _guard = self.lock.lock();
+        self.drained.notify_all();
+    }
+}
+
+/// The sending half of the channel from the transport to a device worker.
+///
+/// Tracks how many events the worker has not received yet, since an
+/// unbounded channel grows without limit if the driver keeps notifying a
+/// stalled worker.
+#[derive(Debug)]
+pub struct WakeSender<T> {
+    tx: Sender<T>,
+    depth: Arc<Depth>,
+}
+
+impl<T> Clone for WakeSender<T> {
+    fn clone(&self) -> Self {
+        WakeSender {
+            tx: self.tx.clone(),
+            depth: self.depth.clone(),
+        }
+    }
+}
+
+impl<T> WakeSender<T> {
+    pub fn send(&self, event: T) -> Result<(), SendError<T>> {
+        let depth = &*self.depth;
+        if depth.param.backpressure {
+            depth.wait_below_high_water();
+        }
+        let count = depth.count.fetch_add(1, Ordering::AcqRel) + 1;
+        if let Err(e) = self.tx.send(event) {
+            depth.count.fetch_sub(1, Ordering::AcqRel);
+            return Err(e);
+        }
+        if count == depth.param.high_water {
+            depth.high_water_hits.fetch_add(1, Ordering::AcqRel);
+            log::warn!(
+                "{}: {count} wake events pending, worker is falling behind",
+                depth.name
+            );
+        }
+        Ok(())
+    }
+
+    pub fn depth(&self) -> usize {
+        self.depth.count.load(Ordering::Acquire)
+    }
+}
+
+#[derive(Debug)]
+pub struct WakeReceiver<T> {
+    rx: Receiver<T>,
+    depth: Arc<Depth>,
+}
+
+impl<T> WakeReceiver<T> {
+    pub fn try_recv(&self) -> Result<T, TryRecvError> {
+        let event = self.rx.try_recv()?;
+        let depth = &*self.depth;
+        let prev = depth.count.fetch_sub(1, Ordering::AcqRel);
+        if depth.param.backpressure && prev == depth.param.high_water {
+            depth.wake_senders();
+        }
+        Ok(event)
+    }
+}
+
+impl<T> Drop for WakeReceiver<T> {
+    fn drop(&mut self) {
+        self.depth.closed.store(true, Ordering::Release);
+        self.depth.wake_senders();
+    }
+}
+
+pub fn channel<T>(name: Arc<String>, param: WakeChannelParam) -> (WakeSender<T>, WakeReceiver<T>) {
+    let (tx, rx) = mpsc::channel();
+    let depth = Arc::new(Depth {
+        name,
+        param,
+        count: AtomicUsize::new(0),
+        high_water_hits: AtomicU64::new(0),
+        closed: AtomicBool::new(false),
+        lock: Mutex::new(()),
+        drained: Condvar::new(),
+    });
+    let sender = WakeSender {
+        tx,
+        depth: depth.clone(),
+    };
+    (sender, WakeReceiver { rx, depth })
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    use crate::virtio::dev::wake::{channel, WakeChannelParam};
+
+    #[test]
+    fn test_high_water() {
+        let param = WakeChannelParam {
+            high_water: 8,
+            backpressure: false,
+        };
+        let (tx, rx) = channel(Arc::new("test".to_owned()), param);
+        let hits = || tx.depth.high_water_hits.load(Ordering::Acquire);
+
+        for i in 0..7 {
+            tx.send(i).unwrap();
+        }
+        assert_eq!(hits(), 0);
+        tx.send(7).unwrap();
+        assert_eq!(hits(), 1);
+        // warns once per crossing
+        for i in 8..100 {
+            tx.send(i).unwrap();
+        }
+        assert_eq!(tx.depth(), 100);
+        assert_eq!(hits(), 1);
+
+        for i in 0..100 {
+            assert_eq!(rx.try_recv(), Ok(i));
+        }
+        assert_eq!(tx.depth(), 0);
+        for i in 0..8 {
+            tx.send(i).unwrap();
+        }
+        assert_eq!(hits(), 2);
+
+        drop(rx);
+        assert!(tx.send(8).is_err());
+        assert_eq!(tx.depth(), 8);
+    }
+
+    #[test]
+    fn test_backpressure() {
+        let param = WakeChannelParam {
+            high_water: 4,
+            backpressure: true,
+        };
+        let (tx, rx) = channel(Arc::new("test".to_owned()), param);
+        let sender = thread::spawn(move || {
+            for i in 0..8 {
+                tx.send(i).unwrap();
+            }
+            tx
+        });
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(rx.depth.count.load(Ordering::Acquire), 4);
+        for i in 0..8 {
+            loop {
+                match rx.try_recv() {
+                    Ok(event) => break assert_eq!(event, i),
+                    Err(_) => thread::yield_now(),
+                }
+            }
+        }
+        let tx = sender.join().unwrap();
+        assert_eq!(tx.depth(), 0);
+
+        // a closed channel never blocks senders
+        for i in 0..4 {
+            tx.send(i).unwrap();
+        }
+        let blocked = thread::spawn(move || tx.send(4));
+        thread::sleep(Duration::from_millis(50));
+        drop(rx);
+        assert!(blocked.join().unwrap().is_err());
+    }
+}
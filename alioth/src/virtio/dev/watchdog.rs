@@ -0,0 +1,111 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use crate::utils::thread_name;
+use crate::virtio::dev::Register;
+use crate::virtio::DevStatus;
+
+/// Deserialized from the timeout alone, e.g. `watchdog=500`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(transparent)]
+pub struct WatchdogParam {
+    /// How long a worker may be busy with one event.
+    pub timeout_ms: u64,
+}
+
+/// Detects a device worker that stops making progress.
+///
+/// The worker increments the heartbeat before and after waiting for
+/// events, so an odd value means the worker is busy. The worker is stuck
+/// if the heartbeat stays at the same odd value for a whole timeout. A
+/// worker waiting for events is never considered stuck.
+///
+/// A stuck worker cannot be stopped safely, so the device is marked as
+/// needing a reset and the driver is left to recover it.
+#[derive(Debug)]
+pub struct WorkerWatchdog {
+    stalls: Arc<AtomicU64>,
+    stop_tx: Option<Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl WorkerWatchdog {
+    pub fn new(
+        name: Arc<String>,
+        heartbeat: Arc<AtomicU64>,
+        param: WatchdogParam,
+        reg: Arc<Register>,
+    ) -> io::Result<Self> {
+        let stalls = Arc::new(AtomicU64::new(0));
+        let (stop_tx, stop_rx) = mpsc::channel();
+        let thread_stalls = stalls.clone();
+        let thread_name = thread_name(&format!("{name}-watchdog"));
+        let watchdog_thread = move || {
+            let timeout = Duration::from_millis(param.timeout_ms);
+            let mut last = heartbeat.load(Ordering::Acquire);
+            let mut reported = false;
+            while let Err(RecvTimeoutError::Timeout) = stop_rx.recv_timeout(timeout) {
+                let current = heartbeat.load(Ordering::Acquire);
+                if current != last || current & 1 == 0 {
+                    last = current;
+                    reported = false;
+                    continue;
+                }
+                if reported {
+                    continue;
+                }
+                reported = true;
+                log::error!(
+                    "{name}: worker is stuck for more than {timeout:?}, device needs reset"
+                );
+                let needs_reset = DevStatus::NEEDS_RESET.bits();
+                reg.status.fetch_or(needs_reset, Ordering::AcqRel);
+                thread_stalls.fetch_add(1, Ordering::AcqRel);
+            }
+        };
+        let handle = std::thread::Builder::new()
+            .name(thread_name)
+            .spawn(watchdog_thread)?;
+        Ok(WorkerWatchdog {
+            stalls,
+            stop_tx: Some(stop_tx),
+            handle: Some(handle),
+        })
+    }
+
+    /// Number of times the worker was found stuck.
+    pub fn stalls(&self) -> u64 {
+        self.stalls.load(Ordering::Acquire)
+    }
+}
+
+impl Drop for WorkerWatchdog {
+    fn drop(&mut self) {
+        self.stop_tx.take();
+        if let Some(handle) = self.handle.take() {
+            if let Err(e) = handle.join() {
+                log::error!("failed to join watchdog thread: {e:?}");
+            }
+        }
+    }
+}
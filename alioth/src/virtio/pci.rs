@@ -12,77 +12,183 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::fmt::Debug;
+use std::iter::zip;
 use std::marker::PhantomData;
 use std::mem::size_of;
 use std::os::fd::{AsFd, AsRawFd, RawFd};
-use std::sync::atomic::{AtomicU16, Ordering};
-use std::sync::mpsc::Sender;
+use std::sync::atomic::{AtomicU16, AtomicU8, Ordering};
 use std::sync::Arc;
 
+use bitflags::bitflags;
 use macros::Layout;
 use mio::Waker;
 use parking_lot::{Mutex, RwLock};
 use zerocopy::{AsBytes, FromBytes, FromZeroes};
 
 use crate::hv::{IoeventFd, IoeventFdRegistry, IrqFd, MsiSender};
-use crate::mem::emulated::{Action, Mmio};
+use crate::mem::emulated::{Action, Mmio, MmioRange, TracingMmio};
 use crate::mem::{MemRange, MemRegion, MemRegionCallback, MemRegionEntry};
+use crate::migration::{self, Migrate};
 use crate::pci::cap::{
-    MsixCap, MsixCapMmio, MsixCapOffset, MsixMsgCtrl, MsixTableEntry, MsixTableMmio,
-    MsixTableMmioEntry, PciCap, PciCapHdr, PciCapId, PciCapList,
+    AerCap, AerUncorrectable, MsixCap, MsixCapMmio, MsixCapOffset, MsixMsgCtrl, MsixPbaMmio,
+    MsixTableEntry, MsixTableMmio, MsixTableMmioEntry, PciCap, PciCapHdr, PciCapId, PciCapList,
+    PmCap, PmHandler, PowerState, SrIovCap, SrIovHandler, MAX_MSIX_TABLE_SIZE, SRIOV_VF_OFFSET,
+    SRIOV_VF_STRIDE,
 };
 use crate::pci::config::{
-    CommonHeader, DeviceHeader, EmulatedConfig, HeaderType, PciConfig, BAR_MEM32, BAR_MEM64,
-    BAR_PREFETCHABLE,
+    CommonHeader, DeviceHeader, EmulatedConfig, HeaderType, PciConfig, VfConfig, BAR_MEM32,
+    BAR_MEM64, BAR_PREFETCHABLE,
 };
-use crate::pci::{self, Pci, PciBar};
+use crate::pci::segment::PciSegment;
+use crate::pci::{self, Bdf, Pci, PciBar, PciDevice};
 use crate::utils::{
     get_atomic_high32, get_atomic_low32, get_high32, get_low32, set_atomic_high32, set_atomic_low32,
 };
-use crate::virtio::dev::{Register, WakeEvent};
-use crate::virtio::queue::Queue;
-use crate::virtio::{error, DevStatus, IrqSender, Result};
-use crate::{impl_mmio_for_zerocopy, mem};
+use crate::virtio::dev::wake::WakeSender;
+#[cfg(any(test, fuzzing))]
+use crate::virtio::dev::wake::{self, WakeChannelParam};
+use crate::virtio::dev::{DeviceDescription, Register, WakeEvent};
+use crate::virtio::queue::{Queue, QUEUE_SIZE_MAX};
+use crate::virtio::{error, DevStatus, IrqSender, LogLevel, Result, VirtioFeature};
+use crate::{align_up, dev_log, impl_mmio_for_zerocopy, mem};
 
 use super::dev::{Virtio, VirtioDevice};
 use super::DeviceId;
 
 const VIRTIO_MSI_NO_VECTOR: u16 = 0xffff;
 
+bitflags! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    pub struct IsrStatus: u8 {
+        const QUEUE = 1 << 0;
+        const CONFIG = 1 << 1;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VirtioIrq {
+    Config,
+    Queue(u16),
+}
+
+/// Maps the interrupts of a virtio device to MSI-X vectors.
+///
+/// The driver programs the table through the common configuration
+/// structure. Several interrupts may share one vector.
 #[derive(Debug)]
-struct VirtioPciMsixVector {
+pub struct IrqRoutingTable {
     config: AtomicU16,
     queues: Vec<AtomicU16>,
 }
 
+impl IrqRoutingTable {
+    fn new(num_queues: u16) -> Self {
+        IrqRoutingTable {
+            config: AtomicU16::new(VIRTIO_MSI_NO_VECTOR),
+            queues: (0..num_queues)
+                .map(|_| AtomicU16::new(VIRTIO_MSI_NO_VECTOR))
+                .collect(),
+        }
+    }
+
+    fn entry(&self, irq: VirtioIrq) -> Option<&AtomicU16> {
+        match irq {
+            VirtioIrq::Config => Some(&self.config),
+            VirtioIrq::Queue(index) => self.queues.get(index as usize),
+        }
+    }
+
+    /// Returns the vector of `irq`, or `None` if `irq` refers to a queue
+    /// that does not exist.
+    pub fn get(&self, irq: VirtioIrq) -> Option<u16> {
+        self.entry(irq).map(|v| v.load(Ordering::Acquire))
+    }
+
+    pub fn set(&self, irq: VirtioIrq, vector: u16) -> Result<()> {
+        let Some(entry) = self.entry(irq) else {
+            return error::InvalidIrq { irq }.fail();
+        };
+        entry.store(vector, Ordering::Release);
+        Ok(())
+    }
+
+    fn reset(&self) {
+        self.config.store(VIRTIO_MSI_NO_VECTOR, Ordering::Release);
+        for vector in self.queues.iter() {
+            vector.store(VIRTIO_MSI_NO_VECTOR, Ordering::Release);
+        }
+    }
+}
+
+impl Migrate for IrqRoutingTable {
+    fn schema_version(&self) -> u32 {
+        1
+    }
+
+    fn save(&self) -> Vec<u8> {
+        let vectors = std::iter::once(&self.config).chain(self.queues.iter());
+        vectors
+            .flat_map(|v| v.load(Ordering::Acquire).to_le_bytes())
+            .collect()
+    }
+
+    fn restore(&self, _version: u32, data: &[u8]) -> migration::Result<()> {
+        let expected = (self.queues.len() + 1) * size_of::<u16>();
+        if data.len() != expected {
+            return migration::error::Restore {
+                name: "irq routing table",
+                msg: format!("expected {expected} bytes, got {}", data.len()),
+            }
+            .fail();
+        }
+        let vectors = std::iter::once(&self.config).chain(self.queues.iter());
+        for (vector, bytes) in zip(vectors, data.chunks_exact(size_of::<u16>())) {
+            vector.store(u16::from_le_bytes([bytes[0], bytes[1]]), Ordering::Release);
+        }
+        Ok(())
+    }
+}
+
 #[derive(Debug)]
 pub struct PciIrqSender<S>
 where
     S: MsiSender,
 {
-    msix_vector: VirtioPciMsixVector,
-    msix_table: Arc<MsixTableMmio<S::IrqFd>>,
-    msi_sender: S,
+    irq_routing: IrqRoutingTable,
+    msix_table: Arc<MsixTableMmio<S>>,
+    reg: Arc<Register>,
+    pending: Mutex<Vec<u16>>,
+    isr: AtomicU8,
+    aer: AerCap,
 }
 
 impl<S> PciIrqSender<S>
 where
     S: MsiSender,
 {
-    fn send(&self, vector: u16) {
-        let entries = &self.msix_table.entries;
+    fn msg(&self, vector: u16) -> Option<(u64, u32)> {
+        let entries = self.msix_table.entries.read();
         let Some(entry) = entries.get(vector as usize) else {
             log::error!("invalid config vector: {:x}", vector);
-            return;
+            return None;
         };
         let entry = entry.read();
         if entry.get_masked() {
             log::info!("{} is masked", vector);
-            return;
+            self.msix_table.set_pending(vector);
+            return None;
         }
         let data = entry.get_data();
         let addr = ((entry.get_addr_hi() as u64) << 32) | (entry.get_addr_lo() as u64);
-        if let Err(e) = self.msi_sender.send(addr, data) {
+        Some((addr, data))
+    }
+
+    fn send(&self, vector: u16) {
+        let Some((addr, data)) = self.msg(vector) else {
+            return;
+        };
+        if let Err(e) = self.msix_table.msi_sender.send(addr, data) {
             log::error!("send msi data = {data:#x} to {addr:#x}: {e}")
         } else {
             log::trace!("send msi data = {data:#x} to {addr:#x}: done")
@@ -90,14 +196,14 @@ where
     }
 
     fn get_irqfd(&self, vector: u16) -> Result<RawFd> {
-        let entries = &self.msix_table.entries;
+        let entries = self.msix_table.entries.read();
         let Some(entry) = entries.get(vector as usize) else {
             return error::InvalidMsixVector { vector }.fail();
         };
         let mut entry = entry.write();
         match &*entry {
             MsixTableMmioEntry::Entry(e) => {
-                let irqfd = self.msi_sender.create_irqfd()?;
+                let irqfd = self.msix_table.msi_sender.create_irqfd()?;
                 irqfd.set_addr_hi(e.addr_hi)?;
                 irqfd.set_addr_lo(e.addr_lo)?;
                 irqfd.set_data(e.data)?;
@@ -116,32 +222,59 @@ where
     S: MsiSender,
 {
     fn config_irq(&self) {
-        let vector = self.msix_vector.config.load(Ordering::Acquire);
+        self.reg.config_generation.fetch_add(1, Ordering::AcqRel);
+        let vector = self.irq_routing.config.load(Ordering::Acquire);
         if vector != VIRTIO_MSI_NO_VECTOR {
             self.send(vector)
+        } else {
+            self.isr
+                .fetch_or(IsrStatus::CONFIG.bits(), Ordering::AcqRel);
         }
     }
 
     fn queue_irq(&self, idx: u16) {
-        let Some(vector) = self.msix_vector.queues.get(idx as usize) else {
+        let Some(vector) = self.irq_routing.get(VirtioIrq::Queue(idx)) else {
             log::error!("invalid queue index: {idx}");
             return;
         };
-        let vector = vector.load(Ordering::Acquire);
-        if vector != VIRTIO_MSI_NO_VECTOR {
-            self.send(vector);
+        if vector == VIRTIO_MSI_NO_VECTOR {
+            self.isr.fetch_or(IsrStatus::QUEUE.bits(), Ordering::AcqRel);
+            return;
+        }
+        let mut pending = self.pending.lock();
+        if !pending.contains(&vector) {
+            pending.push(vector);
+        }
+    }
+
+    fn flush(&self) {
+        let vectors = std::mem::take(&mut *self.pending.lock());
+        let msgs: Vec<_> = vectors.into_iter().filter_map(|v| self.msg(v)).collect();
+        if msgs.is_empty() {
+            return;
+        }
+        if let Err(e) = self.msix_table.msi_sender.send_batch(&msgs) {
+            log::error!("send msi batch {msgs:x?}: {e}")
+        } else {
+            log::trace!("send msi batch {msgs:x?}: done")
+        }
+    }
+
+    fn device_error(&self) {
+        if self.aer.report_uncorrectable(AerUncorrectable::INTERNAL) {
+            self.send(0)
         }
     }
 
     fn config_irqfd(&self) -> Result<RawFd> {
-        self.get_irqfd(self.msix_vector.config.load(Ordering::Acquire))
+        self.get_irqfd(self.irq_routing.config.load(Ordering::Acquire))
     }
 
     fn queue_irqfd(&self, idx: u16) -> Result<RawFd> {
-        let Some(vector) = self.msix_vector.queues.get(idx as usize) else {
+        let Some(vector) = self.irq_routing.get(VirtioIrq::Queue(idx)) else {
             return error::InvalidQueueIndex { index: idx }.fail();
         };
-        self.get_irqfd(vector.load(Ordering::Acquire))
+        self.get_irqfd(vector)
     }
 }
 
@@ -188,46 +321,149 @@ where
     reg: Arc<Register>,
     queues: Arc<Vec<Queue>>,
     irq_sender: Arc<PciIrqSender<M>>,
-    event_tx: Sender<WakeEvent<PciIrqSender<M>>>,
+    event_tx: WakeSender<WakeEvent<PciIrqSender<M>>>,
     waker: Arc<Waker>,
+    log_level: LogLevel,
+    /// Features of the PCI transport offered besides those of the device.
+    transport_feature: u64,
 }
 
 impl<M> VirtioPciRegisterMmio<M>
 where
     M: MsiSender,
 {
+    /// Creates registers of a device with `num_queues` queues, whose events
+    /// are not handled by any device worker.
+    #[cfg(any(test, fuzzing))]
+    pub fn new_detached(msi_sender: M, num_queues: u16) -> Self {
+        let poll = mio::Poll::new().unwrap();
+        let waker = Waker::new(poll.registry(), mio::Token(0)).unwrap();
+        let name = Arc::new("detached".to_owned());
+        let (event_tx, _) = wake::channel(name.clone(), WakeChannelParam::default());
+        let reg = Arc::new(Register::default());
+        let queues = (0..num_queues).map(|_| Queue {
+            size: AtomicU16::new(QUEUE_SIZE_MAX),
+            ..Default::default()
+        });
+        let table_entries = num_queues as usize + 1;
+        VirtioPciRegisterMmio {
+            name,
+            reg: reg.clone(),
+            queues: Arc::new(queues.collect()),
+            irq_sender: Arc::new(PciIrqSender {
+                irq_routing: IrqRoutingTable::new(num_queues),
+                msix_table: Arc::new(MsixTableMmio::new(table_entries, table_entries, msi_sender)),
+                reg,
+                pending: Mutex::new(Vec::new()),
+                isr: AtomicU8::new(0),
+                aer: AerCap::new(),
+            }),
+            event_tx,
+            waker: Arc::new(waker),
+            log_level: LogLevel::default(),
+            transport_feature: 0,
+        }
+    }
+
     fn wake_up_dev(&self, event: WakeEvent<PciIrqSender<M>>) {
         if let Err(e) = self.event_tx.send(event) {
-            log::error!("{}: failed to send event: {e}", self.name);
+            dev_log!(
+                self.log_level,
+                Error,
+                "{}: failed to send event: {e}",
+                self.name
+            );
             return;
         }
         if let Err(e) = self.waker.wake() {
-            log::error!("{}: failed to wake up device: {e}", self.name);
+            dev_log!(
+                self.log_level,
+                Error,
+                "{}: failed to wake up device: {e}",
+                self.name
+            );
         }
     }
 
-    fn reset(&self) {
-        let config_msix = &self.irq_sender.msix_vector.config;
-        config_msix.store(VIRTIO_MSI_NO_VECTOR, Ordering::Release);
-        for q_vector in self.irq_sender.msix_vector.queues.iter() {
-            q_vector.store(VIRTIO_MSI_NO_VECTOR, Ordering::Release);
+    fn set_needs_reset(&self) {
+        let needs_reset = DevStatus::NEEDS_RESET.bits();
+        let old = self.reg.status.fetch_or(needs_reset, Ordering::AcqRel);
+        if DevStatus::from_bits_retain(old).contains(DevStatus::DRIVER_OK) {
+            self.irq_sender.config_irq();
         }
-        for entry in self.irq_sender.msix_table.entries.iter() {
+    }
+
+    fn reset(&self) {
+        self.irq_sender.irq_routing.reset();
+        self.irq_sender.isr.store(0, Ordering::Release);
+        for entry in self.irq_sender.msix_table.entries.read().iter() {
             let mut entry = entry.write();
             *entry = MsixTableMmioEntry::Entry(MsixTableEntry::default());
         }
+        self.irq_sender.msix_table.clear_pending();
         for q in self.queues.iter() {
             q.enabled.store(false, Ordering::Release);
         }
     }
 
+    /// Stops the device worker and clears the registers, as if the driver
+    /// wrote 0 to the device status.
+    fn reset_device(&self) {
+        self.wake_up_dev(WakeEvent::Reset);
+        self.reset();
+        self.reg.status.store(0, Ordering::Release);
+    }
+
+    /// Checks the features accepted by the driver when it sets
+    /// `FEATURES_OK`.
+    fn features_valid(&self) -> bool {
+        let reg = &*self.reg;
+        let feature = reg.driver_feature.load(Ordering::Acquire);
+        let unknown = feature & !(reg.device_feature | self.transport_feature);
+        if unknown != 0 {
+            dev_log!(
+                self.log_level,
+                Warn,
+                "{}: driver accepted features {unknown:#x} not offered",
+                self.name
+            );
+            return false;
+        }
+        let platform = VirtioFeature::from_bits_retain(feature);
+        if platform.contains(VirtioFeature::ORDER_PLATFORM)
+            && !platform.contains(VirtioFeature::ACCESS_PLATFORM)
+        {
+            dev_log!(
+                self.log_level,
+                Warn,
+                "{}: driver accepted ORDER_PLATFORM without ACCESS_PLATFORM",
+                self.name
+            );
+            return false;
+        }
+        let missing = reg.required_feature & !feature;
+        if missing != 0 {
+            dev_log!(
+                self.log_level,
+                Warn,
+                "{}: driver did not accept required features {missing:#x}",
+                self.name
+            );
+            return false;
+        }
+        true
+    }
+
     fn msix_change_allowed(&self, old: u16) -> bool {
-        let Some(entry) = self.irq_sender.msix_table.entries.get(old as usize) else {
+        let entries = self.irq_sender.msix_table.entries.read();
+        let Some(entry) = entries.get(old as usize) else {
             return true;
         };
         let entry = entry.read();
         if let MsixTableMmioEntry::IrqFd(fd) = &*entry {
-            log::error!(
+            dev_log!(
+                self.log_level,
+                Error,
                 "{}: MSI-X vector {old:#x} was assigned to irqfd {:#x}",
                 self.name,
                 fd.as_fd().as_raw_fd(),
@@ -254,10 +490,11 @@ where
                 reg.device_feature_sel.load(Ordering::Acquire) as u64
             }
             VirtioCommonCfg::LAYOUT_DEVICE_FEATURE => {
+                let feature = reg.device_feature | self.transport_feature;
                 if reg.device_feature_sel.load(Ordering::Acquire) > 0 {
-                    get_high32(reg.device_feature) as u64
+                    get_high32(feature) as u64
                 } else {
-                    get_low32(reg.device_feature) as u64
+                    get_low32(feature) as u64
                 }
             }
             VirtioCommonCfg::LAYOUT_DRIVER_FEATURE_SELECT => {
@@ -271,12 +508,12 @@ where
                 }
             }
             VirtioCommonCfg::LAYOUT_CONFIG_MSIX_VECTOR => {
-                self.irq_sender.msix_vector.config.load(Ordering::Acquire) as u64
+                self.irq_sender.irq_routing.config.load(Ordering::Acquire) as u64
             }
             VirtioCommonCfg::LAYOUT_NUM_QUEUES => self.queues.len() as u64,
             VirtioCommonCfg::LAYOUT_DEVICE_STATUS => reg.status.load(Ordering::Acquire) as u64,
             VirtioCommonCfg::LAYOUT_CONFIG_GENERATION => {
-                0 // TODO: support device config change at runtime
+                reg.config_generation.load(Ordering::Acquire) as u64
             }
             VirtioCommonCfg::LAYOUT_QUEUE_SELECT => reg.queue_sel.load(Ordering::Acquire) as u64,
             VirtioCommonCfg::LAYOUT_QUEUE_SIZE => {
@@ -288,12 +525,10 @@ where
                 }
             }
             VirtioCommonCfg::LAYOUT_QUEUE_MSIX_VECTOR => {
-                let q_sel = reg.queue_sel.load(Ordering::Acquire) as usize;
-                if let Some(msix_vector) = self.irq_sender.msix_vector.queues.get(q_sel) {
-                    msix_vector.load(Ordering::Acquire) as u64
-                } else {
-                    VIRTIO_MSI_NO_VECTOR as u64
-                }
+                let q_sel = reg.queue_sel.load(Ordering::Acquire);
+                let irq_routing = &self.irq_sender.irq_routing;
+                let vector = irq_routing.get(VirtioIrq::Queue(q_sel));
+                vector.unwrap_or(VIRTIO_MSI_NO_VECTOR) as u64
             }
             VirtioCommonCfg::LAYOUT_QUEUE_ENABLE => {
                 let q_sel = reg.queue_sel.load(Ordering::Acquire) as usize;
@@ -354,14 +589,18 @@ where
                     0
                 }
             }
-            VirtioCommonCfg::LAYOUT_QUEUE_NOTIFY_DATA => {
-                todo!()
-            }
-            VirtioCommonCfg::LAYOUT_QUEUE_RESET => {
-                todo!()
+            // VIRTIO_F_NOTIF_CONFIG_DATA and VIRTIO_F_RING_RESET are not
+            // offered, so these registers read as zero.
+            VirtioCommonCfg::LAYOUT_QUEUE_NOTIFY_DATA => 0,
+            VirtioCommonCfg::LAYOUT_QUEUE_RESET => 0,
+            // Reading the ISR status acknowledges the interrupt.
+            (VirtioPciRegister::OFFSET_ISR_STATUS, 1 | 4) => {
+                self.irq_sender.isr.swap(0, Ordering::AcqRel) as u64
             }
             _ => {
-                log::error!(
+                dev_log!(
+                    self.log_level,
+                    Error,
                     "{}: read invalid register: offset = {offset:#x}, size = {size}",
                     self.name
                 );
@@ -388,25 +627,57 @@ where
                 }
             }
             VirtioCommonCfg::LAYOUT_CONFIG_MSIX_VECTOR => {
-                let config_msix = &self.irq_sender.msix_vector.config;
+                let config_msix = &self.irq_sender.irq_routing.config;
                 let old = config_msix.load(Ordering::Acquire);
                 if self.msix_change_allowed(old) {
                     config_msix.store(val as u16, Ordering::Release);
-                    log::trace!(
+                    dev_log!(
+                        self.log_level,
+                        Trace,
                         "{}: config MSI-X vector update: {old:#x} -> {val:#x}",
                         self.name
                     );
                 } else {
-                    log::error!(
+                    dev_log!(
+                        self.log_level,
+                        Error,
                         "{}: cannot change config MSI-X vector from {old:#x} to {val:#x}",
                         self.name
                     )
                 }
             }
             VirtioCommonCfg::LAYOUT_DEVICE_STATUS => {
-                let status = DevStatus::from_bits_truncate(val as u8);
-                let old = reg.status.swap(status.bits(), Ordering::AcqRel);
-                let old = DevStatus::from_bits_retain(old);
+                let mut status = DevStatus::from_bits_truncate(val as u8);
+                let old = DevStatus::from_bits_retain(reg.status.load(Ordering::Acquire));
+                if !old.transition_valid(status) {
+                    dev_log!(
+                        self.log_level,
+                        Warn,
+                        "{}: invalid status transition: {old:?} -> {status:?}",
+                        self.name
+                    );
+                    self.set_needs_reset();
+                    return Ok(Action::None);
+                }
+                if !old.contains(DevStatus::FEATURES_OK)
+                    && status.contains(DevStatus::FEATURES_OK)
+                    && !self.features_valid()
+                {
+                    status.remove(DevStatus::FEATURES_OK | DevStatus::DRIVER_OK);
+                }
+                // The device worker may set NEEDS_RESET at any time, which
+                // must survive anything but a reset.
+                let update = |current: u8| {
+                    let mut new = status;
+                    if !new.is_empty() {
+                        new |= DevStatus::from_bits_retain(current) & DevStatus::NEEDS_RESET;
+                    }
+                    Some(new.bits())
+                };
+                let (Ok(current) | Err(current)) =
+                    reg.status
+                        .fetch_update(Ordering::AcqRel, Ordering::Acquire, update);
+                let old = DevStatus::from_bits_retain(current);
                 if (old ^ status).contains(DevStatus::DRIVER_OK) {
                     let event = if status.contains(DevStatus::DRIVER_OK) {
                         WakeEvent::Start {
@@ -423,28 +694,47 @@ where
             VirtioCommonCfg::LAYOUT_QUEUE_SELECT => {
                 reg.queue_sel.store(val as u16, Ordering::Relaxed);
                 if self.queues.get(val as usize).is_none() {
-                    log::error!("{}: unknown queue index {val}", self.name)
+                    dev_log!(
+                        self.log_level,
+                        Error,
+                        "{}: unknown queue index {val}",
+                        self.name
+                    )
                 }
             }
             VirtioCommonCfg::LAYOUT_QUEUE_SIZE => {
                 let q_sel = reg.queue_sel.load(Ordering::Relaxed) as usize;
                 if let Some(q) = self.queues.get(q_sel) {
-                    // TODO: validate queue size
-                    q.size.store(val as u16, Ordering::Release);
+                    let size = val as u16;
+                    if size.is_power_of_two() && size <= QUEUE_SIZE_MAX {
+                        q.size.store(size, Ordering::Release);
+                    } else {
+                        dev_log!(
+                            self.log_level,
+                            Warn,
+                            "{}: queue {q_sel}: invalid size {val}",
+                            self.name
+                        );
+                        self.set_needs_reset();
+                    }
                 }
             }
             VirtioCommonCfg::LAYOUT_QUEUE_MSIX_VECTOR => {
                 let q_sel = reg.queue_sel.load(Ordering::Relaxed) as usize;
-                if let Some(msix_vector) = self.irq_sender.msix_vector.queues.get(q_sel) {
+                if let Some(msix_vector) = self.irq_sender.irq_routing.queues.get(q_sel) {
                     let old = msix_vector.load(Ordering::Acquire);
                     if self.msix_change_allowed(old) {
                         msix_vector.store(val as u16, Ordering::Release);
-                        log::trace!(
+                        dev_log!(
+                            self.log_level,
+                            Trace,
                             "{}: queue {q_sel} MSI-X vector update: {old:#x} -> {val:#x}",
                             self.name
                         );
                     } else {
-                        log::error!(
+                        dev_log!(
+                            self.log_level,
+                            Error,
                             "{}: cannot change queue {q_sel} MSI-X vector from {old:#x} to {val:#x}",
                             self.name
                         )
@@ -494,7 +784,12 @@ where
                 }
             }
             VirtioCommonCfg::LAYOUT_QUEUE_RESET => {
-                todo!()
+                dev_log!(
+                    self.log_level,
+                    Error,
+                    "{}: queue reset is not supported",
+                    self.name
+                )
             }
             (offset, _)
                 if offset >= VirtioPciRegister::OFFSET_QUEUE_NOTIFY
@@ -503,12 +798,19 @@ where
                             + size_of::<u32>() * self.queues.len() =>
             {
                 let q_index = (offset - VirtioPciRegister::OFFSET_QUEUE_NOTIFY) as u16 / 4;
-                log::warn!("{}: notifying queue-{q_index} by vm exit!", self.name);
+                dev_log!(
+                    self.log_level,
+                    Warn,
+                    "{}: notifying queue-{q_index} by vm exit!",
+                    self.name
+                );
                 let event = WakeEvent::Notify { q_index };
                 self.wake_up_dev(event)
             }
             _ => {
-                log::error!(
+                dev_log!(
+                    self.log_level,
+                    Error,
                     "{}: write 0x{val:0width$x} to invalid register offset = {offset:#x}",
                     self.name,
                     width = 2 * size as usize
@@ -595,6 +897,11 @@ impl PciCap for VirtioPciCap {
     }
 
     fn reset(&self) {}
+
+    /// Read-only.
+    fn restore(&self, _data: &[u8], _memory: &mem::Memory) -> pci::Result<()> {
+        Ok(())
+    }
 }
 
 #[repr(C, align(4))]
@@ -611,6 +918,11 @@ impl PciCap for VirtioPciCap64 {
         PciCap::set_next(&mut self.cap, val)
     }
     fn reset(&self) {}
+
+    /// Read-only.
+    fn restore(&self, _data: &[u8], _memory: &mem::Memory) -> pci::Result<()> {
+        Ok(())
+    }
 }
 
 #[repr(C, align(4))]
@@ -626,6 +938,11 @@ impl PciCap for VirtioPciNotifyCap {
         self.cap.header.next = val;
     }
     fn reset(&self) {}
+
+    /// Read-only.
+    fn restore(&self, _data: &[u8], _memory: &mem::Memory) -> pci::Result<()> {
+        Ok(())
+    }
 }
 
 #[derive(Debug)]
@@ -638,6 +955,7 @@ where
     pub dev: VirtioDevice<D, PciIrqSender<M>, E>,
     pub config: Arc<EmulatedConfig>,
     pub registers: Arc<VirtioPciRegisterMmio<M>>,
+    msix_cap: Arc<RwLock<MsixCap>>,
 }
 
 impl<D, M, E> VirtioPciDevice<D, M, E>
@@ -646,10 +964,56 @@ where
     D: Virtio,
     E: IoeventFd,
 {
+    pub fn irq_routing(&self) -> &IrqRoutingTable {
+        &self.registers.irq_sender.irq_routing
+    }
+
+    /// Returns the current size of the MSI-X table.
+    pub fn num_msix_vectors(&self) -> usize {
+        self.msix_cap.read().control.table_len() as usize + 1
+    }
+
+    /// Describes the device, including the MSI-X vectors of the queues.
+    pub fn describe(&self) -> DeviceDescription {
+        let mut desc = self.dev.describe();
+        let irq_routing = self.irq_routing();
+        for (index, info) in desc.queue_infos.iter_mut().enumerate() {
+            let vector = irq_routing.get(VirtioIrq::Queue(index as u16));
+            info.msix_vector = vector.filter(|v| *v != VIRTIO_MSI_NO_VECTOR);
+        }
+        desc
+    }
+
+    /// Resizes the MSI-X table to `num_entries`, e.g. after the number of
+    /// active queues changed, and notifies the driver with a config change.
+    pub fn resize_msix_table(&self, num_entries: u16) -> Result<()> {
+        let irq_sender = &self.registers.irq_sender;
+        irq_sender.msix_table.resize(num_entries as usize)?;
+        self.msix_cap.write().control.set_table_len(num_entries - 1);
+        irq_sender.config_irq();
+        Ok(())
+    }
+
     pub fn new<R>(
         dev: VirtioDevice<D, PciIrqSender<M>, E>,
         msi_sender: M,
         ioeventfd_reg: R,
+        trace_mmio: bool,
+    ) -> Result<Self>
+    where
+        R: IoeventFdRegistry<IoeventFd = E>,
+    {
+        Self::build(dev, msi_sender, ioeventfd_reg, trace_mmio, None)
+    }
+
+    /// Builds the device, and if `sriov` is given, makes it an SR-IOV PF
+    /// with the given number of VFs handled by the handler.
+    fn build<R>(
+        dev: VirtioDevice<D, PciIrqSender<M>, E>,
+        msi_sender: M,
+        ioeventfd_reg: R,
+        trace_mmio: bool,
+        sriov: Option<(u16, Arc<dyn SrIovHandler>)>,
     ) -> Result<Self>
     where
         R: IoeventFdRegistry<IoeventFd = E>,
@@ -665,19 +1029,36 @@ where
                 subclass,
                 ..Default::default()
             },
-            subsystem: VIRTIO_DEVICE_ID_BASE + D::device_id() as u16,
+            subsystem: dev
+                .subsystem_device_id
+                .unwrap_or(VIRTIO_DEVICE_ID_BASE + D::device_id() as u16),
             ..Default::default()
         };
         let device_config = dev.device_config.clone();
         let num_queues = dev.queue_regs.len();
+        // The device never has more queues than `queue_regs`, so the MSI-X
+        // table is laid out for its maximum size up front and can be resized
+        // later without moving anything else in bar0.
         let table_entries = num_queues + 1;
+        let max_vectors = match msi_sender.max_vectors() {
+            Some(max) => max.min(MAX_MSIX_TABLE_SIZE),
+            None => MAX_MSIX_TABLE_SIZE,
+        };
+        if table_entries > max_vectors as usize {
+            return error::TooManyMsixVectors {
+                num: table_entries,
+                max: max_vectors,
+            }
+            .fail();
+        }
 
         let msix_table_offset = 0;
         let msix_table_size = size_of::<MsixTableEntry>() * table_entries;
 
-        let msix_pba_offset = 8 << 10;
+        let msix_pba_offset = align_up!(msix_table_size, 4 << 10).max(8 << 10);
+        let msix_pba_size = align_up!(table_entries.div_ceil(64) * size_of::<u64>(), 4 << 10);
 
-        let virtio_register_offset = 12 << 10;
+        let virtio_register_offset = msix_pba_offset + msix_pba_size;
         let device_config_offset =
             virtio_register_offset + size_of::<VirtioPciRegister>() + size_of::<u32>() * num_queues;
 
@@ -747,11 +1128,9 @@ where
             length: device_config.size() as u32,
             ..Default::default()
         };
-        let entries = (0..table_entries)
-            .map(|_| RwLock::new(MsixTableMmioEntry::Entry(MsixTableEntry::default())))
-            .collect();
-        let msix_table = Arc::new(MsixTableMmio { entries });
-        let bar0_size = 16 << 10;
+        let msix_table = Arc::new(MsixTableMmio::new(table_entries, table_entries, msi_sender));
+        let bar0_size = ((device_config_offset as u64 + device_config.size()).next_power_of_two())
+            .max(16 << 10);
         let mut bar0 = MemRegion {
             ranges: vec![],
             entries: vec![MemRegionEntry {
@@ -759,11 +1138,36 @@ where
                 type_: mem::MemRegionType::Hidden,
             }],
             callbacks: Mutex::new(vec![]),
+            dirty_callbacks: Mutex::new(vec![]),
         };
 
+        let aer = AerCap::new();
+        let registers = Arc::new(VirtioPciRegisterMmio {
+            name: dev.name.clone(),
+            reg: dev.reg.clone(),
+            event_tx: dev.event_tx.clone(),
+            waker: dev.waker.clone(),
+            log_level: dev.log_level.clone(),
+            queues: dev.queue_regs.clone(),
+            irq_sender: Arc::new(PciIrqSender {
+                irq_routing: IrqRoutingTable::new(num_queues as u16),
+                msix_table: msix_table.clone(),
+                reg: dev.reg.clone(),
+                pending: Mutex::new(Vec::new()),
+                isr: AtomicU8::new(0),
+                aer: aer.clone(),
+            }),
+            transport_feature: match sriov {
+                Some(_) => VirtioFeature::SR_IOV.bits(),
+                None => 0,
+            },
+        });
+
+        let msix_cap = Arc::new(RwLock::new(cap_msix));
         let mut caps: Vec<Box<(dyn PciCap)>> = vec![
+            Box::new(PmCap::new(registers.clone())),
             Box::new(MsixCapMmio {
-                cap: RwLock::new(cap_msix),
+                cap: msix_cap.clone(),
             }),
             Box::new(cap_common),
             Box::new(cap_isr),
@@ -798,36 +1202,33 @@ where
         }
 
         let cap_list = PciCapList::try_from(caps)?;
-
-        let msix_vector = VirtioPciMsixVector {
-            config: AtomicU16::new(VIRTIO_MSI_NO_VECTOR),
-            queues: (0..num_queues)
-                .map(|_| AtomicU16::new(VIRTIO_MSI_NO_VECTOR))
-                .collect(),
-        };
-
-        let registers = Arc::new(VirtioPciRegisterMmio {
-            name: dev.name.clone(),
-            reg: dev.reg.clone(),
-            event_tx: dev.event_tx.clone(),
-            waker: dev.waker.clone(),
-            queues: dev.queue_regs.clone(),
-            irq_sender: Arc::new(PciIrqSender {
-                msix_vector,
-                msix_table: msix_table.clone(),
-                msi_sender,
-            }),
+        cap_list.add_ext_cap(Box::new(aer))?;
+        let msix_pba = Arc::new(MsixPbaMmio {
+            table: msix_table.clone(),
         });
-        bar0.ranges.push(MemRange::Emulated(msix_table));
+        let msix_pba_gap = msix_pba_size as u64 - msix_pba.size();
+        bar0.ranges.push(MemRange::emulated(msix_table));
+        bar0.ranges
+            .push(MemRange::Span((msix_pba_offset - msix_table_size) as u64));
+        bar0.ranges.push(MemRange::emulated(msix_pba));
+        bar0.ranges.push(MemRange::Span(msix_pba_gap));
+        let trace = |name: &str, range: MmioRange| -> MmioRange {
+            if trace_mmio {
+                let name = Arc::new(format!("{}: {name}", dev.name));
+                Arc::new(TracingMmio::new(name, range))
+            } else {
+                range
+            }
+        };
         bar0.ranges
-            .push(MemRange::Span((12 << 10) - msix_table_size as u64));
-        bar0.ranges.push(MemRange::Emulated(registers.clone()));
+            .push(MemRange::emulated(trace("registers", registers.clone())));
         bar0.callbacks.lock().push(Box::new(IoeventFdCallback {
             registry: ioeventfd_reg,
             ioeventfds: dev.ioeventfds.clone(),
         }));
         if device_config.size() > 0 {
-            bar0.ranges.push(MemRange::Emulated(device_config))
+            bar0.ranges
+                .push(MemRange::emulated(trace("device config", device_config)))
         }
         let mut bars = [const { PciBar::Empty }; 6];
         let mut bar_masks = [0; 6];
@@ -853,6 +1254,12 @@ where
             }
         }
 
+        if let Some((total_vfs, handler)) = sriov {
+            let vf_device_id = VIRTIO_DEVICE_ID_BASE + D::device_id() as u16;
+            let cap = SrIovCap::new(total_vfs, vf_device_id, header.bars, bar_masks, handler);
+            cap_list.add_ext_cap(Box::new(cap))?;
+        }
+
         let config = Arc::new(EmulatedConfig::new_device(
             header, bar_masks, bars, cap_list,
         ));
@@ -861,6 +1268,7 @@ where
             dev,
             config,
             registers,
+            msix_cap,
         })
     }
 }
@@ -875,9 +1283,926 @@ where
         self.config.clone()
     }
     fn reset(&self) -> pci::Result<()> {
-        self.registers.wake_up_dev(WakeEvent::Reset);
-        self.registers.reset();
-        self.dev.reg.status.store(0, Ordering::Release);
+        self.registers.reset_device();
         Ok(())
     }
 }
+
+impl<M> PmHandler for VirtioPciRegisterMmio<M>
+where
+    M: MsiSender,
+{
+    fn set_power_state(&self, old: PowerState, new: PowerState) {
+        dev_log!(
+            self.log_level,
+            Info,
+            "{}: power state {old:?} -> {new:?}",
+            self.name
+        );
+        // No_Soft_Reset is clear, so the driver initializes the device
+        // again once it is back in D0.
+        if new == PowerState::D3_HOT {
+            self.reset_device();
+        }
+    }
+}
+
+/// A VF of a [`VirtioSrIovPf`].
+#[derive(Debug)]
+pub struct VirtioVf<D, M, E>
+where
+    D: Virtio,
+    M: MsiSender,
+    E: IoeventFd,
+{
+    pub dev: VirtioPciDevice<D, M, E>,
+    config: Arc<VfConfig>,
+}
+
+impl<D, M, E> Pci for VirtioVf<D, M, E>
+where
+    M: MsiSender,
+    D: Virtio,
+    E: IoeventFd,
+{
+    fn config(&self) -> Arc<dyn PciConfig> {
+        self.config.clone()
+    }
+
+    fn reset(&self) -> pci::Result<()> {
+        self.dev.reset()
+    }
+}
+
+/// Builds the device of VF `index` at the given BDF.
+pub type VfBuilder<D, M, E> =
+    Box<dyn Fn(u16, Bdf) -> Result<VirtioPciDevice<D, M, E>> + Send + Sync + 'static>;
+
+/// Creates VFs on the PCI segment of the PF.
+pub struct VirtioVfs<D, M, E>
+where
+    D: Virtio,
+    M: MsiSender,
+    E: IoeventFd,
+{
+    name: Arc<String>,
+    pf_bdf: Bdf,
+    total_vfs: u16,
+    segment: Arc<PciSegment>,
+    build: VfBuilder<D, M, E>,
+    vfs: Mutex<Vec<Arc<VirtioVf<D, M, E>>>>,
+}
+
+impl<D, M, E> Debug for VirtioVfs<D, M, E>
+where
+    D: Virtio,
+    M: MsiSender,
+    E: IoeventFd,
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("VirtioVfs")
+            .field("name", &self.name)
+            .field("pf_bdf", &self.pf_bdf)
+            .field("total_vfs", &self.total_vfs)
+            .field("vfs", &self.vfs)
+            .finish()
+    }
+}
+
+impl<D, M, E> VirtioVfs<D, M, E>
+where
+    D: Virtio,
+    M: MsiSender,
+    E: IoeventFd,
+{
+    /// VFs take the functions after `pf_bdf` in the same slot, since ARI is
+    /// not supported. Devices made by `build` must have the same BAR layout
+    /// as the PF.
+    pub fn new(
+        name: Arc<String>,
+        pf_bdf: Bdf,
+        total_vfs: u16,
+        segment: Arc<PciSegment>,
+        build: VfBuilder<D, M, E>,
+    ) -> Result<Self> {
+        let free_funcs = 7 - pf_bdf.func();
+        let max = match free_funcs.checked_sub(SRIOV_VF_OFFSET) {
+            Some(n) => n / SRIOV_VF_STRIDE + 1,
+            None => 0,
+        };
+        if total_vfs > max {
+            return error::TooManyVfs {
+                num: total_vfs,
+                max,
+            }
+            .fail();
+        }
+        Ok(VirtioVfs {
+            name,
+            pf_bdf,
+            total_vfs,
+            segment,
+            build,
+            vfs: Mutex::new(Vec::new()),
+        })
+    }
+
+    fn vf_bdf(&self, index: u16) -> Bdf {
+        Bdf(self.pf_bdf.0 + SRIOV_VF_OFFSET + index * SRIOV_VF_STRIDE)
+    }
+}
+
+impl<D, M, E> SrIovHandler for VirtioVfs<D, M, E>
+where
+    D: Virtio,
+    M: MsiSender,
+    E: IoeventFd,
+{
+    fn set_num_vfs(&self, num_vfs: u16) {
+        let mut vfs = self.vfs.lock();
+        for index in 0..vfs.len() {
+            self.segment.remove(self.vf_bdf(index as u16));
+        }
+        vfs.clear();
+        for index in 0..num_vfs {
+            let bdf = self.vf_bdf(index);
+            let dev = match (self.build)(index, bdf) {
+                Ok(dev) => dev,
+                Err(e) => {
+                    log::error!("{}: failed to create VF {index}: {e:?}", self.name);
+                    break;
+                }
+            };
+            dev.config.get_header().set_bdf(bdf);
+            let name = dev.dev.name.clone();
+            let vf = Arc::new(VirtioVf {
+                config: Arc::new(VfConfig::new(dev.config.clone())),
+                dev,
+            });
+            let pci_dev = PciDevice::new(name.clone(), vf.clone());
+            if self.segment.add(bdf, pci_dev).is_some() {
+                log::error!("{}: {bdf} is occupied, cannot add VF {index}", self.name);
+                break;
+            }
+            log::info!("{bdf}: VF {index} of {}: {name}", self.name);
+            vfs.push(vf);
+        }
+    }
+
+    fn vf_bar(&self, vf: u16, index: usize) -> Option<Arc<MemRegion>> {
+        let vfs = self.vfs.lock();
+        let bars = vfs.get(vf as usize)?.dev.config.get_header().bars.read();
+        match &bars[index] {
+            PciBar::Mem(region) => Some(region.clone()),
+            _ => None,
+        }
+    }
+}
+
+/// A virtio device that is also an SR-IOV PF.
+///
+/// The device offers [`VirtioFeature::SR_IOV`], with which the driver can
+/// create VFs through the SR-IOV capability. Each VF is a separate device
+/// with its own queues and MSI-X table, and its BARs are placed in the VF
+/// BARs of the PF.
+#[derive(Debug)]
+pub struct VirtioSrIovPf<D, M, E>
+where
+    D: Virtio,
+    M: MsiSender,
+    E: IoeventFd,
+{
+    pub pf: VirtioPciDevice<D, M, E>,
+    pub vfs: Arc<VirtioVfs<D, M, E>>,
+}
+
+impl<D, M, E> VirtioSrIovPf<D, M, E>
+where
+    D: Virtio,
+    M: MsiSender,
+    E: IoeventFd,
+{
+    pub fn new<R>(
+        dev: VirtioDevice<D, PciIrqSender<M>, E>,
+        msi_sender: M,
+        ioeventfd_reg: R,
+        trace_mmio: bool,
+        vfs: VirtioVfs<D, M, E>,
+    ) -> Result<Self>
+    where
+        R: IoeventFdRegistry<IoeventFd = E>,
+    {
+        let vfs = Arc::new(vfs);
+        let sriov = Some((vfs.total_vfs, vfs.clone() as Arc<dyn SrIovHandler>));
+        let pf = VirtioPciDevice::build(dev, msi_sender, ioeventfd_reg, trace_mmio, sriov)?;
+        Ok(VirtioSrIovPf { pf, vfs })
+    }
+}
+
+impl<D, M, E> Pci for VirtioSrIovPf<D, M, E>
+where
+    M: MsiSender,
+    D: Virtio,
+    E: IoeventFd,
+{
+    fn config(&self) -> Arc<dyn PciConfig> {
+        self.pf.config.clone()
+    }
+
+    fn reset(&self) -> pci::Result<()> {
+        self.pf.reset()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    use assert_matches::assert_matches;
+    use mio::event::Event;
+    use mio::Registry;
+
+    use crate::hv::test::{FakeIoeventFd, FakeIoeventFdRegistry, FakeMsiSender, FakeVmMemory};
+    use crate::hv::VmEntry;
+    use crate::mem::emulated::{Action, Mmio};
+    use crate::mem::mapped::{ArcMemPages, RamBus};
+    use crate::mem::{MemRegionType, Memory};
+    use crate::migration::Migrate;
+    use crate::pci::bus::PciBus;
+    use crate::pci::cap::{
+        AerRegs, MsixPbaMmio, MsixTableEntry, MsixTableMmioEntry, MsixVectorCtrl, PciCapId,
+        PcieExtCapHdr, PcieExtCapId, PmRegs, PowerState, SrIovCtrl, SrIovRegs,
+    };
+    use crate::pci::config::OFFSET_BAR0;
+    use crate::pci::{Bdf, Pci, PciBar, PciDevice};
+    use crate::virtio::dev::entropy::{Entropy, EntropyConfig, EntropyFeature, RngSource};
+    use crate::virtio::dev::{QueueInfo, Virtio, VirtioDevice};
+    use crate::virtio::pci::{
+        IrqRoutingTable, VirtioCommonCfg, VirtioIrq, VirtioPciDevice, VirtioPciRegister,
+        VirtioPciRegisterMmio, VirtioSrIovPf, VirtioVfs, VIRTIO_MSI_NO_VECTOR,
+    };
+    use crate::virtio::queue::split::{Desc, DescFlag};
+    use crate::virtio::queue::{Queue, VirtQueue};
+    use crate::virtio::{
+        DevStatus, DeviceId, Error, IrqSender, Result, VirtioFeature, FEATURE_BUILT_IN,
+    };
+
+    #[test]
+    fn test_queue_size_validation() {
+        let registers = VirtioPciRegisterMmio::new_detached(FakeMsiSender::default(), 1);
+        let (offset, size) = VirtioCommonCfg::LAYOUT_QUEUE_SIZE;
+
+        registers.write(offset as u64, size as u8, 128).unwrap();
+        registers.write(offset as u64, size as u8, 100).unwrap();
+        assert_eq!(registers.read(offset as u64, size as u8).unwrap(), 128);
+        let status = DevStatus::from_bits_retain(registers.reg.status.load(Ordering::Acquire));
+        assert!(status.contains(DevStatus::NEEDS_RESET));
+
+        registers.write(offset as u64, size as u8, 64).unwrap();
+        assert_eq!(registers.read(offset as u64, size as u8).unwrap(), 64);
+        for invalid in [0, 512] {
+            registers.write(offset as u64, size as u8, invalid).unwrap();
+            assert_eq!(registers.read(offset as u64, size as u8).unwrap(), 64);
+        }
+    }
+
+    #[test]
+    fn test_unsupported_queue_registers() {
+        let registers = VirtioPciRegisterMmio::new_detached(FakeMsiSender::default(), 1);
+        for (offset, size) in [
+            VirtioCommonCfg::LAYOUT_QUEUE_NOTIFY_DATA,
+            VirtioCommonCfg::LAYOUT_QUEUE_RESET,
+        ] {
+            assert_eq!(registers.read(offset as u64, size as u8).unwrap(), 0);
+            registers.write(offset as u64, size as u8, 1).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_queue_irq_batch() {
+        let registers = VirtioPciRegisterMmio::new_detached(FakeMsiSender::default(), 2);
+        let irq_sender = &registers.irq_sender;
+        for (index, entry) in irq_sender.msix_table.entries.read().iter().enumerate() {
+            *entry.write() = MsixTableMmioEntry::Entry(MsixTableEntry {
+                addr_lo: 0xfee0_0000,
+                addr_hi: 0,
+                data: 0x30 + index as u32,
+                control: MsixVectorCtrl(0),
+            });
+        }
+        let irq_routing = &irq_sender.irq_routing;
+        irq_routing.set(VirtioIrq::Queue(0), 1).unwrap();
+        irq_routing.set(VirtioIrq::Queue(1), 2).unwrap();
+
+        for _ in 0..100 {
+            irq_sender.queue_irq(0);
+            irq_sender.queue_irq(1);
+        }
+        assert!(irq_sender.msix_table.msi_sender.msis.lock().is_empty());
+
+        irq_sender.flush();
+        assert_eq!(
+            *irq_sender.msix_table.msi_sender.msis.lock(),
+            [(0xfee0_0000, 0x31), (0xfee0_0000, 0x32)]
+        );
+        assert_eq!(
+            irq_sender
+                .msix_table
+                .msi_sender
+                .calls
+                .load(Ordering::Relaxed),
+            1
+        );
+
+        irq_sender.flush();
+        assert_eq!(
+            irq_sender
+                .msix_table
+                .msi_sender
+                .calls
+                .load(Ordering::Relaxed),
+            1
+        );
+    }
+
+    #[test]
+    fn test_msix_pending_bit() {
+        let registers = VirtioPciRegisterMmio::new_detached(FakeMsiSender::default(), 1);
+        let irq_sender = &registers.irq_sender;
+        let msix_table = &irq_sender.msix_table;
+        let pba = MsixPbaMmio {
+            table: msix_table.clone(),
+        };
+        *msix_table.entries.read()[1].write() = MsixTableMmioEntry::Entry(MsixTableEntry {
+            addr_lo: 0xfee0_0000,
+            addr_hi: 0,
+            data: 0x31,
+            control: MsixVectorCtrl(1),
+        });
+        irq_sender.irq_routing.set(VirtioIrq::Queue(0), 1).unwrap();
+
+        irq_sender.queue_irq(0);
+        irq_sender.flush();
+        assert!(msix_table.msi_sender.msis.lock().is_empty());
+        assert_eq!(pba.read(0, 8).unwrap(), 0b10);
+
+        // Unmasking vector 1 delivers the pending message.
+        msix_table.write(16 + 12, 4, 0).unwrap();
+        assert_eq!(*msix_table.msi_sender.msis.lock(), [(0xfee0_0000, 0x31)]);
+        assert_eq!(pba.read(0, 8).unwrap(), 0);
+
+        msix_table.write(16 + 12, 4, 1).unwrap();
+        msix_table.write(16 + 12, 4, 0).unwrap();
+        assert_eq!(msix_table.msi_sender.msis.lock().len(), 1);
+
+        // A message that fails to be delivered stays pending.
+        msix_table.write(16 + 12, 4, 1).unwrap();
+        irq_sender.queue_irq(0);
+        irq_sender.flush();
+        msix_table.msi_sender.fail.store(true, Ordering::Relaxed);
+        msix_table.write(16 + 12, 4, 0).unwrap();
+        assert_eq!(pba.read(0, 8).unwrap(), 0b10);
+        msix_table.msi_sender.fail.store(false, Ordering::Relaxed);
+        msix_table.write(16 + 12, 4, 1).unwrap();
+        msix_table.write(16 + 12, 4, 0).unwrap();
+        assert_eq!(msix_table.msi_sender.msis.lock().len(), 2);
+        assert_eq!(pba.read(0, 8).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_irq_routing_table() {
+        let table = IrqRoutingTable::new(3);
+        assert_eq!(table.get(VirtioIrq::Config), Some(VIRTIO_MSI_NO_VECTOR));
+        assert_eq!(table.get(VirtioIrq::Queue(3)), None);
+        assert!(table.set(VirtioIrq::Queue(3), 0).is_err());
+
+        // queues 1 and 2 share vector 1
+        table.set(VirtioIrq::Config, 0).unwrap();
+        table.set(VirtioIrq::Queue(0), 2).unwrap();
+        table.set(VirtioIrq::Queue(1), 1).unwrap();
+        table.set(VirtioIrq::Queue(2), 1).unwrap();
+
+        let restored = IrqRoutingTable::new(3);
+        assert!(restored.restore(1, &[0; 6]).is_err());
+        restored.restore(1, &table.save()).unwrap();
+        for irq in [VirtioIrq::Config, VirtioIrq::Queue(0), VirtioIrq::Queue(1)] {
+            assert_eq!(restored.get(irq), table.get(irq));
+        }
+        assert_eq!(restored.get(VirtioIrq::Queue(2)), Some(1));
+
+        restored.reset();
+        assert_eq!(
+            restored.get(VirtioIrq::Queue(2)),
+            Some(VIRTIO_MSI_NO_VECTOR)
+        );
+    }
+
+    #[test]
+    fn test_isr_status_read_clear() {
+        let registers = VirtioPciRegisterMmio::new_detached(FakeMsiSender::default(), 2);
+        let offset = VirtioPciRegister::OFFSET_ISR_STATUS as u64;
+        assert_eq!(registers.read(offset, 1).unwrap(), 0);
+
+        // no MSI-X vector is assigned, so interrupts are reported in ISR
+        let irq_sender = &registers.irq_sender;
+        irq_sender.queue_irq(1);
+        assert_eq!(registers.read(offset, 1).unwrap(), 0b01);
+        assert_eq!(registers.read(offset, 1).unwrap(), 0);
+
+        irq_sender.queue_irq(0);
+        irq_sender.config_irq();
+        assert_eq!(registers.read(offset, 4).unwrap(), 0b11);
+        assert_eq!(registers.read(offset, 4).unwrap(), 0);
+
+        irq_sender.irq_routing.set(VirtioIrq::Queue(0), 1).unwrap();
+        irq_sender.queue_irq(0);
+        assert_eq!(registers.read(offset, 1).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_device_error() {
+        let registers = VirtioPciRegisterMmio::new_detached(FakeMsiSender::default(), 1);
+        let irq_sender = &registers.irq_sender;
+        *irq_sender.msix_table.entries.read()[0].write() =
+            MsixTableMmioEntry::Entry(MsixTableEntry {
+                addr_lo: 0xfee0_0000,
+                addr_hi: 0,
+                data: 0x30,
+                control: MsixVectorCtrl(0),
+            });
+
+        // uncorrectable internal errors are masked by default
+        irq_sender.device_error();
+        assert!(irq_sender.msix_table.msi_sender.msis.lock().is_empty());
+
+        let mask = AerRegs::OFFSET_UNCORRECTABLE_MASK as u64;
+        irq_sender.aer.write(mask, 4, 0).unwrap();
+        irq_sender.device_error();
+        assert_eq!(
+            *irq_sender.msix_table.msi_sender.msis.lock(),
+            [(0xfee0_0000, 0x30)]
+        );
+        let status = AerRegs::OFFSET_UNCORRECTABLE_STATUS as u64;
+        assert_eq!(irq_sender.aer.read(status, 4).unwrap(), 1 << 22);
+    }
+
+    #[test]
+    fn test_pm_d3_hot() {
+        const DESC_ADDR: u64 = 0x0;
+        const AVAIL_ADDR: u64 = 0x100;
+        const USED_ADDR: u64 = 0x200;
+        const BUF_ADDR: u64 = 0x1000;
+
+        let memory = Arc::new(RamBus::new(FakeVmMemory));
+        let pages = ArcMemPages::from_anonymous(0x2000, None).unwrap();
+        memory.add(0, pages, MemRegionType::Ram, false).unwrap();
+        let desc = Desc {
+            addr: BUF_ADDR,
+            len: 0x100,
+            flag: DescFlag::WRITE.bits(),
+            next: 0,
+        };
+        memory.write(DESC_ADDR, &desc).unwrap();
+
+        let name = Arc::new("entropy".to_owned());
+        let entropy = Entropy::new(name.clone(), RngSource::Urandom).unwrap();
+        let registry = FakeIoeventFdRegistry::default();
+        let dev = VirtioDevice::new(
+            name,
+            entropy,
+            memory.clone(),
+            &registry,
+            false,
+            None,
+            #[cfg(target_os = "linux")]
+            None,
+        )
+        .unwrap();
+        let pci_dev = VirtioPciDevice::new(dev, FakeMsiSender::default(), registry, false).unwrap();
+        let config = pci_dev.config();
+        let mut pm = config.read(0x34, 1).unwrap();
+        while config.read(pm, 1).unwrap() != PciCapId::PowerManagement as u64 {
+            pm = config.read(pm + 1, 1).unwrap();
+            assert_ne!(pm, 0);
+        }
+        let pmcsr = pm + PmRegs::OFFSET_PMCSR as u64;
+
+        // Sets up the rings with one buffer available and starts the device
+        // as a driver would.
+        let start = || {
+            // avail.flags = 0, avail.idx = 1, avail.ring[0] = 0
+            memory.write(AVAIL_ADDR, &[0u16, 1, 0]).unwrap();
+            memory.write(USED_ADDR, &[0u16, 0]).unwrap();
+            let reg = &pci_dev.dev.queue_regs[0];
+            reg.size.store(4, Ordering::Release);
+            reg.desc.store(DESC_ADDR, Ordering::Release);
+            reg.driver.store(AVAIL_ADDR, Ordering::Release);
+            reg.device.store(USED_ADDR, Ordering::Release);
+            reg.enabled.store(true, Ordering::Release);
+            let (offset, size) = VirtioCommonCfg::LAYOUT_DEVICE_STATUS;
+            for status in [
+                DevStatus::ACK,
+                DevStatus::DRIVER,
+                DevStatus::FEATURES_OK,
+                DevStatus::DRIVER_OK,
+            ] {
+                let status = pci_dev.dev.reg.status.load(Ordering::Acquire) | status.bits();
+                let registers = &pci_dev.registers;
+                registers
+                    .write(offset as u64, size as u8, status as u64)
+                    .unwrap();
+            }
+        };
+        let used_idx = || memory.read::<[u16; 2]>(USED_ADDR).unwrap()[1];
+
+        start();
+        pci_dev.dev.notify_queue(0).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while used_idx() == 0 {
+            assert!(Instant::now() < deadline);
+            std::thread::sleep(Duration::from_millis(1));
+        }
+
+        config
+            .write(pmcsr, 2, PowerState::D3_HOT.raw() as u64)
+            .unwrap();
+        assert_eq!(config.read(pmcsr, 2).unwrap() & 0b11, 3);
+        assert_eq!(pci_dev.dev.reg.status.load(Ordering::Acquire), 0);
+        // avail.idx = 2, avail.ring[1] = 0
+        memory.write(AVAIL_ADDR + 2, &[2u16, 0, 0]).unwrap();
+        pci_dev.dev.notify_queue(0).unwrap();
+        std::thread::sleep(Duration::from_millis(50));
+        assert_eq!(used_idx(), 1);
+
+        // The driver initializes the device again in D0.
+        config.write(pmcsr, 2, PowerState::D0.raw() as u64).unwrap();
+        start();
+        pci_dev.dev.notify_queue(0).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while used_idx() == 0 {
+            assert!(Instant::now() < deadline);
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn test_describe() {
+        let memory = Arc::new(RamBus::new(FakeVmMemory));
+        let name = Arc::new("entropy".to_owned());
+        let entropy = Entropy::new(name.clone(), RngSource::Urandom).unwrap();
+        let registry = FakeIoeventFdRegistry::default();
+        let dev = VirtioDevice::new(
+            name,
+            entropy,
+            memory,
+            &registry,
+            false,
+            None,
+            #[cfg(target_os = "linux")]
+            None,
+        )
+        .unwrap();
+        let pci_dev = VirtioPciDevice::new(dev, FakeMsiSender::default(), registry, false).unwrap();
+        let registers = &pci_dev.registers;
+        let write = |(offset, size): (usize, usize), val: u64| {
+            registers.write(offset as u64, size as u8, val).unwrap();
+        };
+
+        let desc = pci_dev.describe();
+        assert_eq!(desc.name, "entropy");
+        assert_eq!(desc.device_id, DeviceId::Entropy);
+        assert_eq!(desc.num_queues, 1);
+        assert_eq!(desc.features_driver, 0);
+        assert_eq!(desc.status, DevStatus::empty());
+        assert_eq!(desc.queue_infos[0].msix_vector, None);
+
+        // The driver acknowledges VERSION_1 only.
+        let feature = desc.features_device & VirtioFeature::VERSION_1.bits();
+        write(VirtioCommonCfg::LAYOUT_DRIVER_FEATURE_SELECT, 1);
+        write(VirtioCommonCfg::LAYOUT_DRIVER_FEATURE, feature >> 32);
+        write(VirtioCommonCfg::LAYOUT_QUEUE_SELECT, 0);
+        write(VirtioCommonCfg::LAYOUT_QUEUE_SIZE, 64);
+        write(VirtioCommonCfg::LAYOUT_QUEUE_MSIX_VECTOR, 1);
+        write(VirtioCommonCfg::LAYOUT_QUEUE_DESC_LO, 0x1000);
+        write(VirtioCommonCfg::LAYOUT_QUEUE_DRIVER_LO, 0x2000);
+        write(VirtioCommonCfg::LAYOUT_QUEUE_DEVICE_LO, 0x3000);
+        write(VirtioCommonCfg::LAYOUT_QUEUE_ENABLE, 1);
+        let mut status = DevStatus::empty();
+        for bit in [
+            DevStatus::ACK,
+            DevStatus::DRIVER,
+            DevStatus::FEATURES_OK,
+            DevStatus::DRIVER_OK,
+        ] {
+            status |= bit;
+            write(VirtioCommonCfg::LAYOUT_DEVICE_STATUS, status.bits() as u64);
+        }
+
+        let desc = pci_dev.describe();
+        assert_ne!(desc.features_device, feature);
+        assert_eq!(desc.features_driver, VirtioFeature::VERSION_1.bits());
+        assert_eq!(desc.status, status);
+        assert_eq!(
+            desc.queue_infos,
+            [QueueInfo {
+                size: 64,
+                enabled: true,
+                desc: 0x1000,
+                driver: 0x2000,
+                device: 0x3000,
+                msix_vector: Some(1),
+            }]
+        );
+    }
+
+    /// A device that does nothing, for testing the transport.
+    #[derive(Debug)]
+    struct TestDev {
+        num_queues: u16,
+        required_feature: u64,
+    }
+
+    impl Virtio for TestDev {
+        type Config = EntropyConfig;
+        type Feature = EntropyFeature;
+
+        fn num_queues(&self) -> u16 {
+            self.num_queues
+        }
+
+        fn reset(&mut self, _registry: &Registry) {}
+
+        fn device_id() -> DeviceId {
+            DeviceId::Entropy
+        }
+
+        fn config(&self) -> Arc<EntropyConfig> {
+            Arc::new(EntropyConfig)
+        }
+
+        fn feature(&self) -> u64 {
+            FEATURE_BUILT_IN
+        }
+
+        fn required_features(&self) -> u64 {
+            self.required_feature
+        }
+
+        fn activate(
+            &mut self,
+            _registry: &Registry,
+            _feature: u64,
+            _memory: &RamBus,
+            _irq_sender: &impl IrqSender,
+            _queues: &[Queue],
+        ) -> Result<()> {
+            Ok(())
+        }
+
+        fn handle_queue(
+            &mut self,
+            _index: u16,
+            _queues: &[impl VirtQueue],
+            _irq_sender: &impl IrqSender,
+            _registry: &Registry,
+        ) -> Result<()> {
+            Ok(())
+        }
+
+        fn handle_event(
+            &mut self,
+            _event: &Event,
+            _queues: &[impl VirtQueue],
+            _irq_sender: &impl IrqSender,
+            _registry: &Registry,
+        ) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    fn new_test_pci_dev(
+        dev: TestDev,
+        msi_sender: FakeMsiSender,
+        restricted_memory: bool,
+    ) -> Result<VirtioPciDevice<TestDev, FakeMsiSender, FakeIoeventFd>> {
+        let registry = FakeIoeventFdRegistry::default();
+        let dev = VirtioDevice::new(
+            Arc::new("test".to_owned()),
+            dev,
+            Arc::new(RamBus::new(FakeVmMemory)),
+            &registry,
+            restricted_memory,
+            None,
+            #[cfg(target_os = "linux")]
+            None,
+        )?;
+        VirtioPciDevice::new(dev, msi_sender, registry, false)
+    }
+
+    #[test]
+    fn test_max_msix_vectors() {
+        let new_pci_dev = |num_queues, max_vectors| {
+            let dev = TestDev {
+                num_queues,
+                required_feature: 0,
+            };
+            let msi_sender = FakeMsiSender {
+                max_vectors,
+                ..Default::default()
+            };
+            new_test_pci_dev(dev, msi_sender, false)
+        };
+
+        let pci_dev = new_pci_dev(255, Some(256)).unwrap();
+        assert_eq!(pci_dev.num_msix_vectors(), 256);
+
+        assert_matches!(
+            new_pci_dev(300, Some(256)),
+            Err(Error::TooManyMsixVectors {
+                num: 301,
+                max: 256,
+                ..
+            })
+        );
+        // limited by the size of the MSI-X capability
+        assert_matches!(
+            new_pci_dev(2048, None),
+            Err(Error::TooManyMsixVectors {
+                num: 2049,
+                max: 2048,
+                ..
+            })
+        );
+    }
+
+    #[test]
+    fn test_check_features() {
+        let new_pci_dev = |restricted_memory| {
+            // works only with drivers that accept VERSION_1
+            let dev = TestDev {
+                num_queues: 1,
+                required_feature: VirtioFeature::VERSION_1.bits(),
+            };
+            new_test_pci_dev(dev, FakeMsiSender::default(), restricted_memory).unwrap()
+        };
+        let driver = DevStatus::ACK | DevStatus::DRIVER;
+        let features_ok = driver | DevStatus::FEATURES_OK;
+        let negotiate = |pci_dev: &VirtioPciDevice<_, _, _>, feature: u64| {
+            let write = |(offset, size): (usize, usize), val: u64| {
+                let registers = &pci_dev.registers;
+                registers.write(offset as u64, size as u8, val).unwrap();
+            };
+            write(VirtioCommonCfg::LAYOUT_DEVICE_STATUS, 0);
+            write(VirtioCommonCfg::LAYOUT_DEVICE_STATUS, driver.bits() as u64);
+            write(VirtioCommonCfg::LAYOUT_DRIVER_FEATURE_SELECT, 0);
+            write(
+                VirtioCommonCfg::LAYOUT_DRIVER_FEATURE,
+                feature & 0xffff_ffff,
+            );
+            write(VirtioCommonCfg::LAYOUT_DRIVER_FEATURE_SELECT, 1);
+            write(VirtioCommonCfg::LAYOUT_DRIVER_FEATURE, feature >> 32);
+            write(
+                VirtioCommonCfg::LAYOUT_DEVICE_STATUS,
+                features_ok.bits() as u64,
+            );
+            DevStatus::from_bits_retain(pci_dev.dev.reg.status.load(Ordering::Acquire))
+        };
+
+        let pci_dev = new_pci_dev(false);
+        // rejected by the device
+        let feature = VirtioFeature::EVENT_IDX.bits();
+        assert_eq!(negotiate(&pci_dev, feature), driver);
+        // not offered by the device
+        let feature = VirtioFeature::VERSION_1 | VirtioFeature::ACCESS_PLATFORM;
+        assert_eq!(negotiate(&pci_dev, feature.bits()), driver);
+        assert_eq!(negotiate(&pci_dev, FEATURE_BUILT_IN), features_ok);
+
+        // ORDER_PLATFORM depends on ACCESS_PLATFORM
+        let pci_dev = new_pci_dev(true);
+        let feature = VirtioFeature::VERSION_1 | VirtioFeature::ORDER_PLATFORM;
+        assert_eq!(negotiate(&pci_dev, feature.bits()), driver);
+        let feature = feature | VirtioFeature::ACCESS_PLATFORM;
+        assert_eq!(negotiate(&pci_dev, feature.bits()), features_ok);
+    }
+
+    #[test]
+    fn test_sriov() {
+        let memory = Memory::new(FakeVmMemory);
+        let ram_bus = memory.ram_bus();
+        let new_dev = move |name: String| {
+            let name = Arc::new(name);
+            let entropy = Entropy::new(name.clone(), RngSource::Urandom)?;
+            let registry = FakeIoeventFdRegistry::default();
+            let dev = VirtioDevice::new(
+                name,
+                entropy,
+                ram_bus.clone(),
+                &registry,
+                false,
+                None,
+                #[cfg(target_os = "linux")]
+                None,
+            )?;
+            VirtioPciDevice::new(dev, FakeMsiSender::default(), registry, false)
+        };
+        let bus = PciBus::new();
+        let pf_bdf = Bdf(1 << 3);
+        let name = Arc::new("entropy".to_owned());
+        let new_vf = new_dev.clone();
+        let build = move |index, _| new_vf(format!("entropy-vf{index}"));
+        let vfs = VirtioVfs::new(
+            name.clone(),
+            pf_bdf,
+            2,
+            bus.segment.clone(),
+            Box::new(build),
+        );
+        let vfs = vfs.unwrap();
+        let pf = new_dev("entropy".to_owned()).unwrap();
+        let pf = VirtioSrIovPf::new(
+            pf.dev,
+            FakeMsiSender::default(),
+            FakeIoeventFdRegistry::default(),
+            false,
+            vfs,
+        );
+        let pf = Arc::new(pf.unwrap());
+        pf.config().get_header().set_bdf(pf_bdf);
+        bus.add(pf_bdf, PciDevice::new(name, pf.clone()));
+
+        let registers = &pf.pf.registers;
+        let (offset, size) = VirtioCommonCfg::LAYOUT_DEVICE_FEATURE_SELECT;
+        registers.write(offset as u64, size as u8, 1).unwrap();
+        let (offset, size) = VirtioCommonCfg::LAYOUT_DEVICE_FEATURE;
+        let feature_hi = registers.read(offset as u64, size as u8).unwrap();
+        assert_eq!(feature_hi << 32 & VirtioFeature::SR_IOV.bits(), 1 << 37);
+
+        // The SR-IOV capability follows AER.
+        let segment = &bus.segment;
+        let pf_config = |offset: u64| (pf_bdf.0 as u64) << 12 | offset;
+        let aer = PcieExtCapHdr(segment.read(pf_config(0x100), 4).unwrap() as u32);
+        assert_eq!(aer.id(), PcieExtCapId::AdvancedErrorReporting as u32);
+        let sriov = pf_config(aer.next() as u64);
+        let header = PcieExtCapHdr(segment.read(sriov, 4).unwrap() as u32);
+        assert_eq!(header.id(), PcieExtCapId::SingleRootIoVirtualization as u32);
+        assert_eq!(header.next(), 0);
+        let read = |offset: usize, size: u8| segment.read(sriov + offset as u64, size).unwrap();
+        let write = |offset: usize, size: u8, val: u64| {
+            let action = segment.write(sriov + offset as u64, size, val).unwrap();
+            if let Action::ChangeLayout { callback } = action {
+                callback.change(&memory).unwrap();
+            }
+        };
+        assert_eq!(read(SrIovRegs::OFFSET_TOTAL_VFS, 2), 2);
+        assert_eq!(read(SrIovRegs::OFFSET_VF_DEVICE_ID, 2), 0x1044);
+
+        // Each VF has a BAR 0 of the same size as the PF's.
+        write(SrIovRegs::OFFSET_VF_BARS, 4, u32::MAX as u64);
+        let bar0_size = !read(SrIovRegs::OFFSET_VF_BARS, 4) as u32 as u64 + 1;
+        let pf_bars = pf.pf.config.header.bars.read();
+        let PciBar::Mem(pf_bar0) = &pf_bars[0] else {
+            panic!("{:?}", pf_bars[0])
+        };
+        assert_eq!(bar0_size, pf_bar0.size());
+        drop(pf_bars);
+        write(SrIovRegs::OFFSET_VF_BARS, 4, 0xc000_0000);
+        write(SrIovRegs::OFFSET_NUM_VFS, 2, 2);
+        let control = SrIovCtrl::VF_ENABLE | SrIovCtrl::VF_MSE;
+        write(SrIovRegs::OFFSET_CONTROL, 2, control.bits() as u64);
+
+        let vf_bdfs = [Bdf(pf_bdf.0 + 1), Bdf(pf_bdf.0 + 2)];
+        for bdf in vf_bdfs {
+            let vf_config = |offset: u64| (bdf.0 as u64) << 12 | offset;
+            assert_eq!(segment.read(vf_config(0), 4).unwrap(), 0xffff_ffff);
+            assert_eq!(segment.read(vf_config(OFFSET_BAR0 as u64), 4).unwrap(), 0);
+            // class and subclass of the entropy device
+            assert_eq!(segment.read(vf_config(0xa), 2).unwrap(), 0xff00);
+        }
+        let entries = memory.mem_region_entries();
+        let addrs: Vec<_> = entries.iter().map(|(addr, e)| (*addr, e.size)).collect();
+        assert_eq!(
+            addrs,
+            [
+                (0xc000_0000, bar0_size),
+                (0xc000_0000 + bar0_size, bar0_size)
+            ]
+        );
+        // MSI-X vector 0 of VF 1 is masked by default.
+        assert_matches!(
+            memory.handle_mmio(0xc000_0000 + bar0_size + 12, None, 4),
+            Ok(VmEntry::Mmio { data: 1 })
+        );
+
+        // NumVFs cannot change while VFs are enabled.
+        write(SrIovRegs::OFFSET_NUM_VFS, 2, 1);
+        assert_eq!(read(SrIovRegs::OFFSET_NUM_VFS, 2), 2);
+
+        write(SrIovRegs::OFFSET_CONTROL, 2, 0);
+        assert!(memory.mem_region_entries().is_empty());
+        let devices = segment.devices.read();
+        assert!(vf_bdfs.iter().all(|bdf| !devices.contains_key(bdf)));
+        assert!(devices.contains_key(&pf_bdf));
+    }
+}
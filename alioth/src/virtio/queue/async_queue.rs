@@ -0,0 +1,239 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Access to virtqueues from devices running on Tokio.
+
+use std::fs::File;
+use std::io::{self, ErrorKind, Read};
+use std::os::fd::OwnedFd;
+
+use tokio::io::unix::AsyncFd;
+use tokio::sync::Mutex;
+
+use crate::virtio::queue::{Descriptor, LockedQueue, QueueGuard, VirtQueue};
+use crate::virtio::{error, Result};
+
+/// A descriptor chain copied out of guest memory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DescChain {
+    pub id: u16,
+    /// Data in the device-readable buffers.
+    pub readable: Vec<u8>,
+    /// Total size of the device-writable buffers.
+    pub writable_len: usize,
+}
+
+impl From<&Descriptor<'_>> for DescChain {
+    fn from(desc: &Descriptor) -> Self {
+        DescChain {
+            id: desc.id,
+            readable: desc
+                .readable
+                .iter()
+                .flat_map(|b| b.iter().copied())
+                .collect(),
+            writable_len: desc.writable.iter().map(|b| b.len()).sum(),
+        }
+    }
+}
+
+/// Wraps a [`VirtQueue`] with async methods.
+///
+/// Chains are processed one at a time: the chain returned by
+/// [`AsyncVirtQueue::next_chain`] stays the next chain until it is passed
+/// to [`AsyncVirtQueue::push_used`].
+#[derive(Debug)]
+pub struct AsyncVirtQueue<Q> {
+    queue: Mutex<Q>,
+    notifier: AsyncFd<File>,
+}
+
+impl<Q> AsyncVirtQueue<Q>
+where
+    Q: VirtQueue,
+{
+    /// `notifier` is the non-blocking eventfd signaled by the driver, e.g. an
+    /// ioeventfd. Must be called within a Tokio runtime with IO enabled.
+    pub fn new(queue: Q, notifier: OwnedFd) -> io::Result<Self> {
+        Ok(AsyncVirtQueue {
+            queue: Mutex::new(queue),
+            notifier: AsyncFd::new(File::from(notifier))?,
+        })
+    }
+
+    fn try_next_chain(&self, queue: &Q) -> Result<Option<DescChain>> {
+        let guard = queue.lock_ram_layout();
+        let layout = guard.queue()?;
+        if let Some(desc) = layout.next_desc() {
+            return Ok(Some(DescChain::from(&desc?)));
+        }
+        layout.enable_notification(true);
+        match layout.next_desc() {
+            Some(desc) => Ok(Some(DescChain::from(&desc?))),
+            None => Ok(None),
+        }
+    }
+
+    async fn wait_notification(&self) -> io::Result<()> {
+        loop {
+            let mut ready = self.notifier.readable().await?;
+            let mut buf = [0u8; 8];
+            match ready.get_inner().read(&mut buf) {
+                Ok(_) => return Ok(()),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => ready.clear_ready(),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Waits for the next descriptor chain made available by the driver.
+    ///
+    /// Returns `None` if the queue is broken, e.g. the driver made an
+    /// invalid chain available, or the notifier fails.
+    pub async fn next_chain(&self) -> Option<DescChain> {
+        loop {
+            let queue = self.queue.lock().await;
+            match self.try_next_chain(&queue) {
+                Ok(Some(chain)) => return Some(chain),
+                Ok(None) => {}
+                Err(e) => {
+                    log::error!("failed to get the next chain: {e:?}");
+                    return None;
+                }
+            }
+            drop(queue);
+            if let Err(e) = self.wait_notification().await {
+                log::error!("failed to wait for notifications: {e}");
+                return None;
+            }
+        }
+    }
+
+    /// Writes `data` to the writable buffers of `chain`, and returns it to the
+    /// driver. Returns whether the driver expects an interrupt.
+    pub async fn push_used(&self, chain: DescChain, data: &[u8]) -> Result<bool> {
+        let queue = self.queue.lock().await;
+        let guard = queue.lock_ram_layout();
+        let mut layout = guard.queue()?;
+        let mut desc = match layout.next_desc() {
+            Some(Ok(desc)) if desc.id == chain.id => desc,
+            Some(Err(e)) => return Err(e),
+            _ => return error::InvalidDescriptor { id: chain.id }.fail(),
+        };
+        let mut len = 0;
+        for buf in desc.writable.iter_mut() {
+            let count = std::cmp::min(buf.len(), data.len() - len);
+            buf[..count].copy_from_slice(&data[len..len + count]);
+            len += count;
+        }
+        layout.push_used(desc, len);
+        Ok(layout.interrupt_enabled())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::mem::size_of;
+    use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+    use std::sync::atomic::{AtomicBool, AtomicU16, AtomicU64};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    use libc::{eventfd, EFD_CLOEXEC, EFD_NONBLOCK};
+
+    use crate::ffi;
+    use crate::hv::test::FakeVmMemory;
+    use crate::mem::mapped::{ArcMemPages, RamBus};
+    use crate::mem::MemRegionType;
+    use crate::virtio::queue::async_queue::{AsyncVirtQueue, DescChain};
+    use crate::virtio::queue::split::{AvailHeader, Desc, DescFlag, SplitQueue, UsedHeader};
+    use crate::virtio::queue::Queue;
+
+    const DESC_ADDR: u64 = 0x0;
+    const AVAIL_ADDR: u64 = 0x100;
+    const USED_ADDR: u64 = 0x200;
+    const BUF_ADDR: u64 = 0x1000;
+
+    #[test]
+    fn test_async_virt_queue() {
+        let ram_bus = Arc::new(RamBus::new(FakeVmMemory));
+        let pages = ArcMemPages::from_anonymous(0x2000, None).unwrap();
+        ram_bus.add(0, pages, MemRegionType::Ram, false).unwrap();
+        let descs = [
+            Desc {
+                addr: BUF_ADDR,
+                len: 4,
+                flag: DescFlag::NEXT.bits(),
+                next: 1,
+            },
+            Desc {
+                addr: BUF_ADDR + 0x10,
+                len: 8,
+                flag: DescFlag::WRITE.bits(),
+                next: 0,
+            },
+        ];
+        ram_bus.write(DESC_ADDR, &descs).unwrap();
+        ram_bus.write(BUF_ADDR, b"ping").unwrap();
+        let reg = Queue {
+            size: AtomicU16::new(4),
+            desc: AtomicU64::new(DESC_ADDR),
+            driver: AtomicU64::new(AVAIL_ADDR),
+            device: AtomicU64::new(USED_ADDR),
+            enabled: AtomicBool::new(true),
+        };
+        let queue = SplitQueue::new(&reg, ram_bus.clone(), 0, None);
+        let fd = ffi!(unsafe { eventfd(0, EFD_CLOEXEC | EFD_NONBLOCK) }).unwrap();
+        let notifier = unsafe { OwnedFd::from_raw_fd(fd) };
+        let kick = notifier.try_clone().unwrap();
+
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_io()
+            .build()
+            .unwrap();
+        let driver_ram_bus = ram_bus.clone();
+        let driver = thread::spawn(move || {
+            // Makes the chain available after the device starts waiting.
+            thread::sleep(Duration::from_millis(50));
+            let ring_addr = AVAIL_ADDR + size_of::<AvailHeader>() as u64;
+            driver_ram_bus.write(ring_addr, &0u16).unwrap();
+            driver_ram_bus.write(AVAIL_ADDR, &[0u16, 1]).unwrap();
+            let val = 1u64.to_ne_bytes();
+            ffi!(unsafe { libc::write(kick.as_raw_fd(), val.as_ptr() as _, 8) }).unwrap();
+        });
+        rt.block_on(async {
+            let q = AsyncVirtQueue::new(queue, notifier).unwrap();
+            let chain = q.next_chain().await.unwrap();
+            assert_eq!(
+                chain,
+                DescChain {
+                    id: 0,
+                    readable: b"ping".to_vec(),
+                    writable_len: 8,
+                }
+            );
+            assert!(q.push_used(chain, b"pong").await.unwrap());
+        });
+        driver.join().unwrap();
+
+        let [_flags, used_index]: [u16; 2] = ram_bus.read(USED_ADDR).unwrap();
+        assert_eq!(used_index, 1);
+        let used_elem: [u32; 2] = ram_bus
+            .read(USED_ADDR + size_of::<UsedHeader>() as u64)
+            .unwrap();
+        assert_eq!(used_elem, [0, 4]);
+        assert_eq!(&ram_bus.read::<[u8; 4]>(BUF_ADDR + 0x10).unwrap(), b"pong");
+    }
+}
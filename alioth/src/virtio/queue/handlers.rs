@@ -16,38 +16,90 @@ use std::io::{self, ErrorKind, Read, Write};
 use std::sync::atomic::{fence, Ordering};
 
 use crate::virtio::queue::{Descriptor, LockedQueue, QueueGuard, VirtQueue};
-use crate::virtio::{IrqSender, Result};
+use crate::virtio::{Error, IrqSender, Result};
+
+pub const DESC_BATCH_SIZE: usize = 64;
 
 pub fn handle_desc(
     dev_name: &str,
     q_index: u16,
     queue: &impl VirtQueue,
     irq_sender: &impl IrqSender,
+    op: impl FnMut(&mut Descriptor) -> io::Result<usize>,
+) -> Result<()> {
+    handle_desc_batch(dev_name, q_index, queue, irq_sender, 1, op)
+}
+
+/// Processes up to `max` descriptors at a time and sends at most one
+/// interrupt for each batch.
+pub fn handle_desc_batch(
+    dev_name: &str,
+    q_index: u16,
+    queue: &impl VirtQueue,
+    irq_sender: &impl IrqSender,
+    max: usize,
     mut op: impl FnMut(&mut Descriptor) -> io::Result<usize>,
 ) -> Result<()> {
     let guard = queue.lock_ram_layout();
     let mut q = guard.queue()?;
+    // Reused across batches so that handling a descriptor chain does not
+    // allocate.
+    let mut descs = Vec::with_capacity(max);
+    let mut used = Vec::with_capacity(max);
     'out: loop {
         if !q.has_next_desc() {
             break;
         }
         q.enable_notification(false);
-        while let Some(desc) = q.next_desc() {
-            let mut desc = desc?;
-            match op(&mut desc) {
-                Err(e) if e.kind() == ErrorKind::WouldBlock => break 'out,
-                Err(e) => {
-                    log::error!("{dev_name}: queue {q_index}: {e}");
+        loop {
+            match q.try_take_batch(max, &mut descs) {
+                Err(Error::InvalidBuffer { id, source, .. }) => {
+                    log::error!("{dev_name}: queue {q_index}: descriptor {id}: {source}");
+                    // Return the descriptor to the driver without touching
+                    // its buffers.
+                    let desc = Descriptor {
+                        id,
+                        readable: vec![],
+                        writable: vec![],
+                    };
+                    used.push((desc, 0));
+                }
+                r => r?,
+            }
+            if descs.is_empty() && used.is_empty() {
+                if q.has_next_desc() {
+                    // The descriptor cannot be taken now, e.g. the bounce
+                    // buffer is out of slots. Retry on the next notification.
                     q.enable_notification(true);
                     break 'out;
                 }
-                Ok(len) => {
-                    q.push_used(desc, len);
-                    if q.interrupt_enabled() {
-                        fence(Ordering::SeqCst);
-                        irq_sender.queue_irq(q_index)
+                break;
+            }
+            let mut stop = false;
+            for mut desc in descs.drain(..) {
+                match op(&mut desc) {
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => stop = true,
+                    Err(e) => {
+                        log::error!("{dev_name}: queue {q_index}: {e}");
+                        q.enable_notification(true);
+                        stop = true;
+                    }
+                    Ok(len) => {
+                        used.push((desc, len));
+                        continue;
                     }
                 }
+                break;
+            }
+            if !used.is_empty() {
+                q.push_used_batch(used.drain(..));
+                if q.interrupt_enabled() {
+                    fence(Ordering::SeqCst);
+                    irq_sender.queue_irq(q_index)
+                }
+            }
+            if stop {
+                break 'out;
             }
         }
         q.enable_notification(true);
@@ -80,11 +132,18 @@ pub fn queue_to_writer(
     queue: &impl VirtQueue,
     irq_sender: &impl IrqSender,
 ) -> Result<()> {
-    handle_desc(dev_name, q_index, queue, irq_sender, |desc| {
-        if writer.write_vectored(&desc.readable)? == 0 {
-            Err(ErrorKind::WriteZero.into())
-        } else {
-            Ok(0)
-        }
-    })
+    handle_desc_batch(
+        dev_name,
+        q_index,
+        queue,
+        irq_sender,
+        DESC_BATCH_SIZE,
+        |desc| {
+            if writer.write_vectored(&desc.readable)? == 0 {
+                Err(ErrorKind::WriteZero.into())
+            } else {
+                Ok(0)
+            }
+        },
+    )
 }
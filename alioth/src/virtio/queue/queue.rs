@@ -12,11 +12,14 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::collections::BTreeMap;
 use std::io::{IoSlice, IoSliceMut};
 use std::sync::atomic::{AtomicBool, AtomicU16, AtomicU64};
 
 use crate::virtio::Result;
 
+#[cfg(feature = "async-device")]
+pub mod async_queue;
 pub mod handlers;
 pub mod split;
 
@@ -40,12 +43,49 @@ pub struct Descriptor<'m> {
 
 pub trait LockedQueue<'g> {
     fn next_desc(&self) -> Option<Result<Descriptor<'g>>>;
+    /// Appends up to `max` available descriptors to `descs`, starting from
+    /// the next one to be used. They must be returned to the driver in order
+    /// through [`LockedQueue::push_used`].
+    fn try_take_batch(&self, max: usize, descs: &mut Vec<Descriptor<'g>>) -> Result<()>;
     fn has_next_desc(&self) -> bool;
     fn push_used(&mut self, desc: Descriptor, len: usize) -> u16;
+    fn push_used_batch<'d>(&mut self, used: impl IntoIterator<Item = (Descriptor<'d>, usize)>) {
+        for (desc, len) in used {
+            self.push_used(desc, len);
+        }
+    }
     fn enable_notification(&self, enabled: bool);
     fn interrupt_enabled(&self) -> bool;
 }
 
+/// Holds descriptors completed out of order until all descriptors taken
+/// before them are completed.
+#[derive(Debug, Default)]
+pub struct ReorderBuffer<'m> {
+    next: u64,
+    pending: BTreeMap<u64, (Descriptor<'m>, usize)>,
+}
+
+impl<'m> ReorderBuffer<'m> {
+    /// Records the completion of the `seq`-th descriptor taken from the queue.
+    pub fn complete(&mut self, seq: u64, desc: Descriptor<'m>, len: usize) {
+        self.pending.insert(seq, (desc, len));
+    }
+
+    /// Returns completed descriptors to the driver in the order they were
+    /// taken. Returns the number of descriptors pushed to the used ring.
+    pub fn flush<'g>(&mut self, queue: &mut impl LockedQueue<'g>) -> usize {
+        let mut used = vec![];
+        while let Some(entry) = self.pending.remove(&self.next) {
+            used.push(entry);
+            self.next += 1;
+        }
+        let count = used.len();
+        queue.push_used_batch(used);
+        count
+    }
+}
+
 pub trait QueueGuard {
     fn queue(&self) -> Result<impl LockedQueue>;
 }
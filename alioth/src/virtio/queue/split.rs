@@ -12,18 +12,22 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::cell::UnsafeCell;
+use std::cell::{Cell, RefCell, UnsafeCell};
+use std::collections::HashMap;
+use std::io::{IoSlice, IoSliceMut};
 use std::mem::size_of;
 use std::sync::atomic::{fence, Ordering};
 use std::sync::Arc;
 
 use bitflags::bitflags;
 use macros::Layout;
+use snafu::ResultExt;
 use zerocopy::{AsBytes, FromBytes, FromZeroes};
 
+use crate::mem::bounce::{BounceBuffer, BounceSlot, BOUNCE_SLOT_SIZE};
 use crate::mem::mapped::{RamBus, RamLayoutGuard};
 use crate::virtio::queue::{Descriptor, LockedQueue, Queue, QueueGuard, VirtQueue};
-use crate::virtio::{error, Result, VirtioFeature};
+use crate::virtio::{error, IrqSender, Result, VirtioFeature};
 
 #[repr(C, align(16))]
 #[derive(Debug, Clone, Default, FromBytes, FromZeroes, AsBytes)]
@@ -91,11 +95,19 @@ struct Register {
 pub struct SplitQueue {
     pub memory: Arc<RamBus>,
     register: Register,
+    bounce_buffer: Option<Arc<BounceBuffer>>,
 }
 
 struct SplitQueueGuard<'m, 'q> {
     guard: RamLayoutGuard<'m>,
     register: &'q Register,
+    bounce_buffer: Option<&'q BounceBuffer>,
+}
+
+/// Guest buffers of a descriptor whose data is staged in bounce slots.
+struct Bounced<'g> {
+    _slots: Vec<BounceSlot<'g>>,
+    writable: Vec<(u64, u64)>,
 }
 
 struct SplitLayout<'g, 'm> {
@@ -105,12 +117,18 @@ struct SplitLayout<'g, 'm> {
     avail_ring: &'g [UnsafeCell<u16>],
     used_event: Option<&'g UnsafeCell<u16>>,
 
+    used_gpa: u64,
     used: &'g UnsafeCell<UsedHeader>,
     used_ring: &'g [UnsafeCell<UsedElem>],
-    avail_event: Option<&'g UnsafeCell<u16>>,
+    avail_event: Option<(u64, &'g UnsafeCell<u16>)>,
     used_index: u16,
+    signaled_index: Cell<u16>,
+    in_order: bool,
 
     desc: &'g [UnsafeCell<Desc>],
+
+    bounce_buffer: Option<&'g BounceBuffer>,
+    bounced: RefCell<HashMap<u16, Bounced<'g>>>,
 }
 
 type DescIov = (Vec<(u64, u64)>, Vec<(u64, u64)>);
@@ -121,7 +139,9 @@ impl<'g, 'm> SplitLayout<'g, 'm> {
     }
 
     pub fn set_used_index(&self) {
-        unsafe { &mut *self.used.get() }.idx = self.used_index
+        unsafe { &mut *self.used.get() }.idx = self.used_index;
+        self.guard
+            .mark_dirty(self.used_gpa, size_of::<UsedHeader>() as u64);
     }
 
     pub fn used_event(&self) -> Option<u16> {
@@ -130,20 +150,30 @@ impl<'g, 'm> SplitLayout<'g, 'm> {
 
     pub fn set_avail_event(&self, index: u16) -> Option<()> {
         match self.avail_event {
-            Some(avail_event) => {
+            Some((gpa, avail_event)) => {
                 *unsafe { &mut *avail_event.get() } = index;
+                self.guard.mark_dirty(gpa, size_of::<u16>() as u64);
                 Some(())
             }
             None => None,
         }
     }
 
+    /// The flags of both rings are little-endian in guest memory.
     pub fn set_flag_notification(&self, enabled: bool) {
-        unsafe { &mut *self.used.get() }.flags = (!enabled) as _;
+        let flags = if enabled {
+            UsedFlag::empty()
+        } else {
+            UsedFlag::NO_NOTIFY
+        };
+        unsafe { &mut *self.used.get() }.flags = flags.bits().to_le();
+        self.guard
+            .mark_dirty(self.used_gpa, size_of::<UsedHeader>() as u64);
     }
 
     pub fn flag_interrupt_enabled(&self) -> bool {
-        unsafe { &*self.avail.get() }.flags == 0
+        let flags = u16::from_le(unsafe { &*self.avail.get() }.flags);
+        !AvailFlag::from_bits_retain(flags).contains(AvailFlag::NO_INTERRUPT)
     }
 
     pub fn read_avail(&self, index: u16) -> u16 {
@@ -205,20 +235,114 @@ impl<'g, 'm> SplitLayout<'g, 'm> {
         Ok((readable, writeable))
     }
 
-    fn get_next_desc(&self) -> Result<Option<Descriptor<'g>>> {
-        if self.used_index == self.avail_index() {
-            return Ok(None);
-        }
-        let desc_id = self.read_avail(self.used_index);
+    fn write_used_elem(&self, index: u16, id: u16, len: usize) {
+        let used_elem = UsedElem {
+            id: id as u32,
+            len: len as u32,
+        };
+        let wrapped_index = index as usize & (self.used_ring.len() - 1);
+        *unsafe { &mut *self.used_ring.get_unchecked(wrapped_index).get() } = used_elem;
+        let gpa = self.used_gpa
+            + size_of::<UsedHeader>() as u64
+            + (wrapped_index * size_of::<UsedElem>()) as u64;
+        self.guard.mark_dirty(gpa, size_of::<UsedElem>() as u64);
+    }
+
+    /// Returns `None` if the bounce buffer runs out of slots, in which case
+    /// the descriptor stays in the available ring.
+    fn get_avail_desc(&self, index: u16) -> Result<Option<Descriptor<'g>>> {
+        let desc_id = self.read_avail(index);
         let (readable, writable) = self.get_desc_iov(desc_id)?;
-        let readable = self.guard.translate_iov(&readable)?;
-        let writable = self.guard.translate_iov_mut(&writable)?;
+        let Some(bounce_buffer) = self.bounce_buffer else {
+            let readable = (self.guard.translate_iov(&readable))
+                .context(error::InvalidBuffer { id: desc_id })?;
+            let writable = (self.guard.translate_iov_mut(&writable))
+                .context(error::InvalidBuffer { id: desc_id })?;
+            return Ok(Some(Descriptor {
+                id: desc_id,
+                readable,
+                writable,
+            }));
+        };
+        // The guest buffers are never handed to the device. Data is copied
+        // between them and the slots by guest physical address.
+        let mut slots = Vec::new();
+        let mut alloc = |len| {
+            let slot = bounce_buffer.alloc()?;
+            // The slot is kept in `self.bounced` until the descriptor is
+            // returned to the driver.
+            let buf = unsafe { slot.as_mut_slice(len) };
+            slots.push(slot);
+            Some(buf)
+        };
+        let mut bounced_readable = Vec::new();
+        for &(gpa, len) in readable.iter() {
+            for offset in (0..len).step_by(BOUNCE_SLOT_SIZE) {
+                let size = std::cmp::min(len - offset, BOUNCE_SLOT_SIZE as u64);
+                let Some(buf) = alloc(size as usize) else {
+                    return Ok(None);
+                };
+                (self.guard.read_bytes(gpa + offset, buf))
+                    .context(error::InvalidBuffer { id: desc_id })?;
+                bounced_readable.push(IoSlice::new(buf));
+            }
+        }
+        let mut bounced_writable = Vec::new();
+        for &(gpa, len) in writable.iter() {
+            (self.guard.check_range(gpa, len)).context(error::InvalidBuffer { id: desc_id })?;
+            for offset in (0..len).step_by(BOUNCE_SLOT_SIZE) {
+                let size = std::cmp::min(len - offset, BOUNCE_SLOT_SIZE as u64);
+                let Some(buf) = alloc(size as usize) else {
+                    return Ok(None);
+                };
+                bounced_writable.push(IoSliceMut::new(buf));
+            }
+        }
+        let bounced = Bounced {
+            _slots: slots,
+            writable,
+        };
+        self.bounced.borrow_mut().insert(desc_id, bounced);
         Ok(Some(Descriptor {
             id: desc_id,
-            readable,
-            writable,
+            readable: bounced_readable,
+            writable: bounced_writable,
         }))
     }
+
+    /// Copies the first `len` bytes written by the device back to the guest
+    /// buffers and releases the bounce slots of `desc`.
+    fn unbounce(&self, desc: &Descriptor, len: usize) {
+        let Some(bounced) = self.bounced.borrow_mut().remove(&desc.id) else {
+            return;
+        };
+        let mut src_bufs = desc.writable.iter();
+        let mut remaining = len;
+        for &(gpa, size) in bounced.writable.iter() {
+            for offset in (0..size).step_by(BOUNCE_SLOT_SIZE) {
+                let Some(src) = src_bufs.next() else {
+                    return;
+                };
+                if remaining == 0 {
+                    return;
+                }
+                let count = std::cmp::min(remaining, src.len());
+                // The range was checked when the descriptor was taken.
+                if let Err(e) = self.guard.write_bytes(gpa + offset, &src[..count]) {
+                    log::error!("descriptor {}: {e}", desc.id);
+                    return;
+                }
+                remaining -= count;
+            }
+        }
+    }
+
+    fn get_next_desc(&self) -> Result<Option<Descriptor<'g>>> {
+        if self.used_index == self.avail_index() {
+            return Ok(None);
+        }
+        self.get_avail_desc(self.used_index)
+    }
 }
 
 impl<'g, 'm> LockedQueue<'g> for SplitLayout<'g, 'm> {
@@ -226,24 +350,66 @@ impl<'g, 'm> LockedQueue<'g> for SplitLayout<'g, 'm> {
         self.get_next_desc().transpose()
     }
 
+    fn try_take_batch(&self, max: usize, descs: &mut Vec<Descriptor<'g>>) -> Result<()> {
+        let pending = self.avail_index().wrapping_sub(self.used_index);
+        let count = std::cmp::min(
+            max,
+            std::cmp::min(pending, self.avail_ring.len() as u16) as usize,
+        );
+        for offset in 0..count as u16 {
+            match self.get_avail_desc(self.used_index.wrapping_add(offset)) {
+                Ok(Some(desc)) => descs.push(desc),
+                // Out of bounce slots. The rest are taken after the ones
+                // taken so far are returned to the driver.
+                Ok(None) => break,
+                // Return the valid descriptors first; the error is reported
+                // when the invalid one becomes the next to use.
+                Err(_) if offset > 0 => break,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
     fn has_next_desc(&self) -> bool {
         self.used_index != self.avail_index()
     }
 
     fn push_used(&mut self, desc: Descriptor, len: usize) -> u16 {
         let used_index = self.used_index;
-        let used_elem = UsedElem {
-            id: desc.id as u32,
-            len: len as u32,
-        };
-        let wrapped_index = used_index as usize & (self.used_ring.len() - 1);
-        *unsafe { &mut *self.used_ring.get_unchecked(wrapped_index).get() } = used_elem;
+        self.unbounce(&desc, len);
+        self.write_used_elem(used_index, desc.id, len);
         fence(Ordering::SeqCst);
         self.used_index = used_index.wrapping_add(1);
         self.set_used_index();
         used_index
     }
 
+    fn push_used_batch<'d>(&mut self, used: impl IntoIterator<Item = (Descriptor<'d>, usize)>) {
+        if !self.in_order {
+            for (desc, len) in used {
+                self.push_used(desc, len);
+            }
+            return;
+        }
+        // With IN_ORDER, only the used element of the last buffer in a batch
+        // is written.
+        let mut last = None;
+        let mut used_index = self.used_index;
+        for (desc, len) in used {
+            self.unbounce(&desc, len);
+            last = Some((used_index, desc.id, len));
+            used_index = used_index.wrapping_add(1);
+        }
+        let Some((last_index, id, len)) = last else {
+            return;
+        };
+        self.write_used_elem(last_index, id, len);
+        fence(Ordering::SeqCst);
+        self.used_index = used_index;
+        self.set_used_index();
+    }
+
     fn enable_notification(&self, enabled: bool) {
         if self.avail_event.is_some() {
             let mut avail_index = self.avail_index();
@@ -267,8 +433,12 @@ impl<'g, 'm> LockedQueue<'g> for SplitLayout<'g, 'm> {
     }
 
     fn interrupt_enabled(&self) -> bool {
+        let new = self.used_index;
+        let old = self.signaled_index.replace(new);
         match self.used_event() {
-            Some(used_event) => used_event == self.used_index.wrapping_sub(1),
+            Some(used_event) => {
+                new.wrapping_sub(used_event).wrapping_sub(1) < new.wrapping_sub(old)
+            }
             None => self.flag_interrupt_enabled(),
         }
     }
@@ -276,6 +446,12 @@ impl<'g, 'm> LockedQueue<'g> for SplitLayout<'g, 'm> {
 
 impl<'m, 'q> QueueGuard for SplitQueueGuard<'m, 'q> {
     fn queue(&self) -> Result<impl LockedQueue> {
+        self.layout()
+    }
+}
+
+impl<'m, 'q> SplitQueueGuard<'m, 'q> {
+    fn layout(&self) -> Result<SplitLayout<'_, 'm>> {
         let mut avail_event = None;
         let mut used_event = None;
         let queue_size = self.register.size as u64;
@@ -283,7 +459,7 @@ impl<'m, 'q> QueueGuard for SplitQueueGuard<'m, 'q> {
             let avail_event_gpa = self.register.used
                 + size_of::<UsedHeader>() as u64
                 + queue_size * size_of::<UsedElem>() as u64;
-            avail_event = Some(self.guard.get_ref(avail_event_gpa)?);
+            avail_event = Some((avail_event_gpa, self.guard.get_ref(avail_event_gpa)?));
             let used_event_gpa = self.register.avail
                 + size_of::<AvailHeader>() as u64
                 + queue_size * size_of::<u16>() as u64;
@@ -298,17 +474,30 @@ impl<'m, 'q> QueueGuard for SplitQueueGuard<'m, 'q> {
             avail: self.guard.get_ref(self.register.avail)?,
             avail_ring: self.guard.get_slice(avail_ring_gpa, queue_size)?,
             used_event,
+            used_gpa: self.register.used,
             used,
             used_index,
+            signaled_index: Cell::new(used_index),
+            in_order: self.register.feature.contains(VirtioFeature::IN_ORDER),
             used_ring: self.guard.get_slice(used_ring_gpa, queue_size)?,
             avail_event,
             desc: self.guard.get_slice(self.register.desc, queue_size)?,
+            bounce_buffer: self.bounce_buffer,
+            bounced: RefCell::new(HashMap::new()),
         })
     }
 }
 
 impl SplitQueue {
-    pub fn new(reg: &Queue, memory: Arc<RamBus>, feature: u64) -> Self {
+    /// Creates a queue from the registers written by the driver. If
+    /// `ACCESS_PLATFORM` is negotiated and `bounce_buffer` is provided, data
+    /// of descriptors is copied through the bounce buffer.
+    pub fn new(
+        reg: &Queue,
+        memory: Arc<RamBus>,
+        feature: u64,
+        bounce_buffer: Option<Arc<BounceBuffer>>,
+    ) -> Self {
         let register = if reg.enabled.load(Ordering::Acquire) {
             Register {
                 size: reg.size.load(Ordering::Acquire),
@@ -320,7 +509,55 @@ impl SplitQueue {
         } else {
             Register::default()
         };
-        Self { memory, register }
+        let feature = VirtioFeature::from_bits_retain(feature);
+        let bounce_buffer =
+            bounce_buffer.filter(|_| feature.contains(VirtioFeature::ACCESS_PLATFORM));
+        Self {
+            memory,
+            register,
+            bounce_buffer,
+        }
+    }
+
+    fn guard(&self) -> SplitQueueGuard<'_, '_> {
+        SplitQueueGuard {
+            guard: self.memory.lock_layout(),
+            register: &self.register,
+            bounce_buffer: self.bounce_buffer.as_deref(),
+        }
+    }
+
+    /// Returns all descriptors still in the available ring to the driver
+    /// with no data, and sends an interrupt if any is returned. Returns
+    /// the number of descriptors returned.
+    ///
+    /// Descriptors a device has taken but not used stay in the available
+    /// ring of a split queue, so this also returns buffers the device has
+    /// not looked at yet.
+    pub fn drain_used(&self, q_index: u16, irq_sender: &impl IrqSender) -> Result<usize> {
+        if self.register.size == 0 {
+            return Ok(0);
+        }
+        let guard = self.guard();
+        let mut q = guard.layout()?;
+        let pending = q.avail_index().wrapping_sub(q.used_index);
+        let count = std::cmp::min(pending, self.register.size) as usize;
+        let used = (0..count as u16).map(|offset| {
+            let desc = Descriptor {
+                id: q.read_avail(q.used_index.wrapping_add(offset)),
+                readable: vec![],
+                writable: vec![],
+            };
+            (desc, 0)
+        });
+        let used: Vec<_> = used.collect();
+        q.push_used_batch(used);
+        if count > 0 {
+            // The driver is going away, so interrupt suppression is ignored.
+            fence(Ordering::SeqCst);
+            irq_sender.queue_irq(q_index);
+        }
+        Ok(count)
     }
 }
 
@@ -338,10 +575,423 @@ impl VirtQueue for SplitQueue {
     }
 
     fn lock_ram_layout(&self) -> impl QueueGuard {
-        let guard = self.memory.lock_layout();
-        SplitQueueGuard {
-            guard,
-            register: &self.register,
+        self.guard()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{ErrorKind, Read, Write};
+    use std::mem::size_of;
+    use std::sync::atomic::{AtomicBool, AtomicU16, AtomicU64};
+    use std::sync::Arc;
+
+    use assert_matches::assert_matches;
+    use parking_lot::Mutex;
+
+    use crate::hv::test::FakeVmMemory;
+    use crate::mem::bounce::{BounceBuffer, BOUNCE_SLOT_SIZE};
+    use crate::mem::mapped::{ArcMemPages, RamBus};
+    use crate::mem::{DirtyPageCallback, MemRegion, MemRegionType, Memory};
+    use crate::virtio::queue::handlers::handle_desc_batch;
+    use crate::virtio::queue::split::{AvailHeader, Desc, DescFlag, SplitQueue, UsedHeader};
+    use crate::virtio::queue::{LockedQueue, Queue, QueueGuard, ReorderBuffer, VirtQueue};
+    use crate::virtio::test::FakeIrqSender;
+    use crate::virtio::{Error, VirtioFeature};
+
+    const DESC_ADDR: u64 = 0x0;
+    const AVAIL_ADDR: u64 = 0x100;
+    const USED_ADDR: u64 = 0x200;
+    const BUF_ADDR: u64 = 0x1000;
+
+    const QUEUE_SIZE: u16 = 4;
+
+    #[derive(Debug)]
+    struct DirtyPages(Arc<Mutex<Vec<(u64, u64)>>>);
+
+    impl DirtyPageCallback for DirtyPages {
+        fn page_dirtied(&self, gpa: u64, size: u64) {
+            self.0.lock().push((gpa, size));
+        }
+    }
+
+    fn setup_queue(
+        ram_bus: &Arc<RamBus>,
+        avail_index: u16,
+        feature: u64,
+        bounce_buffer: Option<Arc<BounceBuffer>>,
+    ) -> SplitQueue {
+        let pages = ArcMemPages::from_anonymous(0x2000, None).unwrap();
+        ram_bus.add(0, pages, MemRegionType::Ram, false).unwrap();
+        init_queue(ram_bus, avail_index, feature, bounce_buffer)
+    }
+
+    /// Fills the rings in memory mapped at 0.
+    fn init_queue(
+        ram_bus: &Arc<RamBus>,
+        avail_index: u16,
+        feature: u64,
+        bounce_buffer: Option<Arc<BounceBuffer>>,
+    ) -> SplitQueue {
+        for i in 0..QUEUE_SIZE as u64 {
+            let desc = Desc {
+                addr: BUF_ADDR + i * 0x10,
+                len: 0x10,
+                flag: 0,
+                next: 0,
+            };
+            ram_bus
+                .write(DESC_ADDR + i * size_of::<Desc>() as u64, &desc)
+                .unwrap();
+            let entry_addr = AVAIL_ADDR + size_of::<AvailHeader>() as u64 + i * 2;
+            ram_bus.write(entry_addr, &(i as u16)).unwrap();
+        }
+        // avail.flags = 0
+        ram_bus.write(AVAIL_ADDR, &[0u16, avail_index]).unwrap();
+
+        let reg = Queue {
+            size: AtomicU16::new(QUEUE_SIZE),
+            desc: AtomicU64::new(DESC_ADDR),
+            driver: AtomicU64::new(AVAIL_ADDR),
+            device: AtomicU64::new(USED_ADDR),
+            enabled: AtomicBool::new(true),
+        };
+        SplitQueue::new(&reg, ram_bus.clone(), feature, bounce_buffer)
+    }
+
+    fn read_used(ram_bus: &RamBus) -> (u16, Vec<[u32; 2]>) {
+        let [_flags, idx]: [u16; 2] = ram_bus.read(USED_ADDR).unwrap();
+        let ring_addr = USED_ADDR + size_of::<UsedHeader>() as u64;
+        let ring = (0..QUEUE_SIZE as u64)
+            .map(|i| ram_bus.read(ring_addr + i * 8).unwrap())
+            .collect();
+        (idx, ring)
+    }
+
+    #[test]
+    fn test_try_take_batch() {
+        let ram_bus = Arc::new(RamBus::new(FakeVmMemory));
+        let queue = setup_queue(&ram_bus, 3, 0, None);
+        let guard = queue.lock_ram_layout();
+        let mut q = guard.queue().unwrap();
+
+        let mut descs = vec![];
+        q.try_take_batch(2, &mut descs).unwrap();
+        let ids: Vec<_> = descs.iter().map(|d| d.id).collect();
+        assert_eq!(ids, [0, 1]);
+        drop(descs);
+
+        let mut descs = vec![];
+        q.try_take_batch(8, &mut descs).unwrap();
+        assert_eq!(descs.len(), 3);
+        assert_eq!(descs[0].readable[0].len(), 0x10);
+        q.push_used(descs.remove(0), 0);
+
+        let mut descs = vec![];
+        q.try_take_batch(8, &mut descs).unwrap();
+        let ids: Vec<_> = descs.iter().map(|d| d.id).collect();
+        assert_eq!(ids, [1, 2]);
+    }
+
+    #[test]
+    fn test_index_wrap_around() {
+        let ram_bus = Arc::new(RamBus::new(FakeVmMemory));
+        // 3 buffers available across the u16 boundary: 0xfffe, 0xffff, 0x0
+        let queue = setup_queue(&ram_bus, 1, 0, None);
+        ram_bus.write(USED_ADDR, &[0u16, 0xfffe]).unwrap();
+        let guard = queue.lock_ram_layout();
+        let mut q = guard.queue().unwrap();
+
+        assert!(q.has_next_desc());
+        let mut descs = vec![];
+        q.try_take_batch(8, &mut descs).unwrap();
+        let ids: Vec<_> = descs.iter().map(|d| d.id).collect();
+        assert_eq!(ids, [2, 3, 0]);
+        for desc in descs {
+            let id = desc.id;
+            q.push_used(desc, id as usize);
+        }
+        assert!(!q.has_next_desc());
+        assert_matches!(q.next_desc(), None);
+        assert_eq!(
+            read_used(&ram_bus),
+            (1, vec![[0, 0], [0, 0], [2, 2], [3, 3]])
+        );
+    }
+
+    #[test]
+    fn test_flag_notification() {
+        let ram_bus = Arc::new(RamBus::new(FakeVmMemory));
+        let queue = setup_queue(&ram_bus, 0, 0, None);
+        let guard = queue.lock_ram_layout();
+        let q = guard.queue().unwrap();
+
+        q.enable_notification(false);
+        assert_eq!(ram_bus.read::<[u8; 2]>(USED_ADDR).unwrap(), [1, 0]);
+        q.enable_notification(true);
+        assert_eq!(ram_bus.read::<[u8; 2]>(USED_ADDR).unwrap(), [0, 0]);
+
+        assert!(q.interrupt_enabled());
+        ram_bus.write(AVAIL_ADDR, &[1u8, 0]).unwrap();
+        assert!(!q.interrupt_enabled());
+    }
+
+    #[test]
+    fn test_in_order() {
+        let ram_bus = Arc::new(RamBus::new(FakeVmMemory));
+        let feature = VirtioFeature::IN_ORDER.bits();
+        let queue = setup_queue(&ram_bus, 3, feature, None);
+        let guard = queue.lock_ram_layout();
+        let mut q = guard.queue().unwrap();
+
+        let mut descs = vec![];
+        q.try_take_batch(8, &mut descs).unwrap();
+        assert_eq!(descs.len(), 3);
+        let mut reorder = ReorderBuffer::default();
+        let desc_2 = descs.pop().unwrap();
+        let desc_1 = descs.pop().unwrap();
+        let desc_0 = descs.pop().unwrap();
+
+        reorder.complete(2, desc_2, 0x12);
+        assert_eq!(reorder.flush(&mut q), 0);
+        assert_eq!(read_used(&ram_bus), (0, vec![[0, 0]; 4]));
+
+        reorder.complete(0, desc_0, 0x10);
+        assert_eq!(reorder.flush(&mut q), 1);
+        assert_eq!(
+            read_used(&ram_bus),
+            (1, vec![[0, 0x10], [0, 0], [0, 0], [0, 0]])
+        );
+
+        // Only the last buffer of a batch gets a used element.
+        reorder.complete(1, desc_1, 0x11);
+        assert_eq!(reorder.flush(&mut q), 2);
+        assert_eq!(
+            read_used(&ram_bus),
+            (3, vec![[0, 0x10], [0, 0], [2, 0x12], [0, 0]])
+        );
+    }
+
+    #[test]
+    fn test_bounce_buffer() {
+        let ram_bus = Arc::new(RamBus::new(FakeVmMemory));
+        let bounce_buffer = Arc::new(BounceBuffer::new(2 * BOUNCE_SLOT_SIZE).unwrap());
+        let feature = VirtioFeature::ACCESS_PLATFORM.bits();
+        let queue = setup_queue(&ram_bus, 2, feature, Some(bounce_buffer.clone()));
+        let request: [u8; 0x10] = std::array::from_fn(|i| i as u8);
+        ram_bus.write(BUF_ADDR, &request).unwrap();
+        let desc = Desc {
+            addr: BUF_ADDR + 0x10,
+            len: 0x10,
+            flag: DescFlag::WRITE.bits(),
+            next: 0,
+        };
+        ram_bus
+            .write(DESC_ADDR + size_of::<Desc>() as u64, &desc)
+            .unwrap();
+
+        let guard = queue.lock_ram_layout();
+        let mut q = guard.queue().unwrap();
+        let mut descs = vec![];
+        q.try_take_batch(2, &mut descs).unwrap();
+        assert!(bounce_buffer.alloc().is_none());
+        assert_eq!(&*descs[0].readable[0], &request);
+
+        let response: [u8; 0x10] = std::array::from_fn(|i| 0xff - i as u8);
+        descs[1].writable[0].copy_from_slice(&response);
+        assert_eq!(
+            ram_bus.read::<[u8; 0x10]>(BUF_ADDR + 0x10).unwrap(),
+            [0; 0x10]
+        );
+
+        let desc_1 = descs.pop().unwrap();
+        let desc_0 = descs.pop().unwrap();
+        q.push_used(desc_0, 0);
+        q.push_used(desc_1, 0xc);
+        let mut expected = [0; 0x10];
+        expected[..0xc].copy_from_slice(&response[..0xc]);
+        assert_eq!(
+            ram_bus.read::<[u8; 0x10]>(BUF_ADDR + 0x10).unwrap(),
+            expected
+        );
+        assert_eq!(ram_bus.read::<[u8; 0x10]>(BUF_ADDR).unwrap(), request);
+
+        let slots = [bounce_buffer.alloc(), bounce_buffer.alloc()];
+        assert!(slots.iter().all(|s| s.is_some()));
+    }
+
+    #[test]
+    fn test_bounce_buffer_exhausted() {
+        let ram_bus = Arc::new(RamBus::new(FakeVmMemory));
+        let bounce_buffer = Arc::new(BounceBuffer::new(BOUNCE_SLOT_SIZE).unwrap());
+        let feature = VirtioFeature::ACCESS_PLATFORM.bits();
+        let queue = setup_queue(&ram_bus, 2, feature, Some(bounce_buffer.clone()));
+        let guard = queue.lock_ram_layout();
+        let mut q = guard.queue().unwrap();
+
+        let slot = bounce_buffer.alloc().unwrap();
+        let mut descs = vec![];
+        q.try_take_batch(2, &mut descs).unwrap();
+        assert!(descs.is_empty());
+        assert!(q.next_desc().is_none());
+        assert!(q.has_next_desc());
+        drop(slot);
+
+        q.try_take_batch(2, &mut descs).unwrap();
+        let ids: Vec<_> = descs.iter().map(|d| d.id).collect();
+        assert_eq!(ids, [0]);
+        q.push_used(descs.pop().unwrap(), 0);
+
+        q.try_take_batch(2, &mut descs).unwrap();
+        let ids: Vec<_> = descs.iter().map(|d| d.id).collect();
+        assert_eq!(ids, [1]);
+    }
+
+    #[test]
+    fn test_invalid_buffer() {
+        let ram_bus = Arc::new(RamBus::new(FakeVmMemory));
+        let queue = setup_queue(&ram_bus, 3, 0, None);
+        let desc = Desc {
+            addr: 0x1_0000,
+            len: 0x10,
+            flag: 0,
+            next: 0,
+        };
+        ram_bus
+            .write(DESC_ADDR + size_of::<Desc>() as u64, &desc)
+            .unwrap();
+        let guard = queue.lock_ram_layout();
+        let mut q = guard.queue().unwrap();
+
+        let mut descs = vec![];
+        q.try_take_batch(8, &mut descs).unwrap();
+        assert_eq!(descs.len(), 1);
+        q.push_used(descs.pop().unwrap(), 0);
+        assert_matches!(
+            q.try_take_batch(8, &mut vec![]),
+            Err(Error::InvalidBuffer { id: 1, .. })
+        );
+    }
+
+    #[test]
+    fn test_reset_cycles() {
+        let ram_bus = Arc::new(RamBus::new(FakeVmMemory));
+        // Maps the memory and fills the descriptor table.
+        setup_queue(&ram_bus, 0, 0, None);
+        let reg = Queue {
+            size: AtomicU16::new(QUEUE_SIZE),
+            desc: AtomicU64::new(DESC_ADDR),
+            driver: AtomicU64::new(AVAIL_ADDR),
+            device: AtomicU64::new(USED_ADDR),
+            enabled: AtomicBool::new(true),
+        };
+        let irq_sender = FakeIrqSender::default();
+
+        for cycle in 0..1000u16 {
+            // The driver re-initializes the rings after each reset.
+            let avail_index = cycle % QUEUE_SIZE + 1;
+            ram_bus.write(USED_ADDR, &[0u16, 0]).unwrap();
+            ram_bus.write(AVAIL_ADDR, &[0u16, avail_index]).unwrap();
+            let queue = SplitQueue::new(&reg, ram_bus.clone(), 0, None);
+
+            let block_at = cycle % 3;
+            let mut count = 0;
+            handle_desc_batch("test", 0, &queue, &irq_sender, 2, |_| {
+                count += 1;
+                if count > block_at {
+                    Err(ErrorKind::WouldBlock.into())
+                } else {
+                    Ok(0)
+                }
+            })
+            .unwrap();
+
+            let (used_index, _) = read_used(&ram_bus);
+            assert_eq!(used_index, block_at.min(avail_index));
+        }
+    }
+
+    #[test]
+    fn test_push_used_dirty() {
+        let memory = Memory::new(FakeVmMemory);
+        let pages = ArcMemPages::from_anonymous(0x2000, None).unwrap();
+        let region = MemRegion::with_mapped(pages, MemRegionType::Ram);
+        let dirty = Arc::new(Mutex::new(vec![]));
+        region
+            .dirty_callbacks
+            .lock()
+            .push(Box::new(DirtyPages(dirty.clone())));
+        memory.add_region(0, Arc::new(region)).unwrap();
+        let ram_bus = memory.ram_bus();
+        let feature = VirtioFeature::EVENT_IDX.bits();
+        let queue = init_queue(&ram_bus, 1, feature, None);
+        memory.flush_dirty().unwrap();
+        dirty.lock().clear();
+
+        let guard = queue.lock_ram_layout();
+        let mut q = guard.queue().unwrap();
+        let desc = q.next_desc().unwrap().unwrap();
+        q.push_used(desc, 0);
+        memory.flush_dirty().unwrap();
+        assert_eq!(*dirty.lock(), [(0, 0x1000)]);
+
+        // the device suppresses notifications with avail_event
+        dirty.lock().clear();
+        q.enable_notification(false);
+        memory.flush_dirty().unwrap();
+        assert_eq!(*dirty.lock(), [(0, 0x1000)]);
+    }
+
+    #[test]
+    fn test_desc_across_regions() {
+        const BOUNDARY: u64 = 1 << 30;
+        let ram_bus = Arc::new(RamBus::new(FakeVmMemory));
+        let queue = setup_queue(&ram_bus, 1, 0, None);
+        for gpa in [BOUNDARY - 0x1000, BOUNDARY, BOUNDARY + 0x1000] {
+            let pages = ArcMemPages::from_anonymous(0x1000, None).unwrap();
+            ram_bus.add(gpa, pages, MemRegionType::Ram, false).unwrap();
         }
+        // The request crosses 1 GiB, and the response buffer crosses the
+        // next region boundary.
+        let descs = [
+            Desc {
+                addr: BOUNDARY - 0x8,
+                len: 0x10,
+                flag: DescFlag::NEXT.bits(),
+                next: 1,
+            },
+            Desc {
+                addr: BOUNDARY + 0x1000 - 0x8,
+                len: 0x10,
+                flag: DescFlag::WRITE.bits(),
+                next: 0,
+            },
+        ];
+        ram_bus.write(DESC_ADDR, &descs).unwrap();
+        let request: [u8; 0x10] = std::array::from_fn(|i| i as u8);
+        ram_bus.write(BOUNDARY - 0x8, &request).unwrap();
+
+        let guard = queue.lock_ram_layout();
+        let mut q = guard.queue().unwrap();
+        let mut descs = vec![];
+        q.try_take_batch(8, &mut descs).unwrap();
+        assert_eq!(descs.len(), 1);
+        let desc = &mut descs[0];
+        assert_eq!(desc.readable.len(), 2);
+        assert_eq!(desc.writable.len(), 2);
+
+        let mut buf = Vec::new();
+        assert_eq!(buf.write_vectored(&desc.readable).unwrap(), 0x10);
+        assert_eq!(buf, request);
+
+        let response: [u8; 0x10] = std::array::from_fn(|i| 0xff - i as u8);
+        let mut src = &response[..];
+        assert_eq!(src.read_vectored(&mut desc.writable).unwrap(), 0x10);
+        q.push_used(descs.pop().unwrap(), 0x10);
+        assert_eq!(
+            ram_bus.read::<[u8; 0x10]>(BOUNDARY + 0x1000 - 0x8).unwrap(),
+            response
+        );
+        assert_eq!(read_used(&ram_bus).0, 1);
     }
 }
@@ -13,10 +13,14 @@
 // limitations under the License.
 
 use std::fmt::Debug;
+use std::io::ErrorKind;
 use std::os::fd::RawFd;
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 
 use bitflags::bitflags;
+use log::{Level, LevelFilter};
 use snafu::Snafu;
 
 use crate::errors::{trace_error, DebugTrace};
@@ -59,12 +63,27 @@ pub enum Error {
     PollEvents { error: std::io::Error },
     #[snafu(display("Failed to create a worker thread"))]
     WorkerThread { error: std::io::Error },
+    #[snafu(display("Device worker has exited"))]
+    WorkerExited,
     #[snafu(display("Invalid descriptor id {id}"))]
     InvalidDescriptor { id: u16 },
     #[snafu(display("Invalid queue index {index}"))]
     InvalidQueueIndex { index: u16 },
+    #[snafu(display("Invalid interrupt {irq:?}"))]
+    InvalidIrq { irq: pci::VirtioIrq },
     #[snafu(display("Invalid msix vector {vector}"))]
     InvalidMsixVector { vector: u16 },
+    #[snafu(display("Descriptor {id} refers to invalid guest memory"))]
+    InvalidBuffer {
+        id: u16,
+        source: Box<crate::mem::Error>,
+    },
+    #[snafu(display("Invalid zone size {size:#x}"))]
+    InvalidZoneSize { size: u64 },
+    #[snafu(display("Cannot have {num} VFs, the maximum is {max}"))]
+    TooManyVfs { num: u16, max: u16 },
+    #[snafu(display("Cannot have {num} MSI-X vectors, the maximum is {max}"))]
+    TooManyMsixVectors { num: usize, max: u32 },
     #[cfg(target_os = "linux")]
     #[snafu(display("vhost-user error"), context(false))]
     Vu { source: Box<vu::Error> },
@@ -73,6 +92,19 @@ pub enum Error {
     Vhost { source: Box<vhost::Error> },
 }
 
+impl Error {
+    /// Returns `true` if the failed operation may succeed when retried.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Error::System { error, .. } => matches!(
+                error.kind(),
+                ErrorKind::Interrupted | ErrorKind::WouldBlock | ErrorKind::TimedOut
+            ),
+            _ => false,
+        }
+    }
+}
+
 type Result<T, E = Error> = std::result::Result<T, E>;
 
 bitflags! {
@@ -84,12 +116,15 @@ bitflags! {
         const VERSION_1 = 1 << 32;
         const ACCESS_PLATFORM = 1 << 33;
         const RING_PACKED = 1 << 34;
+        const IN_ORDER = 1 << 35;
+        const ORDER_PLATFORM = 1 << 36;
+        const SR_IOV = 1 << 37;
     }
 }
 
 const FEATURE_BUILT_IN: u64 = VirtioFeature::EVENT_IDX.bits() | VirtioFeature::VERSION_1.bits();
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum DeviceId {
     Net = 1,
     Block = 2,
@@ -97,12 +132,13 @@ pub enum DeviceId {
     Socket = 19,
     Iommu = 23,
     Mem = 24,
+    Sound = 25,
     FileSystem = 26,
     Pmem = 27,
 }
 
 bitflags! {
-    #[derive(Debug, Default, Clone, Copy)]
+    #[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
     pub struct DevStatus: u8 {
         const ACK = 1;
         const DRIVER = 2;
@@ -113,9 +149,140 @@ bitflags! {
     }
 }
 
+impl DevStatus {
+    /// Checks if a driver may change the device status from `self` to `new`,
+    /// following the initialization sequence in virtio spec 1.2, section 3.1.
+    pub fn transition_valid(self, new: DevStatus) -> bool {
+        if new.is_empty() {
+            return true;
+        }
+        let cleared = (self - new) - DevStatus::NEEDS_RESET;
+        if !cleared.is_empty() {
+            return false;
+        }
+        let prerequisites = [
+            (DevStatus::DRIVER, DevStatus::ACK),
+            (DevStatus::FEATURES_OK, DevStatus::DRIVER),
+            (DevStatus::DRIVER_OK, DevStatus::FEATURES_OK),
+        ];
+        prerequisites
+            .into_iter()
+            .all(|(bit, required)| !new.contains(bit) || new.contains(required))
+    }
+}
+
+/// The most verbose log level of a device, which can be changed at runtime
+/// to debug one device without flooding the log with the others.
+#[derive(Debug, Clone)]
+pub struct LogLevel(Arc<AtomicUsize>);
+
+impl LogLevel {
+    pub fn set(&self, level: LevelFilter) {
+        self.0.store(level as usize, Ordering::Relaxed)
+    }
+
+    pub fn enabled(&self, level: Level) -> bool {
+        level as usize <= self.0.load(Ordering::Relaxed)
+    }
+}
+
+impl Default for LogLevel {
+    fn default() -> Self {
+        LogLevel(Arc::new(AtomicUsize::new(LevelFilter::Trace as usize)))
+    }
+}
+
+/// Logs a message at `level` if it is enabled by the [`LogLevel`] of a
+/// device, e.g. `dev_log!(self.log_level, Error, "{}: failed", self.name)`.
+#[macro_export]
+macro_rules! dev_log {
+    ($log_level:expr, $level:ident, $($arg:tt)+) => {
+        if $log_level.enabled(::log::Level::$level) {
+            ::log::log!(::log::Level::$level, $($arg)+)
+        }
+    };
+}
+
 pub trait IrqSender: Send + Sync + Debug + 'static {
     fn queue_irq(&self, idx: u16);
     fn config_irq(&self);
+    /// Delivers queue interrupts deferred by `queue_irq`. Device workers call
+    /// it at the end of each event handling pass.
+    fn flush(&self) {}
+    /// Notifies the driver that the device hit an error it cannot recover
+    /// from.
+    fn device_error(&self) {}
     fn queue_irqfd(&self, idx: u16) -> Result<RawFd>;
     fn config_irqfd(&self) -> Result<RawFd>;
 }
+
+#[cfg(test)]
+pub(crate) mod test {
+    use std::os::fd::RawFd;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use parking_lot::Mutex;
+
+    use crate::virtio::{DevStatus, IrqSender, Result};
+
+    #[derive(Debug, Default)]
+    pub struct FakeIrqSender {
+        pub config_irqs: AtomicU32,
+        pub queue_irqs: Mutex<Vec<u16>>,
+    }
+
+    impl IrqSender for FakeIrqSender {
+        fn queue_irq(&self, idx: u16) {
+            self.queue_irqs.lock().push(idx)
+        }
+
+        fn config_irq(&self) {
+            self.config_irqs.fetch_add(1, Ordering::AcqRel);
+        }
+
+        fn queue_irqfd(&self, _idx: u16) -> Result<RawFd> {
+            unimplemented!()
+        }
+
+        fn config_irqfd(&self) -> Result<RawFd> {
+            unimplemented!()
+        }
+    }
+
+    #[test]
+    fn test_status_transition() {
+        let ack = DevStatus::ACK;
+        let driver = ack | DevStatus::DRIVER;
+        let features_ok = driver | DevStatus::FEATURES_OK;
+        let driver_ok = features_ok | DevStatus::DRIVER_OK;
+
+        let valid = [
+            (DevStatus::empty(), ack),
+            (ack, driver),
+            (driver, features_ok),
+            (features_ok, driver_ok),
+            (driver_ok, driver_ok),
+            (driver_ok, DevStatus::empty()),
+            (features_ok, DevStatus::empty()),
+            (driver, driver | DevStatus::FAILED),
+            (driver_ok | DevStatus::NEEDS_RESET, driver_ok),
+        ];
+        for (old, new) in valid {
+            assert!(old.transition_valid(new), "{old:?} -> {new:?}");
+        }
+
+        let invalid = [
+            (DevStatus::empty(), DevStatus::DRIVER),
+            (ack, ack | DevStatus::FEATURES_OK),
+            (driver, driver | DevStatus::DRIVER_OK),
+            (DevStatus::empty(), driver_ok - DevStatus::FEATURES_OK),
+            (driver_ok, features_ok),
+            (features_ok, driver),
+            (driver_ok, driver_ok - DevStatus::ACK),
+            (driver | DevStatus::FAILED, driver),
+        ];
+        for (old, new) in invalid {
+            assert!(!old.transition_valid(new), "{old:?} -> {new:?}");
+        }
+    }
+}
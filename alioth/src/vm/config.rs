@@ -0,0 +1,205 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! VM descriptions in TOML, e.g.
+//!
+//! ```toml
+//! [vm]
+//! memory_mb = 1024
+//! vcpus = 2
+//!
+//! [device]
+//! console = true
+//!
+//! [[device.block]]
+//! path = "/path/to/disk.img"
+//!
+//! [[device.net]]
+//! if = "tap0"
+//! mtu = 1500
+//!
+//! [boot]
+//! kernel = "/path/to/bzImage"
+//! cmdline = "console=ttyS0"
+//! ```
+
+use std::fs;
+use std::num::{NonZeroU32, NonZeroU64};
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+use snafu::{ResultExt, Snafu};
+
+use crate::errors::{trace_error, DebugTrace};
+use crate::virtio::dev::blk::BlockParam;
+#[cfg(target_os = "linux")]
+use crate::virtio::dev::net::NetParam;
+
+#[trace_error]
+#[derive(Snafu, DebugTrace)]
+#[snafu(module, context(suffix(false)))]
+pub enum Error {
+    #[snafu(display("Failed to read {path:?}"))]
+    ReadFile {
+        path: PathBuf,
+        error: std::io::Error,
+    },
+    #[snafu(display("Invalid VM config"))]
+    Parse { error: toml::de::Error },
+}
+
+type Result<T, E = Error> = std::result::Result<T, E>;
+
+fn default_vcpus() -> NonZeroU32 {
+    NonZeroU32::MIN
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct VmSection {
+    pub memory_mb: NonZeroU64,
+    #[serde(default = "default_vcpus")]
+    pub vcpus: NonZeroU32,
+}
+
+#[derive(Default, Deserialize)]
+pub struct DeviceSection {
+    /// Adds a serial console.
+    #[serde(default)]
+    pub console: bool,
+    #[serde(default)]
+    pub block: Vec<BlockParam>,
+    #[cfg(target_os = "linux")]
+    #[serde(default)]
+    pub net: Vec<NetParam>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct BootSection {
+    pub kernel: PathBuf,
+    #[serde(default)]
+    pub cmdline: String,
+    pub initramfs: Option<PathBuf>,
+}
+
+/// A VM description, consumed by [`VmBuilder::with_config`][super::VmBuilder::with_config].
+#[derive(Deserialize)]
+pub struct VmConfig {
+    pub vm: VmSection,
+    #[serde(default)]
+    pub device: DeviceSection,
+    pub boot: Option<BootSection>,
+}
+
+impl VmConfig {
+    pub fn from_toml(s: &str) -> Result<Self> {
+        toml::from_str(s).context(error::Parse)
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let s = fs::read_to_string(path).context(error::ReadFile { path })?;
+        Self::from_toml(&s)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use crate::vm::config::{BootSection, VmConfig, VmSection};
+
+    #[test]
+    fn test_vm_config_from_toml() {
+        let s = r#"
+            [vm]
+            memory_mb = 2048
+            vcpus = 4
+
+            [device]
+            console = true
+
+            [[device.block]]
+            path = "/var/lib/alioth/root.img"
+            id = "root"
+
+            [[device.block]]
+            path = "/var/lib/alioth/data.img"
+            read_only = true
+
+            [[device.net]]
+            if = "tap0"
+            mac = "ea:d7:a8:e8:c6:2f"
+            mtu = 1500
+            queue_pairs = 4
+
+            [boot]
+            kernel = "/var/lib/alioth/bzImage"
+            cmdline = "console=ttyS0 root=/dev/vda"
+            initramfs = "/var/lib/alioth/initramfs.img"
+        "#;
+        let config = VmConfig::from_toml(s).unwrap();
+        assert_eq!(
+            config.vm,
+            VmSection {
+                memory_mb: 2048.try_into().unwrap(),
+                vcpus: 4.try_into().unwrap(),
+            }
+        );
+        assert!(config.device.console);
+        let [root, data] = config.device.block.as_slice() else {
+            panic!("{:?}", config.device.block)
+        };
+        assert_eq!(root.path, Path::new("/var/lib/alioth/root.img"));
+        assert_eq!(root.id.as_deref(), Some("root"));
+        assert!(!root.read_only);
+        assert_eq!(data.path, Path::new("/var/lib/alioth/data.img"));
+        assert!(data.read_only);
+        #[cfg(target_os = "linux")]
+        {
+            let [net] = config.device.net.as_slice() else {
+                panic!("expect 1 net device")
+            };
+            assert_eq!(net.if_name.as_deref(), Some("tap0"));
+            assert_eq!(
+                net.mac.map(|m| m.octets()),
+                Some([0xea, 0xd7, 0xa8, 0xe8, 0xc6, 0x2f])
+            );
+            assert_eq!(net.mtu, 1500);
+            assert_eq!(net.queue_pairs.map(u16::from), Some(4));
+        }
+        assert_eq!(
+            config.boot,
+            Some(BootSection {
+                kernel: "/var/lib/alioth/bzImage".into(),
+                cmdline: "console=ttyS0 root=/dev/vda".to_owned(),
+                initramfs: Some("/var/lib/alioth/initramfs.img".into()),
+            })
+        );
+    }
+
+    #[test]
+    fn test_vm_config_invalid() {
+        for s in [
+            "[vm]\nmemory_mb = -1",
+            "[vm]\nmemory_mb = 0",
+            "[vm]\nmemory_mb = 512\nvcpus = 0",
+        ] {
+            assert!(VmConfig::from_toml(s).is_err(), "{s}");
+        }
+        #[cfg(target_os = "linux")]
+        {
+            let s = "[vm]\nmemory_mb = 512\n[[device.net]]\nmtu = 1500\nqueue_pairs = 257";
+            assert!(VmConfig::from_toml(s).is_err());
+        }
+    }
+}
@@ -0,0 +1,886 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+pub mod config;
+
+use std::collections::BTreeMap;
+#[cfg(target_os = "linux")]
+use std::os::unix::thread::JoinHandleExt;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Arc;
+use std::thread;
+#[cfg(target_os = "linux")]
+use std::time::Duration;
+
+use parking_lot::{Condvar, Mutex, RwLock};
+use snafu::{ResultExt, Snafu};
+
+#[cfg(target_arch = "aarch64")]
+use crate::arch::layout::PL011_START;
+use crate::board::{ArchBoard, Board, BoardConfig, STATE_CREATED, STATE_RUNNING};
+#[cfg(target_os = "linux")]
+use crate::cgroup::Cgroup;
+#[cfg(target_arch = "x86_64")]
+use crate::device::debugcon::{DebugCon, BOCHS_DEBUGCON_PORT, DEBUGCON_PORT};
+use crate::device::fw_cfg::{FwCfg, FwCfgItemParam, PORT_SELECTOR};
+#[cfg(target_os = "linux")]
+use crate::device::ivshmem::{IvshMem, IvshMemParam};
+#[cfg(target_arch = "x86_64")]
+use crate::device::pit::{I8254, PIT_PORT};
+#[cfg(target_arch = "aarch64")]
+use crate::device::pl011::Pl011;
+use crate::device::pvpanic::PvPanic;
+#[cfg(target_arch = "x86_64")]
+use crate::device::rtc::{Rtc, RTC_PORT};
+#[cfg(target_arch = "x86_64")]
+use crate::device::serial::Serial;
+use crate::device::xhci::Xhci;
+use crate::errors::{trace_error, DebugTrace};
+#[cfg(target_os = "linux")]
+use crate::hv::VcpuPreemptor;
+use crate::hv::{Hypervisor, IoeventFdRegistry, VcpuStatsSnapshot, Vm, VmConfig};
+use crate::loader::{ExecType, Payload};
+use crate::mem::Memory;
+#[cfg(target_arch = "aarch64")]
+use crate::mem::{MemRegion, MemRegionType};
+use crate::pci::bus::PciBus;
+use crate::pci::hotplug::VmBus;
+use crate::pci::{Bdf, PciDevice};
+#[cfg(target_os = "linux")]
+use crate::vfio::container::VfioContainer;
+#[cfg(target_os = "linux")]
+use crate::vfio::group::{VfioDevice, VfioGroup};
+use crate::virtio::dev::blk::BlockParam;
+#[cfg(target_os = "linux")]
+use crate::virtio::dev::net::NetParam;
+use crate::virtio::dev::{DevParam, Virtio, VirtioDevice};
+use crate::virtio::pci::{VirtioPciDevice, VirtioSrIovPf, VirtioVfs};
+
+#[trace_error]
+#[derive(Snafu, DebugTrace)]
+#[snafu(module, context(suffix(false)))]
+pub enum Error {
+    #[snafu(display("Hypervisor internal error"), context(false))]
+    HvError { source: Box<crate::hv::Error> },
+    #[snafu(display("Failed to create board"), context(false))]
+    CreateBoard { source: Box<crate::board::Error> },
+    #[snafu(display("Failed to create VCPU-{id} thread"))]
+    VcpuThread { id: u32, error: std::io::Error },
+    #[cfg(target_os = "linux")]
+    #[snafu(display("Failed to create the VCPU preemptor"))]
+    VcpuPreemptor { error: std::io::Error },
+    #[snafu(display("Failed to create a console"))]
+    CreateConsole { error: std::io::Error },
+    #[cfg(target_arch = "x86_64")]
+    #[snafu(display("Failed to create a PIT"))]
+    CreatePit { error: std::io::Error },
+    #[cfg(target_arch = "x86_64")]
+    #[snafu(display("Failed to create an RTC"))]
+    CreateRtc { error: std::io::Error },
+    #[snafu(display("Failed to create fw-cfg device"))]
+    FwCfg { error: std::io::Error },
+    #[snafu(display("Failed to create a VirtIO device"), context(false))]
+    CreateVirtio { source: Box<crate::virtio::Error> },
+    #[snafu(display("Failed to create an xHCI controller"))]
+    CreateXhci { source: Box<crate::pci::Error> },
+    #[cfg(target_os = "linux")]
+    #[snafu(display("Failed to create an ivshmem device"))]
+    CreateIvshMem {
+        source: Box<crate::device::ivshmem::Error>,
+    },
+    #[snafu(display("Failed to hotplug a PCI device"), context(false))]
+    Hotplug { source: Box<crate::pci::Error> },
+    #[cfg(target_os = "linux")]
+    #[snafu(display("Failed to add a VFIO device"), context(false))]
+    Vfio { source: Box<crate::vfio::Error> },
+    #[cfg(target_os = "linux")]
+    #[snafu(display("Failed to set up the cgroup"), context(false))]
+    Cgroup { source: Box<crate::cgroup::Error> },
+    #[snafu(display("VCPU-{id} error"))]
+    VcpuError {
+        id: u32,
+        source: Box<crate::board::Error>,
+    },
+}
+
+type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Host memory that may be mapped into a VM on top of its RAM, e.g. for
+/// firmware and BARs backed by memory.
+const MEM_BUDGET_OVERHEAD: u64 = 64 << 20;
+
+pub struct Machine<H>
+where
+    H: Hypervisor,
+{
+    board: Arc<Board<H::Vm>>,
+    event_rx: Receiver<u32>,
+    _event_tx: Sender<u32>,
+    #[cfg(target_os = "linux")]
+    vfio_groups: BTreeMap<u32, Arc<VfioGroup>>,
+    #[cfg(target_os = "linux")]
+    vfio_devs: Vec<VfioDevice>,
+    #[cfg(target_os = "linux")]
+    preemptor: Option<VcpuPreemptor>,
+}
+
+pub type VirtioPciDev<D, H> = VirtioPciDevice<
+    D,
+    <<H as Hypervisor>::Vm as Vm>::MsiSender,
+    <<<H as Hypervisor>::Vm as Vm>::IoeventFdRegistry as IoeventFdRegistry>::IoeventFd,
+>;
+
+pub type VirtioSrIovDev<D, H> = VirtioSrIovPf<
+    D,
+    <<H as Hypervisor>::Vm as Vm>::MsiSender,
+    <<<H as Hypervisor>::Vm as Vm>::IoeventFdRegistry as IoeventFdRegistry>::IoeventFd,
+>;
+
+impl<H> Machine<H>
+where
+    H: Hypervisor + 'static,
+{
+    pub fn new(hv: H, config: BoardConfig) -> Result<Self> {
+        // Joins the cgroup first so that kernel memory of the VM is charged.
+        #[cfg(target_os = "linux")]
+        let cgroup = match &config.cgroup {
+            Some(cgroup_config) => Some(Cgroup::new(cgroup_config)?),
+            None => None,
+        };
+        let vm_config = VmConfig {
+            coco: config.coco.clone(),
+        };
+        let mut vm = hv.create_vm(&vm_config)?;
+        let vm_memory = vm.create_vm_memory()?;
+        let memory = Memory::new(vm_memory);
+        let budget = config.mem_size + MEM_BUDGET_OVERHEAD;
+        memory.ram_bus().budget().set_limit(budget);
+        let arch = ArchBoard::new(&hv, &vm, &config)?;
+        let pci_bus = PciBus::new();
+        let vm_bus = VmBus::new(pci_bus.segment.clone(), None);
+
+        let board = Arc::new(Board {
+            vm,
+            memory,
+            arch,
+            config,
+            state: AtomicU8::new(STATE_CREATED),
+            payload: RwLock::new(None),
+            vcpus: Arc::new(RwLock::new(Vec::new())),
+            mp_sync: Arc::new((Mutex::new(0), Condvar::new())),
+            io_devs: RwLock::new(Vec::new()),
+            #[cfg(target_arch = "aarch64")]
+            mmio_devs: RwLock::new(Vec::new()),
+            pci_bus,
+            vm_bus,
+            fw_cfg: Mutex::new(None),
+            vcpu_stats: RwLock::new(BTreeMap::new()),
+            #[cfg(target_os = "linux")]
+            vfio_container: Mutex::new(None),
+            #[cfg(target_os = "linux")]
+            cgroup,
+        });
+
+        let (event_tx, event_rx) = mpsc::channel();
+
+        let mut vcpus = board.vcpus.write();
+        for vcpu_id in 0..board.config.num_cpu {
+            let (boot_tx, boot_rx) = mpsc::channel();
+            let event_tx = event_tx.clone();
+            let board = board.clone();
+            let handle = thread::Builder::new()
+                .name(format!("vcpu-{vcpu_id}"))
+                .spawn(move || board.run_vcpu(vcpu_id, event_tx, boot_rx))
+                .context(error::VcpuThread { id: vcpu_id })?;
+            event_rx.recv().unwrap();
+            vcpus.push((handle, boot_tx));
+        }
+        #[cfg(target_os = "linux")]
+        let preemptor = match board.config.vcpu_quantum_ms {
+            Some(quantum_ms) => {
+                let threads = vcpus
+                    .iter()
+                    .enumerate()
+                    .map(|(id, (handle, _))| (id as u32, handle.as_pthread_t()))
+                    .collect::<Vec<_>>();
+                let quantum = Duration::from_millis(quantum_ms);
+                let preemptor =
+                    VcpuPreemptor::new(quantum, &threads).context(error::VcpuPreemptor)?;
+                Some(preemptor)
+            }
+            None => None,
+        };
+        drop(vcpus);
+
+        board.arch_init()?;
+
+        let machine = Machine {
+            board,
+            event_rx,
+            _event_tx: event_tx,
+            #[cfg(target_os = "linux")]
+            vfio_groups: BTreeMap::new(),
+            #[cfg(target_os = "linux")]
+            vfio_devs: Vec::new(),
+            #[cfg(target_os = "linux")]
+            preemptor,
+        };
+
+        Ok(machine)
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    pub fn add_com1(&self) -> Result<(), Error> {
+        let irq_sender = self.board.vm.create_irq_sender(4)?;
+        let com1 = Serial::new(0x3f8, irq_sender).context(error::CreateConsole)?;
+        self.board.io_devs.write().push((0x3f8, Arc::new(com1)));
+        Ok(())
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    pub fn add_pit(&self) -> Result<(), Error> {
+        let irq_sender = self.board.arch.timer_irq_sender();
+        let pit = I8254::new(irq_sender).context(error::CreatePit)?;
+        self.board.io_devs.write().push((PIT_PORT, Arc::new(pit)));
+        Ok(())
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    pub fn add_rtc(&self) -> Result<(), Error> {
+        let irq_sender = self.board.arch.rtc_irq_sender();
+        let rtc = Rtc::new(irq_sender).context(error::CreateRtc)?;
+        self.board.io_devs.write().push((RTC_PORT, Arc::new(rtc)));
+        Ok(())
+    }
+
+    /// Captures messages that firmware writes to the debug ports 0x402 and
+    /// 0xe9 into `output`.
+    #[cfg(target_arch = "x86_64")]
+    pub fn add_debugcon(&self, output: Box<dyn std::io::Write + Send>) -> Result<(), Error> {
+        let debugcon = Arc::new(DebugCon::new("debugcon".to_owned(), output));
+        let mut io_devs = self.board.io_devs.write();
+        io_devs.push((DEBUGCON_PORT, debugcon.clone()));
+        io_devs.push((BOCHS_DEBUGCON_PORT, debugcon));
+        Ok(())
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    pub fn add_pl011(&self) -> Result<(), Error> {
+        let irq_line = self.board.vm.create_irq_sender(1)?;
+        let pl011_dev = Pl011::new(PL011_START, irq_line).context(error::CreateConsole)?;
+        self.board.mmio_devs.write().push((
+            PL011_START,
+            Arc::new(MemRegion::with_emulated(
+                Arc::new(pl011_dev),
+                MemRegionType::Hidden,
+            )),
+        ));
+        Ok(())
+    }
+
+    pub fn add_pci_dev(&mut self, bdf: Option<Bdf>, dev: PciDevice) -> Result<(), Error> {
+        let name = dev.name.clone();
+        let bdf = if let Some(bdf) = bdf {
+            bdf
+        } else {
+            self.board.pci_bus.reserve(None, name.clone()).unwrap()
+        };
+        let config = dev.dev.config();
+        self.board.pci_bus.add(bdf, dev);
+        let header = config.get_header();
+        header.set_bdf(bdf);
+        log::info!("{bdf}: device: {name}");
+        Ok(())
+    }
+
+    pub fn add_pvpanic(&mut self) -> Result<(), Error> {
+        let dev = PvPanic::new();
+        let pci_dev = PciDevice::new("pvpanic".to_owned().into(), Arc::new(dev));
+        self.add_pci_dev(None, pci_dev)
+    }
+
+    /// Adds an xHCI controller with no USB devices attached.
+    pub fn add_xhci(&mut self) -> Result<Arc<Xhci<<H::Vm as Vm>::MsiSender>>, Error> {
+        let name = Arc::new("xhci".to_owned());
+        let bdf = self.board.pci_bus.reserve(None, name.clone()).unwrap();
+        let msi_sender = self.board.vm.create_msi_sender(
+            #[cfg(target_arch = "aarch64")]
+            u32::from(bdf.0),
+        )?;
+        let memory = self.board.memory.ram_bus();
+        let xhci = Xhci::new(name.clone(), memory, msi_sender).context(error::CreateXhci)?;
+        let xhci = Arc::new(xhci);
+        let pci_dev = PciDevice::new(name, xhci.clone());
+        self.add_pci_dev(Some(bdf), pci_dev)?;
+        Ok(xhci)
+    }
+
+    /// Adds an ivshmem device that joins the server at `param.socket`.
+    #[cfg(target_os = "linux")]
+    pub fn add_ivshmem(
+        &mut self,
+        name: String,
+        param: &IvshMemParam,
+    ) -> Result<Arc<IvshMem<<H::Vm as Vm>::MsiSender>>, Error> {
+        let name = Arc::new(name);
+        let bdf = self.board.pci_bus.reserve(None, name.clone()).unwrap();
+        let msi_sender = self.board.vm.create_msi_sender(
+            #[cfg(target_arch = "aarch64")]
+            u32::from(bdf.0),
+        )?;
+        let dev = IvshMem::new(name.clone(), param, msi_sender).context(error::CreateIvshMem)?;
+        let dev = Arc::new(dev);
+        let pci_dev = PciDevice::new(name, dev.clone());
+        self.add_pci_dev(Some(bdf), pci_dev)?;
+        Ok(dev)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn vfio_container(&self) -> Result<Arc<VfioContainer>, Error> {
+        let mut container = self.board.vfio_container.lock();
+        if let Some(container) = &*container {
+            return Ok(container.clone());
+        }
+        let new_container = Arc::new(VfioContainer::new()?);
+        *container = Some(new_container.clone());
+        Ok(new_container)
+    }
+
+    /// Opens `device`, e.g. `0000:01:00.0`, of IOMMU group `group_id` for
+    /// pass-through.
+    ///
+    /// Guest RAM is mapped into the shared VFIO container when the VM boots.
+    /// VirtIO devices added after the container is created get
+    /// VIRTIO_F_ACCESS_PLATFORM.
+    #[cfg(target_os = "linux")]
+    pub fn add_vfio_dev(&mut self, group_id: u32, device: &str) -> Result<(), Error> {
+        let group = match self.vfio_groups.get(&group_id) {
+            Some(group) => group.clone(),
+            None => {
+                let container = self.vfio_container()?;
+                let group = Arc::new(VfioGroup::new(container, group_id)?);
+                self.vfio_groups.insert(group_id, group.clone());
+                group
+            }
+        };
+        let dev = group.add_device(device)?;
+        self.vfio_devs.push(dev);
+        Ok(())
+    }
+
+    pub fn add_fw_cfg(
+        &mut self,
+        params: impl Iterator<Item = FwCfgItemParam>,
+    ) -> Result<Arc<Mutex<FwCfg>>, Error> {
+        let items = params
+            .map(|p| p.build())
+            .collect::<Result<Vec<_>, _>>()
+            .context(error::FwCfg)?;
+        let fw_cfg = Arc::new(Mutex::new(
+            FwCfg::new(self.board.memory.ram_bus(), items).context(error::FwCfg)?,
+        ));
+        let mut io_devs = self.board.io_devs.write();
+        io_devs.push((PORT_SELECTOR, fw_cfg.clone()));
+        *self.board.fw_cfg.lock() = Some(fw_cfg.clone());
+        Ok(fw_cfg)
+    }
+
+    pub fn add_virtio_dev<D, P>(
+        &mut self,
+        name: String,
+        param: P,
+    ) -> Result<Arc<VirtioPciDev<D, H>>, Error>
+    where
+        P: DevParam<Device = D>,
+        D: Virtio,
+    {
+        let name = Arc::new(name);
+        let bdf = self.board.pci_bus.reserve(None, name.clone()).unwrap();
+        let dev = param.build_with_memory(name.clone(), self.board.memory.ram_bus())?;
+        let registry = self.board.vm.create_ioeventfd_registry()?;
+        let virtio_dev = VirtioDevice::new(
+            name.clone(),
+            dev,
+            self.board.memory.ram_bus(),
+            &registry,
+            self.board.config.coco.is_some(),
+            self.board.config.bounce_buffer_size,
+            #[cfg(target_os = "linux")]
+            self.board.vfio_container.lock().clone(),
+        )?;
+        let msi_sender = self.board.vm.create_msi_sender(
+            #[cfg(target_arch = "aarch64")]
+            u32::from(bdf.0),
+        )?;
+        let dev = VirtioPciDevice::new(
+            virtio_dev,
+            msi_sender,
+            registry,
+            self.board.config.trace_mmio,
+        )?;
+        let dev = Arc::new(dev);
+        let pci_dev = PciDevice::new(name.clone(), dev.clone());
+        self.add_pci_dev(Some(bdf), pci_dev)?;
+        Ok(dev)
+    }
+
+    /// Adds a virtio device that can have up to `total_vfs` SR-IOV VFs. Each
+    /// VF is built from a copy of `param` when the driver enables VFs.
+    pub fn add_virtio_sriov_dev<D, P>(
+        &mut self,
+        name: String,
+        param: P,
+        total_vfs: u16,
+    ) -> Result<Arc<VirtioSrIovDev<D, H>>, Error>
+    where
+        P: DevParam<Device = D> + Clone + Send + Sync + 'static,
+        D: Virtio,
+    {
+        let name = Arc::new(name);
+        let bdf = self.board.pci_bus.reserve(None, name.clone()).unwrap();
+        // The board owns the PF, which owns the builder.
+        let board = Arc::downgrade(&self.board);
+        let vf_param = param.clone();
+        let vf_name = name.clone();
+        let build = move |index: u16, bdf: Bdf| {
+            let Some(board) = board.upgrade() else {
+                Err(std::io::Error::from(std::io::ErrorKind::NotConnected))?
+            };
+            let name = Arc::new(format!("{vf_name}-vf{index}"));
+            let ram_bus = board.memory.ram_bus();
+            let dev = vf_param.clone().build_with_memory(name.clone(), ram_bus)?;
+            let registry = board.vm.create_ioeventfd_registry()?;
+            let virtio_dev = VirtioDevice::new(
+                name,
+                dev,
+                board.memory.ram_bus(),
+                &registry,
+                board.config.coco.is_some(),
+                board.config.bounce_buffer_size,
+                #[cfg(target_os = "linux")]
+                board.vfio_container.lock().clone(),
+            )?;
+            let msi_sender = board.vm.create_msi_sender(
+                #[cfg(target_arch = "aarch64")]
+                u32::from(bdf.0),
+            )?;
+            #[cfg(not(target_arch = "aarch64"))]
+            let _ = bdf;
+            VirtioPciDevice::new(virtio_dev, msi_sender, registry, board.config.trace_mmio)
+        };
+        let segment = self.board.pci_bus.segment.clone();
+        let vfs = VirtioVfs::new(name.clone(), bdf, total_vfs, segment, Box::new(build))?;
+        let dev = param.build_with_memory(name.clone(), self.board.memory.ram_bus())?;
+        let registry = self.board.vm.create_ioeventfd_registry()?;
+        let virtio_dev = VirtioDevice::new(
+            name.clone(),
+            dev,
+            self.board.memory.ram_bus(),
+            &registry,
+            self.board.config.coco.is_some(),
+            self.board.config.bounce_buffer_size,
+            #[cfg(target_os = "linux")]
+            self.board.vfio_container.lock().clone(),
+        )?;
+        let msi_sender = self.board.vm.create_msi_sender(
+            #[cfg(target_arch = "aarch64")]
+            u32::from(bdf.0),
+        )?;
+        let dev = VirtioSrIovPf::new(
+            virtio_dev,
+            msi_sender,
+            registry,
+            self.board.config.trace_mmio,
+            vfs,
+        )?;
+        let dev = Arc::new(dev);
+        let pci_dev = PciDevice::new(name.clone(), dev.clone());
+        self.add_pci_dev(Some(bdf), pci_dev)?;
+        Ok(dev)
+    }
+
+    /// Creates a virtio device and attaches it to the running VM.
+    #[cfg(target_arch = "x86_64")]
+    pub fn attach_virtio_dev<D, P>(&self, name: String, param: P) -> Result<Bdf, Error>
+    where
+        P: DevParam<Device = D>,
+        D: Virtio,
+    {
+        let name = Arc::new(name);
+        let dev = param.build_with_memory(name.clone(), self.board.memory.ram_bus())?;
+        let registry = self.board.vm.create_ioeventfd_registry()?;
+        let virtio_dev = VirtioDevice::new(
+            name.clone(),
+            dev,
+            self.board.memory.ram_bus(),
+            &registry,
+            self.board.config.coco.is_some(),
+            self.board.config.bounce_buffer_size,
+            #[cfg(target_os = "linux")]
+            self.board.vfio_container.lock().clone(),
+        )?;
+        let msi_sender = self.board.vm.create_msi_sender()?;
+        let dev = VirtioPciDevice::new(
+            virtio_dev,
+            msi_sender,
+            registry,
+            self.board.config.trace_mmio,
+        )?;
+        let pci_dev = PciDevice::new(name, Arc::new(dev));
+        let bdf = self.board.vm_bus.attach(pci_dev)?;
+        Ok(bdf)
+    }
+
+    /// Detaches the PCI device at `bdf` from the running VM.
+    pub fn detach_pci_dev(&self, bdf: Bdf) -> Result<(), Error> {
+        self.board.vm_bus.detach(bdf, &self.board.memory)?;
+        Ok(())
+    }
+
+    /// Returns the exit counts of each vCPU, ordered by vCPU ID.
+    pub fn vcpu_stats(&self) -> Vec<(u32, VcpuStatsSnapshot)> {
+        let stats = self.board.vcpu_stats.read();
+        stats.iter().map(|(id, s)| (*id, s.snapshot())).collect()
+    }
+
+    pub fn add_payload(&mut self, payload: Payload) {
+        *self.board.payload.write() = Some(payload)
+    }
+
+    pub fn boot(&mut self) -> Result<(), Error> {
+        let vcpus = self.board.vcpus.read();
+        self.board.state.store(STATE_RUNNING, Ordering::Release);
+        for (_, boot_tx) in vcpus.iter() {
+            boot_tx.send(()).unwrap();
+        }
+        Ok(())
+    }
+
+    pub fn wait(&mut self) -> Vec<Result<()>> {
+        self.event_rx.recv().unwrap();
+        let vcpus = self.board.vcpus.read();
+        for _ in 1..vcpus.len() {
+            self.event_rx.recv().unwrap();
+        }
+        drop(vcpus);
+        // The preemptor must stop before the vCPU threads are joined.
+        #[cfg(target_os = "linux")]
+        self.preemptor.take();
+        let mut vcpus = self.board.vcpus.write();
+        vcpus
+            .drain(..)
+            .enumerate()
+            .map(|(id, (handle, _))| match handle.join() {
+                Err(e) => {
+                    log::error!("cannot join vcpu {}: {:?}", id, e);
+                    Ok(())
+                }
+                Ok(r) => r.context(error::Vcpu { id: id as u32 }),
+            })
+            .collect()
+    }
+}
+
+/// Assembles a [`Machine`] with the commonly used devices, e.g.
+///
+/// ```no_run
+/// # #[cfg(target_os = "linux")]
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// # use alioth::hv::{Kvm, KvmConfig};
+/// # use alioth::vm::VmBuilder;
+/// let mut builder = VmBuilder::new(Kvm::new(KvmConfig::default())?);
+/// builder.memory(512).vcpus(2).add_console();
+/// builder.boot_kernel("bzImage", "console=ttyS0");
+/// let mut vm = builder.build()?;
+/// vm.boot()?;
+/// # Ok(())
+/// # }
+/// # #[cfg(not(target_os = "linux"))]
+/// # fn main() {}
+/// ```
+pub struct VmBuilder<H> {
+    hv: H,
+    config: BoardConfig,
+    console: bool,
+    blks: Vec<BlockParam>,
+    #[cfg(target_os = "linux")]
+    nets: Vec<NetParam>,
+    payload: Option<Payload>,
+}
+
+impl<H> VmBuilder<H>
+where
+    H: Hypervisor + 'static,
+{
+    /// Creates a builder of a VM with 1 vCPU and 1 GiB of memory.
+    pub fn new(hv: H) -> Self {
+        VmBuilder {
+            hv,
+            config: BoardConfig {
+                mem_size: 1 << 30,
+                num_cpu: 1,
+                ..Default::default()
+            },
+            console: false,
+            blks: Vec::new(),
+            #[cfg(target_os = "linux")]
+            nets: Vec::new(),
+            payload: None,
+        }
+    }
+
+    /// Creates a builder from a VM description, e.g. one loaded by
+    /// [`config::VmConfig::from_file`].
+    pub fn with_config(hv: H, config: config::VmConfig) -> Self {
+        let mut builder = VmBuilder::new(hv);
+        builder
+            .memory(config.vm.memory_mb.get())
+            .vcpus(config.vm.vcpus.get());
+        if config.device.console {
+            builder.add_console();
+        }
+        builder.blks = config.device.block;
+        #[cfg(target_os = "linux")]
+        {
+            builder.nets = config.device.net;
+        }
+        if let Some(boot) = config.boot {
+            builder.boot_kernel(boot.kernel, &boot.cmdline);
+            if let Some(initramfs) = boot.initramfs {
+                builder.initramfs(initramfs);
+            }
+        }
+        builder
+    }
+
+    pub fn memory(&mut self, size_mb: u64) -> &mut Self {
+        self.config.mem_size = size_mb << 20;
+        self
+    }
+
+    pub fn vcpus(&mut self, count: u32) -> &mut Self {
+        self.config.num_cpu = count;
+        self
+    }
+
+    pub fn add_block(&mut self, param: BlockParam) -> &mut Self {
+        self.blks.push(param);
+        self
+    }
+
+    #[cfg(target_os = "linux")]
+    pub fn add_net(&mut self, param: NetParam) -> &mut Self {
+        self.nets.push(param);
+        self
+    }
+
+    /// Adds a serial console, COM1 on x86_64 and a PL011 on aarch64.
+    pub fn add_console(&mut self) -> &mut Self {
+        self.console = true;
+        self
+    }
+
+    pub fn boot_kernel(&mut self, path: impl Into<PathBuf>, cmdline: &str) -> &mut Self {
+        self.payload = Some(Payload {
+            executable: path.into(),
+            exec_type: ExecType::Linux,
+            initramfs: self.payload.take().and_then(|p| p.initramfs),
+            cmd_line: Some(cmdline.to_owned()),
+            firmware_vars: None,
+        });
+        self
+    }
+
+    /// Sets the initramfs of the kernel given by [`VmBuilder::boot_kernel`].
+    pub fn initramfs(&mut self, path: impl Into<PathBuf>) -> &mut Self {
+        if let Some(payload) = &mut self.payload {
+            payload.initramfs = Some(path.into());
+        }
+        self
+    }
+
+    /// Creates the VM with all devices added, ready to [`Machine::boot`].
+    pub fn build(self) -> Result<Machine<H>> {
+        let mut vm = Machine::new(self.hv, self.config)?;
+        #[cfg(target_arch = "x86_64")]
+        vm.add_pit()?;
+        #[cfg(target_arch = "x86_64")]
+        vm.add_rtc()?;
+        if self.console {
+            #[cfg(target_arch = "x86_64")]
+            vm.add_com1()?;
+            #[cfg(target_arch = "aarch64")]
+            vm.add_pl011()?;
+        }
+        for (index, param) in self.blks.into_iter().enumerate() {
+            vm.add_virtio_dev(format!("virtio-blk-{index}"), param)?;
+        }
+        #[cfg(target_os = "linux")]
+        for (index, param) in self.nets.into_iter().enumerate() {
+            vm.add_virtio_dev(format!("virtio-net-{index}"), param)?;
+        }
+        if let Some(payload) = self.payload {
+            vm.add_payload(payload);
+        }
+        Ok(vm)
+    }
+}
+
+#[cfg(test)]
+#[cfg(target_os = "linux")]
+mod test {
+    use std::ffi::OsString;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::time::{Duration, Instant};
+
+    use crate::hv::{Kvm, KvmConfig};
+    use crate::virtio::dev::blk::BlockParam;
+    use crate::vm::VmBuilder;
+
+    fn disk_images(count: usize) -> Vec<PathBuf> {
+        let pid = std::process::id();
+        (0..count)
+            .map(|index| {
+                let path = std::env::temp_dir().join(format!("alioth-vm-{pid}-{index}.img"));
+                fs::write(&path, vec![0u8; 1 << 20]).unwrap();
+                path
+            })
+            .collect()
+    }
+
+    fn add_disks(builder: &mut VmBuilder<Kvm>, images: &[PathBuf]) {
+        for path in images {
+            builder.add_block(BlockParam {
+                path: path.clone(),
+                ..Default::default()
+            });
+        }
+    }
+
+    #[test]
+    #[cfg_attr(not(feature = "test-hv"), ignore)]
+    fn test_vm_builder_multiple_blocks() {
+        let images = disk_images(3);
+        let mut builder = VmBuilder::new(Kvm::new(KvmConfig::default()).unwrap());
+        builder.memory(64);
+        add_disks(&mut builder, &images);
+        let vm = builder.build().unwrap();
+
+        let devices = vm.board.pci_bus.segment.devices.read();
+        let mut blks: Vec<_> = devices
+            .iter()
+            .filter(|(_, dev)| dev.name.starts_with("virtio-blk-"))
+            .map(|(bdf, dev)| (dev.name.to_string(), *bdf))
+            .collect();
+        drop(devices);
+        blks.sort();
+        let names: Vec<_> = blks.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, ["virtio-blk-0", "virtio-blk-1", "virtio-blk-2"]);
+        let mut bdfs: Vec<_> = blks.iter().map(|(_, bdf)| *bdf).collect();
+        bdfs.dedup();
+        assert_eq!(bdfs.len(), 3);
+
+        // A thread names itself after it starts.
+        let workers = || {
+            let mut names = vec![];
+            for task in fs::read_dir("/proc/self/task").unwrap() {
+                let Ok(comm) = fs::read_to_string(task.unwrap().path().join("comm")) else {
+                    continue;
+                };
+                if comm.starts_with("vblk-") {
+                    names.push(comm.trim_end().to_owned());
+                }
+            }
+            names.sort();
+            names
+        };
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while workers().len() < 3 {
+            assert!(Instant::now() < deadline, "{:?}", workers());
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(workers(), ["vblk-0", "vblk-1", "vblk-2"]);
+
+        drop(vm);
+        for path in images {
+            fs::remove_file(path).unwrap();
+        }
+    }
+
+    /// Returns `$ALIOTH_TEST_KERNEL` and `$ALIOTH_TEST_INITRAMFS`, or `None`
+    /// to skip a boot test if either is unset.
+    fn test_kernel() -> Option<(OsString, OsString)> {
+        let kernel = std::env::var_os("ALIOTH_TEST_KERNEL")?;
+        let initramfs = std::env::var_os("ALIOTH_TEST_INITRAMFS")?;
+        Some((kernel, initramfs))
+    }
+
+    /// Boots `$ALIOTH_TEST_KERNEL` with `$ALIOTH_TEST_INITRAMFS`, which has
+    /// busybox, into a shell that powers off the VM.
+    #[test]
+    #[cfg_attr(not(feature = "test-hv"), ignore)]
+    fn test_vm_builder_boot() {
+        let Some((kernel, initramfs)) = test_kernel() else {
+            return;
+        };
+        let console = if cfg!(target_arch = "x86_64") {
+            "ttyS0"
+        } else {
+            "ttyAMA0"
+        };
+        let cmdline = format!(r#"console={console} panic=-1 rdinit=/bin/sh -- -c "poweroff -f""#);
+        let mut builder = VmBuilder::new(Kvm::new(KvmConfig::default()).unwrap());
+        builder.memory(512).vcpus(2).add_console();
+        builder.boot_kernel(kernel, &cmdline).initramfs(initramfs);
+        let mut vm = builder.build().unwrap();
+        vm.boot().unwrap();
+        for result in vm.wait() {
+            result.unwrap();
+        }
+    }
+
+    /// Boots like [`test_vm_builder_boot`] with 3 disks, into each of which
+    /// the guest writes its device name.
+    #[test]
+    #[cfg_attr(not(feature = "test-hv"), ignore)]
+    fn test_vm_builder_boot_blocks() {
+        let Some((kernel, initramfs)) = test_kernel() else {
+            return;
+        };
+        let images = disk_images(3);
+        let script = "mount -t devtmpfs dev /dev; \
+            echo vda > /dev/vda; echo vdb > /dev/vdb; echo vdc > /dev/vdc; \
+            sync; poweroff -f";
+        let cmdline = format!(r#"panic=-1 rdinit=/bin/sh -- -c "{script}""#);
+        let mut builder = VmBuilder::new(Kvm::new(KvmConfig::default()).unwrap());
+        builder.memory(512);
+        builder.boot_kernel(kernel, &cmdline).initramfs(initramfs);
+        add_disks(&mut builder, &images);
+        let mut vm = builder.build().unwrap();
+        vm.boot().unwrap();
+        for result in vm.wait() {
+            result.unwrap();
+        }
+        drop(vm);
+
+        for (path, name) in images.iter().zip(["vda", "vdb", "vdc"]) {
+            let data = fs::read(path).unwrap();
+            assert_eq!(&data[..4], format!("{name}\n").as_bytes());
+            fs::remove_file(path).unwrap();
+        }
+    }
+}
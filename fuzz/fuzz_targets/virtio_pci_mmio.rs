@@ -0,0 +1,39 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#![no_main]
+
+use alioth::hv::test::FakeMsiSender;
+use alioth::mem::emulated::Mmio;
+use alioth::virtio::pci::VirtioPciRegisterMmio;
+use libfuzzer_sys::fuzz_target;
+
+const NUM_QUEUES: u16 = 4;
+
+// Each access is encoded in 11 bytes: offset (u16), size (u8), and val
+// (u64), all little-endian. Bit 7 of the size byte selects a write and the
+// lower 2 bits select an access size of 1, 2, 4, or 8 bytes.
+fuzz_target!(|data: &[u8]| {
+    let registers = VirtioPciRegisterMmio::new_detached(FakeMsiSender::default(), NUM_QUEUES);
+    for access in data.chunks_exact(11) {
+        let offset = u16::from_le_bytes([access[0], access[1]]) as u64;
+        let size = 1 << (access[2] & 0b11);
+        let val = u64::from_le_bytes(access[3..].try_into().unwrap());
+        if access[2] & 0x80 == 0 {
+            let _ = registers.read(offset, size);
+        } else {
+            let _ = registers.write(offset, size, val);
+        }
+    }
+});
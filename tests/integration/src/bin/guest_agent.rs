@@ -0,0 +1,87 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A guest agent that runs commands sent from the host over vsock.
+//!
+//! Each connection carries one command line. The agent runs it with `sh -c`
+//! and replies with the exit code and the combined output, see
+//! [`alioth_integration::Guest::run`].
+
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::mem::size_of;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::process::{Command, Stdio};
+use std::ptr::null_mut;
+
+use alioth_integration::{write_response, AGENT_PORT};
+
+fn listen(port: u32) -> std::io::Result<OwnedFd> {
+    let fd = unsafe { libc::socket(libc::AF_VSOCK, libc::SOCK_STREAM | libc::SOCK_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    let socket = unsafe { OwnedFd::from_raw_fd(fd) };
+    let mut addr: libc::sockaddr_vm = unsafe { std::mem::zeroed() };
+    addr.svm_family = libc::AF_VSOCK as _;
+    addr.svm_cid = libc::VMADDR_CID_ANY;
+    addr.svm_port = port;
+    let addr_ptr = &addr as *const libc::sockaddr_vm as *const libc::sockaddr;
+    let addr_len = size_of::<libc::sockaddr_vm>() as libc::socklen_t;
+    if unsafe { libc::bind(fd, addr_ptr, addr_len) } < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    if unsafe { libc::listen(fd, 16) } < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(socket)
+}
+
+fn serve(conn: File) -> std::io::Result<()> {
+    let mut cmd = String::new();
+    BufReader::new(&conn).read_line(&mut cmd)?;
+    let output = Command::new("/bin/sh")
+        .arg("-c")
+        .arg(format!("{} 2>&1", cmd.trim_end()))
+        .stdin(Stdio::null())
+        .output()?;
+    let code = output.status.code().unwrap_or(-1);
+    let mut conn = conn;
+    write_response(&mut conn, code, &output.stdout)?;
+    conn.flush()
+}
+
+fn main() -> std::io::Result<()> {
+    let socket = listen(AGENT_PORT)?;
+    loop {
+        let fd = unsafe {
+            libc::accept4(
+                socket.as_raw_fd(),
+                null_mut(),
+                null_mut(),
+                libc::SOCK_CLOEXEC,
+            )
+        };
+        if fd < 0 {
+            eprintln!("accept: {}", std::io::Error::last_os_error());
+            continue;
+        }
+        let conn = unsafe { File::from_raw_fd(fd) };
+        std::thread::spawn(move || {
+            if let Err(e) = serve(conn) {
+                eprintln!("serve: {e}")
+            }
+        });
+    }
+}
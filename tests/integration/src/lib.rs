@@ -0,0 +1,383 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! End-to-end test framework of Alioth.
+//!
+//! Tests boot a Linux guest with the `alioth` binary and run commands in
+//! the guest through `alioth-guest-agent`, which listens on vsock port
+//! [`AGENT_PORT`]. Guest images are taken from environment variables,
+//!
+//! - `ALIOTH_TEST_KERNEL`: a Linux kernel with virtio-blk, virtio-net, and
+//!   vhost-vsock guest drivers built in,
+//! - `ALIOTH_TEST_INITRAMFS`: an initramfs with busybox, whose init starts
+//!   `alioth-guest-agent`, which can be built statically with
+//!   `cargo build --bin alioth-guest-agent --target x86_64-unknown-linux-musl`.
+//!
+//! The host needs `/dev/kvm` and `/dev/vhost-vsock`. The tests are ignored
+//! unless feature `test-hv` is enabled, e.g.,
+//!
+//! ```sh
+//! cargo build -p alioth-cli
+//! cargo test -p alioth-integration --features test-hv
+//! ```
+
+use std::io::{self, ErrorKind, Write};
+use std::mem::size_of;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::path::{Path, PathBuf};
+use std::process::{ExitStatus, Stdio};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use assert_cmd::cargo::CommandCargoExt;
+use tokio::io::unix::AsyncFd;
+use tokio::io::AsyncReadExt;
+use tokio::process::{Child, Command};
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, timeout, Instant};
+
+pub const AGENT_PORT: u32 = 1024;
+
+#[cfg(target_arch = "x86_64")]
+pub const GUEST_CONSOLE: &str = "ttyS0";
+#[cfg(target_arch = "aarch64")]
+pub const GUEST_CONSOLE: &str = "ttyAMA0";
+
+/// Writes the result of a command as `<exit code> <output length>\n`
+/// followed by the output.
+pub fn write_response(w: &mut impl Write, code: i32, output: &[u8]) -> io::Result<()> {
+    writeln!(w, "{code} {}", output.len())?;
+    w.write_all(output)
+}
+
+#[derive(Debug)]
+pub struct CmdOutput {
+    pub code: i32,
+    pub output: Vec<u8>,
+}
+
+fn parse_response(buf: &[u8]) -> io::Result<CmdOutput> {
+    let invalid = || io::Error::new(ErrorKind::InvalidData, "invalid agent response");
+    let header_len = buf.iter().position(|b| *b == b'\n').ok_or_else(invalid)?;
+    let header = std::str::from_utf8(&buf[..header_len]).map_err(|_| invalid())?;
+    let (code, len) = header.split_once(' ').ok_or_else(invalid)?;
+    let code = code.parse().map_err(|_| invalid())?;
+    let len: usize = len.parse().map_err(|_| invalid())?;
+    let output = &buf[header_len + 1..];
+    if output.len() != len {
+        return Err(invalid());
+    }
+    Ok(CmdOutput {
+        code,
+        output: output.to_vec(),
+    })
+}
+
+#[derive(Debug, Clone)]
+pub struct TestImages {
+    pub kernel: PathBuf,
+    pub initramfs: PathBuf,
+}
+
+impl TestImages {
+    pub fn from_env() -> Self {
+        let get = |name| match std::env::var_os(name) {
+            Some(path) => PathBuf::from(path),
+            None => panic!("${name} is not set"),
+        };
+        TestImages {
+            kernel: get("ALIOTH_TEST_KERNEL"),
+            initramfs: get("ALIOTH_TEST_INITRAMFS"),
+        }
+    }
+}
+
+fn alloc_cid() -> u32 {
+    static COUNT: AtomicU32 = AtomicU32::new(0);
+    let base = 3 + (std::process::id() & 0xffff) * 16;
+    base + COUNT.fetch_add(1, Ordering::Relaxed)
+}
+
+#[derive(Debug)]
+pub struct VmBuilder {
+    images: TestImages,
+    mem_size: String,
+    num_cpu: u32,
+    args: Vec<String>,
+}
+
+impl VmBuilder {
+    pub fn new(images: TestImages) -> Self {
+        VmBuilder {
+            images,
+            mem_size: "512M".to_owned(),
+            num_cpu: 2,
+            args: vec![],
+        }
+    }
+
+    pub fn mem_size(mut self, mem_size: &str) -> Self {
+        mem_size.clone_into(&mut self.mem_size);
+        self
+    }
+
+    pub fn num_cpu(mut self, num_cpu: u32) -> Self {
+        self.num_cpu = num_cpu;
+        self
+    }
+
+    pub fn blk(mut self, path: &Path) -> Self {
+        self.args.push("--blk".to_owned());
+        self.args.push(format!("path={}", path.display()));
+        self
+    }
+
+    /// Adds a virtio-net device; `param` is passed to `alioth run --net`.
+    pub fn net(mut self, param: &str) -> Self {
+        self.args.push("--net".to_owned());
+        self.args.push(param.to_owned());
+        self
+    }
+
+    /// Runs the VMM in a cgroup; `param` is passed to `alioth run --cgroup`.
+    pub fn cgroup(mut self, param: &str) -> Self {
+        self.args.push("--cgroup".to_owned());
+        self.args.push(param.to_owned());
+        self
+    }
+
+    pub fn spawn(self) -> io::Result<TestVm> {
+        let cid = alloc_cid();
+        let mut cmd = std::process::Command::cargo_bin("alioth").map_err(io::Error::other)?;
+        cmd.arg("run")
+            .arg("--kernel")
+            .arg(&self.images.kernel)
+            .arg("--initramfs")
+            .arg(&self.images.initramfs)
+            .arg("--cmd-line")
+            .arg(format!("console={GUEST_CONSOLE} panic=-1"))
+            .arg("--mem-size")
+            .arg(&self.mem_size)
+            .arg("--num-cpu")
+            .arg(self.num_cpu.to_string())
+            .arg("--vsock")
+            .arg(format!("vhost,cid={cid}"))
+            .args(&self.args);
+        let mut child = Command::from(cmd)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()?;
+
+        let console = Arc::new(Mutex::new(Vec::new()));
+        let mut stdout = child.stdout.take().unwrap();
+        let buf = console.clone();
+        let console_task = tokio::spawn(async move {
+            let mut chunk = [0u8; 4096];
+            while let Ok(len @ 1..) = stdout.read(&mut chunk).await {
+                buf.lock().unwrap().extend_from_slice(&chunk[..len]);
+            }
+        });
+        Ok(TestVm {
+            child,
+            guest: Guest { cid },
+            console,
+            console_task,
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct TestVm {
+    child: Child,
+    guest: Guest,
+    console: Arc<Mutex<Vec<u8>>>,
+    console_task: JoinHandle<()>,
+}
+
+impl TestVm {
+    pub fn guest(&self) -> &Guest {
+        &self.guest
+    }
+
+    /// Returns the console output so far.
+    pub fn console(&self) -> Vec<u8> {
+        self.console.lock().unwrap().clone()
+    }
+
+    /// Waits until `pattern` shows up in the console output.
+    pub async fn wait_console(&self, pattern: &str, duration: Duration) -> bool {
+        let deadline = Instant::now() + duration;
+        loop {
+            let console = self.console();
+            let found = console
+                .windows(pattern.len())
+                .any(|w| w == pattern.as_bytes());
+            if found {
+                return true;
+            }
+            if Instant::now() >= deadline {
+                return false;
+            }
+            sleep(Duration::from_millis(100)).await;
+        }
+    }
+
+    /// Waits for the VMM process to exit.
+    pub async fn wait(&mut self, duration: Duration) -> io::Result<ExitStatus> {
+        let status = timeout(duration, self.child.wait()).await??;
+        self.console_task.abort();
+        Ok(status)
+    }
+}
+
+/// A connection to the agent in a guest.
+#[derive(Debug)]
+pub struct Guest {
+    cid: u32,
+}
+
+impl Guest {
+    pub fn cid(&self) -> u32 {
+        self.cid
+    }
+
+    /// Runs `cmd` with `sh -c` in the guest and returns its exit code and
+    /// the combined stdout and stderr.
+    pub async fn run(&self, cmd: &str) -> io::Result<CmdOutput> {
+        let stream = VsockStream::connect(self.cid, AGENT_PORT).await?;
+        stream.write_all(format!("{cmd}\n").as_bytes()).await?;
+        let buf = stream.read_to_end().await?;
+        parse_response(&buf)
+    }
+
+    /// Waits until the agent in the guest accepts commands.
+    pub async fn wait_ready(&self, duration: Duration) -> io::Result<()> {
+        let deadline = Instant::now() + duration;
+        loop {
+            match self.run("true").await {
+                Ok(_) => return Ok(()),
+                Err(e) if Instant::now() >= deadline => return Err(e),
+                Err(_) => sleep(Duration::from_millis(500)).await,
+            }
+        }
+    }
+}
+
+struct VsockStream {
+    fd: AsyncFd<OwnedFd>,
+}
+
+impl VsockStream {
+    async fn connect(cid: u32, port: u32) -> io::Result<Self> {
+        let flags = libc::SOCK_STREAM | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC;
+        let fd = unsafe { libc::socket(libc::AF_VSOCK, flags, 0) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let socket = unsafe { OwnedFd::from_raw_fd(fd) };
+        let mut addr: libc::sockaddr_vm = unsafe { std::mem::zeroed() };
+        addr.svm_family = libc::AF_VSOCK as _;
+        addr.svm_cid = cid;
+        addr.svm_port = port;
+        let addr_ptr = &addr as *const libc::sockaddr_vm as *const libc::sockaddr;
+        let addr_len = size_of::<libc::sockaddr_vm>() as libc::socklen_t;
+        if unsafe { libc::connect(fd, addr_ptr, addr_len) } < 0 {
+            let e = io::Error::last_os_error();
+            if e.raw_os_error() != Some(libc::EINPROGRESS) {
+                return Err(e);
+            }
+        }
+        let stream = VsockStream {
+            fd: AsyncFd::new(socket)?,
+        };
+        let _ = stream.fd.writable().await?;
+        let mut error: libc::c_int = 0;
+        let mut len = size_of::<libc::c_int>() as libc::socklen_t;
+        let ret = unsafe {
+            libc::getsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                libc::SO_ERROR,
+                &mut error as *mut _ as *mut _,
+                &mut len,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if error != 0 {
+            return Err(io::Error::from_raw_os_error(error));
+        }
+        Ok(stream)
+    }
+
+    async fn write_all(&self, mut buf: &[u8]) -> io::Result<()> {
+        while !buf.is_empty() {
+            let mut guard = self.fd.writable().await?;
+            let ret = guard.try_io(|fd| {
+                let ret = unsafe { libc::write(fd.as_raw_fd(), buf.as_ptr() as _, buf.len()) };
+                if ret < 0 {
+                    Err(io::Error::last_os_error())
+                } else {
+                    Ok(ret as usize)
+                }
+            });
+            if let Ok(len) = ret {
+                buf = &buf[len?..];
+            }
+        }
+        Ok(())
+    }
+
+    async fn read_to_end(&self) -> io::Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        let mut chunk = [0u8; 4096];
+        loop {
+            let mut guard = self.fd.readable().await?;
+            let ret = guard.try_io(|fd| {
+                let ret =
+                    unsafe { libc::read(fd.as_raw_fd(), chunk.as_mut_ptr() as _, chunk.len()) };
+                if ret < 0 {
+                    Err(io::Error::last_os_error())
+                } else {
+                    Ok(ret as usize)
+                }
+            });
+            match ret {
+                Ok(Ok(0)) => return Ok(buf),
+                Ok(Ok(len)) => buf.extend_from_slice(&chunk[..len]),
+                Ok(Err(e)) => return Err(e),
+                Err(_would_block) => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{parse_response, write_response};
+
+    #[test]
+    fn test_agent_response() {
+        let mut buf = Vec::new();
+        write_response(&mut buf, 3, b"hello\n").unwrap();
+        assert_eq!(buf, b"3 6\nhello\n");
+        let output = parse_response(&buf).unwrap();
+        assert_eq!(output.code, 3);
+        assert_eq!(output.output, b"hello\n");
+        assert!(parse_response(b"3 7\nhello\n").is_err());
+    }
+}
@@ -0,0 +1,138 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::net::Ipv4Addr;
+use std::os::unix::process::ExitStatusExt;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use alioth_integration::{TestImages, TestVm, VmBuilder, GUEST_CONSOLE};
+use tokio::net::UdpSocket;
+use tokio::time::timeout;
+
+const BOOT_TIMEOUT: Duration = Duration::from_secs(60);
+
+async fn boot(builder: VmBuilder) -> TestVm {
+    let vm = builder.spawn().unwrap();
+    if let Err(e) = vm.guest().wait_ready(BOOT_TIMEOUT).await {
+        let console = String::from_utf8_lossy(&vm.console()).into_owned();
+        panic!("guest agent is not ready: {e}\nconsole output:\n{console}");
+    }
+    vm
+}
+
+#[tokio::test]
+#[cfg_attr(not(feature = "test-hv"), ignore)]
+async fn test_blk_content() {
+    let path = std::env::temp_dir().join(format!("alioth-it-{}.img", std::process::id()));
+    let mut disk = vec![0u8; 1 << 20];
+    disk[..16].copy_from_slice(b"alioth-disk-test");
+    std::fs::write(&path, &disk).unwrap();
+
+    let vm = boot(VmBuilder::new(TestImages::from_env()).blk(&path)).await;
+    let ret = vm.guest().run("head -c 16 /dev/vda").await.unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(ret.code, 0);
+    assert_eq!(ret.output, b"alioth-disk-test");
+}
+
+/// Needs a tap device named by `$ALIOTH_TEST_TAP`, with host address
+/// `$ALIOTH_TEST_TAP_IP` in a /24 network. The guest takes the next address.
+#[tokio::test]
+#[cfg_attr(not(feature = "test-hv"), ignore)]
+async fn test_net() {
+    let tap = std::env::var("ALIOTH_TEST_TAP").unwrap();
+    let host_ip: Ipv4Addr = std::env::var("ALIOTH_TEST_TAP_IP")
+        .unwrap()
+        .parse()
+        .unwrap();
+    let guest_ip = Ipv4Addr::from(u32::from(host_ip) + 1);
+    let socket = UdpSocket::bind((host_ip, 0)).await.unwrap();
+    let port = socket.local_addr().unwrap().port();
+
+    let net = format!("if={tap},mac=ea:d7:a8:e8:c6:2f,mtu=1500");
+    let vm = boot(VmBuilder::new(TestImages::from_env()).net(&net)).await;
+    let guest = vm.guest();
+
+    let ret = guest
+        .run("ip link set lo up && ping -c 1 -W 5 127.0.0.1")
+        .await;
+    assert_eq!(ret.unwrap().code, 0);
+
+    let setup = format!("ip addr add {guest_ip}/24 dev eth0 && ip link set eth0 up");
+    assert_eq!(guest.run(&setup).await.unwrap().code, 0);
+    let send = format!("echo alioth-udp-test | nc -u -w 1 {host_ip} {port}");
+    assert_eq!(guest.run(&send).await.unwrap().code, 0);
+
+    let mut buf = [0u8; 64];
+    let (len, addr) = timeout(Duration::from_secs(10), socket.recv_from(&mut buf))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(&buf[..len], b"alioth-udp-test\n");
+    assert_eq!(addr.ip(), guest_ip);
+}
+
+#[tokio::test]
+#[cfg_attr(not(feature = "test-hv"), ignore)]
+async fn test_console() {
+    let vm = boot(VmBuilder::new(TestImages::from_env())).await;
+    let cmd = format!("echo alioth-console-test > /dev/{GUEST_CONSOLE}");
+    assert_eq!(vm.guest().run(&cmd).await.unwrap().code, 0);
+    let found = vm
+        .wait_console("alioth-console-test", Duration::from_secs(5))
+        .await;
+    assert!(found);
+}
+
+#[tokio::test]
+#[cfg_attr(not(feature = "test-hv"), ignore)]
+async fn test_shutdown() {
+    let mut vm = boot(VmBuilder::new(TestImages::from_env())).await;
+    // The agent may be gone before it replies.
+    let _ = vm.guest().run("poweroff -f").await;
+    let status = vm.wait(Duration::from_secs(30)).await.unwrap();
+    assert!(status.success());
+}
+
+/// Needs a cgroup v2 directory `$ALIOTH_TEST_CGROUP` with the cpu and memory
+/// controllers enabled for its children.
+#[tokio::test]
+#[cfg_attr(not(feature = "test-hv"), ignore)]
+async fn test_cgroup_oom() {
+    let parent = PathBuf::from(std::env::var_os("ALIOTH_TEST_CGROUP").unwrap());
+    let path = parent.join(format!("alioth-it-{}", std::process::id()));
+    let param = format!(
+        "path={},cpu_quota_us=200000,memory_limit_bytes=256M",
+        path.display()
+    );
+    let builder = VmBuilder::new(TestImages::from_env())
+        .mem_size("1G")
+        .cgroup(&param);
+    let mut vm = boot(builder).await;
+
+    // The root file system is in guest RAM, so filling it faults in guest
+    // memory beyond the limit of the cgroup.
+    let _ = vm.guest().run("head -c 768M /dev/zero > /fill").await;
+    let status = vm.wait(Duration::from_secs(60)).await.unwrap();
+    assert_eq!(status.signal(), Some(libc::SIGKILL));
+
+    let events = std::fs::read_to_string(path.join("memory.events")).unwrap();
+    let oom_kills = events
+        .lines()
+        .find_map(|line| line.strip_prefix("oom_kill "))
+        .unwrap();
+    assert_ne!(oom_kills, "0");
+    std::fs::remove_dir(&path).unwrap();
+}